@@ -0,0 +1,46 @@
+//! Embeds the git commit being built, for `/api/version` and the startup log.
+//!
+//! Builds from outside a git checkout (e.g. a source tarball) can set
+//! `SHAFT_GIT_COMMIT` in the environment instead.
+
+use std::env;
+use std::process::Command;
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=SHAFT_GIT_COMMIT");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+
+    let commit = env::var("SHAFT_GIT_COMMIT")
+        .ok()
+        .or_else(git_commit)
+        .unwrap_or_else(|| "unknown".to_string());
+
+    println!("cargo:rustc-env=SHAFT_GIT_COMMIT={}", commit);
+}
+
+/// The short hash of HEAD, with `-dirty` appended if there are uncommitted
+/// changes.
+fn git_commit() -> Option<String> {
+    let output = Command::new("git")
+        .args([
+            "describe",
+            "--always",
+            "--dirty",
+            "--abbrev=12",
+            "--exclude=*",
+        ])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    let commit = String::from_utf8(output.stdout).ok()?;
+    let commit = commit.trim();
+    if commit.is_empty() {
+        None
+    } else {
+        Some(commit.to_string())
+    }
+}
@@ -0,0 +1,220 @@
+//! Parsing human readable amounts of money, such as `12.50`, `£3` or
+//! `-1,200.00`, into the currency's minor unit (e.g. pence).
+//!
+//! This is shared by everything that accepts amounts from people: the web
+//! form, the JSON API, statement imports and the Slack command.
+
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+use serde::{Serialize, Serializer};
+use snafu::Snafu;
+
+use std::fmt;
+
+use crate::money::Money;
+use crate::settings::CurrencySettings;
+
+/// Why an amount couldn't be parsed.
+#[derive(Debug, Clone, PartialEq, Eq, Snafu)]
+pub enum AmountError {
+    #[snafu(display("No amount given"))]
+    Empty,
+
+    #[snafu(display("Invalid amount '{}'", input))]
+    Invalid { input: String },
+
+    #[snafu(display("Amount '{}' is too large", input))]
+    TooLarge { input: String },
+}
+
+/// Parse an amount written in the given currency's notation into minor
+/// units.
+///
+/// Accepts an optional sign and currency symbol in either order, thousands
+/// separated into groups of three, and up to the currency's number of decimal
+/// places. With the default settings that's e.g. `12.50`, `£3`, `-£1,200.00`
+/// or `.5`. Surrounding whitespace is ignored.
+pub fn parse_amount(input: &str, currency: &CurrencySettings) -> Result<i64, AmountError> {
+    let value = input.trim();
+    if value.is_empty() {
+        return Err(AmountError::Empty);
+    }
+
+    let invalid = || AmountError::Invalid {
+        input: input.to_string(),
+    };
+    let too_large = || AmountError::TooLarge {
+        input: input.to_string(),
+    };
+
+    // The sign can go either side of the symbol, but not both.
+    let (negative, unsigned) = strip_sign(value);
+    let signed = unsigned.len() != value.len();
+    let value = if currency.symbol.is_empty() {
+        unsigned
+    } else {
+        unsigned
+            .strip_prefix(currency.symbol.as_str())
+            .unwrap_or(unsigned)
+    };
+    let (negative, value) = if signed {
+        (negative, value)
+    } else {
+        strip_sign(value)
+    };
+
+    let (whole, fraction) = match value.find(currency.decimal_separator.as_str()) {
+        Some(idx) if !currency.decimal_separator.is_empty() => (
+            &value[..idx],
+            Some(&value[idx + currency.decimal_separator.len()..]),
+        ),
+        _ => (value, None),
+    };
+
+    let whole_digits = parse_whole(whole, &currency.thousands_separator).ok_or_else(invalid)?;
+
+    let minor_units = currency.minor_units as usize;
+    let fraction_digits = match fraction {
+        None => String::new(),
+        Some(fraction) => {
+            if fraction.is_empty()
+                || fraction.len() > minor_units
+                || !fraction.chars().all(|c| c.is_ascii_digit())
+            {
+                return Err(invalid());
+            }
+            fraction.to_string()
+        }
+    };
+
+    if whole_digits.is_empty() && fraction.is_none() {
+        return Err(invalid());
+    }
+
+    // Pad the fraction out to the full number of minor units, so that the
+    // amount is just the digits of both parts.
+    let digits = format!(
+        "{}{:0<width$}",
+        whole_digits,
+        fraction_digits,
+        width = minor_units
+    );
+    let digits = digits.trim_start_matches('0');
+    let amount: i64 = if digits.is_empty() {
+        0
+    } else {
+        digits.parse().map_err(|_| too_large())?
+    };
+
+    Ok(if negative { -amount } else { amount })
+}
+
+/// Split a leading `+` or `-` off the value.
+fn strip_sign(value: &str) -> (bool, &str) {
+    if let Some(rest) = value.strip_prefix('-') {
+        (true, rest)
+    } else if let Some(rest) = value.strip_prefix('+') {
+        (false, rest)
+    } else {
+        (false, value)
+    }
+}
+
+/// Check the whole part is digits, optionally with separators between groups
+/// of three, and return just the digits.
+fn parse_whole(whole: &str, separator: &str) -> Option<String> {
+    if separator.is_empty() || !whole.contains(separator) {
+        return if whole.chars().all(|c| c.is_ascii_digit()) {
+            Some(whole.to_string())
+        } else {
+            None
+        };
+    }
+
+    let mut groups = whole.split(separator);
+    let first = groups.next()?;
+    if first.is_empty() || first.len() > 3 || !first.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let mut digits = first.to_string();
+    for group in groups {
+        if group.len() != 3 || !group.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        digits.push_str(group);
+    }
+
+    Some(digits)
+}
+
+/// An amount in a request body: either a number already in minor units, or a
+/// string in the currency's notation to be parsed with [parse_amount].
+///
+/// Form fields are always strings, so amounts submitted by forms are always
+/// parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AmountInput {
+    Minor(i64),
+    Text(String),
+}
+
+impl AmountInput {
+    /// Get the amount in the given currency.
+    pub fn resolve(&self, currency: &CurrencySettings) -> Result<Money, AmountError> {
+        let amount = match self {
+            AmountInput::Minor(amount) => *amount,
+            AmountInput::Text(text) => parse_amount(text, currency)?,
+        };
+        Ok(Money::new(amount, currency.currency()))
+    }
+}
+
+impl From<i64> for AmountInput {
+    fn from(amount: i64) -> AmountInput {
+        AmountInput::Minor(amount)
+    }
+}
+
+impl Serialize for AmountInput {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            AmountInput::Minor(amount) => serializer.serialize_i64(*amount),
+            AmountInput::Text(text) => serializer.serialize_str(text),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for AmountInput {
+    fn deserialize<D>(deserializer: D) -> Result<AmountInput, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct AmountVisitor;
+
+        impl<'de> Visitor<'de> for AmountVisitor {
+            type Value = AmountInput;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("an integer amount in minor units, or a string amount")
+            }
+
+            fn visit_i64<E: de::Error>(self, value: i64) -> Result<AmountInput, E> {
+                Ok(AmountInput::Minor(value))
+            }
+
+            fn visit_u64<E: de::Error>(self, value: u64) -> Result<AmountInput, E> {
+                if value > i64::MAX as u64 {
+                    Err(E::custom(format!("Amount {} is too large", value)))
+                } else {
+                    Ok(AmountInput::Minor(value as i64))
+                }
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<AmountInput, E> {
+                Ok(AmountInput::Text(value.to_string()))
+            }
+        }
+
+        deserializer.deserialize_any(AmountVisitor)
+    }
+}
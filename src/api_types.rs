@@ -0,0 +1,129 @@
+//! The request and response bodies of the JSON API.
+//!
+//! These are used by both the server's handlers and the
+//! [client](crate::client), so the two can't drift apart. To keep older
+//! clients working with newer servers and vice versa, unknown fields are
+//! ignored, fields are only ever added, and added fields have defaults.
+//!
+//! Amounts in responses are in the minor unit of the instance's currency, e.g.
+//! pence.
+
+use serde::{Deserialize, Serialize};
+
+use crate::amount::AmountInput;
+use crate::db::{self, TransactionKind};
+
+/// The body of a incoming request shaft the given user.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShaftUserBody {
+    /// The other party in the transaction.
+    pub other_user: String,
+    /// The amount in pence owed. Positive means shafter is owed money by other
+    /// user, negative means shafer owes money. May also be given as a string
+    /// in the configured currency's notation, e.g. `"12.50"`.
+    pub amount: AmountInput,
+    /// The human readable description of the transasction. May be left out
+    /// if reasons aren't required.
+    #[serde(default)]
+    pub reason: String,
+}
+
+/// The response to creating a transaction.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShaftUserResponse {
+    pub transaction_id: i64,
+}
+
+/// The response to creating transactions in bulk.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BulkShaftResponse {
+    /// Whether the transactions were created. Either all are or none are.
+    pub committed: bool,
+    /// The result for each transaction in the request, in order.
+    #[serde(default)]
+    pub results: Vec<BulkShaftResult>,
+}
+
+/// The result of one transaction in a bulk request.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BulkShaftResult {
+    /// The ID of the new transaction, if they were committed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transaction_id: Option<i64>,
+    /// Why the transaction is invalid, if it is.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// A user's balance.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserBalance {
+    pub user_id: String,
+    pub display_name: String,
+    /// Positive means they are owed money.
+    pub balance: i64,
+}
+
+impl From<&db::User> for UserBalance {
+    fn from(user: &db::User) -> UserBalance {
+        UserBalance {
+            user_id: user.user_id.clone(),
+            display_name: user.display_name.clone(),
+            balance: user.balance.minor_units(),
+        }
+    }
+}
+
+/// The response of `/api/v1/balances`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BalancesResponse {
+    /// Most in debt first.
+    pub users: Vec<UserBalance>,
+}
+
+/// A stored transaction.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransactionEntry {
+    pub id: i64,
+    /// The user who created the transaction.
+    pub shafter: String,
+    /// The other party in the transaction.
+    pub shaftee: String,
+    /// Positive means shafter is owed the amount.
+    pub amount: i64,
+    /// When the transaction happened, in seconds since the epoch.
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub datetime: chrono::DateTime<chrono::Utc>,
+    pub reason: String,
+    #[serde(default = "default_transaction_kind")]
+    pub kind: TransactionKind,
+}
+
+fn default_transaction_kind() -> TransactionKind {
+    TransactionKind::Shaft
+}
+
+impl From<db::Transaction> for TransactionEntry {
+    /// Convert a stored transaction, i.e. one with its ID set.
+    fn from(transaction: db::Transaction) -> TransactionEntry {
+        TransactionEntry {
+            id: transaction.id.unwrap_or_default(),
+            shafter: transaction.shafter,
+            shaftee: transaction.shaftee,
+            amount: transaction.amount.minor_units(),
+            datetime: transaction.datetime,
+            reason: transaction.reason,
+            kind: transaction.kind,
+        }
+    }
+}
+
+/// The body of error responses.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    /// What went wrong, for programs, e.g. `NotFound` or `InvalidAmount`.
+    #[serde(default)]
+    pub kind: String,
+    /// What went wrong, for people.
+    pub error: String,
+}
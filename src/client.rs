@@ -0,0 +1,160 @@
+//! A client for the shaft JSON API, for bots, scripts and other tools.
+//!
+//! Requires building with the `client` feature. Requests are authenticated
+//! with an API token, created at `/api/tokens` or on the tokens page, e.g.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), shaft::client::ClientError> {
+//! use shaft::amount::AmountInput;
+//! use shaft::api_types::ShaftUserBody;
+//! use shaft::client::Client;
+//!
+//! let client = Client::new("https://shaft.example.com", "<token>")?;
+//!
+//! for user in client.balances().await? {
+//!     println!("{}: {}", user.display_name, user.balance);
+//! }
+//!
+//! client
+//!     .shaft(&ShaftUserBody {
+//!         other_user: "bob".to_string(),
+//!         amount: AmountInput::Text("12.50".to_string()),
+//!         reason: "Lunch".to_string(),
+//!     })
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Requests and responses use the types in [api_types](crate::api_types).
+
+use reqwest::header::AUTHORIZATION;
+use reqwest::{RequestBuilder, StatusCode};
+use serde::de::DeserializeOwned;
+use snafu::{ResultExt, Snafu};
+use url::Url;
+
+use crate::api_types::{
+    BalancesResponse, ErrorResponse, ShaftUserBody, ShaftUserResponse, TransactionEntry,
+    UserBalance,
+};
+
+/// Why a request to the API failed.
+#[derive(Debug, Snafu)]
+pub enum ClientError {
+    #[snafu(display("Invalid URL: {}", source))]
+    InvalidUrl { source: url::ParseError },
+
+    #[snafu(display("Request failed: {}", source))]
+    RequestError { source: reqwest::Error },
+
+    /// The server rejected the request, e.g. because the token is invalid or
+    /// the other user doesn't exist. The `kind` is that of the server's
+    /// [ErrorResponse], or empty if it didn't send one.
+    #[snafu(display("Got {} response: {}", status, message))]
+    ApiError {
+        status: StatusCode,
+        kind: String,
+        message: String,
+    },
+}
+
+/// A client for one shaft instance, authenticated with an API token.
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    /// The URL the API paths are relative to, ending in a slash.
+    base_url: Url,
+    token: String,
+}
+
+impl Client {
+    /// Create a client for the instance at `base_url`, e.g.
+    /// `https://shaft.example.com`, including any `web_root`.
+    pub fn new(base_url: &str, token: impl Into<String>) -> Result<Client, ClientError> {
+        Client::with_http_client(reqwest::Client::new(), base_url, token)
+    }
+
+    /// Create a client that makes requests with the given HTTP client, e.g.
+    /// one with a timeout or proxy configured.
+    pub fn with_http_client(
+        http: reqwest::Client,
+        base_url: &str,
+        token: impl Into<String>,
+    ) -> Result<Client, ClientError> {
+        let mut base_url = Url::parse(base_url).context(InvalidUrl)?;
+        if !base_url.path().ends_with('/') {
+            let path = format!("{}/", base_url.path());
+            base_url.set_path(&path);
+        }
+
+        Ok(Client {
+            http,
+            base_url,
+            token: token.into(),
+        })
+    }
+
+    /// Use the named ledger rather than the default one.
+    pub fn for_ledger(&self, ledger: &str) -> Result<Client, ClientError> {
+        let base_url = self
+            .base_url
+            .join(&format!("l/{}/", ledger))
+            .context(InvalidUrl)?;
+
+        Ok(Client {
+            base_url,
+            ..self.clone()
+        })
+    }
+
+    /// Get everyone's balances, most in debt first.
+    pub async fn balances(&self) -> Result<Vec<UserBalance>, ClientError> {
+        let response: BalancesResponse = self.send(self.get("api/v1/balances")?).await?;
+        Ok(response.users)
+    }
+
+    /// Get the most recent transactions, newest first.
+    pub async fn transactions(&self) -> Result<Vec<TransactionEntry>, ClientError> {
+        self.send(self.get("api/transactions")?).await
+    }
+
+    /// Create a transaction between the token's user and another, returning
+    /// its ID.
+    pub async fn shaft(&self, body: &ShaftUserBody) -> Result<i64, ClientError> {
+        let url = self.base_url.join("api/shaft").context(InvalidUrl)?;
+        let response: ShaftUserResponse = self.send(self.http.post(url).json(body)).await?;
+        Ok(response.transaction_id)
+    }
+
+    fn get(&self, path: &str) -> Result<RequestBuilder, ClientError> {
+        let url = self.base_url.join(path).context(InvalidUrl)?;
+        Ok(self.http.get(url))
+    }
+
+    /// Send the request with the token, parsing a successful response as
+    /// JSON.
+    async fn send<T: DeserializeOwned>(&self, request: RequestBuilder) -> Result<T, ClientError> {
+        let response = request
+            .header(AUTHORIZATION, format!("Bearer {}", self.token))
+            .send()
+            .await
+            .context(RequestError)?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            let error = serde_json::from_str(&body).unwrap_or(ErrorResponse {
+                kind: String::new(),
+                error: body,
+            });
+            return Err(ClientError::ApiError {
+                status,
+                kind: error.kind,
+                message: error.error,
+            });
+        }
+
+        response.json().await.context(RequestError)
+    }
+}
@@ -0,0 +1,295 @@
+//! Bundling an instance's configuration, to move it to another host.
+//!
+//! `shaft admin config export` writes the settings from the config files,
+//! minus secrets, and the state of each feature as a single TOML or JSON
+//! bundle. `shaft admin config import` turns a bundle back into a settings
+//! file, taking the secrets from the new host's own config.
+//!
+//! Settings given by environment variables aren't included, as they tend to
+//! be specific to a host. Features changed through the admin API aren't
+//! persisted, so the bundle has their configured state. Inbound hooks are
+//! secret URLs belonging to users, so they aren't included either.
+
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, ResultExt, Snafu};
+use toml::value::{Table, Value};
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::features::{Feature, FeatureFlags};
+use crate::settings::Settings;
+
+/// The version of the bundle format written by [ConfigBundle::new].
+pub const BUNDLE_VERSION: u32 = 1;
+
+/// The settings left out of bundles, as dotted paths. A key ending in `[]`
+/// is an array of tables, each of which has the rest of the path removed.
+pub const SECRET_SETTINGS: &[&str] = &[
+    "github.client_secret",
+    "github.state",
+    "slack.signing_secret",
+    "invites.signing_secret",
+    "error_reporting.sentry_dsn",
+    // May include a password, e.g. `redis://:password@host`.
+    "redis.url",
+    "webhooks[].secret",
+    "tenants[].github.client_secret",
+    "tenants[].github.state",
+    "tenants[].webhooks[].secret",
+];
+
+/// Error reading or applying a bundle.
+#[derive(Debug, Snafu)]
+pub enum BundleError {
+    #[snafu(display("Failed to parse TOML bundle: {}", source))]
+    ParseToml { source: toml::de::Error },
+
+    #[snafu(display("Failed to parse JSON bundle: {}", source))]
+    ParseJson { source: serde_json::Error },
+
+    #[snafu(display("Failed to write TOML bundle: {}", source))]
+    WriteToml { source: toml::ser::Error },
+
+    #[snafu(display("Failed to write JSON bundle: {}", source))]
+    WriteJson { source: serde_json::Error },
+
+    #[snafu(display("Unsupported bundle version {}", version))]
+    UnsupportedVersion { version: u32 },
+
+    #[snafu(display("Unknown feature {}", name))]
+    UnknownFeature { name: String },
+
+    #[snafu(display("Bundle doesn't give valid settings: {}", source))]
+    InvalidSettings { source: toml::de::Error },
+}
+
+/// How a bundle is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BundleFormat {
+    Toml,
+    Json,
+}
+
+impl BundleFormat {
+    /// JSON if the path ends in `.json`, otherwise TOML.
+    pub fn from_path(path: &Path) -> BundleFormat {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("json") => BundleFormat::Json,
+            _ => BundleFormat::Toml,
+        }
+    }
+}
+
+/// An instance's configuration without its secrets.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConfigBundle {
+    pub version: u32,
+    /// The secret settings that were left out, as dotted paths with array
+    /// indices, e.g. `webhooks.0.secret`.
+    #[serde(default)]
+    pub redacted: Vec<String>,
+    /// Whether each feature was enabled, by name.
+    #[serde(default)]
+    pub features: BTreeMap<String, bool>,
+    /// The settings, as they appear in a config file.
+    pub settings: Table,
+}
+
+/// A settings file made from a bundle.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportedSettings {
+    pub settings: Table,
+    /// Secrets that were left out of the bundle and aren't in the host's
+    /// config, so must be filled in before the settings can be used.
+    pub missing_secrets: Vec<String>,
+}
+
+impl ConfigBundle {
+    /// Bundle the settings, leaving out the secrets.
+    pub fn new(mut settings: Table, features: &FeatureFlags) -> ConfigBundle {
+        let mut redacted = Vec::new();
+        for pattern in SECRET_SETTINGS {
+            remove_matching(&mut settings, pattern, "", &mut redacted);
+        }
+
+        let features = features
+            .snapshot()
+            .into_iter()
+            .map(|(feature, enabled)| (feature.as_str().to_string(), enabled))
+            .collect();
+
+        ConfigBundle {
+            version: BUNDLE_VERSION,
+            redacted,
+            features,
+            settings,
+        }
+    }
+
+    /// Read a bundle written by [ConfigBundle::write].
+    pub fn parse(text: &str, format: BundleFormat) -> Result<ConfigBundle, BundleError> {
+        let bundle: ConfigBundle = match format {
+            BundleFormat::Toml => toml::from_str(text).context(ParseToml)?,
+            BundleFormat::Json => serde_json::from_str(text).context(ParseJson)?,
+        };
+
+        ensure!(
+            bundle.version == BUNDLE_VERSION,
+            UnsupportedVersion {
+                version: bundle.version
+            }
+        );
+
+        Ok(bundle)
+    }
+
+    pub fn write(&self, format: BundleFormat) -> Result<String, BundleError> {
+        match format {
+            // TOML needs a table's plain values before its subtables, which
+            // the settings don't keep to, so go via a `Value` which sorts
+            // them.
+            BundleFormat::Toml => Value::try_from(self)
+                .and_then(|value| toml::to_string_pretty(&value))
+                .context(WriteToml),
+            BundleFormat::Json => serde_json::to_string_pretty(self).context(WriteJson),
+        }
+    }
+
+    /// Turn the bundle into settings for a host, taking the secrets left out
+    /// of the bundle from the host's config.
+    ///
+    /// If every secret was found, the settings are checked to be valid.
+    pub fn into_settings(self, host: &Table) -> Result<ImportedSettings, BundleError> {
+        let mut settings = self.settings;
+
+        let mut features = Table::new();
+        for (name, enabled) in self.features {
+            ensure!(
+                Feature::ALL.iter().any(|feature| feature.as_str() == name),
+                UnknownFeature { name }
+            );
+            features.insert(name, Value::Boolean(enabled));
+        }
+        if !features.is_empty() {
+            settings.insert("features".to_string(), Value::Table(features));
+            // Folded into `features.public_read` by the bundle.
+            settings.remove("public_read");
+        }
+
+        let mut missing_secrets = Vec::new();
+        for path in self.redacted {
+            match get_path(host, &path) {
+                Some(value) => set_path(&mut settings, &path, value.clone()),
+                None => missing_secrets.push(path),
+            }
+        }
+
+        if missing_secrets.is_empty() {
+            Value::Table(settings.clone())
+                .try_into::<Settings>()
+                .context(InvalidSettings)?;
+        }
+
+        Ok(ImportedSettings {
+            settings,
+            missing_secrets,
+        })
+    }
+}
+
+/// Look up a dotted path in nested tables, where keys within arrays are
+/// indices.
+fn get_path<'a>(table: &'a Table, path: &str) -> Option<&'a Value> {
+    let mut keys = path.split('.');
+    let mut value = table.get(keys.next()?)?;
+    for key in keys {
+        value = match value {
+            Value::Table(table) => table.get(key)?,
+            Value::Array(items) => items.get(key.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+    Some(value)
+}
+
+/// Set a dotted path in nested tables, creating tables as needed. Keys
+/// within arrays are indices, and the value isn't set if the array doesn't
+/// have the element.
+fn set_path(table: &mut Table, path: &str, new_value: Value) {
+    let keys: Vec<&str> = path.split('.').collect();
+    let (first, rest) = keys.split_first().expect("split gives at least one key");
+    let value = table
+        .entry(first.to_string())
+        .or_insert_with(|| Value::Table(Table::new()));
+    set_value(value, rest, new_value);
+}
+
+fn set_value(value: &mut Value, keys: &[&str], new_value: Value) {
+    let (key, rest) = match keys.split_first() {
+        Some(split) => split,
+        None => {
+            *value = new_value;
+            return;
+        }
+    };
+
+    if let Value::Array(items) = value {
+        if let Some(item) = key.parse::<usize>().ok().and_then(|idx| items.get_mut(idx)) {
+            set_value(item, rest, new_value);
+        }
+        return;
+    }
+
+    if !value.is_table() {
+        *value = Value::Table(Table::new());
+    }
+    let child = value
+        .as_table_mut()
+        .expect("just made a table")
+        .entry(key.to_string())
+        .or_insert_with(|| Value::Table(Table::new()));
+    set_value(child, rest, new_value);
+}
+
+/// Remove the settings matching a pattern from [SECRET_SETTINGS], adding
+/// the dotted path of each one removed to `removed`. `prefix` is the path to
+/// `table`.
+fn remove_matching(table: &mut Table, pattern: &str, prefix: &str, removed: &mut Vec<String>) {
+    let (key, rest) = match pattern.split_once('.') {
+        Some((key, rest)) => (key, Some(rest)),
+        None => (pattern, None),
+    };
+    let (key, is_array) = match key.strip_suffix("[]") {
+        Some(key) => (key, true),
+        None => (key, false),
+    };
+    let path = if prefix.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", prefix, key)
+    };
+
+    let rest = match rest {
+        Some(rest) => rest,
+        None => {
+            if table.remove(key).is_some() {
+                removed.push(path);
+            }
+            return;
+        }
+    };
+
+    match table.get_mut(key) {
+        Some(Value::Table(inner)) if !is_array => remove_matching(inner, rest, &path, removed),
+        Some(Value::Array(items)) if is_array => {
+            for (idx, item) in items.iter_mut().enumerate() {
+                if let Value::Table(inner) = item {
+                    let path = format!("{}.{}", path, idx);
+                    remove_matching(inner, rest, &path, removed);
+                }
+            }
+        }
+        _ => {}
+    }
+}
@@ -0,0 +1,110 @@
+//! Policing what people write in reasons, e.g. to keep out profanity or
+//! emoji.
+//!
+//! Reasons are passed through the configured [ContentPolicy] after their
+//! length has been checked and before they're stored, so a policy can either
+//! reject a reason or change it, e.g. by masking words. By default everything
+//! is allowed; [DenylistPolicy] is configured from the `[content_policy]`
+//! settings.
+
+use snafu::Snafu;
+
+use crate::settings::{ContentPolicySettings, DenylistAction};
+
+/// Something that decides what content is allowed.
+pub trait ContentPolicy: Send + Sync {
+    /// Check some text, returning it as it should be stored.
+    fn check(&self, text: &str) -> Result<String, PolicyError>;
+}
+
+/// Why content was rejected.
+#[derive(Debug, Clone, PartialEq, Eq, Snafu)]
+pub enum PolicyError {
+    #[snafu(display("contains disallowed content"))]
+    Disallowed,
+}
+
+/// The default [ContentPolicy], which allows everything unchanged.
+#[derive(Debug, Clone, Default)]
+pub struct AllowAllPolicy;
+
+impl ContentPolicy for AllowAllPolicy {
+    fn check(&self, text: &str) -> Result<String, PolicyError> {
+        Ok(text.to_string())
+    }
+}
+
+/// A [ContentPolicy] that rejects or masks a list of words, phrases or
+/// emoji.
+///
+/// Entries are matched ignoring case. An entry that starts or ends with a
+/// letter or digit only matches whole words, so `ass` doesn't match `class`.
+#[derive(Debug, Clone)]
+pub struct DenylistPolicy {
+    /// The lowercased entries, as characters.
+    denylist: Vec<Vec<char>>,
+    action: DenylistAction,
+}
+
+impl DenylistPolicy {
+    pub fn new(settings: &ContentPolicySettings) -> DenylistPolicy {
+        DenylistPolicy {
+            denylist: settings
+                .denylist
+                .iter()
+                .map(|entry| lowercase_chars(entry.trim()))
+                .filter(|entry| !entry.is_empty())
+                .collect(),
+            action: settings.action,
+        }
+    }
+
+    /// Whether the entry appears in the text at the given character index.
+    fn matches_at(text: &[char], idx: usize, entry: &[char]) -> bool {
+        let end = idx + entry.len();
+        if end > text.len() || text[idx..end] != *entry {
+            return false;
+        }
+
+        let starts_word =
+            !entry[0].is_alphanumeric() || idx == 0 || !text[idx - 1].is_alphanumeric();
+        let ends_word = !entry[entry.len() - 1].is_alphanumeric()
+            || end == text.len()
+            || !text[end].is_alphanumeric();
+
+        starts_word && ends_word
+    }
+}
+
+impl ContentPolicy for DenylistPolicy {
+    fn check(&self, text: &str) -> Result<String, PolicyError> {
+        let lowered = lowercase_chars(text);
+        let mut masked: Vec<char> = text.chars().collect();
+        let mut found = false;
+
+        for idx in 0..lowered.len() {
+            for entry in &self.denylist {
+                if Self::matches_at(&lowered, idx, entry) {
+                    found = true;
+                    for c in &mut masked[idx..idx + entry.len()] {
+                        *c = '*';
+                    }
+                }
+            }
+        }
+
+        match (found, self.action) {
+            (false, _) => Ok(text.to_string()),
+            (true, DenylistAction::Reject) => Err(PolicyError::Disallowed),
+            (true, DenylistAction::Mask) => Ok(masked.into_iter().collect()),
+        }
+    }
+}
+
+/// Lowercase each character of the text, keeping a character for character
+/// correspondence with the original so matches can be masked.
+fn lowercase_chars(text: &str) -> Vec<char> {
+    text.chars()
+        .map(|c| c.to_lowercase().next().unwrap_or(c))
+        .collect()
+}
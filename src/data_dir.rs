@@ -0,0 +1,197 @@
+//! Running from a single data directory, e.g. a Docker volume, without any
+//! config files.
+//!
+//! `shaft --data-dir <dir>` keeps the database in the directory and reads
+//! `settings.toml` from it, writing a demo config there on first run with a
+//! freshly generated secret. GitHub login isn't configured by the demo config,
+//! so while the instance has no other users an admin user is given a token
+//! that expires after a day, written to [BOOTSTRAP_TOKEN_FILE] for the
+//! operator to use. Alternatively, the operator can configure GitHub login and
+//! the first admin through the `/setup` page, whose settings are saved to
+//! [SETUP_FILE]. Finishing setup deactivates the bootstrap admin, revoking
+//! their token.
+
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::db::{Database, DatabaseError, Outbox, Scope, DEFAULT_LEDGER_ID};
+use crate::settings::GithubSettings;
+
+/// The settings file in the data directory.
+pub const SETTINGS_FILE: &str = "settings.toml";
+
+/// The settings chosen through the setup page, which override
+/// [SETTINGS_FILE].
+pub const SETUP_FILE: &str = "setup.toml";
+
+/// The database file in the data directory.
+pub const DATABASE_FILE: &str = "shaft.db";
+
+/// The file in the data directory holding the bootstrap admin's token.
+pub const BOOTSTRAP_TOKEN_FILE: &str = "bootstrap_token";
+
+/// How long the bootstrap admin's token works for. A new one is issued each
+/// time shaft starts until setup is done.
+const BOOTSTRAP_TOKEN_LIFETIME_HOURS: i64 = 24;
+
+/// The user created to administer a new instance. Underscores aren't allowed
+/// in GitHub logins, so this can't clash with someone logging in.
+pub const BOOTSTRAP_ADMIN: &str = "local_admin";
+
+/// A directory holding everything an instance needs.
+#[derive(Debug, Clone)]
+pub struct DataDir {
+    path: PathBuf,
+}
+
+impl DataDir {
+    pub fn new(path: impl Into<PathBuf>) -> DataDir {
+        DataDir { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn settings_file(&self) -> PathBuf {
+        self.path.join(SETTINGS_FILE)
+    }
+
+    pub fn setup_file(&self) -> PathBuf {
+        self.path.join(SETUP_FILE)
+    }
+
+    pub fn database_file(&self) -> PathBuf {
+        self.path.join(DATABASE_FILE)
+    }
+
+    pub fn bootstrap_token_file(&self) -> PathBuf {
+        self.path.join(BOOTSTRAP_TOKEN_FILE)
+    }
+
+    /// Write the bootstrap admin's token to its file, readable only by us,
+    /// replacing any older token.
+    pub fn write_bootstrap_token(&self, token: &str) -> io::Result<PathBuf> {
+        let path = self.bootstrap_token_file();
+        self.remove_bootstrap_token()?;
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options.open(&path)?.write_all(token.as_bytes())?;
+
+        Ok(path)
+    }
+
+    /// Delete the bootstrap admin's token file, if there is one.
+    pub fn remove_bootstrap_token(&self) -> io::Result<()> {
+        match fs::remove_file(self.bootstrap_token_file()) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Create the directory and a demo settings file, unless they already
+    /// exist. Returns whether the settings file was created.
+    pub fn init(&self) -> io::Result<bool> {
+        fs::create_dir_all(&self.path)?;
+
+        let settings_file = self.settings_file();
+        if settings_file.exists() {
+            return Ok(false);
+        }
+
+        fs::write(&settings_file, self.demo_settings())?;
+        Ok(true)
+    }
+
+    /// A config that runs the instance from the directory without GitHub
+    /// login, with a new random secret.
+    pub fn demo_settings(&self) -> String {
+        let state: String = thread_rng().sample_iter(&Alphanumeric).take(32).collect();
+
+        format!(
+            r#"# Generated by `shaft --data-dir` on first run. Edit as needed, see
+# settings-example.toml for what can be configured.
+
+bind = "0.0.0.0:8975"
+database_file = {database_file}
+admins = [{admin}]
+
+# GitHub login isn't configured, so the instance is used through the API with
+# the token in the bootstrap_token file. Register a GitHub OAuth app and fill
+# in its details to let people log in.
+[github]
+client_id = ""
+client_secret = ""
+state = {state}
+required_org = ""
+"#,
+            database_file = toml_string(self.database_file().display().to_string()),
+            admin = toml_string(BOOTSTRAP_ADMIN.to_string()),
+            state = toml_string(state),
+        )
+    }
+}
+
+/// Quote a string for a TOML file.
+fn toml_string(value: String) -> String {
+    toml::Value::String(value).to_string()
+}
+
+/// If the instance has no users other than the bootstrap admin, create them
+/// if needed and give them a new token granting every scope, returning the
+/// token. The token expires after a day.
+pub async fn bootstrap_admin(database: &dyn Database) -> Result<Option<String>, DatabaseError> {
+    let users = database.get_all_users(DEFAULT_LEDGER_ID).await?;
+    if users.keys().any(|user_id| user_id != BOOTSTRAP_ADMIN) {
+        return Ok(None);
+    }
+
+    let user_id = match database
+        .get_user_by_github_id(BOOTSTRAP_ADMIN.to_string())
+        .await?
+    {
+        Some(user_id) => user_id,
+        None => {
+            database
+                .add_user_by_github_id(
+                    BOOTSTRAP_ADMIN.to_string(),
+                    "Admin".to_string(),
+                    Outbox::none(),
+                )
+                .await?
+        }
+    };
+
+    let expires = chrono::Utc::now() + chrono::Duration::hours(BOOTSTRAP_TOKEN_LIFETIME_HOURS);
+    let token = database
+        .create_token_for_user(
+            user_id,
+            vec![Scope::Read, Scope::Write, Scope::Admin],
+            expires,
+            Outbox::none(),
+        )
+        .await?;
+
+    Ok(Some(token))
+}
+
+/// Whether the instance still needs setting up: GitHub login isn't configured
+/// and nobody but the bootstrap admin exists.
+pub async fn needs_setup(
+    database: &dyn Database,
+    github: &GithubSettings,
+) -> Result<bool, DatabaseError> {
+    if !github.client_id.is_empty() {
+        return Ok(false);
+    }
+
+    let users = database.get_all_users(DEFAULT_LEDGER_ID).await?;
+    Ok(users.keys().all(|user_id| user_id == BOOTSTRAP_ADMIN))
+}
@@ -0,0 +1,264 @@
+//! Makes anonymised copies of the database, so that they can be shared to
+//! help reproduce bugs.
+
+use rand::seq::SliceRandom;
+use rand::{thread_rng, Rng};
+use rusqlite::{params, Connection, OpenFlags};
+use snafu::{ensure, ResultExt, Snafu};
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::db::migrations::SQLITE_MIGRATIONS;
+
+/// Names given to users in the copy. Users beyond the number of combinations
+/// get numbered names.
+const FIRST_NAMES: &[&str] = &[
+    "Alex", "Billie", "Charlie", "Dana", "Eden", "Frankie", "Gray", "Harper", "Indigo", "Jesse",
+    "Kai", "Logan", "Morgan", "Noel", "Oakley", "Parker", "Quinn", "Riley", "Sam", "Taylor",
+];
+const SURNAMES: &[&str] = &[
+    "Archer", "Baker", "Carter", "Dyer", "Fisher", "Mason", "Porter", "Potter", "Smith", "Turner",
+];
+
+/// Reasons given to transactions in the copy, and to seeded transactions.
+pub(crate) const REASONS: &[&str] = &[
+    "Coffee",
+    "Lunch",
+    "Dinner",
+    "Drinks",
+    "Groceries",
+    "Taxi",
+    "Train tickets",
+    "Cinema",
+    "Takeaway",
+    "Snacks",
+    "Birthday present",
+    "Office supplies",
+];
+
+/// Error making an anonymised copy.
+#[derive(Debug, Snafu)]
+pub enum AnonymiseError {
+    /// We won't overwrite an existing file.
+    #[snafu(display("{} already exists", path.display()))]
+    OutputExists { path: PathBuf },
+
+    /// The source database needs migrating, or is from a newer version.
+    #[snafu(display(
+        "Database schema is version {}, expected {}. Start shaft against it to migrate it first.",
+        found,
+        expected
+    ))]
+    SchemaVersion { found: i64, expected: i64 },
+
+    #[snafu(display("Sqlite error: {}", source))]
+    Sqlite { source: rusqlite::Error },
+}
+
+/// What was changed in an anonymised copy.
+#[derive(Debug, Clone, Default)]
+pub struct AnonymiseSummary {
+    /// Number of users renamed.
+    pub users: usize,
+    /// Number of transactions rewritten.
+    pub transactions: usize,
+    /// The factor every amount was scaled by.
+    pub amount_scale: f64,
+}
+
+/// Write an anonymised copy of the database at `source` to `dest`.
+///
+/// Users get fake IDs and names, transactions get fake reasons, and every
+/// amount is scaled by the same random factor so that who owes whom, and
+/// roughly how much, is unchanged. Ledgers other than the default are renamed.
+/// Tokens, Slack links, inbound hooks, attachments and audit log details are
+/// dropped. The source database is only read from.
+pub fn anonymise_database(source: &Path, dest: &Path) -> Result<AnonymiseSummary, AnonymiseError> {
+    ensure!(
+        !dest.exists(),
+        OutputExists {
+            path: dest.to_path_buf()
+        }
+    );
+
+    let source_conn =
+        Connection::open_with_flags(source, OpenFlags::SQLITE_OPEN_READ_ONLY).context(Sqlite)?;
+
+    let found: i64 = source_conn
+        .query_row("PRAGMA user_version", params![], |row| row.get(0))
+        .context(Sqlite)?;
+    let expected = SQLITE_MIGRATIONS.len() as i64;
+    ensure!(found == expected, SchemaVersion { found, expected });
+
+    // Takes a consistent snapshot, even if shaft is using the database.
+    source_conn
+        .execute(
+            "VACUUM INTO $1",
+            params![dest.to_string_lossy().into_owned()],
+        )
+        .context(Sqlite)?;
+    drop(source_conn);
+
+    let mut conn = Connection::open(dest).context(Sqlite)?;
+    let summary = rewrite(&mut conn)?;
+
+    // Make sure nothing of the original data is left in free pages.
+    conn.execute_batch("VACUUM").context(Sqlite)?;
+
+    Ok(summary)
+}
+
+/// Anonymise the copy in place.
+fn rewrite(conn: &mut Connection) -> Result<AnonymiseSummary, AnonymiseError> {
+    let mut rng = thread_rng();
+    let txn = conn.transaction().context(Sqlite)?;
+
+    // Include anyone in a transaction, in case they're missing from `users`.
+    let user_ids: Vec<String> = txn
+        .prepare(
+            "SELECT user_id FROM users
+            UNION SELECT shafter FROM transactions
+            UNION SELECT shaftee FROM transactions",
+        )
+        .context(Sqlite)?
+        .query_map(params![], |row| row.get(0))
+        .context(Sqlite)?
+        .collect::<Result<_, _>>()
+        .context(Sqlite)?;
+
+    let mut order: Vec<usize> = (0..user_ids.len()).collect();
+    order.shuffle(&mut rng);
+
+    let renames: BTreeMap<String, (String, String)> = user_ids
+        .into_iter()
+        .zip(order)
+        .map(|(user_id, idx)| (user_id, (format!("user{}", idx + 1), fake_name(idx))))
+        .collect();
+
+    // Renames are done a table at a time using a lookup table, so that a
+    // user renamed to an ID someone else had doesn't get renamed twice.
+    txn.execute_batch(
+        "CREATE TEMP TABLE renames (old_id TEXT PRIMARY KEY, new_id TEXT NOT NULL, name TEXT NOT NULL);",
+    )
+    .context(Sqlite)?;
+    for (user_id, (new_id, name)) in &renames {
+        txn.execute(
+            "INSERT INTO renames (old_id, new_id, name) VALUES ($1, $2, $3)",
+            params![user_id, new_id, name],
+        )
+        .context(Sqlite)?;
+    }
+
+    // `users`, `github_users` and `ledger_members` are keyed by user ID, so
+    // are rebuilt rather than updated to avoid clashes part way through. The
+    // change log is renamed first, as the triggers on `users` and
+    // `transactions` add changes with the new IDs.
+    txn.execute_batch(
+        "UPDATE changes
+            SET user_id = (SELECT new_id FROM renames WHERE old_id = user_id)
+            WHERE user_id IS NOT NULL;
+
+        CREATE TEMP TABLE new_users AS
+            SELECT new_id, name, active FROM users JOIN renames ON users.user_id = renames.old_id;
+        DELETE FROM users;
+        INSERT INTO users (user_id, display_name, active) SELECT new_id, name, active FROM new_users;
+
+        CREATE TEMP TABLE new_github_users AS
+            SELECT DISTINCT new_id FROM github_users
+            JOIN renames ON github_users.user_id = renames.old_id;
+        DELETE FROM github_users;
+        INSERT INTO github_users (user_id, github_id) SELECT new_id, new_id FROM new_github_users;
+
+        CREATE TEMP TABLE new_ledger_members AS
+            SELECT ledger_id, new_id FROM ledger_members
+            JOIN renames ON ledger_members.user_id = renames.old_id;
+        DELETE FROM ledger_members;
+        INSERT INTO ledger_members (ledger_id, user_id)
+            SELECT ledger_id, new_id FROM new_ledger_members;
+
+        UPDATE transactions
+            SET shafter = (SELECT new_id FROM renames WHERE old_id = shafter),
+                shaftee = (SELECT new_id FROM renames WHERE old_id = shaftee);
+        UPDATE audit_log
+            SET actor = COALESCE((SELECT new_id FROM renames WHERE old_id = actor), 'unknown');
+
+        DROP TABLE renames;
+        DROP TABLE new_users;
+        DROP TABLE new_github_users;
+        DROP TABLE new_ledger_members;",
+    )
+    .context(Sqlite)?;
+
+    let amount_scale = rng.gen_range(0.5, 2.0);
+
+    let transactions: Vec<(i64, i64)> = txn
+        .prepare("SELECT id, amount FROM transactions")
+        .context(Sqlite)?
+        .query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))
+        .context(Sqlite)?
+        .collect::<Result<_, _>>()
+        .context(Sqlite)?;
+
+    for (id, amount) in &transactions {
+        let reason = REASONS.choose(&mut rng).expect("reasons not empty");
+        txn.execute(
+            "UPDATE transactions SET amount = $1, reason = $2 WHERE id = $3",
+            params![scale_amount(*amount, amount_scale), reason, id],
+        )
+        .context(Sqlite)?;
+    }
+
+    txn.execute_batch(
+        "DELETE FROM tokens;
+        DELETE FROM slack_users;
+        DELETE FROM inbound_hooks;
+        DELETE FROM receipt_suggestions;
+        DELETE FROM attachments;
+        DELETE FROM user_preferences;
+        DELETE FROM shared_values;
+        DELETE FROM invalidations;
+        DELETE FROM outbox;
+        DELETE FROM outbox_attempts;
+        DELETE FROM security_events;
+        UPDATE audit_log SET target = NULL, details = NULL;
+        UPDATE ledgers SET name = 'ledger' || id, display_name = 'Ledger ' || id WHERE id != 1;
+        DELETE FROM last_modified;",
+    )
+    .context(Sqlite)?;
+
+    txn.commit().context(Sqlite)?;
+
+    Ok(AnonymiseSummary {
+        users: renames.len(),
+        transactions: transactions.len(),
+        amount_scale,
+    })
+}
+
+/// The fake display name for the `idx`th user.
+pub(crate) fn fake_name(idx: usize) -> String {
+    let first = FIRST_NAMES[idx % FIRST_NAMES.len()];
+    let surname = SURNAMES[(idx / FIRST_NAMES.len()) % SURNAMES.len()];
+    let round = idx / (FIRST_NAMES.len() * SURNAMES.len());
+
+    if round == 0 {
+        format!("{} {}", first, surname)
+    } else {
+        format!("{} {} {}", first, surname, round + 1)
+    }
+}
+
+/// Scale the amount, keeping its sign and never rounding it to zero.
+fn scale_amount(amount: i64, scale: f64) -> i64 {
+    if amount == 0 {
+        return 0;
+    }
+
+    let scaled = (amount as f64 * scale).round() as i64;
+    if scaled == 0 {
+        amount.signum()
+    } else {
+        scaled
+    }
+}
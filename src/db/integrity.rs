@@ -0,0 +1,98 @@
+//! Checks that rows only reference users that exist, shared by the database
+//! backends.
+
+use serde::Serialize;
+
+/// A kind of row that references a user that doesn't exist.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IntegrityProblemKind {
+    /// Transactions where the shafter or shaftee is unknown. Repaired by
+    /// adding a deactivated user to the transactions' ledgers, so that
+    /// balances still add up.
+    TransactionUnknownUser,
+    /// Tokens for an unknown user. Repaired by deleting them.
+    OrphanedToken,
+    /// GitHub logins for an unknown user. Repaired by deleting them, so that
+    /// the next login creates the user afresh.
+    OrphanedGithubUser,
+}
+
+impl IntegrityProblemKind {
+    /// Human readable description of the problem.
+    pub fn description(self) -> &'static str {
+        match self {
+            IntegrityProblemKind::TransactionUnknownUser => "transactions reference unknown user",
+            IntegrityProblemKind::OrphanedToken => "tokens belong to unknown user",
+            IntegrityProblemKind::OrphanedGithubUser => "GitHub login belongs to unknown user",
+        }
+    }
+}
+
+/// Rows referencing a user that doesn't exist.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct IntegrityProblem {
+    pub kind: IntegrityProblemKind,
+    /// The unknown user.
+    pub user_id: String,
+    /// How many rows reference them.
+    pub count: i64,
+}
+
+/// For each kind of problem, a query returning the unknown users and how many
+/// rows reference each, and the statements that repair them, in order.
+pub(crate) const INTEGRITY_CHECKS: &[(IntegrityProblemKind, &str, &[&str])] = &[
+    (
+        IntegrityProblemKind::TransactionUnknownUser,
+        r#"
+        SELECT user_id, COUNT(*) FROM (
+            SELECT shafter AS user_id FROM transactions
+            UNION ALL
+            SELECT shaftee AS user_id FROM transactions
+        )
+        WHERE user_id NOT IN (SELECT user_id FROM users)
+        GROUP BY user_id
+        ORDER BY user_id
+        "#,
+        &[
+            r#"
+            INSERT OR IGNORE INTO ledger_members (ledger_id, user_id)
+            SELECT DISTINCT ledger_id, user_id FROM (
+                SELECT ledger_id, shafter AS user_id FROM transactions
+                UNION ALL
+                SELECT ledger_id, shaftee AS user_id FROM transactions
+            )
+            WHERE user_id NOT IN (SELECT user_id FROM users)
+            "#,
+            r#"
+            INSERT INTO users (user_id, display_name, active)
+            SELECT DISTINCT user_id, user_id, 0 FROM (
+                SELECT shafter AS user_id FROM transactions
+                UNION ALL
+                SELECT shaftee AS user_id FROM transactions
+            )
+            WHERE user_id NOT IN (SELECT user_id FROM users)
+            "#,
+        ],
+    ),
+    (
+        IntegrityProblemKind::OrphanedToken,
+        r#"
+        SELECT user_id, COUNT(*) FROM tokens
+        WHERE user_id NOT IN (SELECT user_id FROM users)
+        GROUP BY user_id
+        ORDER BY user_id
+        "#,
+        &["DELETE FROM tokens WHERE user_id NOT IN (SELECT user_id FROM users)"],
+    ),
+    (
+        IntegrityProblemKind::OrphanedGithubUser,
+        r#"
+        SELECT user_id, COUNT(*) FROM github_users
+        WHERE user_id NOT IN (SELECT user_id FROM users)
+        GROUP BY user_id
+        ORDER BY user_id
+        "#,
+        &["DELETE FROM github_users WHERE user_id NOT IN (SELECT user_id FROM users)"],
+    ),
+];
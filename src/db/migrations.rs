@@ -0,0 +1,379 @@
+//! Schema migrations for the sqlite database.
+//!
+//! The schema version is tracked in sqlite's `user_version` pragma, and each
+//! entry below upgrades the schema from the version equal to its index. New
+//! migrations must only ever be appended.
+
+/// The ordered list of migrations for the sqlite database.
+pub(crate) const SQLITE_MIGRATIONS: &[&str] = &[
+    // 1: The initial schema. Uses `IF NOT EXISTS` as databases created before
+    // we tracked schema versions will already have these tables.
+    r#"
+    CREATE TABLE IF NOT EXISTS tokens ( user_id TEXT NOT NULL, token TEXT NOT NULL );
+    CREATE TABLE IF NOT EXISTS github_users (user_id text primary key not null, github_id text not null);
+    CREATE TABLE IF NOT EXISTS users ( user_id TEXT NOT NULL UNIQUE, display_name TEXT );
+    CREATE TABLE IF NOT EXISTS "transactions" (id integer primary key autoincrement not null, shafter TEXT NOT NULL, shaftee TEXT NOT NULL, amount BIGINT NOT NULL, time_sec BIGINT NOT NULL, reason TEXT NOT NULL);
+    "#,
+    // 2: Attachments on transactions, and the details suggested for them by
+    // the receipt processor.
+    r#"
+    CREATE TABLE attachments (
+        id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+        transaction_id BIGINT NOT NULL,
+        uploader TEXT NOT NULL,
+        content_type TEXT NOT NULL,
+        data BLOB NOT NULL,
+        time_sec BIGINT NOT NULL
+    );
+    CREATE INDEX attachments_transaction_id ON attachments(transaction_id);
+
+    CREATE TABLE receipt_suggestions (
+        attachment_id BIGINT NOT NULL,
+        amount BIGINT,
+        merchant TEXT
+    );
+    CREATE INDEX receipt_suggestions_attachment_id ON receipt_suggestions(attachment_id);
+    "#,
+    // 3: A counter bumped on every change to users or transactions, used to
+    // cheaply tell if the ledger has changed.
+    r#"
+    CREATE TABLE ledger_version ( version BIGINT NOT NULL );
+    INSERT INTO ledger_version (version) VALUES (0);
+
+    CREATE TRIGGER transactions_insert_version AFTER INSERT ON transactions
+    BEGIN UPDATE ledger_version SET version = version + 1; END;
+    CREATE TRIGGER transactions_update_version AFTER UPDATE ON transactions
+    BEGIN UPDATE ledger_version SET version = version + 1; END;
+    CREATE TRIGGER transactions_delete_version AFTER DELETE ON transactions
+    BEGIN UPDATE ledger_version SET version = version + 1; END;
+    CREATE TRIGGER users_insert_version AFTER INSERT ON users
+    BEGIN UPDATE ledger_version SET version = version + 1; END;
+    CREATE TRIGGER users_update_version AFTER UPDATE ON users
+    BEGIN UPDATE ledger_version SET version = version + 1; END;
+    CREATE TRIGGER users_delete_version AFTER DELETE ON users
+    BEGIN UPDATE ledger_version SET version = version + 1; END;
+    "#,
+    // 4: An append-only log of changes to users and transactions, used for
+    // incremental sync. Replaces the ledger version counter, as the latest
+    // change sequence number serves the same purpose.
+    r#"
+    DROP TRIGGER transactions_insert_version;
+    DROP TRIGGER transactions_update_version;
+    DROP TRIGGER transactions_delete_version;
+    DROP TRIGGER users_insert_version;
+    DROP TRIGGER users_update_version;
+    DROP TRIGGER users_delete_version;
+    DROP TABLE ledger_version;
+
+    CREATE TABLE changes (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+        transaction_id BIGINT,
+        user_id TEXT
+    );
+
+    CREATE TRIGGER transactions_insert_change AFTER INSERT ON transactions
+    BEGIN INSERT INTO changes (transaction_id) VALUES (NEW.id); END;
+    CREATE TRIGGER transactions_update_change AFTER UPDATE ON transactions
+    BEGIN INSERT INTO changes (transaction_id) VALUES (NEW.id); END;
+    CREATE TRIGGER transactions_delete_change AFTER DELETE ON transactions
+    BEGIN INSERT INTO changes (transaction_id) VALUES (OLD.id); END;
+    CREATE TRIGGER users_insert_change AFTER INSERT ON users
+    BEGIN INSERT INTO changes (user_id) VALUES (NEW.user_id); END;
+    CREATE TRIGGER users_update_change AFTER UPDATE ON users
+    BEGIN INSERT INTO changes (user_id) VALUES (NEW.user_id); END;
+    CREATE TRIGGER users_delete_change AFTER DELETE ON users
+    BEGIN INSERT INTO changes (user_id) VALUES (OLD.user_id); END;
+    "#,
+    // 5: A revision number on transactions, bumped on each edit so that
+    // concurrent edits can be detected.
+    r#"
+    ALTER TABLE transactions ADD COLUMN revision BIGINT NOT NULL DEFAULT 1;
+    "#,
+    // 6: Gives tokens an ID, scopes and an optional name. Named tokens are
+    // API tokens, the rest are login sessions. Existing sessions keep the
+    // access they had.
+    r#"
+    CREATE TABLE tokens_new (
+        id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+        user_id TEXT NOT NULL,
+        token TEXT NOT NULL,
+        scopes TEXT NOT NULL,
+        name TEXT
+    );
+    INSERT INTO tokens_new (user_id, token, scopes)
+        SELECT user_id, token, 'read write' FROM tokens;
+    DROP TABLE tokens;
+    ALTER TABLE tokens_new RENAME TO tokens;
+    CREATE INDEX tokens_token ON tokens(token);
+    CREATE INDEX tokens_user_id ON tokens(user_id);
+    "#,
+    // 7: The audit log of actions taken by users.
+    r#"
+    CREATE TABLE audit_log (
+        id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+        actor TEXT NOT NULL,
+        action TEXT NOT NULL,
+        target TEXT,
+        details TEXT,
+        time_sec BIGINT NOT NULL
+    );
+    CREATE INDEX audit_log_actor ON audit_log(actor, id);
+    CREATE INDEX audit_log_action ON audit_log(action, id);
+    "#,
+    // 8: Indexes for the hot lookups. Balances are summed per shafter and
+    // shaftee, so those need indexing as well as the token and login lookups.
+    r#"
+    CREATE INDEX IF NOT EXISTS tokens_token ON tokens(token);
+    CREATE INDEX IF NOT EXISTS transactions_shafter ON transactions(shafter);
+    CREATE INDEX IF NOT EXISTS transactions_shaftee ON transactions(shaftee);
+    CREATE INDEX IF NOT EXISTS transactions_time_sec ON transactions(time_sec);
+    CREATE INDEX IF NOT EXISTS github_users_github_id ON github_users(github_id);
+    "#,
+    // 9: When login sessions stop working. NULL for API tokens and sessions
+    // created before sessions expired.
+    r#"
+    ALTER TABLE tokens ADD COLUMN expires_sec BIGINT;
+    "#,
+    // 10: Slack users linked to shaft users, for the slash command.
+    r#"
+    CREATE TABLE slack_users (
+        team_id TEXT NOT NULL,
+        slack_user_id TEXT NOT NULL,
+        user_id TEXT NOT NULL,
+        PRIMARY KEY (team_id, slack_user_id)
+    );
+    "#,
+    // 11: Secret URLs that create transactions for their user, for
+    // automations.
+    r#"
+    CREATE TABLE inbound_hooks (
+        id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+        hook_id TEXT NOT NULL UNIQUE,
+        user_id TEXT NOT NULL,
+        name TEXT NOT NULL,
+        created_sec BIGINT NOT NULL
+    );
+    CREATE INDEX inbound_hooks_user_id ON inbound_hooks(user_id);
+    "#,
+    // 12: What kind of transaction each is, so that opening balances and
+    // corrections made by admins can be told apart from shafts.
+    r#"
+    ALTER TABLE transactions ADD COLUMN kind TEXT NOT NULL DEFAULT 'shaft';
+    "#,
+    // 13: Several independent ledgers in one instance. Existing transactions
+    // and users are moved into the default ledger.
+    r#"
+    CREATE TABLE ledgers (
+        id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+        name TEXT NOT NULL UNIQUE,
+        display_name TEXT NOT NULL
+    );
+    INSERT INTO ledgers (id, name, display_name) VALUES (1, 'default', 'Shaft');
+
+    CREATE TABLE ledger_members (
+        ledger_id BIGINT NOT NULL,
+        user_id TEXT NOT NULL,
+        PRIMARY KEY (ledger_id, user_id)
+    );
+    CREATE INDEX ledger_members_user_id ON ledger_members(user_id);
+    INSERT INTO ledger_members (ledger_id, user_id) SELECT 1, user_id FROM users;
+
+    ALTER TABLE transactions ADD COLUMN ledger_id BIGINT NOT NULL DEFAULT 1;
+    CREATE INDEX transactions_ledger_id ON transactions(ledger_id, id);
+    "#,
+    // 14: Whether users can still use shaft. Deactivated users keep their
+    // balance and history but can't log in.
+    r#"
+    ALTER TABLE users ADD COLUMN active BOOLEAN NOT NULL DEFAULT 1;
+    "#,
+    // 15: When each login session was issued or last extended, so that
+    // sessions in use can be extended. Sessions created before this never
+    // are.
+    r#"
+    ALTER TABLE tokens ADD COLUMN issued_sec BIGINT;
+    "#,
+    // 16: Security relevant events, e.g. failed logins, kept apart from the
+    // audit log of what users did.
+    r#"
+    CREATE TABLE security_events (
+        id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+        kind TEXT NOT NULL,
+        user_id TEXT,
+        ip TEXT,
+        details TEXT,
+        time_sec BIGINT NOT NULL
+    );
+    CREATE INDEX security_events_kind ON security_events(kind, id);
+    "#,
+    // 17: Each user's defaults for the shaft form. Quick amounts are stored
+    // as comma separated minor units.
+    r#"
+    CREATE TABLE user_preferences (
+        user_id TEXT PRIMARY KEY NOT NULL,
+        default_counterparty TEXT,
+        default_currency TEXT,
+        quick_amounts TEXT NOT NULL DEFAULT ''
+    );
+    "#,
+    // 18: State shared between instances using the same database, see
+    // `shared_state`. Values are short lived, e.g. confirmation codes, and
+    // invalidations are only kept long enough for every instance to see them.
+    r#"
+    CREATE TABLE shared_values (
+        key TEXT PRIMARY KEY NOT NULL,
+        value TEXT NOT NULL,
+        expires_sec BIGINT NOT NULL
+    );
+    CREATE TABLE invalidations (
+        id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+        instance_id TEXT NOT NULL,
+        payload TEXT NOT NULL,
+        time_sec BIGINT NOT NULL
+    );
+    "#,
+    // 19: Deliveries of notifications and webhooks waiting to be made, and
+    // how they went.
+    r#"
+    CREATE TABLE outbox (
+        id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+        kind TEXT NOT NULL,
+        target TEXT NOT NULL,
+        payload TEXT NOT NULL,
+        status TEXT NOT NULL,
+        attempts INTEGER NOT NULL DEFAULT 0,
+        last_error TEXT,
+        created_sec BIGINT NOT NULL,
+        next_attempt_sec BIGINT,
+        finished_sec BIGINT
+    );
+    CREATE INDEX outbox_status_next_attempt ON outbox(status, next_attempt_sec);
+    "#,
+    // 20: Each attempt to deliver an outbox message, for debugging webhooks.
+    r#"
+    CREATE TABLE outbox_attempts (
+        id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+        outbox_id BIGINT NOT NULL,
+        attempted_sec BIGINT NOT NULL,
+        status_code INTEGER,
+        latency_ms BIGINT NOT NULL,
+        response TEXT,
+        error TEXT
+    );
+    CREATE INDEX outbox_attempts_outbox_id ON outbox_attempts(outbox_id);
+    "#,
+    // 21: When what the pages show last changed, for `Last-Modified`
+    // headers. Scopes are `global` for users and ledgers, `ledger:<id>` for a
+    // ledger's transactions and `user:<id>` for what only that user sees.
+    // Logging in or out touches the user's scope, so that a browser shared
+    // by several users doesn't reuse another user's page.
+    r#"
+    CREATE TABLE last_modified (
+        scope TEXT PRIMARY KEY NOT NULL,
+        modified_sec BIGINT NOT NULL
+    );
+
+    CREATE TRIGGER transactions_insert_modified AFTER INSERT ON transactions
+    BEGIN INSERT OR REPLACE INTO last_modified (scope, modified_sec) VALUES ('ledger:' || NEW.ledger_id, CAST(strftime('%s', 'now') AS INTEGER)); END;
+    CREATE TRIGGER transactions_update_modified AFTER UPDATE ON transactions
+    BEGIN INSERT OR REPLACE INTO last_modified (scope, modified_sec) VALUES ('ledger:' || NEW.ledger_id, CAST(strftime('%s', 'now') AS INTEGER)); END;
+    CREATE TRIGGER transactions_delete_modified AFTER DELETE ON transactions
+    BEGIN INSERT OR REPLACE INTO last_modified (scope, modified_sec) VALUES ('ledger:' || OLD.ledger_id, CAST(strftime('%s', 'now') AS INTEGER)); END;
+
+    CREATE TRIGGER users_insert_modified AFTER INSERT ON users
+    BEGIN INSERT OR REPLACE INTO last_modified (scope, modified_sec) VALUES ('global', CAST(strftime('%s', 'now') AS INTEGER)); END;
+    CREATE TRIGGER users_update_modified AFTER UPDATE ON users
+    BEGIN INSERT OR REPLACE INTO last_modified (scope, modified_sec) VALUES ('global', CAST(strftime('%s', 'now') AS INTEGER)); END;
+    CREATE TRIGGER users_delete_modified AFTER DELETE ON users
+    BEGIN INSERT OR REPLACE INTO last_modified (scope, modified_sec) VALUES ('global', CAST(strftime('%s', 'now') AS INTEGER)); END;
+    CREATE TRIGGER ledgers_insert_modified AFTER INSERT ON ledgers
+    BEGIN INSERT OR REPLACE INTO last_modified (scope, modified_sec) VALUES ('global', CAST(strftime('%s', 'now') AS INTEGER)); END;
+    CREATE TRIGGER ledgers_update_modified AFTER UPDATE ON ledgers
+    BEGIN INSERT OR REPLACE INTO last_modified (scope, modified_sec) VALUES ('global', CAST(strftime('%s', 'now') AS INTEGER)); END;
+
+    CREATE TRIGGER ledger_members_insert_modified AFTER INSERT ON ledger_members
+    BEGIN
+        INSERT OR REPLACE INTO last_modified (scope, modified_sec) VALUES ('ledger:' || NEW.ledger_id, CAST(strftime('%s', 'now') AS INTEGER));
+        INSERT OR REPLACE INTO last_modified (scope, modified_sec) VALUES ('user:' || NEW.user_id, CAST(strftime('%s', 'now') AS INTEGER));
+    END;
+    CREATE TRIGGER ledger_members_delete_modified AFTER DELETE ON ledger_members
+    BEGIN
+        INSERT OR REPLACE INTO last_modified (scope, modified_sec) VALUES ('ledger:' || OLD.ledger_id, CAST(strftime('%s', 'now') AS INTEGER));
+        INSERT OR REPLACE INTO last_modified (scope, modified_sec) VALUES ('user:' || OLD.user_id, CAST(strftime('%s', 'now') AS INTEGER));
+    END;
+
+    CREATE TRIGGER user_preferences_insert_modified AFTER INSERT ON user_preferences
+    BEGIN INSERT OR REPLACE INTO last_modified (scope, modified_sec) VALUES ('user:' || NEW.user_id, CAST(strftime('%s', 'now') AS INTEGER)); END;
+    CREATE TRIGGER user_preferences_update_modified AFTER UPDATE ON user_preferences
+    BEGIN INSERT OR REPLACE INTO last_modified (scope, modified_sec) VALUES ('user:' || NEW.user_id, CAST(strftime('%s', 'now') AS INTEGER)); END;
+    CREATE TRIGGER user_preferences_delete_modified AFTER DELETE ON user_preferences
+    BEGIN INSERT OR REPLACE INTO last_modified (scope, modified_sec) VALUES ('user:' || OLD.user_id, CAST(strftime('%s', 'now') AS INTEGER)); END;
+    CREATE TRIGGER tokens_insert_modified AFTER INSERT ON tokens
+    BEGIN INSERT OR REPLACE INTO last_modified (scope, modified_sec) VALUES ('user:' || NEW.user_id, CAST(strftime('%s', 'now') AS INTEGER)); END;
+    CREATE TRIGGER tokens_delete_modified AFTER DELETE ON tokens
+    BEGIN INSERT OR REPLACE INTO last_modified (scope, modified_sec) VALUES ('user:' || OLD.user_id, CAST(strftime('%s', 'now') AS INTEGER)); END;
+    "#,
+    // 22: Changes to transactions also log the users whose balances they
+    // change. The horizon is the change log's oldest valid cursor, raised
+    // when compaction drops changes that clients may not have seen.
+    r#"
+    DROP TRIGGER transactions_insert_change;
+    DROP TRIGGER transactions_update_change;
+    DROP TRIGGER transactions_delete_change;
+
+    CREATE TRIGGER transactions_insert_change AFTER INSERT ON transactions
+    BEGIN
+        INSERT INTO changes (transaction_id) VALUES (NEW.id);
+        INSERT INTO changes (user_id) SELECT NEW.shafter UNION SELECT NEW.shaftee;
+    END;
+    CREATE TRIGGER transactions_update_change AFTER UPDATE ON transactions
+    BEGIN
+        INSERT INTO changes (transaction_id) VALUES (NEW.id);
+        INSERT INTO changes (user_id)
+            SELECT OLD.shafter UNION SELECT OLD.shaftee
+            UNION SELECT NEW.shafter UNION SELECT NEW.shaftee;
+    END;
+    CREATE TRIGGER transactions_delete_change AFTER DELETE ON transactions
+    BEGIN
+        INSERT INTO changes (transaction_id) VALUES (OLD.id);
+        INSERT INTO changes (user_id) SELECT OLD.shafter UNION SELECT OLD.shaftee;
+    END;
+
+    CREATE TABLE changes_horizon ( seq BIGINT NOT NULL );
+    INSERT INTO changes_horizon (seq) VALUES (0);
+    "#,
+];
+
+/// Indexes the schema is expected to have, along with a query that should use
+/// each. Checked at startup using `EXPLAIN QUERY PLAN`, so that a missing
+/// index shows up in the logs rather than as slow requests.
+pub(crate) const EXPECTED_INDEXES: &[(&str, &str)] = &[
+    (
+        "tokens(token)",
+        "SELECT user_id FROM tokens WHERE token = ''",
+    ),
+    (
+        "transactions(shafter)",
+        "SELECT amount FROM transactions WHERE shafter = ''",
+    ),
+    (
+        "transactions(shaftee)",
+        "SELECT amount FROM transactions WHERE shaftee = ''",
+    ),
+    (
+        "transactions(time_sec)",
+        "SELECT id FROM transactions WHERE time_sec >= 0",
+    ),
+    (
+        "github_users(github_id)",
+        "SELECT user_id FROM github_users WHERE github_id = ''",
+    ),
+    (
+        "transactions(ledger_id, id)",
+        "SELECT amount FROM transactions WHERE ledger_id = 0 ORDER BY id DESC",
+    ),
+];
+
+/// Whether a line of `EXPLAIN QUERY PLAN` output shows an index being used.
+pub(crate) fn plan_uses_index(detail: &str) -> bool {
+    detail.contains(" USING INDEX ") || detail.contains(" USING COVERING INDEX ")
+}
@@ -5,32 +5,114 @@ use futures::future::LocalBoxFuture;
 
 use linear_map::LinearMap;
 use r2d2;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
 use rusqlite;
 use serde;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use snafu::{Backtrace, Snafu};
 
-// mod postgres;
+use std::sync::{Arc, Mutex};
+
+use crate::events::Event;
+use crate::money::{Currency, CurrencyCode, Money};
+
+mod anonymise;
+mod integrity;
+mod migrations;
 mod sqlite;
+#[cfg(feature = "sqlx")]
+mod sqlx_sqlite;
 
-// pub use self::postgres::PostgresDatabase;
+pub use self::anonymise::{anonymise_database, AnonymiseError, AnonymiseSummary};
+pub(crate) use self::anonymise::{fake_name, REASONS};
+pub use self::integrity::{IntegrityProblem, IntegrityProblemKind};
 pub use self::sqlite::SqliteDatabase;
+#[cfg(feature = "sqlx")]
+pub use self::sqlx_sqlite::SqlxDatabase;
+
+/// The display name given to users who have deleted their account.
+pub const DELETED_USER_DISPLAY_NAME: &str = "Deleted user";
+
+/// The ledger that existed before there could be several, which everyone is
+/// a member of.
+pub const DEFAULT_LEDGER_ID: i64 = 1;
+
+/// An independent set of users' balances and transactions, e.g. for a flat or
+/// a holiday.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Ledger {
+    pub id: i64,
+    /// The name used in URLs, e.g. `flat` in `/l/flat/home`.
+    pub name: String,
+    /// A human readable name.
+    pub display_name: String,
+}
 
 /// A single transaction between two users.
 #[derive(Clone, Debug, Serialize)]
 pub struct Transaction {
+    /// The ID of the transaction, or None if it hasn't been stored yet.
+    pub id: Option<i64>,
     /// The user who is creating the transaction.
     pub shafter: String,
     /// The other party in the transaction.
     pub shaftee: String,
-    /// The amount of money in pence. Positive means shafter is owed the amount,
+    /// The amount of money. Positive means shafter is owed the amount,
     /// negative means shafter owes the amount.
-    pub amount: i64,
+    pub amount: Money,
     /// Time transaction happened.
     #[serde(serialize_with = "serialize_time")]
     pub datetime: chrono::DateTime<chrono::Utc>,
     /// A human readable description of the transaction.
     pub reason: String,
+    /// Whether this is a shaft or an adjustment made by an admin.
+    pub kind: TransactionKind,
+}
+
+/// What kind of transaction something is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransactionKind {
+    /// One user paid for something on behalf of another.
+    Shaft,
+    /// An opening balance or correction recorded by an admin.
+    Adjustment,
+}
+
+impl TransactionKind {
+    /// The name of the kind, as used in the API and database.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TransactionKind::Shaft => "shaft",
+            TransactionKind::Adjustment => "adjustment",
+        }
+    }
+
+    /// Parse a kind from its name.
+    pub fn from_name(name: &str) -> Option<TransactionKind> {
+        match name {
+            "shaft" => Some(TransactionKind::Shaft),
+            "adjustment" => Some(TransactionKind::Adjustment),
+            _ => None,
+        }
+    }
+}
+
+/// The user an access token belongs to, and what the token is allowed to do.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenUser {
+    /// Their internal shaft user ID
+    pub user_id: String,
+    /// Their display name
+    pub display_name: String,
+    /// What the token is allowed to do.
+    pub scopes: Vec<Scope>,
+    /// When the token was issued or last extended, if it's a login session
+    /// that can be extended.
+    pub issued: Option<chrono::DateTime<chrono::Utc>>,
+    /// When the token stops working, if ever.
+    pub expires: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 /// A user and their balance
@@ -41,10 +123,594 @@ pub struct User {
     /// Their display name
     pub display_name: String,
     /// Their current balance
-    pub balance: i64,
+    pub balance: Money,
+}
+
+/// What one user owes another, from the transactions between them.
+#[derive(Debug, Clone, Serialize)]
+pub struct Debt {
+    /// The user who owes money.
+    pub debtor: String,
+    /// The user who is owed it.
+    pub creditor: String,
+    /// How much is owed. Always positive.
+    pub amount: Money,
+    /// When the debtor started owing the creditor, i.e. the first
+    /// transaction since they were last square.
+    #[serde(serialize_with = "serialize_time")]
+    pub since: chrono::DateTime<chrono::Utc>,
+    /// When they were last square, or owed the other way round. None if
+    /// they never have been.
+    #[serde(serialize_with = "serialize_optional_time")]
+    pub last_settled: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// The users sorted by balance, most in debt first, then by user ID.
+pub fn users_by_balance(users: &LinearMap<String, User>) -> Vec<&User> {
+    let mut users: Vec<_> = users.values().collect();
+    users.sort_by(|a, b| {
+        a.balance
+            .cmp(&b.balance)
+            .then_with(|| a.user_id.cmp(&b.user_id))
+    });
+    users
+}
+
+/// The changes to the ledger after a sync cursor.
+#[derive(Debug, Clone, Serialize)]
+pub struct LedgerChanges {
+    /// The cursor to use to fetch the changes after these.
+    pub next_cursor: i64,
+    /// The current state of transactions that were added or changed.
+    pub transactions: Vec<Transaction>,
+    /// The IDs of transactions that were deleted.
+    pub deleted_transactions: Vec<i64>,
+    /// The current state of users that were added or changed, including
+    /// those whose balances changed.
+    pub users: Vec<User>,
+    /// Whether changes after the cursor have been compacted away. If so
+    /// nothing else is returned, and the client should reload the ledger and
+    /// then sync from `next_cursor`.
+    pub resync_required: bool,
+}
+
+/// A file attached to a transaction, e.g. a photo of a receipt.
+#[derive(Debug, Clone)]
+pub struct Attachment {
+    /// The transaction the file is attached to.
+    pub transaction_id: i64,
+    /// The user who uploaded the file.
+    pub uploader: String,
+    /// The MIME type of the file.
+    pub content_type: String,
+    /// The contents of the file.
+    pub data: Vec<u8>,
+}
+
+/// An [Attachment] without its contents.
+#[derive(Debug, Clone, Serialize)]
+pub struct AttachmentInfo {
+    /// The ID of the attachment.
+    pub id: i64,
+    /// The transaction the file is attached to.
+    pub transaction_id: i64,
+    /// The MIME type of the file.
+    pub content_type: String,
+    /// Time the file was uploaded.
+    #[serde(serialize_with = "serialize_time")]
+    pub datetime: chrono::DateTime<chrono::Utc>,
+}
+
+/// A Slack account linked to a user.
+#[derive(Debug, Clone, Serialize)]
+pub struct SlackLink {
+    /// The Slack workspace the account is in.
+    pub team_id: String,
+    /// The user's ID within the workspace.
+    pub slack_user_id: String,
+}
+
+/// Everything held about a user, as returned by a data export.
+#[derive(Debug, Clone, Serialize)]
+pub struct UserExport {
+    /// The user and their balance.
+    pub user: User,
+    /// The GitHub logins linked to the user.
+    pub github_ids: Vec<String>,
+    /// Transactions the user is a party to, oldest first.
+    pub transactions: Vec<Transaction>,
+    /// The user's API tokens. Doesn't include the tokens themselves.
+    pub api_tokens: Vec<ApiToken>,
+    /// The number of login sessions the user has.
+    pub sessions: i64,
+    /// Files the user has uploaded, without their contents.
+    pub attachments: Vec<AttachmentInfo>,
+    /// Actions the user has taken, oldest first.
+    pub audit_log: Vec<AuditEntry>,
+    /// The user's defaults for the shaft form.
+    pub preferences: UserPreferences,
+    /// The Slack accounts linked to the user.
+    pub slack_links: Vec<SlackLink>,
+    /// The user's inbound hooks. Doesn't include their secret IDs.
+    pub inbound_hooks: Vec<InboundHook>,
+    /// The ledgers the user is a member of.
+    pub ledgers: Vec<Ledger>,
+    /// Security events involving the user, oldest first.
+    pub security_events: Vec<SecurityEvent>,
+}
+
+/// A user's defaults for the shaft form.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct UserPreferences {
+    /// The user picked in the form to begin with.
+    pub default_counterparty: Option<String>,
+    /// The currency amounts are entered in.
+    pub default_currency: Option<CurrencyCode>,
+    /// Amounts offered as buttons that fill in the form.
+    pub quick_amounts: Vec<Money>,
+}
+
+/// Details of a receipt suggested by a
+/// [ReceiptProcessor](crate::receipts::ReceiptProcessor).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ReceiptSuggestion {
+    /// The total amount on the receipt in pence, if found.
+    pub amount: Option<i64>,
+    /// The merchant the receipt is from, if found.
+    pub merchant: Option<String>,
+}
+
+/// An entry in the audit log, recording an action taken by a user.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditEntry {
+    /// The ID of the entry, or None if it hasn't been stored yet.
+    pub id: Option<i64>,
+    /// The user who took the action.
+    pub actor: String,
+    /// What was done, e.g. `transaction.delete`.
+    pub action: String,
+    /// What the action was done to, if anything.
+    pub target: Option<String>,
+    /// Human readable details of the action.
+    pub details: Option<String>,
+    /// Time the action was taken.
+    #[serde(serialize_with = "serialize_time")]
+    pub datetime: chrono::DateTime<chrono::Utc>,
+}
+
+/// Which audit log entries to fetch. Entries are returned newest first.
+#[derive(Debug, Clone, Default)]
+pub struct AuditFilter {
+    /// Only include actions by this user.
+    pub actor: Option<String>,
+    /// Only include actions of this type.
+    pub action: Option<String>,
+    /// Only include actions on this target.
+    pub target: Option<String>,
+    /// Only include actions taken at or after this time.
+    pub from: Option<chrono::DateTime<chrono::Utc>>,
+    /// Only include actions taken before this time.
+    pub to: Option<chrono::DateTime<chrono::Utc>>,
+    /// Only include entries with an ID less than this, for pagination.
+    pub before: Option<i64>,
+    /// The maximum number of entries to return.
+    pub limit: u32,
+}
+
+/// A security relevant event, e.g. a failed login or a request with an
+/// invalid token.
+#[derive(Debug, Clone, Serialize)]
+pub struct SecurityEvent {
+    /// The ID of the event, or None if it hasn't been stored yet.
+    pub id: Option<i64>,
+    /// What happened, e.g. `login.state_mismatch`.
+    pub kind: String,
+    /// The user involved, if known.
+    pub user_id: Option<String>,
+    /// The address the request came from, if known.
+    pub ip: Option<String>,
+    /// Human readable details of the event.
+    pub details: Option<String>,
+    /// Time the event happened.
+    #[serde(serialize_with = "serialize_time")]
+    pub datetime: chrono::DateTime<chrono::Utc>,
+}
+
+/// Which security events to fetch. Events are returned newest first.
+#[derive(Debug, Clone, Default)]
+pub struct SecurityEventFilter {
+    /// Only include events of this kind.
+    pub kind: Option<String>,
+    /// Only include events with an ID less than this, for pagination.
+    pub before: Option<i64>,
+    /// The maximum number of events to return.
+    pub limit: u32,
+}
+
+/// A cache invalidation published by an instance sharing the database.
+#[derive(Debug, Clone)]
+pub struct StoredInvalidation {
+    pub id: i64,
+    /// The instance that published it.
+    pub instance_id: String,
+    /// The invalidation, serialized by the publisher.
+    pub payload: String,
+}
+
+/// What an outbox message is delivered to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutboxKind {
+    /// A notification for a user, delivered by the notifier. The target is
+    /// the user's ID.
+    Notification,
+    /// An outbound webhook. The target is the webhook's ID.
+    Webhook,
+}
+
+impl OutboxKind {
+    /// The name of the kind, as used in the API and database.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OutboxKind::Notification => "notification",
+            OutboxKind::Webhook => "webhook",
+        }
+    }
+
+    /// Parse a kind from its name.
+    pub fn from_name(name: &str) -> Option<OutboxKind> {
+        match name {
+            "notification" => Some(OutboxKind::Notification),
+            "webhook" => Some(OutboxKind::Webhook),
+            _ => None,
+        }
+    }
+}
+
+/// How far along its delivery an outbox message is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutboxStatus {
+    /// Not yet delivered, and will be tried again.
+    Pending,
+    Delivered,
+    /// Gave up after too many failed attempts.
+    Failed,
+}
+
+impl OutboxStatus {
+    /// The name of the status, as used in the API and database.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OutboxStatus::Pending => "pending",
+            OutboxStatus::Delivered => "delivered",
+            OutboxStatus::Failed => "failed",
+        }
+    }
+
+    /// Parse a status from its name.
+    pub fn from_name(name: &str) -> Option<OutboxStatus> {
+        match name {
+            "pending" => Some(OutboxStatus::Pending),
+            "delivered" => Some(OutboxStatus::Delivered),
+            "failed" => Some(OutboxStatus::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// A delivery to add to the outbox.
+#[derive(Debug, Clone)]
+pub struct NewOutboxMessage {
+    pub kind: OutboxKind,
+    /// Who or what the message is for, depending on its kind.
+    pub target: String,
+    /// The JSON to deliver.
+    pub payload: String,
+}
+
+/// Works out the outbox messages announcing an event, e.g. one for each
+/// webhook interested in it.
+pub trait OutboxRouter: Send + Sync {
+    fn messages(&self, event: &Event) -> Vec<NewOutboxMessage>;
+}
+
+/// The events announcing a change. Their outbox messages are added in the
+/// same database transaction as the change, so they're delivered if and only
+/// if the change is made.
+///
+/// The events are built from what the change returns, e.g. a new
+/// transaction's ID, and kept to be published once it has been committed.
+/// Clones share the events.
+pub struct Outbox<T> {
+    inner: Arc<Mutex<OutboxInner<T>>>,
+}
+
+/// Builds the events announcing a change from its result.
+type BuildEvents<T> = Box<dyn FnOnce(&T) -> Vec<Event> + Send>;
+
+struct OutboxInner<T> {
+    build: Option<BuildEvents<T>>,
+    router: Option<Arc<dyn OutboxRouter>>,
+    events: Vec<Event>,
+}
+
+impl<T> Outbox<T> {
+    /// Announce the change with the events `build` returns, routing them to
+    /// the outbox with `router`, if any.
+    pub fn new<F>(router: Option<Arc<dyn OutboxRouter>>, build: F) -> Outbox<T>
+    where
+        F: FnOnce(&T) -> Vec<Event> + Send + 'static,
+    {
+        Outbox {
+            inner: Arc::new(Mutex::new(OutboxInner {
+                build: Some(Box::new(build)),
+                router,
+                events: Vec::new(),
+            })),
+        }
+    }
+
+    /// Don't announce the change, e.g. for changes made by admin commands
+    /// rather than users.
+    pub fn none() -> Outbox<T> {
+        Outbox {
+            inner: Arc::new(Mutex::new(OutboxInner {
+                build: None,
+                router: None,
+                events: Vec::new(),
+            })),
+        }
+    }
+
+    /// Build the events from the change's result, returning the outbox
+    /// messages to add along with the change.
+    pub fn messages(&self, result: &T) -> Vec<NewOutboxMessage> {
+        let mut inner = self.inner.lock().expect("outbox lock poisoned");
+
+        let events = match inner.build.take() {
+            Some(build) => build(result),
+            None => return Vec::new(),
+        };
+        let messages = match &inner.router {
+            Some(router) => events.iter().flat_map(|e| router.messages(e)).collect(),
+            None => Vec::new(),
+        };
+
+        inner.events = events;
+        messages
+    }
+
+    /// Take the events built once the change has been made, to publish them.
+    pub fn take_events(&self) -> Vec<Event> {
+        let mut inner = self.inner.lock().expect("outbox lock poisoned");
+        std::mem::take(&mut inner.events)
+    }
+}
+
+impl<T> Clone for Outbox<T> {
+    fn clone(&self) -> Outbox<T> {
+        Outbox {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+/// A delivery in the outbox, and how it's going.
+#[derive(Debug, Clone, Serialize)]
+pub struct OutboxMessage {
+    pub id: i64,
+    pub kind: OutboxKind,
+    /// Who or what the message is for, depending on its kind.
+    pub target: String,
+    /// The JSON to deliver.
+    pub payload: String,
+    pub status: OutboxStatus,
+    /// How many times delivery has been tried.
+    pub attempts: i64,
+    /// Why the last attempt failed, if it did.
+    pub last_error: Option<String>,
+    #[serde(serialize_with = "serialize_time")]
+    pub created: chrono::DateTime<chrono::Utc>,
+    /// When delivery will next be tried, if it's pending.
+    #[serde(serialize_with = "serialize_optional_time")]
+    pub next_attempt: Option<chrono::DateTime<chrono::Utc>>,
+    /// When it was delivered or given up on.
+    #[serde(serialize_with = "serialize_optional_time")]
+    pub finished: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// Which outbox messages to fetch. Messages are returned newest first.
+#[derive(Debug, Clone, Default)]
+pub struct OutboxFilter {
+    /// Only include messages of this kind.
+    pub kind: Option<OutboxKind>,
+    /// Only include messages with this status.
+    pub status: Option<OutboxStatus>,
+    /// Only include messages for this target.
+    pub target: Option<String>,
+    /// Only include messages with an ID less than this, for pagination.
+    pub before: Option<i64>,
+    /// The maximum number of messages to return.
+    pub limit: u32,
+}
+
+/// The outcome of trying to deliver an outbox message.
+#[derive(Debug, Clone)]
+pub struct OutboxAttempt {
+    /// Why delivery failed, or None if it succeeded.
+    pub error: Option<String>,
+    /// When to try again after a failure, or None to give up.
+    pub next_attempt: Option<chrono::DateTime<chrono::Utc>>,
+    /// The HTTP status code of the response, for webhooks that responded.
+    pub status_code: Option<u16>,
+    /// How long the attempt took, in milliseconds.
+    pub latency_ms: i64,
+    /// The start of the response body, for webhooks that responded.
+    pub response: Option<String>,
+}
+
+/// A past attempt to deliver an outbox message.
+#[derive(Debug, Clone, Serialize)]
+pub struct OutboxAttemptRecord {
+    /// The ID of the outbox message.
+    pub outbox_id: i64,
+    #[serde(serialize_with = "serialize_time")]
+    pub attempted: chrono::DateTime<chrono::Utc>,
+    pub status_code: Option<u16>,
+    pub latency_ms: i64,
+    pub response: Option<String>,
+    /// Why the attempt failed, if it did.
+    pub error: Option<String>,
+}
+
+/// Which transactions to fetch. Transactions are returned newest first.
+#[derive(Debug, Clone, Default)]
+pub struct TransactionFilter {
+    /// Only include transactions this user is a party to.
+    pub user: Option<String>,
+    /// Only include transactions at or after this time.
+    pub from: Option<chrono::DateTime<chrono::Utc>>,
+    /// Only include transactions before this time.
+    pub to: Option<chrono::DateTime<chrono::Utc>>,
+    /// Only include transactions with an ID less than this, for pagination.
+    pub before: Option<i64>,
+    /// The maximum number of transactions to return.
+    pub limit: u32,
+}
+
+/// How to order users.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UserSort {
+    /// Most in debt first.
+    Balance,
+    /// Alphabetically by display name, ignoring case.
+    Name,
+}
+
+impl Default for UserSort {
+    fn default() -> UserSort {
+        UserSort::Balance
+    }
+}
+
+/// Which users to fetch.
+#[derive(Debug, Clone, Default)]
+pub struct UserFilter {
+    /// Only include users who are, or aren't, active.
+    pub active: Option<bool>,
+    /// Only include users whose ID or display name starts with this,
+    /// ignoring case.
+    pub prefix: Option<String>,
+    /// Only include users whose balance isn't zero.
+    pub nonzero_balance: bool,
+    /// Leave out settled users, i.e. those with a zero balance and no
+    /// transactions since this time.
+    pub settled_before: Option<chrono::DateTime<chrono::Utc>>,
+    pub sort: UserSort,
+    /// The maximum number of users to return, or None for all of them.
+    pub limit: Option<u32>,
+    /// How many users to skip, for pagination.
+    pub offset: u32,
+}
+
+/// A user, their balance and whether they can still use shaft.
+#[derive(Debug, Clone, Serialize)]
+pub struct UserEntry {
+    #[serde(flatten)]
+    pub user: User,
+    /// False if they've been deactivated.
+    pub active: bool,
+}
+
+/// Something an access token can be permitted to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// View balances and transactions.
+    Read,
+    /// Create and change transactions.
+    Write,
+    /// Administer the instance.
+    Admin,
+    /// View the user's balances through the Home Assistant sensor. Implied
+    /// by `read`.
+    Sensor,
+}
+
+impl Scope {
+    /// The name of the scope, as used in the API and database.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Write => "write",
+            Scope::Admin => "admin",
+            Scope::Sensor => "sensor",
+        }
+    }
+
+    /// Parse a scope from its name.
+    pub fn from_name(name: &str) -> Option<Scope> {
+        match name {
+            "read" => Some(Scope::Read),
+            "write" => Some(Scope::Write),
+            "admin" => Some(Scope::Admin),
+            "sensor" => Some(Scope::Sensor),
+            _ => None,
+        }
+    }
+
+    /// Whether a token with this scope may do what `other` allows.
+    pub fn grants(self, other: Scope) -> bool {
+        self == other || (self == Scope::Read && other == Scope::Sensor)
+    }
+}
+
+/// An access token created for use with the API, as opposed to a login
+/// session.
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiToken {
+    /// The ID of the token. This is not the secret token itself.
+    pub id: i64,
+    /// A human readable name for the token.
+    pub name: String,
+    /// What the token is allowed to do.
+    pub scopes: Vec<Scope>,
+}
+
+/// A secret URL that creates transactions on behalf of a user, e.g. from
+/// IFTTT or Zapier.
+#[derive(Debug, Clone, Serialize)]
+pub struct InboundHook {
+    /// The ID of the hook. This is not the secret hook ID used in its URL.
+    pub id: i64,
+    /// A human readable name for the hook.
+    pub name: String,
+    #[serde(serialize_with = "serialize_time")]
+    pub created: chrono::DateTime<chrono::Utc>,
+}
+
+/// A snapshot of how busy the database is.
+#[derive(Debug, Clone, Serialize)]
+pub struct PoolStats {
+    /// Number of operations queued or running.
+    pub in_flight: usize,
+    /// Number of operations that may be queued or running before new ones
+    /// are rejected.
+    pub max_in_flight: usize,
+    /// Number of open connections.
+    pub connections: u32,
+    /// Number of open connections not currently in use.
+    pub idle_connections: u32,
+    /// Maximum number of connections the pool will open.
+    pub max_connections: u32,
 }
 
 /// A generic datastore for the app
+///
+/// Changes that are announced with events take an [Outbox], whose messages
+/// are added in the same database transaction as the change.
 pub trait Database: Send + Sync {
     /// Get local user ID by their Github login ID
     fn get_user_by_github_id(
@@ -52,50 +718,593 @@ pub trait Database: Send + Sync {
         github_user_id: String,
     ) -> LocalBoxFuture<'static, Result<Option<String>, DatabaseError>>;
 
-    /// Add a new user from github
+    /// Add a new user from github. They are made a member of the default
+    /// ledger.
     fn add_user_by_github_id(
         &self,
         github_user_id: String,
         display_name: String,
+        outbox: Outbox<String>,
     ) -> LocalBoxFuture<'static, Result<String, DatabaseError>>;
 
-    /// Create a new Shaft access token
+    /// Get the local user ID linked to a Slack user, if any.
+    fn get_user_by_slack_id(
+        &self,
+        team_id: String,
+        slack_user_id: String,
+    ) -> LocalBoxFuture<'static, Result<Option<String>, DatabaseError>>;
+
+    /// Link a Slack user to a local user, replacing any existing link for
+    /// that Slack user.
+    fn link_slack_user(
+        &self,
+        team_id: String,
+        slack_user_id: String,
+        user_id: String,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>>;
+
+    /// Create a new Shaft access token for a login session, which stops
+    /// working after `expires`.
     fn create_token_for_user(
         &self,
         user_id: String,
+        scopes: Vec<Scope>,
+        expires: chrono::DateTime<chrono::Utc>,
+        outbox: Outbox<String>,
     ) -> LocalBoxFuture<'static, Result<String, DatabaseError>>;
 
     /// Delete a Shaft access token.
     fn delete_token(&self, token: String) -> LocalBoxFuture<'static, Result<(), DatabaseError>>;
 
-    /// Get a user by Shaft access token.
+    /// Extend a login session, recording that it was reissued at `issued`
+    /// and now stops working after `expires`.
+    fn refresh_token(
+        &self,
+        token: String,
+        issued: chrono::DateTime<chrono::Utc>,
+        expires: chrono::DateTime<chrono::Utc>,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>>;
+
+    /// Get the user and the token's scopes by Shaft access token. Expired
+    /// tokens are ignored.
     fn get_user_from_token(
         &self,
         token: String,
-    ) -> LocalBoxFuture<'static, Result<Option<User>, DatabaseError>>;
+    ) -> LocalBoxFuture<'static, Result<Option<TokenUser>, DatabaseError>>;
+
+    /// Get the ID of the user a Shaft access token belongs to. Cheaper than
+    /// [Database::get_user_from_token] when only the token's validity matters.
+    fn get_user_id_for_token(
+        &self,
+        token: String,
+    ) -> LocalBoxFuture<'static, Result<Option<String>, DatabaseError>>;
+
+    /// Create a named API token, returning its ID and the token.
+    fn create_api_token(
+        &self,
+        user_id: String,
+        name: String,
+        scopes: Vec<Scope>,
+        outbox: Outbox<(i64, String)>,
+    ) -> LocalBoxFuture<'static, Result<(i64, String), DatabaseError>>;
+
+    /// Get the API tokens belonging to a user.
+    fn get_api_tokens(
+        &self,
+        user_id: String,
+    ) -> LocalBoxFuture<'static, Result<Vec<ApiToken>, DatabaseError>>;
+
+    /// Create a named inbound hook for a user, returning its ID and the
+    /// secret hook ID.
+    fn create_inbound_hook(
+        &self,
+        user_id: String,
+        name: String,
+        outbox: Outbox<(i64, String)>,
+    ) -> LocalBoxFuture<'static, Result<(i64, String), DatabaseError>>;
+
+    /// Get the inbound hooks belonging to a user.
+    fn get_inbound_hooks(
+        &self,
+        user_id: String,
+    ) -> LocalBoxFuture<'static, Result<Vec<InboundHook>, DatabaseError>>;
+
+    /// Delete one of the user's inbound hooks by ID. Returns whether the hook
+    /// existed.
+    fn delete_inbound_hook(
+        &self,
+        user_id: String,
+        id: i64,
+        outbox: Outbox<bool>,
+    ) -> LocalBoxFuture<'static, Result<bool, DatabaseError>>;
+
+    /// Get the ID of the user a secret hook ID belongs to.
+    fn get_user_for_inbound_hook(
+        &self,
+        hook_id: String,
+    ) -> LocalBoxFuture<'static, Result<Option<String>, DatabaseError>>;
 
-    /// Get a user's balance in pence
+    /// Add an entry to the audit log, returning its ID.
+    fn add_audit_entry(
+        &self,
+        entry: AuditEntry,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>>;
+
+    /// Get the audit log entries matching the filter, newest first.
+    fn get_audit_entries(
+        &self,
+        filter: AuditFilter,
+    ) -> LocalBoxFuture<'static, Result<Vec<AuditEntry>, DatabaseError>>;
+
+    /// Record a security event, returning its ID.
+    fn add_security_event(
+        &self,
+        event: SecurityEvent,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>>;
+
+    /// Get the security events matching the filter, newest first.
+    fn get_security_events(
+        &self,
+        filter: SecurityEventFilter,
+    ) -> LocalBoxFuture<'static, Result<Vec<SecurityEvent>, DatabaseError>>;
+
+    /// Delete one of the user's API tokens by ID. Returns whether the token
+    /// existed.
+    fn delete_api_token(
+        &self,
+        user_id: String,
+        token_id: i64,
+        outbox: Outbox<bool>,
+    ) -> LocalBoxFuture<'static, Result<bool, DatabaseError>>;
+
+    /// Create a new ledger with no members.
+    fn create_ledger(
+        &self,
+        name: String,
+        display_name: String,
+        outbox: Outbox<Ledger>,
+    ) -> LocalBoxFuture<'static, Result<Ledger, DatabaseError>>;
+
+    /// Get a ledger by its name, if it exists.
+    fn get_ledger_by_name(
+        &self,
+        name: String,
+    ) -> LocalBoxFuture<'static, Result<Option<Ledger>, DatabaseError>>;
+
+    /// Get the ledgers a user is a member of.
+    fn get_ledgers_for_user(
+        &self,
+        user_id: String,
+    ) -> LocalBoxFuture<'static, Result<Vec<Ledger>, DatabaseError>>;
+
+    /// Add a user to a ledger. Does nothing if they're already a member.
+    fn add_ledger_member(
+        &self,
+        ledger_id: i64,
+        user_id: String,
+        outbox: Outbox<()>,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>>;
+
+    /// Whether the user is a member of the ledger.
+    fn is_ledger_member(
+        &self,
+        ledger_id: i64,
+        user_id: String,
+    ) -> LocalBoxFuture<'static, Result<bool, DatabaseError>>;
+
+    /// Get a user's balance
     fn get_balance_for_user(
         &self,
+        ledger_id: i64,
         user: String,
-    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>>;
+    ) -> LocalBoxFuture<'static, Result<Money, DatabaseError>>;
+
+    /// Get what each other user owes the user from the transactions between
+    /// them, i.e. the balance is positive if they owe the user and negative
+    /// if the user owes them. Users who are square are left out. Sorted with
+    /// those the user owes most first.
+    fn get_balances_by_counterparty(
+        &self,
+        ledger_id: i64,
+        user_id: String,
+    ) -> LocalBoxFuture<'static, Result<Vec<User>, DatabaseError>>;
 
-    /// Get a map of all users from local user ID to [User] object
+    /// Get what each pair of users owe each other, from the transactions
+    /// between them. Pairs who are square are left out. Sorted with the
+    /// largest debts first.
+    fn get_outstanding_debts(
+        &self,
+        ledger_id: i64,
+    ) -> LocalBoxFuture<'static, Result<Vec<Debt>, DatabaseError>>;
+
+    /// Get a map of all members of the ledger from local user ID to [User]
+    /// object
     fn get_all_users(
         &self,
+        ledger_id: i64,
     ) -> LocalBoxFuture<'static, Result<LinearMap<String, User>, DatabaseError>>;
 
-    /// Commit a new Shaft [Transaction]
+    /// Get the members of the ledger matching the filter, in the order it
+    /// asks for.
+    fn get_users(
+        &self,
+        ledger_id: i64,
+        filter: UserFilter,
+    ) -> LocalBoxFuture<'static, Result<Vec<UserEntry>, DatabaseError>>;
+
+    /// Commit a new Shaft [Transaction] to the ledger, returning its ID.
     fn shaft_user(
         &self,
+        ledger_id: i64,
         transaction: Transaction,
-    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>>;
+        outbox: Outbox<i64>,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>>;
+
+    /// Commit several new Shaft [Transaction]s atomically, returning their
+    /// IDs in order. If any of them fail then none are committed. Rows are
+    /// inserted several at a time in one SQL transaction, so this is much
+    /// faster than calling [Database::shaft_user] for each.
+    fn insert_transactions_batch(
+        &self,
+        ledger_id: i64,
+        transactions: Vec<Transaction>,
+        outbox: Outbox<Vec<i64>>,
+    ) -> LocalBoxFuture<'static, Result<Vec<i64>, DatabaseError>>;
+
+    /// Get a single Shaft transaction by ID
+    fn get_transaction(
+        &self,
+        ledger_id: i64,
+        transaction_id: i64,
+    ) -> LocalBoxFuture<'static, Result<Option<Transaction>, DatabaseError>>;
 
     /// Get a list of the most recent Shaft transactions
     fn get_last_transactions(
         &self,
+        ledger_id: i64,
+        limit: u32,
+    ) -> LocalBoxFuture<'static, Result<Vec<Transaction>, DatabaseError>>;
+
+    /// Get the transactions matching the filter, newest first.
+    fn get_transactions(
+        &self,
+        ledger_id: i64,
+        filter: TransactionFilter,
+    ) -> LocalBoxFuture<'static, Result<Vec<Transaction>, DatabaseError>>;
+
+    /// Get the current revision of a transaction, or None if it doesn't exist.
+    fn get_transaction_revision(
+        &self,
+        ledger_id: i64,
+        transaction_id: i64,
+    ) -> LocalBoxFuture<'static, Result<Option<i64>, DatabaseError>>;
+
+    /// Update the amount and reason of a transaction, returning its new
+    /// revision.
+    ///
+    /// If `expected_revision` is given and doesn't match the current revision
+    /// then the update fails with [DatabaseError::RevisionMismatch].
+    fn update_transaction(
+        &self,
+        ledger_id: i64,
+        transaction_id: i64,
+        expected_revision: Option<i64>,
+        amount: Money,
+        reason: String,
+        outbox: Outbox<i64>,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>>;
+
+    /// Delete a transaction.
+    ///
+    /// If `expected_revision` is given and doesn't match the current revision
+    /// then the delete fails with [DatabaseError::RevisionMismatch].
+    fn delete_transaction(
+        &self,
+        ledger_id: i64,
+        transaction_id: i64,
+        expected_revision: Option<i64>,
+        outbox: Outbox<()>,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>>;
+
+    /// Get the current ledger version, which increases whenever a user or
+    /// transaction changes. This is also the latest sync cursor.
+    fn get_ledger_version(&self) -> LocalBoxFuture<'static, Result<i64, DatabaseError>>;
+
+    /// Get when anything shown on the ledger's pages last changed, to the
+    /// second, including what's only shown to `user_id` if given. Returns
+    /// None if nothing has changed since the database was upgraded to track
+    /// it.
+    fn get_last_modified(
+        &self,
+        ledger_id: i64,
+        user_id: Option<String>,
+    ) -> LocalBoxFuture<'static, Result<Option<chrono::DateTime<chrono::Utc>>, DatabaseError>>;
+
+    /// Get up to `limit` changes to the ledger after the given sync cursor.
+    ///
+    /// The cursor is shared between ledgers, so changes to other ledgers are
+    /// skipped over. Deleted transactions may be from any ledger. A cursor of
+    /// zero always gets every change still in the log.
+    fn get_changes_since(
+        &self,
+        ledger_id: i64,
+        cursor: i64,
         limit: u32,
+    ) -> LocalBoxFuture<'static, Result<LedgerChanges, DatabaseError>>;
+
+    /// Get every Shaft transaction in the ledger, oldest first
+    fn get_all_transactions(
+        &self,
+        ledger_id: i64,
     ) -> LocalBoxFuture<'static, Result<Vec<Transaction>, DatabaseError>>;
+
+    /// Store a new [Attachment], returning its ID.
+    fn add_attachment(
+        &self,
+        attachment: Attachment,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>>;
+
+    /// Store the details suggested for an attachment.
+    fn add_receipt_suggestion(
+        &self,
+        attachment_id: i64,
+        suggestion: ReceiptSuggestion,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>>;
+
+    /// Get the suggestions for all attachments of a transaction, as pairs of
+    /// attachment ID and suggestion.
+    fn get_receipt_suggestions(
+        &self,
+        transaction_id: i64,
+    ) -> LocalBoxFuture<'static, Result<Vec<(i64, ReceiptSuggestion)>, DatabaseError>>;
+
+    /// Get the user's defaults for the shaft form. Users who haven't set any
+    /// get the default, empty, preferences.
+    fn get_user_preferences(
+        &self,
+        user_id: String,
+    ) -> LocalBoxFuture<'static, Result<UserPreferences, DatabaseError>>;
+
+    /// Replace the user's defaults for the shaft form.
+    fn set_user_preferences(
+        &self,
+        user_id: String,
+        preferences: UserPreferences,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>>;
+
+    /// Get everything held about the user, or None if they're unknown.
+    fn export_user(
+        &self,
+        user_id: String,
+    ) -> LocalBoxFuture<'static, Result<Option<UserExport>, DatabaseError>>;
+
+    /// Delete the user's account. Their login identities and tokens are
+    /// removed, and they are renamed to an anonymous user ID everywhere else
+    /// so that the ledger still balances. Returns the anonymous user ID.
+    fn delete_user(
+        &self,
+        user_id: String,
+        outbox: Outbox<String>,
+    ) -> LocalBoxFuture<'static, Result<String, DatabaseError>>;
+
+    /// Stop the user using shaft, e.g. when they move out. Their tokens,
+    /// Slack links and inbound hooks are removed and they can't log in again,
+    /// but their balance and history are kept.
+    fn deactivate_user(
+        &self,
+        user_id: String,
+        outbox: Outbox<()>,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>>;
+
+    /// Get the IDs of deactivated users.
+    fn get_deactivated_users(&self) -> LocalBoxFuture<'static, Result<Vec<String>, DatabaseError>>;
+
+    /// Merge one user into another, e.g. if someone logged in with a second
+    /// GitHub account. Their transactions, attachments, inbound hooks, Slack
+    /// links and ledger memberships move to `into`, then `from` is removed
+    /// along with its login identity and tokens. The audit log is left as it
+    /// was.
+    fn merge_users(
+        &self,
+        from: String,
+        into: String,
+        outbox: Outbox<()>,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>>;
+
+    /// Delete tokens that have expired, and if `issued_before` is given login
+    /// sessions that were issued or last extended before then. Returns how
+    /// many were deleted.
+    fn prune_tokens(
+        &self,
+        issued_before: Option<chrono::DateTime<chrono::Utc>>,
+        outbox: Outbox<u64>,
+    ) -> LocalBoxFuture<'static, Result<u64, DatabaseError>>;
+
+    /// Store a value shared between instances until it expires, replacing
+    /// any value with the same key.
+    fn put_shared_value(
+        &self,
+        key: String,
+        value: String,
+        expires: chrono::DateTime<chrono::Utc>,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>>;
+
+    /// Remove a shared value, returning it if it hadn't expired.
+    fn take_shared_value(
+        &self,
+        key: String,
+    ) -> LocalBoxFuture<'static, Result<Option<String>, DatabaseError>>;
+
+    /// Publish a cache invalidation to the other instances, returning its ID.
+    fn add_invalidation(
+        &self,
+        instance_id: String,
+        payload: String,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>>;
+
+    /// Get the invalidations with IDs greater than `after`, oldest first.
+    fn get_invalidations(
+        &self,
+        after: i64,
+    ) -> LocalBoxFuture<'static, Result<Vec<StoredInvalidation>, DatabaseError>>;
+
+    /// Get the ID of the latest invalidation, or 0 if there are none.
+    fn get_last_invalidation_id(&self) -> LocalBoxFuture<'static, Result<i64, DatabaseError>>;
+
+    /// Delete expired shared values and invalidations published before the
+    /// given time. Returns how many rows were deleted.
+    fn prune_shared_state(
+        &self,
+        published_before: chrono::DateTime<chrono::Utc>,
+    ) -> LocalBoxFuture<'static, Result<u64, DatabaseError>>;
+
+    /// Add deliveries to the outbox in one transaction, returning their IDs
+    /// in order. They're first tried at `next_attempt`.
+    fn add_outbox_messages(
+        &self,
+        messages: Vec<NewOutboxMessage>,
+        next_attempt: chrono::DateTime<chrono::Utc>,
+    ) -> LocalBoxFuture<'static, Result<Vec<i64>, DatabaseError>>;
+
+    /// Claim up to `limit` pending outbox messages that are due, oldest
+    /// first, by pushing their next attempt back to `lease_until`. If the
+    /// claimant dies before recording an attempt they're retried after that.
+    fn claim_outbox_messages(
+        &self,
+        lease_until: chrono::DateTime<chrono::Utc>,
+        limit: u32,
+    ) -> LocalBoxFuture<'static, Result<Vec<OutboxMessage>, DatabaseError>>;
+
+    /// Record an attempt to deliver an outbox message, updating its status.
+    fn record_outbox_attempt(
+        &self,
+        id: i64,
+        attempt: OutboxAttempt,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>>;
+
+    /// Get the outbox message with the given ID, if it exists.
+    fn get_outbox_message(
+        &self,
+        id: i64,
+    ) -> LocalBoxFuture<'static, Result<Option<OutboxMessage>, DatabaseError>>;
+
+    /// Get the recorded attempts to deliver the given outbox messages, oldest
+    /// first.
+    fn get_outbox_attempts(
+        &self,
+        outbox_ids: Vec<i64>,
+    ) -> LocalBoxFuture<'static, Result<Vec<OutboxAttemptRecord>, DatabaseError>>;
+
+    /// Get the outbox messages matching the filter, newest first.
+    fn get_outbox_messages(
+        &self,
+        filter: OutboxFilter,
+    ) -> LocalBoxFuture<'static, Result<Vec<OutboxMessage>, DatabaseError>>;
+
+    /// Delete outbox messages that were delivered or given up on before the
+    /// given time, along with their attempts. Returns how many messages were
+    /// deleted.
+    fn prune_outbox(
+        &self,
+        finished_before: chrono::DateTime<chrono::Utc>,
+    ) -> LocalBoxFuture<'static, Result<u64, DatabaseError>>;
+
+    /// Delete changes from the sync log that are superseded by a later change
+    /// to the same row, or are to rows that no longer exist. Cursors from
+    /// before the latter then require a resync. Returns how many were
+    /// deleted.
+    fn compact_changes(&self) -> LocalBoxFuture<'static, Result<u64, DatabaseError>>;
+
+    /// Rebuild the database to reclaim unused space, and update the
+    /// statistics the query planner uses.
+    fn vacuum(&self) -> LocalBoxFuture<'static, Result<(), DatabaseError>>;
+
+    /// Find rows that reference users that don't exist, e.g. transactions
+    /// with an unknown shafter or tokens of a removed user. If `repair` is set
+    /// they're fixed in the same transaction. Returns the problems found.
+    fn check_integrity(
+        &self,
+        repair: bool,
+    ) -> LocalBoxFuture<'static, Result<Vec<IntegrityProblem>, DatabaseError>>;
+
+    /// Get the version of the database's schema, i.e. how many migrations
+    /// have been applied.
+    fn get_schema_version(&self) -> LocalBoxFuture<'static, Result<i64, DatabaseError>>;
+
+    /// Get current usage of the connection pool and operation queue.
+    fn pool_stats(&self) -> PoolStats;
+
+    /// The name of the implementation, as used in the `database_backend`
+    /// setting.
+    fn backend_name(&self) -> &'static str;
+}
+
+/// How many transactions [Database::insert_transactions_batch] inserts per
+/// statement. Keeps the number of parameters under SQLite's default limit of
+/// 999.
+const TRANSACTIONS_PER_INSERT: usize = 100;
+
+/// An `INSERT` of `rows` transactions, each taking the shafter, shaftee,
+/// amount, time, reason, kind and ledger as positional parameters.
+fn insert_transactions_sql(rows: usize) -> String {
+    let mut sql = String::from(
+        "INSERT INTO transactions (shafter, shaftee, amount, time_sec, reason, kind, ledger_id) VALUES ",
+    );
+    for idx in 0..rows {
+        if idx > 0 {
+            sql.push_str(", ");
+        }
+        sql.push_str("(?, ?, ?, ?, ?, ?, ?)");
+    }
+    sql
+}
+
+/// Turn a prefix into a `LIKE` pattern matching it, escaping wildcards with
+/// `\`.
+fn like_prefix(prefix: &str) -> String {
+    let mut pattern = String::with_capacity(prefix.len() + 1);
+    for c in prefix.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    pattern
+}
+
+/// Format quick amounts for the `user_preferences` table.
+fn format_quick_amounts(amounts: &[Money]) -> String {
+    let amounts: Vec<_> = amounts
+        .iter()
+        .map(|amount| amount.minor_units().to_string())
+        .collect();
+    amounts.join(",")
+}
+
+/// Build preferences from a `user_preferences` row. Anything that doesn't
+/// parse is ignored rather than failing the page.
+fn parse_preferences(
+    default_counterparty: Option<String>,
+    default_currency: Option<String>,
+    quick_amounts: &str,
+    currency: Currency,
+) -> UserPreferences {
+    UserPreferences {
+        default_counterparty,
+        default_currency: default_currency.and_then(|code| code.parse().ok()),
+        quick_amounts: quick_amounts
+            .split(',')
+            .filter_map(|amount| amount.parse().ok())
+            .map(|amount| Money::new(amount, currency))
+            .collect(),
+    }
+}
+
+/// Generate the user ID that replaces a deleted user's.
+fn new_anonymous_user_id() -> String {
+    let suffix: String = thread_rng().sample_iter(&Alphanumeric).take(16).collect();
+    format!("deleted-{}", suffix)
 }
 
 /// Error using database.
@@ -108,6 +1317,20 @@ pub enum DatabaseError {
         backtrace: Backtrace,
     },
 
+    /// Too many database operations are already queued.
+    #[snafu(display("Database is busy"))]
+    Busy,
+
+    /// The blocking task running the operation failed, e.g. panicked.
+    #[snafu(display("Database task failed: {}", source))]
+    BlockingTaskError { source: tokio::task::JoinError },
+
+    /// Error from a database backend that doesn't have its own variant.
+    #[snafu(display("Database error: {}", source))]
+    BackendError {
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
     /// SQLite error.
     #[snafu(display("Sqlite error: {}", source))]
     SqliteError {
@@ -115,16 +1338,35 @@ pub enum DatabaseError {
         backtrace: Backtrace,
     },
 
-    /// Postgres error.
-    #[snafu(display("Postgres error: {}", source))]
-    PostgresError {
-        source: ::postgres::Error,
-        backtrace: Backtrace,
-    },
-
     /// One of the users is unknown.
     #[snafu(display("Unknown user: {}", user_id))]
     UnknownUser { user_id: String },
+
+    /// The user has been deactivated.
+    #[snafu(display("User {} has been deactivated", user_id))]
+    UserDeactivated { user_id: String },
+
+    /// A ledger with the name already exists.
+    #[snafu(display("Ledger {} already exists", name))]
+    LedgerExists { name: String },
+
+    /// The transaction is unknown.
+    #[snafu(display("Unknown transaction: {}", transaction_id))]
+    UnknownTransaction { transaction_id: i64 },
+
+    /// The transaction has been changed since the revision the caller
+    /// expected.
+    #[snafu(display(
+        "Transaction {} is at revision {}, not {}",
+        transaction_id,
+        revision,
+        expected_revision
+    ))]
+    RevisionMismatch {
+        transaction_id: i64,
+        revision: i64,
+        expected_revision: i64,
+    },
 }
 
 /// Serialize time into timestamp.
@@ -134,3 +1376,17 @@ where
 {
     serializer.serialize_i64(date.timestamp())
 }
+
+/// Serialize an optional time into a timestamp or null.
+fn serialize_optional_time<S>(
+    date: &Option<chrono::DateTime<chrono::Utc>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    match date {
+        Some(date) => serializer.serialize_some(&date.timestamp()),
+        None => serializer.serialize_none(),
+    }
+}
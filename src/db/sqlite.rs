@@ -1,47 +1,157 @@
 use chrono;
 use chrono::TimeZone;
-use futures::future::LocalBoxFuture;
-use futures::{compat::Future01CompatExt, FutureExt};
-use futures_cpupool::CpuPool;
+use futures::future::{self, LocalBoxFuture};
+use futures::FutureExt;
 use linear_map::LinearMap;
 use r2d2;
 use r2d2_sqlite::SqliteConnectionManager;
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
 use rusqlite;
-use rusqlite::params;
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter};
 use snafu::ResultExt;
 
+use std::collections::BTreeSet;
 use std::path::Path;
-
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
+
+use crate::db::integrity::INTEGRITY_CHECKS;
+use crate::db::migrations::{plan_uses_index, EXPECTED_INDEXES, SQLITE_MIGRATIONS};
+use crate::db::{
+    format_quick_amounts, insert_transactions_sql, like_prefix, new_anonymous_user_id,
+    parse_preferences, ApiToken, Attachment, AttachmentInfo, AuditEntry, AuditFilter,
+    BlockingTaskError, ConnectionPoolError, Database, DatabaseError, Debt, InboundHook,
+    IntegrityProblem, Ledger, LedgerChanges, NewOutboxMessage, Outbox, OutboxAttempt,
+    OutboxAttemptRecord, OutboxFilter, OutboxKind, OutboxMessage, OutboxStatus, PoolStats,
+    ReceiptSuggestion, Scope, SecurityEvent, SecurityEventFilter, SlackLink, SqliteError,
+    StoredInvalidation, TokenUser, Transaction, TransactionFilter, TransactionKind, User,
+    UserEntry, UserExport, UserFilter, UserPreferences, UserSort, DEFAULT_LEDGER_ID,
+    DELETED_USER_DISPLAY_NAME, TRANSACTIONS_PER_INSERT,
+};
+use crate::money::{Currency, Money};
+use crate::settings::{DatabasePoolSettings, SqliteSettings};
+
+/// How many prepared statements each connection keeps cached. Must be more
+/// than the number of distinct queries prepared with `prepare_cached`, of
+/// which there are about 70, so that none get evicted.
+const STATEMENT_CACHE_CAPACITY: usize = 128;
+
+/// Sets up each new pooled connection, applying the configured pragmas.
+#[derive(Debug)]
+struct ConnectionCustomizer {
+    settings: SqliteSettings,
+}
 
-use crate::db::{ConnectionPoolError, Database, DatabaseError, SqliteError, Transaction, User};
+impl r2d2::CustomizeConnection<rusqlite::Connection, rusqlite::Error> for ConnectionCustomizer {
+    fn on_acquire(&self, conn: &mut rusqlite::Connection) -> Result<(), rusqlite::Error> {
+        conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
+        conn.busy_timeout(Duration::from_millis(self.settings.busy_timeout_ms))?;
+        conn.execute_batch(&format!(
+            "PRAGMA journal_mode = {}; PRAGMA synchronous = {}; PRAGMA foreign_keys = {};",
+            self.settings.journal_mode.as_str(),
+            self.settings.synchronous.as_str(),
+            if self.settings.foreign_keys {
+                "ON"
+            } else {
+                "OFF"
+            },
+        ))
+    }
+}
 
 /// An implementation of [Database] using sqlite.Database
 ///
-/// Safe to clone as the thread and connection pools will be shared.
+/// Safe to clone as the connection pool and queue limits will be shared.
 #[derive(Clone)]
 pub struct SqliteDatabase {
-    /// Thread pool used to do database operations.
-    cpu_pool: CpuPool,
     /// SQLite connection pool.
     db_pool: Arc<r2d2::Pool<SqliteConnectionManager>>,
+    /// Number of database operations queued or running.
+    in_flight: Arc<AtomicUsize>,
+    /// The maximum number of database operations that may be queued or
+    /// running before we start rejecting new ones.
+    max_in_flight: usize,
+    /// The currency amounts are stored in.
+    currency: Currency,
 }
 
 impl SqliteDatabase {
-    /// Create new instance with given path. If file does not exist a new
-    /// database is created.
+    /// Create new instance with given path and default settings. If file does
+    /// not exist a new database is created.
     pub fn with_path<P: AsRef<Path>>(path: P) -> SqliteDatabase {
+        SqliteDatabase::with_settings(
+            path,
+            &DatabasePoolSettings::default(),
+            &SqliteSettings::default(),
+        )
+    }
+
+    /// Create new instance with given path, pool settings and connection
+    /// pragmas. If file does not exist a new database is created.
+    ///
+    /// Each connection to `:memory:` gets its own database, so in memory
+    /// databases only ever use one connection.
+    pub fn with_settings<P: AsRef<Path>>(
+        path: P,
+        settings: &DatabasePoolSettings,
+        sqlite_settings: &SqliteSettings,
+    ) -> SqliteDatabase {
+        let max_connections = if path.as_ref() == Path::new(":memory:") {
+            1
+        } else {
+            settings.max_connections
+        };
+
         let manager = SqliteConnectionManager::file(path);
-        let pool = r2d2::Pool::new(manager).unwrap();
+        let pool = r2d2::Pool::builder()
+            .max_size(max_connections)
+            .connection_timeout(Duration::from_secs(settings.connection_timeout_secs))
+            .connection_customizer(Box::new(ConnectionCustomizer {
+                settings: sqlite_settings.clone(),
+            }))
+            .build(manager)
+            .unwrap();
 
         SqliteDatabase {
-            cpu_pool: CpuPool::new_num_cpus(),
             db_pool: Arc::new(pool),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            max_in_flight: settings.max_queued,
+            currency: Currency::default(),
         }
     }
 
+    /// Set the currency amounts are stored in. Defaults to GBP.
+    pub fn with_currency(mut self, currency: Currency) -> SqliteDatabase {
+        self.currency = currency;
+        self
+    }
+
+    /// Runs the given blocking database work on the tokio blocking thread
+    /// pool.
+    ///
+    /// Fails immediately with [DatabaseError::Busy] if there are already too
+    /// many operations queued, rather than letting requests pile up.
+    fn spawn<F, T>(&self, f: F) -> LocalBoxFuture<'static, Result<T, DatabaseError>>
+    where
+        F: FnOnce() -> Result<T, DatabaseError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let guard = match InFlightGuard::acquire(&self.in_flight, self.max_in_flight) {
+            Some(guard) => guard,
+            None => return future::err(DatabaseError::Busy).boxed_local(),
+        };
+
+        tokio::task::spawn_blocking(move || {
+            let _guard = guard;
+            f()
+        })
+        .map(|res| res.context(BlockingTaskError).and_then(|res| res))
+        .boxed_local()
+    }
+
     /// Runs the given statements synchronously
     pub fn run_statements(&self, stmts: &str) -> Result<(), DatabaseError> {
         let conn = self.db_pool.get().context(ConnectionPoolError)?;
@@ -50,6 +160,60 @@ impl SqliteDatabase {
 
         Ok(())
     }
+
+    /// Synchronously brings the database schema up to date, applying any
+    /// outstanding migrations.
+    pub fn migrate(&self) -> Result<(), DatabaseError> {
+        let mut conn = self.db_pool.get().context(ConnectionPoolError)?;
+
+        let current_version: i64 = conn
+            .query_row("PRAGMA user_version", params![], |row| row.get(0))
+            .context(SqliteError)?;
+
+        for (idx, migration) in SQLITE_MIGRATIONS
+            .iter()
+            .enumerate()
+            .skip(current_version as usize)
+        {
+            let txn = conn.transaction().context(SqliteError)?;
+
+            txn.execute_batch(migration).context(SqliteError)?;
+            txn.execute_batch(&format!("PRAGMA user_version = {}", idx + 1))
+                .context(SqliteError)?;
+
+            txn.commit().context(SqliteError)?;
+        }
+
+        Ok(())
+    }
+
+    /// Synchronously checks that the queries that should be using an index
+    /// are, returning the indexes that look to be missing.
+    pub fn missing_indexes(&self) -> Result<Vec<&'static str>, DatabaseError> {
+        let conn = self.db_pool.get().context(ConnectionPoolError)?;
+
+        let mut missing = Vec::new();
+        for (index, query) in EXPECTED_INDEXES {
+            let mut stmt = conn
+                .prepare(&format!("EXPLAIN QUERY PLAN {}", query))
+                .context(SqliteError)?;
+
+            let details: Result<Vec<String>, _> = stmt
+                .query_map(params![], |row| row.get(3))
+                .context(SqliteError)?
+                .collect();
+
+            if !details
+                .context(SqliteError)?
+                .iter()
+                .any(|detail| plan_uses_index(detail))
+            {
+                missing.push(*index);
+            }
+        }
+
+        Ok(missing)
+    }
 }
 
 impl Database for SqliteDatabase {
@@ -59,321 +223,2991 @@ impl Database for SqliteDatabase {
     ) -> LocalBoxFuture<'static, Result<Option<String>, DatabaseError>> {
         let db_pool = self.db_pool.clone();
 
-        self.cpu_pool
-            .spawn_fn(move || -> Result<_, DatabaseError> {
-                let conn = db_pool.get().context(ConnectionPoolError)?;
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
 
-                let row = conn
-                    .query_row(
-                        "SELECT user_id FROM github_users WHERE github_id = $1",
-                        &[&github_user_id],
-                        |row| row.get(0),
-                    )
-                    .map(Some)
-                    .or_else(|err| {
-                        if let rusqlite::Error::QueryReturnedNoRows = err {
-                            Ok(None)
-                        } else {
-                            Err(err)
-                        }
-                    })
-                    .context(SqliteError)?;
+            let row = conn
+                .prepare_cached("SELECT user_id FROM github_users WHERE github_id = $1")
+                .context(SqliteError)?
+                .query_row(&[&github_user_id], |row| row.get(0))
+                .map(Some)
+                .or_else(|err| {
+                    if let rusqlite::Error::QueryReturnedNoRows = err {
+                        Ok(None)
+                    } else {
+                        Err(err)
+                    }
+                })
+                .context(SqliteError)?;
 
-                Ok(row)
-            })
-            .compat()
-            .boxed()
+            Ok(row)
+        })
     }
 
     fn add_user_by_github_id(
         &self,
         github_user_id: String,
         display_name: String,
+        outbox: Outbox<String>,
     ) -> LocalBoxFuture<'static, Result<String, DatabaseError>> {
         let db_pool = self.db_pool.clone();
 
-        self.cpu_pool
-            .spawn_fn(move || -> Result<_, DatabaseError> {
-                let conn = db_pool.get().context(ConnectionPoolError)?;
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let mut conn = db_pool.get().context(ConnectionPoolError)?;
+            let txn = conn.transaction().context(SqliteError)?;
 
-                conn.execute(
-                    "INSERT INTO github_users (user_id, github_id)
+            txn.prepare_cached(
+                "INSERT INTO github_users (user_id, github_id)
                 VALUES ($1, $1)",
-                    &[&github_user_id],
-                )
-                .context(SqliteError)?;
+            )
+            .context(SqliteError)?
+            .execute(&[&github_user_id])
+            .context(SqliteError)?;
+
+            txn.prepare_cached(
+                "INSERT INTO users (user_id, display_name)
+                VALUES ($1, $2)",
+            )
+            .context(SqliteError)?
+            .execute(&[&github_user_id, &display_name])
+            .context(SqliteError)?;
 
-                conn.execute(
-                    "INSERT INTO users (user_id, display_name)
+            txn.prepare_cached(
+                "INSERT INTO ledger_members (ledger_id, user_id)
                 VALUES ($1, $2)",
-                    &[&github_user_id, &display_name],
+            )
+            .context(SqliteError)?
+            .execute(params![DEFAULT_LEDGER_ID, github_user_id])
+            .context(SqliteError)?;
+
+            add_change_outbox_txn(&txn, &outbox, &github_user_id)?;
+            txn.commit().context(SqliteError)?;
+
+            Ok(github_user_id)
+        })
+    }
+
+    fn get_user_by_slack_id(
+        &self,
+        team_id: String,
+        slack_user_id: String,
+    ) -> LocalBoxFuture<'static, Result<Option<String>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
+
+            let row = conn
+                .prepare_cached(
+                    "SELECT user_id FROM slack_users WHERE team_id = $1 AND slack_user_id = $2",
                 )
+                .context(SqliteError)?
+                .query_row(&[&team_id, &slack_user_id], |row| row.get(0))
+                .map(Some)
+                .or_else(|err| {
+                    if let rusqlite::Error::QueryReturnedNoRows = err {
+                        Ok(None)
+                    } else {
+                        Err(err)
+                    }
+                })
                 .context(SqliteError)?;
 
-                Ok(github_user_id)
-            })
-            .compat()
-            .boxed()
+            Ok(row)
+        })
+    }
+
+    fn link_slack_user(
+        &self,
+        team_id: String,
+        slack_user_id: String,
+        user_id: String,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
+
+            conn.prepare_cached(
+                "INSERT OR REPLACE INTO slack_users (team_id, slack_user_id, user_id)
+                VALUES ($1, $2, $3)",
+            )
+            .context(SqliteError)?
+            .execute(&[&team_id, &slack_user_id, &user_id])
+            .context(SqliteError)?;
+
+            Ok(())
+        })
     }
 
     fn create_token_for_user(
         &self,
         user_id: String,
+        scopes: Vec<Scope>,
+        expires: chrono::DateTime<chrono::Utc>,
+        outbox: Outbox<String>,
     ) -> LocalBoxFuture<'static, Result<String, DatabaseError>> {
         let db_pool = self.db_pool.clone();
 
-        self.cpu_pool
-            .spawn_fn(move || -> Result<_, DatabaseError> {
-                let conn = db_pool.get().context(ConnectionPoolError)?;
-
-                let token: String = thread_rng().sample_iter(&Alphanumeric).take(32).collect();
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let mut conn = db_pool.get().context(ConnectionPoolError)?;
+            let txn = conn.transaction().context(SqliteError)?;
 
-                conn.execute(
-                    "INSERT INTO tokens (user_id, token) VALUES ($1, $2)",
-                    &[&user_id, &token],
-                )
+            let deactivated: bool = txn
+                .prepare_cached("SELECT COUNT(*) > 0 FROM users WHERE user_id = $1 AND NOT active")
+                .context(SqliteError)?
+                .query_row(&[&user_id], |row| row.get(0))
                 .context(SqliteError)?;
+            if deactivated {
+                return Err(DatabaseError::UserDeactivated { user_id });
+            }
 
-                Ok(token)
-            })
-            .compat()
-            .boxed()
+            let token: String = thread_rng().sample_iter(&Alphanumeric).take(32).collect();
+
+            txn.prepare_cached(
+                r#"
+                INSERT INTO tokens (user_id, token, scopes, expires_sec, issued_sec)
+                VALUES ($1, $2, $3, $4, $5)
+                "#,
+            )
+            .context(SqliteError)?
+            .execute(params![
+                user_id,
+                token,
+                format_scopes(&scopes),
+                expires.timestamp(),
+                chrono::Utc::now().timestamp()
+            ])
+            .context(SqliteError)?;
+
+            add_change_outbox_txn(&txn, &outbox, &token)?;
+            txn.commit().context(SqliteError)?;
+
+            Ok(token)
+        })
     }
 
     fn delete_token(&self, token: String) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
         let db_pool = self.db_pool.clone();
 
-        self.cpu_pool
-            .spawn_fn(move || -> Result<_, DatabaseError> {
-                let conn = db_pool.get().context(ConnectionPoolError)?;
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
 
-                conn.execute("DELETE FROM tokens WHERE token = $1", &[&token])
-                    .context(SqliteError)?;
+            conn.prepare_cached("DELETE FROM tokens WHERE token = $1")
+                .context(SqliteError)?
+                .execute(&[&token])
+                .context(SqliteError)?;
 
-                Ok(())
-            })
-            .compat()
-            .boxed()
+            Ok(())
+        })
+    }
+
+    fn refresh_token(
+        &self,
+        token: String,
+        issued: chrono::DateTime<chrono::Utc>,
+        expires: chrono::DateTime<chrono::Utc>,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
+
+            // SQLite numbers `$N` params in the order they first appear.
+            conn.prepare_cached(
+                "UPDATE tokens SET issued_sec = $1, expires_sec = $2 WHERE token = $3",
+            )
+            .context(SqliteError)?
+            .execute(params![issued.timestamp(), expires.timestamp(), token])
+            .context(SqliteError)?;
+
+            Ok(())
+        })
     }
 
     fn get_user_from_token(
         &self,
         token: String,
-    ) -> LocalBoxFuture<'static, Result<Option<User>, DatabaseError>> {
+    ) -> LocalBoxFuture<'static, Result<Option<TokenUser>, DatabaseError>> {
         let db_pool = self.db_pool.clone();
 
-        self.cpu_pool
-            .spawn_fn(move || -> Result<_, DatabaseError> {
-                let conn = db_pool.get().context(ConnectionPoolError)?;
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
 
-                let row = conn
-                    .query_row(
-                        r#"
-                SELECT user_id, display_name, COALESCE(balance, 0)
+            let row = conn
+                .prepare_cached(
+                    r#"
+                SELECT user_id, display_name, scopes, issued_sec, expires_sec
                 FROM tokens
                 INNER JOIN users USING (user_id)
-                LEFT JOIN (
-                    SELECT user_id, SUM(amount) as balance
-                    FROM (
-                        SELECT shafter AS user_id, SUM(amount) AS amount
-                        FROM transactions GROUP BY shafter
-                        UNION ALL
-                        SELECT shaftee AS user_id, -SUM(amount) AS amount
-                        FROM transactions GROUP BY shaftee
-                    ) t GROUP BY user_id
+                WHERE token = $1 AND (expires_sec IS NULL OR expires_sec > $2)
+                "#,
                 )
-                USING (user_id)
-                WHERE token = $1
+                .context(SqliteError)?
+                .query_row(params![token, chrono::Utc::now().timestamp()], |row| {
+                    let scopes: String = row.get(2)?;
+                    let issued: Option<i64> = row.get(3)?;
+                    let expires: Option<i64> = row.get(4)?;
+                    Ok(TokenUser {
+                        user_id: row.get(0)?,
+                        display_name: row.get(1)?,
+                        scopes: parse_scopes(&scopes),
+                        issued: issued.map(|secs| chrono::Utc.timestamp(secs, 0)),
+                        expires: expires.map(|secs| chrono::Utc.timestamp(secs, 0)),
+                    })
+                })
+                .map(Some)
+                .or_else(|err| {
+                    if let rusqlite::Error::QueryReturnedNoRows = err {
+                        Ok(None)
+                    } else {
+                        Err(err)
+                    }
+                })
+                .context(SqliteError)?;
+
+            Ok(row)
+        })
+    }
+
+    fn get_user_id_for_token(
+        &self,
+        token: String,
+    ) -> LocalBoxFuture<'static, Result<Option<String>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
+
+            let row = conn
+                .prepare_cached(
+                    r#"
+                SELECT user_id FROM tokens
+                WHERE token = $1 AND (expires_sec IS NULL OR expires_sec > $2)
                 "#,
-                        &[&token],
-                        |row| {
-                            Ok(User {
-                                user_id: row.get(0)?,
-                                display_name: row.get(1)?,
-                                balance: row.get(2)?,
-                            })
-                        },
-                    )
-                    .map(Some)
-                    .or_else(|err| {
-                        if let rusqlite::Error::QueryReturnedNoRows = err {
-                            Ok(None)
-                        } else {
-                            Err(err)
-                        }
+                )
+                .context(SqliteError)?
+                .query_row(params![token, chrono::Utc::now().timestamp()], |row| {
+                    row.get(0)
+                })
+                .map(Some)
+                .or_else(|err| {
+                    if let rusqlite::Error::QueryReturnedNoRows = err {
+                        Ok(None)
+                    } else {
+                        Err(err)
+                    }
+                })
+                .context(SqliteError)?;
+
+            Ok(row)
+        })
+    }
+
+    fn create_api_token(
+        &self,
+        user_id: String,
+        name: String,
+        scopes: Vec<Scope>,
+        outbox: Outbox<(i64, String)>,
+    ) -> LocalBoxFuture<'static, Result<(i64, String), DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let mut conn = db_pool.get().context(ConnectionPoolError)?;
+            let txn = conn.transaction().context(SqliteError)?;
+
+            let token: String = thread_rng().sample_iter(&Alphanumeric).take(32).collect();
+
+            txn.prepare_cached(
+                "INSERT INTO tokens (user_id, token, scopes, name) VALUES ($1, $2, $3, $4)",
+            )
+            .context(SqliteError)?
+            .execute(params![user_id, token, format_scopes(&scopes), name])
+            .context(SqliteError)?;
+
+            let created = (txn.last_insert_rowid(), token);
+            add_change_outbox_txn(&txn, &outbox, &created)?;
+            txn.commit().context(SqliteError)?;
+
+            Ok(created)
+        })
+    }
+
+    fn get_api_tokens(
+        &self,
+        user_id: String,
+    ) -> LocalBoxFuture<'static, Result<Vec<ApiToken>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
+
+            let mut stmt = conn
+                .prepare_cached(
+                    r#"
+                        SELECT id, name, scopes FROM tokens
+                        WHERE user_id = $1 AND name IS NOT NULL
+                        ORDER BY id
+                        "#,
+                )
+                .context(SqliteError)?;
+
+            let rows: Result<Vec<_>, _> = stmt
+                .query_map(&[&user_id], |row| {
+                    let scopes: String = row.get(2)?;
+                    Ok(ApiToken {
+                        id: row.get(0)?,
+                        name: row.get(1)?,
+                        scopes: parse_scopes(&scopes),
                     })
-                    .context(SqliteError)?;
+                })
+                .context(SqliteError)?
+                .collect();
 
-                Ok(row)
-            })
-            .compat()
-            .boxed()
+            Ok(rows.context(SqliteError)?)
+        })
     }
 
-    fn get_balance_for_user(
+    fn create_inbound_hook(
         &self,
-        user: String,
-    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>> {
+        user_id: String,
+        name: String,
+        outbox: Outbox<(i64, String)>,
+    ) -> LocalBoxFuture<'static, Result<(i64, String), DatabaseError>> {
         let db_pool = self.db_pool.clone();
 
-        self.cpu_pool
-            .spawn_fn(move || -> Result<_, DatabaseError> {
-                let conn = db_pool.get().context(ConnectionPoolError)?;
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let mut conn = db_pool.get().context(ConnectionPoolError)?;
+            let txn = conn.transaction().context(SqliteError)?;
 
-                let row = conn
-                    .query_row(
-                        r#"SELECT (
-                    SELECT COALESCE(SUM(amount), 0)
-                    FROM transactions
-                    WHERE shafter = $1
-                ) - (
-                    SELECT COALESCE(SUM(amount), 0)
-                    FROM transactions
-                    WHERE shaftee = $1
-                )"#,
-                        &[&user],
-                        |row| row.get(0),
-                    )
-                    .context(SqliteError)?;
+            let hook_id: String = thread_rng().sample_iter(&Alphanumeric).take(32).collect();
 
-                Ok(row)
-            })
-            .compat()
-            .boxed()
+            txn.prepare_cached(
+                "INSERT INTO inbound_hooks (hook_id, user_id, name, created_sec)
+                VALUES ($1, $2, $3, $4)",
+            )
+            .context(SqliteError)?
+            .execute(params![
+                hook_id,
+                user_id,
+                name,
+                chrono::Utc::now().timestamp()
+            ])
+            .context(SqliteError)?;
+
+            let created = (txn.last_insert_rowid(), hook_id);
+            add_change_outbox_txn(&txn, &outbox, &created)?;
+            txn.commit().context(SqliteError)?;
+
+            Ok(created)
+        })
     }
 
-    fn get_all_users(
+    fn get_inbound_hooks(
         &self,
-    ) -> LocalBoxFuture<'static, Result<LinearMap<String, User>, DatabaseError>> {
+        user_id: String,
+    ) -> LocalBoxFuture<'static, Result<Vec<InboundHook>, DatabaseError>> {
         let db_pool = self.db_pool.clone();
 
-        self.cpu_pool
-            .spawn_fn(move || -> Result<_, DatabaseError> {
-                let conn = db_pool.get().context(ConnectionPoolError)?;
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
 
-                let mut stmt = conn
-                    .prepare(
-                        r#"
-                SELECT user_id, display_name, COALESCE(balance, 0) AS balance
-                FROM users
-                LEFT JOIN (
-                    SELECT user_id, SUM(amount) as balance
-                    FROM (
-                        SELECT shafter AS user_id, SUM(amount) AS amount
-                        FROM transactions GROUP BY shafter
-                        UNION ALL
-                        SELECT shaftee AS user_id, -SUM(amount) AS amount
-                        FROM transactions GROUP BY shaftee
-                    ) t GROUP BY user_id
+            let mut stmt = conn
+                .prepare_cached(
+                    "SELECT id, name, created_sec FROM inbound_hooks WHERE user_id = $1 ORDER BY id",
                 )
-                USING (user_id)
-                ORDER BY balance ASC
-                "#,
-                    )
-                    .context(SqliteError)?;
+                .context(SqliteError)?;
 
-                let rows: Result<LinearMap<String, User>, _> = stmt
-                    .query_map(params![], |row| {
-                        Ok((
-                            row.get(0)?,
-                            User {
-                                user_id: row.get(0)?,
-                                display_name: row.get(1)?,
-                                balance: row.get(2)?,
-                            },
-                        ))
+            let rows: Result<Vec<_>, _> = stmt
+                .query_map(&[&user_id], |row| {
+                    Ok(InboundHook {
+                        id: row.get(0)?,
+                        name: row.get(1)?,
+                        created: chrono::Utc.timestamp(row.get(2)?, 0),
                     })
-                    .context(SqliteError)?
-                    .collect();
+                })
+                .context(SqliteError)?
+                .collect();
 
-                Ok(rows.context(SqliteError)?)
-            })
-            .compat()
-            .boxed()
+            Ok(rows.context(SqliteError)?)
+        })
     }
 
-    fn shaft_user(
+    fn delete_inbound_hook(
         &self,
-        transaction: Transaction,
-    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        user_id: String,
+        id: i64,
+        outbox: Outbox<bool>,
+    ) -> LocalBoxFuture<'static, Result<bool, DatabaseError>> {
         let db_pool = self.db_pool.clone();
 
-        self.cpu_pool
-            .spawn_fn(move || -> Result<_, DatabaseError> {
-                let conn = db_pool.get().context(ConnectionPoolError)?;
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let mut conn = db_pool.get().context(ConnectionPoolError)?;
+            let txn = conn.transaction().context(SqliteError)?;
 
-                match conn.query_row(
-                    "SELECT user_id FROM users WHERE user_id = $1",
-                    &[&transaction.shaftee],
-                    |_row| Ok(()),
-                ) {
-                    Ok(_) => (),
-                    Err(rusqlite::Error::QueryReturnedNoRows) => {
-                        return Err(DatabaseError::UnknownUser {
-                            user_id: transaction.shaftee,
-                        })
+            let deleted = txn
+                .prepare_cached("DELETE FROM inbound_hooks WHERE id = $1 AND user_id = $2")
+                .context(SqliteError)?
+                .execute(params![id, user_id])
+                .context(SqliteError)?;
+
+            let deleted = deleted > 0;
+            add_change_outbox_txn(&txn, &outbox, &deleted)?;
+            txn.commit().context(SqliteError)?;
+
+            Ok(deleted)
+        })
+    }
+
+    fn get_user_for_inbound_hook(
+        &self,
+        hook_id: String,
+    ) -> LocalBoxFuture<'static, Result<Option<String>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
+
+            let row = conn
+                .prepare_cached("SELECT user_id FROM inbound_hooks WHERE hook_id = $1")
+                .context(SqliteError)?
+                .query_row(&[&hook_id], |row| row.get(0))
+                .map(Some)
+                .or_else(|err| {
+                    if let rusqlite::Error::QueryReturnedNoRows = err {
+                        Ok(None)
+                    } else {
+                        Err(err)
                     }
-                    Err(err) => Err(err).context(SqliteError)?,
-                }
+                })
+                .context(SqliteError)?;
 
-                let mut stmt = conn
-                    .prepare(
-                        "INSERT INTO transactions (shafter, shaftee, amount, time_sec, reason)\
+            Ok(row)
+        })
+    }
+
+    fn add_audit_entry(
+        &self,
+        entry: AuditEntry,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
+
+            let mut stmt = conn
+                .prepare_cached(
+                    "INSERT INTO audit_log (actor, action, target, details, time_sec)\
                      VALUES ($1, $2, $3, $4, $5)",
-                    )
-                    .context(SqliteError)?;
+                )
+                .context(SqliteError)?;
 
-                stmt.execute(params![
-                    &transaction.shafter,
-                    &transaction.shaftee,
-                    &transaction.amount,
-                    &transaction.datetime.timestamp(),
-                    &transaction.reason,
+            let entry_id = stmt
+                .insert(params![
+                    &entry.actor,
+                    &entry.action,
+                    &entry.target,
+                    &entry.details,
+                    &entry.datetime.timestamp(),
                 ])
                 .context(SqliteError)?;
 
-                Ok(())
-            })
-            .compat()
-            .boxed()
+            Ok(entry_id)
+        })
     }
 
-    fn get_last_transactions(
+    fn get_audit_entries(
         &self,
-        limit: u32,
-    ) -> LocalBoxFuture<'static, Result<Vec<Transaction>, DatabaseError>> {
+        filter: AuditFilter,
+    ) -> LocalBoxFuture<'static, Result<Vec<AuditEntry>, DatabaseError>> {
         let db_pool = self.db_pool.clone();
 
-        self.cpu_pool
-            .spawn_fn(move || -> Result<_, DatabaseError> {
-                let conn = db_pool.get().context(ConnectionPoolError)?;
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
 
-                let mut stmt = conn
-                    .prepare(
-                        r#"SELECT shafter, shaftee, amount, time_sec, reason
-                FROM transactions
+            let mut stmt = conn
+                .prepare_cached(
+                    r#"SELECT id, actor, action, target, details, time_sec
+                FROM audit_log
+                WHERE ($1 IS NULL OR actor = $1)
+                    AND ($2 IS NULL OR action = $2)
+                    AND ($3 IS NULL OR time_sec >= $3)
+                    AND ($4 IS NULL OR time_sec < $4)
+                    AND ($5 IS NULL OR id < $5)
+                    AND ($6 IS NULL OR target = $6)
                 ORDER BY id DESC
-                LIMIT $1
+                LIMIT $7
                 "#,
-                    )
-                    .context(SqliteError)?;
+                )
+                .context(SqliteError)?;
 
-                let rows: Result<Vec<_>, _> = stmt
-                    .query_map(&[&limit], |row| {
-                        Ok(Transaction {
-                            shafter: row.get(0)?,
-                            shaftee: row.get(1)?,
-                            amount: row.get(2)?,
-                            datetime: chrono::Utc.timestamp(row.get(3)?, 0),
-                            reason: row.get(4)?,
+            let rows: Result<Vec<_>, _> = stmt
+                .query_map(
+                    params![
+                        filter.actor,
+                        filter.action,
+                        filter.from.map(|from| from.timestamp()),
+                        filter.to.map(|to| to.timestamp()),
+                        filter.before,
+                        filter.target,
+                        filter.limit,
+                    ],
+                    |row| {
+                        Ok(AuditEntry {
+                            id: Some(row.get(0)?),
+                            actor: row.get(1)?,
+                            action: row.get(2)?,
+                            target: row.get(3)?,
+                            details: row.get(4)?,
+                            datetime: chrono::Utc.timestamp(row.get(5)?, 0),
                         })
-                    })
-                    .context(SqliteError)?
-                    .collect();
+                    },
+                )
+                .context(SqliteError)?
+                .collect();
 
-                Ok(rows.context(SqliteError)?)
-            })
-            .compat()
-            .boxed()
+            Ok(rows.context(SqliteError)?)
+        })
+    }
+
+    fn add_security_event(
+        &self,
+        event: SecurityEvent,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
+
+            let mut stmt = conn
+                .prepare_cached(
+                    "INSERT INTO security_events (kind, user_id, ip, details, time_sec)\
+                     VALUES ($1, $2, $3, $4, $5)",
+                )
+                .context(SqliteError)?;
+
+            let event_id = stmt
+                .insert(params![
+                    &event.kind,
+                    &event.user_id,
+                    &event.ip,
+                    &event.details,
+                    &event.datetime.timestamp(),
+                ])
+                .context(SqliteError)?;
+
+            Ok(event_id)
+        })
+    }
+
+    fn get_security_events(
+        &self,
+        filter: SecurityEventFilter,
+    ) -> LocalBoxFuture<'static, Result<Vec<SecurityEvent>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
+
+            let mut stmt = conn
+                .prepare_cached(
+                    r#"SELECT id, kind, user_id, ip, details, time_sec
+                FROM security_events
+                WHERE ($1 IS NULL OR kind = $1)
+                    AND ($2 IS NULL OR id < $2)
+                ORDER BY id DESC
+                LIMIT $3
+                "#,
+                )
+                .context(SqliteError)?;
+
+            let rows: Result<Vec<_>, _> = stmt
+                .query_map(params![filter.kind, filter.before, filter.limit], |row| {
+                    Ok(SecurityEvent {
+                        id: Some(row.get(0)?),
+                        kind: row.get(1)?,
+                        user_id: row.get(2)?,
+                        ip: row.get(3)?,
+                        details: row.get(4)?,
+                        datetime: chrono::Utc.timestamp(row.get(5)?, 0),
+                    })
+                })
+                .context(SqliteError)?
+                .collect();
+
+            Ok(rows.context(SqliteError)?)
+        })
+    }
+
+    fn delete_api_token(
+        &self,
+        user_id: String,
+        token_id: i64,
+        outbox: Outbox<bool>,
+    ) -> LocalBoxFuture<'static, Result<bool, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let mut conn = db_pool.get().context(ConnectionPoolError)?;
+            let txn = conn.transaction().context(SqliteError)?;
+
+            let deleted = txn
+                .prepare_cached(
+                    "DELETE FROM tokens WHERE id = $1 AND user_id = $2 AND name IS NOT NULL",
+                )
+                .context(SqliteError)?
+                .execute(params![token_id, user_id])
+                .context(SqliteError)?;
+
+            let deleted = deleted > 0;
+            add_change_outbox_txn(&txn, &outbox, &deleted)?;
+            txn.commit().context(SqliteError)?;
+
+            Ok(deleted)
+        })
+    }
+
+    fn create_ledger(
+        &self,
+        name: String,
+        display_name: String,
+        outbox: Outbox<Ledger>,
+    ) -> LocalBoxFuture<'static, Result<Ledger, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let mut conn = db_pool.get().context(ConnectionPoolError)?;
+            let txn = conn.transaction().context(SqliteError)?;
+
+            let exists = txn
+                .prepare_cached("SELECT id FROM ledgers WHERE name = $1")
+                .context(SqliteError)?
+                .exists(&[&name])
+                .context(SqliteError)?;
+            if exists {
+                return Err(DatabaseError::LedgerExists { name });
+            }
+
+            let id = txn
+                .prepare_cached("INSERT INTO ledgers (name, display_name) VALUES ($1, $2)")
+                .context(SqliteError)?
+                .insert(&[&name, &display_name])
+                .context(SqliteError)?;
+
+            let ledger = Ledger {
+                id,
+                name,
+                display_name,
+            };
+            add_change_outbox_txn(&txn, &outbox, &ledger)?;
+            txn.commit().context(SqliteError)?;
+
+            Ok(ledger)
+        })
+    }
+
+    fn get_ledger_by_name(
+        &self,
+        name: String,
+    ) -> LocalBoxFuture<'static, Result<Option<Ledger>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
+
+            let row = conn
+                .prepare_cached("SELECT id, name, display_name FROM ledgers WHERE name = $1")
+                .context(SqliteError)?
+                .query_row(&[&name], |row| {
+                    Ok(Ledger {
+                        id: row.get(0)?,
+                        name: row.get(1)?,
+                        display_name: row.get(2)?,
+                    })
+                })
+                .map(Some)
+                .or_else(|err| {
+                    if let rusqlite::Error::QueryReturnedNoRows = err {
+                        Ok(None)
+                    } else {
+                        Err(err)
+                    }
+                })
+                .context(SqliteError)?;
+
+            Ok(row)
+        })
+    }
+
+    fn get_ledgers_for_user(
+        &self,
+        user_id: String,
+    ) -> LocalBoxFuture<'static, Result<Vec<Ledger>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
+
+            let mut stmt = conn
+                .prepare_cached(
+                    r#"SELECT id, name, display_name
+                FROM ledgers
+                JOIN ledger_members ON ledgers.id = ledger_members.ledger_id
+                WHERE user_id = $1
+                ORDER BY id ASC
+                "#,
+                )
+                .context(SqliteError)?;
+
+            let rows: Result<Vec<_>, _> = stmt
+                .query_map(&[&user_id], |row| {
+                    Ok(Ledger {
+                        id: row.get(0)?,
+                        name: row.get(1)?,
+                        display_name: row.get(2)?,
+                    })
+                })
+                .context(SqliteError)?
+                .collect();
+
+            Ok(rows.context(SqliteError)?)
+        })
+    }
+
+    fn add_ledger_member(
+        &self,
+        ledger_id: i64,
+        user_id: String,
+        outbox: Outbox<()>,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let mut conn = db_pool.get().context(ConnectionPoolError)?;
+            let txn = conn.transaction().context(SqliteError)?;
+
+            let known = txn
+                .prepare_cached("SELECT user_id FROM users WHERE user_id = $1")
+                .context(SqliteError)?
+                .exists(&[&user_id])
+                .context(SqliteError)?;
+            if !known {
+                return Err(DatabaseError::UnknownUser { user_id });
+            }
+
+            txn.prepare_cached(
+                "INSERT OR IGNORE INTO ledger_members (ledger_id, user_id)
+                VALUES ($1, $2)",
+            )
+            .context(SqliteError)?
+            .execute(params![ledger_id, user_id])
+            .context(SqliteError)?;
+
+            add_change_outbox_txn(&txn, &outbox, &())?;
+            txn.commit().context(SqliteError)?;
+
+            Ok(())
+        })
+    }
+
+    fn is_ledger_member(
+        &self,
+        ledger_id: i64,
+        user_id: String,
+    ) -> LocalBoxFuture<'static, Result<bool, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
+
+            is_ledger_member_txn(&conn, ledger_id, &user_id)
+        })
+    }
+
+    fn get_balance_for_user(
+        &self,
+        ledger_id: i64,
+        user: String,
+    ) -> LocalBoxFuture<'static, Result<Money, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+        let currency = self.currency;
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
+
+            let row = conn
+                .prepare_cached(
+                    r#"SELECT (
+                    SELECT COALESCE(SUM(amount), 0)
+                    FROM transactions
+                    WHERE shafter = $1 AND ledger_id = $2
+                ) - (
+                    SELECT COALESCE(SUM(amount), 0)
+                    FROM transactions
+                    WHERE shaftee = $1 AND ledger_id = $2
+                )"#,
+                )
+                .context(SqliteError)?
+                .query_row(params![user, ledger_id], |row| {
+                    row.get(0).map(|amount| Money::new(amount, currency))
+                })
+                .context(SqliteError)?;
+
+            Ok(row)
+        })
+    }
+
+    fn get_balances_by_counterparty(
+        &self,
+        ledger_id: i64,
+        user_id: String,
+    ) -> LocalBoxFuture<'static, Result<Vec<User>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+        let currency = self.currency;
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
+
+            let mut stmt = conn
+                .prepare_cached(
+                    r#"
+                    SELECT other, COALESCE(display_name, other), SUM(amount) AS balance
+                    FROM (
+                        SELECT shaftee AS other, amount FROM transactions
+                        WHERE ledger_id = $1 AND shafter = $2
+                        UNION ALL
+                        SELECT shafter AS other, -amount FROM transactions
+                        WHERE ledger_id = $1 AND shaftee = $2
+                    ) t
+                    LEFT JOIN users ON users.user_id = t.other
+                    WHERE other != $2
+                    GROUP BY other
+                    HAVING balance != 0
+                    ORDER BY balance ASC, other
+                    "#,
+                )
+                .context(SqliteError)?;
+
+            let rows: Result<Vec<_>, _> = stmt
+                .query_map(params![ledger_id, user_id], |row| {
+                    Ok(User {
+                        user_id: row.get(0)?,
+                        display_name: row.get(1)?,
+                        balance: Money::new(row.get(2)?, currency),
+                    })
+                })
+                .context(SqliteError)?
+                .collect();
+
+            Ok(rows.context(SqliteError)?)
+        })
+    }
+
+    fn get_outstanding_debts(
+        &self,
+        ledger_id: i64,
+    ) -> LocalBoxFuture<'static, Result<Vec<Debt>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+        let currency = self.currency;
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
+
+            // Each pair's transactions are signed by the order of the pair's
+            // user IDs, so that a positive balance means the second owes the
+            // first. A debt starts when the running balance leaves zero or
+            // changes sign, and is settled when it returns to zero or changes
+            // sign.
+            let mut stmt = conn
+                .prepare_cached(
+                    r#"
+                    WITH pairs AS (
+                        SELECT
+                            MIN(shafter, shaftee) AS a,
+                            MAX(shafter, shaftee) AS b,
+                            CASE WHEN shafter < shaftee THEN amount ELSE -amount END AS amount,
+                            time_sec,
+                            id
+                        FROM transactions
+                        WHERE ledger_id = $1 AND shafter != shaftee
+                    ),
+                    running AS (
+                        SELECT a, b, amount, time_sec,
+                            SUM(amount) OVER w AS balance,
+                            SUM(amount) OVER w - amount AS prev
+                        FROM pairs
+                        WINDOW w AS (PARTITION BY a, b ORDER BY time_sec, id ROWS UNBOUNDED PRECEDING)
+                    )
+                    SELECT a, b, SUM(amount) AS total,
+                        MAX(CASE WHEN balance != 0 AND (prev = 0 OR (balance > 0) != (prev > 0))
+                            THEN time_sec END) AS since_sec,
+                        MAX(CASE WHEN prev != 0 AND (balance = 0 OR (balance > 0) != (prev > 0))
+                            THEN time_sec END) AS settled_sec
+                    FROM running
+                    GROUP BY a, b
+                    HAVING total != 0
+                    ORDER BY ABS(total) DESC, since_sec, a, b
+                    "#,
+                )
+                .context(SqliteError)?;
+
+            let rows: Result<Vec<_>, _> = stmt
+                .query_map(params![ledger_id], |row| {
+                    let a: String = row.get(0)?;
+                    let b: String = row.get(1)?;
+                    let total: i64 = row.get(2)?;
+                    let settled_sec: Option<i64> = row.get(4)?;
+                    let (debtor, creditor) = if total > 0 { (b, a) } else { (a, b) };
+                    Ok(Debt {
+                        debtor,
+                        creditor,
+                        amount: Money::new(total.abs(), currency),
+                        since: chrono::Utc.timestamp(row.get(3)?, 0),
+                        last_settled: settled_sec.map(|sec| chrono::Utc.timestamp(sec, 0)),
+                    })
+                })
+                .context(SqliteError)?
+                .collect();
+
+            Ok(rows.context(SqliteError)?)
+        })
+    }
+
+    fn get_all_users(
+        &self,
+        ledger_id: i64,
+    ) -> LocalBoxFuture<'static, Result<LinearMap<String, User>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+        let currency = self.currency;
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
+
+            let mut stmt = conn
+                .prepare_cached(
+                    r#"
+                SELECT user_id, display_name, COALESCE(balance, 0) AS balance
+                FROM users
+                JOIN ledger_members USING (user_id)
+                LEFT JOIN (
+                    SELECT user_id, SUM(amount) as balance
+                    FROM (
+                        SELECT shafter AS user_id, SUM(amount) AS amount
+                        FROM transactions WHERE ledger_id = $1 GROUP BY shafter
+                        UNION ALL
+                        SELECT shaftee AS user_id, -SUM(amount) AS amount
+                        FROM transactions WHERE ledger_id = $1 GROUP BY shaftee
+                    ) t GROUP BY user_id
+                )
+                USING (user_id)
+                WHERE ledger_members.ledger_id = $1
+                ORDER BY balance ASC
+                "#,
+                )
+                .context(SqliteError)?;
+
+            let rows: Result<LinearMap<String, User>, _> = stmt
+                .query_map(&[&ledger_id], |row| {
+                    Ok((
+                        row.get(0)?,
+                        User {
+                            user_id: row.get(0)?,
+                            display_name: row.get(1)?,
+                            balance: Money::new(row.get(2)?, currency),
+                        },
+                    ))
+                })
+                .context(SqliteError)?
+                .collect();
+
+            Ok(rows.context(SqliteError)?)
+        })
+    }
+
+    fn get_users(
+        &self,
+        ledger_id: i64,
+        filter: UserFilter,
+    ) -> LocalBoxFuture<'static, Result<Vec<UserEntry>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+        let currency = self.currency;
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
+
+            let mut stmt = conn
+                .prepare_cached(
+                    r#"
+                SELECT user_id, display_name, COALESCE(balance, 0) AS balance, active
+                FROM users
+                JOIN ledger_members USING (user_id)
+                LEFT JOIN (
+                    SELECT user_id, SUM(amount) as balance, MAX(last_sec) AS last_sec
+                    FROM (
+                        SELECT shafter AS user_id, SUM(amount) AS amount, MAX(time_sec) AS last_sec
+                        FROM transactions WHERE ledger_id = $1 GROUP BY shafter
+                        UNION ALL
+                        SELECT shaftee AS user_id, -SUM(amount) AS amount, MAX(time_sec) AS last_sec
+                        FROM transactions WHERE ledger_id = $1 GROUP BY shaftee
+                    ) t GROUP BY user_id
+                )
+                USING (user_id)
+                WHERE ledger_members.ledger_id = $1
+                    AND ($2 IS NULL OR active = $2)
+                    AND ($3 IS NULL OR user_id LIKE $3 ESCAPE '\' OR display_name LIKE $3 ESCAPE '\')
+                    AND (NOT $4 OR COALESCE(balance, 0) != 0)
+                    AND ($5 IS NULL OR COALESCE(balance, 0) != 0 OR last_sec >= $5)
+                ORDER BY CASE WHEN $6 THEN lower(display_name) END, balance ASC, user_id
+                LIMIT COALESCE($7, -1) OFFSET $8
+                "#,
+                )
+                .context(SqliteError)?;
+
+            let rows: Result<Vec<_>, _> = stmt
+                .query_map(
+                    params![
+                        ledger_id,
+                        filter.active,
+                        filter.prefix.as_deref().map(like_prefix),
+                        filter.nonzero_balance,
+                        filter.settled_before.map(|before| before.timestamp()),
+                        filter.sort == UserSort::Name,
+                        filter.limit,
+                        filter.offset,
+                    ],
+                    |row| {
+                        Ok(UserEntry {
+                            user: User {
+                                user_id: row.get(0)?,
+                                display_name: row.get(1)?,
+                                balance: Money::new(row.get(2)?, currency),
+                            },
+                            active: row.get(3)?,
+                        })
+                    },
+                )
+                .context(SqliteError)?
+                .collect();
+
+            Ok(rows.context(SqliteError)?)
+        })
+    }
+
+    fn shaft_user(
+        &self,
+        ledger_id: i64,
+        transaction: Transaction,
+        outbox: Outbox<i64>,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let mut conn = db_pool.get().context(ConnectionPoolError)?;
+            let txn = conn.transaction().context(SqliteError)?;
+
+            if !is_ledger_member_txn(&txn, ledger_id, &transaction.shaftee)? {
+                return Err(DatabaseError::UnknownUser {
+                    user_id: transaction.shaftee,
+                });
+            }
+
+            let transaction_id = txn
+                .prepare_cached(
+                    "INSERT INTO transactions (shafter, shaftee, amount, time_sec, reason, kind, ledger_id)\
+                     VALUES ($1, $2, $3, $4, $5, $6, $7)",
+                )
+                .context(SqliteError)?
+                .insert(params![
+                    &transaction.shafter,
+                    &transaction.shaftee,
+                    &transaction.amount.minor_units(),
+                    &transaction.datetime.timestamp(),
+                    &transaction.reason,
+                    transaction.kind.as_str(),
+                    ledger_id,
+                ])
+                .context(SqliteError)?;
+
+            add_change_outbox_txn(&txn, &outbox, &transaction_id)?;
+            txn.commit().context(SqliteError)?;
+
+            Ok(transaction_id)
+        })
+    }
+
+    fn get_last_transactions(
+        &self,
+        ledger_id: i64,
+        limit: u32,
+    ) -> LocalBoxFuture<'static, Result<Vec<Transaction>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+        let currency = self.currency;
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
+
+            let mut stmt = conn
+                .prepare_cached(
+                    r#"SELECT id, shafter, shaftee, amount, time_sec, reason, kind
+                FROM transactions
+                WHERE ledger_id = $1
+                ORDER BY id DESC
+                LIMIT $2
+                "#,
+                )
+                .context(SqliteError)?;
+
+            let rows: Result<Vec<_>, _> = stmt
+                .query_map(params![ledger_id, limit], |row| {
+                    Ok(Transaction {
+                        id: Some(row.get(0)?),
+                        shafter: row.get(1)?,
+                        shaftee: row.get(2)?,
+                        amount: Money::new(row.get(3)?, currency),
+                        datetime: chrono::Utc.timestamp(row.get(4)?, 0),
+                        reason: row.get(5)?,
+                        kind: parse_kind(&row.get::<_, String>(6)?),
+                    })
+                })
+                .context(SqliteError)?
+                .collect();
+
+            Ok(rows.context(SqliteError)?)
+        })
+    }
+
+    fn get_transactions(
+        &self,
+        ledger_id: i64,
+        filter: TransactionFilter,
+    ) -> LocalBoxFuture<'static, Result<Vec<Transaction>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+        let currency = self.currency;
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
+
+            let mut stmt = conn
+                .prepare_cached(
+                    r#"SELECT id, shafter, shaftee, amount, time_sec, reason, kind
+                FROM transactions
+                WHERE ($1 IS NULL OR shafter = $1 OR shaftee = $1)
+                    AND ($2 IS NULL OR time_sec >= $2)
+                    AND ($3 IS NULL OR time_sec < $3)
+                    AND ($4 IS NULL OR id < $4)
+                    AND ledger_id = $5
+                ORDER BY id DESC
+                LIMIT $6
+                "#,
+                )
+                .context(SqliteError)?;
+
+            let rows: Result<Vec<_>, _> = stmt
+                .query_map(
+                    params![
+                        filter.user,
+                        filter.from.map(|from| from.timestamp()),
+                        filter.to.map(|to| to.timestamp()),
+                        filter.before,
+                        ledger_id,
+                        filter.limit,
+                    ],
+                    |row| {
+                        Ok(Transaction {
+                            id: Some(row.get(0)?),
+                            shafter: row.get(1)?,
+                            shaftee: row.get(2)?,
+                            amount: Money::new(row.get(3)?, currency),
+                            datetime: chrono::Utc.timestamp(row.get(4)?, 0),
+                            reason: row.get(5)?,
+                            kind: parse_kind(&row.get::<_, String>(6)?),
+                        })
+                    },
+                )
+                .context(SqliteError)?
+                .collect();
+
+            Ok(rows.context(SqliteError)?)
+        })
+    }
+
+    fn get_transaction_revision(
+        &self,
+        ledger_id: i64,
+        transaction_id: i64,
+    ) -> LocalBoxFuture<'static, Result<Option<i64>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
+
+            get_transaction_revision_txn(&conn, ledger_id, transaction_id)
+        })
+    }
+
+    fn update_transaction(
+        &self,
+        ledger_id: i64,
+        transaction_id: i64,
+        expected_revision: Option<i64>,
+        amount: Money,
+        reason: String,
+        outbox: Outbox<i64>,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let mut conn = db_pool.get().context(ConnectionPoolError)?;
+            let txn = conn.transaction().context(SqliteError)?;
+
+            let revision =
+                check_transaction_revision_txn(&txn, ledger_id, transaction_id, expected_revision)?;
+
+            txn.prepare_cached(
+                "UPDATE transactions SET amount = $1, reason = $2, revision = $3
+                WHERE id = $4",
+            )
+            .context(SqliteError)?
+            .execute(params![
+                amount.minor_units(),
+                reason,
+                revision + 1,
+                transaction_id
+            ])
+            .context(SqliteError)?;
+
+            let revision = revision + 1;
+            add_change_outbox_txn(&txn, &outbox, &revision)?;
+            txn.commit().context(SqliteError)?;
+
+            Ok(revision)
+        })
+    }
+
+    fn delete_transaction(
+        &self,
+        ledger_id: i64,
+        transaction_id: i64,
+        expected_revision: Option<i64>,
+        outbox: Outbox<()>,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let mut conn = db_pool.get().context(ConnectionPoolError)?;
+            let txn = conn.transaction().context(SqliteError)?;
+
+            check_transaction_revision_txn(&txn, ledger_id, transaction_id, expected_revision)?;
+
+            txn.prepare_cached("DELETE FROM transactions WHERE id = $1")
+                .context(SqliteError)?
+                .execute(&[&transaction_id])
+                .context(SqliteError)?;
+
+            add_change_outbox_txn(&txn, &outbox, &())?;
+            txn.commit().context(SqliteError)?;
+
+            Ok(())
+        })
+    }
+
+    fn get_ledger_version(&self) -> LocalBoxFuture<'static, Result<i64, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
+
+            let version = conn
+                .prepare_cached("SELECT COALESCE(MAX(seq), 0) FROM changes")
+                .context(SqliteError)?
+                .query_row(params![], |row| row.get(0))
+                .context(SqliteError)?;
+
+            Ok(version)
+        })
+    }
+
+    fn get_last_modified(
+        &self,
+        ledger_id: i64,
+        user_id: Option<String>,
+    ) -> LocalBoxFuture<'static, Result<Option<chrono::DateTime<chrono::Utc>>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
+
+            let modified_sec: Option<i64> = conn
+                .prepare_cached(
+                    "SELECT MAX(modified_sec) FROM last_modified
+                    WHERE scope IN ('global', 'ledger:' || $1, 'user:' || $2)",
+                )
+                .context(SqliteError)?
+                .query_row(params![ledger_id, user_id], |row| row.get(0))
+                .context(SqliteError)?;
+
+            Ok(modified_sec.map(|secs| chrono::Utc.timestamp(secs, 0)))
+        })
+    }
+
+    fn get_changes_since(
+        &self,
+        ledger_id: i64,
+        cursor: i64,
+        limit: u32,
+    ) -> LocalBoxFuture<'static, Result<LedgerChanges, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+        let currency = self.currency;
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let mut conn = db_pool.get().context(ConnectionPoolError)?;
+
+            // We use a transaction so that we get a consistent snapshot.
+            let txn = conn.transaction().context(SqliteError)?;
+
+            let (horizon, latest): (i64, i64) = txn
+                .query_row(
+                    "SELECT (SELECT seq FROM changes_horizon), (SELECT COALESCE(MAX(seq), 0) FROM changes)",
+                    params![],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .context(SqliteError)?;
+            if cursor > 0 && cursor < horizon {
+                return Ok(LedgerChanges {
+                    next_cursor: latest,
+                    transactions: Vec::new(),
+                    deleted_transactions: Vec::new(),
+                    users: Vec::new(),
+                    resync_required: true,
+                });
+            }
+
+            let mut next_cursor = cursor;
+            let mut transaction_ids = BTreeSet::new();
+            let mut user_ids = BTreeSet::new();
+
+            {
+                let mut stmt = txn
+                    .prepare_cached(
+                        r#"SELECT seq, transaction_id, user_id
+                    FROM changes
+                    WHERE seq > $1
+                    ORDER BY seq ASC
+                    LIMIT $2
+                    "#,
+                    )
+                    .context(SqliteError)?;
+
+                let mut rows = stmt.query(params![cursor, limit]).context(SqliteError)?;
+                while let Some(row) = rows.next().context(SqliteError)? {
+                    next_cursor = row.get(0).context(SqliteError)?;
+
+                    let transaction_id: Option<i64> = row.get(1).context(SqliteError)?;
+                    let user_id: Option<String> = row.get(2).context(SqliteError)?;
+
+                    transaction_ids.extend(transaction_id);
+                    user_ids.extend(user_id);
+                }
+            }
+
+            let mut transactions = Vec::new();
+            let mut deleted_transactions = Vec::new();
+
+            {
+                let mut stmt = txn
+                    .prepare_cached(
+                        r#"SELECT id, shafter, shaftee, amount, time_sec, reason, kind, ledger_id
+                    FROM transactions
+                    WHERE id = $1
+                    "#,
+                    )
+                    .context(SqliteError)?;
+
+                for transaction_id in transaction_ids {
+                    let res = stmt.query_row(&[&transaction_id], |row| {
+                        let transaction = Transaction {
+                            id: Some(row.get(0)?),
+                            shafter: row.get(1)?,
+                            shaftee: row.get(2)?,
+                            amount: Money::new(row.get(3)?, currency),
+                            datetime: chrono::Utc.timestamp(row.get(4)?, 0),
+                            reason: row.get(5)?,
+                            kind: parse_kind(&row.get::<_, String>(6)?),
+                        };
+                        Ok((transaction, row.get::<_, i64>(7)?))
+                    });
+
+                    match res {
+                        Ok((transaction, id)) if id == ledger_id => transactions.push(transaction),
+                        // Changes to other ledgers are skipped.
+                        Ok(_) => {}
+                        Err(rusqlite::Error::QueryReturnedNoRows) => {
+                            deleted_transactions.push(transaction_id)
+                        }
+                        Err(err) => Err(err).context(SqliteError)?,
+                    }
+                }
+            }
+
+            let mut users = Vec::new();
+
+            {
+                let mut stmt = txn
+                    .prepare_cached(
+                        r#"SELECT user_id, display_name, (
+                        SELECT COALESCE(SUM(amount), 0)
+                        FROM transactions
+                        WHERE shafter = $1 AND ledger_id = $2
+                    ) - (
+                        SELECT COALESCE(SUM(amount), 0)
+                        FROM transactions
+                        WHERE shaftee = $1 AND ledger_id = $2
+                    )
+                    FROM users
+                    JOIN ledger_members USING (user_id)
+                    WHERE user_id = $1 AND ledger_id = $2
+                    "#,
+                    )
+                    .context(SqliteError)?;
+
+                for user_id in user_ids {
+                    let res = stmt.query_row(params![user_id, ledger_id], |row| {
+                        Ok(User {
+                            user_id: row.get(0)?,
+                            display_name: row.get(1)?,
+                            balance: Money::new(row.get(2)?, currency),
+                        })
+                    });
+
+                    match res {
+                        Ok(user) => users.push(user),
+                        Err(rusqlite::Error::QueryReturnedNoRows) => {}
+                        Err(err) => Err(err).context(SqliteError)?,
+                    }
+                }
+            }
+
+            Ok(LedgerChanges {
+                next_cursor,
+                transactions,
+                deleted_transactions,
+                users,
+                resync_required: false,
+            })
+        })
+    }
+
+    fn get_all_transactions(
+        &self,
+        ledger_id: i64,
+    ) -> LocalBoxFuture<'static, Result<Vec<Transaction>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+        let currency = self.currency;
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
+
+            let mut stmt = conn
+                .prepare_cached(
+                    r#"SELECT id, shafter, shaftee, amount, time_sec, reason, kind
+                FROM transactions
+                WHERE ledger_id = $1
+                ORDER BY id ASC
+                "#,
+                )
+                .context(SqliteError)?;
+
+            let rows: Result<Vec<_>, _> = stmt
+                .query_map(&[&ledger_id], |row| {
+                    Ok(Transaction {
+                        id: Some(row.get(0)?),
+                        shafter: row.get(1)?,
+                        shaftee: row.get(2)?,
+                        amount: Money::new(row.get(3)?, currency),
+                        datetime: chrono::Utc.timestamp(row.get(4)?, 0),
+                        reason: row.get(5)?,
+                        kind: parse_kind(&row.get::<_, String>(6)?),
+                    })
+                })
+                .context(SqliteError)?
+                .collect();
+
+            Ok(rows.context(SqliteError)?)
+        })
+    }
+
+    fn insert_transactions_batch(
+        &self,
+        ledger_id: i64,
+        transactions: Vec<Transaction>,
+        outbox: Outbox<Vec<i64>>,
+    ) -> LocalBoxFuture<'static, Result<Vec<i64>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let mut conn = db_pool.get().context(ConnectionPoolError)?;
+            let txn = conn.transaction().context(SqliteError)?;
+
+            // Dropping the transaction on error rolls back anything we've
+            // inserted so far.
+            let mut checked = BTreeSet::new();
+            for transaction in &transactions {
+                if checked.insert(transaction.shaftee.as_str())
+                    && !is_ledger_member_txn(&txn, ledger_id, &transaction.shaftee)?
+                {
+                    return Err(DatabaseError::UnknownUser {
+                        user_id: transaction.shaftee.clone(),
+                    });
+                }
+            }
+
+            let mut transaction_ids = Vec::with_capacity(transactions.len());
+
+            for chunk in transactions.chunks(TRANSACTIONS_PER_INSERT) {
+                let mut values = Vec::with_capacity(chunk.len() * 7);
+                for transaction in chunk {
+                    values.extend(vec![
+                        Value::Text(transaction.shafter.clone()),
+                        Value::Text(transaction.shaftee.clone()),
+                        Value::Integer(transaction.amount.minor_units()),
+                        Value::Integer(transaction.datetime.timestamp()),
+                        Value::Text(transaction.reason.clone()),
+                        Value::Text(transaction.kind.as_str().to_string()),
+                        Value::Integer(ledger_id),
+                    ]);
+                }
+
+                // The last chunk's SQL depends on its size, so isn't cached
+                // in case it evicts statements we do reuse.
+                txn.prepare(&insert_transactions_sql(chunk.len()))
+                    .context(SqliteError)?
+                    .execute(params_from_iter(values))
+                    .context(SqliteError)?;
+
+                // A single insert gets consecutive IDs, as we hold the write
+                // lock.
+                let last_id = txn.last_insert_rowid();
+                let first_id = last_id - chunk.len() as i64 + 1;
+                transaction_ids.extend(first_id..=last_id);
+            }
+
+            add_change_outbox_txn(&txn, &outbox, &transaction_ids)?;
+            txn.commit().context(SqliteError)?;
+
+            Ok(transaction_ids)
+        })
+    }
+
+    fn get_transaction(
+        &self,
+        ledger_id: i64,
+        transaction_id: i64,
+    ) -> LocalBoxFuture<'static, Result<Option<Transaction>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+        let currency = self.currency;
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
+
+            let row = conn
+                .prepare_cached(
+                    r#"SELECT id, shafter, shaftee, amount, time_sec, reason, kind
+                FROM transactions
+                WHERE id = $1 AND ledger_id = $2
+                "#,
+                )
+                .context(SqliteError)?
+                .query_row(params![transaction_id, ledger_id], |row| {
+                    Ok(Transaction {
+                        id: Some(row.get(0)?),
+                        shafter: row.get(1)?,
+                        shaftee: row.get(2)?,
+                        amount: Money::new(row.get(3)?, currency),
+                        datetime: chrono::Utc.timestamp(row.get(4)?, 0),
+                        reason: row.get(5)?,
+                        kind: parse_kind(&row.get::<_, String>(6)?),
+                    })
+                })
+                .map(Some)
+                .or_else(|err| {
+                    if let rusqlite::Error::QueryReturnedNoRows = err {
+                        Ok(None)
+                    } else {
+                        Err(err)
+                    }
+                })
+                .context(SqliteError)?;
+
+            Ok(row)
+        })
+    }
+
+    fn add_attachment(
+        &self,
+        attachment: Attachment,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+                let conn = db_pool.get().context(ConnectionPoolError)?;
+
+                let mut stmt = conn
+                    .prepare_cached(
+                        "INSERT INTO attachments (transaction_id, uploader, content_type, data, time_sec)\
+                     VALUES ($1, $2, $3, $4, $5)",
+                    )
+                    .context(SqliteError)?;
+
+                let attachment_id = stmt
+                    .insert(params![
+                        &attachment.transaction_id,
+                        &attachment.uploader,
+                        &attachment.content_type,
+                        &attachment.data,
+                        &chrono::Utc::now().timestamp(),
+                    ])
+                    .context(SqliteError)?;
+
+                Ok(attachment_id)
+            })
+    }
+
+    fn add_receipt_suggestion(
+        &self,
+        attachment_id: i64,
+        suggestion: ReceiptSuggestion,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
+
+            conn.prepare_cached(
+                "INSERT INTO receipt_suggestions (attachment_id, amount, merchant)
+                VALUES ($1, $2, $3)",
+            )
+            .context(SqliteError)?
+            .execute(params![
+                &attachment_id,
+                &suggestion.amount,
+                &suggestion.merchant
+            ])
+            .context(SqliteError)?;
+
+            Ok(())
+        })
+    }
+
+    fn get_receipt_suggestions(
+        &self,
+        transaction_id: i64,
+    ) -> LocalBoxFuture<'static, Result<Vec<(i64, ReceiptSuggestion)>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
+
+            let mut stmt = conn
+                .prepare_cached(
+                    r#"SELECT attachment_id, amount, merchant
+                FROM receipt_suggestions
+                INNER JOIN attachments ON attachments.id = receipt_suggestions.attachment_id
+                WHERE transaction_id = $1
+                ORDER BY attachment_id ASC
+                "#,
+                )
+                .context(SqliteError)?;
+
+            let rows: Result<Vec<_>, _> = stmt
+                .query_map(&[&transaction_id], |row| {
+                    Ok((
+                        row.get(0)?,
+                        ReceiptSuggestion {
+                            amount: row.get(1)?,
+                            merchant: row.get(2)?,
+                        },
+                    ))
+                })
+                .context(SqliteError)?
+                .collect();
+
+            Ok(rows.context(SqliteError)?)
+        })
+    }
+
+    fn get_user_preferences(
+        &self,
+        user_id: String,
+    ) -> LocalBoxFuture<'static, Result<UserPreferences, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+        let currency = self.currency;
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
+
+            get_user_preferences_txn(&conn, &user_id, currency)
+        })
+    }
+
+    fn set_user_preferences(
+        &self,
+        user_id: String,
+        preferences: UserPreferences,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
+
+            conn.execute(
+                r#"INSERT INTO user_preferences
+                    (user_id, default_counterparty, default_currency, quick_amounts)
+                VALUES ($1, $2, $3, $4)
+                ON CONFLICT (user_id) DO UPDATE SET
+                    default_counterparty = excluded.default_counterparty,
+                    default_currency = excluded.default_currency,
+                    quick_amounts = excluded.quick_amounts
+                "#,
+                params![
+                    user_id,
+                    preferences.default_counterparty,
+                    preferences
+                        .default_currency
+                        .map(|code| code.as_str().to_string()),
+                    format_quick_amounts(&preferences.quick_amounts),
+                ],
+            )
+            .context(SqliteError)?;
+
+            Ok(())
+        })
+    }
+
+    fn export_user(
+        &self,
+        user_id: String,
+    ) -> LocalBoxFuture<'static, Result<Option<UserExport>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+        let currency = self.currency;
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let mut conn = db_pool.get().context(ConnectionPoolError)?;
+            // Read everything in one transaction so the export is consistent.
+            let txn = conn.transaction().context(SqliteError)?;
+
+            let user = txn
+                .prepare(
+                    r#"SELECT user_id, display_name, (
+                    SELECT COALESCE(SUM(amount), 0) FROM transactions WHERE shafter = $1
+                ) - (
+                    SELECT COALESCE(SUM(amount), 0) FROM transactions WHERE shaftee = $1
+                )
+                FROM users
+                WHERE user_id = $1
+                "#,
+                )
+                .context(SqliteError)?
+                .query_row(&[&user_id], |row| {
+                    Ok(User {
+                        user_id: row.get(0)?,
+                        display_name: row.get(1)?,
+                        balance: Money::new(row.get(2)?, currency),
+                    })
+                })
+                .map(Some)
+                .or_else(|err| {
+                    if let rusqlite::Error::QueryReturnedNoRows = err {
+                        Ok(None)
+                    } else {
+                        Err(err)
+                    }
+                })
+                .context(SqliteError)?;
+
+            let user = match user {
+                Some(user) => user,
+                None => return Ok(None),
+            };
+
+            let github_ids: Result<Vec<String>, _> = txn
+                .prepare("SELECT github_id FROM github_users WHERE user_id = $1")
+                .context(SqliteError)?
+                .query_map(&[&user_id], |row| row.get(0))
+                .context(SqliteError)?
+                .collect();
+
+            let transactions: Result<Vec<_>, _> = txn
+                .prepare(
+                    r#"SELECT id, shafter, shaftee, amount, time_sec, reason, kind
+                FROM transactions
+                WHERE shafter = $1 OR shaftee = $1
+                ORDER BY id ASC
+                "#,
+                )
+                .context(SqliteError)?
+                .query_map(&[&user_id], |row| {
+                    Ok(Transaction {
+                        id: Some(row.get(0)?),
+                        shafter: row.get(1)?,
+                        shaftee: row.get(2)?,
+                        amount: Money::new(row.get(3)?, currency),
+                        datetime: chrono::Utc.timestamp(row.get(4)?, 0),
+                        reason: row.get(5)?,
+                        kind: parse_kind(&row.get::<_, String>(6)?),
+                    })
+                })
+                .context(SqliteError)?
+                .collect();
+
+            let api_tokens: Result<Vec<_>, _> = txn
+                .prepare(
+                    "SELECT id, name, scopes FROM tokens
+                    WHERE user_id = $1 AND name IS NOT NULL
+                    ORDER BY id",
+                )
+                .context(SqliteError)?
+                .query_map(&[&user_id], |row| {
+                    let scopes: String = row.get(2)?;
+                    Ok(ApiToken {
+                        id: row.get(0)?,
+                        name: row.get(1)?,
+                        scopes: parse_scopes(&scopes),
+                    })
+                })
+                .context(SqliteError)?
+                .collect();
+
+            let sessions = txn
+                .prepare("SELECT COUNT(*) FROM tokens WHERE user_id = $1 AND name IS NULL")
+                .context(SqliteError)?
+                .query_row(&[&user_id], |row| row.get(0))
+                .context(SqliteError)?;
+
+            let attachments: Result<Vec<_>, _> = txn
+                .prepare(
+                    "SELECT id, transaction_id, content_type, time_sec FROM attachments
+                    WHERE uploader = $1
+                    ORDER BY id",
+                )
+                .context(SqliteError)?
+                .query_map(&[&user_id], |row| {
+                    Ok(AttachmentInfo {
+                        id: row.get(0)?,
+                        transaction_id: row.get(1)?,
+                        content_type: row.get(2)?,
+                        datetime: chrono::Utc.timestamp(row.get(3)?, 0),
+                    })
+                })
+                .context(SqliteError)?
+                .collect();
+
+            let audit_log: Result<Vec<_>, _> = txn
+                .prepare(
+                    "SELECT id, actor, action, target, details, time_sec FROM audit_log
+                    WHERE actor = $1
+                    ORDER BY id",
+                )
+                .context(SqliteError)?
+                .query_map(&[&user_id], |row| {
+                    Ok(AuditEntry {
+                        id: Some(row.get(0)?),
+                        actor: row.get(1)?,
+                        action: row.get(2)?,
+                        target: row.get(3)?,
+                        details: row.get(4)?,
+                        datetime: chrono::Utc.timestamp(row.get(5)?, 0),
+                    })
+                })
+                .context(SqliteError)?
+                .collect();
+
+            let preferences = get_user_preferences_txn(&txn, &user_id, currency)?;
+
+            let slack_links: Result<Vec<_>, _> = txn
+                .prepare(
+                    "SELECT team_id, slack_user_id FROM slack_users
+                    WHERE user_id = $1
+                    ORDER BY team_id, slack_user_id",
+                )
+                .context(SqliteError)?
+                .query_map(&[&user_id], |row| {
+                    Ok(SlackLink {
+                        team_id: row.get(0)?,
+                        slack_user_id: row.get(1)?,
+                    })
+                })
+                .context(SqliteError)?
+                .collect();
+
+            let inbound_hooks: Result<Vec<_>, _> = txn
+                .prepare(
+                    "SELECT id, name, created_sec FROM inbound_hooks WHERE user_id = $1 ORDER BY id",
+                )
+                .context(SqliteError)?
+                .query_map(&[&user_id], |row| {
+                    Ok(InboundHook {
+                        id: row.get(0)?,
+                        name: row.get(1)?,
+                        created: chrono::Utc.timestamp(row.get(2)?, 0),
+                    })
+                })
+                .context(SqliteError)?
+                .collect();
+
+            let ledgers: Result<Vec<_>, _> = txn
+                .prepare(
+                    r#"SELECT id, name, display_name
+                FROM ledgers
+                JOIN ledger_members ON ledgers.id = ledger_members.ledger_id
+                WHERE user_id = $1
+                ORDER BY id ASC
+                "#,
+                )
+                .context(SqliteError)?
+                .query_map(&[&user_id], |row| {
+                    Ok(Ledger {
+                        id: row.get(0)?,
+                        name: row.get(1)?,
+                        display_name: row.get(2)?,
+                    })
+                })
+                .context(SqliteError)?
+                .collect();
+
+            let security_events: Result<Vec<_>, _> = txn
+                .prepare(
+                    "SELECT id, kind, user_id, ip, details, time_sec FROM security_events
+                    WHERE user_id = $1
+                    ORDER BY id",
+                )
+                .context(SqliteError)?
+                .query_map(&[&user_id], |row| {
+                    Ok(SecurityEvent {
+                        id: Some(row.get(0)?),
+                        kind: row.get(1)?,
+                        user_id: row.get(2)?,
+                        ip: row.get(3)?,
+                        details: row.get(4)?,
+                        datetime: chrono::Utc.timestamp(row.get(5)?, 0),
+                    })
+                })
+                .context(SqliteError)?
+                .collect();
+
+            Ok(Some(UserExport {
+                user,
+                github_ids: github_ids.context(SqliteError)?,
+                transactions: transactions.context(SqliteError)?,
+                api_tokens: api_tokens.context(SqliteError)?,
+                sessions,
+                attachments: attachments.context(SqliteError)?,
+                audit_log: audit_log.context(SqliteError)?,
+                preferences,
+                slack_links: slack_links.context(SqliteError)?,
+                inbound_hooks: inbound_hooks.context(SqliteError)?,
+                ledgers: ledgers.context(SqliteError)?,
+                security_events: security_events.context(SqliteError)?,
+            }))
+        })
+    }
+
+    fn delete_user(
+        &self,
+        user_id: String,
+        outbox: Outbox<String>,
+    ) -> LocalBoxFuture<'static, Result<String, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let mut conn = db_pool.get().context(ConnectionPoolError)?;
+            let txn = conn.transaction().context(SqliteError)?;
+
+            let anonymous_id = new_anonymous_user_id();
+
+            let renamed = txn
+                .execute(
+                    "UPDATE users SET user_id = $1, display_name = $2 WHERE user_id = $3",
+                    params![anonymous_id, DELETED_USER_DISPLAY_NAME, user_id],
+                )
+                .context(SqliteError)?;
+            if renamed == 0 {
+                return Err(DatabaseError::UnknownUser { user_id });
+            }
+
+            // Each is run separately as `execute` only runs one statement.
+            for stmt in &[
+                "UPDATE transactions SET shafter = $1 WHERE shafter = $2",
+                "UPDATE transactions SET shaftee = $1 WHERE shaftee = $2",
+                "UPDATE attachments SET uploader = $1 WHERE uploader = $2",
+                "UPDATE audit_log SET actor = $1 WHERE actor = $2",
+                "UPDATE audit_log SET target = $1 WHERE target = $2",
+                "UPDATE security_events SET user_id = $1 WHERE user_id = $2",
+                "UPDATE changes SET user_id = $1 WHERE user_id = $2",
+                "UPDATE ledger_members SET user_id = $1 WHERE user_id = $2",
+                "UPDATE user_preferences SET default_counterparty = $1
+                    WHERE default_counterparty = $2",
+            ] {
+                txn.execute(stmt, params![anonymous_id, user_id])
+                    .context(SqliteError)?;
+            }
+
+            txn.execute("DELETE FROM github_users WHERE user_id = $1", &[&user_id])
+                .context(SqliteError)?;
+            txn.execute("DELETE FROM tokens WHERE user_id = $1", &[&user_id])
+                .context(SqliteError)?;
+            txn.execute("DELETE FROM slack_users WHERE user_id = $1", &[&user_id])
+                .context(SqliteError)?;
+            txn.execute("DELETE FROM inbound_hooks WHERE user_id = $1", &[&user_id])
+                .context(SqliteError)?;
+            txn.execute(
+                "DELETE FROM user_preferences WHERE user_id = $1",
+                &[&user_id],
+            )
+            .context(SqliteError)?;
+
+            add_change_outbox_txn(&txn, &outbox, &anonymous_id)?;
+            txn.commit().context(SqliteError)?;
+
+            Ok(anonymous_id)
+        })
+    }
+
+    fn deactivate_user(
+        &self,
+        user_id: String,
+        outbox: Outbox<()>,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let mut conn = db_pool.get().context(ConnectionPoolError)?;
+            let txn = conn.transaction().context(SqliteError)?;
+
+            let updated = txn
+                .execute(
+                    "UPDATE users SET active = 0 WHERE user_id = $1",
+                    &[&user_id],
+                )
+                .context(SqliteError)?;
+            if updated == 0 {
+                return Err(DatabaseError::UnknownUser { user_id });
+            }
+
+            for stmt in &[
+                "DELETE FROM tokens WHERE user_id = $1",
+                "DELETE FROM slack_users WHERE user_id = $1",
+                "DELETE FROM inbound_hooks WHERE user_id = $1",
+            ] {
+                txn.execute(stmt, &[&user_id]).context(SqliteError)?;
+            }
+
+            add_change_outbox_txn(&txn, &outbox, &())?;
+            txn.commit().context(SqliteError)?;
+
+            Ok(())
+        })
+    }
+
+    fn get_deactivated_users(&self) -> LocalBoxFuture<'static, Result<Vec<String>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
+
+            let mut stmt = conn
+                .prepare_cached("SELECT user_id FROM users WHERE NOT active ORDER BY user_id")
+                .context(SqliteError)?;
+
+            let rows: Result<Vec<String>, _> = stmt
+                .query_map(params![], |row| row.get(0))
+                .context(SqliteError)?
+                .collect();
+
+            rows.context(SqliteError)
+        })
+    }
+
+    fn merge_users(
+        &self,
+        from: String,
+        into: String,
+        outbox: Outbox<()>,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let mut conn = db_pool.get().context(ConnectionPoolError)?;
+            let txn = conn.transaction().context(SqliteError)?;
+
+            for user_id in &[&from, &into] {
+                let exists: bool = txn
+                    .query_row(
+                        "SELECT COUNT(*) > 0 FROM users WHERE user_id = $1",
+                        params![user_id],
+                        |row| row.get(0),
+                    )
+                    .context(SqliteError)?;
+                if !exists {
+                    return Err(DatabaseError::UnknownUser {
+                        user_id: user_id.to_string(),
+                    });
+                }
+            }
+
+            // Each is run separately as `execute` only runs one statement.
+            for stmt in &[
+                "UPDATE transactions SET shafter = $1 WHERE shafter = $2",
+                "UPDATE transactions SET shaftee = $1 WHERE shaftee = $2",
+                "UPDATE attachments SET uploader = $1 WHERE uploader = $2",
+                "UPDATE inbound_hooks SET user_id = $1 WHERE user_id = $2",
+                "UPDATE slack_users SET user_id = $1 WHERE user_id = $2",
+                "INSERT OR IGNORE INTO ledger_members (ledger_id, user_id)
+                    SELECT ledger_id, $1 FROM ledger_members WHERE user_id = $2",
+            ] {
+                txn.execute(stmt, params![into, from])
+                    .context(SqliteError)?;
+            }
+
+            // Users only have one GitHub account, so `from`'s can't move.
+            for stmt in &[
+                "DELETE FROM ledger_members WHERE user_id = $1",
+                "DELETE FROM github_users WHERE user_id = $1",
+                "DELETE FROM tokens WHERE user_id = $1",
+                "DELETE FROM users WHERE user_id = $1",
+            ] {
+                txn.execute(stmt, &[&from]).context(SqliteError)?;
+            }
+
+            add_change_outbox_txn(&txn, &outbox, &())?;
+            txn.commit().context(SqliteError)?;
+
+            Ok(())
+        })
+    }
+
+    fn prune_tokens(
+        &self,
+        issued_before: Option<chrono::DateTime<chrono::Utc>>,
+        outbox: Outbox<u64>,
+    ) -> LocalBoxFuture<'static, Result<u64, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let mut conn = db_pool.get().context(ConnectionPoolError)?;
+            let txn = conn.transaction().context(SqliteError)?;
+
+            // Sessions from before issue times were recorded count as old.
+            let deleted = txn
+                .prepare_cached(
+                    r#"
+                    DELETE FROM tokens
+                    WHERE expires_sec < $1
+                        OR (name IS NULL AND $2 IS NOT NULL AND COALESCE(issued_sec, 0) < $2)
+                    "#,
+                )
+                .context(SqliteError)?
+                .execute(params![
+                    chrono::Utc::now().timestamp(),
+                    issued_before.map(|issued_before| issued_before.timestamp()),
+                ])
+                .context(SqliteError)?;
+
+            let deleted = deleted as u64;
+            add_change_outbox_txn(&txn, &outbox, &deleted)?;
+            txn.commit().context(SqliteError)?;
+
+            Ok(deleted)
+        })
+    }
+
+    fn put_shared_value(
+        &self,
+        key: String,
+        value: String,
+        expires: chrono::DateTime<chrono::Utc>,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
+
+            conn.prepare_cached(
+                "INSERT OR REPLACE INTO shared_values (key, value, expires_sec) VALUES ($1, $2, $3)",
+            )
+            .context(SqliteError)?
+            .execute(params![key, value, expires.timestamp()])
+            .context(SqliteError)?;
+
+            Ok(())
+        })
+    }
+
+    fn take_shared_value(
+        &self,
+        key: String,
+    ) -> LocalBoxFuture<'static, Result<Option<String>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let mut conn = db_pool.get().context(ConnectionPoolError)?;
+            let txn = conn.transaction().context(SqliteError)?;
+
+            let value = txn
+                .prepare_cached(
+                    "SELECT value FROM shared_values WHERE key = $1 AND expires_sec > $2",
+                )
+                .context(SqliteError)?
+                .query_row(params![key, chrono::Utc::now().timestamp()], |row| {
+                    row.get(0)
+                })
+                .map(Some)
+                .or_else(|err| {
+                    if let rusqlite::Error::QueryReturnedNoRows = err {
+                        Ok(None)
+                    } else {
+                        Err(err)
+                    }
+                })
+                .context(SqliteError)?;
+
+            txn.execute("DELETE FROM shared_values WHERE key = $1", params![key])
+                .context(SqliteError)?;
+
+            txn.commit().context(SqliteError)?;
+
+            Ok(value)
+        })
+    }
+
+    fn add_invalidation(
+        &self,
+        instance_id: String,
+        payload: String,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
+
+            let id = conn
+                .prepare_cached(
+                    "INSERT INTO invalidations (instance_id, payload, time_sec) VALUES ($1, $2, $3)",
+                )
+                .context(SqliteError)?
+                .insert(params![instance_id, payload, chrono::Utc::now().timestamp()])
+                .context(SqliteError)?;
+
+            Ok(id)
+        })
+    }
+
+    fn get_invalidations(
+        &self,
+        after: i64,
+    ) -> LocalBoxFuture<'static, Result<Vec<StoredInvalidation>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
+
+            let mut stmt = conn
+                .prepare_cached(
+                    "SELECT id, instance_id, payload FROM invalidations WHERE id > $1 ORDER BY id",
+                )
+                .context(SqliteError)?;
+
+            let rows: Result<Vec<_>, _> = stmt
+                .query_map(params![after], |row| {
+                    Ok(StoredInvalidation {
+                        id: row.get(0)?,
+                        instance_id: row.get(1)?,
+                        payload: row.get(2)?,
+                    })
+                })
+                .context(SqliteError)?
+                .collect();
+
+            Ok(rows.context(SqliteError)?)
+        })
+    }
+
+    fn get_last_invalidation_id(&self) -> LocalBoxFuture<'static, Result<i64, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
+
+            let id = conn
+                .prepare_cached("SELECT COALESCE(MAX(id), 0) FROM invalidations")
+                .context(SqliteError)?
+                .query_row(params![], |row| row.get(0))
+                .context(SqliteError)?;
+
+            Ok(id)
+        })
+    }
+
+    fn prune_shared_state(
+        &self,
+        published_before: chrono::DateTime<chrono::Utc>,
+    ) -> LocalBoxFuture<'static, Result<u64, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
+
+            let values = conn
+                .prepare_cached("DELETE FROM shared_values WHERE expires_sec <= $1")
+                .context(SqliteError)?
+                .execute(params![chrono::Utc::now().timestamp()])
+                .context(SqliteError)?;
+
+            let invalidations = conn
+                .prepare_cached("DELETE FROM invalidations WHERE time_sec < $1")
+                .context(SqliteError)?
+                .execute(params![published_before.timestamp()])
+                .context(SqliteError)?;
+
+            Ok((values + invalidations) as u64)
+        })
+    }
+
+    fn add_outbox_messages(
+        &self,
+        messages: Vec<NewOutboxMessage>,
+        next_attempt: chrono::DateTime<chrono::Utc>,
+    ) -> LocalBoxFuture<'static, Result<Vec<i64>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let mut conn = db_pool.get().context(ConnectionPoolError)?;
+            let txn = conn.transaction().context(SqliteError)?;
+
+            let ids = add_outbox_messages_txn(&txn, messages, next_attempt)?;
+            txn.commit().context(SqliteError)?;
+
+            Ok(ids)
+        })
+    }
+
+    fn claim_outbox_messages(
+        &self,
+        lease_until: chrono::DateTime<chrono::Utc>,
+        limit: u32,
+    ) -> LocalBoxFuture<'static, Result<Vec<OutboxMessage>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let mut conn = db_pool.get().context(ConnectionPoolError)?;
+            let txn = conn.transaction().context(SqliteError)?;
+
+            let mut messages = txn
+                .prepare_cached(
+                    r#"SELECT id, kind, target, payload, status, attempts, last_error,
+                    created_sec, next_attempt_sec, finished_sec
+                FROM outbox
+                WHERE status = $1 AND next_attempt_sec <= $2
+                ORDER BY id
+                LIMIT $3
+                "#,
+                )
+                .context(SqliteError)?
+                .query_map(
+                    params![
+                        OutboxStatus::Pending.as_str(),
+                        chrono::Utc::now().timestamp(),
+                        limit
+                    ],
+                    outbox_message_from_row,
+                )
+                .context(SqliteError)?
+                .collect::<Result<Vec<_>, _>>()
+                .context(SqliteError)?;
+
+            for message in &mut messages {
+                txn.execute(
+                    "UPDATE outbox SET next_attempt_sec = $1 WHERE id = $2",
+                    params![lease_until.timestamp(), message.id],
+                )
+                .context(SqliteError)?;
+                message.next_attempt = Some(lease_until);
+            }
+
+            txn.commit().context(SqliteError)?;
+
+            Ok(messages)
+        })
+    }
+
+    fn record_outbox_attempt(
+        &self,
+        id: i64,
+        attempt: OutboxAttempt,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let mut conn = db_pool.get().context(ConnectionPoolError)?;
+            let txn = conn.transaction().context(SqliteError)?;
+
+            let now = chrono::Utc::now().timestamp();
+            let status = outbox_attempt_status(&attempt);
+            let finished = if status == OutboxStatus::Pending {
+                None
+            } else {
+                Some(now)
+            };
+
+            txn.prepare_cached(
+                r#"UPDATE outbox SET status = $1, attempts = attempts + 1, last_error = $2,
+                    next_attempt_sec = $3, finished_sec = $4
+                WHERE id = $5"#,
+            )
+            .context(SqliteError)?
+            .execute(params![
+                status.as_str(),
+                attempt.error,
+                attempt.next_attempt.map(|time| time.timestamp()),
+                finished,
+                id,
+            ])
+            .context(SqliteError)?;
+
+            txn.prepare_cached(
+                r#"INSERT INTO outbox_attempts
+                    (outbox_id, attempted_sec, status_code, latency_ms, response, error)
+                VALUES ($1, $2, $3, $4, $5, $6)"#,
+            )
+            .context(SqliteError)?
+            .execute(params![
+                id,
+                now,
+                attempt.status_code,
+                attempt.latency_ms,
+                attempt.response,
+                attempt.error,
+            ])
+            .context(SqliteError)?;
+
+            txn.commit().context(SqliteError)?;
+
+            Ok(())
+        })
+    }
+
+    fn get_outbox_message(
+        &self,
+        id: i64,
+    ) -> LocalBoxFuture<'static, Result<Option<OutboxMessage>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
+
+            let row = conn
+                .prepare_cached(
+                    r#"SELECT id, kind, target, payload, status, attempts, last_error,
+                    created_sec, next_attempt_sec, finished_sec
+                FROM outbox
+                WHERE id = $1
+                "#,
+                )
+                .context(SqliteError)?
+                .query_row(params![id], outbox_message_from_row)
+                .map(Some)
+                .or_else(|err| {
+                    if let rusqlite::Error::QueryReturnedNoRows = err {
+                        Ok(None)
+                    } else {
+                        Err(err)
+                    }
+                })
+                .context(SqliteError)?;
+
+            Ok(row)
+        })
+    }
+
+    fn get_outbox_attempts(
+        &self,
+        outbox_ids: Vec<i64>,
+    ) -> LocalBoxFuture<'static, Result<Vec<OutboxAttemptRecord>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
+
+            let mut stmt = conn
+                .prepare_cached(
+                    r#"SELECT outbox_id, attempted_sec, status_code, latency_ms, response, error
+                FROM outbox_attempts
+                WHERE outbox_id = $1
+                ORDER BY id
+                "#,
+                )
+                .context(SqliteError)?;
+
+            let mut attempts = Vec::new();
+            for outbox_id in outbox_ids {
+                let rows = stmt
+                    .query_map(params![outbox_id], |row| {
+                        Ok(OutboxAttemptRecord {
+                            outbox_id: row.get(0)?,
+                            attempted: chrono::Utc.timestamp(row.get(1)?, 0),
+                            status_code: row.get(2)?,
+                            latency_ms: row.get(3)?,
+                            response: row.get(4)?,
+                            error: row.get(5)?,
+                        })
+                    })
+                    .context(SqliteError)?;
+
+                for row in rows {
+                    attempts.push(row.context(SqliteError)?);
+                }
+            }
+
+            Ok(attempts)
+        })
+    }
+
+    fn get_outbox_messages(
+        &self,
+        filter: OutboxFilter,
+    ) -> LocalBoxFuture<'static, Result<Vec<OutboxMessage>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
+
+            let mut stmt = conn
+                .prepare_cached(
+                    r#"SELECT id, kind, target, payload, status, attempts, last_error,
+                    created_sec, next_attempt_sec, finished_sec
+                FROM outbox
+                WHERE ($1 IS NULL OR status = $1)
+                    AND ($2 IS NULL OR target = $2)
+                    AND ($3 IS NULL OR id < $3)
+                    AND ($4 IS NULL OR kind = $4)
+                ORDER BY id DESC
+                LIMIT $5
+                "#,
+                )
+                .context(SqliteError)?;
+
+            let rows: Result<Vec<_>, _> = stmt
+                .query_map(
+                    params![
+                        filter.status.map(OutboxStatus::as_str),
+                        filter.target,
+                        filter.before,
+                        filter.kind.map(OutboxKind::as_str),
+                        filter.limit
+                    ],
+                    outbox_message_from_row,
+                )
+                .context(SqliteError)?
+                .collect();
+
+            Ok(rows.context(SqliteError)?)
+        })
+    }
+
+    fn prune_outbox(
+        &self,
+        finished_before: chrono::DateTime<chrono::Utc>,
+    ) -> LocalBoxFuture<'static, Result<u64, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let mut conn = db_pool.get().context(ConnectionPoolError)?;
+            let txn = conn.transaction().context(SqliteError)?;
+
+            let deleted = txn
+                .execute(
+                    "DELETE FROM outbox WHERE status != $1 AND finished_sec < $2",
+                    params![OutboxStatus::Pending.as_str(), finished_before.timestamp()],
+                )
+                .context(SqliteError)?;
+
+            txn.execute(
+                "DELETE FROM outbox_attempts WHERE outbox_id NOT IN (SELECT id FROM outbox)",
+                params![],
+            )
+            .context(SqliteError)?;
+
+            txn.commit().context(SqliteError)?;
+
+            Ok(deleted as u64)
+        })
+    }
+
+    fn compact_changes(&self) -> LocalBoxFuture<'static, Result<u64, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let mut conn = db_pool.get().context(ConnectionPoolError)?;
+            let txn = conn.transaction().context(SqliteError)?;
+
+            // The latest change is always kept, as it's the ledger version.
+            let superseded = txn
+                .execute(
+                    r#"DELETE FROM changes
+                    WHERE seq < (SELECT MAX(seq) FROM changes)
+                        AND seq NOT IN (SELECT MAX(seq) FROM changes GROUP BY transaction_id, user_id)
+                    "#,
+                    params![],
+                )
+                .context(SqliteError)?;
+
+            // Clients that haven't seen a change to a row that's gone would
+            // never find out about it, so must resync.
+            txn.execute(
+                r#"UPDATE changes_horizon SET seq = MAX(seq, COALESCE((
+                    SELECT MAX(seq) FROM changes
+                    WHERE seq < (SELECT MAX(seq) FROM changes)
+                        AND (transaction_id NOT IN (SELECT id FROM transactions)
+                            OR user_id NOT IN (SELECT user_id FROM users))
+                ), 0))
+                "#,
+                params![],
+            )
+            .context(SqliteError)?;
+
+            let gone = txn
+                .execute(
+                    r#"DELETE FROM changes
+                    WHERE seq < (SELECT MAX(seq) FROM changes)
+                        AND (transaction_id NOT IN (SELECT id FROM transactions)
+                            OR user_id NOT IN (SELECT user_id FROM users))
+                    "#,
+                    params![],
+                )
+                .context(SqliteError)?;
+
+            txn.commit().context(SqliteError)?;
+
+            Ok((superseded + gone) as u64)
+        })
+    }
+
+    fn vacuum(&self) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
+
+            conn.execute_batch("VACUUM; ANALYZE;")
+                .context(SqliteError)?;
+
+            Ok(())
+        })
+    }
+
+    fn check_integrity(
+        &self,
+        repair: bool,
+    ) -> LocalBoxFuture<'static, Result<Vec<IntegrityProblem>, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let mut conn = db_pool.get().context(ConnectionPoolError)?;
+            let txn = conn.transaction().context(SqliteError)?;
+
+            let mut problems = Vec::new();
+            for (kind, query, repair_stmts) in INTEGRITY_CHECKS {
+                let found: Result<Vec<_>, _> = txn
+                    .prepare(query)
+                    .context(SqliteError)?
+                    .query_map(params![], |row| {
+                        Ok(IntegrityProblem {
+                            kind: *kind,
+                            user_id: row.get(0)?,
+                            count: row.get(1)?,
+                        })
+                    })
+                    .context(SqliteError)?
+                    .collect();
+                let found = found.context(SqliteError)?;
+
+                if repair && !found.is_empty() {
+                    for stmt in *repair_stmts {
+                        txn.execute(stmt, params![]).context(SqliteError)?;
+                    }
+                }
+
+                problems.extend(found);
+            }
+
+            txn.commit().context(SqliteError)?;
+
+            Ok(problems)
+        })
+    }
+
+    fn get_schema_version(&self) -> LocalBoxFuture<'static, Result<i64, DatabaseError>> {
+        let db_pool = self.db_pool.clone();
+
+        self.spawn(move || -> Result<_, DatabaseError> {
+            let conn = db_pool.get().context(ConnectionPoolError)?;
+
+            let version = conn
+                .query_row("PRAGMA user_version", params![], |row| row.get(0))
+                .context(SqliteError)?;
+
+            Ok(version)
+        })
+    }
+
+    fn pool_stats(&self) -> PoolStats {
+        let state = self.db_pool.state();
+
+        PoolStats {
+            in_flight: self.in_flight.load(Ordering::SeqCst),
+            max_in_flight: self.max_in_flight,
+            connections: state.connections,
+            idle_connections: state.idle_connections,
+            max_connections: self.db_pool.max_size(),
+        }
+    }
+
+    fn backend_name(&self) -> &'static str {
+        "rusqlite"
+    }
+}
+
+/// Add messages to the outbox, to be attempted from `next_attempt`. Returns
+/// their IDs.
+fn add_outbox_messages_txn(
+    conn: &rusqlite::Connection,
+    messages: Vec<NewOutboxMessage>,
+    next_attempt: chrono::DateTime<chrono::Utc>,
+) -> Result<Vec<i64>, DatabaseError> {
+    let now = chrono::Utc::now().timestamp();
+    let mut ids = Vec::with_capacity(messages.len());
+
+    let mut stmt = conn
+        .prepare_cached(
+            "INSERT INTO outbox (kind, target, payload, status, created_sec, next_attempt_sec)\
+             VALUES ($1, $2, $3, $4, $5, $6)",
+        )
+        .context(SqliteError)?;
+
+    for message in messages {
+        let id = stmt
+            .insert(params![
+                message.kind.as_str(),
+                message.target,
+                message.payload,
+                OutboxStatus::Pending.as_str(),
+                now,
+                next_attempt.timestamp(),
+            ])
+            .context(SqliteError)?;
+        ids.push(id);
+    }
+
+    Ok(ids)
+}
+
+/// Add the outbox messages announcing a change, given its result, within
+/// the change's transaction. They're due straight away.
+fn add_change_outbox_txn<T>(
+    conn: &rusqlite::Connection,
+    outbox: &Outbox<T>,
+    result: &T,
+) -> Result<(), DatabaseError> {
+    add_outbox_messages_txn(conn, outbox.messages(result), chrono::Utc::now())?;
+    Ok(())
+}
+
+/// Get the current revision of a transaction, or None if it doesn't exist.
+fn get_transaction_revision_txn(
+    conn: &rusqlite::Connection,
+    ledger_id: i64,
+    transaction_id: i64,
+) -> Result<Option<i64>, DatabaseError> {
+    conn.prepare_cached("SELECT revision FROM transactions WHERE id = $1 AND ledger_id = $2")
+        .context(SqliteError)?
+        .query_row(params![transaction_id, ledger_id], |row| row.get(0))
+        .map(Some)
+        .or_else(|err| {
+            if let rusqlite::Error::QueryReturnedNoRows = err {
+                Ok(None)
+            } else {
+                Err(err)
+            }
+        })
+        .context(SqliteError)
+}
+
+/// Check that the transaction exists and, if given, is at the expected
+/// revision. Returns the current revision.
+fn check_transaction_revision_txn(
+    conn: &rusqlite::Connection,
+    ledger_id: i64,
+    transaction_id: i64,
+    expected_revision: Option<i64>,
+) -> Result<i64, DatabaseError> {
+    let revision = get_transaction_revision_txn(conn, ledger_id, transaction_id)?
+        .ok_or(DatabaseError::UnknownTransaction { transaction_id })?;
+
+    match expected_revision {
+        Some(expected_revision) if expected_revision != revision => {
+            Err(DatabaseError::RevisionMismatch {
+                transaction_id,
+                revision,
+                expected_revision,
+            })
+        }
+        _ => Ok(revision),
+    }
+}
+
+/// Whether the user is a member of the ledger.
+fn is_ledger_member_txn(
+    conn: &rusqlite::Connection,
+    ledger_id: i64,
+    user_id: &str,
+) -> Result<bool, DatabaseError> {
+    conn.prepare_cached("SELECT user_id FROM ledger_members WHERE ledger_id = $1 AND user_id = $2")
+        .context(SqliteError)?
+        .exists(params![ledger_id, user_id])
+        .context(SqliteError)
+}
+
+/// Get the user's preferences, or the defaults if they haven't set any.
+fn get_user_preferences_txn(
+    conn: &rusqlite::Connection,
+    user_id: &str,
+    currency: Currency,
+) -> Result<UserPreferences, DatabaseError> {
+    conn.prepare_cached(
+        "SELECT default_counterparty, default_currency, quick_amounts
+        FROM user_preferences WHERE user_id = $1",
+    )
+    .context(SqliteError)?
+    .query_row(params![user_id], |row| {
+        let quick_amounts: String = row.get(2)?;
+        Ok(parse_preferences(
+            row.get(0)?,
+            row.get(1)?,
+            &quick_amounts,
+            currency,
+        ))
+    })
+    .or_else(|err| {
+        if let rusqlite::Error::QueryReturnedNoRows = err {
+            Ok(UserPreferences::default())
+        } else {
+            Err(err)
+        }
+    })
+    .context(SqliteError)
+}
+
+/// Format scopes for storage in the `tokens` table.
+pub(super) fn format_scopes(scopes: &[Scope]) -> String {
+    scopes
+        .iter()
+        .map(|scope| scope.as_str())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Parse scopes stored in the `tokens` table, ignoring any we don't know.
+pub(super) fn parse_scopes(scopes: &str) -> Vec<Scope> {
+    scopes
+        .split_whitespace()
+        .filter_map(Scope::from_name)
+        .collect()
+}
+
+/// Parse a transaction kind stored in the `transactions` table. Kinds we
+/// don't know are treated as shafts.
+pub(super) fn parse_kind(kind: &str) -> TransactionKind {
+    TransactionKind::from_name(kind).unwrap_or(TransactionKind::Shaft)
+}
+
+/// Parse an outbox message kind stored in the `outbox` table. Kinds we don't
+/// know are treated as webhooks, which fail to deliver if the target is
+/// unknown.
+pub(super) fn parse_outbox_kind(kind: &str) -> OutboxKind {
+    OutboxKind::from_name(kind).unwrap_or(OutboxKind::Webhook)
+}
+
+/// Parse an outbox message status stored in the `outbox` table. Statuses we
+/// don't know are treated as failed, so they aren't retried.
+pub(super) fn parse_outbox_status(status: &str) -> OutboxStatus {
+    OutboxStatus::from_name(status).unwrap_or(OutboxStatus::Failed)
+}
+
+/// The status an outbox message has after the attempt.
+pub(super) fn outbox_attempt_status(attempt: &OutboxAttempt) -> OutboxStatus {
+    match (&attempt.error, attempt.next_attempt) {
+        (None, _) => OutboxStatus::Delivered,
+        (Some(_), Some(_)) => OutboxStatus::Pending,
+        (Some(_), None) => OutboxStatus::Failed,
+    }
+}
+
+/// Read an outbox message from a row of `id, kind, target, payload, status,
+/// attempts, last_error, created_sec, next_attempt_sec, finished_sec`.
+fn outbox_message_from_row(row: &rusqlite::Row) -> Result<OutboxMessage, rusqlite::Error> {
+    let kind: String = row.get(1)?;
+    let status: String = row.get(4)?;
+    let next_attempt: Option<i64> = row.get(8)?;
+    let finished: Option<i64> = row.get(9)?;
+
+    Ok(OutboxMessage {
+        id: row.get(0)?,
+        kind: parse_outbox_kind(&kind),
+        target: row.get(2)?,
+        payload: row.get(3)?,
+        status: parse_outbox_status(&status),
+        attempts: row.get(5)?,
+        last_error: row.get(6)?,
+        created: chrono::Utc.timestamp(row.get(7)?, 0),
+        next_attempt: next_attempt.map(|time| chrono::Utc.timestamp(time, 0)),
+        finished: finished.map(|time| chrono::Utc.timestamp(time, 0)),
+    })
+}
+
+/// Counts an in flight database operation, decrementing the count when
+/// dropped.
+struct InFlightGuard {
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl InFlightGuard {
+    /// Returns None if there are already `max` operations in flight.
+    fn acquire(in_flight: &Arc<AtomicUsize>, max: usize) -> Option<InFlightGuard> {
+        let mut current = in_flight.load(Ordering::SeqCst);
+        loop {
+            if current >= max {
+                return None;
+            }
+
+            match in_flight.compare_exchange(
+                current,
+                current + 1,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => {
+                    return Some(InFlightGuard {
+                        in_flight: in_flight.clone(),
+                    })
+                }
+                Err(actual) => current = actual,
+            }
+        }
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
     }
 }
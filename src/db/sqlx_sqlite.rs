@@ -0,0 +1,2951 @@
+//! An implementation of [Database] using sqlx, so that database I/O doesn't
+//! block a thread.
+//!
+//! Shares its schema and migrations with [SqliteDatabase](super::SqliteDatabase),
+//! so either can be used with the same database file.
+
+use chrono;
+use chrono::TimeZone;
+use futures::future::{FutureExt, LocalBoxFuture};
+use linear_map::LinearMap;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions, SqliteRow};
+use sqlx::{Connection, Row};
+
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::db::integrity::INTEGRITY_CHECKS;
+use crate::db::migrations::{plan_uses_index, EXPECTED_INDEXES, SQLITE_MIGRATIONS};
+use crate::db::sqlite::{
+    format_scopes, outbox_attempt_status, parse_kind, parse_outbox_kind, parse_outbox_status,
+    parse_scopes,
+};
+use crate::db::{
+    format_quick_amounts, insert_transactions_sql, like_prefix, new_anonymous_user_id,
+    parse_preferences, ApiToken, Attachment, AttachmentInfo, AuditEntry, AuditFilter, Database,
+    DatabaseError, Debt, InboundHook, IntegrityProblem, Ledger, LedgerChanges, NewOutboxMessage,
+    Outbox, OutboxAttempt, OutboxAttemptRecord, OutboxFilter, OutboxKind, OutboxMessage,
+    OutboxStatus, PoolStats, ReceiptSuggestion, Scope, SecurityEvent, SecurityEventFilter,
+    SlackLink, StoredInvalidation, TokenUser, Transaction, TransactionFilter, User, UserEntry,
+    UserExport, UserFilter, UserPreferences, UserSort, DEFAULT_LEDGER_ID,
+    DELETED_USER_DISPLAY_NAME, TRANSACTIONS_PER_INSERT,
+};
+use crate::money::{Currency, Money};
+use crate::settings::{DatabasePoolSettings, SqliteSettings};
+
+/// An implementation of [Database] using sqlx.
+///
+/// Safe to clone as the connection pool will be shared.
+#[derive(Clone)]
+pub struct SqlxDatabase {
+    /// SQLite connection pool.
+    pool: SqlitePool,
+    /// The maximum number of connections the pool will open.
+    max_connections: u32,
+    /// The currency amounts are stored in.
+    currency: Currency,
+}
+
+impl SqlxDatabase {
+    /// Connect to the database at the given path with the given pool
+    /// settings and connection pragmas. If file does not exist a new database
+    /// is created.
+    pub async fn connect<P: AsRef<Path>>(
+        path: P,
+        settings: &DatabasePoolSettings,
+        sqlite_settings: &SqliteSettings,
+    ) -> Result<SqlxDatabase, DatabaseError> {
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true)
+            .busy_timeout(Duration::from_millis(sqlite_settings.busy_timeout_ms))
+            .pragma("journal_mode", sqlite_settings.journal_mode.as_str())
+            .pragma("synchronous", sqlite_settings.synchronous.as_str())
+            .foreign_keys(sqlite_settings.foreign_keys);
+
+        let pool = SqlitePoolOptions::new()
+            .max_connections(settings.max_connections)
+            .acquire_timeout(Duration::from_secs(settings.connection_timeout_secs))
+            .connect_with(options)
+            .await
+            .map_err(sqlx_error)?;
+
+        Ok(SqlxDatabase {
+            pool,
+            max_connections: settings.max_connections,
+            currency: Currency::default(),
+        })
+    }
+
+    /// Set the currency amounts are stored in. Defaults to GBP.
+    pub fn with_currency(mut self, currency: Currency) -> SqlxDatabase {
+        self.currency = currency;
+        self
+    }
+
+    /// Brings the database schema up to date, applying any outstanding
+    /// migrations.
+    pub async fn migrate(&self) -> Result<(), DatabaseError> {
+        let mut conn = self.pool.acquire().await.map_err(sqlx_error)?;
+
+        let current_version: i64 = sqlx::query("PRAGMA user_version")
+            .fetch_one(&mut conn)
+            .await
+            .and_then(|row| row.try_get(0))
+            .map_err(sqlx_error)?;
+
+        for (idx, migration) in SQLITE_MIGRATIONS
+            .iter()
+            .enumerate()
+            .skip(current_version as usize)
+        {
+            let mut txn = conn.begin().await.map_err(sqlx_error)?;
+
+            sqlx::query(migration)
+                .execute(&mut txn)
+                .await
+                .map_err(sqlx_error)?;
+            sqlx::query(&format!("PRAGMA user_version = {}", idx + 1))
+                .execute(&mut txn)
+                .await
+                .map_err(sqlx_error)?;
+
+            txn.commit().await.map_err(sqlx_error)?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the queries that should be using an index are, returning
+    /// the indexes that look to be missing.
+    pub async fn missing_indexes(&self) -> Result<Vec<&'static str>, DatabaseError> {
+        let mut missing = Vec::new();
+        for (index, query) in EXPECTED_INDEXES {
+            let rows = sqlx::query(&format!("EXPLAIN QUERY PLAN {}", query))
+                .fetch_all(&self.pool)
+                .await
+                .map_err(sqlx_error)?;
+
+            let mut uses_index = false;
+            for row in rows {
+                let detail: String = row.try_get(3).map_err(sqlx_error)?;
+                uses_index |= plan_uses_index(&detail);
+            }
+
+            if !uses_index {
+                missing.push(*index);
+            }
+        }
+
+        Ok(missing)
+    }
+}
+
+impl Database for SqlxDatabase {
+    fn get_user_by_github_id(
+        &self,
+        github_user_id: String,
+    ) -> LocalBoxFuture<'static, Result<Option<String>, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let row = sqlx::query("SELECT user_id FROM github_users WHERE github_id = ?1")
+                .bind(github_user_id)
+                .fetch_optional(&pool)
+                .await
+                .map_err(sqlx_error)?;
+
+            row.map(|row| row.try_get(0))
+                .transpose()
+                .map_err(sqlx_error)
+        }
+        .boxed_local()
+    }
+
+    fn add_user_by_github_id(
+        &self,
+        github_user_id: String,
+        display_name: String,
+        outbox: Outbox<String>,
+    ) -> LocalBoxFuture<'static, Result<String, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let mut txn = pool.begin().await.map_err(sqlx_error)?;
+
+            sqlx::query("INSERT INTO github_users (user_id, github_id) VALUES (?1, ?1)")
+                .bind(&github_user_id)
+                .execute(&mut txn)
+                .await
+                .map_err(sqlx_error)?;
+
+            sqlx::query("INSERT INTO users (user_id, display_name) VALUES (?1, ?2)")
+                .bind(&github_user_id)
+                .bind(display_name)
+                .execute(&mut txn)
+                .await
+                .map_err(sqlx_error)?;
+
+            sqlx::query("INSERT INTO ledger_members (ledger_id, user_id) VALUES (?1, ?2)")
+                .bind(DEFAULT_LEDGER_ID)
+                .bind(&github_user_id)
+                .execute(&mut txn)
+                .await
+                .map_err(sqlx_error)?;
+
+            add_change_outbox_txn(&mut txn, &outbox, &github_user_id).await?;
+            txn.commit().await.map_err(sqlx_error)?;
+
+            Ok(github_user_id)
+        }
+        .boxed_local()
+    }
+
+    fn get_user_by_slack_id(
+        &self,
+        team_id: String,
+        slack_user_id: String,
+    ) -> LocalBoxFuture<'static, Result<Option<String>, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let row = sqlx::query(
+                "SELECT user_id FROM slack_users WHERE team_id = ?1 AND slack_user_id = ?2",
+            )
+            .bind(team_id)
+            .bind(slack_user_id)
+            .fetch_optional(&pool)
+            .await
+            .map_err(sqlx_error)?;
+
+            row.map(|row| row.try_get(0))
+                .transpose()
+                .map_err(sqlx_error)
+        }
+        .boxed_local()
+    }
+
+    fn link_slack_user(
+        &self,
+        team_id: String,
+        slack_user_id: String,
+        user_id: String,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            sqlx::query(
+                "INSERT OR REPLACE INTO slack_users (team_id, slack_user_id, user_id)
+                VALUES (?1, ?2, ?3)",
+            )
+            .bind(team_id)
+            .bind(slack_user_id)
+            .bind(user_id)
+            .execute(&pool)
+            .await
+            .map_err(sqlx_error)?;
+
+            Ok(())
+        }
+        .boxed_local()
+    }
+
+    fn create_token_for_user(
+        &self,
+        user_id: String,
+        scopes: Vec<Scope>,
+        expires: chrono::DateTime<chrono::Utc>,
+        outbox: Outbox<String>,
+    ) -> LocalBoxFuture<'static, Result<String, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let mut txn = pool.begin().await.map_err(sqlx_error)?;
+
+            let deactivated =
+                sqlx::query("SELECT user_id FROM users WHERE user_id = ?1 AND NOT active")
+                    .bind(&user_id)
+                    .fetch_optional(&mut txn)
+                    .await
+                    .map_err(sqlx_error)?;
+            if deactivated.is_some() {
+                return Err(DatabaseError::UserDeactivated { user_id });
+            }
+
+            let token: String = thread_rng().sample_iter(&Alphanumeric).take(32).collect();
+
+            sqlx::query(
+                r#"
+                INSERT INTO tokens (user_id, token, scopes, expires_sec, issued_sec)
+                VALUES (?1, ?2, ?3, ?4, ?5)
+                "#,
+            )
+            .bind(user_id)
+            .bind(&token)
+            .bind(format_scopes(&scopes))
+            .bind(expires.timestamp())
+            .bind(chrono::Utc::now().timestamp())
+            .execute(&mut txn)
+            .await
+            .map_err(sqlx_error)?;
+
+            add_change_outbox_txn(&mut txn, &outbox, &token).await?;
+            txn.commit().await.map_err(sqlx_error)?;
+
+            Ok(token)
+        }
+        .boxed_local()
+    }
+
+    fn delete_token(&self, token: String) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            sqlx::query("DELETE FROM tokens WHERE token = ?1")
+                .bind(token)
+                .execute(&pool)
+                .await
+                .map_err(sqlx_error)?;
+
+            Ok(())
+        }
+        .boxed_local()
+    }
+
+    fn refresh_token(
+        &self,
+        token: String,
+        issued: chrono::DateTime<chrono::Utc>,
+        expires: chrono::DateTime<chrono::Utc>,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            sqlx::query("UPDATE tokens SET issued_sec = ?2, expires_sec = ?3 WHERE token = ?1")
+                .bind(token)
+                .bind(issued.timestamp())
+                .bind(expires.timestamp())
+                .execute(&pool)
+                .await
+                .map_err(sqlx_error)?;
+
+            Ok(())
+        }
+        .boxed_local()
+    }
+
+    fn get_user_from_token(
+        &self,
+        token: String,
+    ) -> LocalBoxFuture<'static, Result<Option<TokenUser>, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let row = sqlx::query(
+                r#"
+                SELECT user_id, display_name, scopes, issued_sec, expires_sec
+                FROM tokens
+                INNER JOIN users USING (user_id)
+                WHERE token = ?1 AND (expires_sec IS NULL OR expires_sec > ?2)
+                "#,
+            )
+            .bind(token)
+            .bind(chrono::Utc::now().timestamp())
+            .fetch_optional(&pool)
+            .await
+            .map_err(sqlx_error)?;
+
+            row.map(|row| -> Result<_, sqlx::Error> {
+                let scopes: String = row.try_get(2)?;
+                let issued: Option<i64> = row.try_get(3)?;
+                let expires: Option<i64> = row.try_get(4)?;
+                Ok(TokenUser {
+                    user_id: row.try_get(0)?,
+                    display_name: row.try_get(1)?,
+                    scopes: parse_scopes(&scopes),
+                    issued: issued.map(|secs| chrono::Utc.timestamp(secs, 0)),
+                    expires: expires.map(|secs| chrono::Utc.timestamp(secs, 0)),
+                })
+            })
+            .transpose()
+            .map_err(sqlx_error)
+        }
+        .boxed_local()
+    }
+
+    fn get_user_id_for_token(
+        &self,
+        token: String,
+    ) -> LocalBoxFuture<'static, Result<Option<String>, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let row = sqlx::query(
+                "SELECT user_id FROM tokens WHERE token = ?1 AND (expires_sec IS NULL OR expires_sec > ?2)",
+            )
+            .bind(token)
+            .bind(chrono::Utc::now().timestamp())
+            .fetch_optional(&pool)
+            .await
+            .map_err(sqlx_error)?;
+
+            row.map(|row| row.try_get(0))
+                .transpose()
+                .map_err(sqlx_error)
+        }
+        .boxed_local()
+    }
+
+    fn create_api_token(
+        &self,
+        user_id: String,
+        name: String,
+        scopes: Vec<Scope>,
+        outbox: Outbox<(i64, String)>,
+    ) -> LocalBoxFuture<'static, Result<(i64, String), DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let mut txn = pool.begin().await.map_err(sqlx_error)?;
+
+            let token: String = thread_rng().sample_iter(&Alphanumeric).take(32).collect();
+
+            let done = sqlx::query(
+                "INSERT INTO tokens (user_id, token, scopes, name) VALUES (?1, ?2, ?3, ?4)",
+            )
+            .bind(user_id)
+            .bind(&token)
+            .bind(format_scopes(&scopes))
+            .bind(name)
+            .execute(&mut txn)
+            .await
+            .map_err(sqlx_error)?;
+
+            let created = (done.last_insert_rowid(), token);
+            add_change_outbox_txn(&mut txn, &outbox, &created).await?;
+            txn.commit().await.map_err(sqlx_error)?;
+
+            Ok(created)
+        }
+        .boxed_local()
+    }
+
+    fn get_api_tokens(
+        &self,
+        user_id: String,
+    ) -> LocalBoxFuture<'static, Result<Vec<ApiToken>, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let rows = sqlx::query(
+                r#"
+                SELECT id, name, scopes FROM tokens
+                WHERE user_id = ?1 AND name IS NOT NULL
+                ORDER BY id
+                "#,
+            )
+            .bind(user_id)
+            .fetch_all(&pool)
+            .await
+            .map_err(sqlx_error)?;
+
+            rows.iter()
+                .map(|row| -> Result<_, sqlx::Error> {
+                    let scopes: String = row.try_get(2)?;
+                    Ok(ApiToken {
+                        id: row.try_get(0)?,
+                        name: row.try_get(1)?,
+                        scopes: parse_scopes(&scopes),
+                    })
+                })
+                .collect::<Result<_, _>>()
+                .map_err(sqlx_error)
+        }
+        .boxed_local()
+    }
+
+    fn create_inbound_hook(
+        &self,
+        user_id: String,
+        name: String,
+        outbox: Outbox<(i64, String)>,
+    ) -> LocalBoxFuture<'static, Result<(i64, String), DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let mut txn = pool.begin().await.map_err(sqlx_error)?;
+
+            let hook_id: String = thread_rng().sample_iter(&Alphanumeric).take(32).collect();
+
+            let done = sqlx::query(
+                "INSERT INTO inbound_hooks (hook_id, user_id, name, created_sec)
+                VALUES (?1, ?2, ?3, ?4)",
+            )
+            .bind(&hook_id)
+            .bind(user_id)
+            .bind(name)
+            .bind(chrono::Utc::now().timestamp())
+            .execute(&mut txn)
+            .await
+            .map_err(sqlx_error)?;
+
+            let created = (done.last_insert_rowid(), hook_id);
+            add_change_outbox_txn(&mut txn, &outbox, &created).await?;
+            txn.commit().await.map_err(sqlx_error)?;
+
+            Ok(created)
+        }
+        .boxed_local()
+    }
+
+    fn get_inbound_hooks(
+        &self,
+        user_id: String,
+    ) -> LocalBoxFuture<'static, Result<Vec<InboundHook>, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let rows = sqlx::query(
+                "SELECT id, name, created_sec FROM inbound_hooks WHERE user_id = ?1 ORDER BY id",
+            )
+            .bind(user_id)
+            .fetch_all(&pool)
+            .await
+            .map_err(sqlx_error)?;
+
+            rows.iter()
+                .map(|row| -> Result<_, sqlx::Error> {
+                    Ok(InboundHook {
+                        id: row.try_get(0)?,
+                        name: row.try_get(1)?,
+                        created: chrono::Utc.timestamp(row.try_get(2)?, 0),
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(sqlx_error)
+        }
+        .boxed_local()
+    }
+
+    fn delete_inbound_hook(
+        &self,
+        user_id: String,
+        id: i64,
+        outbox: Outbox<bool>,
+    ) -> LocalBoxFuture<'static, Result<bool, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let mut txn = pool.begin().await.map_err(sqlx_error)?;
+
+            let done = sqlx::query("DELETE FROM inbound_hooks WHERE id = ?1 AND user_id = ?2")
+                .bind(id)
+                .bind(user_id)
+                .execute(&mut txn)
+                .await
+                .map_err(sqlx_error)?;
+
+            let deleted = done.rows_affected() > 0;
+            add_change_outbox_txn(&mut txn, &outbox, &deleted).await?;
+            txn.commit().await.map_err(sqlx_error)?;
+
+            Ok(deleted)
+        }
+        .boxed_local()
+    }
+
+    fn get_user_for_inbound_hook(
+        &self,
+        hook_id: String,
+    ) -> LocalBoxFuture<'static, Result<Option<String>, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let row = sqlx::query("SELECT user_id FROM inbound_hooks WHERE hook_id = ?1")
+                .bind(hook_id)
+                .fetch_optional(&pool)
+                .await
+                .map_err(sqlx_error)?;
+
+            row.map(|row| row.try_get(0))
+                .transpose()
+                .map_err(sqlx_error)
+        }
+        .boxed_local()
+    }
+
+    fn add_audit_entry(
+        &self,
+        entry: AuditEntry,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let done = sqlx::query(
+                "INSERT INTO audit_log (actor, action, target, details, time_sec)\
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )
+            .bind(entry.actor)
+            .bind(entry.action)
+            .bind(entry.target)
+            .bind(entry.details)
+            .bind(entry.datetime.timestamp())
+            .execute(&pool)
+            .await
+            .map_err(sqlx_error)?;
+
+            Ok(done.last_insert_rowid())
+        }
+        .boxed_local()
+    }
+
+    fn get_audit_entries(
+        &self,
+        filter: AuditFilter,
+    ) -> LocalBoxFuture<'static, Result<Vec<AuditEntry>, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let rows = sqlx::query(
+                r#"SELECT id, actor, action, target, details, time_sec
+                FROM audit_log
+                WHERE (?1 IS NULL OR actor = ?1)
+                    AND (?2 IS NULL OR action = ?2)
+                    AND (?3 IS NULL OR time_sec >= ?3)
+                    AND (?4 IS NULL OR time_sec < ?4)
+                    AND (?5 IS NULL OR id < ?5)
+                    AND (?7 IS NULL OR target = ?7)
+                ORDER BY id DESC
+                LIMIT ?6
+                "#,
+            )
+            .bind(filter.actor)
+            .bind(filter.action)
+            .bind(filter.from.map(|from| from.timestamp()))
+            .bind(filter.to.map(|to| to.timestamp()))
+            .bind(filter.before)
+            .bind(i64::from(filter.limit))
+            .bind(filter.target)
+            .fetch_all(&pool)
+            .await
+            .map_err(sqlx_error)?;
+
+            rows.iter()
+                .map(|row| -> Result<_, sqlx::Error> {
+                    Ok(AuditEntry {
+                        id: Some(row.try_get(0)?),
+                        actor: row.try_get(1)?,
+                        action: row.try_get(2)?,
+                        target: row.try_get(3)?,
+                        details: row.try_get(4)?,
+                        datetime: chrono::Utc.timestamp(row.try_get(5)?, 0),
+                    })
+                })
+                .collect::<Result<_, _>>()
+                .map_err(sqlx_error)
+        }
+        .boxed_local()
+    }
+
+    fn add_security_event(
+        &self,
+        event: SecurityEvent,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let done = sqlx::query(
+                "INSERT INTO security_events (kind, user_id, ip, details, time_sec)\
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )
+            .bind(event.kind)
+            .bind(event.user_id)
+            .bind(event.ip)
+            .bind(event.details)
+            .bind(event.datetime.timestamp())
+            .execute(&pool)
+            .await
+            .map_err(sqlx_error)?;
+
+            Ok(done.last_insert_rowid())
+        }
+        .boxed_local()
+    }
+
+    fn get_security_events(
+        &self,
+        filter: SecurityEventFilter,
+    ) -> LocalBoxFuture<'static, Result<Vec<SecurityEvent>, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let rows = sqlx::query(
+                r#"SELECT id, kind, user_id, ip, details, time_sec
+                FROM security_events
+                WHERE (?1 IS NULL OR kind = ?1)
+                    AND (?2 IS NULL OR id < ?2)
+                ORDER BY id DESC
+                LIMIT ?3
+                "#,
+            )
+            .bind(filter.kind)
+            .bind(filter.before)
+            .bind(i64::from(filter.limit))
+            .fetch_all(&pool)
+            .await
+            .map_err(sqlx_error)?;
+
+            rows.iter()
+                .map(|row| -> Result<_, sqlx::Error> {
+                    Ok(SecurityEvent {
+                        id: Some(row.try_get(0)?),
+                        kind: row.try_get(1)?,
+                        user_id: row.try_get(2)?,
+                        ip: row.try_get(3)?,
+                        details: row.try_get(4)?,
+                        datetime: chrono::Utc.timestamp(row.try_get(5)?, 0),
+                    })
+                })
+                .collect::<Result<_, _>>()
+                .map_err(sqlx_error)
+        }
+        .boxed_local()
+    }
+
+    fn delete_api_token(
+        &self,
+        user_id: String,
+        token_id: i64,
+        outbox: Outbox<bool>,
+    ) -> LocalBoxFuture<'static, Result<bool, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let mut txn = pool.begin().await.map_err(sqlx_error)?;
+
+            let done = sqlx::query(
+                "DELETE FROM tokens WHERE id = ?1 AND user_id = ?2 AND name IS NOT NULL",
+            )
+            .bind(token_id)
+            .bind(user_id)
+            .execute(&mut txn)
+            .await
+            .map_err(sqlx_error)?;
+
+            let deleted = done.rows_affected() > 0;
+            add_change_outbox_txn(&mut txn, &outbox, &deleted).await?;
+            txn.commit().await.map_err(sqlx_error)?;
+
+            Ok(deleted)
+        }
+        .boxed_local()
+    }
+
+    fn create_ledger(
+        &self,
+        name: String,
+        display_name: String,
+        outbox: Outbox<Ledger>,
+    ) -> LocalBoxFuture<'static, Result<Ledger, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let mut txn = pool.begin().await.map_err(sqlx_error)?;
+
+            let exists = sqlx::query("SELECT id FROM ledgers WHERE name = ?1")
+                .bind(&name)
+                .fetch_optional(&mut txn)
+                .await
+                .map_err(sqlx_error)?
+                .is_some();
+            if exists {
+                return Err(DatabaseError::LedgerExists { name });
+            }
+
+            let done = sqlx::query("INSERT INTO ledgers (name, display_name) VALUES (?1, ?2)")
+                .bind(&name)
+                .bind(&display_name)
+                .execute(&mut txn)
+                .await
+                .map_err(sqlx_error)?;
+
+            let ledger = Ledger {
+                id: done.last_insert_rowid(),
+                name,
+                display_name,
+            };
+            add_change_outbox_txn(&mut txn, &outbox, &ledger).await?;
+            txn.commit().await.map_err(sqlx_error)?;
+
+            Ok(ledger)
+        }
+        .boxed_local()
+    }
+
+    fn get_ledger_by_name(
+        &self,
+        name: String,
+    ) -> LocalBoxFuture<'static, Result<Option<Ledger>, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let row = sqlx::query("SELECT id, name, display_name FROM ledgers WHERE name = ?1")
+                .bind(name)
+                .fetch_optional(&pool)
+                .await
+                .map_err(sqlx_error)?;
+
+            row.map(|row| ledger_from_row(&row))
+                .transpose()
+                .map_err(sqlx_error)
+        }
+        .boxed_local()
+    }
+
+    fn get_ledgers_for_user(
+        &self,
+        user_id: String,
+    ) -> LocalBoxFuture<'static, Result<Vec<Ledger>, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let rows = sqlx::query(
+                r#"SELECT id, name, display_name
+                FROM ledgers
+                JOIN ledger_members ON ledgers.id = ledger_members.ledger_id
+                WHERE user_id = ?1
+                ORDER BY id ASC
+                "#,
+            )
+            .bind(user_id)
+            .fetch_all(&pool)
+            .await
+            .map_err(sqlx_error)?;
+
+            rows.iter()
+                .map(ledger_from_row)
+                .collect::<Result<_, _>>()
+                .map_err(sqlx_error)
+        }
+        .boxed_local()
+    }
+
+    fn add_ledger_member(
+        &self,
+        ledger_id: i64,
+        user_id: String,
+        outbox: Outbox<()>,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let mut txn = pool.begin().await.map_err(sqlx_error)?;
+
+            let known = sqlx::query("SELECT user_id FROM users WHERE user_id = ?1")
+                .bind(&user_id)
+                .fetch_optional(&mut txn)
+                .await
+                .map_err(sqlx_error)?
+                .is_some();
+            if !known {
+                return Err(DatabaseError::UnknownUser { user_id });
+            }
+
+            sqlx::query(
+                "INSERT OR IGNORE INTO ledger_members (ledger_id, user_id) VALUES (?1, ?2)",
+            )
+            .bind(ledger_id)
+            .bind(user_id)
+            .execute(&mut txn)
+            .await
+            .map_err(sqlx_error)?;
+
+            add_change_outbox_txn(&mut txn, &outbox, &()).await?;
+            txn.commit().await.map_err(sqlx_error)?;
+
+            Ok(())
+        }
+        .boxed_local()
+    }
+
+    fn is_ledger_member(
+        &self,
+        ledger_id: i64,
+        user_id: String,
+    ) -> LocalBoxFuture<'static, Result<bool, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let mut conn = pool.acquire().await.map_err(sqlx_error)?;
+
+            is_ledger_member_txn(&mut conn, ledger_id, &user_id).await
+        }
+        .boxed_local()
+    }
+
+    fn get_balance_for_user(
+        &self,
+        ledger_id: i64,
+        user: String,
+    ) -> LocalBoxFuture<'static, Result<Money, DatabaseError>> {
+        let pool = self.pool.clone();
+        let currency = self.currency;
+
+        async move {
+            sqlx::query(
+                r#"SELECT (
+                    SELECT COALESCE(SUM(amount), 0)
+                    FROM transactions
+                    WHERE shafter = ?1 AND ledger_id = ?2
+                ) - (
+                    SELECT COALESCE(SUM(amount), 0)
+                    FROM transactions
+                    WHERE shaftee = ?1 AND ledger_id = ?2
+                )"#,
+            )
+            .bind(user)
+            .bind(ledger_id)
+            .fetch_one(&pool)
+            .await
+            .and_then(|row| row.try_get(0))
+            .map(|amount| Money::new(amount, currency))
+            .map_err(sqlx_error)
+        }
+        .boxed_local()
+    }
+
+    fn get_balances_by_counterparty(
+        &self,
+        ledger_id: i64,
+        user_id: String,
+    ) -> LocalBoxFuture<'static, Result<Vec<User>, DatabaseError>> {
+        let pool = self.pool.clone();
+        let currency = self.currency;
+
+        async move {
+            let rows = sqlx::query(
+                r#"
+                SELECT other, COALESCE(display_name, other), SUM(amount) AS balance
+                FROM (
+                    SELECT shaftee AS other, amount FROM transactions
+                    WHERE ledger_id = ?1 AND shafter = ?2
+                    UNION ALL
+                    SELECT shafter AS other, -amount FROM transactions
+                    WHERE ledger_id = ?1 AND shaftee = ?2
+                ) t
+                LEFT JOIN users ON users.user_id = t.other
+                WHERE other != ?2
+                GROUP BY other
+                HAVING balance != 0
+                ORDER BY balance ASC, other
+                "#,
+            )
+            .bind(ledger_id)
+            .bind(user_id)
+            .fetch_all(&pool)
+            .await
+            .map_err(sqlx_error)?;
+
+            rows.iter()
+                .map(|row| user_from_row(row, currency))
+                .collect::<Result<_, _>>()
+                .map_err(sqlx_error)
+        }
+        .boxed_local()
+    }
+
+    fn get_outstanding_debts(
+        &self,
+        ledger_id: i64,
+    ) -> LocalBoxFuture<'static, Result<Vec<Debt>, DatabaseError>> {
+        let pool = self.pool.clone();
+        let currency = self.currency;
+
+        async move {
+            // Each pair's transactions are signed by the order of the pair's
+            // user IDs, so that a positive balance means the second owes the
+            // first. A debt starts when the running balance leaves zero or
+            // changes sign, and is settled when it returns to zero or changes
+            // sign.
+            let rows = sqlx::query(
+                r#"
+                WITH pairs AS (
+                    SELECT
+                        MIN(shafter, shaftee) AS a,
+                        MAX(shafter, shaftee) AS b,
+                        CASE WHEN shafter < shaftee THEN amount ELSE -amount END AS amount,
+                        time_sec,
+                        id
+                    FROM transactions
+                    WHERE ledger_id = ?1 AND shafter != shaftee
+                ),
+                running AS (
+                    SELECT a, b, amount, time_sec,
+                        SUM(amount) OVER w AS balance,
+                        SUM(amount) OVER w - amount AS prev
+                    FROM pairs
+                    WINDOW w AS (PARTITION BY a, b ORDER BY time_sec, id ROWS UNBOUNDED PRECEDING)
+                )
+                SELECT a, b, SUM(amount) AS total,
+                    MAX(CASE WHEN balance != 0 AND (prev = 0 OR (balance > 0) != (prev > 0))
+                        THEN time_sec END) AS since_sec,
+                    MAX(CASE WHEN prev != 0 AND (balance = 0 OR (balance > 0) != (prev > 0))
+                        THEN time_sec END) AS settled_sec
+                FROM running
+                GROUP BY a, b
+                HAVING total != 0
+                ORDER BY ABS(total) DESC, since_sec, a, b
+                "#,
+            )
+            .bind(ledger_id)
+            .fetch_all(&pool)
+            .await
+            .map_err(sqlx_error)?;
+
+            rows.iter()
+                .map(|row| -> Result<_, sqlx::Error> {
+                    let a: String = row.try_get(0)?;
+                    let b: String = row.try_get(1)?;
+                    let total: i64 = row.try_get(2)?;
+                    let settled_sec: Option<i64> = row.try_get(4)?;
+                    let (debtor, creditor) = if total > 0 { (b, a) } else { (a, b) };
+                    Ok(Debt {
+                        debtor,
+                        creditor,
+                        amount: Money::new(total.abs(), currency),
+                        since: chrono::Utc.timestamp(row.try_get(3)?, 0),
+                        last_settled: settled_sec.map(|sec| chrono::Utc.timestamp(sec, 0)),
+                    })
+                })
+                .collect::<Result<_, _>>()
+                .map_err(sqlx_error)
+        }
+        .boxed_local()
+    }
+
+    fn get_all_users(
+        &self,
+        ledger_id: i64,
+    ) -> LocalBoxFuture<'static, Result<LinearMap<String, User>, DatabaseError>> {
+        let pool = self.pool.clone();
+        let currency = self.currency;
+
+        async move {
+            let rows = sqlx::query(
+                r#"
+                SELECT user_id, display_name, COALESCE(balance, 0) AS balance
+                FROM users
+                JOIN ledger_members USING (user_id)
+                LEFT JOIN (
+                    SELECT user_id, SUM(amount) as balance
+                    FROM (
+                        SELECT shafter AS user_id, SUM(amount) AS amount
+                        FROM transactions WHERE ledger_id = ?1 GROUP BY shafter
+                        UNION ALL
+                        SELECT shaftee AS user_id, -SUM(amount) AS amount
+                        FROM transactions WHERE ledger_id = ?1 GROUP BY shaftee
+                    ) t GROUP BY user_id
+                )
+                USING (user_id)
+                WHERE ledger_members.ledger_id = ?1
+                ORDER BY balance ASC
+                "#,
+            )
+            .bind(ledger_id)
+            .fetch_all(&pool)
+            .await
+            .map_err(sqlx_error)?;
+
+            rows.iter()
+                .map(|row| -> Result<_, sqlx::Error> {
+                    let user = user_from_row(row, currency)?;
+                    Ok((user.user_id.clone(), user))
+                })
+                .collect::<Result<_, _>>()
+                .map_err(sqlx_error)
+        }
+        .boxed_local()
+    }
+
+    fn get_users(
+        &self,
+        ledger_id: i64,
+        filter: UserFilter,
+    ) -> LocalBoxFuture<'static, Result<Vec<UserEntry>, DatabaseError>> {
+        let pool = self.pool.clone();
+        let currency = self.currency;
+
+        async move {
+            let rows = sqlx::query(
+                r#"
+                SELECT user_id, display_name, COALESCE(balance, 0) AS balance, active
+                FROM users
+                JOIN ledger_members USING (user_id)
+                LEFT JOIN (
+                    SELECT user_id, SUM(amount) as balance, MAX(last_sec) AS last_sec
+                    FROM (
+                        SELECT shafter AS user_id, SUM(amount) AS amount, MAX(time_sec) AS last_sec
+                        FROM transactions WHERE ledger_id = ?1 GROUP BY shafter
+                        UNION ALL
+                        SELECT shaftee AS user_id, -SUM(amount) AS amount, MAX(time_sec) AS last_sec
+                        FROM transactions WHERE ledger_id = ?1 GROUP BY shaftee
+                    ) t GROUP BY user_id
+                )
+                USING (user_id)
+                WHERE ledger_members.ledger_id = ?1
+                    AND (?2 IS NULL OR active = ?2)
+                    AND (?3 IS NULL OR user_id LIKE ?3 ESCAPE '\' OR display_name LIKE ?3 ESCAPE '\')
+                    AND (NOT ?5 OR COALESCE(balance, 0) != 0)
+                    AND (?8 IS NULL OR COALESCE(balance, 0) != 0 OR last_sec >= ?8)
+                ORDER BY CASE WHEN ?4 THEN lower(display_name) END, balance ASC, user_id
+                LIMIT COALESCE(?6, -1) OFFSET ?7
+                "#,
+            )
+            .bind(ledger_id)
+            .bind(filter.active)
+            .bind(filter.prefix.as_deref().map(like_prefix))
+            .bind(filter.sort == UserSort::Name)
+            .bind(filter.nonzero_balance)
+            .bind(filter.limit)
+            .bind(filter.offset)
+            .bind(filter.settled_before.map(|before| before.timestamp()))
+            .fetch_all(&pool)
+            .await
+            .map_err(sqlx_error)?;
+
+            rows.iter()
+                .map(|row| -> Result<_, sqlx::Error> {
+                    Ok(UserEntry {
+                        user: user_from_row(row, currency)?,
+                        active: row.try_get(3)?,
+                    })
+                })
+                .collect::<Result<_, _>>()
+                .map_err(sqlx_error)
+        }
+        .boxed_local()
+    }
+
+    fn shaft_user(
+        &self,
+        ledger_id: i64,
+        transaction: Transaction,
+        outbox: Outbox<i64>,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let mut txn = pool.begin().await.map_err(sqlx_error)?;
+
+            let transaction_id = insert_transaction(&mut txn, ledger_id, transaction).await?;
+
+            add_change_outbox_txn(&mut txn, &outbox, &transaction_id).await?;
+            txn.commit().await.map_err(sqlx_error)?;
+
+            Ok(transaction_id)
+        }
+        .boxed_local()
+    }
+
+    fn get_last_transactions(
+        &self,
+        ledger_id: i64,
+        limit: u32,
+    ) -> LocalBoxFuture<'static, Result<Vec<Transaction>, DatabaseError>> {
+        let pool = self.pool.clone();
+        let currency = self.currency;
+
+        async move {
+            let rows = sqlx::query(
+                r#"SELECT id, shafter, shaftee, amount, time_sec, reason, kind
+                FROM transactions
+                WHERE ledger_id = ?1
+                ORDER BY id DESC
+                LIMIT ?2
+                "#,
+            )
+            .bind(ledger_id)
+            .bind(i64::from(limit))
+            .fetch_all(&pool)
+            .await
+            .map_err(sqlx_error)?;
+
+            rows.iter()
+                .map(|row| transaction_from_row(row, currency))
+                .collect::<Result<_, _>>()
+                .map_err(sqlx_error)
+        }
+        .boxed_local()
+    }
+
+    fn get_transactions(
+        &self,
+        ledger_id: i64,
+        filter: TransactionFilter,
+    ) -> LocalBoxFuture<'static, Result<Vec<Transaction>, DatabaseError>> {
+        let pool = self.pool.clone();
+        let currency = self.currency;
+
+        async move {
+            let rows = sqlx::query(
+                r#"SELECT id, shafter, shaftee, amount, time_sec, reason, kind
+                FROM transactions
+                WHERE (?1 IS NULL OR shafter = ?1 OR shaftee = ?1)
+                    AND (?2 IS NULL OR time_sec >= ?2)
+                    AND (?3 IS NULL OR time_sec < ?3)
+                    AND (?4 IS NULL OR id < ?4)
+                    AND ledger_id = ?6
+                ORDER BY id DESC
+                LIMIT ?5
+                "#,
+            )
+            .bind(filter.user)
+            .bind(filter.from.map(|from| from.timestamp()))
+            .bind(filter.to.map(|to| to.timestamp()))
+            .bind(filter.before)
+            .bind(i64::from(filter.limit))
+            .bind(ledger_id)
+            .fetch_all(&pool)
+            .await
+            .map_err(sqlx_error)?;
+
+            rows.iter()
+                .map(|row| transaction_from_row(row, currency))
+                .collect::<Result<_, _>>()
+                .map_err(sqlx_error)
+        }
+        .boxed_local()
+    }
+
+    fn get_transaction_revision(
+        &self,
+        ledger_id: i64,
+        transaction_id: i64,
+    ) -> LocalBoxFuture<'static, Result<Option<i64>, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let mut conn = pool.acquire().await.map_err(sqlx_error)?;
+
+            get_transaction_revision_txn(&mut conn, ledger_id, transaction_id).await
+        }
+        .boxed_local()
+    }
+
+    fn update_transaction(
+        &self,
+        ledger_id: i64,
+        transaction_id: i64,
+        expected_revision: Option<i64>,
+        amount: Money,
+        reason: String,
+        outbox: Outbox<i64>,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let mut txn = pool.begin().await.map_err(sqlx_error)?;
+
+            let revision = check_transaction_revision_txn(
+                &mut txn,
+                ledger_id,
+                transaction_id,
+                expected_revision,
+            )
+            .await?;
+
+            sqlx::query(
+                "UPDATE transactions SET amount = ?1, reason = ?2, revision = ?3
+                WHERE id = ?4",
+            )
+            .bind(amount.minor_units())
+            .bind(reason)
+            .bind(revision + 1)
+            .bind(transaction_id)
+            .execute(&mut txn)
+            .await
+            .map_err(sqlx_error)?;
+
+            let revision = revision + 1;
+            add_change_outbox_txn(&mut txn, &outbox, &revision).await?;
+            txn.commit().await.map_err(sqlx_error)?;
+
+            Ok(revision)
+        }
+        .boxed_local()
+    }
+
+    fn delete_transaction(
+        &self,
+        ledger_id: i64,
+        transaction_id: i64,
+        expected_revision: Option<i64>,
+        outbox: Outbox<()>,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let mut txn = pool.begin().await.map_err(sqlx_error)?;
+
+            check_transaction_revision_txn(&mut txn, ledger_id, transaction_id, expected_revision)
+                .await?;
+
+            sqlx::query("DELETE FROM transactions WHERE id = ?1")
+                .bind(transaction_id)
+                .execute(&mut txn)
+                .await
+                .map_err(sqlx_error)?;
+
+            add_change_outbox_txn(&mut txn, &outbox, &()).await?;
+            txn.commit().await.map_err(sqlx_error)?;
+
+            Ok(())
+        }
+        .boxed_local()
+    }
+
+    fn get_ledger_version(&self) -> LocalBoxFuture<'static, Result<i64, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            sqlx::query("SELECT COALESCE(MAX(seq), 0) FROM changes")
+                .fetch_one(&pool)
+                .await
+                .and_then(|row| row.try_get(0))
+                .map_err(sqlx_error)
+        }
+        .boxed_local()
+    }
+
+    fn get_last_modified(
+        &self,
+        ledger_id: i64,
+        user_id: Option<String>,
+    ) -> LocalBoxFuture<'static, Result<Option<chrono::DateTime<chrono::Utc>>, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let modified_sec: Option<i64> = sqlx::query(
+                "SELECT MAX(modified_sec) FROM last_modified
+                WHERE scope IN ('global', 'ledger:' || ?1, 'user:' || ?2)",
+            )
+            .bind(ledger_id)
+            .bind(user_id)
+            .fetch_one(&pool)
+            .await
+            .and_then(|row| row.try_get(0))
+            .map_err(sqlx_error)?;
+
+            Ok(modified_sec.map(|secs| chrono::Utc.timestamp(secs, 0)))
+        }
+        .boxed_local()
+    }
+
+    fn get_changes_since(
+        &self,
+        ledger_id: i64,
+        cursor: i64,
+        limit: u32,
+    ) -> LocalBoxFuture<'static, Result<LedgerChanges, DatabaseError>> {
+        let pool = self.pool.clone();
+        let currency = self.currency;
+
+        async move {
+            // We use a transaction so that we get a consistent snapshot.
+            let mut txn = pool.begin().await.map_err(sqlx_error)?;
+
+            let row = sqlx::query(
+                "SELECT (SELECT seq FROM changes_horizon), (SELECT COALESCE(MAX(seq), 0) FROM changes)",
+            )
+            .fetch_one(&mut txn)
+            .await
+            .map_err(sqlx_error)?;
+            let horizon: i64 = row.try_get(0).map_err(sqlx_error)?;
+            if cursor > 0 && cursor < horizon {
+                return Ok(LedgerChanges {
+                    next_cursor: row.try_get(1).map_err(sqlx_error)?,
+                    transactions: Vec::new(),
+                    deleted_transactions: Vec::new(),
+                    users: Vec::new(),
+                    resync_required: true,
+                });
+            }
+
+            let mut next_cursor = cursor;
+            let mut transaction_ids = BTreeSet::new();
+            let mut user_ids = BTreeSet::new();
+
+            let rows = sqlx::query(
+                r#"SELECT seq, transaction_id, user_id
+                FROM changes
+                WHERE seq > ?1
+                ORDER BY seq ASC
+                LIMIT ?2
+                "#,
+            )
+            .bind(cursor)
+            .bind(i64::from(limit))
+            .fetch_all(&mut txn)
+            .await
+            .map_err(sqlx_error)?;
+
+            for row in &rows {
+                next_cursor = row.try_get(0).map_err(sqlx_error)?;
+
+                let transaction_id: Option<i64> = row.try_get(1).map_err(sqlx_error)?;
+                let user_id: Option<String> = row.try_get(2).map_err(sqlx_error)?;
+
+                transaction_ids.extend(transaction_id);
+                user_ids.extend(user_id);
+            }
+
+            let mut transactions = Vec::new();
+            let mut deleted_transactions = Vec::new();
+
+            for transaction_id in transaction_ids {
+                let row = sqlx::query(
+                    r#"SELECT id, shafter, shaftee, amount, time_sec, reason, kind, ledger_id
+                    FROM transactions
+                    WHERE id = ?1
+                    "#,
+                )
+                .bind(transaction_id)
+                .fetch_optional(&mut txn)
+                .await
+                .map_err(sqlx_error)?;
+
+                match row {
+                    Some(row) => {
+                        let row_ledger_id: i64 = row.try_get(7).map_err(sqlx_error)?;
+                        // Changes to other ledgers are skipped.
+                        if row_ledger_id == ledger_id {
+                            transactions
+                                .push(transaction_from_row(&row, currency).map_err(sqlx_error)?);
+                        }
+                    }
+                    None => deleted_transactions.push(transaction_id),
+                }
+            }
+
+            let mut users = Vec::new();
+
+            for user_id in user_ids {
+                let row = sqlx::query(
+                    r#"SELECT user_id, display_name, (
+                        SELECT COALESCE(SUM(amount), 0)
+                        FROM transactions
+                        WHERE shafter = ?1 AND ledger_id = ?2
+                    ) - (
+                        SELECT COALESCE(SUM(amount), 0)
+                        FROM transactions
+                        WHERE shaftee = ?1 AND ledger_id = ?2
+                    )
+                    FROM users
+                    JOIN ledger_members USING (user_id)
+                    WHERE user_id = ?1 AND ledger_id = ?2
+                    "#,
+                )
+                .bind(user_id)
+                .bind(ledger_id)
+                .fetch_optional(&mut txn)
+                .await
+                .map_err(sqlx_error)?;
+
+                if let Some(row) = row {
+                    users.push(user_from_row(&row, currency).map_err(sqlx_error)?);
+                }
+            }
+
+            Ok(LedgerChanges {
+                next_cursor,
+                transactions,
+                deleted_transactions,
+                users,
+                resync_required: false,
+            })
+        }
+        .boxed_local()
+    }
+
+    fn get_all_transactions(
+        &self,
+        ledger_id: i64,
+    ) -> LocalBoxFuture<'static, Result<Vec<Transaction>, DatabaseError>> {
+        let pool = self.pool.clone();
+        let currency = self.currency;
+
+        async move {
+            let rows = sqlx::query(
+                r#"SELECT id, shafter, shaftee, amount, time_sec, reason, kind
+                FROM transactions
+                WHERE ledger_id = ?1
+                ORDER BY id ASC
+                "#,
+            )
+            .bind(ledger_id)
+            .fetch_all(&pool)
+            .await
+            .map_err(sqlx_error)?;
+
+            rows.iter()
+                .map(|row| transaction_from_row(row, currency))
+                .collect::<Result<_, _>>()
+                .map_err(sqlx_error)
+        }
+        .boxed_local()
+    }
+
+    fn insert_transactions_batch(
+        &self,
+        ledger_id: i64,
+        transactions: Vec<Transaction>,
+        outbox: Outbox<Vec<i64>>,
+    ) -> LocalBoxFuture<'static, Result<Vec<i64>, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let mut txn = pool.begin().await.map_err(sqlx_error)?;
+
+            // Dropping the transaction on error rolls back anything we've
+            // inserted so far.
+            let mut checked = BTreeSet::new();
+            for transaction in &transactions {
+                if checked.insert(transaction.shaftee.as_str())
+                    && !is_ledger_member_txn(&mut txn, ledger_id, &transaction.shaftee).await?
+                {
+                    return Err(DatabaseError::UnknownUser {
+                        user_id: transaction.shaftee.clone(),
+                    });
+                }
+            }
+
+            let mut transaction_ids = Vec::with_capacity(transactions.len());
+
+            for chunk in transactions.chunks(TRANSACTIONS_PER_INSERT) {
+                let sql = insert_transactions_sql(chunk.len());
+                let mut query = sqlx::query(&sql);
+                for transaction in chunk {
+                    query = query
+                        .bind(transaction.shafter.as_str())
+                        .bind(transaction.shaftee.as_str())
+                        .bind(transaction.amount.minor_units())
+                        .bind(transaction.datetime.timestamp())
+                        .bind(transaction.reason.as_str())
+                        .bind(transaction.kind.as_str())
+                        .bind(ledger_id);
+                }
+
+                let done = query.execute(&mut txn).await.map_err(sqlx_error)?;
+
+                // A single insert gets consecutive IDs, as we hold the write
+                // lock.
+                let last_id = done.last_insert_rowid();
+                let first_id = last_id - chunk.len() as i64 + 1;
+                transaction_ids.extend(first_id..=last_id);
+            }
+
+            add_change_outbox_txn(&mut txn, &outbox, &transaction_ids).await?;
+            txn.commit().await.map_err(sqlx_error)?;
+
+            Ok(transaction_ids)
+        }
+        .boxed_local()
+    }
+
+    fn get_transaction(
+        &self,
+        ledger_id: i64,
+        transaction_id: i64,
+    ) -> LocalBoxFuture<'static, Result<Option<Transaction>, DatabaseError>> {
+        let pool = self.pool.clone();
+        let currency = self.currency;
+
+        async move {
+            let row = sqlx::query(
+                r#"SELECT id, shafter, shaftee, amount, time_sec, reason, kind
+                FROM transactions
+                WHERE id = ?1 AND ledger_id = ?2
+                "#,
+            )
+            .bind(transaction_id)
+            .bind(ledger_id)
+            .fetch_optional(&pool)
+            .await
+            .map_err(sqlx_error)?;
+
+            row.map(|row| transaction_from_row(&row, currency))
+                .transpose()
+                .map_err(sqlx_error)
+        }
+        .boxed_local()
+    }
+
+    fn add_attachment(
+        &self,
+        attachment: Attachment,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let done = sqlx::query(
+                "INSERT INTO attachments (transaction_id, uploader, content_type, data, time_sec)\
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )
+            .bind(attachment.transaction_id)
+            .bind(attachment.uploader)
+            .bind(attachment.content_type)
+            .bind(attachment.data)
+            .bind(chrono::Utc::now().timestamp())
+            .execute(&pool)
+            .await
+            .map_err(sqlx_error)?;
+
+            Ok(done.last_insert_rowid())
+        }
+        .boxed_local()
+    }
+
+    fn add_receipt_suggestion(
+        &self,
+        attachment_id: i64,
+        suggestion: ReceiptSuggestion,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            sqlx::query(
+                "INSERT INTO receipt_suggestions (attachment_id, amount, merchant)
+                VALUES (?1, ?2, ?3)",
+            )
+            .bind(attachment_id)
+            .bind(suggestion.amount)
+            .bind(suggestion.merchant)
+            .execute(&pool)
+            .await
+            .map_err(sqlx_error)?;
+
+            Ok(())
+        }
+        .boxed_local()
+    }
+
+    fn get_receipt_suggestions(
+        &self,
+        transaction_id: i64,
+    ) -> LocalBoxFuture<'static, Result<Vec<(i64, ReceiptSuggestion)>, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let rows = sqlx::query(
+                r#"SELECT attachment_id, amount, merchant
+                FROM receipt_suggestions
+                INNER JOIN attachments ON attachments.id = receipt_suggestions.attachment_id
+                WHERE transaction_id = ?1
+                ORDER BY attachment_id ASC
+                "#,
+            )
+            .bind(transaction_id)
+            .fetch_all(&pool)
+            .await
+            .map_err(sqlx_error)?;
+
+            rows.iter()
+                .map(|row| -> Result<_, sqlx::Error> {
+                    Ok((
+                        row.try_get(0)?,
+                        ReceiptSuggestion {
+                            amount: row.try_get(1)?,
+                            merchant: row.try_get(2)?,
+                        },
+                    ))
+                })
+                .collect::<Result<_, _>>()
+                .map_err(sqlx_error)
+        }
+        .boxed_local()
+    }
+
+    fn get_user_preferences(
+        &self,
+        user_id: String,
+    ) -> LocalBoxFuture<'static, Result<UserPreferences, DatabaseError>> {
+        let pool = self.pool.clone();
+        let currency = self.currency;
+
+        async move {
+            let row = sqlx::query(
+                "SELECT default_counterparty, default_currency, quick_amounts
+                FROM user_preferences WHERE user_id = ?1",
+            )
+            .bind(&user_id)
+            .fetch_optional(&pool)
+            .await
+            .map_err(sqlx_error)?;
+
+            match row {
+                Some(row) => preferences_from_row(&row, currency).map_err(sqlx_error),
+                None => Ok(UserPreferences::default()),
+            }
+        }
+        .boxed_local()
+    }
+
+    fn set_user_preferences(
+        &self,
+        user_id: String,
+        preferences: UserPreferences,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            sqlx::query(
+                r#"INSERT INTO user_preferences
+                    (user_id, default_counterparty, default_currency, quick_amounts)
+                VALUES (?1, ?2, ?3, ?4)
+                ON CONFLICT (user_id) DO UPDATE SET
+                    default_counterparty = excluded.default_counterparty,
+                    default_currency = excluded.default_currency,
+                    quick_amounts = excluded.quick_amounts
+                "#,
+            )
+            .bind(user_id)
+            .bind(preferences.default_counterparty)
+            .bind(
+                preferences
+                    .default_currency
+                    .map(|code| code.as_str().to_string()),
+            )
+            .bind(format_quick_amounts(&preferences.quick_amounts))
+            .execute(&pool)
+            .await
+            .map_err(sqlx_error)?;
+
+            Ok(())
+        }
+        .boxed_local()
+    }
+
+    fn export_user(
+        &self,
+        user_id: String,
+    ) -> LocalBoxFuture<'static, Result<Option<UserExport>, DatabaseError>> {
+        let pool = self.pool.clone();
+        let currency = self.currency;
+
+        async move {
+            // Read everything in one transaction so the export is consistent.
+            let mut txn = pool.begin().await.map_err(sqlx_error)?;
+
+            let row = sqlx::query(
+                r#"SELECT user_id, display_name, (
+                    SELECT COALESCE(SUM(amount), 0) FROM transactions WHERE shafter = ?1
+                ) - (
+                    SELECT COALESCE(SUM(amount), 0) FROM transactions WHERE shaftee = ?1
+                )
+                FROM users
+                WHERE user_id = ?1
+                "#,
+            )
+            .bind(&user_id)
+            .fetch_optional(&mut txn)
+            .await
+            .map_err(sqlx_error)?;
+
+            let user = match row {
+                Some(row) => user_from_row(&row, currency).map_err(sqlx_error)?,
+                None => return Ok(None),
+            };
+
+            let github_ids = sqlx::query("SELECT github_id FROM github_users WHERE user_id = ?1")
+                .bind(&user_id)
+                .fetch_all(&mut txn)
+                .await
+                .map_err(sqlx_error)?
+                .iter()
+                .map(|row| row.try_get(0))
+                .collect::<Result<_, _>>()
+                .map_err(sqlx_error)?;
+
+            let transactions = sqlx::query(
+                r#"SELECT id, shafter, shaftee, amount, time_sec, reason, kind
+                FROM transactions
+                WHERE shafter = ?1 OR shaftee = ?1
+                ORDER BY id ASC
+                "#,
+            )
+            .bind(&user_id)
+            .fetch_all(&mut txn)
+            .await
+            .map_err(sqlx_error)?
+            .iter()
+            .map(|row| transaction_from_row(row, currency))
+            .collect::<Result<_, _>>()
+            .map_err(sqlx_error)?;
+
+            let api_tokens = sqlx::query(
+                "SELECT id, name, scopes FROM tokens
+                WHERE user_id = ?1 AND name IS NOT NULL
+                ORDER BY id",
+            )
+            .bind(&user_id)
+            .fetch_all(&mut txn)
+            .await
+            .map_err(sqlx_error)?
+            .iter()
+            .map(|row| -> Result<_, sqlx::Error> {
+                let scopes: String = row.try_get(2)?;
+                Ok(ApiToken {
+                    id: row.try_get(0)?,
+                    name: row.try_get(1)?,
+                    scopes: parse_scopes(&scopes),
+                })
+            })
+            .collect::<Result<_, _>>()
+            .map_err(sqlx_error)?;
+
+            let sessions =
+                sqlx::query("SELECT COUNT(*) FROM tokens WHERE user_id = ?1 AND name IS NULL")
+                    .bind(&user_id)
+                    .fetch_one(&mut txn)
+                    .await
+                    .and_then(|row| row.try_get(0))
+                    .map_err(sqlx_error)?;
+
+            let attachments = sqlx::query(
+                "SELECT id, transaction_id, content_type, time_sec FROM attachments
+                WHERE uploader = ?1
+                ORDER BY id",
+            )
+            .bind(&user_id)
+            .fetch_all(&mut txn)
+            .await
+            .map_err(sqlx_error)?
+            .iter()
+            .map(|row| -> Result<_, sqlx::Error> {
+                Ok(AttachmentInfo {
+                    id: row.try_get(0)?,
+                    transaction_id: row.try_get(1)?,
+                    content_type: row.try_get(2)?,
+                    datetime: chrono::Utc.timestamp(row.try_get(3)?, 0),
+                })
+            })
+            .collect::<Result<_, _>>()
+            .map_err(sqlx_error)?;
+
+            let audit_log = sqlx::query(
+                "SELECT id, actor, action, target, details, time_sec FROM audit_log
+                WHERE actor = ?1
+                ORDER BY id",
+            )
+            .bind(&user_id)
+            .fetch_all(&mut txn)
+            .await
+            .map_err(sqlx_error)?
+            .iter()
+            .map(|row| -> Result<_, sqlx::Error> {
+                Ok(AuditEntry {
+                    id: Some(row.try_get(0)?),
+                    actor: row.try_get(1)?,
+                    action: row.try_get(2)?,
+                    target: row.try_get(3)?,
+                    details: row.try_get(4)?,
+                    datetime: chrono::Utc.timestamp(row.try_get(5)?, 0),
+                })
+            })
+            .collect::<Result<_, _>>()
+            .map_err(sqlx_error)?;
+
+            let preferences = sqlx::query(
+                "SELECT default_counterparty, default_currency, quick_amounts
+                FROM user_preferences WHERE user_id = ?1",
+            )
+            .bind(&user_id)
+            .fetch_optional(&mut txn)
+            .await
+            .map_err(sqlx_error)?
+            .map(|row| preferences_from_row(&row, currency))
+            .transpose()
+            .map_err(sqlx_error)?
+            .unwrap_or_default();
+
+            let slack_links = sqlx::query(
+                "SELECT team_id, slack_user_id FROM slack_users
+                WHERE user_id = ?1
+                ORDER BY team_id, slack_user_id",
+            )
+            .bind(&user_id)
+            .fetch_all(&mut txn)
+            .await
+            .map_err(sqlx_error)?
+            .iter()
+            .map(|row| -> Result<_, sqlx::Error> {
+                Ok(SlackLink {
+                    team_id: row.try_get(0)?,
+                    slack_user_id: row.try_get(1)?,
+                })
+            })
+            .collect::<Result<_, _>>()
+            .map_err(sqlx_error)?;
+
+            let inbound_hooks = sqlx::query(
+                "SELECT id, name, created_sec FROM inbound_hooks WHERE user_id = ?1 ORDER BY id",
+            )
+            .bind(&user_id)
+            .fetch_all(&mut txn)
+            .await
+            .map_err(sqlx_error)?
+            .iter()
+            .map(|row| -> Result<_, sqlx::Error> {
+                Ok(InboundHook {
+                    id: row.try_get(0)?,
+                    name: row.try_get(1)?,
+                    created: chrono::Utc.timestamp(row.try_get(2)?, 0),
+                })
+            })
+            .collect::<Result<_, _>>()
+            .map_err(sqlx_error)?;
+
+            let ledgers = sqlx::query(
+                r#"SELECT id, name, display_name
+                FROM ledgers
+                JOIN ledger_members ON ledgers.id = ledger_members.ledger_id
+                WHERE user_id = ?1
+                ORDER BY id ASC
+                "#,
+            )
+            .bind(&user_id)
+            .fetch_all(&mut txn)
+            .await
+            .map_err(sqlx_error)?
+            .iter()
+            .map(ledger_from_row)
+            .collect::<Result<_, _>>()
+            .map_err(sqlx_error)?;
+
+            let security_events = sqlx::query(
+                "SELECT id, kind, user_id, ip, details, time_sec FROM security_events
+                WHERE user_id = ?1
+                ORDER BY id",
+            )
+            .bind(&user_id)
+            .fetch_all(&mut txn)
+            .await
+            .map_err(sqlx_error)?
+            .iter()
+            .map(|row| -> Result<_, sqlx::Error> {
+                Ok(SecurityEvent {
+                    id: Some(row.try_get(0)?),
+                    kind: row.try_get(1)?,
+                    user_id: row.try_get(2)?,
+                    ip: row.try_get(3)?,
+                    details: row.try_get(4)?,
+                    datetime: chrono::Utc.timestamp(row.try_get(5)?, 0),
+                })
+            })
+            .collect::<Result<_, _>>()
+            .map_err(sqlx_error)?;
+
+            Ok(Some(UserExport {
+                user,
+                github_ids,
+                transactions,
+                api_tokens,
+                sessions,
+                attachments,
+                audit_log,
+                preferences,
+                slack_links,
+                inbound_hooks,
+                ledgers,
+                security_events,
+            }))
+        }
+        .boxed_local()
+    }
+
+    fn delete_user(
+        &self,
+        user_id: String,
+        outbox: Outbox<String>,
+    ) -> LocalBoxFuture<'static, Result<String, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let mut txn = pool.begin().await.map_err(sqlx_error)?;
+
+            let anonymous_id = new_anonymous_user_id();
+
+            let renamed =
+                sqlx::query("UPDATE users SET user_id = ?1, display_name = ?2 WHERE user_id = ?3")
+                    .bind(&anonymous_id)
+                    .bind(DELETED_USER_DISPLAY_NAME)
+                    .bind(&user_id)
+                    .execute(&mut txn)
+                    .await
+                    .map_err(sqlx_error)?
+                    .rows_affected();
+            if renamed == 0 {
+                return Err(DatabaseError::UnknownUser { user_id });
+            }
+
+            for stmt in &[
+                "UPDATE transactions SET shafter = ?1 WHERE shafter = ?2",
+                "UPDATE transactions SET shaftee = ?1 WHERE shaftee = ?2",
+                "UPDATE attachments SET uploader = ?1 WHERE uploader = ?2",
+                "UPDATE audit_log SET actor = ?1 WHERE actor = ?2",
+                "UPDATE audit_log SET target = ?1 WHERE target = ?2",
+                "UPDATE security_events SET user_id = ?1 WHERE user_id = ?2",
+                "UPDATE changes SET user_id = ?1 WHERE user_id = ?2",
+                "UPDATE ledger_members SET user_id = ?1 WHERE user_id = ?2",
+                "UPDATE user_preferences SET default_counterparty = ?1
+                    WHERE default_counterparty = ?2",
+            ] {
+                sqlx::query(stmt)
+                    .bind(&anonymous_id)
+                    .bind(&user_id)
+                    .execute(&mut txn)
+                    .await
+                    .map_err(sqlx_error)?;
+            }
+
+            for stmt in &[
+                "DELETE FROM github_users WHERE user_id = ?1",
+                "DELETE FROM tokens WHERE user_id = ?1",
+                "DELETE FROM slack_users WHERE user_id = ?1",
+                "DELETE FROM inbound_hooks WHERE user_id = ?1",
+                "DELETE FROM user_preferences WHERE user_id = ?1",
+            ] {
+                sqlx::query(stmt)
+                    .bind(&user_id)
+                    .execute(&mut txn)
+                    .await
+                    .map_err(sqlx_error)?;
+            }
+
+            add_change_outbox_txn(&mut txn, &outbox, &anonymous_id).await?;
+            txn.commit().await.map_err(sqlx_error)?;
+
+            Ok(anonymous_id)
+        }
+        .boxed_local()
+    }
+
+    fn deactivate_user(
+        &self,
+        user_id: String,
+        outbox: Outbox<()>,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let mut txn = pool.begin().await.map_err(sqlx_error)?;
+
+            let updated = sqlx::query("UPDATE users SET active = 0 WHERE user_id = ?1")
+                .bind(&user_id)
+                .execute(&mut txn)
+                .await
+                .map_err(sqlx_error)?
+                .rows_affected();
+            if updated == 0 {
+                return Err(DatabaseError::UnknownUser { user_id });
+            }
+
+            for stmt in &[
+                "DELETE FROM tokens WHERE user_id = ?1",
+                "DELETE FROM slack_users WHERE user_id = ?1",
+                "DELETE FROM inbound_hooks WHERE user_id = ?1",
+            ] {
+                sqlx::query(stmt)
+                    .bind(&user_id)
+                    .execute(&mut txn)
+                    .await
+                    .map_err(sqlx_error)?;
+            }
+
+            add_change_outbox_txn(&mut txn, &outbox, &()).await?;
+            txn.commit().await.map_err(sqlx_error)?;
+
+            Ok(())
+        }
+        .boxed_local()
+    }
+
+    fn get_deactivated_users(&self) -> LocalBoxFuture<'static, Result<Vec<String>, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let rows = sqlx::query("SELECT user_id FROM users WHERE NOT active ORDER BY user_id")
+                .fetch_all(&pool)
+                .await
+                .map_err(sqlx_error)?;
+
+            rows.iter()
+                .map(|row| row.try_get(0))
+                .collect::<Result<_, _>>()
+                .map_err(sqlx_error)
+        }
+        .boxed_local()
+    }
+
+    fn merge_users(
+        &self,
+        from: String,
+        into: String,
+        outbox: Outbox<()>,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let mut txn = pool.begin().await.map_err(sqlx_error)?;
+
+            for user_id in &[&from, &into] {
+                let row = sqlx::query("SELECT user_id FROM users WHERE user_id = ?1")
+                    .bind(user_id.as_str())
+                    .fetch_optional(&mut txn)
+                    .await
+                    .map_err(sqlx_error)?;
+                if row.is_none() {
+                    return Err(DatabaseError::UnknownUser {
+                        user_id: user_id.to_string(),
+                    });
+                }
+            }
+
+            for stmt in &[
+                "UPDATE transactions SET shafter = ?1 WHERE shafter = ?2",
+                "UPDATE transactions SET shaftee = ?1 WHERE shaftee = ?2",
+                "UPDATE attachments SET uploader = ?1 WHERE uploader = ?2",
+                "UPDATE inbound_hooks SET user_id = ?1 WHERE user_id = ?2",
+                "UPDATE slack_users SET user_id = ?1 WHERE user_id = ?2",
+                "INSERT OR IGNORE INTO ledger_members (ledger_id, user_id)
+                    SELECT ledger_id, ?1 FROM ledger_members WHERE user_id = ?2",
+            ] {
+                sqlx::query(stmt)
+                    .bind(&into)
+                    .bind(&from)
+                    .execute(&mut txn)
+                    .await
+                    .map_err(sqlx_error)?;
+            }
+
+            // Users only have one GitHub account, so `from`'s can't move.
+            for stmt in &[
+                "DELETE FROM ledger_members WHERE user_id = ?1",
+                "DELETE FROM github_users WHERE user_id = ?1",
+                "DELETE FROM tokens WHERE user_id = ?1",
+                "DELETE FROM users WHERE user_id = ?1",
+            ] {
+                sqlx::query(stmt)
+                    .bind(&from)
+                    .execute(&mut txn)
+                    .await
+                    .map_err(sqlx_error)?;
+            }
+
+            add_change_outbox_txn(&mut txn, &outbox, &()).await?;
+            txn.commit().await.map_err(sqlx_error)?;
+
+            Ok(())
+        }
+        .boxed_local()
+    }
+
+    fn prune_tokens(
+        &self,
+        issued_before: Option<chrono::DateTime<chrono::Utc>>,
+        outbox: Outbox<u64>,
+    ) -> LocalBoxFuture<'static, Result<u64, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let mut txn = pool.begin().await.map_err(sqlx_error)?;
+
+            // Sessions from before issue times were recorded count as old.
+            let done = sqlx::query(
+                r#"
+                DELETE FROM tokens
+                WHERE expires_sec < ?1
+                    OR (name IS NULL AND ?2 IS NOT NULL AND COALESCE(issued_sec, 0) < ?2)
+                "#,
+            )
+            .bind(chrono::Utc::now().timestamp())
+            .bind(issued_before.map(|issued_before| issued_before.timestamp()))
+            .execute(&mut txn)
+            .await
+            .map_err(sqlx_error)?;
+
+            let deleted = done.rows_affected();
+            add_change_outbox_txn(&mut txn, &outbox, &deleted).await?;
+            txn.commit().await.map_err(sqlx_error)?;
+
+            Ok(deleted)
+        }
+        .boxed_local()
+    }
+
+    fn put_shared_value(
+        &self,
+        key: String,
+        value: String,
+        expires: chrono::DateTime<chrono::Utc>,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            sqlx::query(
+                "INSERT OR REPLACE INTO shared_values (key, value, expires_sec) VALUES (?1, ?2, ?3)",
+            )
+            .bind(key)
+            .bind(value)
+            .bind(expires.timestamp())
+            .execute(&pool)
+            .await
+            .map_err(sqlx_error)?;
+
+            Ok(())
+        }
+        .boxed_local()
+    }
+
+    fn take_shared_value(
+        &self,
+        key: String,
+    ) -> LocalBoxFuture<'static, Result<Option<String>, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let mut txn = pool.begin().await.map_err(sqlx_error)?;
+
+            let row =
+                sqlx::query("SELECT value FROM shared_values WHERE key = ?1 AND expires_sec > ?2")
+                    .bind(&key)
+                    .bind(chrono::Utc::now().timestamp())
+                    .fetch_optional(&mut txn)
+                    .await
+                    .map_err(sqlx_error)?;
+            let value = row
+                .map(|row| row.try_get(0))
+                .transpose()
+                .map_err(sqlx_error)?;
+
+            sqlx::query("DELETE FROM shared_values WHERE key = ?1")
+                .bind(&key)
+                .execute(&mut txn)
+                .await
+                .map_err(sqlx_error)?;
+
+            txn.commit().await.map_err(sqlx_error)?;
+
+            Ok(value)
+        }
+        .boxed_local()
+    }
+
+    fn add_invalidation(
+        &self,
+        instance_id: String,
+        payload: String,
+    ) -> LocalBoxFuture<'static, Result<i64, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let done = sqlx::query(
+                "INSERT INTO invalidations (instance_id, payload, time_sec) VALUES (?1, ?2, ?3)",
+            )
+            .bind(instance_id)
+            .bind(payload)
+            .bind(chrono::Utc::now().timestamp())
+            .execute(&pool)
+            .await
+            .map_err(sqlx_error)?;
+
+            Ok(done.last_insert_rowid())
+        }
+        .boxed_local()
+    }
+
+    fn get_invalidations(
+        &self,
+        after: i64,
+    ) -> LocalBoxFuture<'static, Result<Vec<StoredInvalidation>, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let rows = sqlx::query(
+                "SELECT id, instance_id, payload FROM invalidations WHERE id > ?1 ORDER BY id",
+            )
+            .bind(after)
+            .fetch_all(&pool)
+            .await
+            .map_err(sqlx_error)?;
+
+            rows.iter()
+                .map(|row| -> Result<_, sqlx::Error> {
+                    Ok(StoredInvalidation {
+                        id: row.try_get(0)?,
+                        instance_id: row.try_get(1)?,
+                        payload: row.try_get(2)?,
+                    })
+                })
+                .collect::<Result<_, _>>()
+                .map_err(sqlx_error)
+        }
+        .boxed_local()
+    }
+
+    fn get_last_invalidation_id(&self) -> LocalBoxFuture<'static, Result<i64, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            sqlx::query("SELECT COALESCE(MAX(id), 0) FROM invalidations")
+                .fetch_one(&pool)
+                .await
+                .and_then(|row| row.try_get(0))
+                .map_err(sqlx_error)
+        }
+        .boxed_local()
+    }
+
+    fn prune_shared_state(
+        &self,
+        published_before: chrono::DateTime<chrono::Utc>,
+    ) -> LocalBoxFuture<'static, Result<u64, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let values = sqlx::query("DELETE FROM shared_values WHERE expires_sec <= ?1")
+                .bind(chrono::Utc::now().timestamp())
+                .execute(&pool)
+                .await
+                .map_err(sqlx_error)?;
+
+            let invalidations = sqlx::query("DELETE FROM invalidations WHERE time_sec < ?1")
+                .bind(published_before.timestamp())
+                .execute(&pool)
+                .await
+                .map_err(sqlx_error)?;
+
+            Ok(values.rows_affected() + invalidations.rows_affected())
+        }
+        .boxed_local()
+    }
+
+    fn add_outbox_messages(
+        &self,
+        messages: Vec<NewOutboxMessage>,
+        next_attempt: chrono::DateTime<chrono::Utc>,
+    ) -> LocalBoxFuture<'static, Result<Vec<i64>, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let mut txn = pool.begin().await.map_err(sqlx_error)?;
+
+            let ids = add_outbox_messages_txn(&mut txn, messages, next_attempt).await?;
+
+            txn.commit().await.map_err(sqlx_error)?;
+
+            Ok(ids)
+        }
+        .boxed_local()
+    }
+
+    fn claim_outbox_messages(
+        &self,
+        lease_until: chrono::DateTime<chrono::Utc>,
+        limit: u32,
+    ) -> LocalBoxFuture<'static, Result<Vec<OutboxMessage>, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let mut txn = pool.begin().await.map_err(sqlx_error)?;
+
+            let rows = sqlx::query(
+                r#"SELECT id, kind, target, payload, status, attempts, last_error,
+                    created_sec, next_attempt_sec, finished_sec
+                FROM outbox
+                WHERE status = ?1 AND next_attempt_sec <= ?2
+                ORDER BY id
+                LIMIT ?3
+                "#,
+            )
+            .bind(OutboxStatus::Pending.as_str())
+            .bind(chrono::Utc::now().timestamp())
+            .bind(i64::from(limit))
+            .fetch_all(&mut txn)
+            .await
+            .map_err(sqlx_error)?;
+
+            let mut messages = rows
+                .iter()
+                .map(outbox_message_from_row)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(sqlx_error)?;
+
+            for message in &mut messages {
+                sqlx::query("UPDATE outbox SET next_attempt_sec = ?1 WHERE id = ?2")
+                    .bind(lease_until.timestamp())
+                    .bind(message.id)
+                    .execute(&mut txn)
+                    .await
+                    .map_err(sqlx_error)?;
+                message.next_attempt = Some(lease_until);
+            }
+
+            txn.commit().await.map_err(sqlx_error)?;
+
+            Ok(messages)
+        }
+        .boxed_local()
+    }
+
+    fn record_outbox_attempt(
+        &self,
+        id: i64,
+        attempt: OutboxAttempt,
+    ) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let mut txn = pool.begin().await.map_err(sqlx_error)?;
+
+            let now = chrono::Utc::now().timestamp();
+            let status = outbox_attempt_status(&attempt);
+            let finished = if status == OutboxStatus::Pending {
+                None
+            } else {
+                Some(now)
+            };
+
+            sqlx::query(
+                r#"UPDATE outbox SET status = ?2, attempts = attempts + 1, last_error = ?3,
+                    next_attempt_sec = ?4, finished_sec = ?5
+                WHERE id = ?1"#,
+            )
+            .bind(id)
+            .bind(status.as_str())
+            .bind(&attempt.error)
+            .bind(attempt.next_attempt.map(|time| time.timestamp()))
+            .bind(finished)
+            .execute(&mut txn)
+            .await
+            .map_err(sqlx_error)?;
+
+            sqlx::query(
+                r#"INSERT INTO outbox_attempts
+                    (outbox_id, attempted_sec, status_code, latency_ms, response, error)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)"#,
+            )
+            .bind(id)
+            .bind(now)
+            .bind(attempt.status_code.map(i64::from))
+            .bind(attempt.latency_ms)
+            .bind(attempt.response)
+            .bind(attempt.error)
+            .execute(&mut txn)
+            .await
+            .map_err(sqlx_error)?;
+
+            txn.commit().await.map_err(sqlx_error)?;
+
+            Ok(())
+        }
+        .boxed_local()
+    }
+
+    fn get_outbox_message(
+        &self,
+        id: i64,
+    ) -> LocalBoxFuture<'static, Result<Option<OutboxMessage>, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let row = sqlx::query(
+                r#"SELECT id, kind, target, payload, status, attempts, last_error,
+                    created_sec, next_attempt_sec, finished_sec
+                FROM outbox
+                WHERE id = ?1
+                "#,
+            )
+            .bind(id)
+            .fetch_optional(&pool)
+            .await
+            .map_err(sqlx_error)?;
+
+            row.as_ref()
+                .map(outbox_message_from_row)
+                .transpose()
+                .map_err(sqlx_error)
+        }
+        .boxed_local()
+    }
+
+    fn get_outbox_attempts(
+        &self,
+        outbox_ids: Vec<i64>,
+    ) -> LocalBoxFuture<'static, Result<Vec<OutboxAttemptRecord>, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let mut attempts = Vec::new();
+            for outbox_id in outbox_ids {
+                let rows = sqlx::query(
+                    r#"SELECT outbox_id, attempted_sec, status_code, latency_ms, response, error
+                    FROM outbox_attempts
+                    WHERE outbox_id = ?1
+                    ORDER BY id
+                    "#,
+                )
+                .bind(outbox_id)
+                .fetch_all(&pool)
+                .await
+                .map_err(sqlx_error)?;
+
+                let rows = rows
+                    .iter()
+                    .map(|row| -> Result<_, sqlx::Error> {
+                        let status_code: Option<i64> = row.try_get(2)?;
+
+                        Ok(OutboxAttemptRecord {
+                            outbox_id: row.try_get(0)?,
+                            attempted: chrono::Utc.timestamp(row.try_get(1)?, 0),
+                            status_code: status_code.and_then(|code| u16::try_from(code).ok()),
+                            latency_ms: row.try_get(3)?,
+                            response: row.try_get(4)?,
+                            error: row.try_get(5)?,
+                        })
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(sqlx_error)?;
+                attempts.extend(rows);
+            }
+
+            Ok(attempts)
+        }
+        .boxed_local()
+    }
+
+    fn get_outbox_messages(
+        &self,
+        filter: OutboxFilter,
+    ) -> LocalBoxFuture<'static, Result<Vec<OutboxMessage>, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let rows = sqlx::query(
+                r#"SELECT id, kind, target, payload, status, attempts, last_error,
+                    created_sec, next_attempt_sec, finished_sec
+                FROM outbox
+                WHERE (?1 IS NULL OR status = ?1)
+                    AND (?2 IS NULL OR target = ?2)
+                    AND (?3 IS NULL OR id < ?3)
+                    AND (?4 IS NULL OR kind = ?4)
+                ORDER BY id DESC
+                LIMIT ?5
+                "#,
+            )
+            .bind(filter.status.map(OutboxStatus::as_str))
+            .bind(filter.target)
+            .bind(filter.before)
+            .bind(filter.kind.map(OutboxKind::as_str))
+            .bind(i64::from(filter.limit))
+            .fetch_all(&pool)
+            .await
+            .map_err(sqlx_error)?;
+
+            rows.iter()
+                .map(outbox_message_from_row)
+                .collect::<Result<_, _>>()
+                .map_err(sqlx_error)
+        }
+        .boxed_local()
+    }
+
+    fn prune_outbox(
+        &self,
+        finished_before: chrono::DateTime<chrono::Utc>,
+    ) -> LocalBoxFuture<'static, Result<u64, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let mut txn = pool.begin().await.map_err(sqlx_error)?;
+
+            let done = sqlx::query("DELETE FROM outbox WHERE status != ?1 AND finished_sec < ?2")
+                .bind(OutboxStatus::Pending.as_str())
+                .bind(finished_before.timestamp())
+                .execute(&mut txn)
+                .await
+                .map_err(sqlx_error)?;
+
+            sqlx::query(
+                "DELETE FROM outbox_attempts WHERE outbox_id NOT IN (SELECT id FROM outbox)",
+            )
+            .execute(&mut txn)
+            .await
+            .map_err(sqlx_error)?;
+
+            txn.commit().await.map_err(sqlx_error)?;
+
+            Ok(done.rows_affected())
+        }
+        .boxed_local()
+    }
+
+    fn compact_changes(&self) -> LocalBoxFuture<'static, Result<u64, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let mut txn = pool.begin().await.map_err(sqlx_error)?;
+
+            // The latest change is always kept, as it's the ledger version.
+            let superseded = sqlx::query(
+                r#"DELETE FROM changes
+                WHERE seq < (SELECT MAX(seq) FROM changes)
+                    AND seq NOT IN (SELECT MAX(seq) FROM changes GROUP BY transaction_id, user_id)
+                "#,
+            )
+            .execute(&mut txn)
+            .await
+            .map_err(sqlx_error)?
+            .rows_affected();
+
+            // Clients that haven't seen a change to a row that's gone would
+            // never find out about it, so must resync.
+            sqlx::query(
+                r#"UPDATE changes_horizon SET seq = MAX(seq, COALESCE((
+                    SELECT MAX(seq) FROM changes
+                    WHERE seq < (SELECT MAX(seq) FROM changes)
+                        AND (transaction_id NOT IN (SELECT id FROM transactions)
+                            OR user_id NOT IN (SELECT user_id FROM users))
+                ), 0))
+                "#,
+            )
+            .execute(&mut txn)
+            .await
+            .map_err(sqlx_error)?;
+
+            let gone = sqlx::query(
+                r#"DELETE FROM changes
+                WHERE seq < (SELECT MAX(seq) FROM changes)
+                    AND (transaction_id NOT IN (SELECT id FROM transactions)
+                        OR user_id NOT IN (SELECT user_id FROM users))
+                "#,
+            )
+            .execute(&mut txn)
+            .await
+            .map_err(sqlx_error)?
+            .rows_affected();
+
+            txn.commit().await.map_err(sqlx_error)?;
+
+            Ok(superseded + gone)
+        }
+        .boxed_local()
+    }
+
+    fn vacuum(&self) -> LocalBoxFuture<'static, Result<(), DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            for statement in &["VACUUM", "ANALYZE"] {
+                sqlx::query(statement)
+                    .execute(&pool)
+                    .await
+                    .map_err(sqlx_error)?;
+            }
+
+            Ok(())
+        }
+        .boxed_local()
+    }
+
+    fn check_integrity(
+        &self,
+        repair: bool,
+    ) -> LocalBoxFuture<'static, Result<Vec<IntegrityProblem>, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            let mut txn = pool.begin().await.map_err(sqlx_error)?;
+
+            let mut problems = Vec::new();
+            for (kind, query, repair_stmts) in INTEGRITY_CHECKS {
+                let rows = sqlx::query(query)
+                    .fetch_all(&mut txn)
+                    .await
+                    .map_err(sqlx_error)?;
+
+                let found = rows
+                    .iter()
+                    .map(|row| -> Result<_, sqlx::Error> {
+                        Ok(IntegrityProblem {
+                            kind: *kind,
+                            user_id: row.try_get(0)?,
+                            count: row.try_get(1)?,
+                        })
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(sqlx_error)?;
+
+                if repair && !found.is_empty() {
+                    for stmt in *repair_stmts {
+                        sqlx::query(stmt)
+                            .execute(&mut txn)
+                            .await
+                            .map_err(sqlx_error)?;
+                    }
+                }
+
+                problems.extend(found);
+            }
+
+            txn.commit().await.map_err(sqlx_error)?;
+
+            Ok(problems)
+        }
+        .boxed_local()
+    }
+
+    fn get_schema_version(&self) -> LocalBoxFuture<'static, Result<i64, DatabaseError>> {
+        let pool = self.pool.clone();
+
+        async move {
+            sqlx::query("PRAGMA user_version")
+                .fetch_one(&pool)
+                .await
+                .and_then(|row| row.try_get(0))
+                .map_err(sqlx_error)
+        }
+        .boxed_local()
+    }
+
+    fn pool_stats(&self) -> PoolStats {
+        let connections = self.pool.size();
+        let idle_connections = self.pool.num_idle() as u32;
+
+        // There is no separate queue: operations wait directly on a
+        // connection, so the connections in use are the operations in flight.
+        PoolStats {
+            in_flight: connections.saturating_sub(idle_connections) as usize,
+            max_in_flight: self.max_connections as usize,
+            connections,
+            idle_connections,
+            max_connections: self.max_connections,
+        }
+    }
+
+    fn backend_name(&self) -> &'static str {
+        "sqlx"
+    }
+}
+
+/// Convert a sqlx error into a [DatabaseError]. Timing out waiting for a
+/// connection is reported as [DatabaseError::Busy].
+fn sqlx_error(err: sqlx::Error) -> DatabaseError {
+    match err {
+        sqlx::Error::PoolTimedOut => DatabaseError::Busy,
+        err => DatabaseError::BackendError {
+            source: Box::new(err),
+        },
+    }
+}
+
+/// Parse a row of `id, shafter, shaftee, amount, time_sec, reason, kind`.
+fn transaction_from_row(row: &SqliteRow, currency: Currency) -> Result<Transaction, sqlx::Error> {
+    Ok(Transaction {
+        id: Some(row.try_get(0)?),
+        shafter: row.try_get(1)?,
+        shaftee: row.try_get(2)?,
+        amount: Money::new(row.try_get(3)?, currency),
+        datetime: chrono::Utc.timestamp(row.try_get(4)?, 0),
+        reason: row.try_get(5)?,
+        kind: parse_kind(row.try_get(6)?),
+    })
+}
+
+/// Parse a row of `user_id, display_name, balance`.
+fn user_from_row(row: &SqliteRow, currency: Currency) -> Result<User, sqlx::Error> {
+    Ok(User {
+        user_id: row.try_get(0)?,
+        display_name: row.try_get(1)?,
+        balance: Money::new(row.try_get(2)?, currency),
+    })
+}
+
+/// Parse a row of `default_counterparty, default_currency, quick_amounts`.
+fn preferences_from_row(
+    row: &SqliteRow,
+    currency: Currency,
+) -> Result<UserPreferences, sqlx::Error> {
+    let quick_amounts: String = row.try_get(2)?;
+    Ok(parse_preferences(
+        row.try_get(0)?,
+        row.try_get(1)?,
+        &quick_amounts,
+        currency,
+    ))
+}
+
+/// Parse a row of `id, name, display_name`.
+/// Read an outbox message from a row of `id, kind, target, payload, status,
+/// attempts, last_error, created_sec, next_attempt_sec, finished_sec`.
+fn outbox_message_from_row(row: &SqliteRow) -> Result<OutboxMessage, sqlx::Error> {
+    let kind: String = row.try_get(1)?;
+    let status: String = row.try_get(4)?;
+    let next_attempt: Option<i64> = row.try_get(8)?;
+    let finished: Option<i64> = row.try_get(9)?;
+
+    Ok(OutboxMessage {
+        id: row.try_get(0)?,
+        kind: parse_outbox_kind(&kind),
+        target: row.try_get(2)?,
+        payload: row.try_get(3)?,
+        status: parse_outbox_status(&status),
+        attempts: row.try_get(5)?,
+        last_error: row.try_get(6)?,
+        created: chrono::Utc.timestamp(row.try_get(7)?, 0),
+        next_attempt: next_attempt.map(|time| chrono::Utc.timestamp(time, 0)),
+        finished: finished.map(|time| chrono::Utc.timestamp(time, 0)),
+    })
+}
+
+fn ledger_from_row(row: &SqliteRow) -> Result<Ledger, sqlx::Error> {
+    Ok(Ledger {
+        id: row.try_get(0)?,
+        name: row.try_get(1)?,
+        display_name: row.try_get(2)?,
+    })
+}
+
+/// Whether the user is a member of the ledger.
+async fn is_ledger_member_txn(
+    conn: &mut sqlx::SqliteConnection,
+    ledger_id: i64,
+    user_id: &str,
+) -> Result<bool, DatabaseError> {
+    let row =
+        sqlx::query("SELECT user_id FROM ledger_members WHERE ledger_id = ?1 AND user_id = ?2")
+            .bind(ledger_id)
+            .bind(user_id)
+            .fetch_optional(conn)
+            .await
+            .map_err(sqlx_error)?;
+
+    Ok(row.is_some())
+}
+
+/// Add messages to the outbox, to be attempted from `next_attempt`. Returns
+/// their IDs.
+async fn add_outbox_messages_txn(
+    conn: &mut sqlx::SqliteConnection,
+    messages: Vec<NewOutboxMessage>,
+    next_attempt: chrono::DateTime<chrono::Utc>,
+) -> Result<Vec<i64>, DatabaseError> {
+    let now = chrono::Utc::now().timestamp();
+    let mut ids = Vec::with_capacity(messages.len());
+    for message in messages {
+        let done = sqlx::query(
+            "INSERT INTO outbox (kind, target, payload, status, created_sec, next_attempt_sec)\
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        )
+        .bind(message.kind.as_str())
+        .bind(message.target)
+        .bind(message.payload)
+        .bind(OutboxStatus::Pending.as_str())
+        .bind(now)
+        .bind(next_attempt.timestamp())
+        .execute(&mut *conn)
+        .await
+        .map_err(sqlx_error)?;
+        ids.push(done.last_insert_rowid());
+    }
+
+    Ok(ids)
+}
+
+/// Add the outbox messages announcing a change, given its result, within
+/// the change's transaction. They're due straight away.
+async fn add_change_outbox_txn<T>(
+    conn: &mut sqlx::SqliteConnection,
+    outbox: &Outbox<T>,
+    result: &T,
+) -> Result<(), DatabaseError> {
+    add_outbox_messages_txn(conn, outbox.messages(result), chrono::Utc::now()).await?;
+    Ok(())
+}
+
+/// Insert a transaction into the ledger, checking that the shaftee is a
+/// member. Returns the new transaction's ID.
+async fn insert_transaction(
+    conn: &mut sqlx::SqliteConnection,
+    ledger_id: i64,
+    transaction: Transaction,
+) -> Result<i64, DatabaseError> {
+    if !is_ledger_member_txn(&mut *conn, ledger_id, &transaction.shaftee).await? {
+        return Err(DatabaseError::UnknownUser {
+            user_id: transaction.shaftee,
+        });
+    }
+
+    let done = sqlx::query(
+        "INSERT INTO transactions (shafter, shaftee, amount, time_sec, reason, kind, ledger_id)\
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+    )
+    .bind(transaction.shafter)
+    .bind(transaction.shaftee)
+    .bind(transaction.amount.minor_units())
+    .bind(transaction.datetime.timestamp())
+    .bind(transaction.reason)
+    .bind(transaction.kind.as_str())
+    .bind(ledger_id)
+    .execute(&mut *conn)
+    .await
+    .map_err(sqlx_error)?;
+
+    Ok(done.last_insert_rowid())
+}
+
+/// Get the current revision of a transaction, or None if it doesn't exist.
+async fn get_transaction_revision_txn(
+    conn: &mut sqlx::SqliteConnection,
+    ledger_id: i64,
+    transaction_id: i64,
+) -> Result<Option<i64>, DatabaseError> {
+    let row = sqlx::query("SELECT revision FROM transactions WHERE id = ?1 AND ledger_id = ?2")
+        .bind(transaction_id)
+        .bind(ledger_id)
+        .fetch_optional(conn)
+        .await
+        .map_err(sqlx_error)?;
+
+    row.map(|row| row.try_get(0))
+        .transpose()
+        .map_err(sqlx_error)
+}
+
+/// Check that the transaction exists and, if given, is at the expected
+/// revision. Returns the current revision.
+async fn check_transaction_revision_txn(
+    conn: &mut sqlx::SqliteConnection,
+    ledger_id: i64,
+    transaction_id: i64,
+    expected_revision: Option<i64>,
+) -> Result<i64, DatabaseError> {
+    let revision = get_transaction_revision_txn(conn, ledger_id, transaction_id)
+        .await?
+        .ok_or(DatabaseError::UnknownTransaction { transaction_id })?;
+
+    match expected_revision {
+        Some(expected_revision) if expected_revision != revision => {
+            Err(DatabaseError::RevisionMismatch {
+                transaction_id,
+                revision,
+                expected_revision,
+            })
+        }
+        _ => Ok(revision),
+    }
+}
@@ -1,7 +1,11 @@
 use actix_web::error::ResponseError;
+use actix_web::http::{header, StatusCode};
+use actix_web::HttpResponse;
 use snafu::{Backtrace, Snafu};
 
-use crate::{db, github};
+use crate::api_types::ErrorResponse;
+use crate::rest::RequestStage;
+use crate::{amount, db, events, http_client, import, mailer, notifier, reason, shared_state};
 
 #[derive(Debug, Snafu)]
 #[snafu(visibility = "pub(crate)")]
@@ -12,11 +16,189 @@ pub enum ShaftError {
         backtrace: Backtrace,
     },
 
+    #[snafu(display("{}", source))]
+    EventError {
+        source: events::EventError,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("{}", source))]
+    SharedStateError {
+        source: shared_state::SharedStateError,
+        backtrace: Backtrace,
+    },
+
     #[snafu(display("{}", source))]
     GithubError {
-        source: github::HttpError,
+        source: http_client::HttpError,
         backtrace: Backtrace,
     },
+
+    #[snafu(display("{}", source))]
+    MailError { source: mailer::MailError },
+
+    #[snafu(display("{}", source))]
+    NotifyError { source: notifier::NotifyError },
+
+    #[snafu(display("Failed to render template: {}", source))]
+    TemplateError {
+        source: handlebars::RenderError,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("{}", source))]
+    ImportError { source: import::ImportError },
+
+    #[snafu(display("{}", source))]
+    InvalidAmount { source: amount::AmountError },
+
+    #[snafu(display("{}", source))]
+    InvalidReason { source: reason::ReasonError },
+
+    #[snafu(display("Failed to save settings: {}", source))]
+    SettingsError {
+        source: std::io::Error,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("Crypto error: {}", source))]
+    CryptoError {
+        source: openssl::error::ErrorStack,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display("{}", message))]
+    BadRequest { message: String },
+
+    #[snafu(display("{}", reason))]
+    Forbidden { reason: &'static str },
+
+    #[snafu(display("Unknown {}", what))]
+    NotFound { what: &'static str },
+
+    #[snafu(display("Missing If-Match header"))]
+    MissingIfMatch,
+
+    #[snafu(display("{}", message))]
+    RateLimited {
+        message: String,
+        retry_after_secs: i64,
+    },
+
+    #[snafu(display("Request body is larger than {} bytes", limit))]
+    PayloadTooLarge { limit: usize },
+
+    #[snafu(display("Access token does not have the '{}' scope", scope.as_str()))]
+    MissingScope { scope: db::Scope },
+
+    #[snafu(display("No {} installed in request, is its middleware registered?", name))]
+    MissingExtension { name: &'static str },
+
+    #[snafu(display("No {} registered as app data", name))]
+    MissingAppData { name: &'static str },
+
+    #[snafu(display("Request timed out while {}", stage))]
+    DeadlineExceeded { stage: RequestStage },
+}
+
+impl ShaftError {
+    /// The name of the variant, used when reporting the error and as the
+    /// `kind` of error responses.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ShaftError::DatabaseError { .. } => "DatabaseError",
+            ShaftError::EventError { .. } => "EventError",
+            ShaftError::SharedStateError { .. } => "SharedStateError",
+            ShaftError::GithubError { .. } => "GithubError",
+            ShaftError::MailError { .. } => "MailError",
+            ShaftError::NotifyError { .. } => "NotifyError",
+            ShaftError::TemplateError { .. } => "TemplateError",
+            ShaftError::ImportError { .. } => "ImportError",
+            ShaftError::InvalidAmount { .. } => "InvalidAmount",
+            ShaftError::InvalidReason { .. } => "InvalidReason",
+            ShaftError::SettingsError { .. } => "SettingsError",
+            ShaftError::CryptoError { .. } => "CryptoError",
+            ShaftError::BadRequest { .. } => "BadRequest",
+            ShaftError::Forbidden { .. } => "Forbidden",
+            ShaftError::NotFound { .. } => "NotFound",
+            ShaftError::MissingIfMatch => "MissingIfMatch",
+            ShaftError::RateLimited { .. } => "RateLimited",
+            ShaftError::PayloadTooLarge { .. } => "PayloadTooLarge",
+            ShaftError::MissingScope { .. } => "MissingScope",
+            ShaftError::MissingExtension { .. } => "MissingExtension",
+            ShaftError::MissingAppData { .. } => "MissingAppData",
+            ShaftError::DeadlineExceeded { .. } => "DeadlineExceeded",
+        }
+    }
 }
 
-impl ResponseError for ShaftError {}
+/// How long clients should wait before retrying when we're overloaded, in
+/// seconds.
+const RETRY_AFTER_SECS: u32 = 1;
+
+impl ResponseError for ShaftError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ShaftError::DatabaseError { source, .. } => match source {
+                db::DatabaseError::Busy | db::DatabaseError::ConnectionPoolError { .. } => {
+                    StatusCode::SERVICE_UNAVAILABLE
+                }
+                db::DatabaseError::UnknownUser { .. } => StatusCode::BAD_REQUEST,
+                db::DatabaseError::UserDeactivated { .. } => StatusCode::FORBIDDEN,
+                db::DatabaseError::UnknownTransaction { .. } => StatusCode::NOT_FOUND,
+                db::DatabaseError::RevisionMismatch { .. } => StatusCode::PRECONDITION_FAILED,
+                db::DatabaseError::LedgerExists { .. } => StatusCode::CONFLICT,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+            ShaftError::GithubError {
+                source: http_client::HttpError::CircuitOpen { .. },
+                ..
+            } => StatusCode::SERVICE_UNAVAILABLE,
+            ShaftError::GithubError { .. }
+            | ShaftError::MailError { .. }
+            | ShaftError::NotifyError { .. } => StatusCode::BAD_GATEWAY,
+            ShaftError::ImportError { .. }
+            | ShaftError::InvalidAmount { .. }
+            | ShaftError::InvalidReason { .. }
+            | ShaftError::BadRequest { .. } => StatusCode::BAD_REQUEST,
+            ShaftError::Forbidden { .. } | ShaftError::MissingScope { .. } => StatusCode::FORBIDDEN,
+            ShaftError::NotFound { .. } => StatusCode::NOT_FOUND,
+            ShaftError::MissingIfMatch => StatusCode::PRECONDITION_REQUIRED,
+            ShaftError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ShaftError::PayloadTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            ShaftError::DeadlineExceeded {
+                stage: RequestStage::Upstream,
+            } => StatusCode::GATEWAY_TIMEOUT,
+            ShaftError::DeadlineExceeded { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            ShaftError::EventError { .. }
+            | ShaftError::SharedStateError { .. }
+            | ShaftError::TemplateError { .. }
+            | ShaftError::SettingsError { .. }
+            | ShaftError::CryptoError { .. }
+            | ShaftError::MissingExtension { .. }
+            | ShaftError::MissingAppData { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let status = self.status_code();
+
+        let mut resp = HttpResponse::build(status);
+        if status == StatusCode::SERVICE_UNAVAILABLE {
+            resp.insert_header((header::RETRY_AFTER, RETRY_AFTER_SECS.to_string()));
+        }
+        if let ShaftError::RateLimited {
+            retry_after_secs, ..
+        } = self
+        {
+            resp.insert_header((header::RETRY_AFTER, retry_after_secs.to_string()));
+        }
+
+        let body = ErrorResponse {
+            kind: self.kind().to_string(),
+            error: self.to_string(),
+        };
+        resp.content_type("application/json; charset=utf-8")
+            .body(serde_json::to_string(&body).expect("error responses can be serialized"))
+    }
+}
@@ -0,0 +1,177 @@
+//! Reports server errors to an external error tracker.
+//!
+//! Requests that fail with a [ShaftError](crate::error::ShaftError) are handed
+//! to the configured [ErrorReporter] by the
+//! [ReportErrors](crate::rest::ReportErrors) middleware, along with details of
+//! the request. [SentryReporter] sends them to Sentry.
+
+use futures::future::{self, BoxFuture, FutureExt};
+use hyper::{Body, Request};
+use rand::{thread_rng, Rng};
+use serde_json::json;
+use snafu::{ResultExt, Snafu};
+use url::Url;
+
+use std::sync::Arc;
+
+use crate::http_client::{GenericHttpClient, HttpError};
+
+/// A failed request to report.
+#[derive(Debug, Clone)]
+pub struct ErrorReport {
+    /// The kind of error, e.g. `DatabaseError`.
+    pub kind: &'static str,
+    /// The error message.
+    pub message: String,
+    /// Where the error was created, if backtraces are enabled.
+    pub backtrace: Option<String>,
+    /// The status code of the response.
+    pub status: u16,
+    pub method: String,
+    pub path: String,
+    /// The [RequestID](crate::rest::RequestID) used in the logs.
+    pub request_id: Option<u32>,
+    /// The user making the request, if logged in.
+    pub user_id: Option<String>,
+}
+
+/// Something that can record errors for later investigation.
+pub trait ErrorReporter: Send + Sync {
+    fn report(&self, report: ErrorReport) -> BoxFuture<'static, Result<(), HttpError>>;
+}
+
+/// The default [ErrorReporter], which drops reports.
+#[derive(Debug, Clone, Default)]
+pub struct NoopErrorReporter;
+
+impl ErrorReporter for NoopErrorReporter {
+    fn report(&self, _report: ErrorReport) -> BoxFuture<'static, Result<(), HttpError>> {
+        future::ok(()).boxed()
+    }
+}
+
+/// Error parsing a Sentry DSN.
+#[derive(Debug, Snafu)]
+pub enum DsnError {
+    #[snafu(display("Invalid Sentry DSN: {}", source))]
+    InvalidUrl { source: url::ParseError },
+    #[snafu(display("Sentry DSN has no public key"))]
+    MissingKey,
+    #[snafu(display("Sentry DSN has no project ID"))]
+    MissingProjectId,
+}
+
+/// An [ErrorReporter] that sends errors to Sentry's store endpoint.
+pub struct SentryReporter {
+    /// The URL events are POSTed to.
+    store_url: String,
+    /// The value of the `X-Sentry-Auth` header.
+    auth_header: String,
+    environment: Option<String>,
+    http_client: Arc<dyn GenericHttpClient>,
+}
+
+impl SentryReporter {
+    /// Create a reporter from a DSN of the form
+    /// `https://<key>@<host>/<project_id>`.
+    pub fn new(
+        dsn: &str,
+        environment: Option<String>,
+        http_client: Arc<dyn GenericHttpClient>,
+    ) -> Result<SentryReporter, DsnError> {
+        let dsn = Url::parse(dsn).context(InvalidUrl)?;
+
+        if dsn.username().is_empty() {
+            return Err(DsnError::MissingKey);
+        }
+
+        let project_id = dsn
+            .path_segments()
+            .and_then(|segments| segments.last())
+            .filter(|project_id| !project_id.is_empty())
+            .ok_or(DsnError::MissingProjectId)?;
+
+        // Sentry may be served under a path prefix, which goes before `/api`.
+        let mut store_url = dsn.clone();
+        store_url.set_username("").expect("DSN is a base URL");
+        store_url.set_password(None).expect("DSN is a base URL");
+        let prefix = dsn
+            .path()
+            .strip_suffix(project_id)
+            .unwrap_or_default()
+            .trim_end_matches('/');
+        store_url.set_path(&format!("{}/api/{}/store/", prefix, project_id));
+
+        let auth_header = format!(
+            "Sentry sentry_version=7, sentry_client=shaft/{}, sentry_key={}",
+            env!("CARGO_PKG_VERSION"),
+            dsn.username(),
+        );
+
+        Ok(SentryReporter {
+            store_url: store_url.to_string(),
+            auth_header,
+            environment,
+            http_client,
+        })
+    }
+}
+
+impl ErrorReporter for SentryReporter {
+    fn report(&self, report: ErrorReport) -> BoxFuture<'static, Result<(), HttpError>> {
+        let event_id: u128 = thread_rng().gen();
+
+        let event = json!({
+            "event_id": format!("{:032x}", event_id),
+            "timestamp": chrono::Utc::now().timestamp(),
+            "platform": "other",
+            "level": "error",
+            "logger": "shaft",
+            "release": concat!("shaft@", env!("CARGO_PKG_VERSION")),
+            "environment": self.environment,
+            "exception": {
+                "values": [{
+                    "type": report.kind,
+                    "value": report.message,
+                }],
+            },
+            "request": {
+                "method": report.method,
+                "url": report.path,
+            },
+            "user": report.user_id.map(|user_id| json!({ "id": user_id })),
+            "tags": {
+                "status_code": report.status.to_string(),
+                "request_id": report.request_id.map(|id| id.to_string()),
+            },
+            "extra": {
+                "backtrace": report.backtrace,
+            },
+        });
+
+        let req = Request::post(self.store_url.as_str())
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .header("X-Sentry-Auth", self.auth_header.as_str())
+            .body(Body::from(event.to_string()));
+
+        let req = match req {
+            Ok(req) => req,
+            Err(source) => return future::err(HttpError::Http { source }).boxed(),
+        };
+
+        let resp_fut = self.http_client.request(req);
+
+        async move {
+            let resp = resp_fut.await?;
+
+            if !resp.status().is_success() {
+                return Err(HttpError::Status {
+                    code: resp.status(),
+                });
+            }
+
+            Ok(())
+        }
+        .boxed()
+    }
+}
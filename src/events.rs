@@ -0,0 +1,424 @@
+//! An internal bus for domain events, so that cross-cutting features like the
+//! audit log don't need hand-wiring into every handler.
+//!
+//! Handlers publish an [Event] after making a change. Changes made through
+//! the database take an [Outbox](EventBus::outbox), so that the event's
+//! outbox messages, e.g. webhook deliveries, are added in the same database
+//! transaction, then [publish](EventBus::publish_outbox) its events once the
+//! change is committed. Each registered
+//! [EventListener] is run in turn before `publish` returns, so listeners that
+//! must not miss events (like the audit log) can fail the request. Consumers
+//! that only want a live feed, e.g. to push to clients, can instead
+//! [subscribe](EventBus::subscribe), though they may miss events if they fall
+//! behind.
+
+use futures::future::{FutureExt, LocalBoxFuture};
+use serde::Serialize;
+use snafu::{ResultExt, Snafu};
+use tokio::sync::broadcast;
+
+use std::error::Error as StdError;
+use std::sync::Arc;
+
+use crate::db::{
+    AuditEntry, Database, DatabaseError, Ledger, Outbox, OutboxRouter, Scope, Transaction,
+};
+use crate::features::Feature;
+
+/// How many events live subscribers can fall behind by before they start
+/// missing events.
+const SUBSCRIBER_CAPACITY: usize = 256;
+
+/// Something that happened to the ledger or a user's account.
+///
+/// Serialized with a `type` field naming the variant, e.g. for publishing to
+/// other services.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// A new transaction was created. The transaction has its ID set.
+    TransactionCreated { transaction: Transaction },
+    /// An admin recorded an opening balance or correction. The transaction
+    /// has its ID set.
+    AdjustmentCreated {
+        actor: String,
+        transaction: Transaction,
+    },
+    /// A transaction was edited, giving it the new revision.
+    TransactionEdited {
+        actor: String,
+        transaction_id: i64,
+        revision: i64,
+    },
+    /// A transaction was deleted.
+    TransactionDeleted { actor: String, transaction_id: i64 },
+    /// Transactions were imported from a bank statement.
+    TransactionsImported { actor: String, count: usize },
+    /// A user logged in for the first time.
+    UserAdded {
+        user_id: String,
+        display_name: String,
+    },
+    /// A user logged in.
+    UserLoggedIn { user_id: String },
+    /// A user deleted their account. The user ID is the anonymous one that
+    /// replaced theirs.
+    UserDeleted { user_id: String },
+    /// An admin deactivated a user.
+    UserDeactivated { actor: String, user_id: String },
+    /// A user reminded another of their balance.
+    UserPoked { actor: String, user_id: String },
+    /// An admin merged one user into another.
+    UsersMerged {
+        actor: String,
+        from: String,
+        into: String,
+    },
+    /// An admin deleted expired login sessions.
+    TokensPruned { actor: String, count: u64 },
+    /// An API token was created.
+    TokenCreated {
+        actor: String,
+        token_id: i64,
+        name: String,
+        scopes: Vec<Scope>,
+    },
+    /// An API token was revoked.
+    TokenRevoked { actor: String, token_id: i64 },
+    /// An inbound hook was created.
+    InboundHookCreated {
+        actor: String,
+        hook_id: i64,
+        name: String,
+    },
+    /// An inbound hook was deleted.
+    InboundHookDeleted { actor: String, hook_id: i64 },
+    /// An admin turned a feature on or off.
+    FeatureToggled {
+        actor: String,
+        feature: Feature,
+        enabled: bool,
+    },
+    /// An admin created a new ledger.
+    LedgerCreated { actor: String, ledger: Ledger },
+    /// A user was added to a ledger, either by an admin or by accepting an
+    /// invitation.
+    LedgerMemberAdded {
+        actor: String,
+        ledger: String,
+        user_id: String,
+    },
+    /// A ledger member emailed someone an invitation to the ledger.
+    InviteSent {
+        actor: String,
+        ledger: String,
+        email: String,
+    },
+}
+
+/// Error publishing an event.
+#[derive(Debug, Snafu)]
+pub enum EventError {
+    /// A listener failed to handle the event.
+    #[snafu(display("Event listener {} failed: {}", listener, source))]
+    ListenerFailed {
+        listener: &'static str,
+        source: Box<dyn StdError + Send + Sync>,
+    },
+    /// The event's outbox messages couldn't be added.
+    #[snafu(display("Failed to add event to outbox: {}", source))]
+    OutboxFailed { source: DatabaseError },
+}
+
+/// Something that reacts to every published [Event].
+pub trait EventListener: Send + Sync {
+    /// Name used when reporting failures.
+    fn name(&self) -> &'static str;
+
+    /// Handle the event. Listeners should ignore events they aren't
+    /// interested in.
+    fn on_event(
+        &self,
+        event: &Event,
+    ) -> LocalBoxFuture<'static, Result<(), Box<dyn StdError + Send + Sync>>>;
+}
+
+/// Distributes published events to the registered listeners and any live
+/// subscribers.
+///
+/// Safe to clone as the listeners and subscribers will be shared.
+#[derive(Clone)]
+pub struct EventBus {
+    listeners: Vec<Arc<dyn EventListener>>,
+    sender: broadcast::Sender<Event>,
+    outbox: Option<(Arc<dyn Database>, Arc<dyn OutboxRouter>)>,
+}
+
+impl EventBus {
+    /// Create a bus with no listeners.
+    pub fn new() -> EventBus {
+        let (sender, _) = broadcast::channel(SUBSCRIBER_CAPACITY);
+        EventBus {
+            listeners: Vec::new(),
+            sender,
+            outbox: None,
+        }
+    }
+
+    /// Register a listener that is run for every published event.
+    pub fn add_listener(&mut self, listener: Arc<dyn EventListener>) {
+        self.listeners.push(listener);
+    }
+
+    /// Get a live feed of events published from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    /// Add outbox messages for published events, as worked out by `router`.
+    pub fn set_outbox_router(
+        &mut self,
+        database: Arc<dyn Database>,
+        router: Arc<dyn OutboxRouter>,
+    ) {
+        self.outbox = Some((database, router));
+    }
+
+    /// Announce a database change with the events `build` returns from its
+    /// result. Pass it to the change, then [publish](EventBus::publish_outbox)
+    /// it.
+    pub fn outbox<T, F>(&self, build: F) -> Outbox<T>
+    where
+        F: FnOnce(&T) -> Vec<Event> + Send + 'static,
+    {
+        let router = self.outbox.as_ref().map(|(_, router)| router.clone());
+        Outbox::new(router, build)
+    }
+
+    /// Announce a new transaction, once it has its ID.
+    pub fn transaction_outbox(&self, transaction: &Transaction) -> Outbox<i64> {
+        let transaction = transaction.clone();
+        self.outbox(move |&transaction_id| {
+            vec![Event::TransactionCreated {
+                transaction: Transaction {
+                    id: Some(transaction_id),
+                    ..transaction
+                },
+            }]
+        })
+    }
+
+    /// Publish the events of a committed change. Their outbox messages were
+    /// added along with the change.
+    pub async fn publish_outbox<T>(&self, outbox: &Outbox<T>) -> Result<(), EventError> {
+        for event in outbox.take_events() {
+            self.notify(event).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Publish an event that isn't part of a database change, adding its
+    /// outbox messages first.
+    pub async fn publish(&self, event: Event) -> Result<(), EventError> {
+        if let Some((database, router)) = &self.outbox {
+            let messages = router.messages(&event);
+            if !messages.is_empty() {
+                database
+                    .add_outbox_messages(messages, chrono::Utc::now())
+                    .await
+                    .context(OutboxFailed)?;
+            }
+        }
+
+        self.notify(event).await
+    }
+
+    /// Run every listener for the event in turn, stopping at the first
+    /// failure, then send it to live subscribers.
+    async fn notify(&self, event: Event) -> Result<(), EventError> {
+        for listener in &self.listeners {
+            listener.on_event(&event).await.context(ListenerFailed {
+                listener: listener.name(),
+            })?;
+        }
+
+        // There being no subscribers isn't an error.
+        let _ = self.sender.send(event);
+
+        Ok(())
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> EventBus {
+        EventBus::new()
+    }
+}
+
+/// Records actions taken by users in the audit log.
+pub struct AuditLogListener {
+    database: Arc<dyn Database>,
+}
+
+impl AuditLogListener {
+    pub fn new(database: Arc<dyn Database>) -> AuditLogListener {
+        AuditLogListener { database }
+    }
+}
+
+impl EventListener for AuditLogListener {
+    fn name(&self) -> &'static str {
+        "audit_log"
+    }
+
+    fn on_event(
+        &self,
+        event: &Event,
+    ) -> LocalBoxFuture<'static, Result<(), Box<dyn StdError + Send + Sync>>> {
+        let (actor, action, target, details) = match event {
+            Event::TransactionEdited {
+                actor,
+                transaction_id,
+                revision,
+            } => (
+                actor,
+                "transaction.edit",
+                Some(transaction_id.to_string()),
+                Some(format!("revision {}", revision)),
+            ),
+            Event::AdjustmentCreated { actor, transaction } => (
+                actor,
+                "transaction.adjust",
+                transaction.id.map(|id| id.to_string()),
+                Some(format!(
+                    "{} owed {} by {}",
+                    transaction.shafter,
+                    transaction.amount.minor_units(),
+                    transaction.shaftee
+                )),
+            ),
+            Event::TransactionDeleted {
+                actor,
+                transaction_id,
+            } => (
+                actor,
+                "transaction.delete",
+                Some(transaction_id.to_string()),
+                None,
+            ),
+            Event::TransactionsImported { actor, count } => (
+                actor,
+                "import.commit",
+                None,
+                Some(format!("{} transactions", count)),
+            ),
+            Event::TokenCreated {
+                actor,
+                token_id,
+                name,
+                scopes,
+            } => {
+                let scope_names: Vec<_> = scopes.iter().map(|scope| scope.as_str()).collect();
+                (
+                    actor,
+                    "token.create",
+                    Some(token_id.to_string()),
+                    Some(format!("{} ({})", name, scope_names.join(" "))),
+                )
+            }
+            Event::TokenRevoked { actor, token_id } => {
+                (actor, "token.delete", Some(token_id.to_string()), None)
+            }
+            Event::InboundHookCreated {
+                actor,
+                hook_id,
+                name,
+            } => (
+                actor,
+                "inbound_hook.create",
+                Some(hook_id.to_string()),
+                Some(name.clone()),
+            ),
+            Event::InboundHookDeleted { actor, hook_id } => (
+                actor,
+                "inbound_hook.delete",
+                Some(hook_id.to_string()),
+                None,
+            ),
+            Event::UserDeleted { user_id } => (user_id, "user.delete", None, None),
+            Event::UserDeactivated { actor, user_id } => {
+                (actor, "user.deactivate", Some(user_id.clone()), None)
+            }
+            Event::UserPoked { actor, user_id } => {
+                (actor, "user.poke", Some(user_id.clone()), None)
+            }
+            Event::UsersMerged { actor, from, into } => (
+                actor,
+                "user.merge",
+                Some(from.clone()),
+                Some(format!("into {}", into)),
+            ),
+            Event::TokensPruned { actor, count } => (
+                actor,
+                "token.prune",
+                None,
+                Some(format!("{} expired sessions", count)),
+            ),
+            Event::FeatureToggled {
+                actor,
+                feature,
+                enabled,
+            } => (
+                actor,
+                "feature.toggle",
+                Some(feature.to_string()),
+                Some(if *enabled { "enabled" } else { "disabled" }.to_string()),
+            ),
+            Event::LedgerCreated { actor, ledger } => (
+                actor,
+                "ledger.create",
+                Some(ledger.name.clone()),
+                Some(ledger.display_name.clone()),
+            ),
+            Event::LedgerMemberAdded {
+                actor,
+                ledger,
+                user_id,
+            } => (
+                actor,
+                "ledger.add_member",
+                Some(ledger.clone()),
+                Some(user_id.clone()),
+            ),
+            Event::InviteSent {
+                actor,
+                ledger,
+                email,
+            } => (
+                actor,
+                "ledger.invite",
+                Some(ledger.clone()),
+                Some(email.clone()),
+            ),
+            Event::TransactionCreated { .. }
+            | Event::UserAdded { .. }
+            | Event::UserLoggedIn { .. } => return async { Ok(()) }.boxed_local(),
+        };
+
+        self.database
+            .add_audit_entry(AuditEntry {
+                id: None,
+                actor: actor.clone(),
+                action: action.to_string(),
+                target,
+                details,
+                datetime: chrono::Utc::now(),
+            })
+            .map(|res| {
+                res.map(|_| ())
+                    .map_err(|err| Box::new(err) as Box<dyn StdError + Send + Sync>)
+            })
+            .boxed_local()
+    }
+}
@@ -0,0 +1,241 @@
+//! Renders the ledger into formats understood by other tools.
+
+use linear_map::LinearMap;
+use serde::Deserialize;
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::db::{AuditEntry, Transaction, User};
+use crate::money::{format_decimal, Money};
+
+/// A plain text accounting format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JournalFormat {
+    /// [Ledger](https://ledger-cli.org), also read by hledger.
+    Ledger,
+    /// [Beancount](https://beancount.github.io).
+    Beancount,
+}
+
+/// Which accounts a journal uses.
+///
+/// Each other user gets an account holding what they owe, named
+/// `<prefix>:<display name>` unless overridden. The other side of each
+/// transaction goes to the offset account.
+#[derive(Debug, Clone)]
+pub struct JournalAccounts {
+    pub prefix: String,
+    pub offset: String,
+    /// Account names for particular users, by user ID.
+    pub overrides: BTreeMap<String, String>,
+}
+
+impl JournalAccounts {
+    fn account_for(&self, user_id: &str, users: &LinearMap<String, User>) -> String {
+        if let Some(account) = self.overrides.get(user_id) {
+            return account.clone();
+        }
+
+        let name = users
+            .get(user_id)
+            .map(|user| user.display_name.as_str())
+            .unwrap_or(user_id);
+        format!("{}:{}", self.prefix, account_component(name))
+    }
+}
+
+/// Render the transactions `user_id` is part of as a plain text accounting
+/// journal, from their point of view. Amounts others owe them are positive.
+pub fn journal(
+    format: JournalFormat,
+    user_id: &str,
+    users: &LinearMap<String, User>,
+    transactions: &[Transaction],
+    accounts: &JournalAccounts,
+    currency: &str,
+) -> String {
+    // Positive amounts are owed to the shafter by the shaftee.
+    let entries: Vec<(&Transaction, String, Money)> = transactions
+        .iter()
+        .filter_map(|txn| {
+            if txn.shafter == user_id {
+                Some((txn, accounts.account_for(&txn.shaftee, users), txn.amount))
+            } else if txn.shaftee == user_id {
+                Some((txn, accounts.account_for(&txn.shafter, users), -txn.amount))
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    let mut out = String::new();
+
+    // Beancount requires accounts to be opened before they're used.
+    if let (JournalFormat::Beancount, Some((first, _, _))) = (format, entries.first()) {
+        let opened: BTreeSet<&str> = entries
+            .iter()
+            .map(|(_, account, _)| account.as_str())
+            .chain(Some(accounts.offset.as_str()))
+            .collect();
+
+        let date = first.datetime.format("%Y-%m-%d");
+        for account in opened {
+            out.push_str(&format!("{} open {}\n", date, account));
+        }
+        out.push('\n');
+    }
+
+    for (txn, account, amount) in entries {
+        let date = txn.datetime.format("%Y-%m-%d");
+        let reason = txn.reason.replace(|c: char| c.is_control(), " ");
+
+        match format {
+            JournalFormat::Ledger => {
+                out.push_str(&format!("{} * {}\n", date, reason));
+            }
+            JournalFormat::Beancount => {
+                out.push_str(&format!(
+                    "{} * \"{}\"\n",
+                    date,
+                    reason.replace('\\', "\\\\").replace('"', "\\\"")
+                ));
+            }
+        }
+
+        out.push_str(&format!(
+            "  {}  {} {}\n",
+            account,
+            format_decimal(amount),
+            currency
+        ));
+        out.push_str(&format!(
+            "  {}  {} {}\n\n",
+            accounts.offset,
+            format_decimal(-amount),
+            currency
+        ));
+    }
+
+    out
+}
+
+/// Turn a display name into something valid as part of an account name in
+/// both formats: starting with a capital letter, and only letters, digits
+/// and dashes.
+fn account_component(name: &str) -> String {
+    let mut component: String = name
+        .trim()
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '-' })
+        .collect();
+
+    match component.chars().next() {
+        Some(first) if first.is_alphabetic() => {
+            let rest = component.split_off(first.len_utf8());
+            first.to_uppercase().chain(rest.chars()).collect()
+        }
+        _ => format!("X{}", component),
+    }
+}
+
+/// Render transactions as a CSV in the layout Splitwise imports and exports.
+///
+/// Splitwise expects a `Date, Description, Category, Cost, Currency` header
+/// followed by one column per participant, holding that participant's net
+/// share of each expense. Participants are labelled with their display name.
+pub fn splitwise_csv(
+    users: &LinearMap<String, User>,
+    transactions: &[Transaction],
+    currency: &str,
+) -> String {
+    let mut out = String::new();
+
+    let mut header = vec![
+        "Date".to_string(),
+        "Description".to_string(),
+        "Category".to_string(),
+        "Cost".to_string(),
+        "Currency".to_string(),
+    ];
+    header.extend(users.values().map(|user| user.display_name.clone()));
+    write_csv_row(&mut out, &header);
+
+    for txn in transactions {
+        let mut row = vec![
+            txn.datetime.format("%Y-%m-%d").to_string(),
+            txn.reason.clone(),
+            "General".to_string(),
+            format_decimal(txn.amount.abs()),
+            currency.to_string(),
+        ];
+
+        // A positive amount means the shafter is owed money by the shaftee.
+        row.extend(users.keys().map(|user_id| {
+            if user_id == &txn.shafter {
+                format_decimal(txn.amount)
+            } else if user_id == &txn.shaftee {
+                format_decimal(-txn.amount)
+            } else {
+                format_decimal(Money::zero(txn.amount.currency()))
+            }
+        }));
+
+        write_csv_row(&mut out, &row);
+    }
+
+    out
+}
+
+/// The header row of an audit log CSV, as followed by [audit_csv_rows].
+pub fn audit_csv_header() -> String {
+    let mut out = String::new();
+
+    let header = ["ID", "Time", "Actor", "Action", "Target", "Details"];
+    write_csv_row(
+        &mut out,
+        &header.iter().map(|h| h.to_string()).collect::<Vec<_>>(),
+    );
+
+    out
+}
+
+/// Render audit log entries as CSV rows, with times in RFC 3339 format.
+/// Successive pages of entries can be rendered separately and concatenated
+/// after [audit_csv_header].
+pub fn audit_csv_rows(entries: &[AuditEntry]) -> String {
+    let mut out = String::new();
+
+    for entry in entries {
+        let row = vec![
+            entry.id.map(|id| id.to_string()).unwrap_or_default(),
+            entry.datetime.to_rfc3339(),
+            entry.actor.clone(),
+            entry.action.clone(),
+            entry.target.clone().unwrap_or_default(),
+            entry.details.clone().unwrap_or_default(),
+        ];
+
+        write_csv_row(&mut out, &row);
+    }
+
+    out
+}
+
+/// Append a single CSV row, quoting fields where necessary.
+fn write_csv_row(out: &mut String, fields: &[String]) {
+    for (idx, field) in fields.iter().enumerate() {
+        if idx > 0 {
+            out.push(',');
+        }
+
+        if field.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+            out.push('"');
+            out.push_str(&field.replace('"', "\"\""));
+            out.push('"');
+        } else {
+            out.push_str(field);
+        }
+    }
+    out.push_str("\r\n");
+}
@@ -0,0 +1,126 @@
+//! Runtime toggles for optional subsystems.
+//!
+//! The initial values come from the `features` section of the settings, but
+//! admins can flip them while the server is running through
+//! `/api/admin/features`. Changes aren't persisted, so are lost on restart.
+//! They're passed on to other instances if the
+//! [shared state](crate::shared_state) is kept in the database.
+
+use chrono::TimeZone;
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+
+use crate::settings::FeatureSettings;
+
+/// An optional subsystem that can be turned on or off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Feature {
+    /// Outbound and inbound webhooks.
+    Webhooks,
+    /// Logged out users may view balances and transactions.
+    PublicRead,
+    /// Members of the required GitHub org who haven't logged in before may
+    /// sign up.
+    RegistrationOpen,
+    /// Transactions may be tagged with a category.
+    Categories,
+}
+
+impl Feature {
+    /// Every feature, in a stable order.
+    pub const ALL: [Feature; 4] = [
+        Feature::Webhooks,
+        Feature::PublicRead,
+        Feature::RegistrationOpen,
+        Feature::Categories,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Feature::Webhooks => "webhooks",
+            Feature::PublicRead => "public_read",
+            Feature::RegistrationOpen => "registration_open",
+            Feature::Categories => "categories",
+        }
+    }
+}
+
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The current state of each [Feature]. Shared between all workers, so a
+/// change is seen by every request started afterwards.
+#[derive(Debug)]
+pub struct FeatureFlags {
+    webhooks: AtomicBool,
+    public_read: AtomicBool,
+    registration_open: AtomicBool,
+    categories: AtomicBool,
+    /// When a feature was last flipped, in seconds since the epoch, or zero
+    /// if none have been.
+    changed_sec: AtomicI64,
+}
+
+impl FeatureFlags {
+    pub fn new(settings: &FeatureSettings) -> FeatureFlags {
+        FeatureFlags {
+            webhooks: AtomicBool::new(settings.webhooks),
+            public_read: AtomicBool::new(settings.public_read),
+            registration_open: AtomicBool::new(settings.registration_open),
+            categories: AtomicBool::new(settings.categories),
+            changed_sec: AtomicI64::new(0),
+        }
+    }
+
+    fn flag(&self, feature: Feature) -> &AtomicBool {
+        match feature {
+            Feature::Webhooks => &self.webhooks,
+            Feature::PublicRead => &self.public_read,
+            Feature::RegistrationOpen => &self.registration_open,
+            Feature::Categories => &self.categories,
+        }
+    }
+
+    pub fn is_enabled(&self, feature: Feature) -> bool {
+        self.flag(feature).load(Ordering::Relaxed)
+    }
+
+    /// Turn the feature on or off, returning whether it was previously on.
+    pub fn set(&self, feature: Feature, enabled: bool) -> bool {
+        let previous = self.flag(feature).swap(enabled, Ordering::Relaxed);
+        if previous != enabled {
+            self.changed_sec
+                .store(chrono::Utc::now().timestamp(), Ordering::Relaxed);
+        }
+        previous
+    }
+
+    /// When a feature was last turned on or off since startup, if ever.
+    pub fn last_changed(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        match self.changed_sec.load(Ordering::Relaxed) {
+            0 => None,
+            secs => Some(chrono::Utc.timestamp(secs, 0)),
+        }
+    }
+
+    /// The current state of every feature.
+    pub fn snapshot(&self) -> BTreeMap<Feature, bool> {
+        Feature::ALL
+            .iter()
+            .map(|&feature| (feature, self.is_enabled(feature)))
+            .collect()
+    }
+}
+
+impl Default for FeatureFlags {
+    fn default() -> FeatureFlags {
+        FeatureFlags::new(&FeatureSettings::default())
+    }
+}
@@ -1,87 +1,34 @@
 //! Implements talking to the Github API
 
-use bytes::buf::BufExt as _;
-use futures::future::{BoxFuture, FutureExt, TryFutureExt};
+use bytes::Buf as _;
 use hyper;
-use hyper::{Body, Request, Response, StatusCode};
-use mockall::automock;
+use hyper::{Body, Request, StatusCode};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json;
-use snafu::{ResultExt, Snafu};
+use snafu::ResultExt;
 use url::Url;
 
-use std::fmt::Debug;
 use std::sync::Arc;
 
-use crate::HttpClient;
-
-#[automock]
-pub trait GenericHttpClient: Send + Sync {
-    fn request(
-        &self,
-        request: Request<Body>,
-    ) -> BoxFuture<'static, Result<Response<Body>, HttpError>>;
-}
-
-impl GenericHttpClient for HttpClient {
-    fn request(
-        &self,
-        request: Request<Body>,
-    ) -> BoxFuture<'static, Result<Response<Body>, HttpError>> {
-        self.request(request)
-            .map_err(|source| HttpError::Hyper { source })
-            .boxed()
-    }
-}
-
-impl<T> GenericHttpClient for Arc<T>
-where
-    T: GenericHttpClient + ?Sized,
-{
-    fn request(
-        &self,
-        request: Request<Body>,
-    ) -> BoxFuture<'static, Result<Response<Body>, HttpError>> {
-        self.as_ref().request(request).boxed()
-    }
-}
+use crate::http_client::{DeserializeError, GenericHttpClient, HttpError};
 
 /// Used to talk to the Github API.
 ///
 /// Can safely be cloned.
-#[derive(Debug, Clone)]
-pub struct GithubApi<G: GenericHttpClient> {
-    pub http_client: G,
-}
-
-/// An error occured talking to Github.
-#[derive(Debug, Snafu)]
-pub enum HttpError {
-    /// Failed to parse response as expected JSON object/
-    #[snafu(display("Failed to parse JSON response from GitHub: {}", source))]
-    DeserializeError { source: serde_json::Error },
-    /// HTTP request failed/
-    #[snafu(display("Failed to send request to GitHub: {}", source))]
-    Hyper { source: hyper::Error },
-    #[snafu(display("Failed to send request to GitHub: {}", source))]
-    Http { source: http::Error },
-    /// Got non-2xx response.
-    #[snafu(display("Got non-200 response from GitHub: {}", code))]
-    Status { code: StatusCode },
+#[derive(Clone)]
+pub struct GithubApi {
+    pub http_client: Arc<dyn GenericHttpClient>,
 }
 
-impl<G> GithubApi<G>
-where
-    G: GenericHttpClient,
-{
+impl GithubApi {
     /// Exchange received OAuth code with Github.
     pub async fn exchange_oauth_code(
         &self,
         client_id: &str,
         client_secret: &str,
         code: &str,
-    ) -> Result<GithubCallbackAuthResponse, HttpError> {
+    ) -> Result<GithubAccessTokenResponse, HttpError> {
         let mut gh = Url::parse("https://github.com/login/oauth/access_token").unwrap();
 
         gh.query_pairs_mut()
@@ -175,6 +122,27 @@ pub struct GithubCallbackAuthResponse {
     pub scope: String,
 }
 
+/// Github's error response to `/login/oauth/access_token`, e.g. when the code
+/// has expired or already been used.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GithubOAuthError {
+    /// The error code, e.g. `bad_verification_code`.
+    pub error: String,
+    /// A human readable description of the error.
+    pub error_description: Option<String>,
+}
+
+/// Github API response to `/login/oauth/access_token`. Errors are returned
+/// with a 200 status, so are parsed along with the success response.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum GithubAccessTokenResponse {
+    /// The code was exchanged for an access token.
+    Granted(GithubCallbackAuthResponse),
+    /// The code was rejected.
+    Denied(GithubOAuthError),
+}
+
 /// Github API repsonse to `/user`
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct GithubUserResponse {
@@ -0,0 +1,263 @@
+//! The outbound HTTP client used to talk to other services.
+//!
+//! Everything that makes outbound requests does so through a shared
+//! [GenericHttpClient] trait object, so that it can be mocked in tests and
+//! the underlying implementation swapped out.
+//!
+//! The shared client is wrapped in an [InstrumentedHttpClient], which tracks
+//! the health of each host in a [HostMonitor] and stops sending requests to
+//! hosts that keep failing until they've had a chance to recover.
+
+use futures::future::{self, BoxFuture, FutureExt};
+use hyper::client::HttpConnector;
+use hyper::{Body, Request, Response, StatusCode};
+use hyper_tls::HttpsConnector;
+use mockall::automock;
+use serde::Serialize;
+use snafu::Snafu;
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::settings::HttpClientSettings;
+
+/// Something that can make outbound HTTP requests.
+#[automock]
+pub trait GenericHttpClient: Send + Sync {
+    fn request(
+        &self,
+        request: Request<Body>,
+    ) -> BoxFuture<'static, Result<Response<Body>, HttpError>>;
+}
+
+impl<T> GenericHttpClient for Arc<T>
+where
+    T: GenericHttpClient + ?Sized,
+{
+    fn request(
+        &self,
+        request: Request<Body>,
+    ) -> BoxFuture<'static, Result<Response<Body>, HttpError>> {
+        self.as_ref().request(request).boxed()
+    }
+}
+
+/// An error occured making an outbound HTTP request.
+#[derive(Debug, Snafu)]
+#[snafu(visibility = "pub(crate)")]
+pub enum HttpError {
+    /// Failed to parse response as expected JSON object/
+    #[snafu(display("Failed to parse JSON response: {}", source))]
+    DeserializeError { source: serde_json::Error },
+    /// HTTP request failed/
+    #[snafu(display("Failed to send request: {}", source))]
+    Hyper { source: hyper::Error },
+    #[snafu(display("Failed to build request: {}", source))]
+    Http { source: http::Error },
+    /// Got non-2xx response.
+    #[snafu(display("Got non-200 response: {}", code))]
+    Status { code: StatusCode },
+    /// The request took longer than the configured timeout.
+    #[snafu(display("Request timed out"))]
+    Timeout,
+    /// The host has been failing, so the request wasn't sent.
+    #[snafu(display("Not sending request as {} has been failing", host))]
+    CircuitOpen { host: String },
+}
+
+/// A [GenericHttpClient] using hyper, with HTTPS support.
+#[derive(Clone)]
+pub struct HyperHttpClient {
+    client: hyper::Client<HttpsConnector<HttpConnector>>,
+    timeout: Duration,
+}
+
+impl HyperHttpClient {
+    /// Create a new client where each request fails if it takes longer than
+    /// `timeout`.
+    pub fn new(timeout: Duration) -> HyperHttpClient {
+        let https = HttpsConnector::new();
+        let client = hyper::Client::builder().build::<_, Body>(https);
+
+        HyperHttpClient { client, timeout }
+    }
+}
+
+impl GenericHttpClient for HyperHttpClient {
+    fn request(
+        &self,
+        request: Request<Body>,
+    ) -> BoxFuture<'static, Result<Response<Body>, HttpError>> {
+        tokio::time::timeout(self.timeout, self.client.request(request))
+            .map(|res| match res {
+                Ok(Ok(resp)) => Ok(resp),
+                Ok(Err(source)) => Err(HttpError::Hyper { source }),
+                Err(_) => Err(HttpError::Timeout),
+            })
+            .boxed()
+    }
+}
+
+/// Build the HTTP client configured by the settings. This should only be
+/// called once, with the result shared.
+pub fn build_http_client(settings: &HttpClientSettings) -> Arc<dyn GenericHttpClient> {
+    Arc::new(HyperHttpClient::new(Duration::from_secs(
+        settings.timeout_secs,
+    )))
+}
+
+/// Counts of the requests made to a host.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct HostStats {
+    /// Requests sent to the host.
+    pub requests: u64,
+    /// Requests that failed to get a response, or got a 5xx response.
+    pub errors: u64,
+    /// Requests that weren't sent because the circuit breaker was open.
+    pub rejected: u64,
+    /// The total time spent waiting for responses, in seconds.
+    pub latency_secs: f64,
+    /// Whether requests to the host are currently being rejected.
+    pub circuit_open: bool,
+}
+
+/// The circuit breaker state and stats for a host.
+#[derive(Debug, Default)]
+struct HostState {
+    stats: HostStats,
+    consecutive_failures: u32,
+    /// Requests are rejected until this time, if the circuit is open.
+    open_until: Option<Instant>,
+}
+
+/// Tracks the health of each host requests are made to, and decides whether
+/// requests to it should be sent.
+///
+/// After `failure_threshold` consecutive failures the host's circuit opens
+/// and requests fail immediately with [HttpError::CircuitOpen]. Once the
+/// cool-down has passed a single trial request is let through: if it
+/// succeeds the circuit closes, otherwise it stays open for another
+/// cool-down.
+#[derive(Debug)]
+pub struct HostMonitor {
+    failure_threshold: u32,
+    cooldown: Duration,
+    hosts: Mutex<BTreeMap<String, HostState>>,
+}
+
+impl HostMonitor {
+    /// Create a monitor. A `failure_threshold` of 0 never opens the circuit,
+    /// so only collects stats.
+    pub fn new(failure_threshold: u32, cooldown: Duration) -> HostMonitor {
+        HostMonitor {
+            failure_threshold,
+            cooldown,
+            hosts: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Called before sending a request to the host. Fails if the request
+    /// shouldn't be sent.
+    fn start(&self, host: &str) -> Result<(), HttpError> {
+        let mut hosts = self.hosts.lock().expect("host monitor lock poisoned");
+        let state = hosts.entry(host.to_string()).or_default();
+
+        if let Some(open_until) = state.open_until {
+            let now = Instant::now();
+            if now < open_until {
+                state.stats.rejected += 1;
+                return Err(HttpError::CircuitOpen {
+                    host: host.to_string(),
+                });
+            }
+
+            // Let this request through as a trial, holding back any others
+            // until we know how it went.
+            state.open_until = Some(now + self.cooldown);
+        }
+
+        state.stats.requests += 1;
+        Ok(())
+    }
+
+    /// Called once a request to the host has finished.
+    fn finish(&self, host: &str, latency: Duration, failed: bool) {
+        let mut hosts = self.hosts.lock().expect("host monitor lock poisoned");
+        let state = hosts.entry(host.to_string()).or_default();
+
+        state.stats.latency_secs += latency.as_secs_f64();
+
+        if failed {
+            state.stats.errors += 1;
+            state.consecutive_failures += 1;
+            if self.failure_threshold > 0 && state.consecutive_failures >= self.failure_threshold {
+                state.open_until = Some(Instant::now() + self.cooldown);
+            }
+        } else {
+            state.consecutive_failures = 0;
+            state.open_until = None;
+        }
+    }
+
+    /// The stats for each host requests have been made to.
+    pub fn stats(&self) -> BTreeMap<String, HostStats> {
+        let now = Instant::now();
+        let hosts = self.hosts.lock().expect("host monitor lock poisoned");
+
+        hosts
+            .iter()
+            .map(|(host, state)| {
+                let mut stats = state.stats.clone();
+                stats.circuit_open = state.open_until.map_or(false, |until| now < until);
+                (host.clone(), stats)
+            })
+            .collect()
+    }
+}
+
+/// A [GenericHttpClient] that records each request in a [HostMonitor], and
+/// fails fast for hosts whose circuit is open.
+pub struct InstrumentedHttpClient {
+    inner: Arc<dyn GenericHttpClient>,
+    monitor: Arc<HostMonitor>,
+}
+
+impl InstrumentedHttpClient {
+    pub fn new(
+        inner: Arc<dyn GenericHttpClient>,
+        monitor: Arc<HostMonitor>,
+    ) -> InstrumentedHttpClient {
+        InstrumentedHttpClient { inner, monitor }
+    }
+}
+
+impl GenericHttpClient for InstrumentedHttpClient {
+    fn request(
+        &self,
+        request: Request<Body>,
+    ) -> BoxFuture<'static, Result<Response<Body>, HttpError>> {
+        let host = request.uri().host().unwrap_or("unknown").to_string();
+
+        if let Err(err) = self.monitor.start(&host) {
+            return future::err(err).boxed();
+        }
+
+        let monitor = self.monitor.clone();
+        let start = Instant::now();
+        let fut = self.inner.request(request);
+
+        async move {
+            let res = fut.await;
+
+            let failed = match &res {
+                Ok(resp) => resp.status().is_server_error(),
+                Err(_) => true,
+            };
+            monitor.finish(&host, start.elapsed(), failed);
+
+            res
+        }
+        .boxed()
+    }
+}
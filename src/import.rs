@@ -0,0 +1,372 @@
+//! Parses bank statements so their lines can be turned into transactions.
+//!
+//! Supports OFX, QIF and CSV statements. Parsed lines can be matched against
+//! existing transactions to spot entries that have already been recorded.
+
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use snafu::Snafu;
+
+use std::str::FromStr;
+
+use crate::amount::parse_amount;
+use crate::db::Transaction;
+use crate::money::Money;
+use crate::settings::CurrencySettings;
+
+/// Date formats we accept in QIF and CSV statements, tried in order.
+const DATE_FORMATS: &[&str] = &["%Y-%m-%d", "%d/%m/%y", "%d/%m/%Y", "%Y%m%d"];
+
+/// The number of days either side of a statement line's date that we look
+/// for a matching transaction.
+pub const MATCH_WINDOW_DAYS: i64 = 3;
+
+/// The supported statement file formats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StatementFormat {
+    Ofx,
+    Qif,
+    Csv,
+}
+
+impl FromStr for StatementFormat {
+    type Err = ImportError;
+
+    fn from_str(s: &str) -> Result<StatementFormat, ImportError> {
+        match s.to_ascii_lowercase().as_str() {
+            "ofx" => Ok(StatementFormat::Ofx),
+            "qif" => Ok(StatementFormat::Qif),
+            "csv" => Ok(StatementFormat::Csv),
+            _ => Err(ImportError::UnknownFormat {
+                format: s.to_string(),
+            }),
+        }
+    }
+}
+
+/// A single entry in a bank statement.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StatementLine {
+    /// The date the entry was posted.
+    pub date: NaiveDate,
+    /// The amount. Negative means money left the account.
+    pub amount: Money,
+    /// The payee and/or memo of the entry.
+    pub description: String,
+}
+
+/// A statement line along with any existing transaction that looks like it
+/// records the same payment.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProposedMatch {
+    pub line: StatementLine,
+    pub existing: Option<Transaction>,
+}
+
+/// Error parsing a bank statement.
+#[derive(Debug, Snafu)]
+pub enum ImportError {
+    /// The requested statement format isn't supported.
+    #[snafu(display("Unknown statement format: {}", format))]
+    UnknownFormat { format: String },
+
+    /// A required column is missing from a CSV statement header.
+    #[snafu(display("Statement is missing a {} column", column))]
+    MissingColumn { column: &'static str },
+
+    /// A statement entry is missing a required field.
+    #[snafu(display("Entry {} is missing a {} field", entry, field))]
+    MissingField { entry: usize, field: &'static str },
+
+    /// A date could not be parsed.
+    #[snafu(display("Entry {} has an invalid date: {}", entry, value))]
+    InvalidDate { entry: usize, value: String },
+
+    /// An amount could not be parsed.
+    #[snafu(display("Entry {} has an invalid amount: {}", entry, value))]
+    InvalidAmount { entry: usize, value: String },
+}
+
+/// Parse a statement in the given format.
+pub fn parse_statement(
+    format: StatementFormat,
+    contents: &str,
+    currency: &CurrencySettings,
+) -> Result<Vec<StatementLine>, ImportError> {
+    match format {
+        StatementFormat::Ofx => parse_ofx(contents, currency),
+        StatementFormat::Qif => parse_qif(contents, currency),
+        StatementFormat::Csv => parse_csv(contents, currency),
+    }
+}
+
+/// Parse an OFX statement. Handles both the SGML (OFX 1.x) and XML (OFX 2.x)
+/// dialects, as we only look at the opening tags.
+pub fn parse_ofx(
+    contents: &str,
+    currency: &CurrencySettings,
+) -> Result<Vec<StatementLine>, ImportError> {
+    let mut lines = Vec::new();
+
+    for (entry, block) in contents.split("<STMTTRN>").skip(1).enumerate() {
+        let block = block.split("</STMTTRN>").next().unwrap_or(block);
+
+        let date_str = ofx_field(block, "DTPOSTED").ok_or(ImportError::MissingField {
+            entry,
+            field: "DTPOSTED",
+        })?;
+        // Dates look like `20200124120000[0:GMT]`, we only care about the day.
+        let date = date_str
+            .get(..8)
+            .and_then(|d| NaiveDate::parse_from_str(d, "%Y%m%d").ok())
+            .ok_or_else(|| ImportError::InvalidDate {
+                entry,
+                value: date_str.to_string(),
+            })?;
+
+        let amount_str = ofx_field(block, "TRNAMT").ok_or(ImportError::MissingField {
+            entry,
+            field: "TRNAMT",
+        })?;
+        let amount = parse_line_amount(entry, amount_str, currency)?;
+
+        let description = join_description(ofx_field(block, "NAME"), ofx_field(block, "MEMO"));
+
+        lines.push(StatementLine {
+            date,
+            amount,
+            description,
+        });
+    }
+
+    Ok(lines)
+}
+
+/// Parse a QIF statement.
+pub fn parse_qif(
+    contents: &str,
+    currency: &CurrencySettings,
+) -> Result<Vec<StatementLine>, ImportError> {
+    let mut lines = Vec::new();
+
+    let mut date = None;
+    let mut amount = None;
+    let mut payee = None;
+    let mut memo = None;
+
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let mut chars = line.chars();
+        let code = chars.next();
+        let value = chars.as_str().trim();
+        let entry = lines.len();
+
+        match code {
+            Some('D') => {
+                date = Some(parse_date(value).ok_or_else(|| ImportError::InvalidDate {
+                    entry,
+                    value: value.to_string(),
+                })?)
+            }
+            Some('T') | Some('U') => amount = Some(parse_line_amount(entry, value, currency)?),
+            Some('P') => payee = Some(value.to_string()),
+            Some('M') => memo = Some(value.to_string()),
+            Some('^') => {
+                lines.push(StatementLine {
+                    date: date.take().ok_or(ImportError::MissingField {
+                        entry,
+                        field: "date",
+                    })?,
+                    amount: amount.take().ok_or(ImportError::MissingField {
+                        entry,
+                        field: "amount",
+                    })?,
+                    description: join_description(payee.take(), memo.take()),
+                });
+            }
+            // Headers (`!Type:Bank`) and fields we don't care about.
+            _ => {}
+        }
+    }
+
+    Ok(lines)
+}
+
+/// Parse a CSV statement. The first row must be a header naming `date` and
+/// `amount` columns, plus optionally `description`, `payee` or `memo`.
+pub fn parse_csv(
+    contents: &str,
+    currency: &CurrencySettings,
+) -> Result<Vec<StatementLine>, ImportError> {
+    let mut rows = contents.lines().filter(|line| !line.trim().is_empty());
+
+    let header: Vec<String> = rows
+        .next()
+        .map(split_csv_row)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|column| column.trim().to_ascii_lowercase())
+        .collect();
+
+    let find_column = |names: &[&str]| header.iter().position(|c| names.contains(&c.as_str()));
+
+    let date_idx = find_column(&["date", "posted", "transaction date"])
+        .ok_or(ImportError::MissingColumn { column: "date" })?;
+    let amount_idx =
+        find_column(&["amount", "value"]).ok_or(ImportError::MissingColumn { column: "amount" })?;
+    let description_idx = find_column(&["description", "payee", "memo", "reference"]);
+
+    let mut lines = Vec::new();
+
+    for (entry, row) in rows.enumerate() {
+        let fields = split_csv_row(row);
+
+        let date_str = fields
+            .get(date_idx)
+            .map(|s| s.trim())
+            .ok_or(ImportError::MissingField {
+                entry,
+                field: "date",
+            })?;
+        let date = parse_date(date_str).ok_or_else(|| ImportError::InvalidDate {
+            entry,
+            value: date_str.to_string(),
+        })?;
+
+        let amount_str =
+            fields
+                .get(amount_idx)
+                .map(|s| s.trim())
+                .ok_or(ImportError::MissingField {
+                    entry,
+                    field: "amount",
+                })?;
+        let amount = parse_line_amount(entry, amount_str, currency)?;
+
+        let description = description_idx
+            .and_then(|idx| fields.get(idx))
+            .map(|s| s.trim().to_string())
+            .unwrap_or_default();
+
+        lines.push(StatementLine {
+            date,
+            amount,
+            description,
+        });
+    }
+
+    Ok(lines)
+}
+
+/// Pair up statement lines with existing transactions involving `user_id` that
+/// have the same (absolute) amount within [MATCH_WINDOW_DAYS] of the line.
+///
+/// Each existing transaction is matched at most once.
+pub fn propose_matches(
+    user_id: &str,
+    lines: Vec<StatementLine>,
+    existing: &[Transaction],
+) -> Vec<ProposedMatch> {
+    let mut used = vec![false; existing.len()];
+
+    lines
+        .into_iter()
+        .map(|line| {
+            let found = existing.iter().enumerate().position(|(idx, txn)| {
+                !used[idx]
+                    && (txn.shafter == user_id || txn.shaftee == user_id)
+                    && txn.amount.abs() == line.amount.abs()
+                    && txn
+                        .datetime
+                        .naive_utc()
+                        .date()
+                        .signed_duration_since(line.date)
+                        .num_days()
+                        .abs()
+                        <= MATCH_WINDOW_DAYS
+            });
+
+            let existing = found.map(|idx| {
+                used[idx] = true;
+                existing[idx].clone()
+            });
+
+            ProposedMatch { line, existing }
+        })
+        .collect()
+}
+
+/// Find the value of an OFX tag within a block, i.e. the text following
+/// `<TAG>` up to the next tag or line break.
+fn ofx_field<'a>(block: &'a str, tag: &str) -> Option<&'a str> {
+    let start = block.find(&format!("<{}>", tag))? + tag.len() + 2;
+    let rest = &block[start..];
+    let end = rest.find(|c| c == '<' || c == '\n' || c == '\r');
+    let value = end.map(|end| &rest[..end]).unwrap_or(rest).trim();
+
+    if value.is_empty() {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+/// Parse a date in any of the [DATE_FORMATS].
+fn parse_date(value: &str) -> Option<NaiveDate> {
+    DATE_FORMATS
+        .iter()
+        .filter_map(|fmt| NaiveDate::parse_from_str(value, fmt).ok())
+        .next()
+}
+
+/// Parse the amount of a statement line.
+fn parse_line_amount(
+    entry: usize,
+    value: &str,
+    currency: &CurrencySettings,
+) -> Result<Money, ImportError> {
+    parse_amount(value, currency)
+        .map(|amount| Money::new(amount, currency.currency()))
+        .map_err(|_| ImportError::InvalidAmount {
+            entry,
+            value: value.to_string(),
+        })
+}
+
+/// Combine a payee and memo into a single description.
+fn join_description<P: AsRef<str>, M: AsRef<str>>(payee: Option<P>, memo: Option<M>) -> String {
+    match (payee, memo) {
+        (Some(p), Some(m)) => format!("{} {}", p.as_ref(), m.as_ref()),
+        (Some(p), None) => p.as_ref().to_string(),
+        (None, Some(m)) => m.as_ref().to_string(),
+        (None, None) => String::new(),
+    }
+}
+
+/// Split a CSV row into fields, handling quoted fields.
+fn split_csv_row(row: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = row.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => fields.push(std::mem::replace(&mut field, String::new())),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+
+    fields
+}
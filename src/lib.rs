@@ -1,13 +1,38 @@
 #[macro_use]
 extern crate slog;
 
-use hyper_tls::HttpsConnector;
-
-/// Short hand for our HTTPS enabled outbound HTTP client.
-type HttpClient = hyper::Client<HttpsConnector<hyper::client::HttpConnector>>;
-
+pub mod amount;
+pub mod api_types;
+#[cfg(feature = "client")]
+pub mod client;
+pub mod config_bundle;
+pub mod content_policy;
+pub mod data_dir;
 pub mod db;
 pub mod error;
+pub mod error_reporting;
+pub mod events;
+pub mod export;
+pub mod features;
 pub mod github;
+pub mod http_client;
+pub mod import;
+pub mod logging;
+pub mod mailer;
+pub mod maintenance;
+pub mod matcher;
+pub mod money;
+pub mod notifier;
+pub mod plugin;
+pub mod rate_limit;
+pub mod reason;
+pub mod receipts;
+#[cfg(feature = "redis")]
+pub mod redis_store;
 pub mod rest;
+pub mod seed;
 pub mod settings;
+pub mod shared_state;
+pub mod signing;
+pub mod version;
+pub mod webhooks;
@@ -0,0 +1,151 @@
+//! Per-module log levels that can be changed while the server is running.
+//!
+//! Modules are named by their path within the crate, e.g. `rest` or
+//! `rest::api`, and a level set for a module also applies to its submodules.
+//! The most specific match wins, falling back to the default level.
+
+use serde::{Deserialize, Serialize};
+use slog::{Drain, Logger, OwnedKVList, Record};
+use sloggers::types::Severity;
+use sloggers::{Config, LoggerConfig};
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+
+use crate::settings::LogSettings;
+
+/// The prefix stripped from record module paths before matching.
+const CRATE_NAME: &str = "shaft";
+
+/// A snapshot of the log levels, as configured and returned by the admin API.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogLevelConfig {
+    /// The level for modules without an override.
+    pub default: Severity,
+    /// Overrides for particular modules.
+    #[serde(default)]
+    pub modules: BTreeMap<String, Severity>,
+}
+
+impl Default for LogLevelConfig {
+    fn default() -> LogLevelConfig {
+        LogLevelConfig {
+            default: Severity::Info,
+            modules: BTreeMap::new(),
+        }
+    }
+}
+
+/// The current log levels, shared between the logger and the admin API.
+///
+/// Safe to clone as the levels will be shared.
+#[derive(Debug, Clone, Default)]
+pub struct LogLevels {
+    inner: Arc<RwLock<LogLevelConfig>>,
+}
+
+impl LogLevels {
+    pub fn new(config: LogLevelConfig) -> LogLevels {
+        LogLevels {
+            inner: Arc::new(RwLock::new(config)),
+        }
+    }
+
+    /// The current levels.
+    pub fn get(&self) -> LogLevelConfig {
+        self.inner.read().expect("lock poisoned").clone()
+    }
+
+    /// Replace the levels. Takes effect for all subsequent log lines.
+    pub fn set(&self, config: LogLevelConfig) {
+        *self.inner.write().expect("lock poisoned") = config;
+    }
+
+    /// The minimum level logged for the given module path, as reported by
+    /// `module_path!()`.
+    pub fn level_for(&self, module_path: &str) -> Severity {
+        let path = strip_crate_name(module_path);
+        let config = self.inner.read().expect("lock poisoned");
+
+        config
+            .modules
+            .iter()
+            .filter(|(module, _)| is_within(path, module))
+            .max_by_key(|(module, _)| module.len())
+            .map(|(_, level)| *level)
+            .unwrap_or(config.default)
+    }
+}
+
+/// Strips the crate name from a module path, so that `shaft::rest::api`
+/// becomes `rest::api`.
+fn strip_crate_name(module_path: &str) -> &str {
+    if module_path == CRATE_NAME {
+        ""
+    } else {
+        module_path
+            .strip_prefix(CRATE_NAME)
+            .and_then(|rest| rest.strip_prefix("::"))
+            .unwrap_or(module_path)
+    }
+}
+
+/// Whether the module path is the module or one of its submodules.
+fn is_within(path: &str, module: &str) -> bool {
+    path == module
+        || path
+            .strip_prefix(module)
+            .map_or(false, |rest| rest.starts_with("::"))
+}
+
+/// A drain that drops records below their module's level.
+pub struct ModuleLevelFilter<D> {
+    drain: D,
+    levels: LogLevels,
+}
+
+impl<D> ModuleLevelFilter<D> {
+    pub fn new(drain: D, levels: LogLevels) -> ModuleLevelFilter<D> {
+        ModuleLevelFilter { drain, levels }
+    }
+}
+
+impl<D: Drain> Drain for ModuleLevelFilter<D> {
+    type Ok = ();
+    type Err = D::Err;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<(), D::Err> {
+        let level = self.levels.level_for(record.module());
+        if record.level().is_at_least(level.as_level()) {
+            self.drain.log(record, values)?;
+        }
+        Ok(())
+    }
+}
+
+/// Build the root logger from the settings, returning the handle used to
+/// change its levels.
+///
+/// The configured output logs everything, with filtering done by
+/// [ModuleLevelFilter] so that modules can be made more verbose than the
+/// default.
+pub fn build_logger(settings: &LogSettings) -> Result<(Logger, LogLevels), sloggers::Error> {
+    let default = match &settings.logger {
+        LoggerConfig::File(config) => config.level,
+        LoggerConfig::Terminal(config) => config.level,
+        LoggerConfig::Null(_) => Severity::Critical,
+    };
+
+    let mut output = settings.logger.clone();
+    output.set_loglevel(Severity::Trace);
+    let output = output.build_logger()?;
+
+    let levels = LogLevels::new(LogLevelConfig {
+        default,
+        modules: settings.modules.clone(),
+    });
+
+    let logger = Logger::root(ModuleLevelFilter::new(output, levels.clone()), o!());
+
+    Ok((logger, levels))
+}
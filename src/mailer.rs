@@ -0,0 +1,126 @@
+//! Sending emails, e.g. invitations to a ledger.
+//!
+//! Emails are handed to the configured [Mailer]. By default they are dropped,
+//! as there's nowhere to send them.
+
+use futures::future::{self, BoxFuture, FutureExt};
+use hyper::{Body, Request, StatusCode};
+use serde::Serialize;
+use snafu::{ResultExt, Snafu};
+
+use std::sync::Arc;
+
+use crate::http_client::{GenericHttpClient, HttpError};
+
+/// A plain text email to a single recipient.
+#[derive(Debug, Clone, Serialize)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub text: String,
+}
+
+/// Something that can send emails.
+pub trait Mailer: Send + Sync {
+    /// Send the email, resolving once it has been accepted for delivery.
+    fn send(&self, email: Email) -> BoxFuture<'static, Result<(), MailError>>;
+}
+
+/// Error sending an email.
+#[derive(Debug, Snafu)]
+pub enum MailError {
+    /// Failed to talk to the mail service.
+    #[snafu(display("Failed to send email: {}", source))]
+    RequestError { source: HttpError },
+    /// Got non-2xx response.
+    #[snafu(display("Got non-200 response from mail service: {}", code))]
+    Status { code: StatusCode },
+    /// Failed to serialize the email.
+    #[snafu(display("Failed to serialize email: {}", source))]
+    SerializeError { source: serde_json::Error },
+}
+
+/// The default [Mailer], which drops every email.
+#[derive(Debug, Clone, Default)]
+pub struct NoopMailer;
+
+impl Mailer for NoopMailer {
+    fn send(&self, _email: Email) -> BoxFuture<'static, Result<(), MailError>> {
+        future::ok(()).boxed()
+    }
+}
+
+/// A [Mailer] that POSTs emails to an HTTP endpoint, e.g. a transactional
+/// email service or a small relay in front of an SMTP server.
+///
+/// The endpoint receives a JSON object with `from`, `to`, `subject` and
+/// `text` fields.
+pub struct HttpMailer {
+    endpoint: String,
+    from: String,
+    http_client: Arc<dyn GenericHttpClient>,
+}
+
+impl HttpMailer {
+    pub fn new(
+        endpoint: String,
+        from: String,
+        http_client: Arc<dyn GenericHttpClient>,
+    ) -> HttpMailer {
+        HttpMailer {
+            endpoint,
+            from,
+            http_client,
+        }
+    }
+}
+
+/// The body POSTed by [HttpMailer].
+#[derive(Serialize)]
+struct HttpMailerBody<'a> {
+    from: &'a str,
+    #[serde(flatten)]
+    email: &'a Email,
+}
+
+impl Mailer for HttpMailer {
+    fn send(&self, email: Email) -> BoxFuture<'static, Result<(), MailError>> {
+        let body = HttpMailerBody {
+            from: &self.from,
+            email: &email,
+        };
+        let body = match serde_json::to_vec(&body) {
+            Ok(body) => body,
+            Err(source) => return future::err(MailError::SerializeError { source }).boxed(),
+        };
+
+        let req = Request::post(self.endpoint.as_str())
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body));
+
+        let req = match req {
+            Ok(req) => req,
+            Err(source) => {
+                return future::err(MailError::RequestError {
+                    source: HttpError::Http { source },
+                })
+                .boxed()
+            }
+        };
+
+        let resp_fut = self.http_client.request(req);
+
+        async move {
+            let resp = resp_fut.await.context(RequestError)?;
+
+            if !resp.status().is_success() {
+                return Err(MailError::Status {
+                    code: resp.status(),
+                });
+            }
+
+            Ok(())
+        }
+        .boxed()
+    }
+}
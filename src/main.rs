@@ -3,21 +3,46 @@ extern crate slog;
 #[macro_use]
 extern crate clap;
 
-use clap::Arg;
+use actix_web::http::KeepAlive;
+use actix_web::web;
+use clap::{AppSettings, Arg, ArgMatches, SubCommand};
 use daemonize::Daemonize;
-use sloggers::Config;
+use slog::Logger;
 
 use std::error::Error;
 use std::fs::File;
 use std::io::Read;
+use std::net::TcpListener;
+use std::path::Path;
 use std::process::exit;
+use std::sync::Arc;
+use std::time::Duration;
 
-use shaft::db::SqliteDatabase;
+use shaft::config_bundle::{BundleFormat, ConfigBundle};
+use shaft::content_policy::DenylistPolicy;
+use shaft::data_dir::{bootstrap_admin, needs_setup, DataDir, BOOTSTRAP_ADMIN};
+use shaft::db::{anonymise_database, Database, DatabaseError, Outbox, SqliteDatabase};
+use shaft::error_reporting::{ErrorReporter, NoopErrorReporter, SentryReporter};
+use shaft::features::FeatureFlags;
+use shaft::http_client::{build_http_client, HostMonitor, InstrumentedHttpClient};
+use shaft::logging::{build_logger, LogLevels};
+use shaft::mailer::HttpMailer;
+use shaft::maintenance::{self, parse_age};
+use shaft::money::MoneyHelper;
+use shaft::notifier::HttpNotifier;
+use shaft::receipts::HttpReceiptProcessor;
 use shaft::rest::{
-    format_pence_as_pounds_helper, register_servlets, AppConfig, AppState, AuthenticateUser,
-    MiddlewareLogger,
+    register_helpers, register_tenant_servlets, spawn_debt_digest, spawn_outbox_dispatcher,
+    spawn_shared_state_poller, tenant_config, tenant_path, validate_tenants, AppConfig, AppState,
+    AuthenticateUser, IpFilter, IpRules, MiddlewareLogger, ReportErrors, RequestDeadline,
+    SessionRefresh, Setup, Tenant, TenantRegistry,
 };
-use shaft::settings::Settings;
+use shaft::seed::{seed, SeedOptions};
+use shaft::settings::{
+    CurrencySettings, DatabaseBackend, Settings, SharedStateBackend, UiSettings,
+};
+use shaft::shared_state::DatabaseSharedState;
+use shaft::version::VersionInfo;
 
 /// Attempts to load and build the handlebars template file.
 macro_rules! load_template {
@@ -44,10 +69,170 @@ fn main() {
                 .takes_value(true)
                 .required(false),
         )
+        .arg(
+            Arg::with_name("data-dir")
+                .long("data-dir")
+                .value_name("DIR")
+                .help(
+                    "Keeps the database and settings in this directory, creating a demo \
+                     config there if it has none",
+                )
+                .takes_value(true),
+        )
+        .subcommand(
+            SubCommand::with_name("anonymise")
+                .about(
+                    "Writes a copy of the database with fake names, reasons and amounts, \
+                     for sharing when reporting bugs",
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .value_name("FILE")
+                        .help("Where to write the copy. Must not already exist.")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("admin")
+                .about("Database maintenance")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("prune")
+                        .about("Deletes old data")
+                        .arg(
+                            Arg::with_name("tokens")
+                                .long("tokens")
+                                .help("Deletes expired tokens")
+                                .required_unless("changes"),
+                        )
+                        .arg(Arg::with_name("changes").long("changes").help(
+                            "Compacts the change log used for syncing, deleting \
+                                     superseded changes and changes to deleted rows",
+                        ))
+                        .arg(
+                            Arg::with_name("older-than")
+                                .long("older-than")
+                                .value_name("AGE")
+                                .help(
+                                    "Also deletes login sessions not issued or extended within \
+                                     this long, e.g. 90d",
+                                )
+                                .takes_value(true),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("vacuum")
+                        .about("Reclaims unused space and updates query planner statistics"),
+                )
+                .subcommand(
+                    SubCommand::with_name("seed")
+                        .about("Adds fake users and transactions, for testing")
+                        .arg(
+                            Arg::with_name("users")
+                                .long("users")
+                                .value_name("COUNT")
+                                .help("Number of users")
+                                .default_value("5"),
+                        )
+                        .arg(
+                            Arg::with_name("transactions")
+                                .long("transactions")
+                                .value_name("COUNT")
+                                .help("Number of transactions to add")
+                                .default_value("200"),
+                        )
+                        .arg(
+                            Arg::with_name("seed")
+                                .long("seed")
+                                .value_name("NUMBER")
+                                .help("Seed for the random data")
+                                .default_value("0"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("check")
+                        .about("Checks for rows that reference users that don't exist")
+                        .arg(
+                            Arg::with_name("repair")
+                                .long("repair")
+                                .help("Repairs the problems found"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("config")
+                        .about("Moves configuration between hosts")
+                        .setting(AppSettings::SubcommandRequiredElseHelp)
+                        .subcommand(
+                            SubCommand::with_name("export")
+                                .about(
+                                    "Writes the settings from the config files, without \
+                                     secrets, and the state of each feature as a bundle",
+                                )
+                                .arg(
+                                    Arg::with_name("output")
+                                        .long("output")
+                                        .short("o")
+                                        .value_name("FILE")
+                                        .help(
+                                            "Where to write the bundle, as JSON if it ends in \
+                                             .json. Defaults to TOML on stdout",
+                                        )
+                                        .takes_value(true),
+                                ),
+                        )
+                        .subcommand(
+                            SubCommand::with_name("import")
+                                .about(
+                                    "Writes a settings file from a bundle, taking secrets from \
+                                     this host's config files",
+                                )
+                                .arg(
+                                    Arg::with_name("bundle")
+                                        .value_name("BUNDLE")
+                                        .help("The bundle, read as JSON if it ends in .json")
+                                        .required(true),
+                                )
+                                .arg(
+                                    Arg::with_name("output")
+                                        .long("output")
+                                        .short("o")
+                                        .value_name("FILE")
+                                        .help("Where to write the settings. Defaults to stdout")
+                                        .takes_value(true),
+                                ),
+                        ),
+                ),
+        )
         .get_matches();
 
     let mut c = config::Config::new();
 
+    // The data directory's settings come first, so config files can override
+    // them.
+    let data_dir = matches.value_of("data-dir").map(DataDir::new);
+    if let Some(data_dir) = &data_dir {
+        if let Err(err) = data_dir.init() {
+            eprintln!(
+                "Failed to set up data directory {}: {}",
+                data_dir.path().display(),
+                err
+            );
+            exit(1)
+        }
+
+        let database_file = data_dir.database_file().display().to_string();
+        c.set_default("database_file", database_file).unwrap();
+
+        if let Err(err) = c.merge(config::File::from(data_dir.settings_file())) {
+            eprintln!("{}", err);
+            exit(1)
+        }
+        if let Err(err) = c.merge(config::File::from(data_dir.setup_file()).required(false)) {
+            eprintln!("{}", err);
+            exit(1)
+        }
+    }
+
     // We can have multiple config files which get merged together
     for file in matches.values_of("config").unwrap_or_default() {
         if let Err(err) = c.merge(config::File::with_name(file)) {
@@ -57,6 +242,10 @@ fn main() {
         }
     }
 
+    // Config bundles only hold settings from files, as the environment tends
+    // to be specific to the host.
+    let file_config = c.clone();
+
     // Also load config from environment
     c.merge(config::Environment::with_prefix("SHAFT")).unwrap();
 
@@ -70,18 +259,144 @@ fn main() {
     };
 
     // Set up logging immediately.
-    let logger = settings.log.build_logger().unwrap();
+    let (logger, log_levels) = build_logger(&settings.log).unwrap();
 
-    // Load and build the templates.
-    let mut hb = handlebars::Handlebars::new();
-    load_template!(logger, hb, &settings.resource_dir, "index");
-    load_template!(logger, hb, &settings.resource_dir, "login");
-    load_template!(logger, hb, &settings.resource_dir, "transactions");
-    load_template!(logger, hb, &settings.resource_dir, "base");
-    hb.register_helper("pence-as-pounds", Box::new(format_pence_as_pounds_helper));
+    if let Some(anonymise_matches) = matches.subcommand_matches("anonymise") {
+        let output = anonymise_matches
+            .value_of("output")
+            .expect("output is required");
+        anonymise(&logger, &settings.database_file, output);
+        return;
+    }
+
+    if let Some(config_matches) = matches
+        .subcommand_matches("admin")
+        .and_then(|admin_matches| admin_matches.subcommand_matches("config"))
+    {
+        config_bundle(&logger, &settings, file_config, config_matches);
+        return;
+    }
+
+    if let Some(admin_matches) = matches.subcommand_matches("admin") {
+        actix_web::rt::System::new().block_on(admin(&logger, &settings, admin_matches));
+        return;
+    }
+
+    if let Err(e) = validate_tenants(&settings.tenants) {
+        crit!(logger, "Invalid tenant settings: {}", e);
+        exit(1);
+    }
+
+    let hb = load_templates(
+        &logger,
+        &settings.resource_dir,
+        &settings.currency,
+        &settings.ui,
+    );
+
+    // Bind the listener before daemonizing so that failures are reported to
+    // the terminal.
+    let listener = match TcpListener::bind(&settings.bind) {
+        Ok(listener) => listener,
+        Err(e) => {
+            crit!(logger, "Failed to bind to {}: {}", settings.bind, e);
+            exit(1);
+        }
+    };
+
+    // If we need to daemonize do so before starting the runtime, as forking
+    // doesn't play well with its threads.
+    if let Some(daemonize_settings) = &settings.daemonize {
+        Daemonize::new()
+            .pid_file(&daemonize_settings.pid_file)
+            .start()
+            .expect("be able to daemonize");
+    }
+
+    // Start the event loop.
+    let res = actix_web::rt::System::new().block_on(run(
+        logger.clone(),
+        log_levels,
+        settings,
+        data_dir,
+        hb,
+        listener,
+    ));
+    if let Err(e) = res {
+        crit!(logger, "Server failed: {}", e);
+        exit(1);
+    }
+}
+
+/// Sets up the database and app state, and runs the HTTP server until it is
+/// shut down.
+async fn run(
+    logger: Logger,
+    log_levels: LogLevels,
+    settings: Settings,
+    data_dir: Option<DataDir>,
+    hb: handlebars::Handlebars<'static>,
+    listener: TcpListener,
+) -> std::io::Result<()> {
+    let database = open_database(&logger, &settings).await;
+
+    if let Some(data_dir) = &data_dir {
+        match bootstrap_admin(database.as_ref()).await {
+            Ok(Some(token)) => match data_dir.write_bootstrap_token(&token) {
+                Ok(path) => warn!(
+                    logger,
+                    "Use the token in {} as a bearer token to administer the instance",
+                    path.display();
+                    "user" => BOOTSTRAP_ADMIN,
+                ),
+                Err(e) => {
+                    crit!(logger, "Failed to write admin token: {}", e);
+                    exit(1);
+                }
+            },
+            Ok(None) => {}
+            Err(e) => {
+                crit!(logger, "Failed to create admin user: {}", e);
+                exit(1);
+            }
+        }
+    }
+
+    let setup = match &data_dir {
+        Some(data_dir) => match needs_setup(database.as_ref(), &settings.github).await {
+            Ok(true) => Some(Arc::new(Setup::new(data_dir))),
+            Ok(false) => None,
+            Err(e) => {
+                crit!(logger, "Failed to check whether setup is needed: {}", e);
+                exit(1);
+            }
+        },
+        None => None,
+    };
 
-    // Set up the database
-    let database = SqliteDatabase::with_path(settings.database_file);
+    if let Some(token) = setup.as_ref().and_then(|setup| setup.token()) {
+        warn!(
+            logger,
+            "Finish setting up shaft at {}/setup?token={}",
+            settings.web_root.trim_end_matches('/'),
+            token,
+        );
+    } else if settings.github.client_id.is_empty() {
+        warn!(
+            logger,
+            "GitHub login isn't configured, so nobody can log in to the web UI"
+        );
+    }
+
+    match VersionInfo::collect(database.as_ref()).await {
+        Ok(info) => info!(
+            logger, "Starting shaft {}", info.version;
+            "git_commit" => info.git_commit,
+            "database_backend" => info.database_backend,
+            "schema_version" => info.schema_version,
+        ),
+        Err(e) => warn!(logger, "Failed to get database schema version: {}", e),
+    }
 
     // Sanitize the webroot to not end in a trailing slash.
     let web_root = settings.web_root.trim_end_matches('/').to_string();
@@ -94,42 +409,644 @@ fn main() {
         web_root,
         required_org: settings.github.required_org.clone(),
         resource_dir: settings.resource_dir.clone(),
+        admins: settings.admins.clone(),
+        session_lifetime_secs: settings.sessions.session_lifetime_secs,
+        remember_me_lifetime_secs: settings.sessions.remember_me_lifetime_secs,
+        slack: settings.slack.clone(),
+        invites: settings.invites.clone(),
+        currency: settings.currency.clone(),
+        reasons: settings.reasons.clone(),
+        balances: settings.balances.clone(),
+        ui: settings.ui.clone(),
+        api: settings.api.clone(),
+        cookies: settings.cookies.clone(),
+        webhooks: settings.webhooks.clone(),
+        outbox: settings.outbox.clone(),
     };
 
     // Holds the state for the shared state of the app. Gets cloned to each thread.
-    let app_state = AppState::new(app_config, hb, database);
+    let host_monitor = Arc::new(HostMonitor::new(
+        settings.http_client.circuit_breaker_failures,
+        Duration::from_secs(settings.http_client.circuit_breaker_cooldown_secs),
+    ));
+    let http_client = Arc::new(InstrumentedHttpClient::new(
+        build_http_client(&settings.http_client),
+        host_monitor.clone(),
+    ));
+    let mut app_state = AppState::new(app_config, hb, database, http_client);
+    app_state.host_monitor = host_monitor;
+    app_state.setup = setup;
 
-    // Set up HTTP server
-    let mut sys = actix_rt::System::new("shaft"); // Need to set up an actix system first.
+    let mut features = settings.features.clone();
+    features.public_read |= settings.public_read;
+    app_state.features = Arc::new(FeatureFlags::new(&features));
+    app_state.log_levels = log_levels;
+    app_state.enable_webhooks();
 
-    let logger_middleware = MiddlewareLogger::new(logger.clone());
+    if let Some(receipt_settings) = settings.receipts.as_ref() {
+        app_state.receipt_processor = Arc::new(HttpReceiptProcessor::new(
+            receipt_settings.endpoint.clone(),
+            app_state.http_client.clone(),
+        ));
+    }
 
-    let http_server = actix_web::HttpServer::new(move || {
-        // This gets called in each thread to set up the HTTP handlers
+    if let Some(mail_settings) = settings.mail.as_ref() {
+        app_state.mailer = Arc::new(HttpMailer::new(
+            mail_settings.endpoint.clone(),
+            mail_settings.from.clone(),
+            app_state.http_client.clone(),
+        ));
+    } else {
+        if settings.invites.is_some() {
+            warn!(
+                logger,
+                "Invites are enabled but mail isn't configured, so won't be delivered"
+            );
+        }
+        if settings.debt_digest.is_some() {
+            warn!(
+                logger,
+                "The debt digest is enabled but mail isn't configured, so won't be delivered"
+            );
+        }
+    }
+
+    if let Some(notification_settings) = settings.notifications.as_ref() {
+        app_state.notifier = Arc::new(HttpNotifier::new(
+            notification_settings.endpoint.clone(),
+            app_state.http_client.clone(),
+        ));
+    }
 
-        let logger_middleware = logger_middleware.clone();
+    if let Some(content_policy) = &settings.content_policy {
+        app_state.content_policy = Arc::new(DenylistPolicy::new(content_policy));
+    }
+
+    let share_state = settings.shared_state.backend != SharedStateBackend::Memory;
+    if settings.shared_state.backend == SharedStateBackend::Database {
+        app_state.set_shared_state(Arc::new(DatabaseSharedState::new(
+            app_state.database.clone(),
+        )));
+    }
 
+    let mut tenants = Vec::with_capacity(settings.tenants.len());
+    for tenant in &settings.tenants {
+        let currency = tenant.currency.as_ref().unwrap_or(&settings.currency);
+        let database =
+            open_database_file(&logger, &settings, &tenant.database_file, currency).await;
+        let mut state = app_state.for_tenant(
+            tenant_config(&app_state.config, tenant),
+            load_templates(&logger, &settings.resource_dir, currency, &settings.ui),
+            database,
+            tenant.features.as_ref().unwrap_or(&features),
+        );
+        if settings.shared_state.backend == SharedStateBackend::Database {
+            state.set_shared_state(Arc::new(DatabaseSharedState::new(state.database.clone())));
+        }
+        state.enable_webhooks();
+
+        info!(logger, "Hosting tenant"; "tenant" => &tenant.name);
+        tenants.push(Tenant {
+            name: tenant.name.clone(),
+            hosts: tenant.hosts.clone(),
+            path: tenant_path(tenant),
+            state,
+        });
+    }
+    use_redis(&logger, &settings, &mut app_state, &mut tenants).await;
+    app_state.tenants = Arc::new(TenantRegistry::new(tenants));
+
+    if share_state {
+        let interval = Duration::from_secs(settings.shared_state.poll_interval_secs);
+        spawn_shared_state_poller(app_state.clone(), interval, logger.clone());
+        for tenant in app_state.tenants.iter() {
+            spawn_shared_state_poller(tenant.state.clone(), interval, logger.clone());
+        }
+    }
+
+    spawn_outbox_dispatcher(app_state.clone(), logger.clone());
+    for tenant in app_state.tenants.iter() {
+        spawn_outbox_dispatcher(tenant.state.clone(), logger.clone());
+    }
+
+    if let Some(debt_digest) = settings.debt_digest.clone() {
+        spawn_debt_digest(app_state.clone(), debt_digest, logger.clone());
+    }
+
+    let error_reporter: Arc<dyn ErrorReporter> = match &settings.error_reporting {
+        Some(reporting) => match SentryReporter::new(
+            &reporting.sentry_dsn,
+            reporting.environment.clone(),
+            app_state.http_client.clone(),
+        ) {
+            Ok(reporter) => Arc::new(reporter),
+            Err(e) => {
+                crit!(logger, "Invalid error reporting settings: {}", e);
+                exit(1);
+            }
+        },
+        None => Arc::new(NoopErrorReporter),
+    };
+
+    let ip_rules = IpRules::parse(&settings.ip_filter.allow, &settings.ip_filter.deny);
+    let admin_ip_rules = IpRules::parse(
+        &settings.ip_filter.admin_allow,
+        &settings.ip_filter.admin_deny,
+    );
+    let ip_filter = match (ip_rules, admin_ip_rules) {
+        (Ok(rules), Ok(admin_rules)) => IpFilter::new(rules, admin_rules),
+        (Err(e), _) | (_, Err(e)) => {
+            crit!(logger, "Invalid IP filter settings: {}", e);
+            exit(1);
+        }
+    };
+
+    // Set up HTTP server
+    let logger_middleware = MiddlewareLogger::new(logger.clone())
+        .with_metrics(app_state.metrics.clone())
+        .with_quiet_paths(settings.http_server.quiet_log_paths.clone());
+    // Each tenant, and the instance itself, authenticates against its own
+    // database.
+    let skip_auth_paths = settings.http_server.skip_auth_paths.clone();
+    let refresh_after_secs = settings.sessions.refresh_after_secs;
+    let session_lifetime_secs = settings.sessions.session_lifetime_secs;
+    let cookies = settings.cookies.clone();
+    let authenticate_user = Arc::new(move |state: &AppState, prefix: &str| {
+        let skipped_paths = skip_auth_paths
+            .iter()
+            .map(|path| format!("{}{}", prefix, path))
+            .collect();
+        let mut authenticate_user =
+            AuthenticateUser::new(state.database.clone(), state.auth_cache.clone())
+                .with_rate_limiter(state.rate_limiter.clone())
+                .with_skipped_paths(skipped_paths);
+        if let Some(refresh_after_secs) = refresh_after_secs {
+            authenticate_user = authenticate_user.with_session_refresh(SessionRefresh {
+                refresh_after: Duration::from_secs(refresh_after_secs),
+                session_lifetime: Duration::from_secs(session_lifetime_secs),
+                cookies: cookies.clone(),
+                web_root: state.config.web_root.clone(),
+            });
+        }
+        authenticate_user
+    });
+    let report_errors = ReportErrors::new(error_reporter);
+    let deadline = RequestDeadline::new(Duration::from_secs(
+        settings.http_server.request_timeout_secs,
+    ));
+
+    let mut http_server = actix_web::HttpServer::new(move || {
+        // This gets called in each thread to set up the HTTP handlers
+
+        // Middleware wrapped last runs first, so the IP filter runs before
+        // authentication, which wraps each tenant's routes, and the deadline
+        // covers authentication. Errors are reported with the request's logger.
         actix_web::App::new()
-            .data(app_state.clone())
-            .app_data(app_state.clone())
-            .wrap(AuthenticateUser::new(app_state.database.clone()))
-            .wrap_fn(move |req, srv| logger_middleware.wrap(req, srv))
-            .configure(|config| register_servlets(config, &app_state))
-    })
-    .bind(&settings.bind)
-    .unwrap();
-
-    // If we need to daemonize do so *just* before starting the event loop
-    if let Some(daemonize_settings) = settings.daemonize {
-        Daemonize::new()
-            .pid_file(daemonize_settings.pid_file)
-            .start()
-            .expect("be able to daemonize");
+            .app_data(web::Data::new(app_state.clone()))
+            .wrap(deadline.clone())
+            .wrap(ip_filter.clone())
+            .wrap(report_errors.clone())
+            .wrap(logger_middleware.clone())
+            .configure(|config| register_tenant_servlets(config, &app_state, &*authenticate_user))
+    });
+
+    let server_settings = &settings.http_server;
+    if let Some(workers) = server_settings.workers {
+        http_server = http_server.workers(workers);
+    }
+    if let Some(secs) = server_settings.client_timeout_secs {
+        http_server = http_server.client_request_timeout(Duration::from_secs(secs));
+    }
+    if let Some(secs) = server_settings.keep_alive_secs {
+        let keep_alive = if secs == 0 {
+            KeepAlive::Disabled
+        } else {
+            KeepAlive::Timeout(Duration::from_secs(secs))
+        };
+        http_server = http_server.keep_alive(keep_alive);
+    }
+    if let Some(max_connections) = server_settings.max_connections {
+        http_server = http_server.max_connections(max_connections);
+    }
+    if let Some(secs) = server_settings.shutdown_timeout_secs {
+        http_server = http_server.shutdown_timeout(secs);
     }
 
-    // Start the event loop.
+    let http_server = http_server.listen(listener)?;
+
     info!(logger, "Started server on http://{}", settings.bind);
-    let _ = sys.block_on(async move { http_server.run().await });
+    http_server.run().await
+}
+
+/// Connects to and migrates the database using the sqlx backend.
+#[cfg(feature = "sqlx")]
+async fn connect_sqlx(
+    logger: &Logger,
+    settings: &Settings,
+    database_file: &str,
+    currency: &CurrencySettings,
+) -> Arc<dyn Database> {
+    let res = async {
+        let database = shaft::db::SqlxDatabase::connect(
+            database_file.to_string(),
+            &settings.database_pool,
+            &settings.sqlite,
+        )
+        .await?
+        .with_currency(currency.currency());
+        database.migrate().await?;
+        Ok::<_, shaft::db::DatabaseError>(database)
+    }
+    .await;
+
+    match res {
+        Ok(database) => {
+            log_missing_indexes(logger, database.missing_indexes().await);
+            Arc::new(database)
+        }
+        Err(e) => {
+            crit!(logger, "Failed to set up database: {}", e);
+            exit(1);
+        }
+    }
+}
+
+/// The sqlx backend isn't available without the `sqlx` feature.
+#[cfg(not(feature = "sqlx"))]
+async fn connect_sqlx(
+    logger: &Logger,
+    _settings: &Settings,
+    _database_file: &str,
+    _currency: &CurrencySettings,
+) -> Arc<dyn Database> {
+    crit!(
+        logger,
+        "The sqlx database backend requires building with the `sqlx` feature"
+    );
+    exit(1);
+}
+
+/// Keeps the auth cache and rate limits of the instance and its tenants in
+/// Redis, and publishes their events to it, if it's configured. Each tenant
+/// has its own keys.
+#[cfg(feature = "redis")]
+async fn use_redis(
+    logger: &Logger,
+    settings: &Settings,
+    app_state: &mut AppState,
+    tenants: &mut [Tenant],
+) {
+    use shaft::redis_store::{
+        RedisConnection, RedisEventPublisher, RedisRateLimiter, RedisSharedState, RedisTokenCache,
+    };
+    use shaft::rest::AUTH_CACHE_TTL;
+
+    let redis_settings = match &settings.redis {
+        Some(redis_settings) => redis_settings,
+        None if settings.shared_state.backend == SharedStateBackend::Redis => {
+            crit!(
+                logger,
+                "The redis shared state backend requires a [redis] section"
+            );
+            exit(1);
+        }
+        None => return,
+    };
+
+    let conn = match RedisConnection::connect(redis_settings).await {
+        Ok(conn) => conn,
+        Err(e) => {
+            crit!(logger, "Failed to connect to Redis: {}", e);
+            exit(1);
+        }
+    };
+
+    let share_state = settings.shared_state.backend == SharedStateBackend::Redis;
+    let configure = |state: &mut AppState, conn: RedisConnection| {
+        state.set_auth_cache(Arc::new(RedisTokenCache::new(conn.clone(), AUTH_CACHE_TTL)));
+        state.rate_limiter = Arc::new(RedisRateLimiter::new(conn.clone()));
+        state.events.add_listener(Arc::new(RedisEventPublisher::new(
+            conn.clone(),
+            logger.clone(),
+        )));
+        if share_state {
+            state.set_shared_state(Arc::new(RedisSharedState::new(conn, logger.clone())));
+        }
+    };
+
+    configure(app_state, conn.clone());
+    for tenant in tenants {
+        configure(
+            &mut tenant.state,
+            conn.with_namespace(&format!("t:{}", tenant.name)),
+        );
+    }
+
+    info!(logger, "Using Redis"; "prefix" => &redis_settings.prefix);
+}
+
+/// Redis isn't available without the `redis` feature.
+#[cfg(not(feature = "redis"))]
+async fn use_redis(
+    logger: &Logger,
+    settings: &Settings,
+    _app_state: &mut AppState,
+    _tenants: &mut [Tenant],
+) {
+    if settings.redis.is_some() || settings.shared_state.backend == SharedStateBackend::Redis {
+        crit!(logger, "Redis requires building with the `redis` feature");
+        exit(1);
+    }
+}
+
+/// Opens and migrates the configured database, exiting on failure.
+async fn open_database(logger: &Logger, settings: &Settings) -> Arc<dyn Database> {
+    open_database_file(
+        logger,
+        settings,
+        &settings.database_file,
+        &settings.currency,
+    )
+    .await
+}
+
+/// Opens and migrates a database file with the configured backend, e.g. a
+/// tenant's, exiting on failure.
+async fn open_database_file(
+    logger: &Logger,
+    settings: &Settings,
+    database_file: &str,
+    currency: &CurrencySettings,
+) -> Arc<dyn Database> {
+    match settings.database_backend {
+        DatabaseBackend::Rusqlite => {
+            let database = SqliteDatabase::with_settings(
+                database_file,
+                &settings.database_pool,
+                &settings.sqlite,
+            )
+            .with_currency(currency.currency());
+            if let Err(e) = database.migrate() {
+                crit!(logger, "Failed to migrate database: {}", e);
+                exit(1);
+            }
+            log_missing_indexes(logger, database.missing_indexes());
+            Arc::new(database)
+        }
+        DatabaseBackend::Sqlx => connect_sqlx(logger, settings, database_file, currency).await,
+    }
+}
+
+/// Runs an `admin` maintenance command, exiting on failure.
+async fn admin(logger: &Logger, settings: &Settings, matches: &ArgMatches<'_>) {
+    let database = open_database(logger, settings).await;
+
+    match matches.subcommand() {
+        ("prune", Some(prune_matches)) => {
+            let older_than = match prune_matches.value_of("older-than").map(parse_age) {
+                Some(Ok(older_than)) => Some(older_than),
+                Some(Err(e)) => {
+                    crit!(logger, "{}", e);
+                    exit(1);
+                }
+                None => None,
+            };
+
+            if prune_matches.is_present("tokens") {
+                match maintenance::prune_tokens(database.as_ref(), older_than, Outbox::none()).await
+                {
+                    Ok(count) => info!(logger, "Pruned tokens"; "count" => count),
+                    Err(e) => {
+                        crit!(logger, "Failed to prune tokens: {}", e);
+                        exit(1);
+                    }
+                }
+            }
+
+            if prune_matches.is_present("changes") {
+                match maintenance::compact_changes(database.as_ref()).await {
+                    Ok(count) => info!(logger, "Compacted change log"; "count" => count),
+                    Err(e) => {
+                        crit!(logger, "Failed to compact change log: {}", e);
+                        exit(1);
+                    }
+                }
+            }
+        }
+        ("vacuum", _) => match maintenance::vacuum(database.as_ref()).await {
+            Ok(()) => info!(logger, "Vacuumed database"),
+            Err(e) => {
+                crit!(logger, "Failed to vacuum database: {}", e);
+                exit(1);
+            }
+        },
+        ("check", Some(check_matches)) => {
+            let repair = check_matches.is_present("repair");
+
+            let problems = match maintenance::check_integrity(database.as_ref(), repair).await {
+                Ok(problems) => problems,
+                Err(e) => {
+                    crit!(logger, "Failed to check database: {}", e);
+                    exit(1);
+                }
+            };
+
+            for problem in &problems {
+                warn!(
+                    logger, "Integrity problem: {}", problem.kind.description();
+                    "user" => problem.user_id.as_str(),
+                    "count" => problem.count,
+                    "repaired" => repair,
+                );
+            }
+
+            if problems.is_empty() {
+                info!(logger, "No integrity problems found");
+            } else if repair {
+                info!(logger, "Repaired integrity problems"; "count" => problems.len());
+            } else {
+                crit!(
+                    logger,
+                    "Found integrity problems, run with --repair to fix them";
+                    "count" => problems.len(),
+                );
+                exit(1);
+            }
+        }
+        ("seed", Some(seed_matches)) => {
+            let parse = |name: &str| -> u64 {
+                match seed_matches.value_of(name).unwrap_or_default().parse() {
+                    Ok(value) => value,
+                    Err(e) => {
+                        crit!(logger, "Invalid --{}: {}", name, e);
+                        exit(1);
+                    }
+                }
+            };
+            let options = SeedOptions {
+                users: parse("users") as usize,
+                transactions: parse("transactions") as usize,
+                seed: parse("seed"),
+            };
+
+            match seed(database.as_ref(), settings.currency.currency(), options).await {
+                Ok(summary) => info!(
+                    logger, "Seeded database";
+                    "users" => summary.users,
+                    "transactions" => summary.transactions,
+                ),
+                Err(e) => {
+                    crit!(logger, "Failed to seed database: {}", e);
+                    exit(1);
+                }
+            }
+        }
+        _ => unreachable!("clap requires a subcommand"),
+    }
+}
+
+/// Runs an `admin config` command, exiting on failure.
+fn config_bundle(
+    logger: &Logger,
+    settings: &Settings,
+    file_config: config::Config,
+    matches: &ArgMatches<'_>,
+) {
+    let file_settings: toml::value::Table = match file_config.try_into() {
+        Ok(file_settings) => file_settings,
+        Err(e) => {
+            crit!(logger, "Failed to read config files: {}", e);
+            exit(1);
+        }
+    };
+
+    let write_output = |output: Option<&str>, contents: String| match output {
+        Some(path) => {
+            if let Err(e) = std::fs::write(path, contents) {
+                crit!(logger, "Failed to write {}: {}", path, e);
+                exit(1);
+            }
+        }
+        None => print!("{}", contents),
+    };
+
+    match matches.subcommand() {
+        ("export", Some(export_matches)) => {
+            let output = export_matches.value_of("output");
+            let format = output.map_or(BundleFormat::Toml, |path| {
+                BundleFormat::from_path(Path::new(path))
+            });
+
+            let mut features = settings.features.clone();
+            features.public_read |= settings.public_read;
+            let bundle = ConfigBundle::new(file_settings, &FeatureFlags::new(&features));
+
+            match bundle.write(format) {
+                Ok(contents) => write_output(output, contents),
+                Err(e) => {
+                    crit!(logger, "{}", e);
+                    exit(1);
+                }
+            }
+
+            info!(
+                logger, "Exported config bundle";
+                "redacted" => bundle.redacted.join(", "),
+            );
+        }
+        ("import", Some(import_matches)) => {
+            let path = import_matches
+                .value_of("bundle")
+                .expect("bundle is required");
+            let imported = std::fs::read_to_string(path)
+                .map_err(|e| e.to_string())
+                .and_then(|text| {
+                    ConfigBundle::parse(&text, BundleFormat::from_path(Path::new(path)))
+                        .and_then(|bundle| bundle.into_settings(&file_settings))
+                        .map_err(|e| e.to_string())
+                });
+            let imported = match imported {
+                Ok(imported) => imported,
+                Err(e) => {
+                    crit!(logger, "Failed to import {}: {}", path, e);
+                    exit(1);
+                }
+            };
+
+            match toml::to_string_pretty(&imported.settings) {
+                Ok(contents) => write_output(import_matches.value_of("output"), contents),
+                Err(e) => {
+                    crit!(logger, "Failed to write settings: {}", e);
+                    exit(1);
+                }
+            }
+
+            for secret in &imported.missing_secrets {
+                warn!(
+                    logger, "Secret isn't in this host's config, fill it in before starting";
+                    "setting" => secret,
+                );
+            }
+        }
+        _ => unreachable!("clap requires a subcommand"),
+    }
+}
+
+/// Runs the `anonymise` command, exiting on failure.
+fn anonymise(logger: &Logger, database_file: &str, output: &str) {
+    match anonymise_database(Path::new(database_file), Path::new(output)) {
+        Ok(summary) => info!(
+            logger, "Wrote anonymised database";
+            "output" => output,
+            "users" => summary.users,
+            "transactions" => summary.transactions,
+        ),
+        Err(e) => {
+            crit!(logger, "Failed to anonymise database: {}", e);
+            exit(1);
+        }
+    }
+}
+
+/// Logs the result of checking the database for missing indexes.
+fn log_missing_indexes(logger: &Logger, res: Result<Vec<&'static str>, DatabaseError>) {
+    match res {
+        Ok(missing) => {
+            debug!(logger, "Checked database indexes"; "missing" => missing.len());
+            for index in missing {
+                warn!(logger, "Database is missing an expected index"; "index" => index);
+            }
+        }
+        Err(e) => warn!(logger, "Failed to check database indexes: {}", e),
+    }
+}
+
+/// Loads and builds the templates, exiting on failure.
+fn load_templates(
+    logger: &Logger,
+    resource_dir: &str,
+    currency: &CurrencySettings,
+    ui: &UiSettings,
+) -> handlebars::Handlebars<'static> {
+    let mut hb = handlebars::Handlebars::new();
+    load_template!(logger, hb, resource_dir, "admin");
+    load_template!(logger, hb, resource_dir, "index");
+    load_template!(logger, hb, resource_dir, "login");
+    load_template!(logger, hb, resource_dir, "login_error");
+    load_template!(logger, hb, resource_dir, "logout");
+    load_template!(logger, hb, resource_dir, "setup");
+    load_template!(logger, hb, resource_dir, "slack_link");
+    load_template!(logger, hb, resource_dir, "transactions");
+    load_template!(logger, hb, resource_dir, "base");
+    hb.register_helper(
+        "pence-as-pounds",
+        Box::new(MoneyHelper::new(currency.clone())),
+    );
+    if let Err(e) = register_helpers(&mut hb, ui) {
+        crit!(logger, "Invalid UI settings: {}", e);
+        exit(1);
+    }
+    hb
 }
 
 /// Attempts to load the template into handlebars instance.
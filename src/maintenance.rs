@@ -0,0 +1,82 @@
+//! Housekeeping of the database, shared by the `shaft admin` commands and the
+//! admin API.
+//!
+//! `shaft admin prune --tokens` deletes expired tokens, and with
+//! `--older-than 90d` also login sessions that haven't been issued or
+//! extended in that long. `--changes` compacts the sync change log. `shaft admin vacuum` reclaims unused space and
+//! updates the query planner's statistics. `shaft admin check` looks for rows
+//! referencing users that don't exist, and with `--repair` fixes them.
+
+use snafu::Snafu;
+
+use crate::db::{Database, DatabaseError, IntegrityProblem, Outbox};
+
+/// Error parsing an age like `90d`.
+#[derive(Debug, Clone, PartialEq, Eq, Snafu)]
+pub enum AgeError {
+    /// The age isn't a number followed by a unit.
+    #[snafu(display(
+        "Invalid age {:?}: expected a number followed by s, m, h, d or w, e.g. 90d",
+        age
+    ))]
+    Invalid { age: String },
+}
+
+/// Parse an age given as a number and a unit, e.g. `90d` or `12h`.
+pub fn parse_age(age: &str) -> Result<chrono::Duration, AgeError> {
+    let invalid = || AgeError::Invalid {
+        age: age.to_string(),
+    };
+
+    let trimmed = age.trim();
+    let split = trimmed.len() - trimmed.chars().last().ok_or_else(invalid)?.len_utf8();
+    let (number, unit) = trimmed.split_at(split);
+    let number: u32 = number.parse().map_err(|_| invalid())?;
+
+    let unit_secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+
+    Ok(chrono::Duration::seconds(i64::from(number) * unit_secs))
+}
+
+/// Delete expired tokens, and if `older_than` is given login sessions that
+/// haven't been issued or extended within it. Returns how many were deleted.
+pub async fn prune_tokens(
+    database: &dyn Database,
+    older_than: Option<chrono::Duration>,
+    outbox: Outbox<u64>,
+) -> Result<u64, DatabaseError> {
+    // Nothing can have been issued before the earliest representable time.
+    let issued_before =
+        older_than.and_then(|older_than| chrono::Utc::now().checked_sub_signed(older_than));
+    database.prune_tokens(issued_before, outbox).await
+}
+
+/// Compact the change log used by `/api/sync`, returning how many changes
+/// were deleted. Clients whose cursor predates a deleted row are told to
+/// resync.
+pub async fn compact_changes(database: &dyn Database) -> Result<u64, DatabaseError> {
+    database.compact_changes().await
+}
+
+/// Reclaim unused space in the database and update the query planner's
+/// statistics.
+pub async fn vacuum(database: &dyn Database) -> Result<(), DatabaseError> {
+    database.vacuum().await
+}
+
+/// Find rows that reference users that don't exist, repairing them if
+/// `repair` is set. Transactions with unknown users get a deactivated user
+/// added so balances still add up, other rows are deleted.
+pub async fn check_integrity(
+    database: &dyn Database,
+    repair: bool,
+) -> Result<Vec<IntegrityProblem>, DatabaseError> {
+    database.check_integrity(repair).await
+}
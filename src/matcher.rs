@@ -0,0 +1,174 @@
+//! Finding the user someone means from what they typed, e.g. `bob` or
+//! `@Bob` in a quick-add line.
+//!
+//! Each user is scored against the query: an exact user ID scores highest,
+//! then an exact display name, then user IDs or display name words starting
+//! with the query, then display names containing it. Failing those, user IDs
+//! and display name words within a small edit distance of the query match as
+//! likely typos, scored by how similar they are. Matching ignores case and a
+//! leading `@`.
+//!
+//! Ledgers are small, so users are scored as needed rather than kept in an
+//! index, which means matches always reflect the current users.
+
+use serde::Serialize;
+
+use crate::db::User;
+
+/// The score of an exact user ID match.
+const EXACT_ID_SCORE: f64 = 1.0;
+
+/// The score of an exact display name match.
+const EXACT_NAME_SCORE: f64 = 0.9;
+
+/// The score of a user ID or display name word starting with the query.
+const PREFIX_SCORE: f64 = 0.8;
+
+/// The score of a display name containing the query.
+const CONTAINS_SCORE: f64 = 0.7;
+
+/// The highest score of a likely typo, which is scaled by how similar it is.
+/// Below [CONTAINS_SCORE] so typos never beat a real match.
+const TYPO_SCORE: f64 = 0.6;
+
+/// How similar a word must be to the query to count as a typo, from 0 to 1.
+const MIN_TYPO_SIMILARITY: f64 = 0.6;
+
+/// A user and how well they match a query, from 0 to 1.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScoredUser<'a> {
+    #[serde(flatten)]
+    pub user: &'a User,
+    pub score: f64,
+}
+
+/// The users matching a query.
+#[derive(Debug, Clone)]
+pub enum UserMatch<'a> {
+    /// Exactly one user matched.
+    Unique(&'a User),
+    /// Several users matched equally well, ordered by display name, or the
+    /// only matches were likely typos, which should be confirmed.
+    Ambiguous(Vec<&'a User>),
+    /// Nobody matched.
+    NoMatch,
+}
+
+/// Find the users the query refers to.
+pub fn match_user<'a, I>(query: &str, users: I) -> UserMatch<'a>
+where
+    I: IntoIterator<Item = &'a User>,
+{
+    let ranked = rank_users(query, users);
+    let top_score = match ranked.first() {
+        Some(top) => top.score,
+        None => return UserMatch::NoMatch,
+    };
+
+    if top_score <= TYPO_SCORE {
+        return UserMatch::Ambiguous(ranked.into_iter().map(|scored| scored.user).collect());
+    }
+
+    let mut best: Vec<&User> = ranked
+        .into_iter()
+        .take_while(|scored| scored.score >= top_score)
+        .map(|scored| scored.user)
+        .collect();
+
+    if best.len() == 1 {
+        UserMatch::Unique(best.remove(0))
+    } else {
+        UserMatch::Ambiguous(best)
+    }
+}
+
+/// Score every user against the query, leaving out those that don't match.
+/// The best matches come first, ties ordered by display name.
+pub fn rank_users<'a, I>(query: &str, users: I) -> Vec<ScoredUser<'a>>
+where
+    I: IntoIterator<Item = &'a User>,
+{
+    let query = query.trim();
+    let query = query.strip_prefix('@').unwrap_or(query).to_lowercase();
+    if query.is_empty() {
+        return Vec::new();
+    }
+
+    let mut ranked: Vec<ScoredUser> = users
+        .into_iter()
+        .filter_map(|user| score_user(&query, user).map(|score| ScoredUser { user, score }))
+        .collect();
+
+    ranked.sort_by(|a, b| {
+        b.score
+            .partial_cmp(&a.score)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then_with(|| a.user.display_name.cmp(&b.user.display_name))
+    });
+
+    ranked
+}
+
+/// How well the user matches the lowercased query, or None if they don't.
+fn score_user(query: &str, user: &User) -> Option<f64> {
+    let user_id = user.user_id.to_lowercase();
+    let display_name = user.display_name.to_lowercase();
+
+    if user_id == query {
+        return Some(EXACT_ID_SCORE);
+    }
+    if display_name == query {
+        return Some(EXACT_NAME_SCORE);
+    }
+    if user_id.starts_with(query)
+        || display_name
+            .split_whitespace()
+            .any(|word| word.starts_with(query))
+    {
+        return Some(PREFIX_SCORE);
+    }
+    if display_name.contains(query) {
+        return Some(CONTAINS_SCORE);
+    }
+
+    std::iter::once(user_id.as_str())
+        .chain(display_name.split_whitespace())
+        .map(|word| similarity(query, word))
+        .filter(|similarity| *similarity >= MIN_TYPO_SIMILARITY)
+        .fold(None, |best: Option<f64>, similarity| {
+            Some(best.map_or(similarity, |best| best.max(similarity)))
+        })
+        .map(|similarity| TYPO_SCORE * similarity)
+}
+
+/// How similar two strings are, from 0 for nothing in common to 1 for equal,
+/// based on their edit distance.
+fn similarity(a: &str, b: &str) -> f64 {
+    let longest = a.chars().count().max(b.chars().count());
+    if longest == 0 {
+        return 1.0;
+    }
+
+    1.0 - levenshtein(a, b) as f64 / longest as f64
+}
+
+/// The number of single character insertions, deletions or substitutions
+/// needed to turn one string into the other.
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+
+    // The distances from the prefix of `a` so far to each prefix of `b`.
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(a_char != *b_char);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+
+    row[b.len()]
+}
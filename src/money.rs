@@ -0,0 +1,309 @@
+//! Amounts of money, and formatting them for display.
+//!
+//! Amounts are stored as integers in the currency's minor unit (e.g. pence),
+//! wrapped in [Money] so that they can't be confused with other integers or
+//! mixed between currencies.
+
+use handlebars::{
+    Context, Handlebars, Helper, HelperDef, HelperResult, Output, RenderContext, RenderError,
+};
+use serde::de::{self, Deserialize, Deserializer};
+use serde::{Serialize, Serializer};
+use snafu::Snafu;
+
+use std::fmt;
+use std::ops::{Add, AddAssign, Neg, Sub, SubAssign};
+use std::str::FromStr;
+
+use crate::settings::CurrencySettings;
+
+/// Errors from parsing a [CurrencyCode].
+#[derive(Debug, Clone, PartialEq, Eq, Snafu)]
+pub enum CurrencyError {
+    #[snafu(display("Invalid currency code '{}', expected e.g. GBP", code))]
+    InvalidCurrencyCode { code: String },
+}
+
+/// An ISO 4217 currency code, e.g. `GBP`.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CurrencyCode([u8; 3]);
+
+impl CurrencyCode {
+    pub const GBP: CurrencyCode = CurrencyCode(*b"GBP");
+
+    pub fn as_str(&self) -> &str {
+        // We only ever store ASCII letters.
+        std::str::from_utf8(&self.0).unwrap_or("???")
+    }
+}
+
+impl FromStr for CurrencyCode {
+    type Err = CurrencyError;
+
+    fn from_str(code: &str) -> Result<CurrencyCode, CurrencyError> {
+        match code.as_bytes() {
+            &[a, b, c] if code.bytes().all(|byte| byte.is_ascii_uppercase()) => {
+                Ok(CurrencyCode([a, b, c]))
+            }
+            _ => Err(CurrencyError::InvalidCurrencyCode {
+                code: code.to_string(),
+            }),
+        }
+    }
+}
+
+impl fmt::Display for CurrencyCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for CurrencyCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for CurrencyCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for CurrencyCode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<CurrencyCode, D::Error> {
+        let code = String::deserialize(deserializer)?;
+        code.parse().map_err(de::Error::custom)
+    }
+}
+
+/// A currency, and how many decimal places its amounts have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Currency {
+    pub code: CurrencyCode,
+    /// The number of minor units in a major unit as a power of ten, e.g. 2
+    /// for pence.
+    pub minor_units: u8,
+}
+
+impl Currency {
+    pub const GBP: Currency = Currency {
+        code: CurrencyCode::GBP,
+        minor_units: 2,
+    };
+}
+
+impl Default for Currency {
+    fn default() -> Currency {
+        Currency::GBP
+    }
+}
+
+/// An amount of money in the minor unit of its currency, e.g. pence.
+///
+/// Arithmetic between amounts in different currencies panics, like overflow.
+/// Serializes as the integer number of minor units.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Money {
+    amount: i64,
+    currency: Currency,
+}
+
+impl Money {
+    pub fn new(amount: i64, currency: Currency) -> Money {
+        Money { amount, currency }
+    }
+
+    pub fn zero(currency: Currency) -> Money {
+        Money::new(0, currency)
+    }
+
+    /// The amount in minor units, e.g. pence.
+    pub fn minor_units(self) -> i64 {
+        self.amount
+    }
+
+    pub fn currency(self) -> Currency {
+        self.currency
+    }
+
+    pub fn is_zero(self) -> bool {
+        self.amount == 0
+    }
+
+    pub fn is_positive(self) -> bool {
+        self.amount > 0
+    }
+
+    pub fn is_negative(self) -> bool {
+        self.amount < 0
+    }
+
+    pub fn abs(self) -> Money {
+        Money::new(self.amount.abs(), self.currency)
+    }
+
+    /// The amount in major units, e.g. pounds, for APIs that expect a
+    /// number.
+    pub fn to_major_units(self) -> f64 {
+        self.amount as f64 / 10f64.powi(i32::from(self.currency.minor_units))
+    }
+
+    fn check_currency(self, other: Money) {
+        assert_eq!(
+            self.currency.code, other.currency.code,
+            "Can't combine amounts in different currencies"
+        );
+    }
+}
+
+impl Add for Money {
+    type Output = Money;
+
+    fn add(self, other: Money) -> Money {
+        self.check_currency(other);
+        Money::new(self.amount + other.amount, self.currency)
+    }
+}
+
+impl AddAssign for Money {
+    fn add_assign(&mut self, other: Money) {
+        *self = *self + other;
+    }
+}
+
+impl Sub for Money {
+    type Output = Money;
+
+    fn sub(self, other: Money) -> Money {
+        self.check_currency(other);
+        Money::new(self.amount - other.amount, self.currency)
+    }
+}
+
+impl SubAssign for Money {
+    fn sub_assign(&mut self, other: Money) {
+        *self = *self - other;
+    }
+}
+
+impl Neg for Money {
+    type Output = Money;
+
+    fn neg(self) -> Money {
+        Money::new(-self.amount, self.currency)
+    }
+}
+
+impl Serialize for Money {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_i64(self.amount)
+    }
+}
+
+/// Serialize an amount as a decimal string, e.g. `"-12.50"`, for use with
+/// `#[serde(serialize_with = "...")]`.
+pub fn serialize_decimal<S: Serializer>(money: &Money, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format_decimal(*money))
+}
+
+/// Format an amount for people to read, e.g. `-£1,200.50`.
+pub fn format(money: Money, currency: &CurrencySettings) -> String {
+    let minor_units = u32::from(money.currency.minor_units);
+    let sign = if money.is_negative() { "-" } else { "" };
+    let (major, minor) = split(money.amount.unsigned_abs(), minor_units);
+
+    let mut out = format!(
+        "{}{}{}",
+        sign,
+        currency.symbol,
+        group_thousands(major, &currency.thousands_separator)
+    );
+    if minor_units > 0 {
+        out.push_str(&currency.decimal_separator);
+        out.push_str(&format!("{:0width$}", minor, width = minor_units as usize));
+    }
+
+    out
+}
+
+/// Format an amount as a plain decimal for other programs to read, e.g.
+/// `-1200.50`.
+pub fn format_decimal(money: Money) -> String {
+    let minor_units = u32::from(money.currency.minor_units);
+    let sign = if money.is_negative() { "-" } else { "" };
+    let (major, minor) = split(money.amount.unsigned_abs(), minor_units);
+
+    if minor_units == 0 {
+        format!("{}{}", sign, major)
+    } else {
+        format!(
+            "{}{}.{:0width$}",
+            sign,
+            major,
+            minor,
+            width = minor_units as usize
+        )
+    }
+}
+
+/// Split an amount into major and minor units.
+fn split(amount: u64, minor_units: u32) -> (u64, u64) {
+    match 10u64.checked_pow(minor_units) {
+        Some(scale) => (amount / scale, amount % scale),
+        // More minor units than fit in an i64, so there are no major units.
+        None => (0, amount),
+    }
+}
+
+/// Insert the separator between each group of three digits.
+fn group_thousands(value: u64, separator: &str) -> String {
+    let digits = value.to_string();
+    if separator.is_empty() {
+        return digits;
+    }
+
+    let mut out = String::new();
+    for (idx, digit) in digits.chars().enumerate() {
+        if idx > 0 && (digits.len() - idx) % 3 == 0 {
+            out.push_str(separator);
+        }
+        out.push(digit);
+    }
+
+    out
+}
+
+/// Handlebars helper formatting an amount in minor units with [format], e.g.
+/// `{{pence-as-pounds balance}}`.
+pub struct MoneyHelper {
+    currency: CurrencySettings,
+}
+
+impl MoneyHelper {
+    pub fn new(currency: CurrencySettings) -> MoneyHelper {
+        MoneyHelper { currency }
+    }
+}
+
+impl HelperDef for MoneyHelper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let amount = h
+            .param(0)
+            .ok_or_else(|| RenderError::new("Missing param"))?
+            .value()
+            .as_i64()
+            .ok_or_else(|| RenderError::new("Param must be a number"))?;
+
+        let money = Money::new(amount, self.currency.currency());
+        out.write(&format(money, &self.currency))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,112 @@
+//! Notifying users of things that need their attention, e.g. being poked to
+//! settle up.
+//!
+//! Notifications are handed to the configured [Notifier]. By default they are
+//! dropped, as users have no address to send them to; deployments that can
+//! reach their users, e.g. via a chat bot, configure an endpoint to deliver
+//! them.
+
+use futures::future::{self, BoxFuture, FutureExt};
+use hyper::{Body, Request, StatusCode};
+use serde::{Deserialize, Serialize};
+use snafu::{ResultExt, Snafu};
+
+use std::sync::Arc;
+
+use crate::http_client::{GenericHttpClient, HttpError};
+
+/// A short plain text message for a single user.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Notification {
+    /// The user to notify.
+    pub user_id: String,
+    pub title: String,
+    pub text: String,
+}
+
+/// Something that can deliver notifications to users.
+pub trait Notifier: Send + Sync {
+    /// Deliver the notification, resolving once it has been accepted.
+    fn notify(&self, notification: Notification) -> BoxFuture<'static, Result<(), NotifyError>>;
+}
+
+/// Error delivering a notification.
+#[derive(Debug, Snafu)]
+pub enum NotifyError {
+    /// Failed to talk to the notification service.
+    #[snafu(display("Failed to send notification: {}", source))]
+    RequestError { source: HttpError },
+    /// Got non-2xx response.
+    #[snafu(display("Got non-200 response from notification service: {}", code))]
+    Status { code: StatusCode },
+    /// Failed to serialize the notification.
+    #[snafu(display("Failed to serialize notification: {}", source))]
+    SerializeError { source: serde_json::Error },
+}
+
+/// The default [Notifier], which drops every notification.
+#[derive(Debug, Clone, Default)]
+pub struct NoopNotifier;
+
+impl Notifier for NoopNotifier {
+    fn notify(&self, _notification: Notification) -> BoxFuture<'static, Result<(), NotifyError>> {
+        future::ok(()).boxed()
+    }
+}
+
+/// A [Notifier] that POSTs notifications to an HTTP endpoint, which is
+/// responsible for reaching the user.
+///
+/// The endpoint receives a JSON object with `user_id`, `title` and `text`
+/// fields.
+pub struct HttpNotifier {
+    endpoint: String,
+    http_client: Arc<dyn GenericHttpClient>,
+}
+
+impl HttpNotifier {
+    pub fn new(endpoint: String, http_client: Arc<dyn GenericHttpClient>) -> HttpNotifier {
+        HttpNotifier {
+            endpoint,
+            http_client,
+        }
+    }
+}
+
+impl Notifier for HttpNotifier {
+    fn notify(&self, notification: Notification) -> BoxFuture<'static, Result<(), NotifyError>> {
+        let body = match serde_json::to_vec(&notification) {
+            Ok(body) => body,
+            Err(source) => return future::err(NotifyError::SerializeError { source }).boxed(),
+        };
+
+        let req = Request::post(self.endpoint.as_str())
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body));
+
+        let req = match req {
+            Ok(req) => req,
+            Err(source) => {
+                return future::err(NotifyError::RequestError {
+                    source: HttpError::Http { source },
+                })
+                .boxed()
+            }
+        };
+
+        let resp_fut = self.http_client.request(req);
+
+        async move {
+            let resp = resp_fut.await.context(RequestError)?;
+
+            if !resp.status().is_success() {
+                return Err(NotifyError::Status {
+                    code: resp.status(),
+                });
+            }
+
+            Ok(())
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,71 @@
+//! Hooks for binaries embedding shaft to add their own behaviour without
+//! forking, e.g. notifying a household chat when someone is shafted.
+//!
+//! Plugins are registered with [AppState::add_plugin](crate::rest::AppState::add_plugin)
+//! before the HTTP server is built.
+
+use actix_web::web::ServiceConfig;
+use futures::future::{self, FutureExt, LocalBoxFuture};
+
+use std::error::Error as StdError;
+use std::sync::Arc;
+
+use crate::db::Transaction;
+use crate::events::{Event, EventListener};
+
+/// A downstream extension to shaft. All hooks default to doing nothing.
+///
+/// Hooks run as part of the request that triggered them, after the change has
+/// been committed. They can't fail the request, so should handle their own
+/// errors.
+pub trait ShaftPlugin: Send + Sync {
+    /// Name of the plugin, for logging.
+    fn name(&self) -> &'static str;
+
+    /// Called after a transaction is created. The transaction has its ID set.
+    fn on_transaction(&self, _transaction: &Transaction) -> LocalBoxFuture<'static, ()> {
+        future::ready(()).boxed_local()
+    }
+
+    /// Called after a user logs in.
+    fn on_login(&self, _user_id: &str) -> LocalBoxFuture<'static, ()> {
+        future::ready(()).boxed_local()
+    }
+
+    /// Register any extra routes the plugin serves. These take precedence over
+    /// the web pages, but not the API.
+    fn register_routes(&self, _config: &mut ServiceConfig) {}
+}
+
+/// Runs a plugin's hooks for the events they correspond to.
+pub(crate) struct PluginListener {
+    plugin: Arc<dyn ShaftPlugin>,
+}
+
+impl PluginListener {
+    pub(crate) fn new(plugin: Arc<dyn ShaftPlugin>) -> PluginListener {
+        PluginListener { plugin }
+    }
+}
+
+impl EventListener for PluginListener {
+    fn name(&self) -> &'static str {
+        self.plugin.name()
+    }
+
+    fn on_event(
+        &self,
+        event: &Event,
+    ) -> LocalBoxFuture<'static, Result<(), Box<dyn StdError + Send + Sync>>> {
+        let hook = match event {
+            Event::TransactionCreated { transaction }
+            | Event::AdjustmentCreated { transaction, .. } => {
+                self.plugin.on_transaction(transaction)
+            }
+            Event::UserLoggedIn { user_id } => self.plugin.on_login(user_id),
+            _ => return future::ok(()).boxed_local(),
+        };
+
+        hook.map(Ok).boxed_local()
+    }
+}
@@ -0,0 +1,61 @@
+//! Counting requests to limit how often something can be done.
+//!
+//! [InMemoryRateLimiter] counts per instance. With Redis configured, the
+//! counts are kept there instead so the limits hold across instances.
+
+use futures::future::{FutureExt, LocalBoxFuture};
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::shared_state::SharedStateError;
+
+/// Counts hits in fixed windows.
+pub trait RateLimiter: Send + Sync {
+    /// Count a hit for the key, allowing `limit` in each `window`. If the
+    /// limit has been exceeded, returns how long until the window ends.
+    fn hit(
+        &self,
+        key: String,
+        limit: u32,
+        window: Duration,
+    ) -> LocalBoxFuture<'static, Result<Option<Duration>, SharedStateError>>;
+}
+
+/// Counts hits in memory, so each instance has its own limits.
+#[derive(Default)]
+pub struct InMemoryRateLimiter {
+    /// When each key's window started, and the hits in it.
+    windows: Mutex<HashMap<String, (Instant, u32)>>,
+}
+
+impl InMemoryRateLimiter {
+    pub fn new() -> InMemoryRateLimiter {
+        InMemoryRateLimiter::default()
+    }
+}
+
+impl RateLimiter for InMemoryRateLimiter {
+    fn hit(
+        &self,
+        key: String,
+        limit: u32,
+        window: Duration,
+    ) -> LocalBoxFuture<'static, Result<Option<Duration>, SharedStateError>> {
+        let mut windows = self.windows.lock().expect("rate limiter lock poisoned");
+        let now = Instant::now();
+        windows.retain(|_, (started, _)| now.duration_since(*started) < window);
+
+        let (started, hits) = windows.entry(key).or_insert((now, 0));
+        *hits += 1;
+
+        let retry_after = if *hits > limit {
+            Some(window - now.duration_since(*started))
+        } else {
+            None
+        };
+
+        async move { Ok(retry_after) }.boxed_local()
+    }
+}
@@ -0,0 +1,76 @@
+//! Checking the reasons people give for transactions.
+//!
+//! Whether a reason is needed and how long it may be are configured with
+//! [ReasonSettings]. This is shared by everything that accepts reasons from
+//! people: the web form, the JSON and GraphQL APIs, inbound hooks and the
+//! Slack command, which then pass the reason through the configured
+//! [ContentPolicy] with [check_reason].
+
+use snafu::{ResultExt, Snafu};
+
+use crate::content_policy::{ContentPolicy, PolicyError};
+use crate::settings::ReasonSettings;
+
+/// Why a reason was rejected.
+#[derive(Debug, Clone, PartialEq, Eq, Snafu)]
+pub enum ReasonError {
+    #[snafu(display("No reason given"))]
+    Missing,
+
+    #[snafu(display("Reason must be at least {} characters", min))]
+    TooShort { min: usize },
+
+    #[snafu(display("Reason must be at most {} characters", max))]
+    TooLong { max: usize },
+
+    #[snafu(display("Reason {}", source))]
+    Disallowed { source: PolicyError },
+}
+
+/// Check a reason against the settings, returning it with surrounding
+/// whitespace removed.
+///
+/// Lengths are counted in characters. An empty reason is allowed if reasons
+/// aren't required, whatever the minimum length.
+pub fn validate_reason(reason: &str, settings: &ReasonSettings) -> Result<String, ReasonError> {
+    let reason = reason.trim();
+    let length = reason.chars().count();
+
+    if length == 0 {
+        return if settings.required {
+            Err(ReasonError::Missing)
+        } else {
+            Ok(String::new())
+        };
+    }
+
+    if length < settings.min_length {
+        return Err(ReasonError::TooShort {
+            min: settings.min_length,
+        });
+    }
+    if length > settings.max_length {
+        return Err(ReasonError::TooLong {
+            max: settings.max_length,
+        });
+    }
+
+    Ok(reason.to_string())
+}
+
+/// Validate a reason and apply the content policy to it, returning the reason
+/// as it should be stored.
+///
+/// Empty reasons aren't passed to the policy.
+pub fn check_reason(
+    reason: &str,
+    settings: &ReasonSettings,
+    policy: &dyn ContentPolicy,
+) -> Result<String, ReasonError> {
+    let reason = validate_reason(reason, settings)?;
+    if reason.is_empty() {
+        return Ok(reason);
+    }
+
+    policy.check(&reason).context(Disallowed)
+}
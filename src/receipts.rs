@@ -0,0 +1,125 @@
+//! Extracts details from uploaded receipts.
+//!
+//! When an attachment is uploaded it is handed to the configured
+//! [ReceiptProcessor], which may suggest an amount and merchant for the
+//! transaction (e.g. by running OCR over a photo of the receipt).
+
+use bytes::Buf as _;
+use bytes::Bytes;
+use futures::future::{self, BoxFuture, FutureExt};
+use hyper::{Body, Request, StatusCode};
+use snafu::{ResultExt, Snafu};
+
+use std::sync::Arc;
+
+use crate::db::ReceiptSuggestion;
+use crate::http_client::{GenericHttpClient, HttpError};
+
+/// Something that can suggest transaction details from an uploaded receipt.
+pub trait ReceiptProcessor: Send + Sync {
+    /// Process an uploaded file, returning a suggestion if any details could
+    /// be extracted.
+    fn process(
+        &self,
+        content_type: String,
+        data: Bytes,
+    ) -> BoxFuture<'static, Result<Option<ReceiptSuggestion>, ReceiptError>>;
+}
+
+/// Error processing a receipt.
+#[derive(Debug, Snafu)]
+pub enum ReceiptError {
+    /// Failed to talk to the receipt processing service.
+    #[snafu(display("Failed to send receipt for processing: {}", source))]
+    RequestError { source: HttpError },
+    /// Got non-2xx response.
+    #[snafu(display("Got non-200 response from receipt processor: {}", code))]
+    Status { code: StatusCode },
+    /// Failed to parse response as expected JSON object.
+    #[snafu(display("Failed to parse receipt processor response: {}", source))]
+    DeserializeError { source: serde_json::Error },
+}
+
+/// The default [ReceiptProcessor], which never suggests anything.
+#[derive(Debug, Clone, Default)]
+pub struct NoopReceiptProcessor;
+
+impl ReceiptProcessor for NoopReceiptProcessor {
+    fn process(
+        &self,
+        _content_type: String,
+        _data: Bytes,
+    ) -> BoxFuture<'static, Result<Option<ReceiptSuggestion>, ReceiptError>> {
+        future::ok(None).boxed()
+    }
+}
+
+/// A [ReceiptProcessor] that POSTs the file to an HTTP endpoint.
+///
+/// The endpoint receives the raw file with its content type, and should
+/// respond with a JSON object with optional `amount` (in pence) and
+/// `merchant` fields.
+pub struct HttpReceiptProcessor {
+    endpoint: String,
+    http_client: Arc<dyn GenericHttpClient>,
+}
+
+impl HttpReceiptProcessor {
+    pub fn new(endpoint: String, http_client: Arc<dyn GenericHttpClient>) -> HttpReceiptProcessor {
+        HttpReceiptProcessor {
+            endpoint,
+            http_client,
+        }
+    }
+}
+
+impl ReceiptProcessor for HttpReceiptProcessor {
+    fn process(
+        &self,
+        content_type: String,
+        data: Bytes,
+    ) -> BoxFuture<'static, Result<Option<ReceiptSuggestion>, ReceiptError>> {
+        let req = Request::post(self.endpoint.as_str())
+            .header(hyper::header::CONTENT_TYPE, content_type)
+            .header(hyper::header::ACCEPT, "application/json")
+            .body(Body::from(data));
+
+        let req = match req {
+            Ok(req) => req,
+            Err(source) => {
+                return future::err(ReceiptError::RequestError {
+                    source: HttpError::Http { source },
+                })
+                .boxed()
+            }
+        };
+
+        let resp_fut = self.http_client.request(req);
+
+        async move {
+            let resp = resp_fut.await.context(RequestError)?;
+
+            if !resp.status().is_success() {
+                return Err(ReceiptError::Status {
+                    code: resp.status(),
+                });
+            }
+
+            let body = hyper::body::aggregate(resp).await.map_err(|source| {
+                ReceiptError::RequestError {
+                    source: HttpError::Hyper { source },
+                }
+            })?;
+
+            let suggestion: ReceiptSuggestion =
+                serde_json::from_reader(body.reader()).context(DeserializeError)?;
+
+            if suggestion.amount.is_none() && suggestion.merchant.is_none() {
+                Ok(None)
+            } else {
+                Ok(Some(suggestion))
+            }
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,483 @@
+//! Keeps state shared between instances in Redis, for deployments that
+//! already run it. Requires the `redis` feature.
+//!
+//! With a `[redis]` block configured, Redis holds:
+//!
+//! - the auth cache, via [RedisTokenCache], so a token looked up by one
+//!   instance is cached for all of them.
+//! - rate limit counters, via [RedisRateLimiter], so the limits hold across
+//!   instances.
+//! - published [events](crate::events), via [RedisEventPublisher], so other
+//!   instances and services can consume them.
+//!
+//! If the shared state backend is `redis` it also holds the
+//! [shared state](crate::shared_state), via [RedisSharedState], with
+//! invalidations sent over pub/sub rather than polled from the database.
+//!
+//! All keys and channels start with the configured prefix, so several
+//! deployments can share a Redis server.
+
+use futures::future::{FutureExt, LocalBoxFuture};
+use futures::StreamExt;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use redis::aio::ConnectionManager;
+use redis::{Client, RedisError};
+use serde::{Deserialize, Serialize};
+use slog::Logger;
+use snafu::ResultExt;
+
+use std::error::Error as StdError;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::db::TokenUser;
+use crate::events::{Event, EventListener};
+use crate::rate_limit::RateLimiter;
+use crate::rest::TokenCache;
+use crate::settings::RedisSettings;
+use crate::shared_state::{InvalidPayload, Invalidation, SharedState, SharedStateError};
+
+/// How long to wait before resubscribing after losing the pub/sub
+/// connection.
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(5);
+
+fn backend_error(err: RedisError) -> SharedStateError {
+    SharedStateError::Backend {
+        source: Box::new(err),
+    }
+}
+
+/// Redis expiries are in milliseconds, and must be at least one.
+fn expiry_millis(ttl: Duration) -> u64 {
+    (ttl.as_millis() as u64).max(1)
+}
+
+/// A connection to Redis, shared by everything stored there.
+#[derive(Clone)]
+pub struct RedisConnection {
+    client: Client,
+    manager: ConnectionManager,
+    prefix: String,
+}
+
+impl RedisConnection {
+    /// Connect to the configured server.
+    pub async fn connect(settings: &RedisSettings) -> Result<RedisConnection, RedisError> {
+        let client = Client::open(settings.url.as_str())?;
+        let manager = ConnectionManager::new(client.clone()).await?;
+
+        Ok(RedisConnection {
+            client,
+            manager,
+            prefix: settings.prefix.clone(),
+        })
+    }
+
+    /// The same connection with keys under `{prefix}:{name}`, e.g. for a
+    /// tenant.
+    pub fn with_namespace(&self, name: &str) -> RedisConnection {
+        RedisConnection {
+            client: self.client.clone(),
+            manager: self.manager.clone(),
+            prefix: format!("{}:{}", self.prefix, name),
+        }
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}:{}", self.prefix, key)
+    }
+}
+
+/// Caches token lookups in Redis, shared by all instances.
+///
+/// Each user has a set of their cached tokens, so they can all be
+/// invalidated at once.
+pub struct RedisTokenCache {
+    conn: RedisConnection,
+    ttl: Duration,
+}
+
+impl RedisTokenCache {
+    /// Create a cache whose entries last for `ttl`.
+    pub fn new(conn: RedisConnection, ttl: Duration) -> RedisTokenCache {
+        RedisTokenCache { conn, ttl }
+    }
+
+    fn token_key(&self, token: &str) -> String {
+        self.conn.key(&format!("auth:token:{}", token))
+    }
+
+    fn user_key(&self, user_id: &str) -> String {
+        self.conn.key(&format!("auth:user:{}", user_id))
+    }
+}
+
+impl TokenCache for RedisTokenCache {
+    fn get(
+        &self,
+        token: &str,
+    ) -> LocalBoxFuture<'static, Result<Option<TokenUser>, SharedStateError>> {
+        let mut manager = self.conn.manager.clone();
+        let key = self.token_key(token);
+
+        async move {
+            let value: Option<String> = redis::cmd("GET")
+                .arg(key)
+                .query_async(&mut manager)
+                .await
+                .map_err(backend_error)?;
+
+            match value {
+                Some(value) => Ok(Some(serde_json::from_str(&value).context(InvalidPayload)?)),
+                None => Ok(None),
+            }
+        }
+        .boxed_local()
+    }
+
+    fn insert(
+        &self,
+        token: String,
+        user: TokenUser,
+    ) -> LocalBoxFuture<'static, Result<(), SharedStateError>> {
+        let mut manager = self.conn.manager.clone();
+        let token_key = self.token_key(&token);
+        let user_key = self.user_key(&user.user_id);
+        let ttl = expiry_millis(self.ttl);
+
+        async move {
+            let value = serde_json::to_string(&user).context(InvalidPayload)?;
+
+            redis::pipe()
+                .atomic()
+                .cmd("SET")
+                .arg(&token_key)
+                .arg(value)
+                .arg("PX")
+                .arg(ttl)
+                .ignore()
+                .cmd("SADD")
+                .arg(&user_key)
+                .arg(&token_key)
+                .ignore()
+                .cmd("PEXPIRE")
+                .arg(&user_key)
+                .arg(ttl)
+                .ignore()
+                .query_async(&mut manager)
+                .await
+                .map_err(backend_error)
+        }
+        .boxed_local()
+    }
+
+    fn invalidate_token(
+        &self,
+        token: &str,
+    ) -> LocalBoxFuture<'static, Result<(), SharedStateError>> {
+        let mut manager = self.conn.manager.clone();
+        let key = self.token_key(token);
+
+        async move {
+            // The token is left in its user's set, which is harmless as the
+            // set only says what to delete.
+            redis::cmd("DEL")
+                .arg(key)
+                .query_async(&mut manager)
+                .await
+                .map_err(backend_error)
+        }
+        .boxed_local()
+    }
+
+    fn invalidate_user(
+        &self,
+        user_id: &str,
+    ) -> LocalBoxFuture<'static, Result<(), SharedStateError>> {
+        let mut manager = self.conn.manager.clone();
+        let user_key = self.user_key(user_id);
+
+        async move {
+            let mut keys: Vec<String> = redis::cmd("SMEMBERS")
+                .arg(&user_key)
+                .query_async(&mut manager)
+                .await
+                .map_err(backend_error)?;
+            keys.push(user_key);
+
+            redis::cmd("DEL")
+                .arg(keys)
+                .query_async(&mut manager)
+                .await
+                .map_err(backend_error)
+        }
+        .boxed_local()
+    }
+}
+
+/// Counts hits in Redis, so the limits hold across instances.
+pub struct RedisRateLimiter {
+    conn: RedisConnection,
+}
+
+impl RedisRateLimiter {
+    pub fn new(conn: RedisConnection) -> RedisRateLimiter {
+        RedisRateLimiter { conn }
+    }
+}
+
+impl RateLimiter for RedisRateLimiter {
+    fn hit(
+        &self,
+        key: String,
+        limit: u32,
+        window: Duration,
+    ) -> LocalBoxFuture<'static, Result<Option<Duration>, SharedStateError>> {
+        let mut manager = self.conn.manager.clone();
+        let key = self.conn.key(&format!("rate:{}", key));
+        let window = expiry_millis(window);
+
+        async move {
+            // Starts the window if there isn't one, then counts the hit.
+            let (hits, remaining): (u32, i64) = redis::pipe()
+                .atomic()
+                .cmd("SET")
+                .arg(&key)
+                .arg(0)
+                .arg("PX")
+                .arg(window)
+                .arg("NX")
+                .ignore()
+                .cmd("INCR")
+                .arg(&key)
+                .cmd("PTTL")
+                .arg(&key)
+                .query_async(&mut manager)
+                .await
+                .map_err(backend_error)?;
+
+            if hits > limit {
+                Ok(Some(Duration::from_millis(remaining.max(0) as u64)))
+            } else {
+                Ok(None)
+            }
+        }
+        .boxed_local()
+    }
+}
+
+/// An invalidation as sent over pub/sub.
+#[derive(Serialize, Deserialize)]
+struct InvalidationMessage {
+    /// The instance that published it, which ignores its own messages.
+    instance_id: String,
+    invalidation: Invalidation,
+}
+
+/// Keeps the shared state in Redis. Invalidations are sent over pub/sub, and
+/// buffered by a background subscriber until the next poll.
+pub struct RedisSharedState {
+    conn: RedisConnection,
+    instance_id: String,
+    received: Arc<Mutex<Vec<Invalidation>>>,
+}
+
+impl RedisSharedState {
+    /// Create the shared state, subscribing to other instances'
+    /// invalidations. Must be called from within the actix runtime.
+    pub fn new(conn: RedisConnection, logger: Logger) -> RedisSharedState {
+        let state = RedisSharedState {
+            conn,
+            instance_id: thread_rng().sample_iter(&Alphanumeric).take(16).collect(),
+            received: Arc::new(Mutex::new(Vec::new())),
+        };
+        state.spawn_subscriber(logger);
+        state
+    }
+
+    /// The ID this instance publishes invalidations under.
+    pub fn instance_id(&self) -> &str {
+        &self.instance_id
+    }
+
+    fn channel(&self) -> String {
+        self.conn.key("invalidations")
+    }
+
+    fn spawn_subscriber(&self, logger: Logger) {
+        let client = self.conn.client.clone();
+        let channel = self.channel();
+        let instance_id = self.instance_id.clone();
+        let received = self.received.clone();
+
+        actix_web::rt::spawn(async move {
+            loop {
+                let res = async {
+                    let mut pubsub = client.get_async_connection().await?.into_pubsub();
+                    pubsub.subscribe(&channel).await?;
+
+                    let mut messages = pubsub.on_message();
+                    while let Some(message) = messages.next().await {
+                        let payload: String = message.get_payload()?;
+                        match serde_json::from_str::<InvalidationMessage>(&payload) {
+                            Ok(message) if message.instance_id == instance_id => {}
+                            Ok(message) => received
+                                .lock()
+                                .expect("invalidations lock poisoned")
+                                .push(message.invalidation),
+                            Err(e) => {
+                                warn!(logger, "Ignoring invalid invalidation"; "err" => e.to_string())
+                            }
+                        }
+                    }
+
+                    Ok::<_, RedisError>(())
+                }
+                .await;
+
+                // Anything published while resubscribing is missed, so cached
+                // entries may be stale until they expire.
+                if let Err(e) = res {
+                    warn!(logger, "Lost Redis subscription"; "err" => e.to_string());
+                }
+                actix_web::rt::time::sleep(RESUBSCRIBE_DELAY).await;
+            }
+        });
+    }
+}
+
+impl SharedState for RedisSharedState {
+    fn put(
+        &self,
+        key: String,
+        value: String,
+        ttl: Duration,
+    ) -> LocalBoxFuture<'static, Result<(), SharedStateError>> {
+        let mut manager = self.conn.manager.clone();
+        let key = self.conn.key(&format!("shared:{}", key));
+
+        async move {
+            redis::cmd("SET")
+                .arg(key)
+                .arg(value)
+                .arg("PX")
+                .arg(expiry_millis(ttl))
+                .query_async(&mut manager)
+                .await
+                .map_err(backend_error)
+        }
+        .boxed_local()
+    }
+
+    fn take(
+        &self,
+        key: String,
+    ) -> LocalBoxFuture<'static, Result<Option<String>, SharedStateError>> {
+        let mut manager = self.conn.manager.clone();
+        let key = self.conn.key(&format!("shared:{}", key));
+
+        async move {
+            let (value,): (Option<String>,) = redis::pipe()
+                .atomic()
+                .cmd("GET")
+                .arg(&key)
+                .cmd("DEL")
+                .arg(&key)
+                .ignore()
+                .query_async(&mut manager)
+                .await
+                .map_err(backend_error)?;
+
+            Ok(value)
+        }
+        .boxed_local()
+    }
+
+    fn publish(
+        &self,
+        invalidation: Invalidation,
+    ) -> LocalBoxFuture<'static, Result<(), SharedStateError>> {
+        let mut manager = self.conn.manager.clone();
+        let channel = self.channel();
+        let message = InvalidationMessage {
+            instance_id: self.instance_id.clone(),
+            invalidation,
+        };
+
+        async move {
+            let payload = serde_json::to_string(&message).context(InvalidPayload)?;
+
+            redis::cmd("PUBLISH")
+                .arg(channel)
+                .arg(payload)
+                .query_async(&mut manager)
+                .await
+                .map_err(backend_error)
+        }
+        .boxed_local()
+    }
+
+    fn poll(&self) -> LocalBoxFuture<'static, Result<Vec<Invalidation>, SharedStateError>> {
+        let invalidations =
+            std::mem::take(&mut *self.received.lock().expect("invalidations lock poisoned"));
+
+        async { Ok(invalidations) }.boxed_local()
+    }
+
+    fn prune(&self) -> LocalBoxFuture<'static, Result<(), SharedStateError>> {
+        // Redis expires values itself.
+        async { Ok(()) }.boxed_local()
+    }
+}
+
+/// Publishes events as JSON to the `{prefix}:events` channel, for other
+/// instances and services to consume.
+///
+/// Failing to publish is logged rather than failing the request, as nothing
+/// in shaft depends on the events being received.
+pub struct RedisEventPublisher {
+    conn: RedisConnection,
+    logger: Logger,
+}
+
+impl RedisEventPublisher {
+    pub fn new(conn: RedisConnection, logger: Logger) -> RedisEventPublisher {
+        RedisEventPublisher { conn, logger }
+    }
+}
+
+impl EventListener for RedisEventPublisher {
+    fn name(&self) -> &'static str {
+        "redis"
+    }
+
+    fn on_event(
+        &self,
+        event: &Event,
+    ) -> LocalBoxFuture<'static, Result<(), Box<dyn StdError + Send + Sync>>> {
+        let mut manager = self.conn.manager.clone();
+        let channel = self.conn.key("events");
+        let logger = self.logger.clone();
+        let payload = serde_json::to_string(event);
+
+        async move {
+            let res = match payload {
+                Ok(payload) => redis::cmd("PUBLISH")
+                    .arg(channel)
+                    .arg(payload)
+                    .query_async::<_, ()>(&mut manager)
+                    .await
+                    .map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string()),
+            };
+
+            if let Err(err) = res {
+                warn!(logger, "Failed to publish event to Redis"; "err" => err);
+            }
+
+            Ok(())
+        }
+        .boxed_local()
+    }
+}
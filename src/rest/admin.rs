@@ -0,0 +1,797 @@
+//! The JSON API and dashboard for instance admins. All endpoints require the
+//! `admin` scope.
+//!
+//! `GET /admin` is a dashboard listing users, recent audit log entries and
+//! the health of the database pool and outbound requests, with buttons for
+//! the user and token actions below.
+//!
+//! `POST /api/admin/adjustments` records an opening balance or correction
+//! between two users, e.g.
+//!
+//! ```json
+//! { "user": "alice", "other_user": "bob", "amount": "£12.50", "reason": "Opening balance" }
+//! ```
+//!
+//! means Alice is owed £12.50 by Bob. Adjustments are ordinary transactions
+//! of the `adjustment` kind, so they show up labelled as such in the history.
+//! They go in the default ledger unless another is named with `ledger`.
+//!
+//! `POST /api/admin/ledgers` creates a new ledger, e.g.
+//!
+//! ```json
+//! { "name": "holiday-2024", "display_name": "Holiday 2024" }
+//! ```
+//!
+//! which is then available at `/l/holiday-2024/...` to users added with
+//! `PUT /api/admin/ledgers/{ledger}/members/{user_id}`.
+//!
+//! `POST /api/admin/users/{user_id}/deactivate` stops a user logging in,
+//! keeping their balance and history. `POST /api/admin/users/{user_id}/merge`
+//! with
+//!
+//! ```json
+//! { "into": "alice" }
+//! ```
+//!
+//! moves everything of the user's to Alice, e.g. if she logged in with a
+//! second GitHub account, then removes them. `POST /api/admin/tokens/prune`
+//! deletes expired login sessions.
+//!
+//! `GET /api/admin/security_events` lists failed logins, requests with
+//! invalid tokens and refused admin requests, newest first.
+
+use actix_web::web::ServiceConfig;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use bytes::Bytes;
+use chrono;
+use futures::{future, stream, Stream, StreamExt};
+use hyper::header::CONTENT_DISPOSITION;
+use serde::Deserialize;
+use serde_json::json;
+use snafu::ResultExt;
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use crate::amount::AmountInput;
+use crate::db::{
+    self, AuditFilter, PoolStats, Scope, SecurityEventFilter, TransactionKind, DEFAULT_LEDGER_ID,
+};
+use crate::error::{
+    DatabaseError, EventError, InvalidAmount, InvalidReason, ShaftError, SharedStateError,
+    TemplateError,
+};
+use crate::events::Event;
+use crate::export;
+use crate::features::Feature;
+use crate::logging::LogLevelConfig;
+use crate::maintenance;
+use crate::reason::check_reason;
+use crate::rest::response::{json_response, ApiJson};
+use crate::rest::views::AdminPage;
+use crate::rest::{authz, AppState, AuthenticatedUser, PageContext, ReqLogger, Section};
+use crate::shared_state::Invalidation;
+
+/// Register servlets with HTTP app
+pub fn register_servlets(config: &mut ServiceConfig) {
+    config.route("/admin", web::get().to(show_dashboard));
+    config.route("/api/admin/audit", web::get().to(get_audit_log));
+    config.route(
+        "/api/admin/security_events",
+        web::get().to(get_security_events),
+    );
+    config.route("/api/admin/pool", web::get().to(get_pool_stats));
+    config.route("/api/admin/features", web::get().to(get_features));
+    config.route("/api/admin/features/{feature}", web::put().to(set_feature));
+    config.route("/api/admin/log_levels", web::get().to(get_log_levels));
+    config.route("/api/admin/log_levels", web::put().to(set_log_levels));
+    config.route("/api/admin/adjustments", web::post().to(create_adjustment));
+    config.route("/api/admin/ledgers", web::post().to(create_ledger));
+    config.route(
+        "/api/admin/ledgers/{ledger}/members/{user_id}",
+        web::put().to(add_ledger_member),
+    );
+    config.route(
+        "/api/admin/users/{user_id}/deactivate",
+        web::post().to(deactivate_user),
+    );
+    config.route(
+        "/api/admin/users/{user_id}/merge",
+        web::post().to(merge_user),
+    );
+    config.route("/api/admin/tokens/prune", web::post().to(prune_tokens));
+}
+
+/// The number of audit log entries shown on the dashboard.
+const DASHBOARD_AUDIT_ENTRIES: u32 = 20;
+
+/// The admin dashboard. Lists users in the default ledger.
+async fn show_dashboard(
+    (state, user): (web::Data<AppState>, AuthenticatedUser),
+) -> Result<HttpResponse, Error> {
+    authz::require_scope(&user, Scope::Admin)?;
+
+    let (users, deactivated, audit) = futures::try_join!(
+        state.database.get_all_users(DEFAULT_LEDGER_ID),
+        state.database.get_deactivated_users(),
+        state.database.get_audit_entries(AuditFilter {
+            limit: DASHBOARD_AUDIT_ENTRIES,
+            ..AuditFilter::default()
+        }),
+    )
+    .context(DatabaseError)?;
+
+    let pool = state.database.pool_stats();
+    let hosts = state.host_monitor.stats();
+
+    let page = AdminPage::new(&users, &deactivated, &state.config.admins)
+        .with_audit(&audit)
+        .with_health(&pool, &hosts);
+    let s = PageContext::new(&state)
+        .user(Some(&user))
+        .section(Section::Admin)
+        .render(&state.handlebars, "admin", &page)
+        .context(TemplateError)?;
+
+    Ok(HttpResponse::Ok().content_type("text/html").body(s))
+}
+
+/// How many audit log entries are fetched from the database at a time while
+/// streaming a CSV export.
+const AUDIT_EXPORT_PAGE_SIZE: u32 = 1000;
+
+/// The format to return the audit log in.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum AuditFormat {
+    Json,
+    Csv,
+}
+
+impl Default for AuditFormat {
+    fn default() -> AuditFormat {
+        AuditFormat::Json
+    }
+}
+
+/// Query parameters for `/api/admin/audit`
+#[derive(Deserialize)]
+struct AuditQuery {
+    /// Only include actions by this user.
+    actor: Option<String>,
+    /// Only include actions of this type.
+    action: Option<String>,
+    /// Only include actions at or after this RFC 3339 time.
+    from: Option<chrono::DateTime<chrono::Utc>>,
+    /// Only include actions before this RFC 3339 time.
+    to: Option<chrono::DateTime<chrono::Utc>>,
+    /// The `next_before` returned by the previous page, if any.
+    before: Option<i64>,
+    /// The maximum number of entries to return. Ignored for CSV exports.
+    #[serde(default = "default_audit_limit")]
+    limit: u32,
+    /// Whether to return JSON or a CSV export.
+    #[serde(default)]
+    format: AuditFormat,
+}
+
+fn default_audit_limit() -> u32 {
+    100
+}
+
+/// Get the audit log, newest first.
+///
+/// JSON responses are paginated: if `next_before` is set it should be passed
+/// as `before` to get the next page. CSV exports stream every matching entry,
+/// fetching [AUDIT_EXPORT_PAGE_SIZE] at a time.
+async fn get_audit_log(
+    (req, state, user, query): (
+        HttpRequest,
+        web::Data<AppState>,
+        AuthenticatedUser,
+        web::Query<AuditQuery>,
+    ),
+) -> Result<HttpResponse, Error> {
+    authz::require_scope(&user, Scope::Admin)?;
+
+    let query = query.into_inner();
+
+    let filter = AuditFilter {
+        actor: query.actor,
+        action: query.action,
+        target: None,
+        from: query.from,
+        to: query.to,
+        before: query.before,
+        limit: query.limit.min(1000),
+    };
+
+    if query.format == AuditFormat::Csv {
+        let filter = AuditFilter {
+            limit: AUDIT_EXPORT_PAGE_SIZE,
+            ..filter
+        };
+
+        return Ok(HttpResponse::Ok()
+            .content_type("text/csv; charset=utf-8")
+            .insert_header((
+                CONTENT_DISPOSITION,
+                "attachment; filename=\"shaft-audit.csv\"",
+            ))
+            .streaming(audit_csv_stream(state.database.clone(), filter)));
+    }
+
+    let limit = filter.limit;
+    let entries = state
+        .database
+        .get_audit_entries(filter)
+        .await
+        .context(DatabaseError)?;
+
+    let next_before = if entries.len() as u32 == limit {
+        entries.last().and_then(|entry| entry.id)
+    } else {
+        None
+    };
+
+    Ok(json_response(
+        &req,
+        HttpResponse::Ok(),
+        &json!({
+            "entries": entries,
+            "next_before": next_before,
+        }),
+    ))
+}
+
+/// Streams the audit log entries matching the filter as a CSV, fetching a
+/// page of `filter.limit` entries at a time. A database error part way
+/// through aborts the response, as the status has already been sent.
+fn audit_csv_stream(
+    database: Arc<dyn db::Database>,
+    filter: AuditFilter,
+) -> impl Stream<Item = Result<Bytes, db::DatabaseError>> {
+    let header = stream::once(future::ok(Bytes::from(export::audit_csv_header())));
+
+    let rows = stream::unfold(Some(filter), move |filter| {
+        let database = database.clone();
+        async move {
+            let mut filter = filter?;
+
+            let entries = match database.get_audit_entries(filter.clone()).await {
+                Ok(entries) => entries,
+                Err(e) => return Some((Err(e), None)),
+            };
+            if entries.is_empty() {
+                return None;
+            }
+
+            let next = if entries.len() as u32 == filter.limit {
+                filter.before = entries.last().and_then(|entry| entry.id);
+                filter.before.map(|_| filter)
+            } else {
+                None
+            };
+
+            Some((Ok(Bytes::from(export::audit_csv_rows(&entries))), next))
+        }
+    });
+
+    header.chain(rows)
+}
+
+/// Query parameters for `/api/admin/security_events`
+#[derive(Deserialize)]
+struct SecurityEventsQuery {
+    /// Only include events of this kind, e.g. `login.denied`.
+    kind: Option<String>,
+    /// The `next_before` returned by the previous page, if any.
+    before: Option<i64>,
+    /// The maximum number of events to return.
+    #[serde(default = "default_audit_limit")]
+    limit: u32,
+}
+
+/// Get the security events, newest first. Paginated like the audit log.
+async fn get_security_events(
+    (req, state, user, query): (
+        HttpRequest,
+        web::Data<AppState>,
+        AuthenticatedUser,
+        web::Query<SecurityEventsQuery>,
+    ),
+) -> Result<HttpResponse, Error> {
+    authz::require_scope(&user, Scope::Admin)?;
+
+    let query = query.into_inner();
+    let limit = query.limit.min(1000);
+
+    let events = state
+        .database
+        .get_security_events(SecurityEventFilter {
+            kind: query.kind,
+            before: query.before,
+            limit,
+        })
+        .await
+        .context(DatabaseError)?;
+
+    let next_before = if events.len() as u32 == limit {
+        events.last().and_then(|event| event.id)
+    } else {
+        None
+    };
+
+    Ok(json_response(
+        &req,
+        HttpResponse::Ok(),
+        &json!({
+            "events": events,
+            "next_before": next_before,
+        }),
+    ))
+}
+
+/// Get how busy the database connection pool and operation queue are.
+async fn get_pool_stats(
+    (state, user): (web::Data<AppState>, AuthenticatedUser),
+) -> Result<ApiJson<PoolStats>, Error> {
+    authz::require_scope(&user, Scope::Admin)?;
+
+    Ok(ApiJson(state.database.pool_stats()))
+}
+
+/// Get whether each optional feature is currently enabled.
+async fn get_features(
+    (state, user): (web::Data<AppState>, AuthenticatedUser),
+) -> Result<ApiJson<BTreeMap<Feature, bool>>, Error> {
+    authz::require_scope(&user, Scope::Admin)?;
+
+    Ok(ApiJson(state.features.snapshot()))
+}
+
+/// The body of a `PUT /api/admin/features/{feature}` request.
+#[derive(Deserialize)]
+struct SetFeatureBody {
+    enabled: bool,
+}
+
+/// Turn a feature on or off until the server restarts.
+async fn set_feature(
+    (state, user, feature, body): (
+        web::Data<AppState>,
+        AuthenticatedUser,
+        web::Path<Feature>,
+        web::Json<SetFeatureBody>,
+    ),
+) -> Result<ApiJson<BTreeMap<Feature, bool>>, Error> {
+    authz::require_scope(&user, Scope::Admin)?;
+
+    let feature = feature.into_inner();
+    let enabled = body.enabled;
+
+    if state.features.set(feature, enabled) != enabled {
+        state
+            .shared_state
+            .publish(Invalidation::Feature { feature, enabled })
+            .await
+            .context(SharedStateError)?;
+
+        state
+            .events
+            .publish(Event::FeatureToggled {
+                actor: user.user_id.clone(),
+                feature,
+                enabled,
+            })
+            .await
+            .context(EventError)?;
+    }
+
+    Ok(ApiJson(state.features.snapshot()))
+}
+
+/// Get the current log levels.
+async fn get_log_levels(
+    (state, user): (web::Data<AppState>, AuthenticatedUser),
+) -> Result<ApiJson<LogLevelConfig>, Error> {
+    authz::require_scope(&user, Scope::Admin)?;
+
+    Ok(ApiJson(state.log_levels.get()))
+}
+
+/// Replace the log levels until the server restarts, e.g. to get debug logs
+/// from one module while investigating a problem.
+async fn set_log_levels(
+    (state, user, body, ReqLogger(logger)): (
+        web::Data<AppState>,
+        AuthenticatedUser,
+        web::Json<LogLevelConfig>,
+        ReqLogger,
+    ),
+) -> Result<ApiJson<LogLevelConfig>, Error> {
+    authz::require_scope(&user, Scope::Admin)?;
+
+    let levels = body.into_inner();
+    info!(
+        logger, "Changed log levels";
+        "default" => format!("{:?}", levels.default),
+        "modules" => format!("{:?}", levels.modules),
+    );
+    state.log_levels.set(levels);
+
+    Ok(ApiJson(state.log_levels.get()))
+}
+
+/// The body of a `POST /api/admin/adjustments` request.
+#[derive(Deserialize)]
+struct AdjustmentBody {
+    /// The user whose balance goes up by `amount`.
+    user: String,
+    /// The user whose balance goes down by `amount`, so that the ledger still
+    /// balances.
+    other_user: String,
+    /// The amount, in the same formats as [ShaftUserBody](crate::rest::ShaftUserBody).
+    amount: AmountInput,
+    reason: String,
+    /// The name of the ledger to record it in, if not the default.
+    ledger: Option<String>,
+}
+
+/// Record an opening balance or correction between two users.
+async fn create_adjustment(
+    (state, user, body, ReqLogger(logger)): (
+        web::Data<AppState>,
+        AuthenticatedUser,
+        web::Json<AdjustmentBody>,
+        ReqLogger,
+    ),
+) -> Result<ApiJson<impl serde::Serialize>, Error> {
+    authz::require_scope(&user, Scope::Admin)?;
+
+    let AdjustmentBody {
+        user: user_id,
+        other_user,
+        amount,
+        reason,
+        ledger,
+    } = body.into_inner();
+    let amount = amount
+        .resolve(&state.config.currency)
+        .context(InvalidAmount)?;
+
+    if user_id == other_user {
+        return Err(ShaftError::BadRequest {
+            message: "An adjustment must be between two different users".to_string(),
+        }
+        .into());
+    }
+    // Adjustments always need a reason, even if shafts don't.
+    if reason.trim().is_empty() {
+        return Err(ShaftError::BadRequest {
+            message: "Adjustments must have a reason".to_string(),
+        }
+        .into());
+    }
+    let reason = check_reason(&reason, &state.config.reasons, &*state.content_policy)
+        .context(InvalidReason)?;
+
+    let ledger_id = match ledger {
+        Some(name) => get_ledger_id(&state, name).await?,
+        None => DEFAULT_LEDGER_ID,
+    };
+
+    // The database checks the other user is in the ledger when the
+    // transaction is created, as it would for a shaft.
+    let users = state
+        .database
+        .get_all_users(ledger_id)
+        .await
+        .context(DatabaseError)?;
+    if !users.contains_key(&user_id) {
+        return Err(ShaftError::BadRequest {
+            message: format!("Unknown user {}", user_id),
+        }
+        .into());
+    }
+
+    let transaction = db::Transaction {
+        id: None,
+        shafter: user_id.clone(),
+        shaftee: other_user.clone(),
+        amount,
+        datetime: chrono::Utc::now(),
+        reason,
+        kind: TransactionKind::Adjustment,
+    };
+
+    let actor = user.user_id.clone();
+    let outbox = state.events.outbox({
+        let transaction = transaction.clone();
+        move |&transaction_id| {
+            vec![Event::AdjustmentCreated {
+                actor,
+                transaction: db::Transaction {
+                    id: Some(transaction_id),
+                    ..transaction
+                },
+            }]
+        }
+    });
+    let transaction_id = state
+        .database
+        .shaft_user(ledger_id, transaction, outbox.clone())
+        .await
+        .context(DatabaseError)?;
+
+    state
+        .events
+        .publish_outbox(&outbox)
+        .await
+        .context(EventError)?;
+
+    info!(
+        logger, "Recorded adjustment";
+        "user" => user_id, "other_user" => other_user, "amount" => amount.minor_units()
+    );
+
+    Ok(ApiJson(json!({ "transaction_id": transaction_id })))
+}
+
+/// Look up a ledger's ID by name.
+async fn get_ledger_id(state: &AppState, name: String) -> Result<i64, ShaftError> {
+    let ledger = state
+        .database
+        .get_ledger_by_name(name)
+        .await
+        .context(DatabaseError)?
+        .ok_or(ShaftError::NotFound { what: "ledger" })?;
+
+    Ok(ledger.id)
+}
+
+/// Whether the name can be used in a ledger's URL: lowercase letters, digits,
+/// `-` and `_`.
+fn is_valid_ledger_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+/// The body of a `POST /api/admin/ledgers` request.
+#[derive(Deserialize)]
+struct CreateLedgerBody {
+    /// The name used in URLs, e.g. `flat` for `/l/flat/home`.
+    name: String,
+    display_name: String,
+}
+
+/// Create a new, empty ledger. Users must be added to it before they can use
+/// it.
+async fn create_ledger(
+    (state, user, body, ReqLogger(logger)): (
+        web::Data<AppState>,
+        AuthenticatedUser,
+        web::Json<CreateLedgerBody>,
+        ReqLogger,
+    ),
+) -> Result<ApiJson<db::Ledger>, Error> {
+    authz::require_scope(&user, Scope::Admin)?;
+
+    let CreateLedgerBody { name, display_name } = body.into_inner();
+
+    if !is_valid_ledger_name(&name) {
+        return Err(ShaftError::BadRequest {
+            message: "Ledger names may only contain a-z, 0-9, '-' and '_'".to_string(),
+        }
+        .into());
+    }
+    if display_name.trim().is_empty() {
+        return Err(ShaftError::BadRequest {
+            message: "Ledgers must have a display name".to_string(),
+        }
+        .into());
+    }
+
+    let actor = user.user_id.clone();
+    let outbox = state.events.outbox(move |ledger: &db::Ledger| {
+        vec![Event::LedgerCreated {
+            actor,
+            ledger: ledger.clone(),
+        }]
+    });
+    let ledger = state
+        .database
+        .create_ledger(name, display_name, outbox.clone())
+        .await
+        .context(DatabaseError)?;
+
+    state
+        .events
+        .publish_outbox(&outbox)
+        .await
+        .context(EventError)?;
+
+    info!(logger, "Created ledger"; "ledger" => &ledger.name, "ledger_id" => ledger.id);
+
+    Ok(ApiJson(ledger))
+}
+
+/// Add a user to a ledger. Adding an existing member does nothing.
+async fn add_ledger_member(
+    (state, user, path, ReqLogger(logger)): (
+        web::Data<AppState>,
+        AuthenticatedUser,
+        web::Path<(String, String)>,
+        ReqLogger,
+    ),
+) -> Result<ApiJson<impl serde::Serialize>, Error> {
+    authz::require_scope(&user, Scope::Admin)?;
+
+    let (ledger, user_id) = path.into_inner();
+    let ledger_id = get_ledger_id(&state, ledger.clone()).await?;
+
+    let outbox = state.events.outbox({
+        let actor = user.user_id.clone();
+        let ledger = ledger.clone();
+        let user_id = user_id.clone();
+        move |_: &()| {
+            vec![Event::LedgerMemberAdded {
+                actor,
+                ledger,
+                user_id,
+            }]
+        }
+    });
+    state
+        .database
+        .add_ledger_member(ledger_id, user_id.clone(), outbox.clone())
+        .await
+        .context(DatabaseError)?;
+
+    state
+        .events
+        .publish_outbox(&outbox)
+        .await
+        .context(EventError)?;
+
+    info!(logger, "Added ledger member"; "ledger" => ledger, "member" => user_id);
+
+    Ok(ApiJson(json!({})))
+}
+
+/// Stop a user logging in. Admins can't deactivate themselves.
+async fn deactivate_user(
+    (state, user, user_id, ReqLogger(logger)): (
+        web::Data<AppState>,
+        AuthenticatedUser,
+        web::Path<String>,
+        ReqLogger,
+    ),
+) -> Result<ApiJson<impl serde::Serialize>, Error> {
+    authz::require_scope(&user, Scope::Admin)?;
+
+    let user_id = user_id.into_inner();
+    if user_id == user.user_id {
+        return Err(ShaftError::BadRequest {
+            message: "You can't deactivate yourself".to_string(),
+        }
+        .into());
+    }
+
+    let outbox = state.events.outbox({
+        let actor = user.user_id.clone();
+        let user_id = user_id.clone();
+        move |_: &()| vec![Event::UserDeactivated { actor, user_id }]
+    });
+    state
+        .database
+        .deactivate_user(user_id.clone(), outbox.clone())
+        .await
+        .context(DatabaseError)?;
+    state
+        .invalidate(Invalidation::User {
+            user_id: user_id.clone(),
+        })
+        .await
+        .context(SharedStateError)?;
+
+    state
+        .events
+        .publish_outbox(&outbox)
+        .await
+        .context(EventError)?;
+
+    info!(logger, "Deactivated user"; "user" => user_id);
+
+    Ok(ApiJson(json!({})))
+}
+
+/// The body of a `POST /api/admin/users/{user_id}/merge` request.
+#[derive(Deserialize)]
+struct MergeUserBody {
+    /// The user to merge into, who is kept.
+    into: String,
+}
+
+/// Merge a user into another, removing them.
+async fn merge_user(
+    (state, user, from, body, ReqLogger(logger)): (
+        web::Data<AppState>,
+        AuthenticatedUser,
+        web::Path<String>,
+        web::Json<MergeUserBody>,
+        ReqLogger,
+    ),
+) -> Result<ApiJson<impl serde::Serialize>, Error> {
+    authz::require_scope(&user, Scope::Admin)?;
+
+    let from = from.into_inner();
+    let into = body.into_inner().into;
+    if from == into {
+        return Err(ShaftError::BadRequest {
+            message: "Can't merge a user into themselves".to_string(),
+        }
+        .into());
+    }
+    if from == user.user_id {
+        return Err(ShaftError::BadRequest {
+            message: "You can't merge yourself into someone else".to_string(),
+        }
+        .into());
+    }
+
+    let outbox = state.events.outbox({
+        let actor = user.user_id.clone();
+        let from = from.clone();
+        let into = into.clone();
+        move |_: &()| vec![Event::UsersMerged { actor, from, into }]
+    });
+    state
+        .database
+        .merge_users(from.clone(), into.clone(), outbox.clone())
+        .await
+        .context(DatabaseError)?;
+    state
+        .invalidate(Invalidation::User {
+            user_id: from.clone(),
+        })
+        .await
+        .context(SharedStateError)?;
+
+    state
+        .events
+        .publish_outbox(&outbox)
+        .await
+        .context(EventError)?;
+
+    info!(logger, "Merged users"; "from" => from, "into" => into);
+
+    Ok(ApiJson(json!({})))
+}
+
+/// Delete expired login sessions.
+async fn prune_tokens(
+    (state, user, ReqLogger(logger)): (web::Data<AppState>, AuthenticatedUser, ReqLogger),
+) -> Result<ApiJson<impl serde::Serialize>, Error> {
+    authz::require_scope(&user, Scope::Admin)?;
+
+    let actor = user.user_id.clone();
+    let outbox = state
+        .events
+        .outbox(move |&count| vec![Event::TokensPruned { actor, count }]);
+    let count = maintenance::prune_tokens(state.database.as_ref(), None, outbox.clone())
+        .await
+        .context(DatabaseError)?;
+
+    state
+        .events
+        .publish_outbox(&outbox)
+        .await
+        .context(EventError)?;
+
+    info!(logger, "Pruned expired sessions"; "count" => count);
+
+    Ok(ApiJson(json!({ "deleted": count })))
+}
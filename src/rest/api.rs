@@ -1,89 +1,1632 @@
 //! The JSON API for interacting with shaft
 
 use actix_web::web::{Json, ServiceConfig};
-use actix_web::{error::ErrorInternalServerError, web, Error, HttpRequest};
+use actix_web::{web, Error, HttpMessage, HttpRequest, HttpResponse};
 use chrono;
-use serde::Serialize;
+use hyper::header::{CONTENT_DISPOSITION, ETAG, IF_MATCH, SET_COOKIE};
+use linear_map::LinearMap;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
-use snafu::ResultExt;
+use snafu::{IntoError, ResultExt};
 
-use crate::db;
-use crate::error::{DatabaseError, ShaftError};
-use crate::rest::{AppState, AuthenticatedUser, ShaftUserBody};
+use std::collections::BTreeMap;
 
-use slog::Logger;
+use crate::amount::AmountInput;
+use crate::api_types::{
+    BalancesResponse, BulkShaftResponse, BulkShaftResult, ShaftUserBody, ShaftUserResponse,
+    TransactionEntry, UserBalance,
+};
+use crate::db::{self, Scope, UserPreferences};
+use crate::error::{
+    DatabaseError, EventError, ImportError, InvalidAmount, InvalidReason, ShaftError,
+    SharedStateError,
+};
+use crate::events::Event;
+use crate::export::{self, JournalAccounts, JournalFormat};
+use crate::import::{self, StatementFormat};
+use crate::matcher::rank_users;
+use crate::money::CurrencyCode;
+use crate::reason::check_reason;
+use crate::rest::response::{json_response, ApiJson};
+use crate::rest::{
+    authz, etag_matches, ledger_etag, read_body, set_request_stage, AppState, AuthenticatedUser,
+    CurrentLedger, ReadAccess, ReqLogger, RequestStage, SessionCookie,
+};
+use crate::shared_state::Invalidation;
 
 /// Register servlets with HTTP app
 pub fn register_servlets(config: &mut ServiceConfig) {
+    config.route("/api/me", web::get().to(get_api_me));
+    config.route("/api/ledgers", web::get().to(get_api_ledgers));
     config.route("/api/balances", web::get().to(get_api_balances));
+    config.route("/api/v1/balances", web::get().to(get_api_balances_v1));
+    config.route("/api/users", web::get().to(get_api_users));
+    config.route("/api/users/match", web::get().to(match_users));
     config.route("/api/transactions", web::get().to(get_api_transactions));
     config.route("/api/shaft", web::post().to(shaft_user));
+    config.route("/api/shaft/bulk", web::post().to(shaft_user_bulk));
+    config.route("/api/sync", web::get().to(get_api_sync));
+    config.route("/api/export/splitwise", web::get().to(export_splitwise));
+    config.route("/api/export/ledger", web::get().to(export_ledger));
+    config.route("/api/import/preview", web::post().to(import_preview));
+    config.route("/api/import/commit", web::post().to(import_commit));
+    config.service(
+        web::resource("/api/transactions/{id}")
+            .route(web::get().to(get_api_transaction))
+            .route(web::put().to(edit_transaction))
+            .route(web::delete().to(delete_transaction)),
+    );
+    config.route(
+        "/api/transactions/{id}/attachments",
+        web::post().to(upload_attachment),
+    );
+    config.route(
+        "/api/transactions/{id}/suggestions",
+        web::get().to(get_suggestions),
+    );
+    config.service(
+        web::resource("/api/tokens")
+            .route(web::get().to(get_api_tokens))
+            .route(web::post().to(create_api_token)),
+    );
+    config.route("/api/tokens/{id}", web::delete().to(delete_api_token));
+    config.route("/api/profile/export", web::get().to(export_profile));
+    config.service(
+        web::resource("/api/profile/preferences")
+            .route(web::get().to(get_preferences))
+            .route(web::put().to(set_preferences)),
+    );
+    config.route("/api/profile/delete", web::post().to(delete_profile));
+}
+
+/// The maximum number of transactions that can be created in one bulk request.
+const MAX_BULK_SHAFT_SIZE: usize = 500;
+
+/// The maximum number of quick amount buttons a user can have.
+const MAX_QUICK_AMOUNTS: usize = 8;
+
+/// The maximum size of an uploaded attachment in bytes.
+const MAX_ATTACHMENT_SIZE: usize = 10 * 1024 * 1024;
+
+/// Get the logged in user, along with their balance.
+async fn get_api_me(
+    (state, user, ledger): (web::Data<AppState>, AuthenticatedUser, CurrentLedger),
+) -> Result<ApiJson<db::User>, Error> {
+    authz::require_scope(&user, Scope::Read)?;
+
+    let balance = state
+        .database
+        .get_balance_for_user(ledger.id, user.user_id.clone())
+        .await
+        .context(DatabaseError)?;
+
+    Ok(ApiJson(db::User {
+        user_id: user.user_id,
+        display_name: user.display_name,
+        balance,
+    }))
+}
+
+/// Get the ledgers the logged in user is a member of.
+async fn get_api_ledgers(
+    (state, user): (web::Data<AppState>, AuthenticatedUser),
+) -> Result<ApiJson<Vec<db::Ledger>>, Error> {
+    authz::require_scope(&user, Scope::Read)?;
+
+    let ledgers = state
+        .database
+        .get_ledgers_for_user(user.user_id)
+        .await
+        .context(DatabaseError)?;
+
+    Ok(ApiJson(ledgers))
 }
 
 /// Get all user's balances as a map from user ID to [User](crate::db::User)
 /// object.
+///
+/// Kept for existing clients, new ones should use `/api/v1/balances`.
 async fn get_api_balances(
-    (state, _user): (web::Data<AppState>, AuthenticatedUser),
-) -> Result<Json<impl Serialize>, Error> {
-    state
+    (req, state, _access, ledger, query): (
+        HttpRequest,
+        web::Data<AppState>,
+        ReadAccess,
+        CurrentLedger,
+        web::Query<BalancesQuery>,
+    ),
+) -> Result<HttpResponse, Error> {
+    balances_response(&req, &state, &ledger, &query, |users| json!(users)).await
+}
+
+/// Get all user's balances as a list of [User](crate::db::User) objects, most
+/// in debt first, e.g.
+///
+/// ```json
+/// { "users": [{ "user_id": "bob", "display_name": "Bob", "balance": -150 }] }
+/// ```
+async fn get_api_balances_v1(
+    (req, state, _access, ledger, query): (
+        HttpRequest,
+        web::Data<AppState>,
+        ReadAccess,
+        CurrentLedger,
+        web::Query<BalancesQuery>,
+    ),
+) -> Result<HttpResponse, Error> {
+    balances_response(&req, &state, &ledger, &query, |users| {
+        json!(BalancesResponse {
+            users: db::users_by_balance(users)
+                .into_iter()
+                .map(UserBalance::from)
+                .collect(),
+        })
+    })
+    .await
+}
+
+/// The most users returned in one page by the users and balances endpoints.
+const MAX_USERS_LIMIT: u32 = 1000;
+
+/// Query parameters for the balances endpoints. By default every member of
+/// the ledger is included.
+#[derive(Deserialize)]
+struct BalancesQuery {
+    /// Only include users whose balance isn't zero.
+    #[serde(default)]
+    nonzero: bool,
+    /// The maximum number of users to include, most in debt first.
+    limit: Option<u32>,
+    /// How many users to skip, for pagination.
+    #[serde(default)]
+    offset: u32,
+}
+
+/// Get the ledger's balances in the shape built by `body`.
+///
+/// Supports `If-None-Match`, returning a 304 if the ledger hasn't changed.
+async fn balances_response(
+    req: &HttpRequest,
+    state: &AppState,
+    ledger: &CurrentLedger,
+    query: &BalancesQuery,
+    body: impl FnOnce(&LinearMap<String, db::User>) -> serde_json::Value,
+) -> Result<HttpResponse, Error> {
+    let etag = ledger_etag(
+        state
+            .database
+            .get_ledger_version()
+            .await
+            .context(DatabaseError)?,
+    );
+
+    if etag_matches(req, &etag) {
+        return Ok(HttpResponse::NotModified()
+            .insert_header((ETAG, etag))
+            .finish());
+    }
+
+    let users = if !query.nonzero && query.limit.is_none() && query.offset == 0 {
+        state
+            .database
+            .get_all_users(ledger.id)
+            .await
+            .context(DatabaseError)?
+    } else {
+        state
+            .database
+            .get_users(
+                ledger.id,
+                db::UserFilter {
+                    nonzero_balance: query.nonzero,
+                    limit: Some(query.limit.unwrap_or(MAX_USERS_LIMIT).min(MAX_USERS_LIMIT)),
+                    offset: query.offset,
+                    ..db::UserFilter::default()
+                },
+            )
+            .await
+            .context(DatabaseError)?
+            .into_iter()
+            .map(|entry| (entry.user.user_id.clone(), entry.user))
+            .collect()
+    };
+
+    let mut builder = HttpResponse::Ok();
+    builder.insert_header((ETAG, etag));
+
+    Ok(json_response(req, builder, &body(&users)))
+}
+
+/// Query parameters for `/api/users`
+#[derive(Deserialize)]
+struct UsersQuery {
+    /// `balance` (the default) or `name`.
+    #[serde(default)]
+    sort: db::UserSort,
+    /// Only include users who are, or aren't, active.
+    active: Option<bool>,
+    /// Only include users whose ID or display name starts with this.
+    q: Option<String>,
+    /// Only include users whose balance isn't zero.
+    #[serde(default)]
+    nonzero: bool,
+    /// The maximum number of users to return.
+    limit: Option<u32>,
+    /// How many users to skip, for pagination.
+    #[serde(default)]
+    offset: u32,
+}
+
+/// A user in the `/api/users` response.
+#[derive(Serialize)]
+struct UserListItem {
+    #[serde(flatten)]
+    entry: db::UserEntry,
+    /// Whether they are an instance admin.
+    admin: bool,
+}
+
+/// List the ledger's members, e.g.
+///
+/// ```json
+/// {
+///   "users": [
+///     { "user_id": "bob", "display_name": "Bob", "balance": -150, "active": true, "admin": false }
+///   ],
+///   "next_offset": null
+/// }
+/// ```
+///
+/// If a `limit` is given and there may be more users, `next_offset` is the
+/// `offset` to fetch the next page with.
+async fn get_api_users(
+    (state, _access, ledger, query): (
+        web::Data<AppState>,
+        ReadAccess,
+        CurrentLedger,
+        web::Query<UsersQuery>,
+    ),
+) -> Result<ApiJson<impl Serialize>, Error> {
+    let UsersQuery {
+        sort,
+        active,
+        q,
+        nonzero,
+        limit,
+        offset,
+    } = query.into_inner();
+    let limit = limit.map(|limit| limit.min(MAX_USERS_LIMIT));
+
+    let entries = state
         .database
-        .get_all_users()
+        .get_users(
+            ledger.id,
+            db::UserFilter {
+                active,
+                prefix: q.filter(|q| !q.is_empty()),
+                nonzero_balance: nonzero,
+                sort,
+                limit,
+                offset,
+                settled_before: None,
+            },
+        )
         .await
-        .map_err(ErrorInternalServerError)
-        .map(Json)
+        .context(DatabaseError)?;
+
+    let next_offset = match limit {
+        Some(limit) if entries.len() as u32 == limit => Some(offset + limit),
+        _ => None,
+    };
+
+    let users: Vec<_> = entries
+        .into_iter()
+        .map(|entry| UserListItem {
+            admin: state.config.admins.contains(&entry.user.user_id),
+            entry,
+        })
+        .collect();
+
+    Ok(ApiJson(
+        json!({ "users": users, "next_offset": next_offset }),
+    ))
+}
+
+/// The number of matches returned by `/api/users/match` by default.
+const DEFAULT_MATCH_LIMIT: usize = 5;
+
+/// The most matches returned by `/api/users/match`.
+const MAX_MATCH_LIMIT: usize = 50;
+
+/// Query parameters for `/api/users/match`
+#[derive(Deserialize)]
+struct MatchQuery {
+    /// What was typed for the user, e.g. `bo` or `@Bob`.
+    q: String,
+    /// The maximum number of matches to return.
+    limit: Option<usize>,
+}
+
+/// Find the members of the ledger a partial or misspelt name may refer to,
+/// best match first, e.g.
+///
+/// ```json
+/// {
+///   "matches": [
+///     { "user_id": "bob", "display_name": "Bob", "balance": -150, "score": 0.8 }
+///   ]
+/// }
+/// ```
+///
+/// Scores range from 0 to 1; see [crate::matcher] for how they're assigned.
+async fn match_users(
+    (state, _access, ledger, query): (
+        web::Data<AppState>,
+        ReadAccess,
+        CurrentLedger,
+        web::Query<MatchQuery>,
+    ),
+) -> Result<ApiJson<impl Serialize>, Error> {
+    let MatchQuery { q, limit } = query.into_inner();
+    let limit = limit.unwrap_or(DEFAULT_MATCH_LIMIT).min(MAX_MATCH_LIMIT);
+
+    let users = state
+        .database
+        .get_all_users(ledger.id)
+        .await
+        .context(DatabaseError)?;
+
+    let mut matches = rank_users(&q, users.values());
+    matches.truncate(limit);
+
+    Ok(ApiJson(json!({ "matches": matches })))
+}
+
+/// Query parameters for `/api/transactions`
+#[derive(Deserialize)]
+struct TransactionsQuery {
+    /// How many of the most recent transactions to return, up to the
+    /// configured maximum.
+    limit: Option<u32>,
 }
 
 /// Get most recent transactions
+///
+/// Supports `If-None-Match`, returning a 304 if the ledger hasn't changed.
 async fn get_api_transactions(
-    (state, _user): (web::Data<AppState>, AuthenticatedUser),
-) -> Result<Json<Vec<db::Transaction>>, Error> {
-    state
+    (req, state, _access, ledger, query): (
+        HttpRequest,
+        web::Data<AppState>,
+        ReadAccess,
+        CurrentLedger,
+        web::Query<TransactionsQuery>,
+    ),
+) -> Result<HttpResponse, Error> {
+    let etag = ledger_etag(
+        state
+            .database
+            .get_ledger_version()
+            .await
+            .context(DatabaseError)?,
+    );
+
+    if etag_matches(&req, &etag) {
+        return Ok(HttpResponse::NotModified()
+            .insert_header((ETAG, etag))
+            .finish());
+    }
+
+    let limit = state.config.api.limit(query.limit);
+    let transactions: Vec<TransactionEntry> = state
+        .database
+        .get_last_transactions(ledger.id, limit)
+        .await
+        .context(DatabaseError)?
+        .into_iter()
+        .map(TransactionEntry::from)
+        .collect();
+
+    let mut builder = HttpResponse::Ok();
+    builder.insert_header((ETAG, etag));
+
+    Ok(json_response(&req, builder, &transactions))
+}
+
+/// Query parameters for `/api/sync`
+#[derive(Deserialize)]
+struct SyncQuery {
+    /// The cursor returned by the previous sync, if any.
+    #[serde(default)]
+    since: i64,
+    /// The maximum number of changes to return.
+    #[serde(default = "default_sync_limit")]
+    limit: u32,
+}
+
+fn default_sync_limit() -> u32 {
+    1000
+}
+
+/// Get the transactions and users that have changed after the given cursor.
+///
+/// Clients should repeat the request with the returned `next_cursor` until it
+/// stops changing. If `resync_required` is set the cursor is too old, and the
+/// client should reload the ledger before syncing from `next_cursor`.
+async fn get_api_sync(
+    (state, _access, ledger, query): (
+        web::Data<AppState>,
+        ReadAccess,
+        CurrentLedger,
+        web::Query<SyncQuery>,
+    ),
+) -> Result<ApiJson<db::LedgerChanges>, Error> {
+    let changes = state
         .database
-        .get_last_transactions(20)
+        .get_changes_since(
+            ledger.id,
+            query.since,
+            query.limit.min(default_sync_limit()),
+        )
         .await
-        .map_err(ErrorInternalServerError)
-        .map(Json)
+        .context(DatabaseError)?;
+
+    Ok(ApiJson(changes))
 }
 
 /// Create a new transaction.
 ///
-/// Returns an empty json object.
+/// Returns the ID of the new transaction.
 async fn shaft_user(
-    (req, state, user, body): (
-        HttpRequest,
+    (state, user, ledger, body, ReqLogger(logger)): (
         web::Data<AppState>,
         AuthenticatedUser,
+        CurrentLedger,
         Json<ShaftUserBody>,
+        ReqLogger,
     ),
-) -> Result<Json<impl Serialize>, ShaftError> {
-    let logger = req
-        .extensions()
-        .get::<Logger>()
-        .expect("no logger installed in request")
-        .clone();
+) -> Result<ApiJson<ShaftUserResponse>, ShaftError> {
+    authz::require_scope(&user, Scope::Write)?;
 
     let ShaftUserBody {
         other_user,
         amount,
         reason,
     } = body.0;
+    let amount = amount
+        .resolve(&state.config.currency)
+        .context(InvalidAmount)?;
+    let reason = check_reason(&reason, &state.config.reasons, &*state.content_policy)
+        .context(InvalidReason)?;
+
+    let transaction = db::Transaction {
+        id: None,
+        shafter: user.user_id.clone(),
+        shaftee: other_user.clone(),
+        amount,
+        datetime: chrono::Utc::now(),
+        reason,
+        kind: db::TransactionKind::Shaft,
+    };
+
+    let outbox = state.events.transaction_outbox(&transaction);
+    let transaction_id = state
+        .database
+        .shaft_user(ledger.id, transaction, outbox.clone())
+        .await
+        .context(DatabaseError)?;
 
     state
+        .events
+        .publish_outbox(&outbox)
+        .await
+        .context(EventError)?;
+
+    info!(
+        logger, "Shafted user";
+        "other_user" => other_user, "amount" => amount.minor_units()
+    );
+
+    Ok(ApiJson(ShaftUserResponse { transaction_id }))
+}
+
+/// Create several transactions atomically: either all of them are created or
+/// none are.
+///
+/// Returns a result for each transaction in the request, in order. On success
+/// each result has the new transaction ID, otherwise a 400 is returned and the
+/// results for invalid transactions have an error.
+async fn shaft_user_bulk(
+    (req, state, user, ledger, body, ReqLogger(logger)): (
+        HttpRequest,
+        web::Data<AppState>,
+        AuthenticatedUser,
+        CurrentLedger,
+        Json<Vec<ShaftUserBody>>,
+        ReqLogger,
+    ),
+) -> Result<HttpResponse, Error> {
+    authz::require_scope(&user, Scope::Write)?;
+
+    let entries = body.into_inner();
+    if entries.len() > MAX_BULK_SHAFT_SIZE {
+        return Err(ShaftError::BadRequest {
+            message: format!(
+                "Can't create more than {} transactions at once",
+                MAX_BULK_SHAFT_SIZE
+            ),
+        }
+        .into());
+    }
+
+    // We check the users up front so that we can report every invalid entry,
+    // rather than just the first.
+    let users = state
         .database
-        .shaft_user(db::Transaction {
-            shafter: user.user_id.clone(),
-            shaftee: other_user.clone(),
-            amount,
-            datetime: chrono::Utc::now(),
-            reason,
+        .get_all_users(ledger.id)
+        .await
+        .context(DatabaseError)?;
+
+    let amounts: Vec<_> = entries
+        .iter()
+        .map(|entry| entry.amount.resolve(&state.config.currency))
+        .collect();
+
+    let errors: Vec<Option<String>> = entries
+        .iter()
+        .zip(&amounts)
+        .map(|(entry, amount)| {
+            if !users.contains_key(&entry.other_user) {
+                Some(format!("Unknown user: {}", entry.other_user))
+            } else if let Err(err) = amount {
+                Some(err.to_string())
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    if errors.iter().any(Option::is_some) {
+        return Ok(json_response(
+            &req,
+            HttpResponse::BadRequest(),
+            &BulkShaftResponse {
+                committed: false,
+                results: errors
+                    .into_iter()
+                    .map(|error| BulkShaftResult {
+                        transaction_id: None,
+                        error,
+                    })
+                    .collect(),
+            },
+        ));
+    }
+
+    let now = chrono::Utc::now();
+    let transactions: Vec<_> = entries
+        .into_iter()
+        .zip(amounts)
+        .map(|(entry, amount)| {
+            amount.map(|amount| db::Transaction {
+                id: None,
+                shafter: user.user_id.clone(),
+                shaftee: entry.other_user,
+                amount,
+                datetime: now,
+                reason: entry.reason,
+                kind: db::TransactionKind::Shaft,
+            })
+        })
+        .collect::<Result<_, _>>()
+        .context(InvalidAmount)?;
+
+    let outbox = state.events.outbox({
+        let transactions = transactions.clone();
+        move |transaction_ids: &Vec<i64>| {
+            transactions
+                .into_iter()
+                .zip(transaction_ids)
+                .map(|(transaction, transaction_id)| Event::TransactionCreated {
+                    transaction: db::Transaction {
+                        id: Some(*transaction_id),
+                        ..transaction
+                    },
+                })
+                .collect()
+        }
+    });
+    let transaction_ids = state
+        .database
+        .insert_transactions_batch(ledger.id, transactions, outbox.clone())
+        .await
+        .context(DatabaseError)?;
+
+    state
+        .events
+        .publish_outbox(&outbox)
+        .await
+        .context(EventError)?;
+
+    info!(logger, "Shafted users in bulk"; "count" => transaction_ids.len());
+
+    Ok(json_response(
+        &req,
+        HttpResponse::Ok(),
+        &BulkShaftResponse {
+            committed: true,
+            results: transaction_ids
+                .into_iter()
+                .map(|transaction_id| BulkShaftResult {
+                    transaction_id: Some(transaction_id),
+                    error: None,
+                })
+                .collect(),
+        },
+    ))
+}
+
+/// Query parameters for `/api/export/splitwise`
+#[derive(Deserialize)]
+struct SplitwiseExportQuery {
+    /// The currency code to label each expense with. Defaults to the
+    /// configured currency.
+    currency: Option<String>,
+}
+
+/// Export the full transaction history as a Splitwise compatible CSV, with a
+/// column per user.
+async fn export_splitwise(
+    (state, user, ledger, query): (
+        web::Data<AppState>,
+        AuthenticatedUser,
+        CurrentLedger,
+        web::Query<SplitwiseExportQuery>,
+    ),
+) -> Result<HttpResponse, ShaftError> {
+    authz::require_scope(&user, Scope::Read)?;
+
+    let users = state
+        .database
+        .get_all_users(ledger.id)
+        .await
+        .context(DatabaseError)?;
+
+    let transactions = state
+        .database
+        .get_all_transactions(ledger.id)
+        .await
+        .context(DatabaseError)?;
+
+    let currency = &state.config.currency;
+    let csv = export::splitwise_csv(
+        &users,
+        &transactions,
+        query
+            .currency
+            .as_deref()
+            .unwrap_or_else(|| currency.code.as_str()),
+    );
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/csv; charset=utf-8")
+        .insert_header((
+            CONTENT_DISPOSITION,
+            "attachment; filename=\"shaft-splitwise.csv\"",
+        ))
+        .body(csv))
+}
+
+/// Query parameters for `/api/export/ledger`
+#[derive(Deserialize)]
+struct LedgerExportQuery {
+    #[serde(default = "default_journal_format")]
+    format: JournalFormat,
+    /// The commodity to give amounts. Defaults to the configured currency.
+    currency: Option<String>,
+    /// The parent of each other user's account.
+    #[serde(default = "default_journal_prefix")]
+    prefix: String,
+    /// The account the other side of each transaction goes to.
+    #[serde(default = "default_journal_offset")]
+    offset: String,
+    /// Accounts for particular users, given as `account.<user_id>=<account>`.
+    #[serde(flatten)]
+    accounts: BTreeMap<String, String>,
+}
+
+fn default_journal_format() -> JournalFormat {
+    JournalFormat::Ledger
+}
+
+fn default_journal_prefix() -> String {
+    "Assets:Shaft".to_string()
+}
+
+fn default_journal_offset() -> String {
+    "Equity:Shaft".to_string()
+}
+
+/// Export the user's transactions as a ledger or beancount journal, for
+/// pulling into plain text accounts.
+async fn export_ledger(
+    (state, user, ledger, query): (
+        web::Data<AppState>,
+        AuthenticatedUser,
+        CurrentLedger,
+        web::Query<LedgerExportQuery>,
+    ),
+) -> Result<HttpResponse, ShaftError> {
+    authz::require_scope(&user, Scope::Read)?;
+
+    let query = query.into_inner();
+    let accounts = JournalAccounts {
+        prefix: query.prefix,
+        offset: query.offset,
+        overrides: query
+            .accounts
+            .into_iter()
+            .filter_map(|(key, account)| {
+                key.strip_prefix("account.")
+                    .map(|user_id| (user_id.to_string(), account))
+            })
+            .collect(),
+    };
+
+    let users = state
+        .database
+        .get_all_users(ledger.id)
+        .await
+        .context(DatabaseError)?;
+
+    let transactions = state
+        .database
+        .get_all_transactions(ledger.id)
+        .await
+        .context(DatabaseError)?;
+
+    let journal = export::journal(
+        query.format,
+        &user.user_id,
+        &users,
+        &transactions,
+        &accounts,
+        query
+            .currency
+            .as_deref()
+            .unwrap_or_else(|| state.config.currency.code.as_str()),
+    );
+
+    let filename = match query.format {
+        JournalFormat::Ledger => "shaft.ledger",
+        JournalFormat::Beancount => "shaft.beancount",
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; charset=utf-8")
+        .insert_header((
+            CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}\"", filename),
+        ))
+        .body(journal))
+}
+
+/// Query parameters for `/api/import/preview`
+#[derive(Deserialize)]
+struct ImportPreviewQuery {
+    /// The format of the uploaded statement.
+    format: StatementFormat,
+}
+
+/// Parse an uploaded bank statement, returning each line along with any
+/// existing transaction that appears to already record it.
+async fn import_preview(
+    (state, user, ledger, query, body): (
+        web::Data<AppState>,
+        AuthenticatedUser,
+        CurrentLedger,
+        web::Query<ImportPreviewQuery>,
+        String,
+    ),
+) -> Result<ApiJson<Vec<import::ProposedMatch>>, Error> {
+    authz::require_scope(&user, Scope::Read)?;
+
+    let lines = import::parse_statement(query.format, &body, &state.config.currency)
+        .context(ImportError)?;
+
+    let existing = state
+        .database
+        .get_all_transactions(ledger.id)
+        .await
+        .context(DatabaseError)?;
+
+    Ok(ApiJson(import::propose_matches(
+        &user.user_id,
+        lines,
+        &existing,
+    )))
+}
+
+/// A statement line the user has mapped to a new transaction.
+#[derive(Deserialize)]
+struct ImportEntry {
+    /// The other party in the transaction.
+    other_user: String,
+    /// The amount, with the same sign convention and formats as
+    /// [ShaftUserBody](crate::api_types::ShaftUserBody).
+    amount: AmountInput,
+    /// The human readable description of the transaction.
+    reason: String,
+    /// The date of the statement line.
+    date: chrono::NaiveDate,
+}
+
+/// The body of a `/api/import/commit` request.
+#[derive(Deserialize)]
+struct ImportCommitBody {
+    entries: Vec<ImportEntry>,
+}
+
+/// Create transactions for the mapped statement lines. Either all of them
+/// are created or none are.
+///
+/// Returns the number of transactions created.
+async fn import_commit(
+    (state, user, ledger, body, ReqLogger(logger)): (
+        web::Data<AppState>,
+        AuthenticatedUser,
+        CurrentLedger,
+        Json<ImportCommitBody>,
+        ReqLogger,
+    ),
+) -> Result<ApiJson<impl Serialize>, ShaftError> {
+    authz::require_scope(&user, Scope::Write)?;
+
+    let transactions = body
+        .into_inner()
+        .entries
+        .into_iter()
+        .map(|entry| {
+            let amount = entry
+                .amount
+                .resolve(&state.config.currency)
+                .context(InvalidAmount)?;
+            let reason = check_reason(&entry.reason, &state.config.reasons, &*state.content_policy)
+                .context(InvalidReason)?;
+
+            Ok(db::Transaction {
+                id: None,
+                shafter: user.user_id.clone(),
+                shaftee: entry.other_user,
+                amount,
+                datetime: chrono::DateTime::from_utc(entry.date.and_hms(0, 0, 0), chrono::Utc),
+                reason,
+                kind: db::TransactionKind::Shaft,
+            })
+        })
+        .collect::<Result<_, ShaftError>>()?;
+
+    let actor = user.user_id.clone();
+    let outbox = state.events.outbox(move |transaction_ids: &Vec<i64>| {
+        vec![Event::TransactionsImported {
+            actor,
+            count: transaction_ids.len(),
+        }]
+    });
+    let count = state
+        .database
+        .insert_transactions_batch(ledger.id, transactions, outbox.clone())
+        .await
+        .context(DatabaseError)?
+        .len();
+
+    state
+        .events
+        .publish_outbox(&outbox)
+        .await
+        .context(EventError)?;
+
+    info!(logger, "Imported transactions"; "count" => count);
+
+    Ok(ApiJson(json!({ "created": count })))
+}
+
+/// The path of a request for a single item by ID, e.g.
+/// `/api/transactions/{id}`. Also matches paths within a ledger.
+#[derive(Deserialize)]
+struct IdPath {
+    id: i64,
+}
+
+/// Fetch a transaction in the ledger, checking that the user is a party to
+/// it. Other people's transactions look the same as those in other ledgers,
+/// so that their IDs can't be probed for.
+async fn get_own_transaction(
+    state: &AppState,
+    user: &AuthenticatedUser,
+    ledger: &CurrentLedger,
+    transaction_id: i64,
+) -> Result<db::Transaction, ShaftError> {
+    let transaction = state
+        .database
+        .get_transaction(ledger.id, transaction_id)
+        .await
+        .context(DatabaseError)?
+        .ok_or(ShaftError::NotFound {
+            what: "transaction",
+        })?;
+
+    if transaction.shafter != user.user_id && transaction.shaftee != user.user_id {
+        return Err(ShaftError::NotFound {
+            what: "transaction",
+        });
+    }
+
+    Ok(transaction)
+}
+
+/// Attach a file, e.g. a photo of a receipt, to a transaction.
+///
+/// The file is passed to the configured receipt processor, whose suggestions
+/// are available from `/api/transactions/{id}/suggestions`. Returns the ID of
+/// the new attachment.
+async fn upload_attachment(
+    (req, state, user, ledger, path, payload, ReqLogger(logger)): (
+        HttpRequest,
+        web::Data<AppState>,
+        AuthenticatedUser,
+        CurrentLedger,
+        web::Path<IdPath>,
+        web::Payload,
+        ReqLogger,
+    ),
+) -> Result<ApiJson<impl Serialize>, Error> {
+    authz::require_scope(&user, Scope::Write)?;
+
+    let transaction_id = path.id;
+    get_own_transaction(&state, &user, &ledger, transaction_id).await?;
+
+    let content_type = req.content_type().to_string();
+    let data = read_body(payload, MAX_ATTACHMENT_SIZE).await?;
+
+    let attachment_id = state
+        .database
+        .add_attachment(db::Attachment {
+            transaction_id,
+            uploader: user.user_id.clone(),
+            content_type: content_type.clone(),
+            data: data.to_vec(),
         })
         .await
         .context(DatabaseError)?;
 
     info!(
-        logger, "Shafted user";
-        "other_user" => other_user, "amount" => amount
+        logger, "Uploaded attachment";
+        "transaction_id" => transaction_id, "attachment_id" => attachment_id
+    );
+
+    set_request_stage(&req, RequestStage::Upstream);
+    let processed = state.receipt_processor.process(content_type, data).await;
+    set_request_stage(&req, RequestStage::Handling);
+
+    match processed {
+        Ok(Some(suggestion)) => state
+            .database
+            .add_receipt_suggestion(attachment_id, suggestion)
+            .await
+            .context(DatabaseError)?,
+        Ok(None) => {}
+        Err(err) => warn!(logger, "Failed to process receipt"; "err" => format!("{}", err)),
+    }
+
+    Ok(ApiJson(json!({ "attachment_id": attachment_id })))
+}
+
+/// Get the details suggested for a transaction from its attachments.
+async fn get_suggestions(
+    (state, user, ledger, path): (
+        web::Data<AppState>,
+        AuthenticatedUser,
+        CurrentLedger,
+        web::Path<IdPath>,
+    ),
+) -> Result<ApiJson<impl Serialize>, Error> {
+    authz::require_scope(&user, Scope::Read)?;
+
+    let transaction_id = path.id;
+    get_own_transaction(&state, &user, &ledger, transaction_id).await?;
+
+    let suggestions = state
+        .database
+        .get_receipt_suggestions(transaction_id)
+        .await
+        .context(DatabaseError)?;
+
+    Ok(ApiJson(
+        suggestions
+            .into_iter()
+            .map(|(attachment_id, suggestion)| {
+                json!({
+                    "attachment_id": attachment_id,
+                    "amount": suggestion.amount,
+                    "merchant": suggestion.merchant,
+                })
+            })
+            .collect::<Vec<_>>(),
+    ))
+}
+
+/// Format a transaction revision as an ETag.
+fn revision_etag(revision: i64) -> String {
+    format!("\"{}\"", revision)
+}
+
+/// Parse the `If-Match` header of a request into the revision it refers to,
+/// or None for `*`.
+///
+/// Changes to transactions must always be conditional, so a missing header is
+/// an error.
+fn parse_if_match(req: &HttpRequest) -> Result<Option<i64>, ShaftError> {
+    let value = req
+        .headers()
+        .get(IF_MATCH)
+        .ok_or(ShaftError::MissingIfMatch)?
+        .to_str()
+        .map_err(|_| invalid_if_match())?
+        .trim();
+
+    if value == "*" {
+        return Ok(None);
+    }
+
+    value
+        .trim_matches('"')
+        .parse()
+        .map(Some)
+        .map_err(|_| invalid_if_match())
+}
+
+fn invalid_if_match() -> ShaftError {
+    ShaftError::BadRequest {
+        message: "Invalid If-Match header".to_string(),
+    }
+}
+
+/// Convert the error from a conditional change to a transaction into the
+/// appropriate response.
+fn conditional_write_error(err: db::DatabaseError) -> Result<HttpResponse, Error> {
+    match err {
+        db::DatabaseError::RevisionMismatch { revision, .. } => {
+            Ok(HttpResponse::PreconditionFailed()
+                .insert_header((ETAG, revision_etag(revision)))
+                .finish())
+        }
+        err => Err(DatabaseError.into_error(err).into()),
+    }
+}
+
+/// Get a single transaction, with its revision as the ETag.
+async fn get_api_transaction(
+    (req, state, user, ledger, path): (
+        HttpRequest,
+        web::Data<AppState>,
+        AuthenticatedUser,
+        CurrentLedger,
+        web::Path<IdPath>,
+    ),
+) -> Result<HttpResponse, Error> {
+    authz::require_scope(&user, Scope::Read)?;
+
+    let transaction_id = path.id;
+
+    // We fetch the revision first so that it is never newer than the
+    // transaction we return.
+    let revision = state
+        .database
+        .get_transaction_revision(ledger.id, transaction_id)
+        .await
+        .context(DatabaseError)?
+        .ok_or(ShaftError::NotFound {
+            what: "transaction",
+        })?;
+
+    let transaction = get_own_transaction(&state, &user, &ledger, transaction_id).await?;
+
+    let mut builder = HttpResponse::Ok();
+    builder.insert_header((ETAG, revision_etag(revision)));
+
+    Ok(json_response(&req, builder, &transaction))
+}
+
+/// The body of a request to edit a transaction.
+#[derive(Deserialize)]
+struct EditTransactionBody {
+    /// The new amount in pence, or a string in the configured currency's
+    /// notation.
+    amount: AmountInput,
+    /// The new human readable description.
+    reason: String,
+}
+
+/// Edit a transaction. Requires an `If-Match` header with the revision being
+/// edited, returning a 412 if the transaction has since changed.
+async fn edit_transaction(
+    (req, state, user, ledger, path, body, ReqLogger(logger)): (
+        HttpRequest,
+        web::Data<AppState>,
+        AuthenticatedUser,
+        CurrentLedger,
+        web::Path<IdPath>,
+        Json<EditTransactionBody>,
+        ReqLogger,
+    ),
+) -> Result<HttpResponse, Error> {
+    authz::require_scope(&user, Scope::Write)?;
+
+    let transaction_id = path.id;
+    let expected_revision = parse_if_match(&req)?;
+
+    let transaction = get_own_transaction(&state, &user, &ledger, transaction_id).await?;
+    if transaction.shafter != user.user_id {
+        return Err(ShaftError::Forbidden {
+            reason: "Only the creator of a transaction can edit it",
+        }
+        .into());
+    }
+
+    let EditTransactionBody { amount, reason } = body.into_inner();
+    let amount = amount
+        .resolve(&state.config.currency)
+        .context(InvalidAmount)?;
+    let reason = check_reason(&reason, &state.config.reasons, &*state.content_policy)
+        .context(InvalidReason)?;
+
+    let actor = user.user_id.clone();
+    let outbox = state.events.outbox(move |&revision| {
+        vec![Event::TransactionEdited {
+            actor,
+            transaction_id,
+            revision,
+        }]
+    });
+    let revision = match state
+        .database
+        .update_transaction(
+            ledger.id,
+            transaction_id,
+            expected_revision,
+            amount,
+            reason,
+            outbox.clone(),
+        )
+        .await
+    {
+        Ok(revision) => revision,
+        Err(err) => return conditional_write_error(err),
+    };
+
+    state
+        .events
+        .publish_outbox(&outbox)
+        .await
+        .context(EventError)?;
+
+    info!(
+        logger, "Edited transaction";
+        "transaction_id" => transaction_id, "revision" => revision
     );
 
-    Ok(Json(json!({})))
+    let mut builder = HttpResponse::Ok();
+    builder.insert_header((ETAG, revision_etag(revision)));
+
+    Ok(json_response(
+        &req,
+        builder,
+        &json!({ "revision": revision }),
+    ))
+}
+
+/// Delete a transaction. Requires an `If-Match` header with the revision being
+/// deleted, returning a 412 if the transaction has since changed.
+async fn delete_transaction(
+    (req, state, user, ledger, path, ReqLogger(logger)): (
+        HttpRequest,
+        web::Data<AppState>,
+        AuthenticatedUser,
+        CurrentLedger,
+        web::Path<IdPath>,
+        ReqLogger,
+    ),
+) -> Result<HttpResponse, Error> {
+    authz::require_scope(&user, Scope::Write)?;
+
+    let transaction_id = path.id;
+    let expected_revision = parse_if_match(&req)?;
+
+    let transaction = get_own_transaction(&state, &user, &ledger, transaction_id).await?;
+    if transaction.shafter != user.user_id {
+        return Err(ShaftError::Forbidden {
+            reason: "Only the creator of a transaction can delete it",
+        }
+        .into());
+    }
+
+    let actor = user.user_id.clone();
+    let outbox = state.events.outbox(move |_: &()| {
+        vec![Event::TransactionDeleted {
+            actor,
+            transaction_id,
+        }]
+    });
+    if let Err(err) = state
+        .database
+        .delete_transaction(ledger.id, transaction_id, expected_revision, outbox.clone())
+        .await
+    {
+        return conditional_write_error(err);
+    }
+
+    state
+        .events
+        .publish_outbox(&outbox)
+        .await
+        .context(EventError)?;
+
+    info!(logger, "Deleted transaction"; "transaction_id" => transaction_id);
+
+    Ok(json_response(&req, HttpResponse::Ok(), &json!({})))
+}
+
+/// Get the user's API tokens. Doesn't include the tokens themselves.
+async fn get_api_tokens(
+    (state, user): (web::Data<AppState>, AuthenticatedUser),
+) -> Result<ApiJson<Vec<db::ApiToken>>, Error> {
+    authz::require_scope(&user, Scope::Read)?;
+
+    let tokens = state
+        .database
+        .get_api_tokens(user.user_id)
+        .await
+        .context(DatabaseError)?;
+
+    Ok(ApiJson(tokens))
+}
+
+/// Body for creating a new API token.
+#[derive(Deserialize)]
+struct CreateApiTokenBody {
+    /// A human readable name for the token.
+    name: String,
+    /// What the token is allowed to do.
+    scopes: Vec<Scope>,
+}
+
+/// Create a new API token, to be used in an `Authorization: Bearer` header.
+///
+/// The token can't be given scopes that the requester doesn't have, and is
+/// only ever returned here.
+async fn create_api_token(
+    (req, state, user, body, ReqLogger(logger)): (
+        HttpRequest,
+        web::Data<AppState>,
+        AuthenticatedUser,
+        Json<CreateApiTokenBody>,
+        ReqLogger,
+    ),
+) -> Result<HttpResponse, Error> {
+    authz::require_scope(&user, Scope::Write)?;
+
+    let CreateApiTokenBody { name, mut scopes } = body.into_inner();
+
+    if name.trim().is_empty() {
+        return Err(ShaftError::BadRequest {
+            message: "Token name must not be empty".to_string(),
+        }
+        .into());
+    }
+    if scopes.is_empty() {
+        return Err(ShaftError::BadRequest {
+            message: "Token must have at least one scope".to_string(),
+        }
+        .into());
+    }
+
+    scopes.sort();
+    scopes.dedup();
+
+    authz::require_scopes(&user, &scopes)?;
+
+    let outbox = state.events.outbox({
+        let actor = user.user_id.clone();
+        let name = name.clone();
+        let scopes = scopes.clone();
+        move |&(token_id, _): &(i64, String)| {
+            vec![Event::TokenCreated {
+                actor,
+                token_id,
+                name,
+                scopes,
+            }]
+        }
+    });
+    let (token_id, token) = state
+        .database
+        .create_api_token(
+            user.user_id.clone(),
+            name.clone(),
+            scopes.clone(),
+            outbox.clone(),
+        )
+        .await
+        .context(DatabaseError)?;
+
+    state
+        .events
+        .publish_outbox(&outbox)
+        .await
+        .context(EventError)?;
+
+    info!(logger, "Created API token"; "token_id" => token_id);
+
+    Ok(json_response(
+        &req,
+        HttpResponse::Ok(),
+        &json!({
+            "id": token_id,
+            "name": name,
+            "scopes": scopes,
+            "token": token,
+        }),
+    ))
+}
+
+/// Revoke one of the user's API tokens.
+async fn delete_api_token(
+    (req, state, user, path, ReqLogger(logger)): (
+        HttpRequest,
+        web::Data<AppState>,
+        AuthenticatedUser,
+        web::Path<IdPath>,
+        ReqLogger,
+    ),
+) -> Result<HttpResponse, Error> {
+    authz::require_scope(&user, Scope::Write)?;
+
+    let token_id = path.id;
+
+    let actor = user.user_id.clone();
+    let outbox = state.events.outbox(move |&deleted| {
+        if deleted {
+            vec![Event::TokenRevoked { actor, token_id }]
+        } else {
+            Vec::new()
+        }
+    });
+    let deleted = state
+        .database
+        .delete_api_token(user.user_id.clone(), token_id, outbox.clone())
+        .await
+        .context(DatabaseError)?;
+
+    if !deleted {
+        return Err(ShaftError::NotFound { what: "token" }.into());
+    }
+
+    // We don't know which token string was deleted, so drop all of the
+    // user's cached tokens.
+    state
+        .invalidate(Invalidation::User {
+            user_id: user.user_id.clone(),
+        })
+        .await
+        .context(SharedStateError)?;
+
+    state
+        .events
+        .publish_outbox(&outbox)
+        .await
+        .context(EventError)?;
+
+    info!(logger, "Deleted API token"; "token_id" => token_id);
+
+    Ok(json_response(&req, HttpResponse::Ok(), &json!({})))
+}
+
+/// Get everything held about the user, for data portability requests.
+async fn export_profile(
+    (state, user): (web::Data<AppState>, AuthenticatedUser),
+) -> Result<ApiJson<db::UserExport>, Error> {
+    authz::require_scope(&user, Scope::Read)?;
+
+    let export = state
+        .database
+        .export_user(user.user_id)
+        .await
+        .context(DatabaseError)?
+        .ok_or(ShaftError::NotFound { what: "user" })?;
+
+    Ok(ApiJson(export))
+}
+
+/// Get the user's defaults for the shaft form.
+async fn get_preferences(
+    (state, user): (web::Data<AppState>, AuthenticatedUser),
+) -> Result<ApiJson<UserPreferences>, Error> {
+    authz::require_scope(&user, Scope::Read)?;
+
+    let preferences = state
+        .database
+        .get_user_preferences(user.user_id)
+        .await
+        .context(DatabaseError)?;
+
+    Ok(ApiJson(preferences))
+}
+
+/// Body for setting the user's defaults for the shaft form. Anything left
+/// out is cleared.
+#[derive(Deserialize)]
+struct PreferencesBody {
+    default_counterparty: Option<String>,
+    default_currency: Option<CurrencyCode>,
+    #[serde(default)]
+    quick_amounts: Vec<AmountInput>,
+}
+
+/// Replace the user's defaults for the shaft form.
+async fn set_preferences(
+    (state, user, body): (
+        web::Data<AppState>,
+        AuthenticatedUser,
+        Json<PreferencesBody>,
+    ),
+) -> Result<ApiJson<UserPreferences>, Error> {
+    authz::require_scope(&user, Scope::Write)?;
+
+    let PreferencesBody {
+        default_counterparty,
+        default_currency,
+        quick_amounts,
+    } = body.into_inner();
+
+    if default_counterparty.as_deref() == Some(user.user_id.as_str()) {
+        return Err(ShaftError::BadRequest {
+            message: "You can't be your own default counterparty".to_string(),
+        }
+        .into());
+    }
+    // Amounts are always in the instance's currency, so another wouldn't
+    // mean anything.
+    if let Some(code) = default_currency {
+        if code != state.config.currency.code {
+            return Err(ShaftError::BadRequest {
+                message: format!(
+                    "The default currency must be {}",
+                    state.config.currency.code
+                ),
+            }
+            .into());
+        }
+    }
+    if quick_amounts.len() > MAX_QUICK_AMOUNTS {
+        return Err(ShaftError::BadRequest {
+            message: format!("At most {} quick amounts are allowed", MAX_QUICK_AMOUNTS),
+        }
+        .into());
+    }
+
+    let quick_amounts = quick_amounts
+        .iter()
+        .map(|amount| amount.resolve(&state.config.currency))
+        .collect::<Result<Vec<_>, _>>()
+        .context(InvalidAmount)?;
+    if quick_amounts.iter().any(|amount| !amount.is_positive()) {
+        return Err(ShaftError::BadRequest {
+            message: "Quick amounts must be positive".to_string(),
+        }
+        .into());
+    }
+
+    let preferences = UserPreferences {
+        default_counterparty,
+        default_currency,
+        quick_amounts,
+    };
+    state
+        .database
+        .set_user_preferences(user.user_id, preferences.clone())
+        .await
+        .context(DatabaseError)?;
+
+    Ok(ApiJson(preferences))
+}
+
+/// Body for deleting the user's account.
+#[derive(Deserialize, Default)]
+struct DeleteProfileBody {
+    /// The token returned by a previous request without one.
+    confirmation_token: Option<String>,
+}
+
+/// Delete the user's account, anonymising them in the ledger.
+///
+/// Needs two requests: the first, without a confirmation token, returns one
+/// that must be sent back within a few minutes to actually delete the
+/// account.
+async fn delete_profile(
+    (req, state, user, body, ReqLogger(logger)): (
+        HttpRequest,
+        web::Data<AppState>,
+        AuthenticatedUser,
+        Option<Json<DeleteProfileBody>>,
+        ReqLogger,
+    ),
+) -> Result<HttpResponse, Error> {
+    authz::require_scope(&user, Scope::Write)?;
+
+    let body = body.map(Json::into_inner).unwrap_or_default();
+
+    let confirmation_token = match body.confirmation_token {
+        Some(token) => token,
+        None => {
+            let token = state
+                .confirmations
+                .issue(&user.user_id)
+                .await
+                .context(SharedStateError)?;
+            return Ok(json_response(
+                &req,
+                HttpResponse::Ok(),
+                &json!({
+                    "confirmation_token": token,
+                    "expires_in_secs": state.confirmations.ttl().as_secs(),
+                }),
+            ));
+        }
+    };
+
+    if !state
+        .confirmations
+        .consume(&user.user_id, &confirmation_token)
+        .await
+        .context(SharedStateError)?
+    {
+        return Err(ShaftError::Forbidden {
+            reason: "Invalid or expired confirmation token",
+        }
+        .into());
+    }
+
+    let outbox = state.events.outbox(|anonymous_id: &String| {
+        vec![Event::UserDeleted {
+            user_id: anonymous_id.clone(),
+        }]
+    });
+    let anonymous_id = state
+        .database
+        .delete_user(user.user_id.clone(), outbox.clone())
+        .await
+        .context(DatabaseError)?;
+
+    state
+        .invalidate(Invalidation::User {
+            user_id: user.user_id.clone(),
+        })
+        .await
+        .context(SharedStateError)?;
+
+    state
+        .events
+        .publish_outbox(&outbox)
+        .await
+        .context(EventError)?;
+
+    info!(logger, "Deleted user"; "anonymous_id" => &anonymous_id);
+
+    // The session is no longer valid, so clear the cookie too.
+    let mut builder = HttpResponse::Ok();
+    builder.insert_header((
+        SET_COOKIE,
+        SessionCookie::removal(&state.config).to_string(),
+    ));
+
+    Ok(json_response(&req, builder, &json!({})))
 }
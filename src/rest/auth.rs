@@ -1,43 +1,253 @@
 //! Handles authenticating an incoming request.
 
-use actix_http::error;
-use actix_http::httpmessage::HttpMessage;
-use actix_service::{Service, Transform};
-use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
-use actix_web::{self, Error, FromRequest, HttpRequest, HttpResponse};
-use futures::future::{ok, LocalBoxFuture};
+use actix_web::dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderValue, SET_COOKIE};
+use actix_web::http::Method;
+use actix_web::{error, Error, FromRequest, HttpMessage, HttpRequest, HttpResponse};
+use futures::future::{err, ok, LocalBoxFuture};
 use futures::FutureExt;
-use hyper::header::LOCATION;
+use hyper::header::{AUTHORIZATION, LOCATION};
+use lru::LruCache;
 use slog::Logger;
+use snafu::ResultExt;
 
-use std::cell::RefCell;
 use std::rc::Rc;
-use std::sync::Arc;
-use std::task::{Context, Poll};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use crate::db::Database;
-use crate::rest::AppState;
+use crate::db::{Database, Scope, TokenUser};
+use crate::error::{DatabaseError, ShaftError, SharedStateError};
+use crate::features::Feature;
+use crate::rate_limit::{InMemoryRateLimiter, RateLimiter};
+use crate::rest::security::{record_security_event, SecurityEventKind};
+use crate::rest::tenants::relative_path;
+use crate::rest::{
+    app_state, authz, next_query, set_request_stage, RequestStage, SessionCookie,
+    SESSION_COOKIE_NAME,
+};
+use crate::settings::CookieSettings;
+use crate::shared_state;
+
+/// How many requests with an unknown or expired token each IP may make in
+/// [INVALID_TOKEN_WINDOW], to slow down guessing tokens.
+pub const INVALID_TOKEN_LIMIT: u32 = 20;
+
+/// The window [INVALID_TOKEN_LIMIT] applies to.
+pub const INVALID_TOKEN_WINDOW: Duration = Duration::from_secs(60);
+
+/// A short lived cache of token lookups, so that authenticating a request
+/// doesn't need a database query.
+///
+/// Entries must be invalidated when their token is deleted, else the token
+/// keeps working until the entry expires.
+pub trait TokenCache: Send + Sync {
+    /// Get the cached user for the token, if any.
+    fn get(
+        &self,
+        token: &str,
+    ) -> LocalBoxFuture<'static, Result<Option<TokenUser>, shared_state::SharedStateError>>;
+
+    /// Cache the user for the token.
+    fn insert(
+        &self,
+        token: String,
+        user: TokenUser,
+    ) -> LocalBoxFuture<'static, Result<(), shared_state::SharedStateError>>;
+
+    /// Remove the token from the cache, e.g. on logout.
+    fn invalidate_token(
+        &self,
+        token: &str,
+    ) -> LocalBoxFuture<'static, Result<(), shared_state::SharedStateError>>;
+
+    /// Remove all the user's tokens from the cache, e.g. when one of their API
+    /// tokens is deleted by ID.
+    fn invalidate_user(
+        &self,
+        user_id: &str,
+    ) -> LocalBoxFuture<'static, Result<(), shared_state::SharedStateError>>;
+}
+
+/// A [TokenCache] in memory, holding the most recently used tokens.
+pub struct AuthCache {
+    ttl: Duration,
+    entries: Mutex<LruCache<String, (Instant, TokenUser)>>,
+}
+
+impl AuthCache {
+    /// Create a cache holding up to `capacity` tokens, each for at most `ttl`.
+    pub fn new(capacity: usize, ttl: Duration) -> AuthCache {
+        AuthCache {
+            ttl,
+            entries: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+}
+
+impl TokenCache for AuthCache {
+    fn get(
+        &self,
+        token: &str,
+    ) -> LocalBoxFuture<'static, Result<Option<TokenUser>, shared_state::SharedStateError>> {
+        let mut entries = self.entries.lock().expect("auth cache lock poisoned");
+
+        let user = match entries.get(token) {
+            Some((inserted, user)) if inserted.elapsed() < self.ttl => Some(user.clone()),
+            Some(_) => {
+                entries.pop(token);
+                None
+            }
+            None => None,
+        };
+
+        ok(user).boxed_local()
+    }
+
+    fn insert(
+        &self,
+        token: String,
+        user: TokenUser,
+    ) -> LocalBoxFuture<'static, Result<(), shared_state::SharedStateError>> {
+        let mut entries = self.entries.lock().expect("auth cache lock poisoned");
+        entries.put(token, (Instant::now(), user));
+
+        ok(()).boxed_local()
+    }
+
+    fn invalidate_token(
+        &self,
+        token: &str,
+    ) -> LocalBoxFuture<'static, Result<(), shared_state::SharedStateError>> {
+        let mut entries = self.entries.lock().expect("auth cache lock poisoned");
+        entries.pop(token);
+
+        ok(()).boxed_local()
+    }
+
+    fn invalidate_user(
+        &self,
+        user_id: &str,
+    ) -> LocalBoxFuture<'static, Result<(), shared_state::SharedStateError>> {
+        let mut entries = self.entries.lock().expect("auth cache lock poisoned");
+
+        let tokens: Vec<String> = entries
+            .iter()
+            .filter(|(_, (_, user))| user.user_id == user_id)
+            .map(|(token, _)| token.clone())
+            .collect();
+
+        for token in tokens {
+            entries.pop(&token);
+        }
+
+        ok(()).boxed_local()
+    }
+}
 
 /// Middleware for annotating requests with valid user authentication.
 ///
+/// The token is taken from the `token` cookie for login sessions, or from an
+/// `Authorization: Bearer` header for API tokens. Lookups are cached in the
+/// given [TokenCache].
+///
+/// Requests with an unknown or expired token are counted per IP by the
+/// [rate limiter](AuthenticateUser::with_rate_limiter), and denied once there
+/// are too many.
+///
+/// Requests to paths starting with one of the
+/// [skipped paths](AuthenticateUser::with_skipped_paths) aren't looked up at
+/// all, so are treated as logged out.
+///
+/// If [session refresh](AuthenticateUser::with_session_refresh) is set up,
+/// login sessions that are used are extended.
+///
 /// **Note**: Does not deny unauthenticated requests.
+#[derive(Clone)]
 pub struct AuthenticateUser {
     database: Arc<dyn Database>,
+    cache: Arc<dyn TokenCache>,
+    rate_limiter: Arc<dyn RateLimiter>,
+    skipped_paths: Arc<Vec<String>>,
+    refresh: Option<Arc<SessionRefresh>>,
 }
 
 impl AuthenticateUser {
-    pub fn new(database: Arc<dyn Database>) -> AuthenticateUser {
-        AuthenticateUser { database }
+    pub fn new(database: Arc<dyn Database>, cache: Arc<dyn TokenCache>) -> AuthenticateUser {
+        AuthenticateUser {
+            database,
+            cache,
+            rate_limiter: Arc::new(InMemoryRateLimiter::new()),
+            skipped_paths: Arc::new(Vec::new()),
+            refresh: None,
+        }
+    }
+
+    /// Count invalid tokens with the given limiter, e.g. one shared with
+    /// other instances. Defaults to counting in memory.
+    pub fn with_rate_limiter(mut self, rate_limiter: Arc<dyn RateLimiter>) -> AuthenticateUser {
+        self.rate_limiter = rate_limiter;
+        self
+    }
+
+    /// Don't authenticate requests to paths starting with any of the given
+    /// prefixes, e.g. `/health` for monitoring probes.
+    pub fn with_skipped_paths(mut self, prefixes: Vec<String>) -> AuthenticateUser {
+        self.skipped_paths = Arc::new(prefixes);
+        self
+    }
+
+    /// Extend login sessions that are used, so that they only expire once
+    /// they stop being used.
+    pub fn with_session_refresh(mut self, refresh: SessionRefresh) -> AuthenticateUser {
+        self.refresh = Some(Arc::new(refresh));
+        self
+    }
+}
+
+/// How login sessions are extended while they're being used.
+///
+/// A session used more than `refresh_after` since it was issued or last
+/// extended gets its full lifetime again, counted from now. If it lasts longer
+/// than an ordinary session the user asked to be remembered, so the cookie's
+/// expiry is updated too.
+#[derive(Debug, Clone)]
+pub struct SessionRefresh {
+    /// How long after being issued or extended a session is extended.
+    pub refresh_after: Duration,
+    /// How long an ordinary session lasts, i.e. one whose cookie is dropped
+    /// when the browser closes.
+    pub session_lifetime: Duration,
+    /// The attributes of the refreshed cookie.
+    pub cookies: CookieSettings,
+    /// The web root, which the cookie's path is taken from.
+    pub web_root: String,
+}
+
+impl SessionRefresh {
+    /// The new expiry of the session, and whether the cookie needs updating,
+    /// if it should be extended now.
+    fn refresh(&self, user: &TokenUser) -> Option<(chrono::DateTime<chrono::Utc>, bool)> {
+        let (issued, expires) = (user.issued?, user.expires?);
+        let now = chrono::Utc::now();
+
+        let refresh_after = chrono::Duration::from_std(self.refresh_after).ok()?;
+        if now - issued < refresh_after {
+            return None;
+        }
+
+        let lifetime = expires - issued;
+        let persistent = lifetime.to_std().ok()? > self.session_lifetime;
+
+        Some((now + lifetime, persistent))
     }
 }
 
-impl<S, B> Transform<S> for AuthenticateUser
+impl<S, B> Transform<S, ServiceRequest> for AuthenticateUser
 where
-    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
     B: 'static,
 {
-    type Request = ServiceRequest;
     type Response = ServiceResponse<B>;
     type Error = Error;
     type InitError = ();
@@ -47,7 +257,11 @@ where
     fn new_transform(&self, service: S) -> Self::Future {
         ok(AuthenticateUserService {
             database: self.database.clone(),
-            service: Rc::new(RefCell::new(service)),
+            cache: self.cache.clone(),
+            rate_limiter: self.rate_limiter.clone(),
+            skipped_paths: self.skipped_paths.clone(),
+            refresh: self.refresh.clone(),
+            service: Rc::new(service),
         })
         .boxed_local()
     }
@@ -55,7 +269,11 @@ where
 
 pub struct AuthenticateUserService<S> {
     database: Arc<dyn Database>,
-    service: Rc<RefCell<S>>,
+    cache: Arc<dyn TokenCache>,
+    rate_limiter: Arc<dyn RateLimiter>,
+    skipped_paths: Arc<Vec<String>>,
+    refresh: Option<Arc<SessionRefresh>>,
+    service: Rc<S>,
 }
 
 /// An authenticated user session.
@@ -66,78 +284,276 @@ pub struct AuthenticateUserService<S> {
 pub struct AuthenticatedUser {
     pub user_id: String,
     pub display_name: String,
+    /// What the token used to authenticate is allowed to do.
+    pub scopes: Vec<Scope>,
 }
 
-impl<S, B> Service for AuthenticateUserService<S>
+impl<S, B> Service<ServiceRequest> for AuthenticateUserService<S>
 where
     B: 'static,
-    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
 {
-    type Request = ServiceRequest;
     type Response = ServiceResponse<B>;
     type Error = Error;
     type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
 
-    fn poll_ready(&mut self, ctx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.service.borrow_mut().poll_ready(ctx)
-    }
+    forward_ready!(service);
 
-    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+    fn call(&self, req: ServiceRequest) -> Self::Future {
         let db = self.database.clone();
+        let cache = self.cache.clone();
+        let rate_limiter = self.rate_limiter.clone();
         let service = self.service.clone();
+        let mut refresh = None;
+
+        if has_prefix(req.path(), &self.skipped_paths) {
+            return service.call(req).boxed_local();
+        }
+
+        let bearer_token = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .filter(|value| value.starts_with("Bearer "))
+            .map(|value| value["Bearer ".len()..].trim().to_string());
 
-        let token = if let Some(token) = req.cookie("token") {
-            token.value().to_string()
+        let (token, source) = if let Some(token) = bearer_token {
+            (token, "bearer")
+        } else if let Some(token) = req.cookie(SESSION_COOKIE_NAME) {
+            // Only login sessions are extended, not API tokens.
+            refresh = self.refresh.clone();
+            (token.value().to_string(), "cookie")
         } else {
-            return service.borrow_mut().call(req).boxed_local();
+            return service.call(req).boxed_local();
         };
 
         async move {
-            let user_opt = db
-                .get_user_from_token(token)
-                .await
-                .map_err(error::ErrorInternalServerError)?;
-
-            if let Some(user) = user_opt {
-                let logger = req
-                    .extensions()
-                    .get::<Logger>()
-                    .expect("logger no longer installed in request")
-                    .clone();
-                let logger = logger.new(o!("user_id" => user.user_id.clone()));
-                info!(logger, "Authenticated user");
-                req.extensions_mut().insert(logger);
-
-                req.extensions_mut().insert(AuthenticatedUser {
-                    user_id: user.user_id,
-                    display_name: user.display_name,
+            set_request_stage(req.request(), RequestStage::Authenticating);
+            let logger = req
+                .extensions()
+                .get::<Logger>()
+                .cloned()
+                .ok_or(ShaftError::MissingExtension { name: "logger" })?;
+
+            // The cache is only an optimisation, so if it's unavailable the
+            // database is used.
+            let cached = cache.get(&token).await.unwrap_or_else(|e| {
+                warn!(logger, "Failed to read auth cache"; "err" => e.to_string());
+                None
+            });
+
+            let user_opt = match cached {
+                Some(user) => Some(user),
+                None => {
+                    let user_opt = db
+                        .get_user_from_token(token.clone())
+                        .await
+                        .context(DatabaseError)?;
+                    if let Some(user) = &user_opt {
+                        if let Err(e) = cache.insert(token.clone(), user.clone()).await {
+                            warn!(logger, "Failed to update auth cache"; "err" => e.to_string());
+                        }
+                    }
+                    user_opt
+                }
+            };
+
+            let mut refreshed_cookie = None;
+            if let (Some(user), Some(refresh)) = (&user_opt, &refresh) {
+                if let Some((expires, persistent)) = refresh.refresh(user) {
+                    db.refresh_token(token.clone(), chrono::Utc::now(), expires)
+                        .await
+                        .context(DatabaseError)?;
+                    cache
+                        .invalidate_token(&token)
+                        .await
+                        .context(SharedStateError)?;
+
+                    if persistent {
+                        let cookie = SessionCookie::with_settings(
+                            &refresh.cookies,
+                            &refresh.web_root,
+                            &token,
+                        )
+                        .expires(expires);
+                        refreshed_cookie = Some(cookie.to_string());
+                    }
+                }
+            }
+
+            let user_id = match user_opt {
+                Some(user) => {
+                    let logger = logger.new(o!("user_id" => user.user_id.clone()));
+                    info!(logger, "Authenticated user");
+                    req.extensions_mut().insert(logger);
+
+                    req.extensions_mut().insert(AuthenticatedUser {
+                        user_id: user.user_id.clone(),
+                        display_name: user.display_name,
+                        scopes: user.scopes,
+                    });
+                    user.user_id
+                }
+                None => {
+                    // Keyed on the peer, as forwarded addresses can be
+                    // forged to dodge the limit.
+                    let ip = req
+                        .peer_addr()
+                        .map(|addr| addr.ip().to_string())
+                        .unwrap_or_else(|| "unknown".to_string());
+                    let retry_after = rate_limiter
+                        .hit(
+                            format!("invalid_token:{}", ip),
+                            INVALID_TOKEN_LIMIT,
+                            INVALID_TOKEN_WINDOW,
+                        )
+                        .await
+                        .context(SharedStateError)?;
+                    if let Some(retry_after) = retry_after {
+                        return Err(ShaftError::RateLimited {
+                            message: "Too many requests with invalid tokens".to_string(),
+                            retry_after_secs: retry_after.as_secs().max(1) as i64,
+                        }
+                        .into());
+                    }
+
+                    record_security_event(
+                        &*db,
+                        &logger,
+                        req.request(),
+                        SecurityEventKind::InvalidToken,
+                        None,
+                        Some(format!("Unknown or expired {} token", source)),
+                    )
+                    .await;
+
+                    set_request_stage(req.request(), RequestStage::Handling);
+                    return service.call(req).await;
+                }
+            };
+
+            set_request_stage(req.request(), RequestStage::Handling);
+            let mut res = service.call(req).await?;
+
+            let admin_denied = res
+                .response()
+                .error()
+                .and_then(|err| err.as_error::<ShaftError>())
+                .map_or(false, |err| {
+                    matches!(
+                        err,
+                        ShaftError::MissingScope {
+                            scope: Scope::Admin
+                        }
+                    )
                 });
+            if admin_denied {
+                record_security_event(
+                    &*db,
+                    &logger,
+                    res.request(),
+                    SecurityEventKind::AdminDenied,
+                    Some(user_id),
+                    Some(format!(
+                        "{} {}",
+                        res.request().method(),
+                        res.request().path()
+                    )),
+                )
+                .await;
+            }
+
+            // Don't clobber the handler's own cookie, e.g. on logout.
+            if let Some(cookie) = refreshed_cookie {
+                if !res.headers().contains_key(SET_COOKIE) {
+                    let value =
+                        HeaderValue::from_str(&cookie).map_err(error::ErrorInternalServerError)?;
+                    res.headers_mut().insert(SET_COOKIE, value);
+                }
             }
 
-            service.borrow_mut().call(req).await
+            Ok(res)
         }
         .boxed_local()
     }
 }
 
+/// Whether the path starts with any of the prefixes.
+pub(super) fn has_prefix(path: &str, prefixes: &[String]) -> bool {
+    prefixes
+        .iter()
+        .any(|prefix| path.starts_with(prefix.as_str()))
+}
+
+/// Builds the error that redirects unauthenticated requests to the login page.
+pub(super) fn login_redirect(req: &HttpRequest) -> Error {
+    let state = match app_state(req) {
+        Ok(state) => state,
+        Err(err) => return err.into(),
+    };
+
+    // Only pages can be returned to, not form submissions.
+    let next = if req.method() == Method::GET {
+        req.uri()
+            .path_and_query()
+            .map(|path| relative_path(path.as_str(), &state.config.web_root))
+    } else {
+        None
+    };
+    let login_url = format!("{}/login{}", state.config.web_root, next_query(next));
+
+    let resp = HttpResponse::Found()
+        .insert_header((LOCATION, login_url))
+        .finish();
+    error::InternalError::from_response("Please login", resp).into()
+}
+
 impl FromRequest for AuthenticatedUser {
-    type Config = ();
     type Error = Error;
     type Future = futures::future::LocalBoxFuture<'static, Result<AuthenticatedUser, Error>>;
 
     fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
-        let root = &req.app_data::<AppState>().unwrap().config.web_root;
-        let login_url = format!("{}/login", root);
-
         let res = req
             .extensions()
             .get::<AuthenticatedUser>()
             .map(Clone::clone)
-            .ok_or_else(|| {
-                let resp = HttpResponse::Found().header(LOCATION, login_url).finish();
-                error::InternalError::from_response("Please login", resp).into()
-            });
+            .ok_or_else(|| login_redirect(req));
+
+        async { res }.boxed_local()
+    }
+}
+
+/// Permission to view the ledger.
+///
+/// Implements FromRequest so can be used as an extractor for read only
+/// endpoints. Requires a valid session unless the `public_read` feature is
+/// enabled, in which case logged out requests are also allowed. Logged in requests must
+/// have the `read` scope.
+#[derive(Clone)]
+pub struct ReadAccess {
+    /// The user making the request, if logged in.
+    pub user: Option<AuthenticatedUser>,
+}
+
+impl FromRequest for ReadAccess {
+    type Error = Error;
+    type Future = futures::future::LocalBoxFuture<'static, Result<ReadAccess, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let user = req.extensions().get::<AuthenticatedUser>().cloned();
+        let public_read = match app_state(req) {
+            Ok(state) => state.features.is_enabled(Feature::PublicRead),
+            Err(e) => return err(e.into()).boxed_local(),
+        };
+
+        let res = match user {
+            Some(user) => authz::require_scope(&user, Scope::Read)
+                .map(|()| ReadAccess { user: Some(user) })
+                .map_err(Error::from),
+            None if public_read => Ok(ReadAccess { user: None }),
+            None => Err(login_redirect(req)),
+        };
 
         async { res }.boxed_local()
     }
@@ -0,0 +1,37 @@
+//! Decides what an authenticated request is allowed to do, based on the scopes
+//! of the token used.
+
+use crate::db::Scope;
+use crate::error::ShaftError;
+use crate::rest::{AppConfig, AuthenticatedUser};
+
+/// The scopes given to a new login session for the user.
+///
+/// Admin scope is only granted to users listed as admins in the config, and
+/// is fixed when the session is created.
+pub fn session_scopes(config: &AppConfig, user_id: &str) -> Vec<Scope> {
+    let mut scopes = vec![Scope::Read, Scope::Write];
+    if config.admins.iter().any(|admin| admin == user_id) {
+        scopes.push(Scope::Admin);
+    }
+    scopes
+}
+
+/// Check the request's token grants the given scope.
+pub fn require_scope(user: &AuthenticatedUser, scope: Scope) -> Result<(), ShaftError> {
+    if user.scopes.iter().any(|granted| granted.grants(scope)) {
+        Ok(())
+    } else {
+        Err(ShaftError::MissingScope { scope })
+    }
+}
+
+/// Check the request's token grants all the given scopes, e.g. before
+/// creating a new token with them. This stops tokens being used to escalate
+/// their own access.
+pub fn require_scopes(user: &AuthenticatedUser, scopes: &[Scope]) -> Result<(), ShaftError> {
+    for scope in scopes {
+        require_scope(user, *scope)?;
+    }
+    Ok(())
+}
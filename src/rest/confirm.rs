@@ -0,0 +1,96 @@
+//! Short lived tokens used to confirm destructive actions.
+
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::shared_state::{SharedState, SharedStateError};
+
+/// Tokens a user must send back to confirm an action, e.g. deleting their
+/// account. Each user has at most one outstanding token, which can only be
+/// used once.
+///
+/// Tokens are kept in the [SharedState], so one issued by one instance can be
+/// used on another.
+pub struct ConfirmationTokens {
+    /// Distinguishes these tokens from others in the shared state.
+    kind: &'static str,
+    ttl: Duration,
+    store: Arc<dyn SharedState>,
+}
+
+impl ConfirmationTokens {
+    /// Create a store whose tokens are valid for `ttl`.
+    pub fn new(
+        kind: &'static str,
+        ttl: Duration,
+        store: Arc<dyn SharedState>,
+    ) -> ConfirmationTokens {
+        ConfirmationTokens { kind, ttl, store }
+    }
+
+    /// How long issued tokens are valid for.
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    fn token_key(&self, token: &str) -> String {
+        format!("{}:token:{}", self.kind, token)
+    }
+
+    fn user_key(&self, user_id: &str) -> String {
+        format!("{}:user:{}", self.kind, user_id)
+    }
+
+    /// Issue a new token for the user, replacing any previous one.
+    pub async fn issue(&self, user_id: &str) -> Result<String, SharedStateError> {
+        let token: String = thread_rng().sample_iter(&Alphanumeric).take(32).collect();
+
+        if let Some(previous) = self.store.take(self.user_key(user_id)).await? {
+            self.store.take(self.token_key(&previous)).await?;
+        }
+
+        self.store
+            .put(self.token_key(&token), user_id.to_string(), self.ttl)
+            .await?;
+        self.store
+            .put(self.user_key(user_id), token.clone(), self.ttl)
+            .await?;
+
+        Ok(token)
+    }
+
+    /// Check the token is the user's current one, using it up if so. Wrong
+    /// tokens, including other users' ones, leave the user's token alone.
+    pub async fn consume(&self, user_id: &str, token: &str) -> Result<bool, SharedStateError> {
+        match self.store.take(self.user_key(user_id)).await? {
+            Some(current) if current == token => {
+                // The token's own key expires when the token does.
+                let owner = self.store.take(self.token_key(token)).await?;
+                Ok(owner.as_deref() == Some(user_id))
+            }
+            Some(current) => {
+                // Put it back. It still can't outlive its token key.
+                self.store
+                    .put(self.user_key(user_id), current, self.ttl)
+                    .await?;
+                Ok(false)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Find the user the token was issued to, using it up. For when the
+    /// token is sent from somewhere the user isn't logged in.
+    pub async fn redeem(&self, token: &str) -> Result<Option<String>, SharedStateError> {
+        let user_id = match self.store.take(self.token_key(token)).await? {
+            Some(user_id) => user_id,
+            None => return Ok(None),
+        };
+        self.store.take(self.user_key(&user_id)).await?;
+
+        Ok(Some(user_id))
+    }
+}
@@ -0,0 +1,134 @@
+//! Enforces an overall deadline on each request.
+
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{Error, HttpMessage, HttpRequest};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use futures::FutureExt;
+use slog::Logger;
+
+use std::cell::Cell;
+use std::fmt;
+use std::rc::Rc;
+use std::time::Duration;
+
+use crate::error::ShaftError;
+
+/// What a request was doing, reported when it misses its deadline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestStage {
+    /// Looking up the user's session.
+    Authenticating,
+    /// Running the handler.
+    Handling,
+    /// Waiting on an upstream service, e.g. GitHub.
+    Upstream,
+}
+
+impl RequestStage {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RequestStage::Authenticating => "authenticating",
+            RequestStage::Handling => "handling",
+            RequestStage::Upstream => "upstream",
+        }
+    }
+}
+
+impl fmt::Display for RequestStage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The current stage of a request, installed into the request extensions by
+/// [RequestDeadline].
+#[derive(Clone)]
+struct StageTracker(Rc<Cell<RequestStage>>);
+
+/// Records what the request is currently doing, so that it can be reported if
+/// the request times out. Does nothing if [RequestDeadline] isn't registered.
+pub fn set_request_stage(req: &HttpRequest, stage: RequestStage) {
+    if let Some(StageTracker(tracker)) = req.extensions().get::<StageTracker>() {
+        tracker.set(stage);
+    }
+}
+
+/// A middleware that fails requests that take longer than the given timeout,
+/// so that a stuck database query or upstream call can't tie up a worker.
+///
+/// Requests stuck waiting on an upstream service get a 504, anything else a
+/// 503.
+#[derive(Clone)]
+pub struct RequestDeadline {
+    timeout: Duration,
+}
+
+impl RequestDeadline {
+    pub fn new(timeout: Duration) -> RequestDeadline {
+        RequestDeadline { timeout }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequestDeadline
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestDeadlineService<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestDeadlineService {
+            timeout: self.timeout,
+            service,
+        })
+    }
+}
+
+pub struct RequestDeadlineService<S> {
+    timeout: Duration,
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestDeadlineService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let tracker = Rc::new(Cell::new(RequestStage::Handling));
+        req.extensions_mut().insert(StageTracker(tracker.clone()));
+
+        let logger = req.extensions().get::<Logger>().cloned();
+        let timeout = self.timeout;
+
+        let fut = self.service.call(req);
+        async move {
+            match actix_web::rt::time::timeout(timeout, fut).await {
+                Ok(res) => res,
+                Err(_) => {
+                    let stage = tracker.get();
+                    if let Some(logger) = logger {
+                        warn!(
+                            logger, "Request exceeded deadline";
+                            "stage" => stage.as_str(), "timeout_secs" => timeout.as_secs_f64(),
+                        );
+                    }
+                    Err(ShaftError::DeadlineExceeded { stage }.into())
+                }
+            }
+        }
+        .boxed_local()
+    }
+}
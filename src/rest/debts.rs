@@ -0,0 +1,240 @@
+//! Summarising who owes whom, to nudge people to settle up.
+//!
+//! `GET /api/debts` returns the largest outstanding debts between pairs of
+//! users, and those that have gone unsettled the longest:
+//!
+//! ```json
+//! {
+//!   "largest": [{
+//!     "debtor": "bob",
+//!     "debtor_name": "Bob",
+//!     "creditor": "alice",
+//!     "creditor_name": "Alice",
+//!     "amount": 1050,
+//!     "since": 1589040000,
+//!     "last_settled": null
+//!   }],
+//!   "longest_unsettled": [...]
+//! }
+//! ```
+//!
+//! `since` is when the pair last went from even to owing, and `last_settled`
+//! when they were last even, or null if never. Each list has at most `limit`
+//! entries, 5 by default.
+//!
+//! If the `debt_digest` setting is given, the same summary for the default
+//! ledger is emailed to its recipients every week or month, counted from when
+//! the server started.
+
+use actix_web::web::ServiceConfig;
+use actix_web::{web, Error};
+use linear_map::LinearMap;
+use serde::{Deserialize, Serialize};
+use slog::Logger;
+use snafu::ResultExt;
+
+use std::fmt::Write;
+
+use crate::db::{self, Database, DEFAULT_LEDGER_ID};
+use crate::error::DatabaseError;
+use crate::mailer::Email;
+use crate::money::{self, Money};
+use crate::rest::response::ApiJson;
+use crate::rest::{AppState, CurrentLedger, ReadAccess};
+use crate::settings::{CurrencySettings, DebtDigestSettings};
+
+/// How many debts each list has if no limit is given.
+const DEFAULT_DEBTS_LIMIT: usize = 5;
+
+/// The most debts each list may have.
+const MAX_DEBTS_LIMIT: usize = 100;
+
+/// Register servlets with HTTP app
+pub fn register_servlets(config: &mut ServiceConfig) {
+    config.route("/api/debts", web::get().to(get_debts));
+}
+
+/// The query parameters of `GET /api/debts`.
+#[derive(Deserialize)]
+struct DebtsQuery {
+    limit: Option<usize>,
+}
+
+/// A debt between two users, with their display names.
+#[derive(Debug, Serialize)]
+struct DebtEntry {
+    debtor: String,
+    debtor_name: String,
+    creditor: String,
+    creditor_name: String,
+    amount: Money,
+    /// Unix timestamp in seconds.
+    since: i64,
+    /// Unix timestamp in seconds.
+    last_settled: Option<i64>,
+}
+
+/// The largest and longest unsettled debts.
+#[derive(Debug, Serialize)]
+struct DebtSummary {
+    largest: Vec<DebtEntry>,
+    longest_unsettled: Vec<DebtEntry>,
+}
+
+impl DebtSummary {
+    /// Summarise the ledger's outstanding debts, listing at most `limit` in
+    /// each section.
+    async fn for_ledger(
+        database: &dyn Database,
+        ledger_id: i64,
+        limit: usize,
+    ) -> Result<DebtSummary, db::DatabaseError> {
+        let (debts, users) = futures::future::try_join(
+            database.get_outstanding_debts(ledger_id),
+            database.get_all_users(ledger_id),
+        )
+        .await?;
+
+        Ok(DebtSummary::from_debts(debts, &users, limit))
+    }
+
+    /// Summarise the debts, which must be ordered largest first.
+    fn from_debts(
+        mut debts: Vec<db::Debt>,
+        users: &LinearMap<String, db::User>,
+        limit: usize,
+    ) -> DebtSummary {
+        let entry = |debt: &db::Debt| {
+            let display_name = |user_id: &String| {
+                users
+                    .get(user_id)
+                    .map(|user| user.display_name.clone())
+                    .unwrap_or_else(|| user_id.clone())
+            };
+
+            DebtEntry {
+                debtor_name: display_name(&debt.debtor),
+                debtor: debt.debtor.clone(),
+                creditor_name: display_name(&debt.creditor),
+                creditor: debt.creditor.clone(),
+                amount: debt.amount,
+                since: debt.since.timestamp(),
+                last_settled: debt.last_settled.map(|time| time.timestamp()),
+            }
+        };
+
+        let largest = debts.iter().take(limit).map(entry).collect();
+
+        debts.sort_by_key(|debt| debt.since);
+        let longest_unsettled = debts.iter().take(limit).map(entry).collect();
+
+        DebtSummary {
+            largest,
+            longest_unsettled,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.largest.is_empty()
+    }
+
+    /// The plain text body of the digest email.
+    fn email_text(
+        &self,
+        currency: &CurrencySettings,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> String {
+        let mut text = String::from("Largest outstanding debts:\n\n");
+        for debt in &self.largest {
+            writeln!(
+                text,
+                "  {} owes {} {}",
+                debt.debtor_name,
+                debt.creditor_name,
+                money::format(debt.amount, currency),
+            )
+            .expect("writing to a string");
+        }
+
+        text.push_str("\nLongest unsettled:\n\n");
+        for debt in &self.longest_unsettled {
+            let days = (now.timestamp() - debt.since) / (24 * 60 * 60);
+            writeln!(
+                text,
+                "  {} has owed {} for {} days ({})",
+                debt.debtor_name,
+                debt.creditor_name,
+                days,
+                money::format(debt.amount, currency),
+            )
+            .expect("writing to a string");
+        }
+
+        text.push_str("\nPlease settle up!\n");
+        text
+    }
+}
+
+/// Get the current ledger's largest and longest unsettled debts.
+async fn get_debts(
+    (state, _access, ledger, query): (
+        web::Data<AppState>,
+        ReadAccess,
+        CurrentLedger,
+        web::Query<DebtsQuery>,
+    ),
+) -> Result<ApiJson<impl Serialize>, Error> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_DEBTS_LIMIT)
+        .min(MAX_DEBTS_LIMIT);
+
+    let summary = DebtSummary::for_ledger(&*state.database, ledger.id, limit)
+        .await
+        .context(DatabaseError)?;
+
+    Ok(ApiJson(summary))
+}
+
+/// Email the default ledger's debt summary to the digest's recipients, if
+/// anyone owes anything. Returns whether it was sent.
+async fn send_debt_digest(
+    state: &AppState,
+    settings: &DebtDigestSettings,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let summary =
+        DebtSummary::for_ledger(&*state.database, DEFAULT_LEDGER_ID, settings.limit).await?;
+    if summary.is_empty() {
+        return Ok(false);
+    }
+
+    let text = summary.email_text(&state.config.currency, chrono::Utc::now());
+    for recipient in &settings.recipients {
+        state
+            .mailer
+            .send(Email {
+                to: recipient.clone(),
+                subject: "Outstanding debts on shaft".to_string(),
+                text: text.clone(),
+            })
+            .await?;
+    }
+
+    Ok(true)
+}
+
+/// Send the debt digest every period in the background, logging failures.
+/// Must be called from within the actix runtime.
+pub fn spawn_debt_digest(state: AppState, settings: DebtDigestSettings, logger: Logger) {
+    actix_web::rt::spawn(async move {
+        loop {
+            actix_web::rt::time::sleep(settings.period.duration()).await;
+
+            match send_debt_digest(&state, &settings).await {
+                Ok(true) => info!(logger, "Sent debt digest"),
+                Ok(false) => {}
+                Err(e) => warn!(logger, "Failed to send debt digest"; "err" => e.to_string()),
+            }
+        }
+    });
+}
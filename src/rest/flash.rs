@@ -0,0 +1,150 @@
+//! One-off messages shown on the next page, e.g. to confirm a form was
+//! submitted after redirecting away from it.
+//!
+//! Messages are kept in the [SharedState](crate::shared_state::SharedState),
+//! so a message set by one instance can be shown by another, and the browser
+//! is given a cookie with a random ID to look it up. The page that shows the
+//! message removes it, so it's only shown once.
+
+use actix_web::{HttpRequest, HttpResponseBuilder};
+use chrono::TimeZone;
+use hyper::header::SET_COOKIE;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+
+use std::time::Duration;
+
+use crate::api_types::ShaftUserBody;
+use crate::rest::{AppConfig, AppState, SessionCookie};
+use crate::shared_state::SharedStateError;
+
+/// The name of the cookie holding the ID of the message.
+pub const FLASH_COOKIE_NAME: &str = "flash";
+
+/// How long a message is kept for. It only needs to last until the redirect
+/// is followed.
+const FLASH_TTL: Duration = Duration::from_secs(60);
+
+/// How the message is styled. Serialized as the matching bootstrap alert
+/// class.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FlashLevel {
+    #[serde(rename = "success")]
+    Success,
+    #[serde(rename = "info")]
+    Info,
+    #[serde(rename = "danger")]
+    Error,
+}
+
+/// A message to show on the next page.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Flash {
+    pub level: FlashLevel,
+    pub message: String,
+    /// What was entered in the shaft form, if the message is why it was
+    /// rejected. The form is then filled back in and the message shown next
+    /// to it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub form: Option<ShaftUserBody>,
+}
+
+impl Flash {
+    pub fn success(message: impl Into<String>) -> Flash {
+        Flash {
+            level: FlashLevel::Success,
+            message: message.into(),
+            form: None,
+        }
+    }
+
+    pub fn info(message: impl Into<String>) -> Flash {
+        Flash {
+            level: FlashLevel::Info,
+            message: message.into(),
+            form: None,
+        }
+    }
+
+    pub fn error(message: impl Into<String>) -> Flash {
+        Flash {
+            level: FlashLevel::Error,
+            message: message.into(),
+            form: None,
+        }
+    }
+
+    /// Attach the rejected shaft form submission.
+    pub fn with_form(mut self, form: ShaftUserBody) -> Flash {
+        self.form = Some(form);
+        self
+    }
+}
+
+fn flash_key(id: &str) -> String {
+    format!("flash:{}", id)
+}
+
+fn flash_cookie(config: &AppConfig, id: &str) -> SessionCookie {
+    SessionCookie::new(config, id).name(FLASH_COOKIE_NAME)
+}
+
+/// Store the message, adding the cookie that shows it on the next page to
+/// the response.
+pub async fn set_flash(
+    state: &AppState,
+    builder: &mut HttpResponseBuilder,
+    flash: &Flash,
+) -> Result<(), SharedStateError> {
+    let id: String = thread_rng().sample_iter(&Alphanumeric).take(32).collect();
+    let value = serde_json::to_string(flash).expect("flash serializes");
+
+    state
+        .shared_state
+        .put(flash_key(&id), value, FLASH_TTL)
+        .await?;
+
+    let expires =
+        chrono::Utc::now() + chrono::Duration::from_std(FLASH_TTL).expect("flash TTL in range");
+    builder.append_header((
+        SET_COOKIE,
+        flash_cookie(&state.config, &id)
+            .expires(expires)
+            .to_string(),
+    ));
+
+    Ok(())
+}
+
+/// Whether the request may have a message to show.
+pub fn has_flash(req: &HttpRequest) -> bool {
+    req.cookie(FLASH_COOKIE_NAME).is_some()
+}
+
+/// Get the request's message, if it has one that hasn't been shown or
+/// expired. The message is removed from the store, and the cookie removed by
+/// the response.
+pub async fn take_flash(
+    state: &AppState,
+    req: &HttpRequest,
+    builder: &mut HttpResponseBuilder,
+) -> Result<Option<Flash>, SharedStateError> {
+    let cookie = match req.cookie(FLASH_COOKIE_NAME) {
+        Some(cookie) => cookie,
+        None => return Ok(None),
+    };
+
+    builder.append_header((
+        SET_COOKIE,
+        flash_cookie(&state.config, "")
+            .expires(chrono::Utc.timestamp(0, 0))
+            .to_string(),
+    ));
+
+    let value = state.shared_state.take(flash_key(cookie.value())).await?;
+
+    // Anything that doesn't parse was stored by a different version, and is
+    // only a message so can be dropped.
+    Ok(value.and_then(|value| serde_json::from_str(&value).ok()))
+}
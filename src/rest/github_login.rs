@@ -1,16 +1,26 @@
 //! Handles login flow using Github OAuth.
 
+use actix_web::http::StatusCode;
 use actix_web::web::ServiceConfig;
-use actix_web::{error, web, Error, HttpResponse};
-use futures_util::future::TryFutureExt;
+use actix_web::{web, Error, HttpRequest, HttpResponse, ResponseError};
 use hyper;
 use serde::Deserialize;
+use serde_json::json;
+use slog::Logger;
+use snafu::{ResultExt, Snafu};
 use url::Url;
 
-use std::sync::Arc;
-
-use crate::github::{GenericHttpClient, GithubApi};
-use crate::rest::{get_expires_string, AppState};
+use crate::error::{DatabaseError, EventError};
+use crate::events::Event;
+use crate::features::Feature;
+use crate::github::{GithubAccessTokenResponse, GithubApi};
+use crate::http_client::HttpError;
+use crate::rest::security::{record_security_event, SecurityEventKind};
+use crate::rest::views::LoginProvider;
+use crate::rest::{
+    authz, set_request_stage, validate_next, AppState, PageContext, ReqLogger, RequestStage,
+    SessionCookie,
+};
 
 /// Register servlets with HTTP app
 pub fn register_servlets(config: &mut ServiceConfig) {
@@ -18,19 +28,59 @@ pub fn register_servlets(config: &mut ServiceConfig) {
     config.route("/github/callback", web::get().to(github_callback));
 }
 
+/// How the login page offers logging in with GitHub.
+pub(super) fn login_provider() -> LoginProvider {
+    LoginProvider {
+        name: "GitHub",
+        icon_url: None,
+        login_path: "github/login",
+    }
+}
+
+/// Query parameters for `/github/login`
+#[derive(Deserialize)]
+struct GithubLoginQuery {
+    /// The path to return to once logged in.
+    next: Option<String>,
+    /// Whether the user asked to stay logged in after closing their browser.
+    #[serde(default)]
+    remember: bool,
+}
+
 /// Handles inbound `/github/login` request to start OAuth flow.
-async fn github_login(state: web::Data<AppState>) -> Result<HttpResponse, Error> {
+///
+/// The path to return to afterwards and whether to remember the user are
+/// carried through GitHub in the `state` parameter, form encoded after the
+/// configured state string.
+async fn github_login(
+    (state, query): (web::Data<AppState>, web::Query<GithubLoginQuery>),
+) -> Result<HttpResponse, Error> {
     let mut gh = Url::parse("https://github.com/login/oauth/authorize").expect("valid url");
 
+    let mut login_params = url::form_urlencoded::Serializer::new(String::new());
+    if let Some(next) = query.next.as_deref().and_then(validate_next) {
+        login_params.append_pair("next", next);
+    }
+    if query.remember {
+        login_params.append_pair("remember", "1");
+    }
+    let login_params = login_params.finish();
+
+    let oauth_state = if login_params.is_empty() {
+        state.config.github_state.clone()
+    } else {
+        format!("{}:{}", state.config.github_state, login_params)
+    };
+
     gh.query_pairs_mut()
         .append_pair("client_id", &state.config.github_client_id)
-        .append_pair("state", &state.config.github_state)
+        .append_pair("state", &oauth_state)
         .append_pair("scope", "read:org");
 
     let redirect_url = gh.to_string();
 
     Ok(HttpResponse::Found()
-        .header(hyper::header::LOCATION, redirect_url.clone())
+        .insert_header((hyper::header::LOCATION, redirect_url.clone()))
         .body(format!("Redirecting to {}\n", &redirect_url)))
 }
 
@@ -39,36 +89,196 @@ async fn github_login(state: web::Data<AppState>) -> Result<HttpResponse, Error>
 struct GithubCallbackRequest {
     /// Code that can be exchanged for a user token.
     code: String,
-    /// A string that we expect to match the configured state string.
+    /// A string that we expect to start with the configured state string,
+    /// optionally followed by `:` and the form encoded [LoginParams].
     state: String,
 }
 
+/// What the user asked for when starting the login, as passed through GitHub.
+#[derive(Debug, Default)]
+struct LoginParams {
+    /// The path to return to once logged in.
+    next: Option<String>,
+    /// Whether to keep the user logged in after closing their browser.
+    remember: bool,
+}
+
+impl LoginParams {
+    /// Parse the part of the OAuth state after the configured state string.
+    fn parse(params: &str) -> LoginParams {
+        let mut login_params = LoginParams::default();
+        for (key, value) in url::form_urlencoded::parse(params.as_bytes()) {
+            match &*key {
+                "next" => login_params.next = validate_next(&value).map(str::to_string),
+                "remember" => login_params.remember = value == "1",
+                _ => {}
+            }
+        }
+        login_params
+    }
+}
+
+/// Why a login attempt failed. The message is shown to the user, so should
+/// tell them what they can do about it.
+#[derive(Debug, Snafu)]
+enum LoginError {
+    #[snafu(display("The login request didn't come from GitHub. Please try again."))]
+    StateMismatch,
+
+    #[snafu(display("GitHub didn't accept the login ({}). Please try again.", error))]
+    BadCode { error: String },
+
+    #[snafu(display("We couldn't reach GitHub. Please try again later."))]
+    GithubUnavailable { source: HttpError },
+
+    #[snafu(display(
+        "GitHub has been having problems, so logging in is paused. Please try again in a few minutes."
+    ))]
+    GithubFailing,
+
+    #[snafu(display(
+        "You must be a member of the {} GitHub organization to use shaft.",
+        org
+    ))]
+    NotInOrg { org: String, github_user: String },
+
+    #[snafu(display("Shaft isn't accepting new users."))]
+    RegistrationClosed,
+}
+
+impl ResponseError for LoginError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            LoginError::StateMismatch | LoginError::BadCode { .. } => StatusCode::BAD_REQUEST,
+            LoginError::GithubUnavailable { .. } => StatusCode::BAD_GATEWAY,
+            LoginError::GithubFailing => StatusCode::SERVICE_UNAVAILABLE,
+            LoginError::NotInOrg { .. } | LoginError::RegistrationClosed => StatusCode::FORBIDDEN,
+        }
+    }
+}
+
 /// Handles inbound `/github/callback` request from github that includes code we
 /// can exchange for a user's access token.
+///
+/// If the login fails the user is shown an error page explaining why.
 async fn github_callback(
-    (query, state): (web::Query<GithubCallbackRequest>, web::Data<AppState>),
+    (req, query, state, ReqLogger(logger)): (
+        HttpRequest,
+        web::Query<GithubCallbackRequest>,
+        web::Data<AppState>,
+        ReqLogger,
+    ),
 ) -> Result<HttpResponse, Error> {
-    if query.state != state.config.github_state {
-        let res = HttpResponse::BadRequest().body("State param mismatch");
-        return Ok(res);
+    match complete_login(&req, &query, &state).await {
+        Err(err) => match err.as_error::<LoginError>() {
+            Some(login_err) => {
+                record_login_failure(&req, &state, &logger, login_err).await;
+                Ok(login_error_page(&state, &logger, login_err))
+            }
+            None => Err(err),
+        },
+        res => res,
     }
+}
 
-    let http_client = state.http_client.clone();
-    let gh_api: GithubApi<Arc<dyn GenericHttpClient>> = GithubApi { http_client };
+/// Record the failed login as a security event if it looks like abuse,
+/// rather than e.g. GitHub being down.
+async fn record_login_failure(
+    req: &HttpRequest,
+    state: &AppState,
+    logger: &Logger,
+    err: &LoginError,
+) {
+    let (kind, user_id) = match err {
+        LoginError::StateMismatch => (SecurityEventKind::LoginStateMismatch, None),
+        LoginError::NotInOrg { github_user, .. } => {
+            (SecurityEventKind::LoginDenied, Some(github_user.clone()))
+        }
+        _ => return,
+    };
 
-    let callback = gh_api
+    record_security_event(
+        &*state.database,
+        logger,
+        req,
+        kind,
+        user_id,
+        Some(err.to_string()),
+    )
+    .await;
+}
+
+/// Render the page telling the user why their login failed, falling back to
+/// plain text if the template is missing.
+fn login_error_page(state: &AppState, logger: &Logger, err: &LoginError) -> HttpResponse {
+    warn!(logger, "Login failed"; "err" => format!("{:?}", err));
+
+    let page = json!({ "message": err.to_string() });
+
+    match PageContext::new(state).render(&state.handlebars, "login_error", &page) {
+        Ok(body) => HttpResponse::build(err.status_code())
+            .content_type("text/html")
+            .body(body),
+        Err(e) => {
+            warn!(logger, "Failed to render login error page"; "err" => e.to_string());
+            err.error_response()
+        }
+    }
+}
+
+/// The login error for a failed request to GitHub, distinguishing requests
+/// that weren't sent because GitHub has been failing.
+fn github_error(source: HttpError) -> LoginError {
+    match source {
+        HttpError::CircuitOpen { .. } => LoginError::GithubFailing,
+        source => LoginError::GithubUnavailable { source },
+    }
+}
+
+/// Exchange the code from GitHub for the user's details, creating the user
+/// if this is their first login, and start a session for them.
+async fn complete_login(
+    req: &HttpRequest,
+    query: &GithubCallbackRequest,
+    state: &AppState,
+) -> Result<HttpResponse, Error> {
+    let login_params = match query.state.strip_prefix(state.config.github_state.as_str()) {
+        Some("") => LoginParams::default(),
+        Some(rest) => match rest.strip_prefix(':') {
+            Some(params) => LoginParams::parse(params),
+            None => return Err(LoginError::StateMismatch.into()),
+        },
+        None => return Err(LoginError::StateMismatch.into()),
+    };
+
+    let gh_api = GithubApi {
+        http_client: state.http_client.clone(),
+    };
+
+    set_request_stage(req, RequestStage::Upstream);
+    let callback = match gh_api
         .exchange_oauth_code(
             &state.config.github_client_id,
             &state.config.github_client_secret,
             &query.code,
         )
         .await
-        .map_err(error::ErrorServiceUnavailable)?;
+        .map_err(github_error)?
+    {
+        GithubAccessTokenResponse::Granted(callback) => callback,
+        GithubAccessTokenResponse::Denied(denied) => {
+            return Err(LoginError::BadCode {
+                error: denied.error_description.unwrap_or(denied.error),
+            }
+            .into())
+        }
+    };
 
     let user = gh_api
         .get_authenticated_user(&callback.access_token)
         .await
-        .map_err(error::ErrorInternalServerError)?;
+        .map_err(github_error)?;
+    set_request_stage(req, RequestStage::Handling);
 
     let github_user_id = user.login.clone();
     let github_name = user.name.clone();
@@ -76,49 +286,97 @@ async fn github_callback(
     let user_id_opt = state
         .database
         .get_user_by_github_id(user.login)
-        .map_err(error::ErrorInternalServerError)
-        .await?;
+        .await
+        .context(DatabaseError)?;
 
     let user_id = if let Some(user_id) = user_id_opt {
         user_id
+    } else if !state.features.is_enabled(Feature::RegistrationOpen) {
+        return Err(LoginError::RegistrationClosed.into());
     } else {
+        set_request_stage(req, RequestStage::Upstream);
         let opt = gh_api
             .get_if_member_of_org(&callback.access_token, &state.config.required_org)
-            .map_err(error::ErrorInternalServerError)
-            .await?;
+            .await
+            .map_err(github_error)?;
+        set_request_stage(req, RequestStage::Handling);
 
         if opt.is_some() {
-            state
+            let display_name = github_name.unwrap_or_else(|| github_user_id.clone());
+            let outbox = state.events.outbox({
+                let display_name = display_name.clone();
+                move |user_id: &String| {
+                    vec![Event::UserAdded {
+                        user_id: user_id.clone(),
+                        display_name,
+                    }]
+                }
+            });
+            let user_id = state
                 .database
-                .add_user_by_github_id(
-                    github_user_id.clone(),
-                    github_name.unwrap_or(github_user_id),
-                )
-                .map_err(error::ErrorInternalServerError)
-                .await?
+                .add_user_by_github_id(github_user_id, display_name, outbox.clone())
+                .await
+                .context(DatabaseError)?;
+
+            state
+                .events
+                .publish_outbox(&outbox)
+                .await
+                .context(EventError)?;
+
+            user_id
         } else {
-            return Err(error::ErrorForbidden("user not in org"));
+            return Err(LoginError::NotInOrg {
+                org: state.config.required_org.clone(),
+                github_user: github_user_id,
+            }
+            .into());
         }
     };
 
+    let lifetime_secs = if login_params.remember {
+        state.config.remember_me_lifetime_secs
+    } else {
+        state.config.session_lifetime_secs
+    };
+    let expires = chrono::Utc::now() + chrono::Duration::seconds(lifetime_secs as i64);
+
+    let outbox = state.events.outbox({
+        let user_id = user_id.clone();
+        move |_: &String| vec![Event::UserLoggedIn { user_id }]
+    });
     let token = state
         .database
-        .create_token_for_user(user_id)
-        .map_err(error::ErrorInternalServerError)
-        .await?;
+        .create_token_for_user(
+            user_id.clone(),
+            authz::session_scopes(&state.config, &user_id),
+            expires,
+            outbox.clone(),
+        )
+        .await
+        .context(DatabaseError)?;
+
+    state
+        .events
+        .publish_outbox(&outbox)
+        .await
+        .context(EventError)?;
+
+    // Unless asked to remember the user we set a session cookie, which the
+    // browser drops when it closes. The token expiring limits how long an
+    // open browser stays logged in.
+    let cookie = SessionCookie::new(&state.config, &token)
+        .maybe_expires(Some(expires).filter(|_| login_params.remember));
 
     Ok(HttpResponse::Found()
-        .header(
-            hyper::header::SET_COOKIE,
+        .insert_header((hyper::header::SET_COOKIE, cookie.to_string()))
+        .insert_header((
+            hyper::header::LOCATION,
             format!(
-                "token={}; HttpOnly; Secure; Path=/; Expires={}; SameSite=lax",
-                token,
-                get_expires_string(),
+                "{}{}",
+                state.config.web_root,
+                login_params.next.as_deref().unwrap_or("/")
             ),
-        )
-        .header(
-            hyper::header::LOCATION,
-            format!("{}/", state.config.web_root),
-        )
+        ))
         .finish())
 }
@@ -0,0 +1,323 @@
+//! A GraphQL API at `/graphql`, alongside the REST API.
+//!
+//! Covers users with their balances, transactions and creating transactions
+//! with the `shaft` mutation. Authentication is the same as the REST API:
+//! queries are allowed for anyone who can read the ledger, and mutations need
+//! a token with the `write` scope.
+//!
+//! Only the default ledger is available through GraphQL.
+//!
+//! Transactions are paginated newest first. Pass the `nextBefore` of one page
+//! as `before` to get the next, until it is null.
+
+use actix_web::web::{self, ServiceConfig};
+use async_graphql::{Context, EmptySubscription, ErrorExtensions, Object, Schema, SimpleObject};
+use async_graphql_actix_web::{GraphQLRequest, GraphQLResponse};
+use chrono::{DateTime, Utc};
+use futures::future::Future;
+use snafu::ResultExt;
+
+use std::sync::Arc;
+
+use crate::content_policy::ContentPolicy;
+use crate::db::{self, Database, Scope, TransactionFilter, DEFAULT_LEDGER_ID};
+use crate::error::{DatabaseError, EventError, InvalidReason, ShaftError};
+use crate::events::EventBus;
+use crate::money::{Currency, Money};
+use crate::reason::check_reason;
+use crate::rest::{authz, AppState, AuthenticatedUser, ReadAccess};
+use crate::settings::ReasonSettings;
+
+/// The maximum nesting of queries, to bound the work done per request.
+const MAX_QUERY_DEPTH: usize = 8;
+
+/// The number of transactions returned if no limit is given.
+const DEFAULT_PAGE_SIZE: u32 = 20;
+
+/// The maximum number of transactions returned in one page.
+const MAX_PAGE_SIZE: u32 = 1000;
+
+pub type ShaftSchema = Schema<QueryRoot, MutationRoot, EmptySubscription>;
+
+/// Register servlets with HTTP app
+pub fn register_servlets(config: &mut ServiceConfig) {
+    let schema = Schema::build(QueryRoot, MutationRoot, EmptySubscription)
+        .limit_depth(MAX_QUERY_DEPTH)
+        .finish();
+
+    config.app_data(web::Data::new(schema));
+    config.route("/graphql", web::post().to(graphql));
+}
+
+/// Per request data made available to resolvers.
+struct RequestData {
+    database: Arc<dyn Database>,
+    events: EventBus,
+    /// The currency amounts are in.
+    currency: Currency,
+    /// What reasons transactions must be given.
+    reasons: ReasonSettings,
+    /// Applied to reasons before they are stored.
+    content_policy: Arc<dyn ContentPolicy>,
+    /// The user making the request, if logged in.
+    user: Option<AuthenticatedUser>,
+}
+
+/// A user and their balance.
+#[derive(SimpleObject)]
+#[graphql(name = "User")]
+struct GqlUser {
+    user_id: String,
+    display_name: String,
+    /// Positive if the user is owed money, in pence.
+    balance: i64,
+}
+
+/// A transaction between two users.
+#[derive(SimpleObject)]
+#[graphql(name = "Transaction")]
+struct GqlTransaction {
+    id: Option<i64>,
+    /// The user that paid.
+    shafter: String,
+    /// The user that owes the money.
+    shaftee: String,
+    /// The amount in pence.
+    amount: i64,
+    time: DateTime<Utc>,
+    reason: String,
+}
+
+/// A page of transactions, newest first.
+#[derive(SimpleObject)]
+struct TransactionPage {
+    transactions: Vec<GqlTransaction>,
+    /// Pass as `before` to get the next page, or null if this is the last.
+    next_before: Option<i64>,
+}
+
+impl From<db::User> for GqlUser {
+    fn from(user: db::User) -> GqlUser {
+        GqlUser {
+            user_id: user.user_id,
+            display_name: user.display_name,
+            balance: user.balance.minor_units(),
+        }
+    }
+}
+
+impl From<db::Transaction> for GqlTransaction {
+    fn from(transaction: db::Transaction) -> GqlTransaction {
+        GqlTransaction {
+            id: transaction.id,
+            shafter: transaction.shafter,
+            shaftee: transaction.shaftee,
+            amount: transaction.amount.minor_units(),
+            time: transaction.datetime,
+            reason: transaction.reason,
+        }
+    }
+}
+
+/// Convert an error into one returned to the client, keeping the kind so that
+/// clients can tell errors apart.
+fn graphql_error(err: ShaftError) -> async_graphql::Error {
+    let kind = err.kind();
+    async_graphql::Error::new(err.to_string()).extend_with(|_, ext| ext.set("kind", kind))
+}
+
+/// Run a future on the current worker.
+///
+/// Resolvers have to be `Send`, but [Database] and [EventBus] futures aren't,
+/// so they are spawned on the worker handling the request and only the
+/// result is sent back.
+fn run_local<F, T>(fut: F) -> impl Future<Output = async_graphql::Result<T>> + Send
+where
+    F: Future<Output = Result<T, ShaftError>> + 'static,
+    T: Send + 'static,
+{
+    let handle = actix_web::rt::spawn(async move { fut.await.map_err(graphql_error) });
+
+    async move {
+        handle
+            .await
+            .unwrap_or_else(|_| Err(async_graphql::Error::new("Request was cancelled")))
+    }
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    /// The logged in user, or null if logged out.
+    async fn me(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<GqlUser>> {
+        let data = ctx.data_unchecked::<RequestData>();
+        let user_id = match &data.user {
+            Some(user) => user.user_id.clone(),
+            None => return Ok(None),
+        };
+
+        let database = data.database.clone();
+        let mut users = run_local(async move {
+            database
+                .get_all_users(DEFAULT_LEDGER_ID)
+                .await
+                .context(DatabaseError)
+        })
+        .await?;
+
+        Ok(users.remove(&user_id).map(GqlUser::from))
+    }
+
+    /// All users, ordered by ID.
+    async fn users(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<GqlUser>> {
+        let database = ctx.data_unchecked::<RequestData>().database.clone();
+        let users = run_local(async move {
+            database
+                .get_all_users(DEFAULT_LEDGER_ID)
+                .await
+                .context(DatabaseError)
+        })
+        .await?;
+
+        Ok(users.into_iter().map(|(_, user)| user.into()).collect())
+    }
+
+    /// The user with the given ID, or null if there isn't one.
+    async fn user(
+        &self,
+        ctx: &Context<'_>,
+        user_id: String,
+    ) -> async_graphql::Result<Option<GqlUser>> {
+        let database = ctx.data_unchecked::<RequestData>().database.clone();
+        let mut users = run_local(async move {
+            database
+                .get_all_users(DEFAULT_LEDGER_ID)
+                .await
+                .context(DatabaseError)
+        })
+        .await?;
+
+        Ok(users.remove(&user_id).map(GqlUser::from))
+    }
+
+    /// Transactions, newest first, optionally only those involving `user`
+    /// and within a time range. `limit` is at most 1000, defaulting to 20.
+    async fn transactions(
+        &self,
+        ctx: &Context<'_>,
+        user: Option<String>,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+        before: Option<i64>,
+        #[graphql(default_with = "DEFAULT_PAGE_SIZE")] limit: u32,
+    ) -> async_graphql::Result<TransactionPage> {
+        if limit > MAX_PAGE_SIZE {
+            return Err(graphql_error(ShaftError::BadRequest {
+                message: format!("limit must be at most {}", MAX_PAGE_SIZE),
+            }));
+        }
+
+        let database = ctx.data_unchecked::<RequestData>().database.clone();
+        let filter = TransactionFilter {
+            user,
+            from,
+            to,
+            before,
+            limit,
+        };
+        let transactions = run_local(async move {
+            database
+                .get_transactions(DEFAULT_LEDGER_ID, filter)
+                .await
+                .context(DatabaseError)
+        })
+        .await?;
+
+        let next_before = if limit > 0 && transactions.len() == limit as usize {
+            transactions.last().and_then(|transaction| transaction.id)
+        } else {
+            None
+        };
+
+        Ok(TransactionPage {
+            transactions: transactions.into_iter().map(GqlTransaction::from).collect(),
+            next_before,
+        })
+    }
+}
+
+pub struct MutationRoot;
+
+#[Object]
+impl MutationRoot {
+    /// Record that the logged in user paid `amount` pence on behalf of
+    /// `otherUser`.
+    async fn shaft(
+        &self,
+        ctx: &Context<'_>,
+        other_user: String,
+        amount: i64,
+        reason: String,
+    ) -> async_graphql::Result<GqlTransaction> {
+        let data = ctx.data_unchecked::<RequestData>();
+        let user = data.user.as_ref().ok_or_else(|| {
+            graphql_error(ShaftError::Forbidden {
+                reason: "Must be logged in",
+            })
+        })?;
+        authz::require_scope(user, Scope::Write).map_err(graphql_error)?;
+        let reason = check_reason(&reason, &data.reasons, &*data.content_policy)
+            .context(InvalidReason)
+            .map_err(graphql_error)?;
+
+        let mut transaction = db::Transaction {
+            id: None,
+            shafter: user.user_id.clone(),
+            shaftee: other_user,
+            amount: Money::new(amount, data.currency),
+            datetime: Utc::now(),
+            reason,
+            kind: db::TransactionKind::Shaft,
+        };
+
+        let database = data.database.clone();
+        let events = data.events.clone();
+        let transaction = run_local(async move {
+            let outbox = events.transaction_outbox(&transaction);
+            let transaction_id = database
+                .shaft_user(DEFAULT_LEDGER_ID, transaction.clone(), outbox.clone())
+                .await
+                .context(DatabaseError)?;
+
+            transaction.id = Some(transaction_id);
+            events.publish_outbox(&outbox).await.context(EventError)?;
+
+            Ok(transaction)
+        })
+        .await?;
+
+        Ok(transaction.into())
+    }
+}
+
+/// Execute a GraphQL request.
+async fn graphql(
+    (state, schema, access, request): (
+        web::Data<AppState>,
+        web::Data<ShaftSchema>,
+        ReadAccess,
+        GraphQLRequest,
+    ),
+) -> GraphQLResponse {
+    let request = request.into_inner().data(RequestData {
+        database: state.database.clone(),
+        events: state.events.clone(),
+        currency: state.config.currency.currency(),
+        reasons: state.config.reasons.clone(),
+        content_policy: state.content_policy.clone(),
+        user: access.user,
+    });
+
+    schema.execute(request).await.into()
+}
@@ -0,0 +1,231 @@
+//! Handlebars helpers for the web pages, other than `pence-as-pounds` which
+//! lives with [Money](crate::money::Money):
+//!
+//! - `{{format-date datetime}}` shows a date in the configured locale and
+//!   format, or `{{format-date datetime "%H:%M"}}` in the given format.
+//! - `{{relative-time datetime}}` shows how long ago a date was, e.g. `3 days
+//!   ago`.
+//! - `{{pluralise count "transaction"}}` shows a count with a noun, e.g. `1
+//!   transaction` or `2 transactions`. An irregular plural can be given as
+//!   a third param.
+//! - `{{percent fraction}}` shows a fraction as a percentage, e.g. `25%`,
+//!   with the number of decimal places optionally given as a second param.
+//!
+//! Dates may be either RFC 3339 strings, as `chrono` serializes them, or
+//! seconds since the epoch. They're shown in UTC.
+
+use chrono::{DateTime, Locale, TimeZone, Utc};
+use handlebars::{
+    Context, Handlebars, Helper, HelperDef, HelperResult, Output, RenderContext, RenderError,
+};
+use serde_json::Value;
+use snafu::Snafu;
+
+use std::convert::TryFrom;
+
+use crate::settings::UiSettings;
+
+/// Errors from setting up the helpers.
+#[derive(Debug, Snafu)]
+pub enum HelperError {
+    #[snafu(display("Unknown locale {:?}, expected e.g. en_GB", locale))]
+    UnknownLocale { locale: String },
+}
+
+/// Registers all the helpers in this module.
+pub fn register_helpers(hb: &mut Handlebars, ui: &UiSettings) -> Result<(), HelperError> {
+    hb.register_helper("format-date", Box::new(DateHelper::new(ui)?));
+    hb.register_helper("relative-time", Box::new(RelativeTimeHelper));
+    hb.register_helper("pluralise", Box::new(PluraliseHelper));
+    hb.register_helper("percent", Box::new(PercentHelper));
+    Ok(())
+}
+
+/// Describes how long before or after `now` the date is, rounded down to the
+/// largest whole unit, e.g. `3 days ago` or `in 2 hours`. Anything within a
+/// minute is `just now`.
+pub fn format_relative_time(datetime: DateTime<Utc>, now: DateTime<Utc>) -> String {
+    let secs = (now - datetime).num_seconds();
+
+    let (count, unit) = match secs.abs() {
+        secs if secs < 60 => return "just now".to_string(),
+        secs if secs < 60 * 60 => (secs / 60, "minute"),
+        secs if secs < 24 * 60 * 60 => (secs / (60 * 60), "hour"),
+        secs if secs < 30 * 24 * 60 * 60 => (secs / (24 * 60 * 60), "day"),
+        secs if secs < 365 * 24 * 60 * 60 => (secs / (30 * 24 * 60 * 60), "month"),
+        secs => (secs / (365 * 24 * 60 * 60), "year"),
+    };
+
+    let duration = pluralise(count, unit, None);
+    if secs < 0 {
+        format!("in {}", duration)
+    } else {
+        format!("{} ago", duration)
+    }
+}
+
+/// The count followed by the noun, in the plural unless the count is one.
+/// The plural is the singular with an `s` added, unless given.
+pub fn pluralise(count: i64, singular: &str, plural: Option<&str>) -> String {
+    if count.abs() == 1 {
+        format!("{} {}", count, singular)
+    } else {
+        match plural {
+            Some(plural) => format!("{} {}", count, plural),
+            None => format!("{} {}s", count, singular),
+        }
+    }
+}
+
+/// The fraction as a percentage with the given number of decimal places,
+/// e.g. `0.125` with one decimal place is `12.5%`.
+pub fn format_percent(fraction: f64, decimals: usize) -> String {
+    format!("{:.*}%", decimals, fraction * 100.0)
+}
+
+/// Handlebars helper showing a date in the configured locale and format.
+struct DateHelper {
+    locale: Locale,
+    format: String,
+}
+
+impl DateHelper {
+    fn new(ui: &UiSettings) -> Result<DateHelper, HelperError> {
+        let locale =
+            Locale::try_from(ui.locale.as_str()).map_err(|_| HelperError::UnknownLocale {
+                locale: ui.locale.clone(),
+            })?;
+
+        Ok(DateHelper {
+            locale,
+            format: ui.date_format.clone(),
+        })
+    }
+}
+
+impl HelperDef for DateHelper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let datetime = datetime_param(h, 0)?;
+        let format = match h.param(1) {
+            Some(param) => param
+                .value()
+                .as_str()
+                .ok_or_else(|| RenderError::new("Format must be a string"))?,
+            None => self.format.as_str(),
+        };
+
+        out.write(&datetime.format_localized(format, self.locale).to_string())?;
+        Ok(())
+    }
+}
+
+/// Handlebars helper showing how long ago a date was, with
+/// [format_relative_time].
+struct RelativeTimeHelper;
+
+impl HelperDef for RelativeTimeHelper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let datetime = datetime_param(h, 0)?;
+        out.write(&format_relative_time(datetime, Utc::now()))?;
+        Ok(())
+    }
+}
+
+/// Handlebars helper showing a count with a noun, with [pluralise].
+struct PluraliseHelper;
+
+impl HelperDef for PluraliseHelper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let count = param(h, 0)?
+            .as_i64()
+            .ok_or_else(|| RenderError::new("Count must be a whole number"))?;
+        let singular = param(h, 1)?
+            .as_str()
+            .ok_or_else(|| RenderError::new("Noun must be a string"))?;
+        let plural = match h.param(2) {
+            Some(param) => Some(
+                param
+                    .value()
+                    .as_str()
+                    .ok_or_else(|| RenderError::new("Plural must be a string"))?,
+            ),
+            None => None,
+        };
+
+        out.write(&pluralise(count, singular, plural))?;
+        Ok(())
+    }
+}
+
+/// Handlebars helper showing a fraction as a percentage, with
+/// [format_percent].
+struct PercentHelper;
+
+impl HelperDef for PercentHelper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let fraction = param(h, 0)?
+            .as_f64()
+            .ok_or_else(|| RenderError::new("Param must be a number"))?;
+        let decimals = match h.param(1) {
+            Some(param) => param
+                .value()
+                .as_u64()
+                .ok_or_else(|| RenderError::new("Decimal places must be a whole number"))?
+                as usize,
+            None => 0,
+        };
+
+        out.write(&format_percent(fraction, decimals))?;
+        Ok(())
+    }
+}
+
+/// The value of the helper's param at `index`.
+fn param<'a>(h: &'a Helper, index: usize) -> Result<&'a Value, RenderError> {
+    h.param(index)
+        .map(|param| param.value())
+        .ok_or_else(|| RenderError::new("Missing param"))
+}
+
+/// The date given as the helper's param at `index`.
+fn datetime_param(h: &Helper, index: usize) -> Result<DateTime<Utc>, RenderError> {
+    let datetime = match param(h, index)? {
+        Value::Number(secs) => secs
+            .as_i64()
+            .and_then(|secs| Utc.timestamp_opt(secs, 0).single()),
+        Value::String(datetime) => DateTime::parse_from_rfc3339(datetime)
+            .ok()
+            .map(|datetime| datetime.with_timezone(&Utc)),
+        _ => None,
+    };
+
+    datetime.ok_or_else(|| RenderError::new("Param must be a date"))
+}
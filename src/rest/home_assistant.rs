@@ -0,0 +1,90 @@
+//! A sensor for Home Assistant's RESTful sensor integration.
+//!
+//! `GET /api/home_assistant/sensor` returns the user's balance in pounds (or
+//! whichever currency is configured) as the value, with everyone's balances as
+//! attributes:
+//!
+//! ```json
+//! {
+//!   "value": 1.5,
+//!   "attributes": {
+//!     "user_id": "alice",
+//!     "display_name": "Alice",
+//!     "balance_pence": 150,
+//!     "balances": { "alice": 1.5, "bob": -1.5 }
+//!   }
+//! }
+//! ```
+//!
+//! Home Assistant needs a token that doesn't expire, so create an API token
+//! with just the `sensor` scope and use it as the sensor's bearer token, with
+//! `value_template: "{{ value_json.value }}"` and
+//! `json_attributes_path: "$.attributes"`.
+
+use actix_web::web::ServiceConfig;
+use actix_web::{web, Error};
+use serde::Serialize;
+use snafu::ResultExt;
+
+use std::collections::BTreeMap;
+
+use crate::db::Scope;
+use crate::error::DatabaseError;
+use crate::money::Money;
+use crate::rest::response::ApiJson;
+use crate::rest::{authz, AppState, AuthenticatedUser, CurrentLedger};
+
+/// Register servlets with HTTP app
+pub fn register_servlets(config: &mut ServiceConfig) {
+    config.route("/api/home_assistant/sensor", web::get().to(get_sensor));
+}
+
+/// The sensor's state.
+#[derive(Debug, Serialize)]
+struct Sensor {
+    /// The user's balance in major units, e.g. pounds.
+    value: f64,
+    attributes: SensorAttributes,
+}
+
+#[derive(Debug, Serialize)]
+struct SensorAttributes {
+    user_id: String,
+    display_name: String,
+    balance_pence: Money,
+    /// Every user's balance in major units, by user ID.
+    balances: BTreeMap<String, f64>,
+}
+
+/// Get the sensor for the logged in user.
+async fn get_sensor(
+    (state, user, ledger): (web::Data<AppState>, AuthenticatedUser, CurrentLedger),
+) -> Result<ApiJson<Sensor>, Error> {
+    authz::require_scope(&user, Scope::Sensor)?;
+
+    let users = state
+        .database
+        .get_all_users(ledger.id)
+        .await
+        .context(DatabaseError)?;
+
+    let balance = users
+        .get(&user.user_id)
+        .map(|me| me.balance)
+        .unwrap_or_else(|| Money::zero(state.config.currency.currency()));
+
+    let balances = users
+        .into_iter()
+        .map(|(user_id, other)| (user_id, other.balance.to_major_units()))
+        .collect();
+
+    Ok(ApiJson(Sensor {
+        value: balance.to_major_units(),
+        attributes: SensorAttributes {
+            user_id: user.user_id,
+            display_name: user.display_name,
+            balance_pence: balance,
+            balances,
+        },
+    }))
+}
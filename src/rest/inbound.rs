@@ -0,0 +1,260 @@
+//! Inbound webhooks, so that automations like IFTTT or Zapier can create
+//! transactions.
+//!
+//! Users create hooks through `/api/inbound_hooks`, each of which gets a
+//! secret URL. POSTing a transaction to it, e.g.
+//!
+//! ```json
+//! { "other_user": "bob", "amount": 500, "reason": "Internet" }
+//! ```
+//!
+//! creates it on behalf of the hook's owner in the default ledger, as if sent
+//! to `/api/shaft`.
+//! Hooks only work while the `webhooks` feature is enabled.
+
+use actix_web::web::{Json, ServiceConfig};
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use serde_json::json;
+use snafu::ResultExt;
+
+use crate::api_types::{ShaftUserBody, ShaftUserResponse};
+use crate::db::{self, Scope, DEFAULT_LEDGER_ID};
+use crate::error::{DatabaseError, EventError, InvalidAmount, InvalidReason, ShaftError};
+use crate::events::Event;
+use crate::features::Feature;
+use crate::reason::check_reason;
+use crate::rest::response::{json_response, ApiJson};
+use crate::rest::{authz, AppState, AuthenticatedUser, ReqLogger};
+
+/// Register servlets with HTTP app
+pub fn register_servlets(config: &mut ServiceConfig) {
+    config.service(
+        web::resource("/api/inbound_hooks")
+            .route(web::get().to(get_inbound_hooks))
+            .route(web::post().to(create_inbound_hook)),
+    );
+    config.route(
+        "/api/inbound_hooks/{id}",
+        web::delete().to(delete_inbound_hook),
+    );
+    config.route(
+        "/integrations/inbound/{hook_id}",
+        web::post().to(inbound_shaft),
+    );
+}
+
+/// Check webhooks are enabled before managing hooks.
+fn require_webhooks(state: &AppState) -> Result<(), ShaftError> {
+    if state.features.is_enabled(Feature::Webhooks) {
+        Ok(())
+    } else {
+        Err(ShaftError::Forbidden {
+            reason: "Webhooks are disabled",
+        })
+    }
+}
+
+/// Get the user's inbound hooks. Doesn't include their secret URLs.
+async fn get_inbound_hooks(
+    (state, user): (web::Data<AppState>, AuthenticatedUser),
+) -> Result<ApiJson<Vec<db::InboundHook>>, Error> {
+    authz::require_scope(&user, Scope::Read)?;
+
+    let hooks = state
+        .database
+        .get_inbound_hooks(user.user_id)
+        .await
+        .context(DatabaseError)?;
+
+    Ok(ApiJson(hooks))
+}
+
+/// Body for creating a new inbound hook.
+#[derive(Deserialize)]
+struct CreateInboundHookBody {
+    /// A human readable name for the hook.
+    name: String,
+}
+
+/// Create a new inbound hook. Its URL is only ever returned here.
+async fn create_inbound_hook(
+    (req, state, user, body, ReqLogger(logger)): (
+        HttpRequest,
+        web::Data<AppState>,
+        AuthenticatedUser,
+        Json<CreateInboundHookBody>,
+        ReqLogger,
+    ),
+) -> Result<HttpResponse, Error> {
+    authz::require_scope(&user, Scope::Write)?;
+    require_webhooks(&state)?;
+
+    let CreateInboundHookBody { name } = body.into_inner();
+
+    if name.trim().is_empty() {
+        return Err(ShaftError::BadRequest {
+            message: "Hook name must not be empty".to_string(),
+        }
+        .into());
+    }
+
+    let outbox = state.events.outbox({
+        let actor = user.user_id.clone();
+        let name = name.clone();
+        move |&(id, _): &(i64, String)| {
+            vec![Event::InboundHookCreated {
+                actor,
+                hook_id: id,
+                name,
+            }]
+        }
+    });
+    let (id, hook_id) = state
+        .database
+        .create_inbound_hook(user.user_id.clone(), name.clone(), outbox.clone())
+        .await
+        .context(DatabaseError)?;
+
+    state
+        .events
+        .publish_outbox(&outbox)
+        .await
+        .context(EventError)?;
+
+    info!(logger, "Created inbound hook"; "hook_id" => id);
+
+    let connection_info = req.connection_info();
+    let url = format!(
+        "{}://{}{}/integrations/inbound/{}",
+        connection_info.scheme(),
+        connection_info.host(),
+        state.config.web_root,
+        hook_id
+    );
+
+    Ok(json_response(
+        &req,
+        HttpResponse::Ok(),
+        &json!({
+            "id": id,
+            "name": name,
+            "url": url,
+        }),
+    ))
+}
+
+/// Delete one of the user's inbound hooks.
+async fn delete_inbound_hook(
+    (req, state, user, path, ReqLogger(logger)): (
+        HttpRequest,
+        web::Data<AppState>,
+        AuthenticatedUser,
+        web::Path<i64>,
+        ReqLogger,
+    ),
+) -> Result<HttpResponse, Error> {
+    authz::require_scope(&user, Scope::Write)?;
+
+    let id = path.into_inner();
+
+    let actor = user.user_id.clone();
+    let outbox = state.events.outbox(move |&deleted| {
+        if deleted {
+            vec![Event::InboundHookDeleted { actor, hook_id: id }]
+        } else {
+            Vec::new()
+        }
+    });
+    let deleted = state
+        .database
+        .delete_inbound_hook(user.user_id.clone(), id, outbox.clone())
+        .await
+        .context(DatabaseError)?;
+
+    if !deleted {
+        return Err(ShaftError::NotFound {
+            what: "inbound hook",
+        }
+        .into());
+    }
+
+    state
+        .events
+        .publish_outbox(&outbox)
+        .await
+        .context(EventError)?;
+
+    info!(logger, "Deleted inbound hook"; "hook_id" => id);
+
+    Ok(json_response(&req, HttpResponse::Ok(), &json!({})))
+}
+
+/// Create a transaction on behalf of the hook's owner.
+async fn inbound_shaft(
+    (state, path, body, ReqLogger(logger)): (
+        web::Data<AppState>,
+        web::Path<String>,
+        Json<ShaftUserBody>,
+        ReqLogger,
+    ),
+) -> Result<ApiJson<ShaftUserResponse>, Error> {
+    // Don't reveal whether hooks exist while they're turned off.
+    if !state.features.is_enabled(Feature::Webhooks) {
+        return Err(ShaftError::NotFound {
+            what: "inbound hook",
+        }
+        .into());
+    }
+
+    let user_id = state
+        .database
+        .get_user_for_inbound_hook(path.into_inner())
+        .await
+        .context(DatabaseError)?
+        .ok_or(ShaftError::NotFound {
+            what: "inbound hook",
+        })?;
+
+    let ShaftUserBody {
+        other_user,
+        amount,
+        reason,
+    } = body.into_inner();
+    let amount = amount
+        .resolve(&state.config.currency)
+        .context(InvalidAmount)?;
+    let reason = check_reason(&reason, &state.config.reasons, &*state.content_policy)
+        .context(InvalidReason)?;
+
+    let mut transaction = db::Transaction {
+        id: None,
+        shafter: user_id.clone(),
+        shaftee: other_user.clone(),
+        amount,
+        datetime: chrono::Utc::now(),
+        reason,
+        kind: db::TransactionKind::Shaft,
+    };
+
+    let outbox = state.events.transaction_outbox(&transaction);
+    let transaction_id = state
+        .database
+        .shaft_user(DEFAULT_LEDGER_ID, transaction.clone(), outbox.clone())
+        .await
+        .context(DatabaseError)?;
+
+    transaction.id = Some(transaction_id);
+    state
+        .events
+        .publish_outbox(&outbox)
+        .await
+        .context(EventError)?;
+
+    info!(
+        logger, "Shafted user from inbound hook";
+        "user_id" => user_id, "other_user" => other_user, "amount" => amount.minor_units()
+    );
+
+    Ok(ApiJson(ShaftUserResponse { transaction_id }))
+}
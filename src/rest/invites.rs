@@ -0,0 +1,256 @@
+//! Inviting people to a ledger by email.
+//!
+//! A member of a ledger can `POST /l/{ledger}/api/invites` with
+//!
+//! ```json
+//! { "email": "carol@example.com" }
+//! ```
+//!
+//! which emails a link to `/invites/accept`. The link is signed and expires
+//! after the configured lifetime. Following it (logging in first if needed)
+//! adds whoever is logged in to the ledger, so the link should be treated like
+//! a password. Requires the `invites` setting, and `mail` to actually deliver
+//! the emails.
+
+use actix_web::web::ServiceConfig;
+use actix_web::{web, Error, HttpResponse};
+use hyper::header::LOCATION;
+use serde::Deserialize;
+use serde_json::json;
+use snafu::ResultExt;
+
+use crate::db::Scope;
+use crate::error::{CryptoError, DatabaseError, EventError, MailError, ShaftError};
+use crate::events::Event;
+use crate::mailer::Email;
+use crate::rest::response::ApiJson;
+use crate::rest::{authz, AppState, AuthenticatedUser, CurrentLedger, ReqLogger};
+use crate::settings::InviteSettings;
+use crate::signing::hmac_sha256_hex;
+
+/// Register the servlets that aren't tied to a ledger.
+pub fn register_servlets(config: &mut ServiceConfig) {
+    config.route("/invites/accept", web::get().to(accept_invite));
+}
+
+/// Register the servlets that are available for each ledger.
+pub fn register_ledger_servlets(config: &mut ServiceConfig) {
+    config.route("/api/invites", web::post().to(send_invite));
+}
+
+/// The body of a `POST /api/invites` request.
+#[derive(Deserialize)]
+struct InviteBody {
+    email: String,
+}
+
+/// The query parameters of an invitation link. The signature covers the
+/// rest.
+#[derive(Deserialize)]
+struct InviteQuery {
+    ledger: String,
+    email: String,
+    /// When the link stops working, as a Unix timestamp.
+    expires: i64,
+    sig: String,
+}
+
+/// Email an invitation to join the current ledger.
+async fn send_invite(
+    (state, user, ledger, body, ReqLogger(logger)): (
+        web::Data<AppState>,
+        AuthenticatedUser,
+        CurrentLedger,
+        web::Json<InviteBody>,
+        ReqLogger,
+    ),
+) -> Result<ApiJson<impl serde::Serialize>, Error> {
+    authz::require_scope(&user, Scope::Write)?;
+
+    let invites = state.config.invites.as_ref().ok_or(ShaftError::NotFound {
+        what: "integration",
+    })?;
+
+    // Everyone is already in the default ledger.
+    let ledger_name = ledger.name.ok_or_else(|| ShaftError::BadRequest {
+        message: "Invitations are only for named ledgers".to_string(),
+    })?;
+
+    let email = body.into_inner().email.trim().to_string();
+    if !is_plausible_email(&email) {
+        return Err(ShaftError::BadRequest {
+            message: "Invalid email address".to_string(),
+        }
+        .into());
+    }
+
+    let display_name = state
+        .database
+        .get_ledger_by_name(ledger_name.clone())
+        .await
+        .context(DatabaseError)?
+        .ok_or(ShaftError::NotFound { what: "ledger" })?
+        .display_name;
+
+    let expires = chrono::Utc::now() + chrono::Duration::seconds(invites.lifetime_secs as i64);
+    let link =
+        invite_link(invites, &ledger_name, &email, expires.timestamp()).context(CryptoError)?;
+
+    state
+        .mailer
+        .send(Email {
+            to: email.clone(),
+            subject: format!("You've been invited to {} on shaft", display_name),
+            text: format!(
+                "{} has invited you to join {} on shaft.\n\n\
+                 Follow this link to join:\n\n{}\n\n\
+                 The link expires on {}.\n",
+                user.display_name,
+                display_name,
+                link,
+                expires.format("%d %b %Y"),
+            ),
+        })
+        .await
+        .context(MailError)?;
+
+    state
+        .events
+        .publish(Event::InviteSent {
+            actor: user.user_id.clone(),
+            ledger: ledger_name.clone(),
+            email: email.clone(),
+        })
+        .await
+        .context(EventError)?;
+
+    info!(logger, "Sent ledger invitation"; "ledger" => ledger_name);
+
+    Ok(ApiJson(json!({})))
+}
+
+/// Add the logged in user to the ledger in a valid invitation link, then send
+/// them to its home page.
+async fn accept_invite(
+    (state, user, query, ReqLogger(logger)): (
+        web::Data<AppState>,
+        AuthenticatedUser,
+        web::Query<InviteQuery>,
+        ReqLogger,
+    ),
+) -> Result<HttpResponse, Error> {
+    let invites = state.config.invites.as_ref().ok_or(ShaftError::NotFound {
+        what: "integration",
+    })?;
+
+    let InviteQuery {
+        ledger,
+        email,
+        expires,
+        sig,
+    } = query.into_inner();
+
+    let expected =
+        signature(&invites.signing_secret, &ledger, &email, expires).context(CryptoError)?;
+    if expected.len() != sig.len() || !openssl::memcmp::eq(expected.as_bytes(), sig.as_bytes()) {
+        return Err(ShaftError::Forbidden {
+            reason: "Invalid invitation link",
+        }
+        .into());
+    }
+    if expires < chrono::Utc::now().timestamp() {
+        return Err(ShaftError::Forbidden {
+            reason: "Invitation link has expired",
+        }
+        .into());
+    }
+
+    let ledger_id = state
+        .database
+        .get_ledger_by_name(ledger.clone())
+        .await
+        .context(DatabaseError)?
+        .ok_or(ShaftError::NotFound { what: "ledger" })?
+        .id;
+
+    let outbox = state.events.outbox({
+        let ledger = ledger.clone();
+        let user_id = user.user_id.clone();
+        move |_: &()| {
+            vec![Event::LedgerMemberAdded {
+                actor: user_id.clone(),
+                ledger,
+                user_id,
+            }]
+        }
+    });
+    state
+        .database
+        .add_ledger_member(ledger_id, user.user_id.clone(), outbox.clone())
+        .await
+        .context(DatabaseError)?;
+
+    state
+        .events
+        .publish_outbox(&outbox)
+        .await
+        .context(EventError)?;
+
+    info!(logger, "Accepted ledger invitation"; "ledger" => &ledger, "member" => &user.user_id);
+
+    Ok(HttpResponse::Found()
+        .insert_header((
+            LOCATION,
+            format!("{}/l/{}/home", state.config.web_root, ledger),
+        ))
+        .finish())
+}
+
+/// Whether the string looks enough like an email address to send to. The
+/// mail service does the real validation.
+fn is_plausible_email(email: &str) -> bool {
+    match email.find('@') {
+        Some(at) => {
+            at > 0
+                && at < email.len() - 1
+                && !email.chars().any(|c| c.is_whitespace() || c.is_control())
+        }
+        None => false,
+    }
+}
+
+/// Build the signed link for an invitation.
+fn invite_link(
+    invites: &InviteSettings,
+    ledger: &str,
+    email: &str,
+    expires: i64,
+) -> Result<String, openssl::error::ErrorStack> {
+    let sig = signature(&invites.signing_secret, ledger, email, expires)?;
+
+    let query = url::form_urlencoded::Serializer::new(String::new())
+        .append_pair("ledger", ledger)
+        .append_pair("email", email)
+        .append_pair("expires", &expires.to_string())
+        .append_pair("sig", &sig)
+        .finish();
+
+    Ok(format!(
+        "{}/invites/accept?{}",
+        invites.public_url.trim_end_matches('/'),
+        query
+    ))
+}
+
+/// Compute the hex encoded HMAC of an invitation's details.
+fn signature(
+    secret: &str,
+    ledger: &str,
+    email: &str,
+    expires: i64,
+) -> Result<String, openssl::error::ErrorStack> {
+    // Neither ledger names nor valid email addresses contain newlines, so
+    // this is unambiguous.
+    let details = format!("{}\n{}\n{}", ledger, email, expires);
+    hmac_sha256_hex(secret, &[details.as_bytes()])
+}
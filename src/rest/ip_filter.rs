@@ -0,0 +1,255 @@
+//! Restricts which client IP addresses may make requests.
+
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::Error;
+use futures::future::{err, ok, Either, LocalBoxFuture, Ready};
+use futures::FutureExt;
+use snafu::Snafu;
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::error::ShaftError;
+use crate::rest::TENANT_PATH_PREFIX;
+
+/// Requests to paths starting with this, or a tenant's paths starting with
+/// it, use the admin rules as well.
+const ADMIN_PATH_PREFIX: &str = "/api/admin/";
+
+/// The admin dashboard, whose paths use the admin rules too.
+const ADMIN_DASHBOARD_PATH: &str = "/admin";
+
+/// Whether the path is to the admin API or dashboard of the instance or a
+/// tenant.
+fn is_admin_path(path: &str) -> bool {
+    let path = path
+        .strip_prefix(TENANT_PATH_PREFIX)
+        .and_then(|rest| rest.find('/').map(|idx| &rest[idx..]))
+        .unwrap_or(path);
+
+    let dashboard = path
+        .strip_prefix(ADMIN_DASHBOARD_PATH)
+        .map_or(false, |rest| rest.is_empty() || rest.starts_with('/'));
+
+    dashboard || path.starts_with(ADMIN_PATH_PREFIX)
+}
+
+/// Error parsing a [Cidr].
+#[derive(Debug, Snafu)]
+pub enum CidrError {
+    /// The address part isn't a valid IP address.
+    #[snafu(display("Invalid IP address in '{}'", value))]
+    InvalidAddress { value: String },
+    /// The prefix length isn't a number or is too long for the address.
+    #[snafu(display("Invalid prefix length in '{}'", value))]
+    InvalidPrefix { value: String },
+}
+
+/// An IP network in CIDR notation, e.g. `192.168.0.0/16`. A bare address
+/// matches only itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Whether the address is in the network. IPv4 addresses mapped into IPv6
+    /// are treated as IPv4.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.network, normalize(addr)) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                let mask = mask_u32(self.prefix_len);
+                u32::from(network) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                let mask = mask_u128(self.prefix_len);
+                u128::from(network) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = CidrError;
+
+    fn from_str(value: &str) -> Result<Cidr, CidrError> {
+        let mut parts = value.trim().splitn(2, '/');
+        let address = parts.next().unwrap_or_default();
+
+        let network = normalize(address.parse().map_err(|_| CidrError::InvalidAddress {
+            value: value.to_string(),
+        })?);
+
+        let max_len = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+
+        let prefix_len = match parts.next() {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or_else(|| CidrError::InvalidPrefix {
+                    value: value.to_string(),
+                })?,
+            None => max_len,
+        };
+
+        Ok(Cidr {
+            network,
+            prefix_len,
+        })
+    }
+}
+
+/// Converts IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`) to IPv4.
+fn normalize(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, high, low] => IpAddr::V4(Ipv4Addr::new(
+                (high >> 8) as u8,
+                high as u8,
+                (low >> 8) as u8,
+                low as u8,
+            )),
+            _ => addr,
+        },
+        IpAddr::V4(_) => addr,
+    }
+}
+
+fn mask_u32(prefix_len: u8) -> u32 {
+    if prefix_len == 0 {
+        0
+    } else {
+        !0 << (32 - u32::from(prefix_len))
+    }
+}
+
+fn mask_u128(prefix_len: u8) -> u128 {
+    if prefix_len == 0 {
+        0
+    } else {
+        !0 << (128 - u32::from(prefix_len))
+    }
+}
+
+/// A set of allowed and denied networks.
+#[derive(Debug, Clone, Default)]
+pub struct IpRules {
+    /// If not empty, only addresses in these networks are permitted.
+    pub allow: Vec<Cidr>,
+    /// Addresses in these networks are never permitted.
+    pub deny: Vec<Cidr>,
+}
+
+impl IpRules {
+    /// Parse the rules from lists of CIDR strings.
+    pub fn parse(allow: &[String], deny: &[String]) -> Result<IpRules, CidrError> {
+        Ok(IpRules {
+            allow: allow.iter().map(|c| c.parse()).collect::<Result<_, _>>()?,
+            deny: deny.iter().map(|c| c.parse()).collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Whether the rules permit the address. Denials take precedence.
+    pub fn permits(&self, addr: IpAddr) -> bool {
+        if self.deny.iter().any(|cidr| cidr.contains(addr)) {
+            return false;
+        }
+
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(addr))
+    }
+
+    /// Like [IpRules::permits], but if the peer address is unknown (e.g. for
+    /// unix sockets) only permits it when there is no allow list.
+    fn permits_peer(&self, peer: Option<IpAddr>) -> bool {
+        match peer {
+            Some(addr) => self.permits(addr),
+            None => self.allow.is_empty(),
+        }
+    }
+}
+
+/// Middleware that rejects requests from addresses not permitted by the
+/// configured rules with a 403. Requests to `/api/admin/` must also be
+/// permitted by the admin rules.
+///
+/// Uses the address of the connecting peer, so when behind a reverse proxy
+/// the rules apply to the proxy's address.
+#[derive(Clone)]
+pub struct IpFilter {
+    rules: Arc<IpRules>,
+    admin_rules: Arc<IpRules>,
+}
+
+impl IpFilter {
+    pub fn new(rules: IpRules, admin_rules: IpRules) -> IpFilter {
+        IpFilter {
+            rules: Arc::new(rules),
+            admin_rules: Arc::new(admin_rules),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for IpFilter
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = IpFilterService<S>;
+    type Future = LocalBoxFuture<'static, Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(IpFilterService {
+            rules: self.rules.clone(),
+            admin_rules: self.admin_rules.clone(),
+            service,
+        })
+        .boxed_local()
+    }
+}
+
+pub struct IpFilterService<S> {
+    rules: Arc<IpRules>,
+    admin_rules: Arc<IpRules>,
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for IpFilterService<S>
+where
+    B: 'static,
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let peer = req.peer_addr().map(|addr| addr.ip());
+        let is_admin = is_admin_path(req.path());
+
+        let permitted =
+            self.rules.permits_peer(peer) && (!is_admin || self.admin_rules.permits_peer(peer));
+
+        if permitted {
+            Either::Left(self.service.call(req))
+        } else {
+            Either::Right(err(ShaftError::Forbidden {
+                reason: "Address not permitted",
+            }
+            .into()))
+        }
+    }
+}
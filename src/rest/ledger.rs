@@ -0,0 +1,80 @@
+//! Working out which ledger a request is for.
+//!
+//! Pages and API endpoints under `/l/{ledger}/...` are for the named ledger,
+//! and are only available to its members. The same endpoints without the
+//! prefix are for the default ledger, which every user is a member of.
+
+use actix_web::dev::Payload;
+use actix_web::{Error, FromRequest, HttpMessage, HttpRequest};
+use futures::future::{err, FutureExt, LocalBoxFuture};
+use snafu::ResultExt;
+
+use crate::db::DEFAULT_LEDGER_ID;
+use crate::error::{DatabaseError, ShaftError};
+use crate::rest::auth::login_redirect;
+use crate::rest::{app_state, AuthenticatedUser};
+
+/// The ledger the request is for.
+///
+/// Implements FromRequest so can be used as an extractor. Fails with a 404 if
+/// the named ledger doesn't exist or the user isn't a member of it, so that
+/// the names of other ledgers aren't leaked. Named ledgers always need a
+/// session, even if the `public_read` feature is enabled.
+#[derive(Clone, Debug)]
+pub struct CurrentLedger {
+    pub id: i64,
+    /// The name from the path, or None for the default ledger.
+    pub name: Option<String>,
+}
+
+impl FromRequest for CurrentLedger {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<CurrentLedger, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let name = match req.match_info().get("ledger") {
+            Some(name) => name.to_string(),
+            None => {
+                return async {
+                    Ok(CurrentLedger {
+                        id: DEFAULT_LEDGER_ID,
+                        name: None,
+                    })
+                }
+                .boxed_local()
+            }
+        };
+
+        let user = match req.extensions().get::<AuthenticatedUser>() {
+            Some(user) => user.clone(),
+            None => return err(login_redirect(req)).boxed_local(),
+        };
+
+        let database = match app_state(req) {
+            Ok(state) => state.database.clone(),
+            Err(e) => return err(e.into()).boxed_local(),
+        };
+
+        async move {
+            let ledger = database
+                .get_ledger_by_name(name)
+                .await
+                .context(DatabaseError)?
+                .ok_or(ShaftError::NotFound { what: "ledger" })?;
+
+            let is_member = database
+                .is_ledger_member(ledger.id, user.user_id)
+                .await
+                .context(DatabaseError)?;
+            if !is_member {
+                return Err(ShaftError::NotFound { what: "ledger" }.into());
+            }
+
+            Ok(CurrentLedger {
+                id: ledger.id,
+                name: Some(ledger.name),
+            })
+        }
+        .boxed_local()
+    }
+}
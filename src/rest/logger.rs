@@ -1,37 +1,118 @@
 //! A logging middleware using [slog]
 
-use actix_http::httpmessage::HttpMessage;
-use actix_service::Service;
-use actix_web::dev::{MessageBody, ServiceRequest, ServiceResponse};
-use actix_web::{self, Error};
-use futures::future::{FutureExt, LocalBoxFuture};
+use actix_web::dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{Error, FromRequest, HttpMessage, HttpRequest};
+use futures::future::{ok, ready, FutureExt, LocalBoxFuture, Ready};
 use rand::{thread_rng, Rng};
 use slog::Logger;
 
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::rest::auth::has_prefix;
+use crate::rest::metrics::{RouteMetrics, UNMATCHED_ROUTE};
+use crate::rest::request_logger;
+
 /// A unique ID assigned to each inbound request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct RequestID(pub u32);
 
+/// The logger for the current request, tagged with the request ID and, once
+/// authenticated, the user ID.
+///
+/// Implements FromRequest so can be used as an extractor. Requires
+/// [MiddlewareLogger] to be registered.
+pub struct ReqLogger(pub Logger);
+
+impl FromRequest for ReqLogger {
+    type Error = Error;
+    type Future = Ready<Result<ReqLogger, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(request_logger(req).map(ReqLogger).map_err(Error::from))
+    }
+}
+
 /// A middleware that logs proccessed requests usig [slog].
+///
+/// Assigns each request a random [RequestID], and installs a logger tagged
+/// with it into the request extensions for [ReqLogger]. If given
+/// [RouteMetrics], also records how long each request took against the route
+/// it matched.
 #[derive(Clone)]
 pub struct MiddlewareLogger {
     logger: Logger,
+    metrics: Option<Arc<RouteMetrics>>,
+    quiet_paths: Arc<Vec<String>>,
 }
 
 impl MiddlewareLogger {
     pub fn new(logger: Logger) -> MiddlewareLogger {
-        MiddlewareLogger { logger }
+        MiddlewareLogger {
+            logger,
+            metrics: None,
+            quiet_paths: Arc::new(Vec::new()),
+        }
     }
 
-    pub fn wrap<'a, B, S>(
-        &self,
-        req: ServiceRequest,
-        srv: &mut S,
-    ) -> LocalBoxFuture<'a, Result<ServiceResponse<B>, Error>>
-    where
-        B: MessageBody,
-        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
-        S::Future: 'a,
-    {
+    /// Record request latencies in the given metrics.
+    pub fn with_metrics(mut self, metrics: Arc<RouteMetrics>) -> MiddlewareLogger {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Only log requests to paths starting with any of the given prefixes if
+    /// they fail, e.g. so that monitoring probes don't flood the logs. They
+    /// are still counted in the metrics.
+    pub fn with_quiet_paths(mut self, prefixes: Vec<String>) -> MiddlewareLogger {
+        self.quiet_paths = Arc::new(prefixes);
+        self
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for MiddlewareLogger
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = MiddlewareLoggerService<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(MiddlewareLoggerService {
+            logger: self.logger.clone(),
+            metrics: self.metrics.clone(),
+            quiet_paths: self.quiet_paths.clone(),
+            service,
+        })
+    }
+}
+
+pub struct MiddlewareLoggerService<S> {
+    logger: Logger,
+    metrics: Option<Arc<RouteMetrics>>,
+    quiet_paths: Arc<Vec<String>>,
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for MiddlewareLoggerService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let start = Instant::now();
         let request_id: u32 = thread_rng().gen();
         let logger = self.logger.new(o!(
             "request_id" => request_id,
@@ -40,15 +121,33 @@ impl MiddlewareLogger {
         ));
 
         let resp_logger = logger.clone();
+        let quiet = has_prefix(req.path(), &self.quiet_paths);
 
         req.extensions_mut().insert(RequestID(request_id));
         req.extensions_mut().insert(logger);
 
-        let fut = srv.call(req);
+        // Use the route pattern rather than the path, so that e.g. each
+        // transaction ID doesn't get its own histogram.
+        let route = self.metrics.clone().map(|metrics| {
+            let pattern = req
+                .match_pattern()
+                .unwrap_or_else(|| UNMATCHED_ROUTE.to_string());
+            (metrics, req.method().to_string(), pattern)
+        });
+
+        let fut = self.service.call(req);
         async move {
-            match fut.await {
+            let res = fut.await;
+
+            if let Some((metrics, method, pattern)) = route {
+                metrics.observe(&method, &pattern, start.elapsed());
+            }
+
+            match res {
                 Ok(resp) => {
-                    info!(resp_logger, "Processed request"; "status_code" => resp.status().as_u16());
+                    if !quiet || resp.status().is_server_error() {
+                        info!(resp_logger, "Processed request"; "status_code" => resp.status().as_u16());
+                    }
                     Ok(resp)
                 }
                 Err(err) => {
@@ -56,6 +155,7 @@ impl MiddlewareLogger {
                     Err(err)
                 }
             }
-        }.boxed_local()
+        }
+        .boxed_local()
     }
 }
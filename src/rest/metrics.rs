@@ -0,0 +1,286 @@
+//! Per route request latency metrics, served at `/metrics` in the Prometheus
+//! text format.
+//!
+//! Requests are grouped by method and the pattern of the route they matched,
+//! e.g. `GET /api/transactions/{id}`, rather than the raw path, so that IDs
+//! in paths don't create a new series per request. Requests that don't match
+//! any route are grouped under `unmatched`.
+//!
+//! Each group has a latency histogram, plus p50, p95 and p99 estimated from
+//! it. The estimates are only as precise as the buckets.
+//!
+//! Outbound requests are also counted per host, from the [HostMonitor].
+
+use actix_web::web::ServiceConfig;
+use actix_web::{web, HttpResponse};
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::http_client::{HostMonitor, HostStats};
+use crate::rest::AppState;
+
+/// The upper bounds of the latency histogram buckets, in seconds. There's an
+/// implicit final bucket for everything slower.
+pub const LATENCY_BUCKETS_SECS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0,
+];
+
+/// The quantiles reported for each route.
+const QUANTILES: [f64; 3] = [0.5, 0.95, 0.99];
+
+/// The route pattern used for requests that didn't match a route.
+pub const UNMATCHED_ROUTE: &str = "unmatched";
+
+/// Register servlets with HTTP app
+pub fn register_servlets(config: &mut ServiceConfig) {
+    config.route("/metrics", web::get().to(get_metrics));
+}
+
+/// A histogram of request latencies.
+#[derive(Debug, Clone, Default)]
+pub struct Histogram {
+    /// The number of requests in each bucket of [LATENCY_BUCKETS_SECS], and
+    /// then those slower than the last bucket.
+    buckets: [u64; LATENCY_BUCKETS_SECS.len() + 1],
+    count: u64,
+    sum_secs: f64,
+}
+
+impl Histogram {
+    /// Record a request that took the given time.
+    pub fn observe(&mut self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        let bucket = LATENCY_BUCKETS_SECS
+            .iter()
+            .position(|&bound| secs <= bound)
+            .unwrap_or(LATENCY_BUCKETS_SECS.len());
+
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum_secs += secs;
+    }
+
+    /// The number of requests recorded.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// The total time taken by the requests recorded, in seconds.
+    pub fn sum_secs(&self) -> f64 {
+        self.sum_secs
+    }
+
+    /// Estimate the given quantile, in seconds, by interpolating within the
+    /// bucket it falls in. Quantiles in the final, unbounded bucket are
+    /// reported as the last bucket's bound. Returns None if nothing has been
+    /// recorded.
+    pub fn quantile(&self, quantile: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let rank = quantile * self.count as f64;
+        let mut seen = 0;
+        for (idx, &count) in self.buckets.iter().enumerate() {
+            if count == 0 || ((seen + count) as f64) < rank {
+                seen += count;
+                continue;
+            }
+
+            let upper = match LATENCY_BUCKETS_SECS.get(idx) {
+                Some(&upper) => upper,
+                None => break,
+            };
+            let lower = if idx == 0 {
+                0.0
+            } else {
+                LATENCY_BUCKETS_SECS[idx - 1]
+            };
+
+            let fraction = ((rank - seen as f64) / count as f64).max(0.0);
+            return Some(lower + (upper - lower) * fraction);
+        }
+
+        Some(LATENCY_BUCKETS_SECS[LATENCY_BUCKETS_SECS.len() - 1])
+    }
+
+    /// The cumulative count of requests at or below each bucket's bound, as
+    /// Prometheus expects.
+    fn cumulative(&self) -> impl Iterator<Item = (f64, u64)> + '_ {
+        LATENCY_BUCKETS_SECS
+            .iter()
+            .zip(&self.buckets)
+            .scan(0, |total, (&bound, &count)| {
+                *total += count;
+                Some((bound, *total))
+            })
+    }
+}
+
+/// Request latency histograms by method and route pattern. Shared between
+/// all workers.
+#[derive(Debug, Default)]
+pub struct RouteMetrics {
+    routes: Mutex<BTreeMap<(String, String), Histogram>>,
+}
+
+impl RouteMetrics {
+    pub fn new() -> RouteMetrics {
+        RouteMetrics::default()
+    }
+
+    /// Record a request to the route with the given pattern.
+    pub fn observe(&self, method: &str, pattern: &str, duration: Duration) {
+        self.routes
+            .lock()
+            .expect("metrics lock poisoned")
+            .entry((method.to_string(), pattern.to_string()))
+            .or_default()
+            .observe(duration);
+    }
+
+    /// A copy of the histograms so far, keyed by method and route pattern.
+    pub fn snapshot(&self) -> BTreeMap<(String, String), Histogram> {
+        self.routes.lock().expect("metrics lock poisoned").clone()
+    }
+
+    /// Render the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let routes = self.snapshot();
+        let mut out = String::new();
+
+        out.push_str(
+            "# HELP shaft_http_request_duration_seconds Time taken to handle requests.\n\
+             # TYPE shaft_http_request_duration_seconds histogram\n",
+        );
+        for ((method, route), histogram) in &routes {
+            let labels = labels(method, route);
+            for (bound, count) in histogram.cumulative() {
+                writeln!(
+                    out,
+                    "shaft_http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                    labels, bound, count
+                )
+                .expect("writing to a string");
+            }
+            writeln!(
+                out,
+                "shaft_http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}\n\
+                 shaft_http_request_duration_seconds_sum{{{}}} {}\n\
+                 shaft_http_request_duration_seconds_count{{{}}} {}",
+                labels,
+                histogram.count(),
+                labels,
+                histogram.sum_secs(),
+                labels,
+                histogram.count(),
+            )
+            .expect("writing to a string");
+        }
+
+        out.push_str(
+            "# HELP shaft_http_request_duration_quantile_seconds Estimated quantiles of the \
+             time taken to handle requests.\n\
+             # TYPE shaft_http_request_duration_quantile_seconds gauge\n",
+        );
+        for ((method, route), histogram) in &routes {
+            let labels = labels(method, route);
+            for &quantile in &QUANTILES {
+                if let Some(value) = histogram.quantile(quantile) {
+                    writeln!(
+                        out,
+                        "shaft_http_request_duration_quantile_seconds{{{},quantile=\"{}\"}} {}",
+                        labels, quantile, value
+                    )
+                    .expect("writing to a string");
+                }
+            }
+        }
+
+        out
+    }
+}
+
+/// Render the stats of outbound requests in the Prometheus text exposition
+/// format.
+pub fn render_hosts(monitor: &HostMonitor) -> String {
+    let hosts = monitor.stats();
+    let mut out = String::new();
+
+    let families: [(&str, &str, &str, fn(&HostStats) -> String); 5] = [
+        (
+            "shaft_http_client_requests_total",
+            "counter",
+            "Outbound requests sent.",
+            |stats| stats.requests.to_string(),
+        ),
+        (
+            "shaft_http_client_errors_total",
+            "counter",
+            "Outbound requests that failed or got a 5xx response.",
+            |stats| stats.errors.to_string(),
+        ),
+        (
+            "shaft_http_client_rejected_total",
+            "counter",
+            "Outbound requests not sent because the host has been failing.",
+            |stats| stats.rejected.to_string(),
+        ),
+        (
+            "shaft_http_client_latency_seconds_total",
+            "counter",
+            "Time spent waiting for outbound responses.",
+            |stats| stats.latency_secs.to_string(),
+        ),
+        (
+            "shaft_http_client_circuit_open",
+            "gauge",
+            "Whether outbound requests to the host are being rejected.",
+            |stats| (stats.circuit_open as u8).to_string(),
+        ),
+    ];
+
+    for (name, kind, help, value) in &families {
+        writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind)
+            .expect("writing to a string");
+        for (host, stats) in &hosts {
+            writeln!(
+                out,
+                "{}{{host=\"{}\"}} {}",
+                name,
+                escape_label(host),
+                value(stats)
+            )
+            .expect("writing to a string");
+        }
+    }
+
+    out
+}
+
+/// The labels identifying a route's series.
+fn labels(method: &str, route: &str) -> String {
+    format!(
+        "method=\"{}\",route=\"{}\"",
+        escape_label(method),
+        escape_label(route)
+    )
+}
+
+/// Escape a label value for the Prometheus text format.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serve the metrics for scraping.
+async fn get_metrics(state: web::Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(state.metrics.render() + &render_hosts(&state.host_monitor))
+}
@@ -1,89 +1,371 @@
 //! Handles all REST endpoints
 
-use actix_web::web::ServiceConfig;
+use actix_web::http::header::{HttpDate, IfModifiedSince, LastModified};
+use actix_web::web::{Data, Payload, ServiceConfig};
+use actix_web::{Error, HttpMessage, HttpRequest};
+use bytes::{Bytes, BytesMut};
 use chrono;
-use futures_cpupool::CpuPool;
+use futures::StreamExt;
 use handlebars;
 use handlebars::Handlebars;
-use hyper_tls::HttpsConnector;
-use serde::Deserialize;
-use serde_json;
+use hyper::header::IF_NONE_MATCH;
+use slog::Logger;
 
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
+use crate::content_policy::{AllowAllPolicy, ContentPolicy};
 use crate::db;
+use crate::error::ShaftError;
+use crate::events::{AuditLogListener, EventBus};
+use crate::features::FeatureFlags;
+use crate::logging::LogLevels;
+use crate::mailer::{Mailer, NoopMailer};
+use crate::notifier::{NoopNotifier, Notifier};
+use crate::plugin::{PluginListener, ShaftPlugin};
+use crate::rate_limit::{InMemoryRateLimiter, RateLimiter};
+use crate::receipts::{NoopReceiptProcessor, ReceiptProcessor};
+use crate::settings::{
+    ApiSettings, BalancesSettings, CookieSettings, CurrencySettings, FeatureSettings,
+    InviteSettings, OutboxSettings, ReasonSettings, SlackSettings, UiSettings, WebhookSettings,
+};
+use crate::shared_state::{InMemorySharedState, Invalidation, SharedState, SharedStateError};
 
+mod admin;
 mod api;
 mod auth;
+mod authz;
+mod confirm;
+mod deadline;
+mod debts;
+mod flash;
 mod github_login;
+mod graphql;
+mod helpers;
+mod home_assistant;
+mod inbound;
+mod invites;
+mod ip_filter;
+mod ledger;
 mod logger;
+mod metrics;
+mod outbox;
+mod page;
+mod poke;
+mod quickadd;
+mod render;
+mod report_errors;
+mod response;
+mod security;
+mod session;
+mod setup;
+mod slack;
 mod static_files;
+mod tenants;
+mod version;
+mod views;
 mod web;
+mod widget;
 
-use crate::github::GenericHttpClient;
+use crate::http_client::{GenericHttpClient, HostMonitor};
 
-pub use self::auth::{AuthenticateUser, AuthenticatedUser};
-pub use self::logger::MiddlewareLogger;
+pub use self::auth::{
+    AuthCache, AuthenticateUser, AuthenticatedUser, ReadAccess, SessionRefresh, TokenCache,
+    INVALID_TOKEN_LIMIT, INVALID_TOKEN_WINDOW,
+};
+pub use self::confirm::ConfirmationTokens;
+pub use self::deadline::{set_request_stage, RequestDeadline, RequestStage};
+pub use self::debts::spawn_debt_digest;
+pub use self::flash::{has_flash, set_flash, take_flash, Flash, FlashLevel, FLASH_COOKIE_NAME};
+pub use self::helpers::{
+    format_percent, format_relative_time, pluralise, register_helpers, HelperError,
+};
+pub use self::ip_filter::{Cidr, CidrError, IpFilter, IpRules};
+pub use self::ledger::CurrentLedger;
+pub use self::logger::{MiddlewareLogger, ReqLogger, RequestID};
+pub use self::metrics::{Histogram, RouteMetrics, LATENCY_BUCKETS_SECS};
+pub use self::outbox::{dispatch_due, next_attempt, spawn_outbox_dispatcher, WebhookRouter};
+pub use self::page::{NavItem, PageContext, PageUser, Section};
+pub use self::render::RenderCache;
+pub use self::report_errors::ReportErrors;
+pub use self::session::{format_cookie_expires, SessionCookie, SESSION_COOKIE_NAME};
+pub use self::setup::Setup;
+pub use self::tenants::{
+    register_tenant_servlets, tenant_config, tenant_path, validate_tenants, Tenant, TenantError,
+    TenantRegistry, TENANT_PATH_PREFIX,
+};
+
+/// The ways people can log in, in the order the login page shows them.
+fn login_providers() -> Vec<views::LoginProvider> {
+    vec![github_login::login_provider()]
+}
 
 /// Registers all servlets in this module with the HTTP app.
 pub fn register_servlets(config: &mut ServiceConfig, state: &AppState) {
+    graphql::register_servlets(config);
+    slack::register_servlets(config);
+    inbound::register_servlets(config);
+    admin::register_servlets(config);
+    invites::register_servlets(config);
+    metrics::register_servlets(config);
+    version::register_servlets(config);
+    setup::register_servlets(config);
+    tenants::register_admin_servlets(config);
+    outbox::register_admin_servlets(config);
+    for plugin in &state.plugins {
+        plugin.register_routes(config);
+    }
+
+    // Each ledger gets its own copy of the pages and API, so that the
+    // relative links in pages stay within the ledger.
+    let ledger_state = state.clone();
+    config.service(
+        actix_web::web::scope("/l/{ledger}")
+            .configure(move |config| register_ledger_servlets(config, &ledger_state)),
+    );
+
+    register_ledger_servlets(config, state);
+}
+
+/// Registers the servlets that are available for each ledger.
+fn register_ledger_servlets(config: &mut ServiceConfig, state: &AppState) {
     github_login::register_servlets(config);
     api::register_servlets(config);
+    debts::register_servlets(config);
+    poke::register_servlets(config);
+    quickadd::register_servlets(config);
+    invites::register_ledger_servlets(config);
+    widget::register_servlets(config);
+    home_assistant::register_servlets(config);
     static_files::register_servlets(config, state);
     web::register_servlets(config)
 }
 
+/// How many token lookups to cache.
+pub const AUTH_CACHE_CAPACITY: usize = 1024;
+
+/// How long token lookups are cached for. Deleted tokens are invalidated
+/// explicitly, so this mainly bounds how stale a cached display name can get.
+pub const AUTH_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// How long users have to confirm a destructive action.
+const CONFIRMATION_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// How long users have to enter a code to link their Slack account.
+const SLACK_LINK_CODE_TTL: Duration = Duration::from_secs(10 * 60);
+
 // Holds the state for the shared state of the app. Gets cloned to each thread.
 #[derive(Clone)]
 pub struct AppState {
     pub database: Arc<dyn db::Database>,
     pub config: AppConfig,
-    pub cpu_pool: futures_cpupool::CpuPool,
     pub handlebars: Arc<handlebars::Handlebars<'static>>,
     pub http_client: Arc<dyn GenericHttpClient>,
+    /// The health of the hosts `http_client` talks to, if it's an
+    /// [InstrumentedHttpClient](crate::http_client::InstrumentedHttpClient).
+    pub host_monitor: Arc<HostMonitor>,
+    pub receipt_processor: Arc<dyn ReceiptProcessor>,
+    pub mailer: Arc<dyn Mailer>,
+    pub notifier: Arc<dyn Notifier>,
+    /// Applied to reasons before they are stored.
+    pub content_policy: Arc<dyn ContentPolicy>,
+    /// First-run setup, if it is available.
+    pub setup: Option<Arc<Setup>>,
+    /// State shared with other instances behind the same load balancer.
+    /// Set with [AppState::set_shared_state].
+    pub shared_state: Arc<dyn SharedState>,
+    /// Set with [AppState::set_auth_cache].
+    pub auth_cache: Arc<dyn TokenCache>,
+    /// Counts requests that are limited, e.g. with invalid tokens.
+    pub rate_limiter: Arc<dyn RateLimiter>,
+    pub confirmations: Arc<ConfirmationTokens>,
+    /// Codes users enter in Slack to link their Slack account.
+    pub slack_link_codes: Arc<ConfirmationTokens>,
+    pub render_cache: Arc<RenderCache>,
+    /// Request latencies by route, recorded by [MiddlewareLogger].
+    pub metrics: Arc<RouteMetrics>,
+    pub events: EventBus,
+    pub features: Arc<FeatureFlags>,
+    pub log_levels: LogLevels,
+    pub plugins: Vec<Arc<dyn ShaftPlugin>>,
+    /// The tenants hosted alongside the instance.
+    pub tenants: Arc<TenantRegistry>,
+    /// When the state was created. Pages depend on the config and templates
+    /// loaded at startup, so haven't been modified before this.
+    pub started: chrono::DateTime<chrono::Utc>,
 }
 
 impl AppState {
     pub fn new(
         config: AppConfig,
         handlebars: Handlebars<'static>,
-        database: impl db::Database + 'static,
+        database: Arc<dyn db::Database>,
+        http_client: Arc<dyn GenericHttpClient>,
     ) -> AppState {
-        // Thread pool to use mainly for DB
-        let cpu_pool = CpuPool::new_num_cpus();
+        let mut events = EventBus::new();
+        events.add_listener(Arc::new(AuditLogListener::new(database.clone())));
 
-        // Set up HTTPS enabled HTTP client
-        let https = HttpsConnector::new();
-        let http_client = hyper::Client::builder().build::<_, hyper::Body>(https);
+        let shared_state: Arc<dyn SharedState> = Arc::new(InMemorySharedState::new());
 
         AppState {
-            database: Arc::new(database),
-            http_client: Arc::new(http_client),
-            cpu_pool,
+            database,
+            http_client,
+            host_monitor: Arc::new(HostMonitor::new(0, Duration::from_secs(0))),
+            receipt_processor: Arc::new(NoopReceiptProcessor),
+            mailer: Arc::new(NoopMailer),
+            notifier: Arc::new(NoopNotifier),
+            content_policy: Arc::new(AllowAllPolicy),
+            setup: None,
+            auth_cache: Arc::new(AuthCache::new(AUTH_CACHE_CAPACITY, AUTH_CACHE_TTL)),
+            rate_limiter: Arc::new(InMemoryRateLimiter::new()),
+            confirmations: Arc::new(ConfirmationTokens::new(
+                "confirm",
+                CONFIRMATION_TTL,
+                shared_state.clone(),
+            )),
+            slack_link_codes: Arc::new(ConfirmationTokens::new(
+                "slack_link",
+                SLACK_LINK_CODE_TTL,
+                shared_state.clone(),
+            )),
+            shared_state,
+            render_cache: Arc::new(RenderCache::new()),
+            metrics: Arc::new(RouteMetrics::new()),
+            events,
+            features: Arc::new(FeatureFlags::default()),
+            log_levels: LogLevels::default(),
+            plugins: Vec::new(),
+            tenants: Arc::new(TenantRegistry::default()),
+            started: chrono::Utc::now(),
             config,
             handlebars: Arc::new(handlebars),
         }
     }
 
-    pub fn with_http_client(
+    /// The state for a tenant with its own config and database. Services that
+    /// talk to the outside world, e.g. the mailer, are shared with the
+    /// instance, while caches, events and feature flags are the tenant's own.
+    pub fn for_tenant(
+        &self,
         config: AppConfig,
         handlebars: Handlebars<'static>,
-        database: impl db::Database + 'static,
-        http_client: impl GenericHttpClient + 'static,
+        database: Arc<dyn db::Database>,
+        features: &FeatureSettings,
     ) -> AppState {
-        // Thread pool to use mainly for DB
-        let cpu_pool = CpuPool::new_num_cpus();
+        let mut state = AppState::new(config, handlebars, database, self.http_client.clone());
+        state.host_monitor = self.host_monitor.clone();
+        state.receipt_processor = self.receipt_processor.clone();
+        state.mailer = self.mailer.clone();
+        state.notifier = self.notifier.clone();
+        state.content_policy = self.content_policy.clone();
+        state.metrics = self.metrics.clone();
+        state.log_levels = self.log_levels.clone();
+        state.features = Arc::new(FeatureFlags::new(features));
+        state
+    }
 
-        AppState {
-            database: Arc::new(database),
-            http_client: Arc::new(http_client),
-            cpu_pool,
-            config,
-            handlebars: Arc::new(handlebars),
+    /// Keep the state shared between instances in the given store. Must be
+    /// called before the HTTP server is built.
+    pub fn set_shared_state(&mut self, shared_state: Arc<dyn SharedState>) {
+        self.confirmations = Arc::new(ConfirmationTokens::new(
+            "confirm",
+            CONFIRMATION_TTL,
+            shared_state.clone(),
+        ));
+        self.slack_link_codes = Arc::new(ConfirmationTokens::new(
+            "slack_link",
+            SLACK_LINK_CODE_TTL,
+            shared_state.clone(),
+        ));
+        self.shared_state = shared_state;
+    }
+
+    /// Cache token lookups in the given cache, e.g. one shared with other
+    /// instances. Must be called before the HTTP server is built.
+    pub fn set_auth_cache(&mut self, auth_cache: Arc<dyn TokenCache>) {
+        self.auth_cache = auth_cache;
+    }
+
+    /// Drop what this instance has cached, then tell the other instances to
+    /// do the same.
+    pub async fn invalidate(&self, invalidation: Invalidation) -> Result<(), SharedStateError> {
+        self.apply_invalidation(&invalidation).await?;
+        self.shared_state.publish(invalidation).await
+    }
+
+    /// Drop what this instance has cached, e.g. for an invalidation published
+    /// by another instance.
+    pub async fn apply_invalidation(
+        &self,
+        invalidation: &Invalidation,
+    ) -> Result<(), SharedStateError> {
+        match invalidation {
+            Invalidation::User { user_id } => self.auth_cache.invalidate_user(user_id).await?,
+            Invalidation::Feature { feature, enabled } => {
+                self.features.set(*feature, *enabled);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Send events to the configured webhooks, via the outbox. Must be called
+    /// after the feature flags are set, and before the HTTP server is built.
+    pub fn enable_webhooks(&mut self) {
+        if self.config.webhooks.is_empty() {
+            return;
         }
+
+        self.events.set_outbox_router(
+            self.database.clone(),
+            Arc::new(WebhookRouter::new(
+                self.features.clone(),
+                self.config.webhooks.clone(),
+            )),
+        );
+    }
+
+    /// Register a plugin. Must be called before the HTTP server is built.
+    pub fn add_plugin(&mut self, plugin: Arc<dyn ShaftPlugin>) {
+        self.events
+            .add_listener(Arc::new(PluginListener::new(plugin.clone())));
+        self.plugins.push(plugin);
     }
 }
 
+/// How often expired shared state is deleted.
+const SHARED_STATE_PRUNE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Apply the invalidations published by other instances every `interval`,
+/// and periodically delete expired shared state.
+pub fn spawn_shared_state_poller(state: AppState, interval: Duration, logger: Logger) {
+    actix_web::rt::spawn(async move {
+        let mut last_pruned = Instant::now();
+
+        loop {
+            actix_web::rt::time::sleep(interval).await;
+
+            match state.shared_state.poll().await {
+                Ok(invalidations) => {
+                    for invalidation in &invalidations {
+                        if let Err(e) = state.apply_invalidation(invalidation).await {
+                            warn!(logger, "Failed to apply invalidation"; "err" => e.to_string());
+                        }
+                    }
+                }
+                Err(e) => warn!(logger, "Failed to poll shared state"; "err" => e.to_string()),
+            }
+
+            if last_pruned.elapsed() >= SHARED_STATE_PRUNE_INTERVAL {
+                last_pruned = Instant::now();
+                if let Err(e) = state.shared_state.prune().await {
+                    warn!(logger, "Failed to prune shared state"; "err" => e.to_string());
+                }
+            }
+        }
+    });
+}
+
 /// Read only config for the app
 #[derive(Clone)]
 pub struct AppConfig {
@@ -93,55 +375,129 @@ pub struct AppConfig {
     pub web_root: String,
     pub required_org: String,
     pub resource_dir: String,
+    /// The user IDs of instance admins.
+    pub admins: Vec<String>,
+    /// How long a login session lasts, in seconds.
+    pub session_lifetime_secs: u64,
+    /// How long a login session lasts if the user asked to stay logged in,
+    /// in seconds.
+    pub remember_me_lifetime_secs: u64,
+    /// The Slack slash command integration, if enabled.
+    pub slack: Option<SlackSettings>,
+    /// Inviting people to ledgers by email, if enabled.
+    pub invites: Option<InviteSettings>,
+    /// How amounts of money are displayed and entered.
+    pub currency: CurrencySettings,
+    /// What reasons transactions must be given.
+    pub reasons: ReasonSettings,
+    /// How balances are listed on the home page.
+    pub balances: BalancesSettings,
+    /// How the web pages are laid out.
+    pub ui: UiSettings,
+    /// Limits on the JSON API's paginated endpoints.
+    pub api: ApiSettings,
+    /// The attributes of the session cookie.
+    pub cookies: CookieSettings,
+    /// Where events are sent while the `webhooks` feature is enabled.
+    pub webhooks: Vec<WebhookSettings>,
+    /// How notifications and webhooks are delivered.
+    pub outbox: OutboxSettings,
 }
 
-/// Formats the current time plus two weeks into a cookie expires field.
-pub fn get_expires_string() -> String {
-    let dt = chrono::Utc::now() + chrono::Duration::weeks(2);
-    const ITEMS: &[chrono::format::Item<'static>] =
-        &[chrono::format::Item::Fixed(chrono::format::Fixed::RFC2822)];
-    dt.format_with_items(ITEMS.iter().cloned()).to_string()
-}
+/// Checks that a URL to return to after logging in is a path within the app,
+/// so that login links can't be used to send users to another site.
+fn validate_next(next: &str) -> Option<&str> {
+    let is_local_path = next.starts_with('/')
+        && !next.starts_with("//")
+        && !next.contains('\\')
+        && !next.chars().any(char::is_control);
 
-/// Format pence into a pretty pounds string
-fn format_pence_as_pounds(pence: i64) -> String {
-    if pence < 0 {
-        format!("-£{:2}.{:02}", -pence / 100, -pence % 100)
+    if is_local_path {
+        Some(next)
     } else {
-        format!("£{:2}.{:02}", pence / 100, pence % 100)
+        None
+    }
+}
+
+/// The query string for a login URL that returns the user to `next`
+/// afterwards, if any.
+fn next_query(next: Option<&str>) -> String {
+    match next {
+        Some(next) => format!(
+            "?{}",
+            url::form_urlencoded::Serializer::new(String::new())
+                .append_pair("next", next)
+                .finish()
+        ),
+        None => String::new(),
     }
 }
 
-/// Handlebars helper function for formatting pence as points.
-pub fn format_pence_as_pounds_helper(
-    h: &handlebars::Helper,
-    _: &handlebars::Handlebars,
-    _: &handlebars::Context,
-    _: &mut handlebars::RenderContext,
-    out: &mut dyn handlebars::Output,
-) -> Result<(), handlebars::RenderError> {
-    let param = h.param(0).unwrap();
-
-    match *param.value() {
-        serde_json::Value::Number(ref number) => {
-            let pence = number
-                .as_i64()
-                .ok_or_else(|| handlebars::RenderError::new("Param must be a number"))?;
-            out.write(&format_pence_as_pounds(pence))?;
-            Ok(())
+/// Get the request's logger, installed by [MiddlewareLogger].
+fn request_logger(req: &HttpRequest) -> Result<Logger, ShaftError> {
+    req.extensions()
+        .get::<Logger>()
+        .cloned()
+        .ok_or(ShaftError::MissingExtension { name: "logger" })
+}
+
+/// Get the [AppState] registered with `App::app_data`.
+fn app_state(req: &HttpRequest) -> Result<&Data<AppState>, ShaftError> {
+    req.app_data::<Data<AppState>>()
+        .ok_or(ShaftError::MissingAppData { name: "AppState" })
+}
+
+/// Read a request body into memory, failing if it is larger than `limit`
+/// bytes.
+async fn read_body(mut payload: Payload, limit: usize) -> Result<Bytes, Error> {
+    let mut body = BytesMut::new();
+
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if body.len() + chunk.len() > limit {
+            return Err(ShaftError::PayloadTooLarge { limit }.into());
         }
-        _ => Err(handlebars::RenderError::new("Param must be a number")),
+        body.extend_from_slice(&chunk);
     }
+
+    Ok(body.freeze())
+}
+
+/// Formats a ledger version as a weak ETag.
+fn ledger_etag(version: i64) -> String {
+    format!("W/\"ledger-{}\"", version)
+}
+
+/// Whether the request has an `If-None-Match` header matching the given ETag,
+/// using weak comparison.
+fn etag_matches(req: &HttpRequest, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+
+    req.headers()
+        .get(IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| {
+            value.split(',').any(|candidate| {
+                let candidate = candidate.trim();
+                candidate == "*" || candidate.trim_start_matches("W/") == etag
+            })
+        })
+        .unwrap_or(false)
+}
+
+/// The `Last-Modified` header for the given time.
+fn last_modified_header(last_modified: chrono::DateTime<chrono::Utc>) -> LastModified {
+    LastModified(HttpDate::from(SystemTime::from(last_modified)))
 }
 
-/// The body of a incoming request shaft the given user.
-#[derive(Deserialize)]
-struct ShaftUserBody {
-    /// The other party in the transaction.
-    other_user: String,
-    /// The amount in pence owed. Positive means shafter is owed money by other
-    /// user, negative means shafer owes money.
-    amount: i64,
-    /// The human readable description of the transasction.
-    reason: String,
+/// Whether the request has an `If-Modified-Since` header that isn't before
+/// the given time. HTTP dates are in whole seconds, so only those are
+/// compared.
+fn not_modified_since(req: &HttpRequest, last_modified: chrono::DateTime<chrono::Utc>) -> bool {
+    req.get_header::<IfModifiedSince>()
+        .map(|IfModifiedSince(since)| {
+            chrono::DateTime::<chrono::Utc>::from(SystemTime::from(since)).timestamp()
+                >= last_modified.timestamp()
+        })
+        .unwrap_or(false)
 }
@@ -0,0 +1,586 @@
+//! Delivering notifications and webhooks so that they aren't lost.
+//!
+//! Deliveries are written to the outbox table before they're attempted. One
+//! that fails, or that is interrupted by a crash, is retried by the
+//! [dispatcher](spawn_outbox_dispatcher) with exponential backoff, until it
+//! succeeds or has been tried `max_attempts` times.
+//!
+//! Webhook deliveries are worked out by [WebhookRouter]. Those for a change
+//! are added in the same database transaction as the change, via its
+//! [Outbox](crate::db::Outbox), so they're kept if and only if the change is.
+//! Those for events that aren't changes, e.g. pokes, are added as the event
+//! is published. Notifications are added by the handler sending them, which
+//! then tries to deliver them straight away.
+//!
+//! Webhooks are POSTed the event as JSON, with a `type` field naming it, e.g.
+//!
+//! ```json
+//! { "type": "user_poked", "actor": "alice", "user_id": "bob" }
+//! ```
+//!
+//! signed as described in [webhooks](crate::webhooks).
+//!
+//! `GET /api/admin/outbox` lists deliveries newest first, optionally only
+//! those with the given `status` (`pending`, `delivered` or `failed`) or
+//! `target` (a user ID or webhook ID). Paginated like the audit log.
+//!
+//! `GET /api/admin/webhooks/{id}/deliveries` lists a webhook's deliveries in
+//! the same way, each with an `attempt_log` of its attempts: when they were
+//! made, the response's `status_code`, `latency_ms`, the start of the
+//! `response` body and the `error`, if any. Whether and when a delivery will
+//! be retried is in its `status` and `next_attempt`.
+//!
+//! `POST /api/admin/webhooks/{id}/deliveries/{delivery_id}/redeliver` sends a
+//! delivery's payload again as a new delivery, returning its
+//! `delivery_id`.
+
+use actix_web::web::ServiceConfig;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Request};
+use serde::Deserialize;
+use serde_json::json;
+use slog::Logger;
+use snafu::ResultExt;
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::db::{
+    self, NewOutboxMessage, OutboxAttempt, OutboxFilter, OutboxKind, OutboxMessage, OutboxRouter,
+    OutboxStatus, Scope,
+};
+use crate::error::{DatabaseError, ShaftError};
+use crate::events::Event;
+use crate::features::{Feature, FeatureFlags};
+use crate::notifier::Notification;
+use crate::rest::response::json_response;
+use crate::rest::{authz, AppState, AuthenticatedUser, ReqLogger};
+use crate::settings::WebhookSettings;
+use crate::webhooks::{self, DELIVERY_HEADER, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+
+/// How long a claimed delivery has to be attempted before another dispatcher
+/// may claim it, in seconds.
+const DELIVERY_LEASE_SECS: i64 = 60;
+
+/// How many due deliveries are attempted at a time.
+const DISPATCH_BATCH_SIZE: u32 = 50;
+
+/// How long to wait before the first retry, in seconds. Doubles after each
+/// failure.
+const RETRY_BASE_SECS: i64 = 30;
+
+/// The longest to wait between retries, in seconds.
+const RETRY_MAX_SECS: i64 = 60 * 60;
+
+/// How often finished deliveries older than the retention period are deleted.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How much of a webhook's response body is recorded, in characters.
+const RESPONSE_SNIPPET_CHARS: usize = 500;
+
+/// Register servlets with HTTP app
+pub fn register_admin_servlets(config: &mut ServiceConfig) {
+    config.route("/api/admin/outbox", web::get().to(get_outbox));
+    config.route(
+        "/api/admin/webhooks/{id}/deliveries",
+        web::get().to(get_webhook_deliveries),
+    );
+    config.route(
+        "/api/admin/webhooks/{id}/deliveries/{delivery_id}/redeliver",
+        web::post().to(redeliver_webhook),
+    );
+}
+
+/// Routes each event to the webhooks interested in it, while the `webhooks`
+/// feature is enabled.
+pub struct WebhookRouter {
+    features: Arc<FeatureFlags>,
+    webhooks: Vec<WebhookSettings>,
+}
+
+impl WebhookRouter {
+    pub fn new(features: Arc<FeatureFlags>, webhooks: Vec<WebhookSettings>) -> WebhookRouter {
+        WebhookRouter { features, webhooks }
+    }
+}
+
+impl OutboxRouter for WebhookRouter {
+    fn messages(&self, event: &Event) -> Vec<NewOutboxMessage> {
+        if !self.features.is_enabled(Feature::Webhooks) {
+            return Vec::new();
+        }
+
+        let payload = serde_json::to_value(event).expect("events can be serialized to JSON");
+        let event_type = payload["type"].as_str().unwrap_or_default();
+
+        self.webhooks
+            .iter()
+            .filter(|webhook| {
+                webhook.events.is_empty() || webhook.events.iter().any(|e| e == event_type)
+            })
+            .map(|webhook| NewOutboxMessage {
+                kind: OutboxKind::Webhook,
+                target: webhook.id.clone(),
+                payload: payload.to_string(),
+            })
+            .collect()
+    }
+}
+
+/// Add a notification to the outbox, then try to deliver it. If that fails
+/// it's left for the dispatcher to retry.
+pub async fn send_notification(
+    state: &AppState,
+    notification: Notification,
+    logger: &Logger,
+) -> Result<(), db::DatabaseError> {
+    let payload =
+        serde_json::to_string(&notification).expect("notifications can be serialized to JSON");
+
+    send_message(
+        state,
+        NewOutboxMessage {
+            kind: OutboxKind::Notification,
+            target: notification.user_id,
+            payload,
+        },
+        logger,
+    )
+    .await
+    .map(|_| ())
+}
+
+/// Add a message to the outbox, then try to deliver it, returning its ID. If
+/// delivery fails it's left for the dispatcher to retry.
+async fn send_message(
+    state: &AppState,
+    new_message: NewOutboxMessage,
+    logger: &Logger,
+) -> Result<i64, db::DatabaseError> {
+    // Claim the delivery for ourselves, so a dispatcher doesn't also try it.
+    let now = chrono::Utc::now();
+    let lease_until = now + chrono::Duration::seconds(DELIVERY_LEASE_SECS);
+    let ids = state
+        .database
+        .add_outbox_messages(vec![new_message.clone()], lease_until)
+        .await?;
+
+    let message = OutboxMessage {
+        id: ids[0],
+        kind: new_message.kind,
+        target: new_message.target,
+        payload: new_message.payload,
+        status: OutboxStatus::Pending,
+        attempts: 0,
+        last_error: None,
+        created: now,
+        next_attempt: Some(lease_until),
+        finished: None,
+    };
+    attempt_delivery(state, &message, logger).await?;
+
+    Ok(message.id)
+}
+
+/// Attempt every due delivery in the outbox, returning how many were
+/// attempted.
+pub async fn dispatch_due(state: &AppState, logger: &Logger) -> Result<usize, db::DatabaseError> {
+    let lease_until = chrono::Utc::now() + chrono::Duration::seconds(DELIVERY_LEASE_SECS);
+    let messages = state
+        .database
+        .claim_outbox_messages(lease_until, DISPATCH_BATCH_SIZE)
+        .await?;
+
+    for message in &messages {
+        attempt_delivery(state, message, logger).await?;
+    }
+
+    Ok(messages.len())
+}
+
+/// Attempt deliveries from the outbox every `poll_interval_secs`, and
+/// periodically delete those that have finished.
+pub fn spawn_outbox_dispatcher(state: AppState, logger: Logger) {
+    actix_web::rt::spawn(async move {
+        let settings = state.config.outbox.clone();
+        let interval = Duration::from_secs(settings.poll_interval_secs);
+        let retention = chrono::Duration::days(i64::from(settings.retention_days));
+        let mut last_pruned = Instant::now();
+
+        loop {
+            actix_web::rt::time::sleep(interval).await;
+
+            if let Err(e) = dispatch_due(&state, &logger).await {
+                warn!(logger, "Failed to dispatch outbox"; "err" => e.to_string());
+            }
+
+            if last_pruned.elapsed() >= PRUNE_INTERVAL {
+                last_pruned = Instant::now();
+                let res = state
+                    .database
+                    .prune_outbox(chrono::Utc::now() - retention)
+                    .await;
+                if let Err(e) = res {
+                    warn!(logger, "Failed to prune outbox"; "err" => e.to_string());
+                }
+            }
+        }
+    });
+}
+
+/// Try to deliver the message, recording how it went.
+async fn attempt_delivery(
+    state: &AppState,
+    message: &OutboxMessage,
+    logger: &Logger,
+) -> Result<(), db::DatabaseError> {
+    let logger = logger.new(o!(
+        "outbox_id" => message.id,
+        "kind" => message.kind.as_str(),
+        "target" => message.target.clone(),
+    ));
+
+    let started = Instant::now();
+    let outcome = deliver(state, message).await;
+    let latency_ms = started.elapsed().as_millis() as i64;
+
+    let next_attempt = match &outcome.error {
+        None => {
+            debug!(logger, "Delivered outbox message");
+            None
+        }
+        Some(error) => {
+            let next_attempt = next_attempt(
+                message.attempts + 1,
+                state.config.outbox.max_attempts,
+                chrono::Utc::now(),
+            );
+            if next_attempt.is_some() {
+                info!(logger, "Failed to deliver outbox message, will retry"; "err" => error);
+            } else {
+                warn!(logger, "Giving up delivering outbox message"; "err" => error);
+            }
+            next_attempt
+        }
+    };
+
+    state
+        .database
+        .record_outbox_attempt(
+            message.id,
+            OutboxAttempt {
+                error: outcome.error,
+                next_attempt,
+                status_code: outcome.status_code,
+                latency_ms,
+                response: outcome.response,
+            },
+        )
+        .await
+}
+
+/// When to retry a delivery that has failed `attempts` times, or None if it
+/// shouldn't be.
+pub fn next_attempt(
+    attempts: i64,
+    max_attempts: u32,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Option<chrono::DateTime<chrono::Utc>> {
+    if attempts >= i64::from(max_attempts) {
+        return None;
+    }
+
+    let exponent = (attempts - 1).max(0).min(16) as u32;
+    let delay = (RETRY_BASE_SECS * 2i64.pow(exponent)).min(RETRY_MAX_SECS);
+
+    Some(now + chrono::Duration::seconds(delay))
+}
+
+/// What happened when delivering a message.
+#[derive(Default)]
+struct DeliveryOutcome {
+    /// Why delivery failed, if it did.
+    error: Option<String>,
+    /// The HTTP status code of the webhook's response.
+    status_code: Option<u16>,
+    /// The start of the webhook's response body.
+    response: Option<String>,
+}
+
+impl DeliveryOutcome {
+    fn failed(error: impl ToString) -> DeliveryOutcome {
+        DeliveryOutcome {
+            error: Some(error.to_string()),
+            ..DeliveryOutcome::default()
+        }
+    }
+}
+
+/// Deliver the message, returning how it went.
+async fn deliver(state: &AppState, message: &OutboxMessage) -> DeliveryOutcome {
+    match message.kind {
+        OutboxKind::Notification => {
+            let notification: Notification = match serde_json::from_str(&message.payload) {
+                Ok(notification) => notification,
+                Err(e) => return DeliveryOutcome::failed(e),
+            };
+
+            match state.notifier.notify(notification).await {
+                Ok(()) => DeliveryOutcome::default(),
+                Err(e) => DeliveryOutcome::failed(e),
+            }
+        }
+        OutboxKind::Webhook => deliver_webhook(state, message).await,
+    }
+}
+
+/// POST the message to its webhook, signed with the webhook's secret.
+async fn deliver_webhook(state: &AppState, message: &OutboxMessage) -> DeliveryOutcome {
+    let webhook = match state
+        .config
+        .webhooks
+        .iter()
+        .find(|webhook| webhook.id == message.target)
+    {
+        Some(webhook) => webhook,
+        None => return DeliveryOutcome::failed(format!("Unknown webhook {}", message.target)),
+    };
+
+    let timestamp = chrono::Utc::now().timestamp().to_string();
+    let signature = match webhooks::sign(&webhook.secret, &timestamp, message.payload.as_bytes()) {
+        Ok(signature) => signature,
+        Err(e) => return DeliveryOutcome::failed(e),
+    };
+
+    let req = Request::post(webhook.url.as_str())
+        .header(CONTENT_TYPE, "application/json")
+        .header(DELIVERY_HEADER, message.id.to_string())
+        .header(TIMESTAMP_HEADER, timestamp)
+        .header(SIGNATURE_HEADER, signature)
+        .body(Body::from(message.payload.clone()));
+    let req = match req {
+        Ok(req) => req,
+        Err(e) => return DeliveryOutcome::failed(e),
+    };
+
+    let resp = match state.http_client.request(req).await {
+        Ok(resp) => resp,
+        Err(e) => return DeliveryOutcome::failed(e),
+    };
+
+    let status = resp.status();
+    // The body is only recorded to help debugging, so failing to read it
+    // doesn't fail the delivery.
+    let response = hyper::body::to_bytes(resp.into_body())
+        .await
+        .ok()
+        .filter(|body| !body.is_empty())
+        .map(|body| {
+            String::from_utf8_lossy(&body)
+                .chars()
+                .take(RESPONSE_SNIPPET_CHARS)
+                .collect()
+        });
+
+    DeliveryOutcome {
+        error: if status.is_success() {
+            None
+        } else {
+            Some(format!("Got {} response", status))
+        },
+        status_code: Some(status.as_u16()),
+        response,
+    }
+}
+
+/// Query parameters for `/api/admin/outbox`
+#[derive(Deserialize)]
+struct OutboxQuery {
+    status: Option<OutboxStatus>,
+    /// Only include deliveries to this user or webhook.
+    target: Option<String>,
+    /// The `next_before` returned by the previous page, if any.
+    before: Option<i64>,
+    /// The maximum number of deliveries to return.
+    #[serde(default = "default_outbox_limit")]
+    limit: u32,
+}
+
+fn default_outbox_limit() -> u32 {
+    100
+}
+
+/// Get the deliveries in the outbox, newest first.
+async fn get_outbox(
+    (req, state, user, query): (
+        HttpRequest,
+        web::Data<AppState>,
+        AuthenticatedUser,
+        web::Query<OutboxQuery>,
+    ),
+) -> Result<HttpResponse, Error> {
+    authz::require_scope(&user, Scope::Admin)?;
+
+    let query = query.into_inner();
+    let limit = query.limit.min(1000);
+
+    let messages = state
+        .database
+        .get_outbox_messages(OutboxFilter {
+            kind: None,
+            status: query.status,
+            target: query.target,
+            before: query.before,
+            limit,
+        })
+        .await
+        .context(DatabaseError)?;
+
+    let next_before = if messages.len() as u32 == limit {
+        messages.last().map(|message| message.id)
+    } else {
+        None
+    };
+
+    Ok(json_response(
+        &req,
+        HttpResponse::Ok(),
+        &json!({
+            "deliveries": messages,
+            "next_before": next_before,
+        }),
+    ))
+}
+
+/// Query parameters for `/api/admin/webhooks/{id}/deliveries`
+#[derive(Deserialize)]
+struct WebhookDeliveriesQuery {
+    status: Option<OutboxStatus>,
+    /// The `next_before` returned by the previous page, if any.
+    before: Option<i64>,
+    /// The maximum number of deliveries to return.
+    #[serde(default = "default_webhook_deliveries_limit")]
+    limit: u32,
+}
+
+fn default_webhook_deliveries_limit() -> u32 {
+    20
+}
+
+/// Get the configured webhook with the given ID.
+fn find_webhook<'a>(state: &'a AppState, id: &str) -> Result<&'a WebhookSettings, ShaftError> {
+    state
+        .config
+        .webhooks
+        .iter()
+        .find(|webhook| webhook.id == id)
+        .ok_or(ShaftError::NotFound { what: "webhook" })
+}
+
+/// Get a webhook's deliveries, newest first, along with their attempts.
+async fn get_webhook_deliveries(
+    (req, state, user, path, query): (
+        HttpRequest,
+        web::Data<AppState>,
+        AuthenticatedUser,
+        web::Path<String>,
+        web::Query<WebhookDeliveriesQuery>,
+    ),
+) -> Result<HttpResponse, Error> {
+    authz::require_scope(&user, Scope::Admin)?;
+
+    let webhook = find_webhook(&state, &path)?;
+    let query = query.into_inner();
+    let limit = query.limit.min(100);
+
+    let messages = state
+        .database
+        .get_outbox_messages(OutboxFilter {
+            kind: Some(OutboxKind::Webhook),
+            status: query.status,
+            target: Some(webhook.id.clone()),
+            before: query.before,
+            limit,
+        })
+        .await
+        .context(DatabaseError)?;
+
+    let attempts = state
+        .database
+        .get_outbox_attempts(messages.iter().map(|message| message.id).collect())
+        .await
+        .context(DatabaseError)?;
+
+    let next_before = if messages.len() as u32 == limit {
+        messages.last().map(|message| message.id)
+    } else {
+        None
+    };
+
+    let deliveries: Vec<_> = messages
+        .iter()
+        .map(|message| {
+            let mut delivery = json!(message);
+            delivery["attempt_log"] = json!(attempts
+                .iter()
+                .filter(|attempt| attempt.outbox_id == message.id)
+                .collect::<Vec<_>>());
+            delivery
+        })
+        .collect();
+
+    Ok(json_response(
+        &req,
+        HttpResponse::Ok(),
+        &json!({
+            "deliveries": deliveries,
+            "next_before": next_before,
+        }),
+    ))
+}
+
+/// Send a webhook delivery's payload again, as a new delivery.
+async fn redeliver_webhook(
+    (req, state, user, path, ReqLogger(logger)): (
+        HttpRequest,
+        web::Data<AppState>,
+        AuthenticatedUser,
+        web::Path<(String, i64)>,
+        ReqLogger,
+    ),
+) -> Result<HttpResponse, Error> {
+    authz::require_scope(&user, Scope::Admin)?;
+
+    let (webhook_id, delivery_id) = path.into_inner();
+    let webhook = find_webhook(&state, &webhook_id)?;
+
+    let message = state
+        .database
+        .get_outbox_message(delivery_id)
+        .await
+        .context(DatabaseError)?
+        .filter(|message| message.kind == OutboxKind::Webhook && message.target == webhook.id)
+        .ok_or(ShaftError::NotFound { what: "delivery" })?;
+
+    let new_id = send_message(
+        &state,
+        NewOutboxMessage {
+            kind: OutboxKind::Webhook,
+            target: message.target,
+            payload: message.payload,
+        },
+        &logger,
+    )
+    .await
+    .context(DatabaseError)?;
+
+    info!(logger, "Redelivered webhook";
+        "webhook" => webhook_id, "delivery_id" => delivery_id, "new_delivery_id" => new_id);
+
+    Ok(json_response(
+        &req,
+        HttpResponse::Ok(),
+        &json!({ "delivery_id": new_id }),
+    ))
+}
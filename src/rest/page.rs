@@ -0,0 +1,183 @@
+//! The fields every page's template gets, e.g. who's logged in, so that the
+//! shared layout in `base.hbs` can rely on them.
+//!
+//! Handlers build a [PageContext] for the logged in user and render their
+//! page's own data with it, e.g.
+//!
+//! ```ignore
+//! let s = PageContext::new(&state)
+//!     .user(Some(&user))
+//!     .section(Section::Balances)
+//!     .render(&state.handlebars, "index", &page)?;
+//! ```
+//!
+//! The page's fields are merged in alongside the common ones, so must not
+//! reuse their names.
+
+use handlebars::{Handlebars, RenderError};
+use serde::Serialize;
+
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use crate::db::Scope;
+use crate::features::Feature;
+use crate::rest::{AppState, AuthenticatedUser, Flash};
+use crate::version::VERSION;
+
+/// The parts of the site in the navigation bar.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Section {
+    Balances,
+    Transactions,
+    Admin,
+}
+
+/// The logged in user, as shown on every page.
+#[derive(Debug, Clone, Serialize)]
+pub struct PageUser {
+    pub user_id: String,
+    pub display_name: String,
+    /// Whether they can see the admin dashboard.
+    pub admin: bool,
+}
+
+/// A link in the navigation bar.
+#[derive(Debug, Clone, Serialize)]
+pub struct NavItem {
+    pub name: &'static str,
+    pub href: String,
+    /// Whether it's the section being viewed.
+    pub active: bool,
+}
+
+/// The fields common to every page. Owns its data, so that it can be moved
+/// to where a page is rendered, e.g. when streaming.
+#[derive(Debug, Clone, Serialize)]
+pub struct PageContext {
+    /// The logged in user, if any.
+    user: Option<PageUser>,
+    web_root: String,
+    version: &'static str,
+    /// A message from the previous request, see [crate::rest::flash].
+    flash: Option<Flash>,
+    nav: Vec<NavItem>,
+    /// Whether each [Feature] is enabled, by name.
+    features: BTreeMap<Feature, bool>,
+}
+
+impl PageContext {
+    /// A context for a logged out user, with nothing in the navigation bar
+    /// marked as active.
+    pub fn new(state: &AppState) -> PageContext {
+        let mut context = PageContext {
+            user: None,
+            web_root: state.config.web_root.clone(),
+            version: VERSION,
+            flash: None,
+            nav: Vec::new(),
+            features: state.features.snapshot(),
+        };
+        context.nav = context.nav_items();
+        context
+    }
+
+    /// Set the logged in user, if any.
+    pub fn user(mut self, user: Option<&AuthenticatedUser>) -> PageContext {
+        self.user = user.map(|user| PageUser {
+            user_id: user.user_id.clone(),
+            display_name: user.display_name.clone(),
+            admin: user.scopes.iter().any(|scope| scope.grants(Scope::Admin)),
+        });
+        self.nav = self.nav_items();
+        self
+    }
+
+    /// Show the message from the previous request.
+    pub fn flash(mut self, flash: Option<Flash>) -> PageContext {
+        self.flash = flash;
+        self
+    }
+
+    /// Mark the section being viewed in the navigation bar.
+    pub fn section(mut self, section: Section) -> PageContext {
+        let sections = self.sections();
+        for (item, item_section) in self.nav.iter_mut().zip(sections) {
+            item.active = item_section == section;
+        }
+        self
+    }
+
+    /// The sections in the navigation bar, which depend on the user.
+    fn sections(&self) -> Vec<Section> {
+        let mut sections = vec![Section::Balances, Section::Transactions];
+        if self.user.as_ref().map_or(false, |user| user.admin) {
+            sections.push(Section::Admin);
+        }
+        sections
+    }
+
+    fn nav_items(&self) -> Vec<NavItem> {
+        // Pages within a ledger link to that ledger's pages, so those links
+        // are relative, but there's only the one admin dashboard.
+        self.sections()
+            .into_iter()
+            .map(|section| match section {
+                Section::Balances => NavItem {
+                    name: "Balances",
+                    href: "home".to_string(),
+                    active: false,
+                },
+                Section::Transactions => NavItem {
+                    name: "Transactions",
+                    href: "transactions".to_string(),
+                    active: false,
+                },
+                Section::Admin => NavItem {
+                    name: "Admin",
+                    href: format!("{}/admin", self.web_root),
+                    active: false,
+                },
+            })
+            .collect()
+    }
+
+    /// The data to render a page's template with: the page's own fields
+    /// along with the common ones.
+    pub fn with<'a, T: Serialize>(&'a self, page: &'a T) -> impl Serialize + 'a {
+        PageData {
+            context: self,
+            page,
+        }
+    }
+
+    /// Render the named template with the page's data.
+    pub fn render<T: Serialize>(
+        &self,
+        hb: &Handlebars,
+        name: &str,
+        page: &T,
+    ) -> Result<String, RenderError> {
+        hb.render(name, &self.with(page))
+    }
+
+    /// Render the named template with the page's data into the writer.
+    pub fn render_to_write<T: Serialize>(
+        &self,
+        hb: &Handlebars,
+        name: &str,
+        page: &T,
+        out: &mut dyn Write,
+    ) -> Result<(), RenderError> {
+        hb.render_to_write(name, &self.with(page), out)
+    }
+}
+
+/// A page's data merged with the [PageContext].
+#[derive(Serialize)]
+struct PageData<'a, T> {
+    #[serde(flatten)]
+    context: &'a PageContext,
+    #[serde(flatten)]
+    page: &'a T,
+}
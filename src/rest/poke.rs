@@ -0,0 +1,120 @@
+//! Reminding another user of their balance.
+//!
+//! `POST /api/users/{id}/poke` sends the user a notification with their
+//! balance in the current ledger, via the outbox and the configured
+//! [Notifier](crate::notifier::Notifier). Each user can poke another at most
+//! once a day; pokes are recorded in the audit log, which is also what the
+//! limit is checked against.
+
+use actix_web::web::ServiceConfig;
+use actix_web::{web, Error};
+use serde::Deserialize;
+use serde_json::json;
+use snafu::ResultExt;
+
+use crate::db::{AuditFilter, Scope};
+use crate::error::{DatabaseError, EventError, ShaftError};
+use crate::events::Event;
+use crate::money;
+use crate::notifier::Notification;
+use crate::rest::outbox::send_notification;
+use crate::rest::response::ApiJson;
+use crate::rest::{authz, AppState, AuthenticatedUser, CurrentLedger, ReqLogger};
+
+/// The audit log action pokes are recorded as.
+const POKE_ACTION: &str = "user.poke";
+
+/// How long a user must wait between poking the same user, in seconds.
+const POKE_INTERVAL_SECS: i64 = 24 * 60 * 60;
+
+/// Register servlets with HTTP app
+pub fn register_servlets(config: &mut ServiceConfig) {
+    config.route("/api/users/{id}/poke", web::post().to(poke_user));
+}
+
+/// The path of a poke request. Also matches paths within a ledger.
+#[derive(Deserialize)]
+struct UserPath {
+    id: String,
+}
+
+/// Remind a member of the current ledger of their balance.
+async fn poke_user(
+    (state, user, ledger, path, ReqLogger(logger)): (
+        web::Data<AppState>,
+        AuthenticatedUser,
+        CurrentLedger,
+        web::Path<UserPath>,
+        ReqLogger,
+    ),
+) -> Result<ApiJson<impl serde::Serialize>, Error> {
+    authz::require_scope(&user, Scope::Write)?;
+
+    let user_id = path.into_inner().id;
+    if user_id == user.user_id {
+        return Err(ShaftError::BadRequest {
+            message: "You can't poke yourself".to_string(),
+        }
+        .into());
+    }
+
+    let users = state
+        .database
+        .get_all_users(ledger.id)
+        .await
+        .context(DatabaseError)?;
+    let poked = users
+        .get(&user_id)
+        .ok_or(ShaftError::NotFound { what: "user" })?;
+
+    let now = chrono::Utc::now();
+    let interval = chrono::Duration::seconds(POKE_INTERVAL_SECS);
+    let last_poke = state
+        .database
+        .get_audit_entries(AuditFilter {
+            actor: Some(user.user_id.clone()),
+            action: Some(POKE_ACTION.to_string()),
+            target: Some(user_id.clone()),
+            from: Some(now - interval),
+            limit: 1,
+            ..AuditFilter::default()
+        })
+        .await
+        .context(DatabaseError)?
+        .into_iter()
+        .next();
+    if let Some(last_poke) = last_poke {
+        let retry_after = last_poke.datetime + interval - now;
+        return Err(ShaftError::RateLimited {
+            message: format!("You've already poked {} today", poked.display_name),
+            retry_after_secs: retry_after.num_seconds().max(1),
+        }
+        .into());
+    }
+
+    let notification = Notification {
+        user_id: user_id.clone(),
+        title: format!("{} poked you", user.display_name),
+        text: format!(
+            "{} is reminding you to settle up. Your balance is {}.",
+            user.display_name,
+            money::format(poked.balance, &state.config.currency),
+        ),
+    };
+    send_notification(&state, notification, &logger)
+        .await
+        .context(DatabaseError)?;
+
+    state
+        .events
+        .publish(Event::UserPoked {
+            actor: user.user_id.clone(),
+            user_id: user_id.clone(),
+        })
+        .await
+        .context(EventError)?;
+
+    info!(logger, "Poked user"; "user" => user_id);
+
+    Ok(ApiJson(json!({})))
+}
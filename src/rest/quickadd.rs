@@ -0,0 +1,215 @@
+//! Adding a transaction from a single line of text, for keyboard driven
+//! clients.
+//!
+//! `POST /api/quickadd` with
+//!
+//! ```json
+//! { "text": "bob 12.50 pizza" }
+//! ```
+//!
+//! records that the logged in user paid 12.50 for bob. The amount may also
+//! come first, e.g. `12.50 bob pizza`, and the user is matched against IDs and
+//! display names, so `Bob` or `@bo` work too. If the user is ambiguous, or only
+//! matched as a likely typo, nothing is created and the response lists the
+//! candidates:
+//!
+//! ```json
+//! {
+//!   "status": "ambiguous",
+//!   "candidates": [{ "user_id": "bob", "display_name": "Bob" }, ...],
+//!   "amount": 1250,
+//!   "reason": "pizza"
+//! }
+//! ```
+//!
+//! Sending the request again with `"other_user"` set to one of the
+//! candidates' IDs creates the transaction, and the response has
+//! `"status": "created"` and the `transaction_id`.
+
+use actix_web::web::{Json, ServiceConfig};
+use actix_web::{web, Error};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use snafu::ResultExt;
+
+use crate::amount::parse_amount;
+use crate::db::{self, Scope};
+use crate::error::{DatabaseError, EventError, InvalidReason, ShaftError};
+use crate::matcher::{match_user, UserMatch};
+use crate::money::Money;
+use crate::reason::check_reason;
+use crate::rest::response::ApiJson;
+use crate::rest::{authz, AppState, AuthenticatedUser, CurrentLedger, ReqLogger};
+use crate::settings::CurrencySettings;
+
+/// Register servlets with HTTP app
+pub fn register_servlets(config: &mut ServiceConfig) {
+    config.route("/api/quickadd", web::post().to(quick_add));
+}
+
+/// The body of a `POST /api/quickadd` request.
+#[derive(Deserialize)]
+struct QuickAddBody {
+    text: String,
+    /// The user picked from the candidates of an ambiguous request, which
+    /// overrides the user in the text.
+    other_user: Option<String>,
+}
+
+/// A quick-add line split into its parts.
+#[derive(Debug)]
+struct QuickAddLine<'a> {
+    /// What was typed for the other user.
+    user: &'a str,
+    amount: Money,
+    reason: &'a str,
+}
+
+/// Someone the user in a quick-add line may have meant.
+#[derive(Serialize)]
+struct Candidate<'a> {
+    user_id: &'a str,
+    display_name: &'a str,
+}
+
+/// Split off the first word, returning it and the rest of the text.
+fn split_word(text: &str) -> (&str, &str) {
+    let text = text.trim();
+    match text.find(char::is_whitespace) {
+        Some(idx) => (&text[..idx], text[idx..].trim_start()),
+        None => (text, ""),
+    }
+}
+
+/// Parse `user amount reason` or `amount user reason`.
+fn parse_line<'a>(text: &'a str, currency: &CurrencySettings) -> Result<QuickAddLine<'a>, String> {
+    let (first, rest) = split_word(text);
+    let (second, reason) = split_word(rest);
+
+    let parse = |amount: &str| {
+        parse_amount(amount, currency)
+            .ok()
+            .map(|amount| Money::new(amount, currency.currency()))
+    };
+
+    let (user, amount) = match (parse(first), parse(second)) {
+        (_, Some(amount)) => (first, amount),
+        (Some(amount), None) => (second, amount),
+        (None, None) => {
+            return Err(format!(
+                "Expected a user and an amount, e.g. \"bob 12.50 pizza\", got \"{}\"",
+                text.trim()
+            ))
+        }
+    };
+
+    if user.is_empty() {
+        return Err("No user given".to_string());
+    }
+    if !amount.is_positive() {
+        return Err("The amount must be positive".to_string());
+    }
+
+    Ok(QuickAddLine {
+        user,
+        amount,
+        reason,
+    })
+}
+
+/// Create a transaction from a line of text, or list the candidates if the
+/// user is ambiguous.
+async fn quick_add(
+    (state, user, ledger, body, ReqLogger(logger)): (
+        web::Data<AppState>,
+        AuthenticatedUser,
+        CurrentLedger,
+        Json<QuickAddBody>,
+        ReqLogger,
+    ),
+) -> Result<ApiJson<impl Serialize>, Error> {
+    authz::require_scope(&user, Scope::Write)?;
+
+    let QuickAddBody { text, other_user } = body.into_inner();
+    let line = parse_line(&text, &state.config.currency)
+        .map_err(|message| ShaftError::BadRequest { message })?;
+    let reason = check_reason(line.reason, &state.config.reasons, &*state.content_policy)
+        .context(InvalidReason)?;
+
+    let users = state
+        .database
+        .get_all_users(ledger.id)
+        .await
+        .context(DatabaseError)?;
+    let others = users.values().filter(|other| other.user_id != user.user_id);
+
+    let other_user = match other_user {
+        Some(other_user) => match users.get(&other_user) {
+            Some(other) if other.user_id != user.user_id => other,
+            _ => return Err(ShaftError::NotFound { what: "user" }.into()),
+        },
+        None => match match_user(line.user, others) {
+            UserMatch::Unique(other) => other,
+            UserMatch::Ambiguous(candidates) => {
+                let candidates: Vec<_> = candidates
+                    .into_iter()
+                    .map(|candidate| Candidate {
+                        user_id: &candidate.user_id,
+                        display_name: &candidate.display_name,
+                    })
+                    .collect();
+
+                return Ok(ApiJson(json!({
+                    "status": "ambiguous",
+                    "candidates": candidates,
+                    "amount": line.amount,
+                    "reason": reason,
+                })));
+            }
+            UserMatch::NoMatch => {
+                return Err(ShaftError::BadRequest {
+                    message: format!("No user matches \"{}\"", line.user),
+                }
+                .into())
+            }
+        },
+    };
+
+    let mut transaction = db::Transaction {
+        id: None,
+        shafter: user.user_id.clone(),
+        shaftee: other_user.user_id.clone(),
+        amount: line.amount,
+        datetime: chrono::Utc::now(),
+        reason,
+        kind: db::TransactionKind::Shaft,
+    };
+
+    let outbox = state.events.transaction_outbox(&transaction);
+    let transaction_id = state
+        .database
+        .shaft_user(ledger.id, transaction.clone(), outbox.clone())
+        .await
+        .context(DatabaseError)?;
+
+    transaction.id = Some(transaction_id);
+    state
+        .events
+        .publish_outbox(&outbox)
+        .await
+        .context(EventError)?;
+
+    info!(
+        logger, "Shafted user with quick-add";
+        "other_user" => &transaction.shaftee, "amount" => line.amount.minor_units()
+    );
+
+    Ok(ApiJson(json!({
+        "status": "created",
+        "transaction_id": transaction_id,
+        "other_user": other_user.user_id,
+        "display_name": other_user.display_name,
+        "amount": transaction.amount,
+        "reason": transaction.reason,
+    })))
+}
@@ -0,0 +1,154 @@
+//! Rendering the handlebars templates for the web pages.
+
+use actix_web::{HttpResponse, HttpResponseBuilder};
+use bytes::{Bytes, BytesMut};
+use futures::channel::mpsc;
+use futures::SinkExt;
+use handlebars::{Handlebars, RenderError};
+use serde::Serialize;
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
+use std::sync::Mutex;
+
+/// Roughly how much rendered HTML to send to the client at a time.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// How many rendered chunks may be waiting to be sent before rendering
+/// pauses, which bounds the memory used by a large page.
+const MAX_QUEUED_CHUNKS: usize = 4;
+
+/// Renders a page on the blocking thread pool, streaming the HTML to the
+/// client with chunked encoding rather than building it all in memory first.
+///
+/// The response is started with `builder`, e.g. to add headers, and `render`
+/// is given the writer to render into, e.g. with
+/// `Handlebars::render_to_write`. Rendering errors abort the response, as the
+/// status has already been sent.
+pub fn stream_html<F>(mut builder: HttpResponseBuilder, render: F) -> HttpResponse
+where
+    F: FnOnce(&mut dyn Write) -> Result<(), RenderError> + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel(MAX_QUEUED_CHUNKS);
+
+    tokio::task::spawn_blocking(move || {
+        let mut writer = ChunkWriter {
+            buf: BytesMut::new(),
+            sender,
+        };
+
+        let res = render(&mut writer)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+            .and_then(|()| writer.flush());
+
+        if let Err(e) = res {
+            // The client may have gone away, in which case there's no one to
+            // tell.
+            let _ = futures::executor::block_on(writer.sender.send(Err(e)));
+        }
+    });
+
+    builder.content_type("text/html").streaming(receiver)
+}
+
+/// Buffers written data, sending it down the channel in chunks.
+struct ChunkWriter {
+    buf: BytesMut,
+    sender: mpsc::Sender<Result<Bytes, io::Error>>,
+}
+
+impl ChunkWriter {
+    /// Sends whatever has been buffered, waiting if the channel is full.
+    fn send_buffered(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+
+        let chunk = self.buf.split().freeze();
+        futures::executor::block_on(self.sender.send(Ok(chunk)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Client went away"))
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= CHUNK_SIZE {
+            self.send_buffered()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_buffered()
+    }
+}
+
+/// The most rendered pages the [RenderCache] holds before it is emptied.
+const MAX_CACHED_PAGES: usize = 64;
+
+/// Caches rendered pages by template name and context, for pages that are
+/// rendered the same way on most hits, e.g. the login page.
+///
+/// Must be cleared if the templates are reloaded.
+#[derive(Default)]
+pub struct RenderCache {
+    pages: Mutex<HashMap<(String, u64), Bytes>>,
+}
+
+impl RenderCache {
+    pub fn new() -> RenderCache {
+        RenderCache::default()
+    }
+
+    /// Renders the template with the given context, or returns the cached
+    /// page if it's been rendered with an identical context before.
+    pub fn render<T: Serialize>(
+        &self,
+        hb: &Handlebars,
+        name: &str,
+        context: &T,
+    ) -> Result<Bytes, RenderError> {
+        let key = (name.to_string(), hash_context(context)?);
+
+        if let Some(page) = self
+            .pages
+            .lock()
+            .expect("render cache lock poisoned")
+            .get(&key)
+        {
+            return Ok(page.clone());
+        }
+
+        let page = Bytes::from(hb.render(name, context)?);
+
+        let mut pages = self.pages.lock().expect("render cache lock poisoned");
+        if pages.len() >= MAX_CACHED_PAGES {
+            pages.clear();
+        }
+        pages.insert(key, page.clone());
+
+        Ok(page)
+    }
+
+    /// Drops all cached pages, e.g. after the templates have been reloaded.
+    pub fn clear(&self) {
+        self.pages
+            .lock()
+            .expect("render cache lock poisoned")
+            .clear();
+    }
+}
+
+/// Hashes the context by its JSON serialization, which is what handlebars
+/// renders from.
+fn hash_context<T: Serialize>(context: &T) -> Result<u64, RenderError> {
+    let json = serde_json::to_vec(context)
+        .map_err(|e| RenderError::new(format!("Failed to serialize context: {}", e)))?;
+
+    let mut hasher = DefaultHasher::new();
+    json.hash(&mut hasher);
+    Ok(hasher.finish())
+}
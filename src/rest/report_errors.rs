@@ -0,0 +1,155 @@
+//! Logs and reports requests that fail with a server error.
+
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::StatusCode;
+use actix_web::{Error, HttpMessage, HttpRequest, ResponseError};
+use futures::future::{ok, LocalBoxFuture, Ready};
+use futures::FutureExt;
+use slog::Logger;
+use snafu::ErrorCompat;
+
+use std::sync::Arc;
+
+use crate::error::ShaftError;
+use crate::error_reporting::{ErrorReport, ErrorReporter};
+use crate::rest::{AuthenticatedUser, RequestID};
+
+/// A middleware that logs server errors along with their backtraces, and
+/// hands them to an [ErrorReporter].
+///
+/// Only [ShaftError]s are reported, and not those caused by being overloaded
+/// (503s) as they are expected under load. Must be registered inside
+/// [MiddlewareLogger](crate::rest::MiddlewareLogger).
+#[derive(Clone)]
+pub struct ReportErrors {
+    reporter: Arc<dyn ErrorReporter>,
+}
+
+impl ReportErrors {
+    pub fn new(reporter: Arc<dyn ErrorReporter>) -> ReportErrors {
+        ReportErrors { reporter }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for ReportErrors
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = ReportErrorsService<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(ReportErrorsService {
+            reporter: self.reporter.clone(),
+            service,
+        })
+    }
+}
+
+pub struct ReportErrorsService<S> {
+    reporter: Arc<dyn ErrorReporter>,
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for ReportErrorsService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        // The request can't be cloned until it has been routed, as the router
+        // needs the only reference to it, so note what a report needs in case
+        // the inner service fails without a response.
+        let method = req.method().to_string();
+        let path = req.path().to_string();
+        let reporter = self.reporter.clone();
+
+        let fut = self.service.call(req);
+        async move {
+            let res = fut.await;
+
+            // Once there's a response we can also see who made the request,
+            // as it has been authenticated.
+            let (err, http_req) = match &res {
+                Ok(resp) => (resp.response().error(), Some(resp.request())),
+                Err(err) => (Some(err), None),
+            };
+
+            if let Some(err) = err.and_then(|err| err.as_error::<ShaftError>()) {
+                report(method, path, http_req, &*reporter, err);
+            }
+
+            res
+        }
+        .boxed_local()
+    }
+}
+
+/// Log the error and send it to the reporter in the background, if it is a
+/// server error worth reporting. The logger, request ID and user come from
+/// the request, if the inner service returned one.
+fn report(
+    method: String,
+    path: String,
+    req: Option<&HttpRequest>,
+    reporter: &dyn ErrorReporter,
+    err: &ShaftError,
+) {
+    let status = err.status_code();
+    if !status.is_server_error() || status == StatusCode::SERVICE_UNAVAILABLE {
+        return;
+    }
+
+    let backtrace = ErrorCompat::backtrace(err).map(|backtrace| backtrace.to_string());
+
+    let extensions = req.map(|req| req.extensions());
+    let logger = extensions
+        .as_ref()
+        .and_then(|extensions| extensions.get::<Logger>().cloned());
+
+    if let Some(logger) = &logger {
+        error!(
+            logger, "Request failed";
+            "err" => err.to_string(),
+            "kind" => err.kind(),
+            "backtrace" => backtrace.clone().unwrap_or_default(),
+        );
+    }
+
+    let report_fut = reporter.report(ErrorReport {
+        kind: err.kind(),
+        message: err.to_string(),
+        backtrace,
+        status: status.as_u16(),
+        method,
+        path,
+        request_id: extensions
+            .as_ref()
+            .and_then(|extensions| extensions.get::<RequestID>())
+            .map(|RequestID(id)| *id),
+        user_id: extensions
+            .as_ref()
+            .and_then(|extensions| extensions.get::<AuthenticatedUser>())
+            .map(|user| user.user_id.clone()),
+    });
+
+    actix_web::rt::spawn(async move {
+        if let Err(e) = report_fut.await {
+            if let Some(logger) = logger {
+                warn!(logger, "Failed to report error"; "err" => e.to_string());
+            }
+        }
+    });
+}
@@ -0,0 +1,105 @@
+//! Helpers for building JSON API responses.
+//!
+//! Responses are always sent as `application/json; charset=utf-8`, and are
+//! pretty-printed if the request has a `pretty=1` query parameter or asks for
+//! `Accept: application/json; indent=N`.
+
+use actix_web::body::BoxBody;
+use actix_web::{HttpRequest, HttpResponse, HttpResponseBuilder, Responder};
+use hyper::header::ACCEPT;
+use serde::Serialize;
+
+/// The content type of all JSON responses.
+const JSON_CONTENT_TYPE: &str = "application/json; charset=utf-8";
+
+/// The indent used for `?pretty=1`.
+const DEFAULT_INDENT: usize = 2;
+
+/// The largest indent we allow clients to ask for.
+const MAX_INDENT: usize = 8;
+
+/// A JSON response with a 200 status, which can be returned from handlers.
+///
+/// Use [json_response] for responses that need a different status or extra
+/// headers.
+pub struct ApiJson<T>(pub T);
+
+impl<T: Serialize> Responder for ApiJson<T> {
+    type Body = BoxBody;
+
+    fn respond_to(self, req: &HttpRequest) -> HttpResponse<Self::Body> {
+        json_response(req, HttpResponse::Ok(), &self.0)
+    }
+}
+
+/// Serialize the value as the body of the response, formatted as the request
+/// asked.
+pub fn json_response<T: Serialize>(
+    req: &HttpRequest,
+    mut builder: HttpResponseBuilder,
+    value: &T,
+) -> HttpResponse {
+    let body = match requested_indent(req) {
+        Some(indent) => to_string_indented(value, indent),
+        None => serde_json::to_string(value),
+    };
+
+    match body {
+        Ok(body) => builder.content_type(JSON_CONTENT_TYPE).body(body),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+/// Serialize the value pretty-printed with the given number of spaces.
+fn to_string_indented<T: Serialize>(value: &T, indent: usize) -> serde_json::Result<String> {
+    let indent = vec![b' '; indent];
+    let formatter = serde_json::ser::PrettyFormatter::with_indent(&indent);
+
+    let mut out = Vec::new();
+    let mut serializer = serde_json::Serializer::with_formatter(&mut out, formatter);
+    value.serialize(&mut serializer)?;
+
+    // serde_json only ever writes valid UTF-8.
+    Ok(String::from_utf8(out).expect("serde_json produced invalid UTF-8"))
+}
+
+/// The indent the request asked for, if it wants pretty-printed output.
+fn requested_indent(req: &HttpRequest) -> Option<usize> {
+    let pretty_param = url::form_urlencoded::parse(req.query_string().as_bytes())
+        .any(|(key, value)| key == "pretty" && (value == "1" || value == "true"));
+    if pretty_param {
+        return Some(DEFAULT_INDENT);
+    }
+
+    req.headers()
+        .get_all(ACCEPT)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(accept_indent)
+        .next()
+}
+
+/// Parses the `indent` parameter from a single `application/json` media range
+/// in an `Accept` header.
+fn accept_indent(media_range: &str) -> Option<usize> {
+    let mut parts = media_range.split(';').map(str::trim);
+
+    if !parts.next()?.eq_ignore_ascii_case("application/json") {
+        return None;
+    }
+
+    parts
+        .filter_map(|param| {
+            let mut kv = param.splitn(2, '=');
+            let key = kv.next()?.trim();
+            let value = kv.next()?.trim().trim_matches('"');
+            if key.eq_ignore_ascii_case("indent") {
+                value.parse::<usize>().ok()
+            } else {
+                None
+            }
+        })
+        .next()
+        .filter(|indent| *indent > 0)
+        .map(|indent| indent.min(MAX_INDENT))
+}
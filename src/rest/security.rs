@@ -0,0 +1,75 @@
+//! Recording security relevant events, so that abuse is visible.
+//!
+//! Failed logins, requests with invalid tokens and requests refused for
+//! lacking admin access are logged at warn level and stored in the
+//! `security_events` table. Admins can review them with
+//! `GET /api/admin/security_events`, optionally filtered by `kind`, paginated
+//! the same way as the audit log.
+
+use actix_web::HttpRequest;
+use slog::Logger;
+
+use crate::db::{Database, SecurityEvent};
+
+/// The kinds of security events recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecurityEventKind {
+    /// A login callback's state didn't match ours, so the login may have been
+    /// forged.
+    LoginStateMismatch,
+    /// Someone outside the required organization tried to log in.
+    LoginDenied,
+    /// A request had a token that doesn't exist or has expired.
+    InvalidToken,
+    /// An authenticated request was refused for lacking admin access.
+    AdminDenied,
+}
+
+impl SecurityEventKind {
+    /// The name the kind is stored and filtered by.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SecurityEventKind::LoginStateMismatch => "login.state_mismatch",
+            SecurityEventKind::LoginDenied => "login.denied",
+            SecurityEventKind::InvalidToken => "token.invalid",
+            SecurityEventKind::AdminDenied => "admin.denied",
+        }
+    }
+}
+
+/// Log and store a security event for the request. Failing to store it is
+/// logged rather than failing the request.
+pub async fn record_security_event(
+    database: &dyn Database,
+    logger: &Logger,
+    req: &HttpRequest,
+    kind: SecurityEventKind,
+    user_id: Option<String>,
+    details: Option<String>,
+) {
+    let ip = req.peer_addr().map(|addr| addr.ip().to_string());
+
+    warn!(
+        logger, "Security event";
+        "kind" => kind.as_str(),
+        "user" => user_id.as_deref().unwrap_or(""),
+        "ip" => ip.as_deref().unwrap_or(""),
+        "details" => details.as_deref().unwrap_or(""),
+        "path" => req.path(),
+    );
+
+    let res = database
+        .add_security_event(SecurityEvent {
+            id: None,
+            kind: kind.as_str().to_string(),
+            user_id,
+            ip,
+            details,
+            datetime: chrono::Utc::now(),
+        })
+        .await;
+
+    if let Err(err) = res {
+        error!(logger, "Failed to store security event"; "err" => err.to_string());
+    }
+}
@@ -0,0 +1,114 @@
+//! Building the `Set-Cookie` headers for login sessions.
+//!
+//! The session token is kept in the `token` cookie. Its `Secure` and
+//! `SameSite` attributes come from the [CookieSettings], and its path from the
+//! configured web root, so that an instance served under a prefix doesn't
+//! send its cookie to its neighbours. It's always `HttpOnly`.
+
+use chrono::TimeZone;
+use url::Url;
+
+use std::fmt;
+
+use crate::rest::AppConfig;
+use crate::settings::{CookieSettings, SameSite};
+
+/// The name of the cookie holding the session token.
+pub const SESSION_COOKIE_NAME: &str = "token";
+
+/// A `Set-Cookie` header value for the session cookie. Without an expiry the
+/// browser drops the cookie when it closes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionCookie {
+    name: String,
+    value: String,
+    path: String,
+    expires: Option<chrono::DateTime<chrono::Utc>>,
+    secure: bool,
+    same_site: SameSite,
+}
+
+impl SessionCookie {
+    /// A cookie that sets the session token, with attributes from the config.
+    pub fn new(config: &AppConfig, token: &str) -> SessionCookie {
+        SessionCookie::with_settings(&config.cookies, &config.web_root, token)
+    }
+
+    /// A cookie that makes the browser forget the session token.
+    pub fn removal(config: &AppConfig) -> SessionCookie {
+        SessionCookie::new(config, "").expires(chrono::Utc.timestamp(0, 0))
+    }
+
+    /// A cookie that sets the session token for an app served at the given
+    /// web root.
+    pub fn with_settings(cookies: &CookieSettings, web_root: &str, token: &str) -> SessionCookie {
+        SessionCookie {
+            name: SESSION_COOKIE_NAME.to_string(),
+            value: token.to_string(),
+            path: cookie_path(web_root),
+            expires: None,
+            secure: cookies.secure,
+            same_site: cookies.same_site,
+        }
+    }
+
+    /// Set when the browser should drop the cookie.
+    pub fn expires(mut self, expires: chrono::DateTime<chrono::Utc>) -> SessionCookie {
+        self.expires = Some(expires);
+        self
+    }
+
+    /// Set when the browser should drop the cookie, if ever.
+    pub fn maybe_expires(
+        mut self,
+        expires: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> SessionCookie {
+        self.expires = expires;
+        self
+    }
+
+    /// Set the name of the cookie.
+    pub fn name(mut self, name: &str) -> SessionCookie {
+        self.name = name.to_string();
+        self
+    }
+}
+
+impl fmt::Display for SessionCookie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}; HttpOnly; ", self.name, self.value)?;
+        if self.secure {
+            write!(f, "Secure; ")?;
+        }
+        write!(
+            f,
+            "Path={}; SameSite={}",
+            self.path,
+            self.same_site.as_str()
+        )?;
+        if let Some(expires) = self.expires {
+            write!(f, "; Expires={}", format_cookie_expires(expires))?;
+        }
+        Ok(())
+    }
+}
+
+/// Formats the time into a cookie expires field.
+pub fn format_cookie_expires(dt: chrono::DateTime<chrono::Utc>) -> String {
+    dt.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// The path the cookie applies to, i.e. the path of the web root. The web
+/// root may be a full URL or just a path.
+fn cookie_path(web_root: &str) -> String {
+    let path = match Url::parse(web_root) {
+        Ok(url) => url.path().trim_end_matches('/').to_string(),
+        Err(_) => web_root.trim_end_matches('/').to_string(),
+    };
+
+    if path.is_empty() {
+        "/".to_string()
+    } else {
+        path
+    }
+}
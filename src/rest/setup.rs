@@ -0,0 +1,275 @@
+//! Finishing setting up a new instance through the browser.
+//!
+//! When running with `--data-dir` and neither GitHub login nor any users are
+//! set up yet, a one-time token is logged at startup along with a link to
+//! `/setup?token=...`. The page asks for the GitHub OAuth app's details and
+//! the GitHub login of the first admin, creates their account, and saves the
+//! settings to the data directory's setup file. The bootstrap admin is
+//! deactivated, revoking their token. The settings take effect once shaft is
+//! restarted, after which the admin can log in with GitHub.
+
+use actix_web::web::ServiceConfig;
+use actix_web::{web, Error, HttpResponse};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use serde::Deserialize;
+use serde_json::json;
+use snafu::ResultExt;
+use toml::value::{Table, Value};
+
+use std::sync::Mutex;
+
+use crate::data_dir::{DataDir, BOOTSTRAP_ADMIN};
+use crate::db::Outbox;
+use crate::error::{DatabaseError, SettingsError, ShaftError, SharedStateError, TemplateError};
+use crate::rest::{AppState, PageContext, ReqLogger};
+use crate::shared_state::Invalidation;
+
+/// Register servlets with HTTP app
+pub fn register_servlets(config: &mut ServiceConfig) {
+    config
+        .route("/setup", web::get().to(show_setup))
+        .route("/setup", web::post().to(complete_setup));
+}
+
+/// The state of first-run setup, if it's available.
+#[derive(Debug)]
+pub struct Setup {
+    /// The token guarding setup, taken while setup is being completed and
+    /// once it's complete.
+    token: Mutex<Option<String>>,
+    /// Where the settings chosen are saved.
+    data_dir: DataDir,
+}
+
+impl Setup {
+    /// Make setup available with a new random token, saving the settings
+    /// chosen to the data directory's setup file.
+    pub fn new(data_dir: &DataDir) -> Setup {
+        let token: String = thread_rng().sample_iter(&Alphanumeric).take(32).collect();
+
+        Setup {
+            token: Mutex::new(Some(token)),
+            data_dir: data_dir.clone(),
+        }
+    }
+
+    /// The token to give to the operator, unless setup is complete.
+    pub fn token(&self) -> Option<String> {
+        self.token.lock().expect("lock poisoned").clone()
+    }
+
+    fn is_valid(&self, token: &str) -> bool {
+        matches(self.token.lock().expect("lock poisoned").as_deref(), token)
+    }
+
+    /// Take the token while completing setup, so that no other request can,
+    /// if it's still valid. It's put back unless the claim is completed.
+    fn claim(&self, token: &str) -> Option<SetupClaim<'_>> {
+        let mut current = self.token.lock().expect("lock poisoned");
+        if matches(current.as_deref(), token) {
+            Some(SetupClaim {
+                setup: self,
+                token: current.take(),
+            })
+        } else {
+            None
+        }
+    }
+}
+
+/// Whether the sent token is the current one, compared in constant time.
+fn matches(current: Option<&str>, sent: &str) -> bool {
+    match current {
+        Some(current) => {
+            current.len() == sent.len() && openssl::memcmp::eq(current.as_bytes(), sent.as_bytes())
+        }
+        None => false,
+    }
+}
+
+/// The setup token, taken by a request completing setup. Dropping the claim
+/// without completing it makes the token valid again, e.g. if saving the
+/// settings fails.
+struct SetupClaim<'a> {
+    setup: &'a Setup,
+    token: Option<String>,
+}
+
+impl SetupClaim<'_> {
+    /// Use up the token for good.
+    fn complete(mut self) {
+        self.token = None;
+    }
+}
+
+impl Drop for SetupClaim<'_> {
+    fn drop(&mut self) {
+        if let Some(token) = self.token.take() {
+            *self.setup.token.lock().expect("lock poisoned") = Some(token);
+        }
+    }
+}
+
+/// Get the setup state if the token is valid. Setup that isn't available
+/// looks like any other unknown page.
+fn check_token<'a>(state: &'a AppState, token: &str) -> Result<&'a Setup, ShaftError> {
+    state
+        .setup
+        .as_deref()
+        .filter(|setup| setup.is_valid(token))
+        .ok_or(ShaftError::NotFound { what: "page" })
+}
+
+/// The query of the setup page.
+#[derive(Deserialize)]
+struct SetupQuery {
+    token: String,
+}
+
+/// The setup form.
+#[derive(Deserialize)]
+struct SetupForm {
+    token: String,
+    client_id: String,
+    client_secret: String,
+    required_org: String,
+    /// The GitHub login of the first admin.
+    admin_login: String,
+    #[serde(default)]
+    admin_name: String,
+}
+
+/// Show the setup form.
+async fn show_setup(
+    (state, query): (web::Data<AppState>, web::Query<SetupQuery>),
+) -> Result<HttpResponse, Error> {
+    check_token(&state, &query.token)?;
+
+    let s = PageContext::new(&state)
+        .render(&state.handlebars, "setup", &json!({ "token": query.token }))
+        .context(TemplateError)?;
+
+    Ok(HttpResponse::Ok().content_type("text/html").body(s))
+}
+
+/// Create the first admin and save the settings.
+async fn complete_setup(
+    (state, form, ReqLogger(logger)): (web::Data<AppState>, web::Form<SetupForm>, ReqLogger),
+) -> Result<HttpResponse, Error> {
+    let form = form.into_inner();
+    let setup = check_token(&state, &form.token)?;
+
+    let fields = [
+        ("client ID", &form.client_id),
+        ("client secret", &form.client_secret),
+        ("organization", &form.required_org),
+        ("admin's GitHub login", &form.admin_login),
+    ];
+    for (name, value) in &fields {
+        if value.trim().is_empty() {
+            return Err(ShaftError::BadRequest {
+                message: format!("The {} is required", name),
+            }
+            .into());
+        }
+    }
+
+    let admin_login = form.admin_login.trim().to_string();
+    let admin_name = match form.admin_name.trim() {
+        "" => admin_login.clone(),
+        name => name.to_string(),
+    };
+
+    // The bootstrap admin is deactivated below, so isn't an admin any more.
+    let mut admins: Vec<_> = state
+        .config
+        .admins
+        .iter()
+        .filter(|admin| *admin != BOOTSTRAP_ADMIN)
+        .cloned()
+        .collect();
+    if !admins.contains(&admin_login) {
+        admins.push(admin_login.clone());
+    }
+
+    let mut github = Table::new();
+    github.insert("client_id".into(), form.client_id.trim().into());
+    github.insert("client_secret".into(), form.client_secret.trim().into());
+    github.insert("required_org".into(), form.required_org.trim().into());
+
+    let mut settings = Table::new();
+    settings.insert(
+        "admins".into(),
+        Value::Array(admins.into_iter().map(Value::from).collect()),
+    );
+    settings.insert("github".into(), Value::Table(github));
+
+    let contents = toml::to_string_pretty(&settings).map_err(|e| ShaftError::BadRequest {
+        message: format!("Invalid settings: {}", e),
+    })?;
+
+    // Only one request gets to finish setup. If it fails the token can be
+    // used to try again.
+    let claim = setup
+        .claim(&form.token)
+        .ok_or(ShaftError::NotFound { what: "page" })?;
+
+    let existing = state
+        .database
+        .get_user_by_github_id(admin_login.clone())
+        .await
+        .context(DatabaseError)?;
+    if existing.is_none() {
+        state
+            .database
+            .add_user_by_github_id(admin_login.clone(), admin_name, Outbox::none())
+            .await
+            .context(DatabaseError)?;
+    }
+
+    let settings_file = setup.data_dir.setup_file();
+    std::fs::write(&settings_file, contents).context(SettingsError)?;
+
+    // The bootstrap admin isn't needed now there's a real one.
+    let bootstrap_admin = state
+        .database
+        .get_user_by_github_id(BOOTSTRAP_ADMIN.to_string())
+        .await
+        .context(DatabaseError)?;
+    if let Some(user_id) = bootstrap_admin {
+        state
+            .database
+            .deactivate_user(user_id.clone(), Outbox::none())
+            .await
+            .context(DatabaseError)?;
+        state
+            .invalidate(Invalidation::User { user_id })
+            .await
+            .context(SharedStateError)?;
+    }
+    setup
+        .data_dir
+        .remove_bootstrap_token()
+        .context(SettingsError)?;
+    claim.complete();
+
+    info!(
+        logger, "Completed setup";
+        "admin" => &admin_login,
+        "settings_file" => settings_file.display().to_string(),
+    );
+
+    let s = PageContext::new(&state)
+        .render(
+            &state.handlebars,
+            "setup",
+            &json!({
+                "done": true,
+                "admin_login": admin_login,
+            }),
+        )
+        .context(TemplateError)?;
+
+    Ok(HttpResponse::Ok().content_type("text/html").body(s))
+}
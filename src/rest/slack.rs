@@ -0,0 +1,567 @@
+//! Lets users record transactions from Slack with a slash command.
+//!
+//! With a slash command, say `/shaft`, whose request URL is
+//! `/integrations/slack/command`, `/shaft @bob 12.50 pizza` records that the
+//! sender paid £12.50 for bob's pizza. The sender's Slack account must be
+//! linked to their shaft account first, by getting a code from
+//! `/integrations/slack/link` and running `/shaft link <code>`.
+//!
+//! If the command has "escape channels, users, and links" turned on, mentions
+//! are resolved through linked Slack accounts, otherwise the name is taken to
+//! be a shaft user ID. Transactions are always recorded in the default ledger.
+//!
+//! Requests are checked against the Slack app's signing secret, see
+//! <https://api.slack.com/authentication/verifying-requests-from-slack>. Slack
+//! gives up on replies that take more than three seconds, so transactions are
+//! acknowledged straight away and the outcome posted to the command's
+//! `response_url`.
+
+use actix_web::web::ServiceConfig;
+use actix_web::{web, Error, HttpRequest, HttpResponse, ResponseError};
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Request};
+use serde_json::json;
+use slog::Logger;
+use snafu::ResultExt;
+
+use std::sync::Arc;
+
+use crate::amount::parse_amount;
+use crate::db::{self, Scope, DEFAULT_LEDGER_ID};
+use crate::error::{
+    CryptoError, DatabaseError, EventError, ShaftError, SharedStateError, TemplateError,
+};
+use crate::http_client::{GenericHttpClient, HttpError};
+use crate::money::{self, Money};
+use crate::reason::check_reason;
+use crate::rest::{authz, read_body, AppState, AuthenticatedUser, PageContext, ReqLogger};
+use crate::settings::{CurrencySettings, SlackSettings};
+use crate::signing::hmac_sha256_hex;
+
+/// The version of Slack's signing scheme we support.
+const SIGNATURE_VERSION: &str = "v0";
+
+/// How far a request's timestamp may be from now, to stop requests being
+/// replayed.
+const MAX_REQUEST_AGE_SECS: i64 = 5 * 60;
+
+/// The largest slash command request we accept.
+const MAX_COMMAND_SIZE: usize = 16 * 1024;
+
+/// The host Slack's `response_url`s are on.
+const RESPONSE_URL_HOST: &str = "hooks.slack.com";
+
+/// Register servlets with HTTP app
+pub fn register_servlets(config: &mut ServiceConfig) {
+    config.route("/integrations/slack/command", web::post().to(slash_command));
+    config.route("/integrations/slack/link", web::get().to(show_link_code));
+}
+
+/// The fields of a slash command request that we use.
+#[derive(Debug, Default)]
+struct SlashCommand {
+    team_id: String,
+    /// The Slack user that ran the command.
+    user_id: String,
+    text: String,
+    /// Where to post delayed replies.
+    response_url: String,
+}
+
+impl SlashCommand {
+    /// Parse the form encoded request body.
+    fn parse(body: &[u8]) -> Result<SlashCommand, ShaftError> {
+        let mut command = SlashCommand::default();
+
+        for (key, value) in url::form_urlencoded::parse(body) {
+            match &*key {
+                "team_id" => command.team_id = value.into_owned(),
+                "user_id" => command.user_id = value.into_owned(),
+                "text" => command.text = value.into_owned(),
+                "response_url" => command.response_url = value.into_owned(),
+                _ => {}
+            }
+        }
+
+        if command.team_id.is_empty() || command.user_id.is_empty() {
+            return Err(ShaftError::BadRequest {
+                message: "Missing team_id or user_id".to_string(),
+            });
+        }
+
+        Ok(command)
+    }
+}
+
+/// What the user asked for.
+#[derive(Debug)]
+enum CommandText {
+    Help,
+    /// Link the Slack account using a code from the link page.
+    Link {
+        code: String,
+    },
+    /// Record that the sender paid `amount` for `other_user`.
+    Shaft {
+        other_user: Mention,
+        amount: Money,
+        reason: String,
+    },
+}
+
+/// Who a transaction is with.
+#[derive(Debug)]
+enum Mention {
+    /// An escaped mention of a Slack user, e.g. `<@U1234|bob>`.
+    Slack {
+        slack_user_id: String,
+        name: Option<String>,
+    },
+    /// A shaft user ID, e.g. `@bob`.
+    Local { user_id: String },
+}
+
+impl Mention {
+    /// How to refer to the user in replies.
+    fn describe(&self) -> String {
+        match self {
+            Mention::Slack {
+                name: Some(name), ..
+            } => format!("@{}", name),
+            Mention::Slack { slack_user_id, .. } => format!("<@{}>", slack_user_id),
+            Mention::Local { user_id } => user_id.clone(),
+        }
+    }
+}
+
+/// Parse the text after the command, or None if it isn't understood.
+fn parse_text(text: &str, currency: &CurrencySettings) -> Option<CommandText> {
+    let (first, rest) = split_word(text);
+
+    match first {
+        "" | "help" => return Some(CommandText::Help),
+        "link" => {
+            return match split_word(rest) {
+                (code, "") if !code.is_empty() => Some(CommandText::Link {
+                    code: code.to_string(),
+                }),
+                _ => None,
+            }
+        }
+        _ => {}
+    }
+
+    let other_user = parse_mention(first)?;
+
+    let (amount, reason) = split_word(rest);
+    let amount = parse_amount(amount, currency)
+        .ok()
+        .map(|amount| Money::new(amount, currency.currency()))
+        .filter(|amount| amount.is_positive())?;
+
+    Some(CommandText::Shaft {
+        other_user,
+        amount,
+        reason: unescape(reason),
+    })
+}
+
+/// Split off the first word, returning it and the rest of the text.
+fn split_word(text: &str) -> (&str, &str) {
+    let text = text.trim();
+    match text.find(char::is_whitespace) {
+        Some(idx) => (&text[..idx], text[idx..].trim_start()),
+        None => (text, ""),
+    }
+}
+
+fn parse_mention(word: &str) -> Option<Mention> {
+    if let Some(inner) = word.strip_prefix("<@").and_then(|w| w.strip_suffix('>')) {
+        let mut parts = inner.splitn(2, '|');
+        let slack_user_id = parts.next().filter(|id| !id.is_empty())?;
+
+        return Some(Mention::Slack {
+            slack_user_id: slack_user_id.to_string(),
+            name: parts.next().map(str::to_string),
+        });
+    }
+
+    let user_id = word.strip_prefix('@').unwrap_or(word);
+    if user_id.is_empty() || user_id.starts_with('<') {
+        return None;
+    }
+
+    Some(Mention::Local {
+        user_id: user_id.to_string(),
+    })
+}
+
+/// Undo Slack's escaping of control characters in message text.
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+/// How to use the command.
+fn usage(slack: &SlackSettings, currency: &CurrencySettings) -> String {
+    let example = money::format(Money::new(1250, currency.currency()), currency);
+    format!(
+        "Usage:\n\
+        • `/shaft @user {} reason`: record that you paid {} for @user\n\
+        • `/shaft link <code>`: link your Slack account, using a code from {}",
+        example,
+        example,
+        link_url(slack)
+    )
+}
+
+/// Where users get a code to link their Slack account.
+fn link_url(slack: &SlackSettings) -> String {
+    format!(
+        "{}/integrations/slack/link",
+        slack.public_url.trim_end_matches('/')
+    )
+}
+
+/// Compute the signature Slack sends for a request with the given body.
+fn signature(
+    secret: &str,
+    timestamp: &str,
+    body: &[u8],
+) -> Result<String, openssl::error::ErrorStack> {
+    let prefix = format!("{}:{}:", SIGNATURE_VERSION, timestamp);
+    let hmac = hmac_sha256_hex(secret, &[prefix.as_bytes(), body])?;
+
+    Ok(format!("{}={}", SIGNATURE_VERSION, hmac))
+}
+
+/// Check the request was signed by Slack recently.
+fn verify_request(req: &HttpRequest, secret: &str, body: &[u8]) -> Result<(), ShaftError> {
+    let header = |name: &str| {
+        req.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .ok_or(ShaftError::Forbidden {
+                reason: "Missing Slack signature",
+            })
+    };
+    let timestamp = header("X-Slack-Request-Timestamp")?;
+    let sent_signature = header("X-Slack-Signature")?;
+
+    let sent_at: i64 = timestamp.parse().map_err(|_| ShaftError::Forbidden {
+        reason: "Invalid Slack request timestamp",
+    })?;
+    // Timestamps so far off that the age overflows are too old too.
+    let age = chrono::Utc::now()
+        .timestamp()
+        .checked_sub(sent_at)
+        .and_then(i64::checked_abs);
+    if age.map_or(true, |age| age > MAX_REQUEST_AGE_SECS) {
+        return Err(ShaftError::Forbidden {
+            reason: "Slack request is too old",
+        });
+    }
+
+    let expected = signature(secret, timestamp, body).context(CryptoError)?;
+    if expected.len() != sent_signature.len()
+        || !openssl::memcmp::eq(expected.as_bytes(), sent_signature.as_bytes())
+    {
+        return Err(ShaftError::Forbidden {
+            reason: "Invalid Slack signature",
+        });
+    }
+
+    Ok(())
+}
+
+/// Check a `response_url` is Slack's, so that replies can't be sent elsewhere.
+fn check_response_url(response_url: &str) -> Result<url::Url, ShaftError> {
+    url::Url::parse(response_url)
+        .ok()
+        .filter(|url| url.scheme() == "https" && url.host_str() == Some(RESPONSE_URL_HOST))
+        .ok_or_else(|| ShaftError::BadRequest {
+            message: "Invalid response_url".to_string(),
+        })
+}
+
+/// A reply only the sender sees.
+fn ephemeral(text: &str) -> serde_json::Value {
+    json!({ "response_type": "ephemeral", "text": text })
+}
+
+/// A reply everyone in the channel sees.
+fn in_channel(text: &str) -> serde_json::Value {
+    json!({ "response_type": "in_channel", "text": text })
+}
+
+/// Handle a slash command from Slack.
+async fn slash_command(
+    (req, state, payload, ReqLogger(logger)): (
+        HttpRequest,
+        web::Data<AppState>,
+        web::Payload,
+        ReqLogger,
+    ),
+) -> Result<HttpResponse, Error> {
+    let slack = state.config.slack.as_ref().ok_or(ShaftError::NotFound {
+        what: "integration",
+    })?;
+
+    let body = read_body(payload, MAX_COMMAND_SIZE).await?;
+    verify_request(&req, &slack.signing_secret, &body)?;
+    let command = SlashCommand::parse(&body)?;
+
+    let (other_user, amount, reason) = match parse_text(&command.text, &state.config.currency) {
+        Some(CommandText::Shaft {
+            other_user,
+            amount,
+            reason,
+        }) => (other_user, amount, reason),
+        Some(CommandText::Link { code }) => {
+            let reply = link_account(&state, &logger, &command, &code).await?;
+            return Ok(HttpResponse::Ok().json(reply));
+        }
+        Some(CommandText::Help) | None => {
+            return Ok(HttpResponse::Ok().json(ephemeral(&usage(slack, &state.config.currency))));
+        }
+    };
+
+    // The reason is checked separately so the user is told what's wrong with
+    // it, rather than just shown the usage.
+    let reason = match check_reason(&reason, &state.config.reasons, &*state.content_policy) {
+        Ok(reason) => reason,
+        Err(err) => return Ok(HttpResponse::Ok().json(ephemeral(&err.to_string()))),
+    };
+
+    let user_id = match state
+        .database
+        .get_user_by_slack_id(command.team_id.clone(), command.user_id.clone())
+        .await
+        .context(DatabaseError)?
+    {
+        Some(user_id) => user_id,
+        None => {
+            let text = format!(
+                "Your Slack account isn't linked to shaft yet. Get a code from {} and run `/shaft link <code>`.",
+                link_url(slack)
+            );
+            return Ok(HttpResponse::Ok().json(ephemeral(&text)));
+        }
+    };
+
+    let response_url = check_response_url(&command.response_url)?;
+
+    let ack = format!(
+        "Recording {} for {}...",
+        money::format(amount, &state.config.currency),
+        other_user.describe()
+    );
+
+    let state = state.clone();
+    actix_web::rt::spawn(async move {
+        let reply = match record_transaction(
+            &state,
+            &logger,
+            &command.team_id,
+            user_id,
+            other_user,
+            amount,
+            reason,
+        )
+        .await
+        {
+            Ok(text) => in_channel(&text),
+            Err(err) if err.status_code().is_client_error() => ephemeral(&err.to_string()),
+            Err(err) => {
+                error!(logger, "Failed to record transaction from Slack"; "err" => err.to_string());
+                ephemeral("Something went wrong recording the transaction, please try again.")
+            }
+        };
+
+        if let Err(err) = post_reply(state.http_client.clone(), response_url, reply).await {
+            warn!(logger, "Failed to reply to Slack"; "err" => err.to_string());
+        }
+    });
+
+    Ok(HttpResponse::Ok().json(ephemeral(&ack)))
+}
+
+/// Link the sender's Slack account to the shaft user the code was issued to.
+async fn link_account(
+    state: &AppState,
+    logger: &Logger,
+    command: &SlashCommand,
+    code: &str,
+) -> Result<serde_json::Value, ShaftError> {
+    let user_id = match state
+        .slack_link_codes
+        .redeem(code)
+        .await
+        .context(SharedStateError)?
+    {
+        Some(user_id) => user_id,
+        None => {
+            return Ok(ephemeral(
+                "That code is invalid or has expired, please get a new one.",
+            ))
+        }
+    };
+
+    state
+        .database
+        .link_slack_user(
+            command.team_id.clone(),
+            command.user_id.clone(),
+            user_id.clone(),
+        )
+        .await
+        .context(DatabaseError)?;
+
+    info!(
+        logger, "Linked Slack user";
+        "user_id" => &user_id, "slack_user_id" => &command.user_id,
+    );
+
+    Ok(ephemeral(&format!(
+        "Your Slack account is now linked to the shaft user {}.",
+        user_id
+    )))
+}
+
+/// Create the transaction, returning the message to post in the channel.
+async fn record_transaction(
+    state: &AppState,
+    logger: &Logger,
+    team_id: &str,
+    user_id: String,
+    other_user: Mention,
+    amount: Money,
+    reason: String,
+) -> Result<String, ShaftError> {
+    let other_user_id = match &other_user {
+        Mention::Slack { slack_user_id, .. } => state
+            .database
+            .get_user_by_slack_id(team_id.to_string(), slack_user_id.clone())
+            .await
+            .context(DatabaseError)?
+            .ok_or_else(|| ShaftError::BadRequest {
+                message: format!(
+                    "{} hasn't linked their Slack account to shaft",
+                    other_user.describe()
+                ),
+            })?,
+        Mention::Local { user_id } => user_id.clone(),
+    };
+
+    if other_user_id == user_id {
+        return Err(ShaftError::BadRequest {
+            message: "You can't shaft yourself".to_string(),
+        });
+    }
+
+    let users = state
+        .database
+        .get_all_users(DEFAULT_LEDGER_ID)
+        .await
+        .context(DatabaseError)?;
+    let display_name = |user_id: &str| users.get(user_id).map(|user| user.display_name.clone());
+
+    let other_display_name =
+        display_name(&other_user_id).ok_or_else(|| ShaftError::BadRequest {
+            message: format!("Unknown shaft user {}", other_user.describe()),
+        })?;
+    let own_display_name = display_name(&user_id).unwrap_or_else(|| user_id.clone());
+
+    let mut transaction = db::Transaction {
+        id: None,
+        shafter: user_id,
+        shaftee: other_user_id,
+        amount,
+        datetime: chrono::Utc::now(),
+        reason,
+        kind: db::TransactionKind::Shaft,
+    };
+
+    let outbox = state.events.transaction_outbox(&transaction);
+    let transaction_id = state
+        .database
+        .shaft_user(DEFAULT_LEDGER_ID, transaction.clone(), outbox.clone())
+        .await
+        .context(DatabaseError)?;
+
+    transaction.id = Some(transaction_id);
+    state
+        .events
+        .publish_outbox(&outbox)
+        .await
+        .context(EventError)?;
+
+    info!(
+        logger, "Shafted user from Slack";
+        "other_user" => &transaction.shaftee, "amount" => amount.minor_units()
+    );
+
+    Ok(format!(
+        "{} paid {} for {}: {}",
+        own_display_name,
+        money::format(amount, &state.config.currency),
+        other_display_name,
+        transaction.reason
+    ))
+}
+
+/// Post a delayed reply to a slash command.
+async fn post_reply(
+    http_client: Arc<dyn GenericHttpClient>,
+    response_url: url::Url,
+    reply: serde_json::Value,
+) -> Result<(), HttpError> {
+    let req = Request::post(response_url.as_str())
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(reply.to_string()))
+        .map_err(|source| HttpError::Http { source })?;
+
+    let resp = http_client.request(req).await?;
+    if !resp.status().is_success() {
+        return Err(HttpError::Status {
+            code: resp.status(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Show the logged in user a code to link their Slack account with.
+async fn show_link_code(
+    (state, user): (web::Data<AppState>, AuthenticatedUser),
+) -> Result<HttpResponse, Error> {
+    authz::require_scope(&user, Scope::Write)?;
+
+    if state.config.slack.is_none() {
+        return Err(ShaftError::NotFound {
+            what: "integration",
+        }
+        .into());
+    }
+
+    let code = state
+        .slack_link_codes
+        .issue(&user.user_id)
+        .await
+        .context(SharedStateError)?;
+
+    let s = PageContext::new(&state)
+        .user(Some(&user))
+        .render(
+            &state.handlebars,
+            "slack_link",
+            &json!({
+                "code": code,
+                "ttl_mins": state.slack_link_codes.ttl().as_secs() / 60,
+            }),
+        )
+        .context(TemplateError)?;
+
+    Ok(HttpResponse::Ok().content_type("text/html").body(s))
+}
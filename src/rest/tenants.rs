@@ -0,0 +1,244 @@
+//! Hosting several independent households from one process.
+//!
+//! Each tenant configured in `[[tenants]]` has its own database and its own
+//! [AppState], built with [AppState::for_tenant], and may override some of the
+//! instance's settings. A tenant is served on its hostnames if it has any,
+//! otherwise under `/t/<name>`. Each tenant's routes are wrapped in their own
+//! authentication middleware, so a token only works for the tenant that issued
+//! it. Requests that don't belong to a tenant are served by the instance
+//! itself.
+//!
+//! Background tasks such as the debt digest only run for the instance itself.
+
+use actix_web::web::{self, ServiceConfig};
+use actix_web::{guard, Error};
+use serde::Serialize;
+use serde_json::json;
+use snafu::{ensure, Snafu};
+
+use std::collections::HashSet;
+
+use crate::db::Scope;
+use crate::rest::response::ApiJson;
+use crate::rest::{
+    authz, register_servlets, AppConfig, AppState, AuthenticateUser, AuthenticatedUser,
+};
+use crate::settings::TenantSettings;
+
+/// The path tenants without hostnames are served under, followed by their
+/// name.
+pub const TENANT_PATH_PREFIX: &str = "/t/";
+
+/// Register servlets with HTTP app
+pub fn register_admin_servlets(config: &mut ServiceConfig) {
+    config.route("/api/admin/tenants", web::get().to(get_tenants));
+}
+
+/// Error in the tenant settings.
+#[derive(Debug, Snafu)]
+pub enum TenantError {
+    #[snafu(display(
+        "Invalid tenant name {:?}, must be lowercase letters, digits and hyphens",
+        name
+    ))]
+    InvalidName { name: String },
+
+    #[snafu(display("Tenant {} is configured more than once", name))]
+    DuplicateName { name: String },
+
+    #[snafu(display("Host {} is used by more than one tenant", host))]
+    DuplicateHost { host: String },
+}
+
+/// Check tenant names are usable in paths, and that names and hosts are
+/// unique.
+pub fn validate_tenants(tenants: &[TenantSettings]) -> Result<(), TenantError> {
+    let mut names = HashSet::new();
+    let mut hosts = HashSet::new();
+
+    for tenant in tenants {
+        let name = &tenant.name;
+        ensure!(
+            !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-'),
+            InvalidName { name }
+        );
+        ensure!(names.insert(name), DuplicateName { name });
+
+        for host in &tenant.hosts {
+            ensure!(
+                hosts.insert(host.to_ascii_lowercase()),
+                DuplicateHost { host }
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// The path a tenant is served under, if it isn't served on its own
+/// hostnames.
+pub fn tenant_path(tenant: &TenantSettings) -> Option<String> {
+    if tenant.hosts.is_empty() {
+        Some(format!("{}{}", TENANT_PATH_PREFIX, tenant.name))
+    } else {
+        None
+    }
+}
+
+/// A request's path and query relative to its tenant's web root, i.e.
+/// without the `/t/<name>` prefix of a path tenant.
+pub fn relative_path<'a>(path: &'a str, web_root: &str) -> &'a str {
+    let name_len = match path.strip_prefix(TENANT_PATH_PREFIX) {
+        Some(rest) => rest.find('/').unwrap_or_else(|| rest.len()),
+        None => return path,
+    };
+    let (prefix, rest) = path.split_at(TENANT_PATH_PREFIX.len() + name_len);
+
+    if !web_root.ends_with(prefix) {
+        path
+    } else if rest.is_empty() {
+        "/"
+    } else {
+        rest
+    }
+}
+
+/// The instance's config with the tenant's overrides applied.
+pub fn tenant_config(base: &AppConfig, tenant: &TenantSettings) -> AppConfig {
+    let mut config = base.clone();
+
+    if let Some(path) = tenant_path(tenant) {
+        config.web_root = format!("{}{}", base.web_root, path);
+    }
+    if let Some(admins) = &tenant.admins {
+        config.admins = admins.clone();
+    }
+    if let Some(github) = &tenant.github {
+        config.github_client_id = github.client_id.clone();
+        config.github_client_secret = github.client_secret.clone();
+        config.github_state = github.state.clone();
+        config.required_org = github.required_org.clone();
+    }
+    if let Some(currency) = &tenant.currency {
+        config.currency = currency.clone();
+    }
+    if let Some(reasons) = &tenant.reasons {
+        config.reasons = reasons.clone();
+    }
+    config.webhooks = tenant.webhooks.clone();
+
+    config
+}
+
+/// A tenant and the state its requests are handled with.
+#[derive(Clone)]
+pub struct Tenant {
+    pub name: String,
+    /// The hostnames the tenant is served on, if any.
+    pub hosts: Vec<String>,
+    /// The path the tenant is served under, if it has no hostnames.
+    pub path: Option<String>,
+    pub state: AppState,
+}
+
+/// The tenants hosted by the instance.
+#[derive(Clone, Default)]
+pub struct TenantRegistry {
+    tenants: Vec<Tenant>,
+}
+
+impl TenantRegistry {
+    pub fn new(tenants: Vec<Tenant>) -> TenantRegistry {
+        TenantRegistry { tenants }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Tenant> {
+        self.tenants.iter().find(|tenant| tenant.name == name)
+    }
+
+    /// The tenant served on the hostname, ignoring any port.
+    pub fn for_host(&self, host: &str) -> Option<&Tenant> {
+        let host = host.split(':').next().unwrap_or(host);
+        self.tenants.iter().find(|tenant| {
+            tenant
+                .hosts
+                .iter()
+                .any(|tenant_host| tenant_host.eq_ignore_ascii_case(host))
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Tenant> {
+        self.tenants.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tenants.is_empty()
+    }
+}
+
+/// Registers each tenant's servlets, then the instance's own, each wrapped
+/// in the authentication middleware `authenticate` gives for its state and
+/// path prefix.
+pub fn register_tenant_servlets<F>(config: &mut ServiceConfig, state: &AppState, authenticate: &F)
+where
+    F: Fn(&AppState, &str) -> AuthenticateUser,
+{
+    for tenant in state.tenants.iter() {
+        let (scope, prefix) = match &tenant.path {
+            Some(path) => (web::scope(path), path.as_str()),
+            None => {
+                let mut hosts = tenant.hosts.iter();
+                let first = hosts.next().expect("tenants have a path or hosts");
+                let guard = hosts.fold(guard::Any(guard::Host(first)), |guard, host| {
+                    guard.or(guard::Host(host))
+                });
+                (web::scope("").guard(guard), "")
+            }
+        };
+
+        let tenant_state = tenant.state.clone();
+        config.service(
+            scope
+                .app_data(web::Data::new(tenant_state.clone()))
+                .wrap(authenticate(&tenant_state, prefix))
+                .configure(move |config| register_servlets(config, &tenant_state)),
+        );
+    }
+
+    let state = state.clone();
+    config.service(
+        web::scope("")
+            .wrap(authenticate(&state, ""))
+            .configure(move |config| register_servlets(config, &state)),
+    );
+}
+
+/// A tenant in the `/api/admin/tenants` response.
+#[derive(Serialize)]
+struct TenantInfo<'a> {
+    name: &'a str,
+    hosts: &'a [String],
+    path: Option<&'a str>,
+}
+
+/// List the tenants hosted by the instance.
+async fn get_tenants(
+    (state, user): (web::Data<AppState>, AuthenticatedUser),
+) -> Result<ApiJson<impl Serialize>, Error> {
+    authz::require_scope(&user, Scope::Admin)?;
+
+    let tenants: Vec<_> = state
+        .tenants
+        .iter()
+        .map(|tenant| TenantInfo {
+            name: &tenant.name,
+            hosts: &tenant.hosts,
+            path: tenant.path.as_deref(),
+        })
+        .collect();
+
+    Ok(ApiJson(json!({ "tenants": tenants })))
+}
@@ -0,0 +1,100 @@
+//! Endpoints describing the server, for deploy scripts and generic clients.
+//! Like `/health` they don't need a login.
+//!
+//! `GET /api/version` reports what's deployed, e.g.
+//!
+//! ```json
+//! {
+//!   "version": "0.1.0",
+//!   "git_commit": "3f9c2a1b7d4e",
+//!   "database_backend": "rusqlite",
+//!   "schema_version": 13
+//! }
+//! ```
+//!
+//! `GET /api/capabilities` reports what the server supports, so clients can
+//! hide what isn't available, e.g.
+//!
+//! ```json
+//! {
+//!   "version": "0.1.0",
+//!   "git_commit": "3f9c2a1b7d4e",
+//!   "features": {
+//!     "webhooks": true,
+//!     "public_read": false,
+//!     "registration_open": true,
+//!     "categories": false
+//!   },
+//!   "multi_currency": false,
+//!   "currency": { "code": "GBP", "symbol": "£", "minor_units": 2 }
+//! }
+//! ```
+//!
+//! Features can be toggled by admins at runtime, so clients shouldn't cache
+//! the capabilities for long.
+
+use actix_web::web::ServiceConfig;
+use actix_web::{web, Error};
+use serde::Serialize;
+use snafu::ResultExt;
+
+use std::collections::BTreeMap;
+
+use crate::error::DatabaseError;
+use crate::features::Feature;
+use crate::rest::response::ApiJson;
+use crate::rest::AppState;
+use crate::version::{VersionInfo, GIT_COMMIT, VERSION};
+
+/// Register servlets with HTTP app
+pub fn register_servlets(config: &mut ServiceConfig) {
+    config.route("/api/version", web::get().to(get_version));
+    config.route("/api/capabilities", web::get().to(get_capabilities));
+}
+
+/// Get the version of the code and database.
+async fn get_version(state: web::Data<AppState>) -> Result<ApiJson<VersionInfo>, Error> {
+    let info = VersionInfo::collect(state.database.as_ref())
+        .await
+        .context(DatabaseError)?;
+
+    Ok(ApiJson(info))
+}
+
+/// The `/api/capabilities` response.
+#[derive(Serialize)]
+struct Capabilities {
+    version: &'static str,
+    git_commit: &'static str,
+    /// Whether each optional feature is currently enabled.
+    features: BTreeMap<Feature, bool>,
+    /// Whether amounts may be in different currencies. Currently they are
+    /// always in the configured currency.
+    multi_currency: bool,
+    currency: CurrencyInfo,
+}
+
+/// The currency amounts are in, so clients can format them.
+#[derive(Serialize)]
+struct CurrencyInfo {
+    code: String,
+    symbol: String,
+    minor_units: u8,
+}
+
+/// Get which optional features the server supports.
+async fn get_capabilities(state: web::Data<AppState>) -> ApiJson<Capabilities> {
+    let currency = &state.config.currency;
+
+    ApiJson(Capabilities {
+        version: VERSION,
+        git_commit: GIT_COMMIT,
+        features: state.features.snapshot(),
+        multi_currency: false,
+        currency: CurrencyInfo {
+            code: currency.code.as_str().to_string(),
+            symbol: currency.symbol.clone(),
+            minor_units: currency.minor_units,
+        },
+    })
+}
@@ -0,0 +1,478 @@
+//! The data passed to the handlebars templates for the web pages, alongside
+//! the [PageContext](crate::rest::PageContext) common to them all.
+
+use linear_map::LinearMap;
+use serde::Serialize;
+
+use std::collections::BTreeMap;
+
+use crate::amount::AmountInput;
+use crate::api_types::ShaftUserBody;
+use crate::db::{
+    users_by_balance, AuditEntry, Ledger, PoolStats, Transaction, TransactionKind, User,
+    UserPreferences, DEFAULT_LEDGER_ID,
+};
+use crate::http_client::HostStats;
+use crate::money::{self, Currency, Money};
+use crate::rest::flash::Flash;
+use crate::settings::{CurrencySettings, ReasonSettings};
+
+/// The data for the `index` template, listing everyone's balances.
+#[derive(Serialize)]
+pub struct IndexPage<'a> {
+    balances: Vec<&'a User>,
+    /// Everyone who can be picked in the form, which may include users left
+    /// out of `balances`.
+    users: Vec<FormUser<'a>>,
+    /// The user's defaults for the form, if anyone is logged in.
+    preferences: Option<FormPreferences<'a>>,
+    /// The link to show or hide settled users, if they're hidden by default.
+    settled: Option<SettledToggle>,
+    /// What the logged in user owes and is owed, if anyone is logged in.
+    net_position: Option<NetPosition<'a>>,
+    /// Links to the user's ledgers, if they are in more than one.
+    ledgers: Vec<LedgerLink<'a>>,
+    reason: ReasonField,
+    /// Why the form was last rejected, and what was entered, if it just was.
+    form_error: Option<FormError<'a>>,
+}
+
+impl<'a> IndexPage<'a> {
+    /// Builds the page with the users sorted by balance, most in debt first.
+    pub fn new(users: &'a LinearMap<String, User>, reasons: &ReasonSettings) -> IndexPage<'a> {
+        IndexPage {
+            balances: users_by_balance(users),
+            users: users_by_balance(users)
+                .into_iter()
+                .map(|user| FormUser {
+                    user_id: &user.user_id,
+                    display_name: &user.display_name,
+                    selected: false,
+                })
+                .collect(),
+            preferences: None,
+            settled: None,
+            net_position: None,
+            ledgers: Vec::new(),
+            reason: ReasonField {
+                required: reasons.required,
+                min_length: reasons.min_length,
+                max_length: reasons.max_length,
+            },
+            form_error: None,
+        }
+    }
+
+    /// Adds a selector for switching between the user's ledgers, marking the
+    /// one being viewed. Does nothing if there's only the one.
+    pub fn with_ledgers(
+        mut self,
+        ledgers: &'a [Ledger],
+        current: i64,
+        web_root: &str,
+    ) -> IndexPage<'a> {
+        if ledgers.len() > 1 {
+            self.ledgers = ledgers
+                .iter()
+                .map(|ledger| LedgerLink::new(ledger, current, web_root))
+                .collect();
+        }
+        self
+    }
+
+    /// Lists only the given balances, e.g. with settled users left out.
+    /// Everyone can still be picked in the form.
+    pub fn with_balances(mut self, balances: Vec<&'a User>) -> IndexPage<'a> {
+        self.balances = balances;
+        self
+    }
+
+    /// Fills in the form with the logged in user's defaults.
+    pub fn with_preferences(
+        mut self,
+        preferences: &'a UserPreferences,
+        currency: &CurrencySettings,
+    ) -> IndexPage<'a> {
+        if let Some(default_counterparty) = &preferences.default_counterparty {
+            for user in &mut self.users {
+                user.selected = user.user_id == default_counterparty.as_str();
+            }
+        }
+
+        self.preferences = Some(FormPreferences {
+            default_currency: preferences
+                .default_currency
+                .as_ref()
+                .map(|code| code.as_str()),
+            quick_amounts: preferences
+                .quick_amounts
+                .iter()
+                .map(|amount| money::format(*amount, currency))
+                .collect(),
+        });
+        self
+    }
+
+    /// Shows why the form was rejected, given a flash message with the
+    /// rejected submission, filling the form back in with what was entered.
+    /// Does nothing for other messages. Must be called after
+    /// [IndexPage::with_preferences], so the entered user is picked rather
+    /// than the default.
+    pub fn with_rejected_form(mut self, flash: &'a Flash) -> IndexPage<'a> {
+        let form = match &flash.form {
+            Some(form) => form,
+            None => return self,
+        };
+
+        for user in &mut self.users {
+            user.selected = user.user_id == form.other_user;
+        }
+
+        self.form_error = Some(FormError::new(&flash.message, form));
+        self
+    }
+
+    /// Adds the link to show or hide settled users.
+    pub fn with_settled_toggle(mut self, settled: SettledToggle) -> IndexPage<'a> {
+        self.settled = Some(settled);
+        self
+    }
+
+    /// Adds the logged in user's totals, from what each other user owes them
+    /// as returned by
+    /// [get_balances_by_counterparty](crate::db::Database::get_balances_by_counterparty).
+    pub fn with_net_position(
+        mut self,
+        counterparties: &'a [User],
+        currency: Currency,
+    ) -> IndexPage<'a> {
+        let mut net_position = NetPosition {
+            owed: Money::new(0, currency),
+            owes: Money::new(0, currency),
+            owed_by: Vec::new(),
+            owes_to: Vec::new(),
+        };
+
+        for user in counterparties {
+            let amount = user.balance.minor_units();
+            if amount > 0 {
+                net_position.owed += user.balance;
+                net_position.owed_by.push(Counterparty {
+                    display_name: &user.display_name,
+                    amount: user.balance,
+                });
+            } else if amount < 0 {
+                net_position.owes += -user.balance;
+                net_position.owes_to.push(Counterparty {
+                    display_name: &user.display_name,
+                    amount: -user.balance,
+                });
+            }
+        }
+
+        // Largest amounts first.
+        net_position.owed_by.reverse();
+
+        self.net_position = Some(net_position);
+        self
+    }
+}
+
+/// A rejected submission of the [IndexPage]'s form, shown with what went
+/// wrong.
+#[derive(Serialize)]
+struct FormError<'a> {
+    error: &'a str,
+    other_user: &'a str,
+    amount: String,
+    reason: &'a str,
+}
+
+impl<'a> FormError<'a> {
+    fn new(error: &'a str, form: &'a ShaftUserBody) -> FormError<'a> {
+        FormError {
+            error,
+            other_user: &form.other_user,
+            amount: match &form.amount {
+                AmountInput::Minor(amount) => amount.to_string(),
+                AmountInput::Text(text) => text.clone(),
+            },
+            reason: &form.reason,
+        }
+    }
+}
+
+/// Someone who can be picked in the [IndexPage]'s form.
+#[derive(Serialize)]
+struct FormUser<'a> {
+    user_id: &'a str,
+    display_name: &'a str,
+    /// Whether they're picked to begin with.
+    selected: bool,
+}
+
+/// The logged in user's defaults for the [IndexPage]'s form.
+#[derive(Serialize)]
+struct FormPreferences<'a> {
+    /// Shown next to the amount, if set.
+    default_currency: Option<&'a str>,
+    /// Amounts offered as buttons, formatted so the form accepts them.
+    quick_amounts: Vec<String>,
+}
+
+/// Whether settled users are being shown on the [IndexPage], so it can link
+/// to the other view.
+#[derive(Serialize)]
+pub struct SettledToggle {
+    /// True if everyone is listed, otherwise settled users were left out.
+    pub show_all: bool,
+    /// How many settled users were left out.
+    pub hidden: usize,
+}
+
+/// The logged in user's totals on the [IndexPage], and who they're with.
+/// Amounts are all positive.
+#[derive(Serialize)]
+struct NetPosition<'a> {
+    /// The total others owe the user.
+    owed: Money,
+    /// The total the user owes others.
+    owes: Money,
+    /// Those who owe the user.
+    owed_by: Vec<Counterparty<'a>>,
+    /// Those the user owes.
+    owes_to: Vec<Counterparty<'a>>,
+}
+
+/// Someone the user owes, or who owes the user, in a [NetPosition].
+#[derive(Serialize)]
+struct Counterparty<'a> {
+    display_name: &'a str,
+    amount: Money,
+}
+
+/// The constraints on the reason input in the [IndexPage]'s form, so the
+/// browser can check them before submitting.
+#[derive(Serialize)]
+struct ReasonField {
+    required: bool,
+    min_length: usize,
+    max_length: usize,
+}
+
+/// A link to a ledger's home page in the [IndexPage].
+#[derive(Serialize)]
+struct LedgerLink<'a> {
+    display_name: &'a str,
+    href: String,
+    current: bool,
+}
+
+impl<'a> LedgerLink<'a> {
+    fn new(ledger: &'a Ledger, current: i64, web_root: &str) -> LedgerLink<'a> {
+        let href = if ledger.id == DEFAULT_LEDGER_ID {
+            format!("{}/home", web_root)
+        } else {
+            format!("{}/l/{}/home", web_root, ledger.name)
+        };
+
+        LedgerLink {
+            display_name: &ledger.display_name,
+            href,
+            current: ledger.id == current,
+        }
+    }
+}
+
+/// The data for the `transactions` template, listing recent transactions.
+#[derive(Serialize)]
+pub struct TransactionsPage<'a> {
+    transactions: Vec<TransactionRow<'a>>,
+}
+
+impl<'a> TransactionsPage<'a> {
+    /// Builds the page, looking up the display names of those involved in
+    /// each transaction.
+    pub fn new(
+        users: &'a LinearMap<String, User>,
+        transactions: &'a [Transaction],
+    ) -> TransactionsPage<'a> {
+        TransactionsPage {
+            transactions: transactions
+                .iter()
+                .map(|txn| TransactionRow::new(users, txn))
+                .collect(),
+        }
+    }
+}
+
+/// A row in the [TransactionsPage].
+#[derive(Serialize)]
+struct TransactionRow<'a> {
+    amount: Money,
+    shafter_id: &'a str,
+    shafter_name: &'a str,
+    shaftee_id: &'a str,
+    shaftee_name: &'a str,
+    /// Shown with the `format-date` and `relative-time` helpers.
+    datetime: chrono::DateTime<chrono::Utc>,
+    reason: &'a str,
+    /// Whether this is an adjustment made by an admin, rather than a shaft.
+    adjustment: bool,
+}
+
+impl<'a> TransactionRow<'a> {
+    fn new(users: &'a LinearMap<String, User>, txn: &'a Transaction) -> TransactionRow<'a> {
+        TransactionRow {
+            amount: txn.amount,
+            shafter_id: &txn.shafter,
+            shafter_name: display_name(users, &txn.shafter),
+            shaftee_id: &txn.shaftee,
+            shaftee_name: display_name(users, &txn.shaftee),
+            datetime: txn.datetime,
+            reason: &txn.reason,
+            adjustment: txn.kind == TransactionKind::Adjustment,
+        }
+    }
+}
+
+/// The user's display name, falling back to their ID for unknown users.
+fn display_name<'a>(users: &'a LinearMap<String, User>, user_id: &'a str) -> &'a str {
+    users
+        .get(user_id)
+        .map(|user| &user.display_name as &str)
+        .unwrap_or(user_id)
+}
+
+/// The data for the `admin` template, the dashboard for instance admins.
+#[derive(Serialize)]
+pub struct AdminPage<'a> {
+    users: Vec<AdminUserRow<'a>>,
+    audit: Vec<AuditRow<'a>>,
+    health: Option<HealthSummary<'a>>,
+}
+
+impl<'a> AdminPage<'a> {
+    /// Builds the page with the users sorted by balance, most in debt first,
+    /// marking those who are deactivated or admins.
+    pub fn new(
+        users: &'a LinearMap<String, User>,
+        deactivated: &'a [String],
+        admins: &'a [String],
+    ) -> AdminPage<'a> {
+        AdminPage {
+            users: users_by_balance(users)
+                .into_iter()
+                .map(|user| AdminUserRow {
+                    user_id: &user.user_id,
+                    display_name: &user.display_name,
+                    balance: user.balance,
+                    active: !deactivated.contains(&user.user_id),
+                    admin: admins.contains(&user.user_id),
+                })
+                .collect(),
+            audit: Vec::new(),
+            health: None,
+        }
+    }
+
+    /// Adds the most recent audit log entries, newest first.
+    pub fn with_audit(mut self, audit: &'a [AuditEntry]) -> AdminPage<'a> {
+        self.audit = audit
+            .iter()
+            .map(|entry| AuditRow {
+                datetime: entry.datetime,
+                actor: &entry.actor,
+                action: &entry.action,
+                target: entry.target.as_deref(),
+                details: entry.details.as_deref(),
+            })
+            .collect();
+        self
+    }
+
+    /// Adds how the database pool and outbound requests are doing.
+    pub fn with_health(
+        mut self,
+        pool: &'a PoolStats,
+        hosts: &'a BTreeMap<String, HostStats>,
+    ) -> AdminPage<'a> {
+        self.health = Some(HealthSummary {
+            pool,
+            hosts: hosts
+                .iter()
+                .map(|(host, stats)| HostRow {
+                    host,
+                    stats,
+                    error_rate: if stats.requests > 0 {
+                        Some(stats.errors as f64 / stats.requests as f64)
+                    } else {
+                        None
+                    },
+                })
+                .collect(),
+        });
+        self
+    }
+}
+
+/// A user in the [AdminPage].
+#[derive(Serialize)]
+struct AdminUserRow<'a> {
+    user_id: &'a str,
+    display_name: &'a str,
+    balance: Money,
+    active: bool,
+    admin: bool,
+}
+
+/// An audit log entry in the [AdminPage].
+#[derive(Serialize)]
+struct AuditRow<'a> {
+    datetime: chrono::DateTime<chrono::Utc>,
+    actor: &'a str,
+    action: &'a str,
+    target: Option<&'a str>,
+    details: Option<&'a str>,
+}
+
+/// How the services shaft depends on are doing, in the [AdminPage].
+#[derive(Serialize)]
+struct HealthSummary<'a> {
+    pool: &'a PoolStats,
+    hosts: Vec<HostRow<'a>>,
+}
+
+/// The outbound requests to a host in the [AdminPage].
+#[derive(Serialize)]
+struct HostRow<'a> {
+    host: &'a str,
+    stats: &'a HostStats,
+    /// The fraction of requests that failed, if any were sent.
+    error_rate: Option<f64>,
+}
+
+/// A way of logging in, shown as a button on the [LoginPage].
+#[derive(Debug, Clone, Serialize)]
+pub struct LoginProvider {
+    /// The name shown on the button, e.g. `GitHub`.
+    pub name: &'static str,
+    /// An image shown on the button, relative to the web root.
+    pub icon_url: Option<&'static str>,
+    /// Where the button goes to start logging in, relative to the web root.
+    pub login_path: &'static str,
+}
+
+/// The data for the `login` template.
+#[derive(Serialize)]
+pub struct LoginPage<'a> {
+    /// Where to send the user once they've logged in.
+    next: Option<&'a str>,
+    providers: &'a [LoginProvider],
+}
+
+impl<'a> LoginPage<'a> {
+    pub fn new(providers: &'a [LoginProvider], next: Option<&'a str>) -> LoginPage<'a> {
+        LoginPage { next, providers }
+    }
+}
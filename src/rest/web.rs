@@ -1,17 +1,31 @@
 //! The web form API for interacting with shaft.
 
-use actix_http::httpmessage::HttpMessage;
 use actix_web::web::ServiceConfig;
-use actix_web::{error, web, Error, HttpRequest, HttpResponse};
+use actix_web::{web, Error, HttpMessage, HttpRequest, HttpResponse};
+use bytes::Bytes;
 use chrono;
-use hyper::header::{LOCATION, SET_COOKIE};
-use itertools::Itertools;
+use hyper::header::{CACHE_CONTROL, LOCATION, SET_COOKIE};
+use serde::Deserialize;
 use serde_json::json;
+use snafu::ResultExt;
 
-use crate::db;
-use crate::rest::{AppState, AuthenticatedUser, ShaftUserBody};
-
-use slog::Logger;
+use crate::api_types::ShaftUserBody;
+use crate::db::{self, Outbox, Scope};
+use crate::error::{
+    DatabaseError, EventError, InvalidAmount, InvalidReason, ShaftError, SharedStateError,
+    TemplateError,
+};
+use crate::features::Feature;
+use crate::money;
+use crate::reason::check_reason;
+use crate::rest::render::stream_html;
+use crate::rest::views::{IndexPage, LoginPage, SettledToggle, TransactionsPage};
+use crate::rest::{
+    authz, has_flash, last_modified_header, login_providers, not_modified_since, set_flash,
+    take_flash, validate_next, AppState, AuthenticatedUser, CurrentLedger, Flash, PageContext,
+    ReadAccess, ReqLogger, Section, SessionCookie, SESSION_COOKIE_NAME,
+};
+use crate::shared_state::Invalidation;
 
 /// Register servlets with HTTP app
 pub fn register_servlets(config: &mut ServiceConfig) {
@@ -19,6 +33,7 @@ pub fn register_servlets(config: &mut ServiceConfig) {
         .route("/", web::get().to(root))
         .route("/home", web::get().to(get_balances))
         .route("/login", web::get().to(show_login))
+        .route("/logout", web::get().to(show_logout))
         .route("/logout", web::post().to(logout))
         .route("/transactions", web::get().to(get_transactions))
         .route("/shaft", web::post().to(shaft_user))
@@ -27,185 +42,532 @@ pub fn register_servlets(config: &mut ServiceConfig) {
 
 /// The top level root. Redirects to /home or /login.
 async fn root((req, state): (HttpRequest, web::Data<AppState>)) -> Result<HttpResponse, Error> {
-    if let Some(token) = req.cookie("token") {
-        let user_opt = state
+    let show_home = if state.features.is_enabled(Feature::PublicRead) {
+        true
+    } else if let Some(token) = req.cookie(SESSION_COOKIE_NAME) {
+        state
             .database
-            .get_user_from_token(token.value().to_string())
+            .get_user_id_for_token(token.value().to_string())
             .await
-            .map_err(error::ErrorInternalServerError)?;
-        if user_opt.is_some() {
-            Ok(HttpResponse::Found().header(LOCATION, "home").finish())
-        } else {
-            Ok(HttpResponse::Found().header(LOCATION, "login").finish())
-        }
+            .context(DatabaseError)?
+            .is_some()
     } else {
-        Ok(HttpResponse::Found().header(LOCATION, "login").finish())
+        false
+    };
+
+    let page = if show_home { "home" } else { "login" };
+
+    Ok(HttpResponse::Found()
+        .insert_header((LOCATION, format!("{}/{}", state.config.web_root, page)))
+        .finish())
+}
+
+/// When what the ledger's pages show to the user last changed, for
+/// `Last-Modified`. Restarts and feature toggles count as changes, as either
+/// may change the pages.
+///
+/// Returns None for logged out users, so that a browser never reuses a page
+/// from before its user logged out, and if the last change was this second,
+/// as a later change within the same second would go unnoticed.
+async fn page_last_modified(
+    state: &AppState,
+    ledger_id: i64,
+    user: Option<&AuthenticatedUser>,
+) -> Result<Option<chrono::DateTime<chrono::Utc>>, Error> {
+    let user_id = match user {
+        Some(user) => user.user_id.clone(),
+        None => return Ok(None),
+    };
+
+    let modified = state
+        .database
+        .get_last_modified(ledger_id, Some(user_id))
+        .await
+        .context(DatabaseError)?;
+
+    let last_modified = modified
+        .into_iter()
+        .chain(state.features.last_changed())
+        .fold(state.started, |a, b| a.max(b));
+
+    if last_modified.timestamp() >= chrono::Utc::now().timestamp() {
+        return Ok(None);
     }
+
+    Ok(Some(last_modified))
+}
+
+/// Query parameters for the home page.
+#[derive(Deserialize)]
+struct BalancesQuery {
+    /// Show settled users even if they're hidden by default.
+    #[serde(default)]
+    all: bool,
 }
 
 /// Get home page with current balances of all users.
+///
+/// If configured, settled users are left out of the balances unless `all` is
+/// set, though they can still be picked in the form. Who counts as settled
+/// changes with time, so the page then has no `Last-Modified`.
+///
+/// If the form was just rejected it's shown again with why, see
+/// [shaft_user]. Pages showing a flash message have no `Last-Modified`
+/// either.
 async fn get_balances(
-    (user, state): (AuthenticatedUser, web::Data<AppState>),
+    (req, access, ledger, state, query): (
+        HttpRequest,
+        ReadAccess,
+        CurrentLedger,
+        web::Data<AppState>,
+        web::Query<BalancesQuery>,
+    ),
 ) -> Result<HttpResponse, Error> {
+    let balances_settings = &state.config.balances;
+    let hiding_settled = balances_settings.hide_settled && !query.all;
+
+    let last_modified = if hiding_settled || has_flash(&req) {
+        None
+    } else {
+        page_last_modified(&state, ledger.id, access.user.as_ref()).await?
+    };
+    if let Some(last_modified) = last_modified {
+        if not_modified_since(&req, last_modified) {
+            return Ok(HttpResponse::NotModified()
+                .insert_header(last_modified_header(last_modified))
+                .finish());
+        }
+    }
+
+    let mut builder = HttpResponse::Ok();
+    builder.content_type("text/html");
+    let flash = take_flash(&state, &req, &mut builder)
+        .await
+        .context(SharedStateError)?;
+
     let hb = state.handlebars.clone();
     let all_users = state
         .database
-        .get_all_users()
+        .get_all_users(ledger.id)
         .await
-        .map_err(error::ErrorInternalServerError)?;
+        .context(DatabaseError)?;
 
-    let mut vec = all_users.values().collect_vec();
-    vec.sort_by_key(|e| e.balance);
+    let unsettled = if hiding_settled {
+        let settled_before = chrono::Utc::now()
+            - chrono::Duration::days(i64::from(balances_settings.settled_after_days));
+        let entries = state
+            .database
+            .get_users(
+                ledger.id,
+                db::UserFilter {
+                    settled_before: Some(settled_before),
+                    ..db::UserFilter::default()
+                },
+            )
+            .await
+            .context(DatabaseError)?;
+        Some(entries)
+    } else {
+        None
+    };
 
-    let s = hb
-        .render(
-            "index",
-            &json!({
-                "display_name": &user.display_name,
-                "balances": vec,
-            }),
+    let (ledgers, counterparties, preferences) = match &access.user {
+        Some(user) => futures::try_join!(
+            state.database.get_ledgers_for_user(user.user_id.clone()),
+            state
+                .database
+                .get_balances_by_counterparty(ledger.id, user.user_id.clone()),
+            state.database.get_user_preferences(user.user_id.clone()),
         )
-        .map_err(|s| error::ErrorInternalServerError(s.to_string()))?;
+        .map(|(ledgers, counterparties, preferences)| {
+            (ledgers, Some(counterparties), Some(preferences))
+        })
+        .context(DatabaseError)?,
+        None => (Vec::new(), None, None),
+    };
 
-    let r = HttpResponse::Ok()
-        .content_type("text/html")
-        .content_length(s.len() as u64)
-        .body(s);
+    // A message about a rejected submission is shown with the form, rather
+    // than at the top of the page.
+    let (flash, rejected) = match flash {
+        Some(flash) if flash.form.is_some() => (None, Some(flash)),
+        flash => (flash, None),
+    };
 
-    Ok(r)
+    let mut page = IndexPage::new(&all_users, &state.config.reasons).with_ledgers(
+        &ledgers,
+        ledger.id,
+        &state.config.web_root,
+    );
+    match &unsettled {
+        Some(entries) if entries.len() < all_users.len() => {
+            page = page
+                .with_balances(entries.iter().map(|entry| &entry.user).collect())
+                .with_settled_toggle(SettledToggle {
+                    show_all: false,
+                    hidden: all_users.len() - entries.len(),
+                });
+        }
+        // Nothing was hidden, so there's nothing to show.
+        Some(_) => {}
+        None if balances_settings.hide_settled => {
+            page = page.with_settled_toggle(SettledToggle {
+                show_all: true,
+                hidden: 0,
+            });
+        }
+        None => {}
+    }
+    if let Some(counterparties) = &counterparties {
+        page = page.with_net_position(counterparties, state.config.currency.currency());
+    }
+    if let Some(preferences) = &preferences {
+        page = page.with_preferences(preferences, &state.config.currency);
+    }
+    if let Some(rejected) = &rejected {
+        page = page.with_rejected_form(rejected);
+    }
+    let s = PageContext::new(&state)
+        .user(access.user.as_ref())
+        .flash(flash)
+        .section(Section::Balances)
+        .render(&hb, "index", &page)
+        .context(TemplateError)?;
+
+    if let Some(last_modified) = last_modified {
+        builder
+            .insert_header(last_modified_header(last_modified))
+            .insert_header((CACHE_CONTROL, "private, no-cache"));
+    }
+
+    Ok(builder.body(s))
+}
+
+/// The most transactions that can be shown on the transactions page.
+const MAX_TRANSACTIONS_LIMIT: u32 = 5000;
+
+/// Query parameters for the transactions page.
+#[derive(Deserialize)]
+struct TransactionsQuery {
+    /// How many of the most recent transactions to show. Defaults to the
+    /// configured page size.
+    limit: Option<u32>,
 }
 
 /// Get list of recent transcations page.
+///
+/// The page is streamed as it's rendered, as it can get large.
 async fn get_transactions(
-    (user, state): (AuthenticatedUser, web::Data<AppState>),
+    (req, access, ledger, state, query): (
+        HttpRequest,
+        ReadAccess,
+        CurrentLedger,
+        web::Data<AppState>,
+        web::Query<TransactionsQuery>,
+    ),
 ) -> Result<HttpResponse, Error> {
-    let all_users = state
-        .database
-        .get_all_users()
-        .await
-        .map_err(error::ErrorInternalServerError)?;
+    let limit = query
+        .limit
+        .unwrap_or(state.config.ui.transactions_page_size)
+        .min(MAX_TRANSACTIONS_LIMIT);
 
-    let transactions = state
-        .database
-        .get_last_transactions(20)
+    let last_modified = if has_flash(&req) {
+        None
+    } else {
+        page_last_modified(&state, ledger.id, access.user.as_ref()).await?
+    };
+    if let Some(last_modified) = last_modified {
+        if not_modified_since(&req, last_modified) {
+            return Ok(HttpResponse::NotModified()
+                .insert_header(last_modified_header(last_modified))
+                .finish());
+        }
+    }
+
+    // The two queries are independent, so run them concurrently.
+    let (all_users, transactions) = futures::try_join!(
+        state.database.get_all_users(ledger.id),
+        state.database.get_last_transactions(ledger.id, limit),
+    )
+    .context(DatabaseError)?;
+
+    let hb = state.handlebars.clone();
+
+    let mut builder = HttpResponse::Ok();
+    let flash = take_flash(&state, &req, &mut builder)
         .await
-        .map_err(error::ErrorInternalServerError)?;
+        .context(SharedStateError)?;
+    if let Some(last_modified) = last_modified {
+        builder
+            .insert_header(last_modified_header(last_modified))
+            .insert_header((CACHE_CONTROL, "private, no-cache"));
+    }
 
-    let page = state
-        .handlebars
-        .render(
-            "transactions",
-            &json!({
-                "display_name": &user.display_name,
-                "transactions": transactions
-                    .into_iter()
-                    .map(|txn| json!({
-                        "amount": txn.amount,
-                        "shafter_id": txn.shafter,
-                        "shafter_name": all_users.get(&txn.shafter)
-                            .map(|u| &u.display_name as &str)
-                            .unwrap_or(&txn.shafter),
-                        "shaftee_id": txn.shaftee,
-                        "shaftee_name": all_users.get(&txn.shaftee)
-                            .map(|u| &u.display_name as &str)
-                            .unwrap_or(&txn.shaftee),
-                        "date": format!("{}", txn.datetime.format("%d %b %Y")),
-                        "reason": txn.reason,
-                    }))
-                    .collect_vec(),
-            }),
-        )
-        .map_err(|e| error::ErrorInternalServerError(e.to_string()))?;
+    let context = PageContext::new(&state)
+        .user(access.user.as_ref())
+        .flash(flash)
+        .section(Section::Transactions);
+
+    Ok(stream_html(builder, move |out| {
+        let page = TransactionsPage::new(&all_users, &transactions);
+        context.render_to_write(&hb, "transactions", &page, out)
+    }))
+}
 
-    Ok(HttpResponse::Ok()
-        .content_type("text/html")
-        .content_length(page.len() as u64)
-        .body(page))
+/// What to tell the user if the submission was rejected because of what they
+/// entered, rather than something going wrong.
+fn form_error_message(err: &ShaftError) -> Option<String> {
+    match err {
+        ShaftError::InvalidAmount { .. } | ShaftError::InvalidReason { .. } => {
+            Some(err.to_string())
+        }
+        ShaftError::DatabaseError {
+            source: db::DatabaseError::UnknownUser { .. },
+            ..
+        }
+        | ShaftError::DatabaseError {
+            source: db::DatabaseError::UserDeactivated { .. },
+            ..
+        } => Some(err.to_string()),
+        _ => None,
+    }
 }
 
 /// Commit a new tranaction request
+///
+/// The user is sent back to the home page with a flash message saying how it
+/// went. If what was entered is invalid, e.g. the amount can't be parsed, the
+/// message holds the submission so that the form can be filled back in.
 async fn shaft_user(
-    (user, req, state, body): (
+    (user, ledger, state, body, ReqLogger(logger)): (
         AuthenticatedUser,
-        HttpRequest,
+        CurrentLedger,
         web::Data<AppState>,
         web::Form<ShaftUserBody>,
+        ReqLogger,
     ),
 ) -> Result<HttpResponse, Error> {
-    let logger = req
-        .extensions()
-        .get::<Logger>()
-        .expect("no logger installed in request")
-        .clone();
-
-    let ShaftUserBody {
-        other_user,
-        amount,
-        reason,
-    } = body.0;
+    authz::require_scope(&user, Scope::Write)?;
+
+    let body = body.into_inner();
+    let (transaction, outbox) = match create_transaction(&state, &user, ledger.id, &body).await {
+        Ok(created) => created,
+        Err(err) => {
+            let error = form_error_message(&err).ok_or(err)?;
+            info!(logger, "Rejected shaft form"; "error" => &error);
+
+            let mut builder = HttpResponse::Found();
+            builder.insert_header((LOCATION, "."));
+            set_flash(&state, &mut builder, &Flash::error(&error).with_form(body))
+                .await
+                .context(SharedStateError)?;
 
+            return Ok(builder.body(format!("{}\n", error)));
+        }
+    };
+
+    let amount = transaction.amount;
     state
-        .database
-        .shaft_user(db::Transaction {
-            shafter: user.user_id.clone(),
-            shaftee: other_user.clone(),
-            amount,
-            datetime: chrono::Utc::now(),
-            reason,
-        })
+        .events
+        .publish_outbox(&outbox)
         .await
-        .map_err(error::ErrorInternalServerError)?;
+        .context(EventError)?;
 
     info!(
         logger, "Shafted user";
-        "other_user" => other_user, "amount" => amount
+        "other_user" => &body.other_user, "amount" => amount.minor_units()
     );
 
-    Ok(HttpResponse::Found()
-        .header(LOCATION, ".")
-        .body("Success\n"))
+    let mut builder = HttpResponse::Found();
+    builder.insert_header((LOCATION, "."));
+    let message = format!(
+        "Saved {} with {}",
+        money::format(amount, &state.config.currency),
+        body.other_user
+    );
+    set_flash(&state, &mut builder, &Flash::success(message))
+        .await
+        .context(SharedStateError)?;
+
+    Ok(builder.body("Success\n"))
+}
+
+/// Check and store the transaction submitted with the form. Returns it with
+/// the outbox announcing it, to publish.
+async fn create_transaction(
+    state: &AppState,
+    user: &AuthenticatedUser,
+    ledger_id: i64,
+    body: &ShaftUserBody,
+) -> Result<(db::Transaction, Outbox<i64>), ShaftError> {
+    let amount = body
+        .amount
+        .resolve(&state.config.currency)
+        .context(InvalidAmount)?;
+    let reason = check_reason(&body.reason, &state.config.reasons, &*state.content_policy)
+        .context(InvalidReason)?;
+
+    let mut transaction = db::Transaction {
+        id: None,
+        shafter: user.user_id.clone(),
+        shaftee: body.other_user.clone(),
+        amount,
+        datetime: chrono::Utc::now(),
+        reason,
+        kind: db::TransactionKind::Shaft,
+    };
+
+    let outbox = state.events.transaction_outbox(&transaction);
+    let transaction_id = state
+        .database
+        .shaft_user(ledger_id, transaction.clone(), outbox.clone())
+        .await
+        .context(DatabaseError)?;
+
+    transaction.id = Some(transaction_id);
+    Ok((transaction, outbox))
 }
 
-/// Login page.
-async fn show_login(state: web::Data<AppState>) -> Result<HttpResponse, Error> {
-    let hb = &state.handlebars;
-    let s = hb
-        .render("login", &json!({}))
-        .map_err(|s| error::ErrorInternalServerError(s.to_string()))?;
+/// Query parameters for `/login`
+#[derive(Deserialize)]
+struct LoginQuery {
+    /// The path to return to once logged in.
+    next: Option<String>,
+}
+
+/// Login page. Unless the user is to be sent somewhere after logging in, or
+/// there's a flash message, it always renders the same, so is served from
+/// the render cache.
+async fn show_login(
+    (req, state, query): (HttpRequest, web::Data<AppState>, web::Query<LoginQuery>),
+) -> Result<HttpResponse, Error> {
+    let providers = login_providers();
+
+    let mut builder = HttpResponse::Ok();
+    builder.content_type("text/html");
+    let flash = take_flash(&state, &req, &mut builder)
+        .await
+        .context(SharedStateError)?;
 
-    let r = HttpResponse::Ok()
-        .content_type("text/html")
-        .content_length(s.len() as u64)
-        .body(s);
+    let next = query.next.as_deref().and_then(validate_next);
+    let s = if next.is_some() || flash.is_some() {
+        PageContext::new(&state)
+            .flash(flash)
+            .render(
+                &state.handlebars,
+                "login",
+                &LoginPage::new(&providers, next),
+            )
+            .map(Bytes::from)
+    } else {
+        state.render_cache.render(
+            &state.handlebars,
+            "login",
+            &PageContext::new(&state).with(&LoginPage::new(&providers, None)),
+        )
+    }
+    .context(TemplateError)?;
 
-    Ok(r)
+    Ok(builder.body(s))
 }
 
-/// Logout user session.
-async fn logout((req, state): (HttpRequest, web::Data<AppState>)) -> Result<HttpResponse, Error> {
-    let logger = req
-        .extensions()
-        .get::<Logger>()
-        .expect("no logger installed in request")
-        .clone();
+/// Query parameters for `/logout`
+#[derive(Deserialize)]
+struct LogoutQuery {
+    /// The path to go to once logged out.
+    redirect: Option<String>,
+}
 
+impl LogoutQuery {
+    /// The query string that passes the redirect on to the logout action.
+    fn query_string(&self) -> String {
+        match self.redirect.as_deref().and_then(validate_next) {
+            Some(redirect) => format!(
+                "?{}",
+                url::form_urlencoded::Serializer::new(String::new())
+                    .append_pair("redirect", redirect)
+                    .finish()
+            ),
+            None => String::new(),
+        }
+    }
+}
+
+/// Asks the user to confirm that they want to log out, so that following a
+/// link can't log them out.
+async fn show_logout(
+    (state, query): (web::Data<AppState>, web::Query<LogoutQuery>),
+) -> Result<HttpResponse, Error> {
+    let s = PageContext::new(&state)
+        .render(
+            &state.handlebars,
+            "logout",
+            &json!({ "redirect_query": query.query_string() }),
+        )
+        .context(TemplateError)?;
+
+    Ok(HttpResponse::Ok().content_type("text/html").body(s))
+}
+
+/// Logout user session, then redirect to the `redirect` path within the app,
+/// or the root if not given.
+async fn logout(
+    (req, query, state, ReqLogger(logger)): (
+        HttpRequest,
+        web::Query<LogoutQuery>,
+        web::Data<AppState>,
+        ReqLogger,
+    ),
+) -> Result<HttpResponse, Error> {
     let db = state.database.clone();
 
-    let resp = HttpResponse::Found()
-        .header(LOCATION, ".")
-        .header(
+    let redirect = query.redirect.as_deref().and_then(validate_next);
+
+    let mut builder = HttpResponse::Found();
+    builder
+        .insert_header((
+            LOCATION,
+            format!("{}{}", state.config.web_root, redirect.unwrap_or("/")),
+        ))
+        .insert_header((
             SET_COOKIE,
-            "token=; HttpOnly; Secure; Path=/; Expires=Thu, 01 Jan 1970 00:00:00 GMT; SameSite=lax",
-        )
-        .body("Signed out\n");
+            SessionCookie::removal(&state.config).to_string(),
+        ));
 
     info!(logger, "Got logout request");
 
-    if let Some(token) = req.cookie("token") {
+    if let Some(token) = req.cookie(SESSION_COOKIE_NAME) {
+        // Delete the token before dropping it from the cache, so that a
+        // request in between can't cache it again.
         db.delete_token(token.value().to_string())
             .await
-            .map_err(error::ErrorInternalServerError)?;
+            .context(DatabaseError)?;
+        state
+            .auth_cache
+            .invalidate_token(token.value())
+            .await
+            .context(SharedStateError)?;
+
+        // Other instances may have the token cached too.
+        let user_id = req
+            .extensions()
+            .get::<AuthenticatedUser>()
+            .map(|user| user.user_id.clone());
+        if let Some(user_id) = user_id {
+            state
+                .shared_state
+                .publish(Invalidation::User { user_id })
+                .await
+                .context(SharedStateError)?;
+        }
     }
 
-    Ok(resp)
+    set_flash(
+        &state,
+        &mut builder,
+        &Flash::info("You have been signed out"),
+    )
+    .await
+    .context(SharedStateError)?;
+
+    Ok(builder.body("Signed out\n"))
 }
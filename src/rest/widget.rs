@@ -0,0 +1,208 @@
+//! A compact summary for home screen widgets and smartwatch clients.
+//!
+//! `GET /api/widget` returns the logged in user's balance, the three users
+//! owed the most and the three owing the most, and the latest transaction:
+//!
+//! ```json
+//! {
+//!   "schema_version": 1,
+//!   "user_id": "alice",
+//!   "display_name": "Alice",
+//!   "balance": 150,
+//!   "creditors": [{ "user_id": "carol", "display_name": "Carol", "balance": 900 }],
+//!   "debtors": [{ "user_id": "bob", "display_name": "Bob", "balance": -1050 }],
+//!   "last_transaction": {
+//!     "id": 12,
+//!     "shafter": "alice",
+//!     "shaftee": "bob",
+//!     "amount": 150,
+//!     "time": 1589040000,
+//!     "reason": "Coffee"
+//!   }
+//! }
+//! ```
+//!
+//! Amounts are in pence and times are Unix timestamps in seconds. The other
+//! users exclude the requester, and `last_transaction` is null if there are
+//! none. Unlike the rest of the API these types are a stable contract: fields
+//! are only ever added, and anything else bumps `schema_version`.
+//!
+//! Responses have an ETag, and a matching `If-None-Match` gets a 304.
+
+use actix_web::web::ServiceConfig;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use hyper::header::{ETAG, VARY};
+use serde::Serialize;
+use snafu::ResultExt;
+
+use crate::db::{self, Scope};
+use crate::error::DatabaseError;
+use crate::money::Money;
+use crate::rest::response::json_response;
+use crate::rest::{authz, etag_matches, AppState, AuthenticatedUser, CurrentLedger};
+
+/// The version of the payload's schema.
+const WIDGET_SCHEMA_VERSION: u32 = 1;
+
+/// How many creditors and debtors to include.
+const WIDGET_TOP_USERS: usize = 3;
+
+/// Register servlets with HTTP app
+pub fn register_servlets(config: &mut ServiceConfig) {
+    config.route("/api/widget", web::get().to(get_widget));
+}
+
+/// The `/api/widget` response.
+#[derive(Debug, Serialize)]
+struct WidgetPayload {
+    schema_version: u32,
+    user_id: String,
+    display_name: String,
+    /// The requester's balance.
+    balance: Money,
+    /// The users owed the most, largest first.
+    creditors: Vec<WidgetUser>,
+    /// The users owing the most, largest debt first.
+    debtors: Vec<WidgetUser>,
+    last_transaction: Option<WidgetTransaction>,
+}
+
+/// Another user and their balance.
+#[derive(Debug, Serialize)]
+struct WidgetUser {
+    user_id: String,
+    display_name: String,
+    balance: Money,
+}
+
+/// A transaction, as shown in a widget.
+#[derive(Debug, Serialize)]
+struct WidgetTransaction {
+    id: Option<i64>,
+    shafter: String,
+    shaftee: String,
+    amount: Money,
+    /// Unix timestamp in seconds.
+    time: i64,
+    reason: String,
+}
+
+impl From<db::User> for WidgetUser {
+    fn from(user: db::User) -> WidgetUser {
+        WidgetUser {
+            user_id: user.user_id,
+            display_name: user.display_name,
+            balance: user.balance,
+        }
+    }
+}
+
+impl From<db::Transaction> for WidgetTransaction {
+    fn from(transaction: db::Transaction) -> WidgetTransaction {
+        WidgetTransaction {
+            id: transaction.id,
+            shafter: transaction.shafter,
+            shaftee: transaction.shaftee,
+            amount: transaction.amount,
+            time: transaction.datetime.timestamp(),
+            reason: transaction.reason,
+        }
+    }
+}
+
+/// The ETag for the widget. Depends on the user as well as the ledger, as
+/// the payload is personal.
+fn widget_etag(user_id: &str, version: i64) -> String {
+    format!(
+        "W/\"widget-{}-{}-{}\"",
+        WIDGET_SCHEMA_VERSION, user_id, version
+    )
+}
+
+/// Get the compact summary for the logged in user.
+async fn get_widget(
+    (req, state, user, ledger): (
+        HttpRequest,
+        web::Data<AppState>,
+        AuthenticatedUser,
+        CurrentLedger,
+    ),
+) -> Result<HttpResponse, Error> {
+    authz::require_scope(&user, Scope::Read)?;
+
+    let etag = widget_etag(
+        &user.user_id,
+        state
+            .database
+            .get_ledger_version()
+            .await
+            .context(DatabaseError)?,
+    );
+
+    if etag_matches(&req, &etag) {
+        return Ok(HttpResponse::NotModified()
+            .insert_header((ETAG, etag))
+            .insert_header((VARY, "Cookie, Authorization"))
+            .finish());
+    }
+
+    let all_users = state
+        .database
+        .get_all_users(ledger.id)
+        .await
+        .context(DatabaseError)?;
+
+    let balance = all_users
+        .get(&user.user_id)
+        .map(|me| me.balance)
+        .unwrap_or_else(|| Money::zero(state.config.currency.currency()));
+
+    let mut others: Vec<db::User> = all_users
+        .into_iter()
+        .map(|(_, other)| other)
+        .filter(|other| other.user_id != user.user_id)
+        .collect();
+    others.sort_by_key(|other| other.balance);
+
+    let debtors = others
+        .iter()
+        .take_while(|other| other.balance.is_negative())
+        .take(WIDGET_TOP_USERS)
+        .cloned()
+        .map(WidgetUser::from)
+        .collect();
+    let creditors = others
+        .iter()
+        .rev()
+        .take_while(|other| other.balance.is_positive())
+        .take(WIDGET_TOP_USERS)
+        .cloned()
+        .map(WidgetUser::from)
+        .collect();
+
+    let last_transaction = state
+        .database
+        .get_last_transactions(ledger.id, 1)
+        .await
+        .context(DatabaseError)?
+        .into_iter()
+        .next()
+        .map(WidgetTransaction::from);
+
+    let payload = WidgetPayload {
+        schema_version: WIDGET_SCHEMA_VERSION,
+        user_id: user.user_id,
+        display_name: user.display_name,
+        balance,
+        creditors,
+        debtors,
+        last_transaction,
+    };
+
+    let mut builder = HttpResponse::Ok();
+    builder
+        .insert_header((ETAG, etag))
+        .insert_header((VARY, "Cookie, Authorization"));
+
+    Ok(json_response(&req, builder, &payload))
+}
@@ -0,0 +1,122 @@
+//! Filling a database with fake users and transactions, for load testing and
+//! reviewing the UI.
+//!
+//! `shaft admin seed --users 5 --transactions 200` adds users `seed1` to
+//! `seed5` to the default ledger and shafts between them. The data only
+//! depends on the `--seed` given, and everything goes through the normal
+//! [Database] methods, so the result looks like real use.
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use snafu::{ensure, ResultExt, Snafu};
+
+use crate::db::{
+    self, fake_name, Database, Outbox, Transaction, TransactionKind, DEFAULT_LEDGER_ID, REASONS,
+};
+use crate::money::{Currency, Money};
+
+/// How far back seeded transactions go.
+const HISTORY_DAYS: i64 = 90;
+
+/// What to seed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SeedOptions {
+    /// Number of users. Existing seeded users are reused.
+    pub users: usize,
+    /// Number of transactions to add between the users.
+    pub transactions: usize,
+    /// Seed for the random number generator.
+    pub seed: u64,
+}
+
+/// What was added.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SeedSummary {
+    /// Number of users created, excluding existing ones.
+    pub users: usize,
+    /// Number of transactions added.
+    pub transactions: usize,
+}
+
+/// Error seeding the database.
+#[derive(Debug, Snafu)]
+pub enum SeedError {
+    /// Transactions need two different users.
+    #[snafu(display("At least two users are needed to add transactions"))]
+    TooFewUsers,
+
+    #[snafu(display("DB error: {}", source))]
+    DatabaseError { source: db::DatabaseError },
+}
+
+/// Add fake users and transactions to the default ledger.
+pub async fn seed(
+    database: &dyn Database,
+    currency: Currency,
+    options: SeedOptions,
+) -> Result<SeedSummary, SeedError> {
+    ensure!(options.transactions == 0 || options.users >= 2, TooFewUsers);
+
+    let mut rng = StdRng::seed_from_u64(options.seed);
+    let mut summary = SeedSummary::default();
+
+    let mut user_ids = Vec::with_capacity(options.users);
+    for idx in 0..options.users {
+        let github_id = format!("seed{}", idx + 1);
+
+        let existing = database
+            .get_user_by_github_id(github_id.clone())
+            .await
+            .context(DatabaseError)?;
+        let user_id = match existing {
+            Some(user_id) => user_id,
+            None => {
+                summary.users += 1;
+                database
+                    .add_user_by_github_id(github_id, fake_name(idx), Outbox::none())
+                    .await
+                    .context(DatabaseError)?
+            }
+        };
+
+        user_ids.push(user_id);
+    }
+
+    // Spread over the history, oldest first so IDs increase with time.
+    let now = chrono::Utc::now();
+    let mut offsets: Vec<i64> = (0..options.transactions)
+        .map(|_| rng.gen_range(0, HISTORY_DAYS * 24 * 60 * 60))
+        .collect();
+    offsets.sort_unstable_by(|a, b| b.cmp(a));
+
+    let transactions: Vec<Transaction> = offsets
+        .into_iter()
+        .map(|offset| {
+            let mut parties = user_ids.choose_multiple(&mut rng, 2);
+            let shafter = parties.next().expect("at least two users").clone();
+            let shaftee = parties.next().expect("at least two users").clone();
+
+            Transaction {
+                id: None,
+                shafter,
+                shaftee,
+                amount: Money::new(rng.gen_range(50, 5000), currency),
+                datetime: now - chrono::Duration::seconds(offset),
+                reason: REASONS
+                    .choose(&mut rng)
+                    .expect("reasons not empty")
+                    .to_string(),
+                kind: TransactionKind::Shaft,
+            }
+        })
+        .collect();
+
+    summary.transactions = database
+        .insert_transactions_batch(DEFAULT_LEDGER_ID, transactions, Outbox::none())
+        .await
+        .context(DatabaseError)?
+        .len();
+
+    Ok(summary)
+}
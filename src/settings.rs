@@ -1,10 +1,16 @@
 //! The configuration settings definitions.
 
 use serde::Deserialize;
+use sloggers::types::Severity;
+use sloggers::LoggerConfig;
+
+use std::collections::BTreeMap;
+
+use crate::money::{Currency, CurrencyCode};
 
 /// Settings for github login. To configure a github OAuth app must have been
 /// provisioned.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct GithubSettings {
     /// The OAuth app "client ID"
     pub client_id: String,
@@ -17,6 +23,718 @@ pub struct GithubSettings {
     pub required_org: String,
 }
 
+/// Which implementation of the database to use.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DatabaseBackend {
+    /// Blocking rusqlite calls run on a thread pool.
+    Rusqlite,
+    /// Async sqlx calls. Requires the `sqlx` feature.
+    Sqlx,
+}
+
+impl Default for DatabaseBackend {
+    fn default() -> DatabaseBackend {
+        DatabaseBackend::Rusqlite
+    }
+}
+
+/// Settings for the database connection pool.
+#[derive(Debug, Deserialize, Clone)]
+pub struct DatabasePoolSettings {
+    /// Maximum number of open database connections.
+    #[serde(default = "default_db_max_connections")]
+    pub max_connections: u32,
+    /// How long to wait for a free connection before giving up, in seconds.
+    #[serde(default = "default_db_connection_timeout_secs")]
+    pub connection_timeout_secs: u64,
+    /// Maximum number of database operations that may be queued or running
+    /// before requests are turned away with a 503.
+    #[serde(default = "default_db_max_queued")]
+    pub max_queued: usize,
+}
+
+impl Default for DatabasePoolSettings {
+    fn default() -> DatabasePoolSettings {
+        DatabasePoolSettings {
+            max_connections: default_db_max_connections(),
+            connection_timeout_secs: default_db_connection_timeout_secs(),
+            max_queued: default_db_max_queued(),
+        }
+    }
+}
+
+/// SQLite's journal mode, see <https://sqlite.org/pragma.html#pragma_journal_mode>.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SqliteJournalMode {
+    Delete,
+    Truncate,
+    Persist,
+    Memory,
+    Wal,
+    Off,
+}
+
+impl SqliteJournalMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SqliteJournalMode::Delete => "DELETE",
+            SqliteJournalMode::Truncate => "TRUNCATE",
+            SqliteJournalMode::Persist => "PERSIST",
+            SqliteJournalMode::Memory => "MEMORY",
+            SqliteJournalMode::Wal => "WAL",
+            SqliteJournalMode::Off => "OFF",
+        }
+    }
+}
+
+/// How often SQLite syncs to disk, see
+/// <https://sqlite.org/pragma.html#pragma_synchronous>.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SqliteSynchronous {
+    Off,
+    Normal,
+    Full,
+    Extra,
+}
+
+impl SqliteSynchronous {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SqliteSynchronous::Off => "OFF",
+            SqliteSynchronous::Normal => "NORMAL",
+            SqliteSynchronous::Full => "FULL",
+            SqliteSynchronous::Extra => "EXTRA",
+        }
+    }
+}
+
+/// Pragmas applied to each SQLite connection when it is opened.
+#[derive(Debug, Deserialize, Clone)]
+pub struct SqliteSettings {
+    /// The journal mode. WAL lets reads proceed while a write is in progress.
+    #[serde(default = "default_sqlite_journal_mode")]
+    pub journal_mode: SqliteJournalMode,
+    /// How long to wait for another connection's lock to be released before
+    /// failing with "database is locked", in milliseconds.
+    #[serde(default = "default_sqlite_busy_timeout_ms")]
+    pub busy_timeout_ms: u64,
+    /// How often to sync to disk.
+    #[serde(default = "default_sqlite_synchronous")]
+    pub synchronous: SqliteSynchronous,
+    /// Whether to enforce foreign key constraints.
+    #[serde(default = "default_sqlite_foreign_keys")]
+    pub foreign_keys: bool,
+}
+
+impl Default for SqliteSettings {
+    fn default() -> SqliteSettings {
+        SqliteSettings {
+            journal_mode: default_sqlite_journal_mode(),
+            busy_timeout_ms: default_sqlite_busy_timeout_ms(),
+            synchronous: default_sqlite_synchronous(),
+            foreign_keys: default_sqlite_foreign_keys(),
+        }
+    }
+}
+
+/// Settings for the outbound HTTP client.
+#[derive(Debug, Deserialize)]
+pub struct HttpClientSettings {
+    /// How long to wait for a response before giving up, in seconds.
+    #[serde(default = "default_http_timeout_secs")]
+    pub timeout_secs: u64,
+    /// How many requests to a host must fail in a row before requests to it
+    /// fail immediately. 0 disables this.
+    #[serde(default = "default_circuit_breaker_failures")]
+    pub circuit_breaker_failures: u32,
+    /// How long to wait before trying a failing host again, in seconds.
+    #[serde(default = "default_circuit_breaker_cooldown_secs")]
+    pub circuit_breaker_cooldown_secs: u64,
+}
+
+impl Default for HttpClientSettings {
+    fn default() -> HttpClientSettings {
+        HttpClientSettings {
+            timeout_secs: default_http_timeout_secs(),
+            circuit_breaker_failures: default_circuit_breaker_failures(),
+            circuit_breaker_cooldown_secs: default_circuit_breaker_cooldown_secs(),
+        }
+    }
+}
+
+/// Tuning for the HTTP server. Apart from the request timeout, anything left
+/// unset uses actix-web's default, which scales the number of workers with the
+/// CPU count.
+#[derive(Debug, Deserialize)]
+pub struct HttpServerSettings {
+    /// Number of worker threads.
+    pub workers: Option<usize>,
+    /// How long a client has to send the request headers, in seconds.
+    pub client_timeout_secs: Option<u64>,
+    /// How long to keep idle connections open, in seconds. 0 disables
+    /// keep-alive.
+    pub keep_alive_secs: Option<u64>,
+    /// Maximum number of concurrent connections per worker.
+    pub max_connections: Option<usize>,
+    /// How long to wait for in-flight requests to finish when shutting down,
+    /// in seconds.
+    pub shutdown_timeout_secs: Option<u64>,
+    /// How long a request may take overall before it is failed, in seconds.
+    #[serde(default = "default_request_timeout_secs")]
+    pub request_timeout_secs: u64,
+    /// Path prefixes whose requests are never authenticated, so don't touch
+    /// the database, even if they have a session cookie. By default this is
+    /// monitoring probes, static files, and webhooks that authenticate
+    /// themselves.
+    #[serde(default = "default_skip_auth_paths")]
+    pub skip_auth_paths: Vec<String>,
+    /// Path prefixes whose requests are only logged if they fail.
+    #[serde(default = "default_probe_paths")]
+    pub quiet_log_paths: Vec<String>,
+}
+
+impl Default for HttpServerSettings {
+    fn default() -> HttpServerSettings {
+        HttpServerSettings {
+            workers: None,
+            client_timeout_secs: None,
+            keep_alive_secs: None,
+            max_connections: None,
+            shutdown_timeout_secs: None,
+            request_timeout_secs: default_request_timeout_secs(),
+            skip_auth_paths: default_skip_auth_paths(),
+            quiet_log_paths: default_probe_paths(),
+        }
+    }
+}
+
+/// Settings for processing uploaded receipts.
+#[derive(Debug, Deserialize)]
+pub struct ReceiptSettings {
+    /// The HTTP endpoint receipts are POSTed to for processing.
+    pub endpoint: String,
+}
+
+/// Settings for sending emails, e.g. ledger invitations.
+#[derive(Debug, Deserialize)]
+pub struct MailSettings {
+    /// The HTTP endpoint emails are POSTed to as JSON.
+    pub endpoint: String,
+    /// The address emails are sent from.
+    pub from: String,
+}
+
+/// Settings for notifying users, e.g. when they're poked.
+#[derive(Debug, Deserialize)]
+pub struct NotificationSettings {
+    /// The HTTP endpoint notifications are POSTed to as JSON.
+    pub endpoint: String,
+}
+
+/// Which client IP addresses may make requests. Each list holds networks in
+/// CIDR notation, e.g. `192.168.0.0/16`, or bare addresses. Denials take
+/// precedence, and an empty allow list allows everything not denied.
+#[derive(Debug, Default, Deserialize)]
+pub struct IpFilterSettings {
+    /// If not empty, only these networks may make requests.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// These networks may not make requests.
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// If not empty, only these networks may use the admin API.
+    #[serde(default)]
+    pub admin_allow: Vec<String>,
+    /// These networks may not use the admin API.
+    #[serde(default)]
+    pub admin_deny: Vec<String>,
+}
+
+/// The initial state of the optional subsystems. Admins can change these at
+/// runtime.
+#[derive(Debug, Deserialize, Clone)]
+pub struct FeatureSettings {
+    /// Whether webhooks are enabled.
+    #[serde(default)]
+    pub webhooks: bool,
+    /// Whether to allow logged out users to view balances and transactions.
+    /// Only suitable for trusted networks.
+    #[serde(default)]
+    pub public_read: bool,
+    /// Whether new members of the required GitHub org may sign up.
+    #[serde(default = "default_registration_open")]
+    pub registration_open: bool,
+    /// Whether transactions may be given categories.
+    #[serde(default)]
+    pub categories: bool,
+}
+
+impl Default for FeatureSettings {
+    fn default() -> FeatureSettings {
+        FeatureSettings {
+            webhooks: false,
+            public_read: false,
+            registration_open: default_registration_open(),
+            categories: false,
+        }
+    }
+}
+
+/// Where and how verbosely to log.
+#[derive(Debug, Default, Deserialize)]
+pub struct LogSettings {
+    /// The output, see [sloggers::LoggerConfig]. Its `level` is the default
+    /// for modules without an override.
+    #[serde(flatten)]
+    pub logger: LoggerConfig,
+    /// Levels for particular modules and their submodules, e.g.
+    /// `rest = "debug"`.
+    #[serde(default)]
+    pub modules: BTreeMap<String, Severity>,
+}
+
+/// Where to report server errors.
+#[derive(Debug, Deserialize)]
+pub struct ErrorReportingSettings {
+    /// The Sentry DSN of the project to report to.
+    pub sentry_dsn: String,
+    /// The environment reported to Sentry, e.g. `production`.
+    pub environment: Option<String>,
+}
+
+/// How long login sessions last.
+#[derive(Debug, Deserialize)]
+pub struct SessionSettings {
+    /// How long a session lasts if the user doesn't ask to stay logged in,
+    /// in seconds. The cookie is dropped when the browser closes.
+    #[serde(default = "default_session_lifetime_secs")]
+    pub session_lifetime_secs: u64,
+    /// How long a session lasts if the user asks to stay logged in, in
+    /// seconds.
+    #[serde(default = "default_remember_me_lifetime_secs")]
+    pub remember_me_lifetime_secs: u64,
+    /// If set, sessions used this many seconds after they were issued or
+    /// last extended get their full lifetime again, so they only expire
+    /// once they stop being used.
+    #[serde(default)]
+    pub refresh_after_secs: Option<u64>,
+}
+
+impl Default for SessionSettings {
+    fn default() -> SessionSettings {
+        SessionSettings {
+            session_lifetime_secs: default_session_lifetime_secs(),
+            remember_me_lifetime_secs: default_remember_me_lifetime_secs(),
+            refresh_after_secs: None,
+        }
+    }
+}
+
+/// Where state shared between instances is kept, see
+/// [shared_state](crate::shared_state).
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SharedStateBackend {
+    /// In memory, for a single instance.
+    Memory,
+    /// In the database, for several instances sharing it.
+    Database,
+    /// In the configured Redis server. Requires the `redis` feature.
+    Redis,
+}
+
+impl Default for SharedStateBackend {
+    fn default() -> SharedStateBackend {
+        SharedStateBackend::Memory
+    }
+}
+
+/// Settings for running several instances behind a load balancer.
+#[derive(Debug, Deserialize, Clone)]
+pub struct SharedStateSettings {
+    #[serde(default)]
+    pub backend: SharedStateBackend,
+    /// How often to check for changes made by other instances, in seconds.
+    #[serde(default = "default_shared_state_poll_interval_secs")]
+    pub poll_interval_secs: u64,
+}
+
+/// Settings for keeping the auth cache, rate limits and events in Redis, see
+/// `redis_store`. Requires the `redis` feature.
+#[derive(Debug, Deserialize, Clone)]
+pub struct RedisSettings {
+    /// E.g. `redis://127.0.0.1:6379/0`.
+    pub url: String,
+    /// Prepended to every key and channel, so deployments can share a server.
+    #[serde(default = "default_redis_prefix")]
+    pub prefix: String,
+}
+
+impl Default for SharedStateSettings {
+    fn default() -> SharedStateSettings {
+        SharedStateSettings {
+            backend: SharedStateBackend::default(),
+            poll_interval_secs: default_shared_state_poll_interval_secs(),
+        }
+    }
+}
+
+/// The `SameSite` attribute of cookies, i.e. whether browsers send them with
+/// requests started by other sites.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SameSite {
+    /// Never sent with requests from other sites.
+    Strict,
+    /// Only sent with top level navigations from other sites, e.g. following
+    /// a link.
+    Lax,
+    /// Always sent. Browsers reject this unless the cookie is also secure.
+    None,
+}
+
+impl SameSite {
+    /// The attribute's value in a `Set-Cookie` header.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        }
+    }
+}
+
+impl Default for SameSite {
+    fn default() -> SameSite {
+        SameSite::Lax
+    }
+}
+
+/// The attributes of the cookies shaft sets.
+#[derive(Debug, Deserialize, Clone)]
+pub struct CookieSettings {
+    /// Whether browsers only send the cookies over HTTPS. Only turn this off
+    /// to test locally over plain HTTP.
+    #[serde(default = "default_cookie_secure")]
+    pub secure: bool,
+    /// Whether browsers send the cookies with requests from other sites.
+    #[serde(default)]
+    pub same_site: SameSite,
+}
+
+impl Default for CookieSettings {
+    fn default() -> CookieSettings {
+        CookieSettings {
+            secure: default_cookie_secure(),
+            same_site: SameSite::default(),
+        }
+    }
+}
+
+/// How balances are listed on the home page.
+#[derive(Debug, Deserialize, Clone)]
+pub struct BalancesSettings {
+    /// Whether to hide settled users, i.e. those with a zero balance and no
+    /// recent transactions, unless asked to show everyone.
+    #[serde(default)]
+    pub hide_settled: bool,
+    /// How many days without transactions before a user with a zero balance
+    /// counts as settled.
+    #[serde(default = "default_settled_after_days")]
+    pub settled_after_days: u32,
+}
+
+impl Default for BalancesSettings {
+    fn default() -> BalancesSettings {
+        BalancesSettings {
+            hide_settled: false,
+            settled_after_days: default_settled_after_days(),
+        }
+    }
+}
+
+/// How the web pages are laid out.
+#[derive(Debug, Deserialize, Clone)]
+pub struct UiSettings {
+    /// How many transactions the transactions page shows unless asked for
+    /// more or fewer.
+    #[serde(default = "default_transactions_page_size")]
+    pub transactions_page_size: u32,
+    /// The locale dates are shown in, e.g. `en_GB` or `fr_FR`, which sets the
+    /// names of months and days.
+    #[serde(default = "default_locale")]
+    pub locale: String,
+    /// How dates are shown, as a `strftime` format.
+    #[serde(default = "default_date_format")]
+    pub date_format: String,
+}
+
+impl Default for UiSettings {
+    fn default() -> UiSettings {
+        UiSettings {
+            transactions_page_size: default_transactions_page_size(),
+            locale: default_locale(),
+            date_format: default_date_format(),
+        }
+    }
+}
+
+/// Limits on the JSON API's paginated endpoints.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ApiSettings {
+    /// How many entries are returned if the request doesn't give a `limit`.
+    #[serde(default = "default_api_default_limit")]
+    pub default_limit: u32,
+    /// The most entries returned, whatever `limit` the request gives.
+    #[serde(default = "default_api_max_limit")]
+    pub max_limit: u32,
+}
+
+impl ApiSettings {
+    /// The number of entries to return for a request's `limit`.
+    pub fn limit(&self, requested: Option<u32>) -> u32 {
+        requested.unwrap_or(self.default_limit).min(self.max_limit)
+    }
+}
+
+impl Default for ApiSettings {
+    fn default() -> ApiSettings {
+        ApiSettings {
+            default_limit: default_api_default_limit(),
+            max_limit: default_api_max_limit(),
+        }
+    }
+}
+
+/// Settings for the Slack slash command. The command's request URL should be
+/// `<public_url>/integrations/slack/command`.
+#[derive(Debug, Deserialize, Clone)]
+pub struct SlackSettings {
+    /// The Slack app's "signing secret", used to check requests are from
+    /// Slack.
+    pub signing_secret: String,
+    /// The URL users reach shaft at, used in links sent to Slack.
+    pub public_url: String,
+}
+
+/// Settings for inviting people to a ledger by email. Invitations are only
+/// delivered if `mail` is also configured.
+#[derive(Debug, Deserialize, Clone)]
+pub struct InviteSettings {
+    /// A random secret used to sign invitation links. Changing it invalidates
+    /// any outstanding invitations.
+    pub signing_secret: String,
+    /// The URL users reach shaft at, used in the emailed links.
+    pub public_url: String,
+    /// How long an invitation link is valid for, in seconds.
+    #[serde(default = "default_invite_lifetime_secs")]
+    pub lifetime_secs: u64,
+}
+
+/// How often the outstanding debts digest is sent.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DigestPeriod {
+    Weekly,
+    Monthly,
+}
+
+impl DigestPeriod {
+    /// How long to wait between digests.
+    pub fn duration(self) -> std::time::Duration {
+        let days = match self {
+            DigestPeriod::Weekly => 7,
+            DigestPeriod::Monthly => 30,
+        };
+        std::time::Duration::from_secs(days * 24 * 60 * 60)
+    }
+}
+
+impl Default for DigestPeriod {
+    fn default() -> DigestPeriod {
+        DigestPeriod::Weekly
+    }
+}
+
+/// Settings for periodically emailing a summary of the default ledger's
+/// largest and longest unsettled debts. Only delivered if `mail` is also
+/// configured.
+#[derive(Debug, Deserialize, Clone)]
+pub struct DebtDigestSettings {
+    /// The email addresses to send the digest to.
+    pub recipients: Vec<String>,
+    /// How often to send it.
+    #[serde(default)]
+    pub period: DigestPeriod,
+    /// How many debts to list in each section.
+    #[serde(default = "default_debt_digest_limit")]
+    pub limit: usize,
+}
+
+/// An endpoint that events are POSTed to while the `webhooks` feature is
+/// enabled.
+#[derive(Debug, Deserialize, Clone)]
+pub struct WebhookSettings {
+    /// Identifies the webhook in the outbox and admin API.
+    pub id: String,
+    pub url: String,
+    /// Key the payloads are signed with, so the receiver can check they came
+    /// from shaft.
+    pub secret: String,
+    /// The types of event to send, e.g. `transaction_created`. All events are
+    /// sent if empty.
+    #[serde(default)]
+    pub events: Vec<String>,
+}
+
+/// Settings for delivering the notifications and webhooks in the outbox.
+#[derive(Debug, Deserialize, Clone)]
+pub struct OutboxSettings {
+    /// How often to look for deliveries that are due, in seconds.
+    #[serde(default = "default_outbox_poll_interval_secs")]
+    pub poll_interval_secs: u64,
+    /// How many times to try a delivery before giving up.
+    #[serde(default = "default_outbox_max_attempts")]
+    pub max_attempts: u32,
+    /// How long to keep finished deliveries for, in days.
+    #[serde(default = "default_outbox_retention_days")]
+    pub retention_days: u32,
+}
+
+impl Default for OutboxSettings {
+    fn default() -> OutboxSettings {
+        OutboxSettings {
+            poll_interval_secs: default_outbox_poll_interval_secs(),
+            max_attempts: default_outbox_max_attempts(),
+            retention_days: default_outbox_retention_days(),
+        }
+    }
+}
+
+/// How amounts of money are displayed and entered. Amounts are stored as
+/// integers in the currency's minor unit, e.g. pence.
+#[derive(Debug, Deserialize, Clone)]
+pub struct CurrencySettings {
+    /// The symbol shown before amounts, e.g. `£`.
+    #[serde(default = "default_currency_symbol")]
+    pub symbol: String,
+    /// The ISO 4217 code, e.g. `GBP`, used in exports.
+    #[serde(default = "default_currency_code")]
+    pub code: CurrencyCode,
+    /// The number of minor units in a major unit as a power of ten, e.g. 2
+    /// for pence or 0 for yen.
+    #[serde(default = "default_currency_minor_units")]
+    pub minor_units: u8,
+    /// Separates groups of thousands, e.g. `,` in `1,200`. May be empty.
+    #[serde(default = "default_currency_thousands_separator")]
+    pub thousands_separator: String,
+    /// Separates the major and minor units, e.g. `.` in `12.50`.
+    #[serde(default = "default_currency_decimal_separator")]
+    pub decimal_separator: String,
+}
+
+impl CurrencySettings {
+    /// The currency amounts are in.
+    pub fn currency(&self) -> Currency {
+        Currency {
+            code: self.code,
+            minor_units: self.minor_units,
+        }
+    }
+}
+
+impl Default for CurrencySettings {
+    fn default() -> CurrencySettings {
+        CurrencySettings {
+            symbol: default_currency_symbol(),
+            code: default_currency_code(),
+            minor_units: default_currency_minor_units(),
+            thousands_separator: default_currency_thousands_separator(),
+            decimal_separator: default_currency_decimal_separator(),
+        }
+    }
+}
+
+/// What reasons transactions must be given. Lengths are in characters, and
+/// apply to the reason with surrounding whitespace removed.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ReasonSettings {
+    /// Whether every transaction needs a reason.
+    #[serde(default = "default_reason_required")]
+    pub required: bool,
+    /// The shortest reason allowed, if one is given.
+    #[serde(default)]
+    pub min_length: usize,
+    /// The longest reason allowed.
+    #[serde(default = "default_reason_max_length")]
+    pub max_length: usize,
+}
+
+impl Default for ReasonSettings {
+    fn default() -> ReasonSettings {
+        ReasonSettings {
+            required: default_reason_required(),
+            min_length: 0,
+            max_length: default_reason_max_length(),
+        }
+    }
+}
+
+/// What is done with reasons containing denied content.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DenylistAction {
+    /// Refuse the reason, and so the transaction.
+    Reject,
+    /// Replace the denied content with asterisks.
+    Mask,
+}
+
+impl Default for DenylistAction {
+    fn default() -> DenylistAction {
+        DenylistAction::Reject
+    }
+}
+
+/// Settings for policing the content of reasons.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ContentPolicySettings {
+    /// Words, phrases or emoji that aren't allowed, matched ignoring case.
+    #[serde(default)]
+    pub denylist: Vec<String>,
+    /// What to do with reasons that contain them.
+    #[serde(default)]
+    pub action: DenylistAction,
+}
+
+/// An independent household hosted by the same process, with its own
+/// database. Settings left out are the instance's.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TenantSettings {
+    /// Identifies the tenant. Only lowercase letters, digits and hyphens.
+    pub name: String,
+    /// Path for the tenant's sqlite database.
+    pub database_file: String,
+    /// The hostnames the tenant is served on. If empty, it's served under
+    /// `/t/<name>` instead.
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// The user IDs of the tenant's admins.
+    pub admins: Option<Vec<String>>,
+    /// The tenant's own GitHub OAuth app.
+    pub github: Option<GithubSettings>,
+    pub currency: Option<CurrencySettings>,
+    pub reasons: Option<ReasonSettings>,
+    pub features: Option<FeatureSettings>,
+    /// The tenant's own webhooks. The instance's aren't sent the tenant's
+    /// events.
+    #[serde(default)]
+    pub webhooks: Vec<WebhookSettings>,
+}
+
 /// Setting for daemonization
 #[derive(Debug, Deserialize)]
 pub struct DaemonizeSettings {
@@ -32,6 +750,9 @@ pub struct Settings {
     /// Path for sqlite database.
     #[serde(default = "default_database_file")]
     pub database_file: String,
+    /// Which database implementation to use.
+    #[serde(default)]
+    pub database_backend: DatabaseBackend,
     /// Directory to look for the web resources
     #[serde(default = "default_resource_dir")]
     pub resource_dir: String,
@@ -43,9 +764,86 @@ pub struct Settings {
     pub bind: String,
     /// Logging config
     #[serde(default)]
-    pub log: sloggers::LoggerConfig,
+    pub log: LogSettings,
     /// If and how to daemonize after start.
     pub daemonize: Option<DaemonizeSettings>,
+    /// If and how to process uploaded receipts.
+    pub receipts: Option<ReceiptSettings>,
+    /// If and where to report server errors.
+    pub error_reporting: Option<ErrorReportingSettings>,
+    /// If and how to accept Slack slash commands.
+    pub slack: Option<SlackSettings>,
+    /// If and how to send emails.
+    pub mail: Option<MailSettings>,
+    /// If and how to notify users.
+    pub notifications: Option<NotificationSettings>,
+    /// If and how to invite people to ledgers by email.
+    pub invites: Option<InviteSettings>,
+    /// If and how to email a digest of outstanding debts.
+    pub debt_digest: Option<DebtDigestSettings>,
+    /// Where to send events, if the `webhooks` feature is enabled.
+    #[serde(default)]
+    pub webhooks: Vec<WebhookSettings>,
+    /// How notifications and webhooks are delivered.
+    #[serde(default)]
+    pub outbox: OutboxSettings,
+    /// How amounts of money are displayed and entered.
+    #[serde(default)]
+    pub currency: CurrencySettings,
+    /// What reasons transactions must be given.
+    #[serde(default)]
+    pub reasons: ReasonSettings,
+    /// If and how to police the content of reasons.
+    pub content_policy: Option<ContentPolicySettings>,
+    /// Other households hosted by the instance.
+    #[serde(default)]
+    pub tenants: Vec<TenantSettings>,
+    /// How balances are listed on the home page.
+    #[serde(default)]
+    pub balances: BalancesSettings,
+    /// How the web pages are laid out.
+    #[serde(default)]
+    pub ui: UiSettings,
+    /// Limits on the JSON API's paginated endpoints.
+    #[serde(default)]
+    pub api: ApiSettings,
+    /// Deprecated alias for `features.public_read`.
+    #[serde(default)]
+    pub public_read: bool,
+    /// Toggles optional subsystems.
+    #[serde(default)]
+    pub features: FeatureSettings,
+    /// The user IDs of instance admins. Their login sessions are given the
+    /// admin scope.
+    #[serde(default)]
+    pub admins: Vec<String>,
+    /// How long login sessions last.
+    #[serde(default)]
+    pub sessions: SessionSettings,
+    /// The attributes of the session cookie.
+    #[serde(default)]
+    pub cookies: CookieSettings,
+    /// Restricts which IP addresses may make requests.
+    #[serde(default)]
+    pub ip_filter: IpFilterSettings,
+    /// Tunes the HTTP server.
+    #[serde(default)]
+    pub http_server: HttpServerSettings,
+    /// Configures outbound HTTP requests, e.g. to GitHub.
+    #[serde(default)]
+    pub http_client: HttpClientSettings,
+    /// Configures the database connection pool.
+    #[serde(default)]
+    pub database_pool: DatabasePoolSettings,
+    /// Configures each SQLite connection.
+    #[serde(default)]
+    pub sqlite: SqliteSettings,
+    /// Where state shared with other instances is kept.
+    #[serde(default)]
+    pub shared_state: SharedStateSettings,
+    /// If and where to keep caches and rate limits in Redis, and publish
+    /// events to.
+    pub redis: Option<RedisSettings>,
 }
 
 // We set some defaults below. This seems to be the easiest way of doing it....
@@ -65,3 +863,161 @@ fn default_web_root() -> String {
 fn default_bind() -> String {
     "127.0.0.1:8975".to_string()
 }
+
+fn default_debt_digest_limit() -> usize {
+    5
+}
+
+fn default_registration_open() -> bool {
+    true
+}
+
+fn default_session_lifetime_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_remember_me_lifetime_secs() -> u64 {
+    14 * 24 * 60 * 60
+}
+
+fn default_cookie_secure() -> bool {
+    true
+}
+
+fn default_settled_after_days() -> u32 {
+    30
+}
+
+fn default_transactions_page_size() -> u32 {
+    20
+}
+
+fn default_locale() -> String {
+    "en_GB".to_string()
+}
+
+fn default_date_format() -> String {
+    "%d %b %Y".to_string()
+}
+
+fn default_api_default_limit() -> u32 {
+    20
+}
+
+fn default_api_max_limit() -> u32 {
+    1000
+}
+
+fn default_invite_lifetime_secs() -> u64 {
+    7 * 24 * 60 * 60
+}
+
+fn default_request_timeout_secs() -> u64 {
+    60
+}
+
+fn default_probe_paths() -> Vec<String> {
+    vec!["/health".to_string(), "/metrics".to_string()]
+}
+
+fn default_skip_auth_paths() -> Vec<String> {
+    let mut paths = default_probe_paths();
+    paths.extend(
+        vec![
+            "/static/",
+            "/integrations/slack/command",
+            "/integrations/inbound/",
+        ]
+        .into_iter()
+        .map(String::from),
+    );
+    paths
+}
+
+fn default_http_timeout_secs() -> u64 {
+    30
+}
+
+fn default_circuit_breaker_failures() -> u32 {
+    5
+}
+
+fn default_circuit_breaker_cooldown_secs() -> u64 {
+    30
+}
+
+fn default_db_max_connections() -> u32 {
+    8
+}
+
+fn default_db_connection_timeout_secs() -> u64 {
+    5
+}
+
+fn default_db_max_queued() -> usize {
+    64
+}
+
+fn default_sqlite_journal_mode() -> SqliteJournalMode {
+    SqliteJournalMode::Wal
+}
+
+fn default_sqlite_busy_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_sqlite_synchronous() -> SqliteSynchronous {
+    SqliteSynchronous::Normal
+}
+
+fn default_sqlite_foreign_keys() -> bool {
+    true
+}
+
+fn default_shared_state_poll_interval_secs() -> u64 {
+    2
+}
+
+fn default_outbox_poll_interval_secs() -> u64 {
+    5
+}
+
+fn default_outbox_max_attempts() -> u32 {
+    10
+}
+
+fn default_outbox_retention_days() -> u32 {
+    7
+}
+
+fn default_redis_prefix() -> String {
+    "shaft".to_string()
+}
+
+fn default_currency_symbol() -> String {
+    "£".to_string()
+}
+
+fn default_currency_code() -> CurrencyCode {
+    CurrencyCode::GBP
+}
+
+fn default_currency_minor_units() -> u8 {
+    2
+}
+
+fn default_currency_thousands_separator() -> String {
+    ",".to_string()
+}
+
+fn default_currency_decimal_separator() -> String {
+    ".".to_string()
+}
+
+fn default_reason_required() -> bool {
+    true
+}
+
+fn default_reason_max_length() -> usize {
+    200
+}
@@ -0,0 +1,268 @@
+//! State that must agree between instances of shaft behind a load balancer.
+//!
+//! Login sessions, API tokens and rate limits (e.g. of pokes, which use the
+//! audit log) already live in the database. What each instance keeps in
+//! memory is:
+//!
+//! - the [TokenCache](crate::rest::TokenCache), unless it's kept in Redis.
+//!   Revoking a token on one instance must evict it from the others' caches,
+//!   so an [Invalidation] is published.
+//! - [ConfirmationTokens](crate::rest::ConfirmationTokens), e.g. Slack link
+//!   codes. A code issued by one instance may be used on another, so they're
+//!   kept in the shared state.
+//! - [FeatureFlags](crate::features::FeatureFlags). Toggling a feature is
+//!   published so every instance follows.
+//! - the render cache, which only depends on the templates, and live
+//!   [event](crate::events) subscribers, which only see events published by
+//!   their own instance.
+//!
+//! [InMemorySharedState], the default, only suits a single instance.
+//! [DatabaseSharedState] keeps the state in the database, so instances using
+//! the same database agree, with each polling for the invalidations published
+//! by the others. With the `redis` feature, `RedisSharedState` keeps it in
+//! Redis instead.
+
+use futures::future::{FutureExt, LocalBoxFuture};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use snafu::{ResultExt, Snafu};
+
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::db::{self, Database};
+use crate::features::Feature;
+
+/// How long invalidations are kept in the database, which must be longer than
+/// any instance takes to poll.
+const INVALIDATION_RETENTION_SECS: i64 = 60 * 60;
+
+/// Error reading or updating the shared state.
+#[derive(Debug, Snafu)]
+#[snafu(visibility = "pub(crate)")]
+pub enum SharedStateError {
+    #[snafu(display("{}", source))]
+    Storage { source: db::DatabaseError },
+
+    #[snafu(display("Invalid invalidation: {}", source))]
+    InvalidPayload { source: serde_json::Error },
+
+    /// Failure of a backend outside the database, e.g. Redis.
+    #[snafu(display("{}", source))]
+    Backend {
+        source: Box<dyn StdError + Send + Sync>,
+    },
+}
+
+/// Something an instance has cached that another instance changed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Invalidation {
+    /// One of the user's tokens was revoked, or their details changed.
+    User { user_id: String },
+    /// An admin turned a feature on or off.
+    Feature { feature: Feature, enabled: bool },
+}
+
+/// Where state shared between instances is kept.
+pub trait SharedState: Send + Sync {
+    /// Store a value for `ttl`, replacing any value with the same key.
+    fn put(
+        &self,
+        key: String,
+        value: String,
+        ttl: Duration,
+    ) -> LocalBoxFuture<'static, Result<(), SharedStateError>>;
+
+    /// Remove a value, returning it if it hadn't expired.
+    fn take(
+        &self,
+        key: String,
+    ) -> LocalBoxFuture<'static, Result<Option<String>, SharedStateError>>;
+
+    /// Tell the other instances to drop what they've cached. The publishing
+    /// instance must apply the invalidation itself.
+    fn publish(
+        &self,
+        invalidation: Invalidation,
+    ) -> LocalBoxFuture<'static, Result<(), SharedStateError>>;
+
+    /// Get the invalidations published by other instances since the last
+    /// poll.
+    fn poll(&self) -> LocalBoxFuture<'static, Result<Vec<Invalidation>, SharedStateError>>;
+
+    /// Delete expired values and old invalidations.
+    fn prune(&self) -> LocalBoxFuture<'static, Result<(), SharedStateError>>;
+}
+
+/// Keeps the shared state in memory, for when there's only one instance.
+#[derive(Default)]
+pub struct InMemorySharedState {
+    values: Mutex<HashMap<String, (Instant, String)>>,
+}
+
+impl InMemorySharedState {
+    pub fn new() -> InMemorySharedState {
+        InMemorySharedState::default()
+    }
+}
+
+impl SharedState for InMemorySharedState {
+    fn put(
+        &self,
+        key: String,
+        value: String,
+        ttl: Duration,
+    ) -> LocalBoxFuture<'static, Result<(), SharedStateError>> {
+        let mut values = self.values.lock().expect("shared state lock poisoned");
+        let now = Instant::now();
+        values.retain(|_, (expires, _)| *expires > now);
+        values.insert(key, (now + ttl, value));
+
+        async { Ok(()) }.boxed_local()
+    }
+
+    fn take(
+        &self,
+        key: String,
+    ) -> LocalBoxFuture<'static, Result<Option<String>, SharedStateError>> {
+        let mut values = self.values.lock().expect("shared state lock poisoned");
+        let value = values
+            .remove(&key)
+            .filter(|(expires, _)| *expires > Instant::now())
+            .map(|(_, value)| value);
+
+        async { Ok(value) }.boxed_local()
+    }
+
+    fn publish(&self, _: Invalidation) -> LocalBoxFuture<'static, Result<(), SharedStateError>> {
+        async { Ok(()) }.boxed_local()
+    }
+
+    fn poll(&self) -> LocalBoxFuture<'static, Result<Vec<Invalidation>, SharedStateError>> {
+        async { Ok(Vec::new()) }.boxed_local()
+    }
+
+    fn prune(&self) -> LocalBoxFuture<'static, Result<(), SharedStateError>> {
+        let mut values = self.values.lock().expect("shared state lock poisoned");
+        let now = Instant::now();
+        values.retain(|_, (expires, _)| *expires > now);
+
+        async { Ok(()) }.boxed_local()
+    }
+}
+
+/// Keeps the shared state in the database, for instances sharing it.
+pub struct DatabaseSharedState {
+    database: Arc<dyn Database>,
+    /// Identifies this instance's invalidations, so it skips its own.
+    instance_id: String,
+    /// The ID of the last invalidation seen, or None before the first poll.
+    last_seen: Arc<Mutex<Option<i64>>>,
+}
+
+impl DatabaseSharedState {
+    pub fn new(database: Arc<dyn Database>) -> DatabaseSharedState {
+        DatabaseSharedState {
+            database,
+            instance_id: thread_rng().sample_iter(&Alphanumeric).take(16).collect(),
+            last_seen: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// The ID this instance publishes invalidations under.
+    pub fn instance_id(&self) -> &str {
+        &self.instance_id
+    }
+}
+
+impl SharedState for DatabaseSharedState {
+    fn put(
+        &self,
+        key: String,
+        value: String,
+        ttl: Duration,
+    ) -> LocalBoxFuture<'static, Result<(), SharedStateError>> {
+        let expires = chrono::Utc::now()
+            + chrono::Duration::from_std(ttl).unwrap_or_else(|_| chrono::Duration::max_value());
+
+        self.database
+            .put_shared_value(key, value, expires)
+            .map(|res| res.context(Storage))
+            .boxed_local()
+    }
+
+    fn take(
+        &self,
+        key: String,
+    ) -> LocalBoxFuture<'static, Result<Option<String>, SharedStateError>> {
+        self.database
+            .take_shared_value(key)
+            .map(|res| res.context(Storage))
+            .boxed_local()
+    }
+
+    fn publish(
+        &self,
+        invalidation: Invalidation,
+    ) -> LocalBoxFuture<'static, Result<(), SharedStateError>> {
+        let database = self.database.clone();
+        let instance_id = self.instance_id.clone();
+
+        async move {
+            let payload = serde_json::to_string(&invalidation).context(InvalidPayload)?;
+            database
+                .add_invalidation(instance_id, payload)
+                .await
+                .context(Storage)?;
+
+            Ok(())
+        }
+        .boxed_local()
+    }
+
+    fn poll(&self) -> LocalBoxFuture<'static, Result<Vec<Invalidation>, SharedStateError>> {
+        let database = self.database.clone();
+        let instance_id = self.instance_id.clone();
+        let last_seen = self.last_seen.clone();
+
+        async move {
+            let after = *last_seen.lock().expect("shared state lock poisoned");
+
+            // Invalidations from before we started are for caches we don't
+            // have.
+            let after = match after {
+                Some(after) => after,
+                None => {
+                    let latest = database.get_last_invalidation_id().await.context(Storage)?;
+                    *last_seen.lock().expect("shared state lock poisoned") = Some(latest);
+                    return Ok(Vec::new());
+                }
+            };
+
+            let rows = database.get_invalidations(after).await.context(Storage)?;
+            if let Some(row) = rows.last() {
+                *last_seen.lock().expect("shared state lock poisoned") = Some(row.id);
+            }
+
+            rows.into_iter()
+                .filter(|row| row.instance_id != instance_id)
+                .map(|row| serde_json::from_str(&row.payload).context(InvalidPayload))
+                .collect()
+        }
+        .boxed_local()
+    }
+
+    fn prune(&self) -> LocalBoxFuture<'static, Result<(), SharedStateError>> {
+        let published_before =
+            chrono::Utc::now() - chrono::Duration::seconds(INVALIDATION_RETENTION_SECS);
+
+        self.database
+            .prune_shared_state(published_before)
+            .map(|res| res.map(|_| ()).context(Storage))
+            .boxed_local()
+    }
+}
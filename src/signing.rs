@@ -0,0 +1,26 @@
+//! HMAC signatures, as used for webhooks, Slack requests and invitation
+//! links.
+
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+
+use std::fmt::Write;
+
+/// The hex encoded HMAC-SHA256 of the parts joined together, keyed with the
+/// secret.
+pub fn hmac_sha256_hex(secret: &str, parts: &[&[u8]]) -> Result<String, ErrorStack> {
+    let key = PKey::hmac(secret.as_bytes())?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    for part in parts {
+        signer.update(part)?;
+    }
+
+    let mut hex = String::new();
+    for byte in signer.sign_to_vec()? {
+        write!(hex, "{:02x}", byte).expect("writing to a string");
+    }
+
+    Ok(hex)
+}
@@ -0,0 +1,37 @@
+//! What exactly is deployed, reported at `/api/version` and logged at
+//! startup.
+
+use serde::Serialize;
+
+use crate::db::{Database, DatabaseError};
+
+/// The version of the crate.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The git commit built, embedded by the build script. `unknown` if it
+/// couldn't be worked out.
+pub const GIT_COMMIT: &str = env!("SHAFT_GIT_COMMIT");
+
+/// The version of the running code and the database it's using.
+#[derive(Debug, Clone, Serialize)]
+pub struct VersionInfo {
+    pub version: &'static str,
+    pub git_commit: &'static str,
+    /// Which database implementation is in use, e.g. `rusqlite`.
+    pub database_backend: &'static str,
+    /// How many migrations have been applied to the database.
+    pub schema_version: i64,
+}
+
+impl VersionInfo {
+    /// Gather the version details, asking the database for its schema
+    /// version.
+    pub async fn collect(database: &dyn Database) -> Result<VersionInfo, DatabaseError> {
+        Ok(VersionInfo {
+            version: VERSION,
+            git_commit: GIT_COMMIT,
+            database_backend: database.backend_name(),
+            schema_version: database.get_schema_version().await?,
+        })
+    }
+}
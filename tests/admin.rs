@@ -0,0 +1,311 @@
+use handlebars::Handlebars;
+use serde_json::json;
+
+use std::sync::Arc;
+
+use shaft::db::{DatabaseError, Outbox, Scope, SqliteDatabase};
+use shaft::http_client::MockGenericHttpClient;
+use shaft::money::MoneyHelper;
+use shaft::rest::{register_helpers, AppState};
+use shaft::settings::{CurrencySettings, UiSettings};
+
+mod common;
+
+use common::{login_user, login_user_with_scopes, setup_app, start_app, test_config};
+
+/// Test that deactivated users are logged out and can't log in again, but
+/// keep their balance.
+#[actix_rt::test]
+async fn test_deactivate_user() {
+    let (srv, app_state) = setup_app(None);
+    let admin_cookie = login_user_with_scopes(
+        &app_state,
+        "admin",
+        vec![Scope::Read, Scope::Write, Scope::Admin],
+    )
+    .await;
+    let alice = login_user(&app_state, "alice").await;
+    let bob = login_user(&app_state, "bob").await;
+
+    let response = srv
+        .post("/api/shaft")
+        .cookie(alice.clone())
+        .send_json(&json!({ "other_user": "bob", "amount": 150, "reason": "Coffee" }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let response = srv
+        .post("/api/admin/users/bob/deactivate")
+        .cookie(alice.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 403);
+
+    let response = srv
+        .post("/api/admin/users/admin/deactivate")
+        .cookie(admin_cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+
+    let response = srv
+        .post("/api/admin/users/bob/deactivate")
+        .cookie(admin_cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let response = srv.get("/api/me").cookie(bob).send().await.unwrap();
+    assert_eq!(response.status(), 302);
+
+    let err = app_state
+        .database
+        .create_token_for_user(
+            "bob".to_owned(),
+            vec![Scope::Read],
+            chrono::Utc::now() + chrono::Duration::days(1),
+            Outbox::none(),
+        )
+        .await
+        .unwrap_err();
+    assert!(matches!(err, DatabaseError::UserDeactivated { .. }));
+
+    let mut response = srv.get("/api/balances").cookie(alice).send().await.unwrap();
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["bob"]["balance"], -150);
+
+    let response = srv
+        .post("/api/admin/users/nobody/deactivate")
+        .cookie(admin_cookie)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+}
+
+/// Test that merging a user moves their transactions to the other user.
+#[actix_rt::test]
+async fn test_merge_users() {
+    let (srv, app_state) = setup_app(None);
+    let admin_cookie = login_user_with_scopes(
+        &app_state,
+        "admin",
+        vec![Scope::Read, Scope::Write, Scope::Admin],
+    )
+    .await;
+    let alice = login_user(&app_state, "alice").await;
+    login_user(&app_state, "bob").await;
+    let bob2 = login_user(&app_state, "bob2").await;
+
+    for (cookie, other_user, amount) in &[(&alice, "bob", 150), (&bob2, "alice", 100)] {
+        let response = srv
+            .post("/api/shaft")
+            .cookie((*cookie).clone())
+            .send_json(&json!({ "other_user": other_user, "amount": amount, "reason": "Lunch" }))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+    }
+
+    let response = srv
+        .post("/api/admin/users/bob2/merge")
+        .cookie(admin_cookie.clone())
+        .send_json(&json!({ "into": "bob2" }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+
+    let response = srv
+        .post("/api/admin/users/bob2/merge")
+        .cookie(admin_cookie.clone())
+        .send_json(&json!({ "into": "bob" }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let response = srv.get("/api/me").cookie(bob2).send().await.unwrap();
+    assert_eq!(response.status(), 302);
+
+    let mut response = srv.get("/api/balances").cookie(alice).send().await.unwrap();
+    let body: serde_json::Value = response.json().await.unwrap();
+    let users = body.as_object().unwrap();
+    assert!(!users.contains_key("bob2"));
+    assert_eq!(users["bob"]["balance"], -50);
+    assert_eq!(users["alice"]["balance"], 50);
+
+    let mut response = srv
+        .get("/api/admin/audit?action=user.merge")
+        .cookie(admin_cookie)
+        .send()
+        .await
+        .unwrap();
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["entries"][0]["target"], "bob2");
+}
+
+/// Test that pruning only deletes expired sessions.
+#[actix_rt::test]
+async fn test_prune_tokens() {
+    let (srv, app_state) = setup_app(None);
+    let admin_cookie = login_user_with_scopes(
+        &app_state,
+        "admin",
+        vec![Scope::Read, Scope::Write, Scope::Admin],
+    )
+    .await;
+    login_user(&app_state, "alice").await;
+
+    app_state
+        .database
+        .create_token_for_user(
+            "alice".to_owned(),
+            vec![Scope::Read],
+            chrono::Utc::now() - chrono::Duration::days(1),
+            Outbox::none(),
+        )
+        .await
+        .unwrap();
+
+    let mut response = srv
+        .post("/api/admin/tokens/prune")
+        .cookie(admin_cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["deleted"], 1);
+
+    let response = srv
+        .get("/api/me")
+        .cookie(admin_cookie)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+}
+
+/// Test that the dashboard is only for admins, and lists users.
+#[actix_rt::test]
+async fn test_dashboard() {
+    let mut hb = Handlebars::new();
+    hb.register_template_file("admin", "res/admin.hbs").unwrap();
+    hb.register_template_file("base", "res/base.hbs").unwrap();
+    hb.register_helper(
+        "pence-as-pounds",
+        Box::new(MoneyHelper::new(CurrencySettings::default())),
+    );
+    register_helpers(&mut hb, &UiSettings::default()).unwrap();
+
+    let database = SqliteDatabase::with_path(":memory:");
+    database.migrate().unwrap();
+    let app_state = AppState::new(
+        test_config(),
+        hb,
+        Arc::new(database),
+        Arc::new(MockGenericHttpClient::new()),
+    );
+    let (srv, app_state) = start_app(app_state);
+
+    let admin_cookie = login_user_with_scopes(
+        &app_state,
+        "admin",
+        vec![Scope::Read, Scope::Write, Scope::Admin],
+    )
+    .await;
+    let alice = login_user(&app_state, "alice").await;
+    login_user(&app_state, "bob").await;
+
+    let response = srv.get("/admin").cookie(alice).send().await.unwrap();
+    assert_eq!(response.status(), 403);
+
+    let response = srv
+        .post("/api/admin/users/bob/deactivate")
+        .cookie(admin_cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let mut response = srv.get("/admin").cookie(admin_cookie).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    let body = response.body().await.unwrap();
+    let body = std::str::from_utf8(&body).unwrap();
+    assert!(body.contains(r#"data-user-id="alice""#), "{}", body);
+    assert!(!body.contains(r#"data-user-id="bob""#), "{}", body);
+    assert!(!body.contains(r#"data-user-id="admin""#), "{}", body);
+    assert!(body.contains("Deactivated"), "{}", body);
+    assert!(body.contains("user.deactivate bob"), "{}", body);
+}
+
+/// Test that invalid tokens and refused admin requests are recorded for
+/// admins to review.
+#[actix_rt::test]
+async fn test_security_events() {
+    let (srv, app_state) = setup_app(None);
+    let admin_cookie = login_user_with_scopes(
+        &app_state,
+        "admin",
+        vec![Scope::Read, Scope::Write, Scope::Admin],
+    )
+    .await;
+    let alice = login_user(&app_state, "alice").await;
+
+    let response = srv
+        .get("/api/me")
+        .cookie(awc::cookie::Cookie::new("token", "bogus"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 302);
+
+    let response = srv
+        .get("/api/admin/audit")
+        .cookie(alice.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 403);
+
+    let response = srv
+        .get("/api/admin/security_events")
+        .cookie(alice)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 403);
+
+    let mut response = srv
+        .get("/api/admin/security_events?limit=2")
+        .cookie(admin_cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    let events = body["events"].as_array().unwrap();
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0]["kind"], "admin.denied");
+    assert_eq!(events[0]["user_id"], "alice");
+    assert_eq!(events[0]["details"], "GET /api/admin/security_events");
+    assert_eq!(events[1]["details"], "GET /api/admin/audit");
+
+    let mut response = srv
+        .get(format!(
+            "/api/admin/security_events?before={}",
+            body["next_before"]
+        ))
+        .cookie(admin_cookie)
+        .send()
+        .await
+        .unwrap();
+    let body: serde_json::Value = response.json().await.unwrap();
+    let events = body["events"].as_array().unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0]["kind"], "token.invalid");
+    assert_eq!(events[0]["user_id"], serde_json::Value::Null);
+}
@@ -0,0 +1,160 @@
+use shaft::amount::{parse_amount, AmountError, AmountInput};
+use shaft::money::{Currency, Money};
+use shaft::settings::CurrencySettings;
+
+fn gbp() -> CurrencySettings {
+    CurrencySettings::default()
+}
+
+#[test]
+fn test_parse_amount() {
+    let cases = [
+        ("0", 0),
+        ("3", 300),
+        ("12.50", 1250),
+        ("12.5", 1250),
+        ("0.01", 1),
+        (".5", 50),
+        ("£3", 300),
+        ("£12.50", 1250),
+        ("1,200.00", 120_000),
+        ("£1,234,567.89", 123_456_789),
+        ("999,999", 99_999_900),
+        ("-4.20", -420),
+        ("+4.20", 420),
+        ("-£4.20", -420),
+        ("£-4.20", -420),
+        ("  12.50\n", 1250),
+    ];
+
+    for (input, expected) in &cases {
+        assert_eq!(
+            parse_amount(input, &gbp()),
+            Ok(*expected),
+            "parsing {:?}",
+            input
+        );
+    }
+}
+
+#[test]
+fn test_parse_amount_invalid() {
+    let cases = [
+        "twelve", "£", "-", ".", "12.", "12.345", "1.2.3", "12,50", "1,20.00", ",100", "100,",
+        "1,2345", "1,,000", "--3", "-£-3", "££3", "3£", "1 200", "- 3", "1e3", "0x10", "12.5a",
+        "١٢",
+    ];
+
+    for input in &cases {
+        assert_eq!(
+            parse_amount(input, &gbp()),
+            Err(AmountError::Invalid {
+                input: input.to_string()
+            }),
+            "parsing {:?}",
+            input
+        );
+    }
+}
+
+#[test]
+fn test_parse_amount_empty() {
+    assert_eq!(parse_amount("", &gbp()), Err(AmountError::Empty));
+    assert_eq!(parse_amount("   ", &gbp()), Err(AmountError::Empty));
+}
+
+#[test]
+fn test_parse_amount_too_large() {
+    assert_eq!(parse_amount("92233720368547758.07", &gbp()), Ok(i64::MAX));
+
+    for input in &[
+        "92233720368547758.08",
+        "100000000000000000",
+        "£99999999999999999999",
+    ] {
+        assert_eq!(
+            parse_amount(input, &gbp()),
+            Err(AmountError::TooLarge {
+                input: input.to_string()
+            }),
+            "parsing {:?}",
+            input
+        );
+    }
+}
+
+#[test]
+fn test_parse_amount_other_currencies() {
+    let eur = CurrencySettings {
+        symbol: "€".to_string(),
+        code: "EUR".parse().unwrap(),
+        minor_units: 2,
+        thousands_separator: ".".to_string(),
+        decimal_separator: ",".to_string(),
+    };
+    assert_eq!(parse_amount("€1.234,56", &eur), Ok(123_456));
+    assert_eq!(parse_amount("-3,5", &eur), Ok(-350));
+    assert_eq!(parse_amount("1234", &eur), Ok(123_400));
+    assert!(parse_amount("12.50", &eur).is_err());
+    assert!(parse_amount("£3", &eur).is_err());
+
+    let jpy = CurrencySettings {
+        symbol: "¥".to_string(),
+        code: "JPY".parse().unwrap(),
+        minor_units: 0,
+        thousands_separator: ",".to_string(),
+        decimal_separator: ".".to_string(),
+    };
+    assert_eq!(parse_amount("¥1,200", &jpy), Ok(1200));
+    assert!(parse_amount("1200.5", &jpy).is_err());
+
+    let chf = CurrencySettings {
+        symbol: "CHF ".to_string(),
+        code: "CHF".parse().unwrap(),
+        minor_units: 2,
+        thousands_separator: "'".to_string(),
+        decimal_separator: ".".to_string(),
+    };
+    assert_eq!(parse_amount("CHF 1'000.05", &chf), Ok(100_005));
+
+    let no_separator = CurrencySettings {
+        thousands_separator: String::new(),
+        ..gbp()
+    };
+    assert_eq!(parse_amount("1200.00", &no_separator), Ok(120_000));
+    assert!(parse_amount("1,200.00", &no_separator).is_err());
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Body {
+    amount: AmountInput,
+}
+
+#[test]
+fn test_amount_input() {
+    // Numbers are minor units, strings are parsed.
+    let body: Body = serde_json::from_str(r#"{"amount": 1250}"#).unwrap();
+    assert_eq!(body.amount, AmountInput::Minor(1250));
+    assert_eq!(
+        body.amount.resolve(&gbp()),
+        Ok(Money::new(1250, Currency::GBP))
+    );
+
+    let body: Body = serde_json::from_str(r#"{"amount": -5}"#).unwrap();
+    assert_eq!(
+        body.amount.resolve(&gbp()),
+        Ok(Money::new(-5, Currency::GBP))
+    );
+
+    let body: Body = serde_json::from_str(r#"{"amount": "£1,200.00"}"#).unwrap();
+    assert_eq!(
+        body.amount.resolve(&gbp()),
+        Ok(Money::new(120_000, Currency::GBP))
+    );
+
+    let body: Body = serde_json::from_str(r#"{"amount": "twelve"}"#).unwrap();
+    assert!(body.amount.resolve(&gbp()).is_err());
+
+    assert!(serde_json::from_str::<Body>(r#"{"amount": 12.5}"#).is_err());
+    assert!(serde_json::from_str::<Body>(r#"{"amount": 18446744073709551615}"#).is_err());
+}
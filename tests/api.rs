@@ -0,0 +1,2475 @@
+use futures::future::{self, BoxFuture, FutureExt, TryFutureExt};
+use handlebars::Handlebars;
+use hyper::{Body, Request, Response};
+use serde_json::json;
+use shaft::db::{
+    AuditEntry, AuditFilter, Outbox, Scope, SecurityEvent, SqliteDatabase, Transaction,
+    TransactionKind, DEFAULT_LEDGER_ID,
+};
+use shaft::features::Feature;
+use shaft::http_client::{HttpError, MockGenericHttpClient};
+use shaft::money::{Currency, Money, MoneyHelper};
+use shaft::receipts::HttpReceiptProcessor;
+use shaft::rest::{register_helpers, AppState};
+use shaft::settings::{
+    CurrencySettings, DatabasePoolSettings, ReasonSettings, SqliteSettings, UiSettings,
+};
+
+use std::sync::Arc;
+use std::time::Duration;
+
+mod common;
+
+use common::{
+    login_user, login_user_with_scopes, setup_app, setup_app_with_config, setup_app_with_database,
+    start_app, test_config,
+};
+
+/// Test that the balances API returns a 304 until the ledger changes.
+#[actix_rt::test]
+async fn test_balances_etag() {
+    let (srv, app_state) = setup_app(None);
+    let cookie = login_user(&app_state, "alice").await;
+    login_user(&app_state, "bob").await;
+
+    let response = srv
+        .get("/api/balances")
+        .cookie(cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let etag = response.headers().get("etag").expect("etag header").clone();
+    assert!(etag.to_str().unwrap().starts_with("W/"));
+
+    // The same ETag should get us a 304.
+    let response = srv
+        .get("/api/balances")
+        .cookie(cookie.clone())
+        .insert_header(("If-None-Match", etag.clone()))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 304);
+
+    // Changing the ledger should change the ETag.
+    let response = srv
+        .post("/api/shaft")
+        .cookie(cookie.clone())
+        .send_json(&json!({
+            "other_user": "bob",
+            "amount": 150,
+            "reason": "Coffee",
+        }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let response = srv
+        .get("/api/balances")
+        .cookie(cookie.clone())
+        .insert_header(("If-None-Match", etag.clone()))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert!(response.headers().get("etag").is_some());
+    assert_ne!(response.headers().get("etag"), Some(&etag));
+}
+
+/// Test that the home and transactions pages return a 304 until something on
+/// them changes.
+#[actix_rt::test]
+async fn test_pages_last_modified() {
+    let mut hb = Handlebars::new();
+    hb.register_template_file("index", "res/index.hbs").unwrap();
+    hb.register_template_file("transactions", "res/transactions.hbs")
+        .unwrap();
+    hb.register_template_file("base", "res/base.hbs").unwrap();
+    hb.register_helper(
+        "pence-as-pounds",
+        Box::new(MoneyHelper::new(CurrencySettings::default())),
+    );
+    register_helpers(&mut hb, &UiSettings::default()).unwrap();
+
+    let database = SqliteDatabase::with_path(":memory:");
+    database.migrate().unwrap();
+    let app_state = AppState::new(
+        test_config(),
+        hb,
+        Arc::new(database),
+        Arc::new(MockGenericHttpClient::new()),
+    );
+    let (srv, app_state) = start_app(app_state);
+    let cookie = login_user(&app_state, "alice").await;
+    login_user(&app_state, "bob").await;
+
+    // Changes made in the current second aren't used, as another could follow
+    // within the same second.
+    actix_rt::time::sleep(Duration::from_millis(1100)).await;
+
+    for path in &["/home", "/transactions"] {
+        let response = srv.get(*path).cookie(cookie.clone()).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+
+        let last_modified = response
+            .headers()
+            .get("last-modified")
+            .expect("last-modified header")
+            .clone();
+
+        let response = srv
+            .get(*path)
+            .cookie(cookie.clone())
+            .insert_header(("If-Modified-Since", last_modified.clone()))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 304, "{}", path);
+
+        // Logged out users never get a 304.
+        let response = srv
+            .get(*path)
+            .insert_header(("If-Modified-Since", last_modified.clone()))
+            .send()
+            .await
+            .unwrap();
+        assert_ne!(response.status(), 304, "{}", path);
+    }
+
+    let response = srv
+        .get("/home")
+        .cookie(cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    let last_modified = response.headers().get("last-modified").unwrap().clone();
+
+    let response = srv
+        .post("/api/shaft")
+        .cookie(cookie.clone())
+        .send_json(&json!({
+            "other_user": "bob",
+            "amount": 150,
+            "reason": "Coffee",
+        }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    for path in &["/home", "/transactions"] {
+        let response = srv
+            .get(*path)
+            .cookie(cookie.clone())
+            .insert_header(("If-Modified-Since", last_modified.clone()))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200, "{}", path);
+    }
+}
+
+/// Test that the v1 balances API lists users in order, most in debt first.
+#[actix_rt::test]
+async fn test_balances_v1() {
+    let (srv, app_state) = setup_app(None);
+    let cookie = login_user(&app_state, "alice").await;
+    login_user(&app_state, "bob").await;
+    login_user(&app_state, "carol").await;
+
+    let response = srv
+        .post("/api/shaft")
+        .cookie(cookie.clone())
+        .send_json(&json!({ "other_user": "carol", "amount": 150, "reason": "Coffee" }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let mut response = srv
+        .get("/api/v1/balances")
+        .cookie(cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let etag = response.headers().get("etag").expect("etag header").clone();
+
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(
+        body,
+        json!({ "users": [
+            { "user_id": "carol", "display_name": "carol", "balance": -150 },
+            { "user_id": "bob", "display_name": "bob", "balance": 0 },
+            { "user_id": "alice", "display_name": "alice", "balance": 150 },
+        ]})
+    );
+
+    let response = srv
+        .get("/api/v1/balances")
+        .cookie(cookie)
+        .insert_header(("If-None-Match", etag))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 304);
+}
+
+/// Test that syncing returns changes after the cursor.
+#[actix_rt::test]
+async fn test_sync() {
+    let (srv, app_state) = setup_app(None);
+    let cookie = login_user(&app_state, "alice").await;
+    login_user(&app_state, "bob").await;
+
+    let response = srv
+        .post("/api/shaft")
+        .cookie(cookie.clone())
+        .send_json(&json!({
+            "other_user": "bob",
+            "amount": 150,
+            "reason": "Coffee",
+        }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let mut response = srv
+        .get("/api/sync")
+        .cookie(cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["users"].as_array().unwrap().len(), 2);
+    assert_eq!(body["transactions"].as_array().unwrap().len(), 1);
+    assert_eq!(body["transactions"][0]["reason"], "Coffee");
+
+    // Nothing should have changed since the returned cursor.
+    let cursor = body["next_cursor"].as_i64().unwrap();
+
+    let mut response = srv
+        .get(format!("/api/sync?since={}", cursor))
+        .cookie(cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["next_cursor"], cursor);
+    assert!(body["users"].as_array().unwrap().is_empty());
+    assert!(body["transactions"].as_array().unwrap().is_empty());
+}
+
+/// Test that edits to transactions require the current revision.
+#[actix_rt::test]
+async fn test_edit_transaction_if_match() {
+    let (srv, app_state) = setup_app(None);
+    let cookie = login_user(&app_state, "alice").await;
+    login_user(&app_state, "bob").await;
+    let carol = login_user(&app_state, "carol").await;
+
+    let mut response = srv
+        .post("/api/shaft")
+        .cookie(cookie.clone())
+        .send_json(&json!({
+            "other_user": "bob",
+            "amount": 150,
+            "reason": "Coffee",
+        }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let body: serde_json::Value = response.json().await.unwrap();
+    let path = format!("/api/transactions/{}", body["transaction_id"]);
+
+    let response = srv.get(&path).cookie(cookie.clone()).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    let etag = response.headers().get("etag").expect("etag").clone();
+
+    // Only the parties to the transaction can see it.
+    let response = srv.get(&path).cookie(carol).send().await.unwrap();
+    assert_eq!(response.status(), 404);
+
+    let edit = json!({ "amount": 200, "reason": "Large coffee" });
+
+    // Edits without a revision are refused.
+    let response = srv
+        .put(&path)
+        .cookie(cookie.clone())
+        .send_json(&edit)
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 428);
+
+    let response = srv
+        .put(&path)
+        .cookie(cookie.clone())
+        .insert_header(("If-Match", etag.clone()))
+        .send_json(&edit)
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    // The old revision is now stale.
+    let response = srv
+        .put(&path)
+        .cookie(cookie.clone())
+        .insert_header(("If-Match", etag.clone()))
+        .send_json(&edit)
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 412);
+
+    let response = srv
+        .delete(&path)
+        .cookie(cookie.clone())
+        .insert_header(("If-Match", etag.clone()))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 412);
+}
+
+/// Test that a bulk shaft with a bad entry creates nothing.
+#[actix_rt::test]
+async fn test_bulk_shaft_atomic() {
+    let (srv, app_state) = setup_app(None);
+    let cookie = login_user(&app_state, "alice").await;
+    login_user(&app_state, "bob").await;
+
+    let mut response = srv
+        .post("/api/shaft/bulk")
+        .cookie(cookie.clone())
+        .send_json(&json!([
+            { "other_user": "bob", "amount": 150, "reason": "Coffee" },
+            { "other_user": "mallory", "amount": 200, "reason": "Tea" },
+        ]))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["committed"], false);
+    assert!(body["results"][0].get("error").is_none());
+    assert!(body["results"][1].get("error").is_some());
+
+    let transactions = app_state
+        .database
+        .get_all_transactions(DEFAULT_LEDGER_ID)
+        .await
+        .unwrap();
+    assert!(transactions.is_empty());
+
+    let mut response = srv
+        .post("/api/shaft/bulk")
+        .cookie(cookie.clone())
+        .send_json(&json!([
+            { "other_user": "bob", "amount": 150, "reason": "Coffee" },
+            { "other_user": "bob", "amount": 200, "reason": "Tea" },
+        ]))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["committed"], true);
+    assert_eq!(body["results"].as_array().unwrap().len(), 2);
+}
+
+/// Test that amounts can be given in pounds, both to the API and the web form.
+#[actix_rt::test]
+async fn test_shaft_amount_in_pounds() {
+    let (srv, app_state) = setup_app(None);
+    let cookie = login_user(&app_state, "alice").await;
+    login_user(&app_state, "bob").await;
+
+    let response = srv
+        .post("/api/shaft")
+        .cookie(cookie.clone())
+        .send_json(&json!({
+            "other_user": "bob",
+            "amount": "£1,200.50",
+            "reason": "Rent",
+        }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let response = srv
+        .post("/shaft")
+        .cookie(cookie.clone())
+        .send_form(&[
+            ("other_user", "bob"),
+            ("amount", "12.5"),
+            ("reason", "Pizza"),
+        ])
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 302);
+
+    let amounts: Vec<_> = app_state
+        .database
+        .get_all_transactions(DEFAULT_LEDGER_ID)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|transaction| transaction.amount.minor_units())
+        .collect();
+    assert_eq!(amounts.len(), 2);
+    assert!(amounts.contains(&120_050));
+    assert!(amounts.contains(&1250));
+
+    let response = srv
+        .post("/api/shaft")
+        .cookie(cookie)
+        .send_json(&json!({
+            "other_user": "bob",
+            "amount": "12.345",
+            "reason": "Coffee",
+        }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+}
+
+/// Test that the version endpoint reports the build and database.
+#[actix_rt::test]
+async fn test_version() {
+    let (srv, _) = setup_app(None);
+
+    let mut response = srv.get("/api/version").send().await.unwrap();
+    assert_eq!(response.status(), 200);
+
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+    assert!(!body["git_commit"].as_str().unwrap().is_empty());
+    assert_eq!(body["database_backend"], "rusqlite");
+    assert!(body["schema_version"].as_i64().unwrap() > 0);
+}
+
+/// Test that the transactions API uses the configured default limit, and
+/// never returns more than the configured maximum.
+#[actix_rt::test]
+async fn test_transactions_limit() {
+    let mut config = test_config();
+    config.api.default_limit = 2;
+    config.api.max_limit = 3;
+    let (srv, app_state) = setup_app_with_config(config, None);
+    let cookie = login_user(&app_state, "alice").await;
+    login_user(&app_state, "bob").await;
+
+    for amount in 1..=4 {
+        let response = srv
+            .post("/api/shaft")
+            .cookie(cookie.clone())
+            .send_json(&json!({ "other_user": "bob", "amount": amount, "reason": "Coffee" }))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+    }
+
+    for (path, expected) in &[
+        ("/api/transactions", 2),
+        ("/api/transactions?limit=1", 1),
+        ("/api/transactions?limit=10", 3),
+    ] {
+        let mut response = srv.get(*path).cookie(cookie.clone()).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body.as_array().unwrap().len(), *expected, "{}", path);
+    }
+}
+
+/// Test that users can be listed, filtered and sorted.
+#[actix_rt::test]
+async fn test_list_users() {
+    let mut config = test_config();
+    config.admins = vec!["bob".to_owned()];
+    let (srv, app_state) = setup_app_with_config(config, None);
+    let cookie = login_user(&app_state, "alice").await;
+    login_user(&app_state, "bob").await;
+    login_user(&app_state, "carol").await;
+
+    let response = srv
+        .post("/api/shaft")
+        .cookie(cookie.clone())
+        .send_json(&json!({ "other_user": "bob", "amount": 150, "reason": "Coffee" }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    app_state
+        .database
+        .deactivate_user("carol".to_owned(), Outbox::none())
+        .await
+        .unwrap();
+
+    let mut response = srv
+        .get("/api/users")
+        .cookie(cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    let users = body["users"].as_array().unwrap();
+    let user_ids: Vec<_> = users.iter().map(|user| &user["user_id"]).collect();
+    assert_eq!(user_ids, vec!["bob", "carol", "alice"]);
+    assert_eq!(users[0]["balance"], -150);
+    assert_eq!(users[0]["admin"], true);
+    assert_eq!(users[1]["active"], false);
+
+    let mut response = srv
+        .get("/api/users?sort=name&active=true")
+        .cookie(cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    let body: serde_json::Value = response.json().await.unwrap();
+    let user_ids: Vec<_> = body["users"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|user| &user["user_id"])
+        .collect();
+    assert_eq!(user_ids, vec!["alice", "bob"]);
+
+    let mut response = srv
+        .get("/api/users?q=CA")
+        .cookie(cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["users"].as_array().unwrap().len(), 1);
+
+    let mut response = srv
+        .get("/api/users?limit=2")
+        .cookie(cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["users"].as_array().unwrap().len(), 2);
+    assert_eq!(body["next_offset"], 2);
+
+    let mut response = srv
+        .get("/api/users?limit=2&offset=2")
+        .cookie(cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["users"][0]["user_id"], "alice");
+    assert_eq!(body["next_offset"], serde_json::Value::Null);
+
+    let mut response = srv
+        .get("/api/users?nonzero=true")
+        .cookie(cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    let body: serde_json::Value = response.json().await.unwrap();
+    let user_ids: Vec<_> = body["users"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|user| &user["user_id"])
+        .collect();
+    assert_eq!(user_ids, vec!["bob", "alice"]);
+
+    let mut response = srv
+        .get("/api/v1/balances?nonzero=true&limit=1")
+        .cookie(cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    let body: serde_json::Value = response.json().await.unwrap();
+    let users = body["users"].as_array().unwrap();
+    assert_eq!(users.len(), 1);
+    assert_eq!(users[0]["user_id"], "bob");
+
+    let response = srv
+        .get("/api/users?sort=age")
+        .cookie(cookie)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+}
+
+/// Test that settled users are left off the home page's balances if
+/// configured, but can still be shown and picked in the form.
+#[actix_rt::test]
+async fn test_home_hides_settled_users() {
+    let mut hb = Handlebars::new();
+    hb.register_template_file("index", "res/index.hbs").unwrap();
+    hb.register_template_file("base", "res/base.hbs").unwrap();
+    hb.register_helper(
+        "pence-as-pounds",
+        Box::new(MoneyHelper::new(CurrencySettings::default())),
+    );
+
+    let mut config = test_config();
+    config.balances.hide_settled = true;
+    let database = SqliteDatabase::with_path(":memory:");
+    database.migrate().unwrap();
+    let app_state = AppState::new(
+        config,
+        hb,
+        Arc::new(database),
+        Arc::new(MockGenericHttpClient::new()),
+    );
+    let (srv, app_state) = start_app(app_state);
+
+    let cookie = login_user(&app_state, "alice").await;
+    for user_id in &["bob", "carol", "dave"] {
+        login_user(&app_state, user_id).await;
+    }
+
+    // Alice and Bob owe each other, Carol and Dave settled up long ago.
+    let long_ago = chrono::Utc::now() - chrono::Duration::days(100);
+    for (shafter, shaftee, datetime) in &[
+        ("alice", "bob", chrono::Utc::now()),
+        ("carol", "dave", long_ago),
+        ("dave", "carol", long_ago),
+    ] {
+        app_state
+            .database
+            .shaft_user(
+                DEFAULT_LEDGER_ID,
+                Transaction {
+                    id: None,
+                    shafter: shafter.to_string(),
+                    shaftee: shaftee.to_string(),
+                    amount: Money::new(100, Currency::GBP),
+                    datetime: *datetime,
+                    reason: "Lunch".to_owned(),
+                    kind: TransactionKind::Shaft,
+                },
+                Outbox::none(),
+            )
+            .await
+            .unwrap();
+    }
+
+    let mut response = srv
+        .get("/home")
+        .cookie(cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body = response.body().await.unwrap();
+    let body = std::str::from_utf8(&body).unwrap();
+    assert!(body.contains(r#"data-user-id="alice""#), "{}", body);
+    assert!(body.contains(r#"data-user-id="bob""#), "{}", body);
+    assert!(!body.contains(r#"data-user-id="carol""#), "{}", body);
+    assert!(body.contains(r#"<option value="carol">"#), "{}", body);
+    assert!(body.contains("Show all (2 settled hidden)"), "{}", body);
+    assert!(body.contains("<li>bob: £1.00</li>"), "{}", body);
+
+    let mut response = srv
+        .get("/home?all=true")
+        .cookie(cookie)
+        .send()
+        .await
+        .unwrap();
+    let body = response.body().await.unwrap();
+    let body = std::str::from_utf8(&body).unwrap();
+    assert!(body.contains(r#"data-user-id="carol""#), "{}", body);
+    assert!(body.contains("Hide settled users"), "{}", body);
+}
+
+/// Test that a rejected form submission is shown again with what went wrong,
+/// rather than as an error page.
+#[actix_rt::test]
+async fn test_shaft_form_error() {
+    let mut hb = Handlebars::new();
+    hb.register_template_file("index", "res/index.hbs").unwrap();
+    hb.register_template_file("base", "res/base.hbs").unwrap();
+    hb.register_helper(
+        "pence-as-pounds",
+        Box::new(MoneyHelper::new(CurrencySettings::default())),
+    );
+
+    let database = SqliteDatabase::with_path(":memory:");
+    database.migrate().unwrap();
+    let app_state = AppState::new(
+        test_config(),
+        hb,
+        Arc::new(database),
+        Arc::new(MockGenericHttpClient::new()),
+    );
+    let (srv, app_state) = start_app(app_state);
+    let cookie = login_user(&app_state, "alice").await;
+    login_user(&app_state, "bob").await;
+
+    let response = srv
+        .post("/shaft")
+        .cookie(cookie.clone())
+        .send_form(&[
+            ("other_user", "bob"),
+            ("amount", "12.5.0"),
+            ("reason", "Pizza & chips"),
+        ])
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 302);
+    let form_error = response.cookie("flash").expect("flash cookie");
+
+    assert!(app_state
+        .database
+        .get_all_transactions(DEFAULT_LEDGER_ID)
+        .await
+        .unwrap()
+        .is_empty());
+
+    let mut response = srv
+        .get("/home")
+        .cookie(cookie.clone())
+        .cookie(form_error)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    // The cookie is only used once.
+    let removal = response.cookie("flash").expect("flash removal");
+    assert_eq!(removal.value(), "");
+
+    let body = response.body().await.unwrap();
+    let body = std::str::from_utf8(&body).unwrap();
+    assert!(body.contains(r#"id="form-error""#), "{}", body);
+    assert!(body.contains(r#"value="12.5.0""#), "{}", body);
+    assert!(body.contains(r#"value="Pizza &amp; chips""#), "{}", body);
+    assert!(
+        body.contains(r#"<option value="bob" selected>"#),
+        "{}",
+        body
+    );
+
+    let response = srv
+        .post("/shaft")
+        .cookie(cookie.clone())
+        .send_form(&[
+            ("other_user", "carol"),
+            ("amount", "1.50"),
+            ("reason", "Coffee"),
+        ])
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 302);
+    let form_error = response.cookie("flash").expect("flash cookie");
+
+    let mut response = srv
+        .get("/home")
+        .cookie(cookie)
+        .cookie(form_error)
+        .send()
+        .await
+        .unwrap();
+    let body = response.body().await.unwrap();
+    let body = std::str::from_utf8(&body).unwrap();
+    assert!(body.contains("Unknown user: carol"), "{}", body);
+}
+
+/// Test that flash messages are shown once on the page after a redirect.
+#[actix_rt::test]
+async fn test_flash_messages() {
+    let mut hb = Handlebars::new();
+    hb.register_template_file("index", "res/index.hbs").unwrap();
+    hb.register_template_file("login", "res/login.hbs").unwrap();
+    hb.register_template_file("base", "res/base.hbs").unwrap();
+    hb.register_helper(
+        "pence-as-pounds",
+        Box::new(MoneyHelper::new(CurrencySettings::default())),
+    );
+
+    let database = SqliteDatabase::with_path(":memory:");
+    database.migrate().unwrap();
+    let app_state = AppState::new(
+        test_config(),
+        hb,
+        Arc::new(database),
+        Arc::new(MockGenericHttpClient::new()),
+    );
+    let (srv, app_state) = start_app(app_state);
+    let cookie = login_user(&app_state, "alice").await;
+    login_user(&app_state, "bob").await;
+
+    let response = srv
+        .post("/shaft")
+        .cookie(cookie.clone())
+        .send_form(&[
+            ("other_user", "bob"),
+            ("amount", "1.50"),
+            ("reason", "Coffee"),
+        ])
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 302);
+    let flash = response.cookie("flash").expect("flash cookie");
+
+    for shown in &[true, false] {
+        let mut response = srv
+            .get("/home")
+            .cookie(cookie.clone())
+            .cookie(flash.clone())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let body = response.body().await.unwrap();
+        let body = std::str::from_utf8(&body).unwrap();
+        assert_eq!(
+            body.contains(
+                r#"<div class="alert alert-success" id="flash">Saved £1.50 with bob</div>"#
+            ),
+            *shown,
+            "{}",
+            body
+        );
+    }
+
+    let response = srv
+        .post("/logout")
+        .cookie(cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 302);
+    let flash = response.cookie("flash").expect("flash cookie");
+
+    let mut response = srv.get("/login").cookie(flash).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    let body = response.body().await.unwrap();
+    let body = std::str::from_utf8(&body).unwrap();
+    assert!(body.contains("You have been signed out"), "{}", body);
+}
+
+#[actix_rt::test]
+async fn test_page_context() {
+    let mut hb = Handlebars::new();
+    hb.register_template_file("transactions", "res/transactions.hbs")
+        .unwrap();
+    hb.register_template_file("base", "res/base.hbs").unwrap();
+    hb.register_helper(
+        "pence-as-pounds",
+        Box::new(MoneyHelper::new(CurrencySettings::default())),
+    );
+    register_helpers(&mut hb, &UiSettings::default()).unwrap();
+
+    let database = SqliteDatabase::with_path(":memory:");
+    database.migrate().unwrap();
+    let app_state = AppState::new(
+        test_config(),
+        hb,
+        Arc::new(database),
+        Arc::new(MockGenericHttpClient::new()),
+    );
+    let (srv, app_state) = start_app(app_state);
+    let cookie = login_user(&app_state, "alice").await;
+    let admin_cookie =
+        login_user_with_scopes(&app_state, "admin", vec![Scope::Read, Scope::Admin]).await;
+
+    let mut response = srv
+        .get("/transactions")
+        .cookie(cookie)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body = response.body().await.unwrap();
+    let body = std::str::from_utf8(&body).unwrap();
+    assert!(body.contains("Signed in as alice"), "{}", body);
+    assert!(
+        body.contains(r#"<li class="active"><a href="transactions">Transactions</a></li>"#),
+        "{}",
+        body
+    );
+    assert!(
+        body.contains(r#"<li><a href="home">Balances</a></li>"#),
+        "{}",
+        body
+    );
+    assert!(!body.contains(">Admin</a>"), "{}", body);
+
+    // Only admins get a link to the dashboard.
+    let mut response = srv
+        .get("/transactions")
+        .cookie(admin_cookie)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body = response.body().await.unwrap();
+    let body = std::str::from_utf8(&body).unwrap();
+    assert!(body.contains("Signed in as admin"), "{}", body);
+    assert!(
+        body.contains(r#"<li><a href="/admin">Admin</a></li>"#),
+        "{}",
+        body
+    );
+}
+
+/// Test that form preferences are stored per user and fill in the form.
+#[actix_rt::test]
+async fn test_preferences() {
+    let mut hb = Handlebars::new();
+    hb.register_template_file("index", "res/index.hbs").unwrap();
+    hb.register_template_file("base", "res/base.hbs").unwrap();
+    hb.register_helper(
+        "pence-as-pounds",
+        Box::new(MoneyHelper::new(CurrencySettings::default())),
+    );
+
+    let database = SqliteDatabase::with_path(":memory:");
+    database.migrate().unwrap();
+    let app_state = AppState::new(
+        test_config(),
+        hb,
+        Arc::new(database),
+        Arc::new(MockGenericHttpClient::new()),
+    );
+    let (srv, app_state) = start_app(app_state);
+    let cookie = login_user(&app_state, "alice").await;
+    let bob = login_user(&app_state, "bob").await;
+
+    let mut response = srv
+        .get("/api/profile/preferences")
+        .cookie(cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(
+        body,
+        json!({ "default_counterparty": null, "default_currency": null, "quick_amounts": [] })
+    );
+
+    for invalid in &[
+        json!({ "default_counterparty": "alice" }),
+        json!({ "quick_amounts": [0] }),
+        json!({ "quick_amounts": ["lots"] }),
+        json!({ "default_currency": "pounds" }),
+        json!({ "default_currency": "EUR" }),
+    ] {
+        let response = srv
+            .put("/api/profile/preferences")
+            .cookie(cookie.clone())
+            .send_json(invalid)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 400, "{}", invalid);
+    }
+
+    let mut response = srv
+        .put("/api/profile/preferences")
+        .cookie(cookie.clone())
+        .send_json(&json!({
+            "default_counterparty": "bob",
+            "default_currency": "GBP",
+            "quick_amounts": ["2.50", 1000],
+        }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["quick_amounts"], json!([250, 1000]));
+
+    let mut response = srv
+        .get("/home")
+        .cookie(cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    let body = response.body().await.unwrap();
+    let body = std::str::from_utf8(&body).unwrap();
+    assert!(
+        body.contains(r#"<option value="bob" selected>"#),
+        "{}",
+        body
+    );
+    assert!(body.contains(r#"data-amount="£2.50""#), "{}", body);
+    assert!(body.contains(r#"data-amount="£10.00""#), "{}", body);
+    assert!(
+        body.contains(r#"<span class="input-group-addon">GBP</span>"#),
+        "{}",
+        body
+    );
+
+    // Preferences are per user.
+    let mut response = srv.get("/home").cookie(bob).send().await.unwrap();
+    let body = response.body().await.unwrap();
+    let body = std::str::from_utf8(&body).unwrap();
+    assert!(!body.contains("selected>"), "{}", body);
+    assert!(!body.contains("quick-amounts"), "{}", body);
+}
+
+/// Test adding transactions from a line of text, confirming ambiguous users.
+#[actix_rt::test]
+async fn test_quick_add() {
+    let (srv, app_state) = setup_app(None);
+    let cookie = login_user(&app_state, "alice").await;
+    for user_id in &["bob", "bobby", "carol"] {
+        login_user(&app_state, user_id).await;
+    }
+
+    let mut response = srv
+        .post("/api/quickadd")
+        .cookie(cookie.clone())
+        .send_json(&json!({ "text": "Carol 12.50 pizza" }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "created");
+    assert_eq!(body["other_user"], "carol");
+    assert_eq!(body["amount"], 1250);
+    assert_eq!(body["reason"], "pizza");
+
+    // An exact ID wins over a longer ID it's a prefix of.
+    let mut response = srv
+        .post("/api/quickadd")
+        .cookie(cookie.clone())
+        .send_json(&json!({ "text": "3 @bob coffee" }))
+        .await
+        .unwrap();
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "created");
+    assert_eq!(body["other_user"], "bob");
+    assert_eq!(body["amount"], 300);
+
+    let mut response = srv
+        .post("/api/quickadd")
+        .cookie(cookie.clone())
+        .send_json(&json!({ "text": "bo 2 cake" }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "ambiguous");
+    assert_eq!(
+        body["candidates"],
+        json!([
+            { "user_id": "bob", "display_name": "bob" },
+            { "user_id": "bobby", "display_name": "bobby" },
+        ])
+    );
+    assert_eq!(body["transaction_id"], serde_json::Value::Null);
+
+    let mut response = srv
+        .post("/api/quickadd")
+        .cookie(cookie.clone())
+        .send_json(&json!({ "text": "bo 2 cake", "other_user": "bobby" }))
+        .await
+        .unwrap();
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "created");
+    assert_eq!(body["other_user"], "bobby");
+
+    for text in &["dave 1 tea", "bob lots tea", "alice 1 tea", "bob -1 tea"] {
+        let response = srv
+            .post("/api/quickadd")
+            .cookie(cookie.clone())
+            .send_json(&json!({ "text": text }))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 400, "{}", text);
+    }
+
+    let mut response = srv
+        .get("/api/balances")
+        .cookie(cookie)
+        .send()
+        .await
+        .unwrap();
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["alice"]["balance"], 1750);
+}
+
+/// Test that users can be found by partial and misspelt names.
+#[actix_rt::test]
+async fn test_match_users() {
+    let (srv, app_state) = setup_app(None);
+    let cookie = login_user(&app_state, "alice").await;
+    for user_id in &["bob", "bobby", "carol"] {
+        login_user(&app_state, user_id).await;
+    }
+
+    let matched = |query: &'static str| {
+        let request = srv
+            .get(format!("/api/users/match?{}", query))
+            .cookie(cookie.clone());
+        async move {
+            let mut response = request.send().await.unwrap();
+            assert_eq!(response.status(), 200, "{}", query);
+            let body: serde_json::Value = response.json().await.unwrap();
+            body["matches"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|user| user["user_id"].as_str().unwrap().to_owned())
+                .collect::<Vec<_>>()
+        }
+    };
+
+    assert_eq!(matched("q=bob").await, vec!["bob", "bobby"]);
+    assert_eq!(matched("q=bo").await, vec!["bob", "bobby"]);
+    assert_eq!(matched("q=bo&limit=1").await, vec!["bob"]);
+    assert_eq!(matched("q=%40Carol").await, vec!["carol"]);
+    assert_eq!(matched("q=carl").await, vec!["carol"]);
+    assert_eq!(matched("q=zzz").await, Vec::<String>::new());
+
+    // A typo alone isn't enough for quick-add to pick the user.
+    let mut response = srv
+        .post("/api/quickadd")
+        .cookie(cookie.clone())
+        .send_json(&json!({ "text": "carl 1 tea" }))
+        .await
+        .unwrap();
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "ambiguous");
+    assert_eq!(
+        body["candidates"],
+        json!([{ "user_id": "carol", "display_name": "carol" }])
+    );
+}
+
+/// Test that the capabilities reflect the feature flags as they change.
+#[actix_rt::test]
+async fn test_capabilities() {
+    let (srv, app_state) = setup_app(None);
+
+    let mut response = srv.get("/api/capabilities").send().await.unwrap();
+    assert_eq!(response.status(), 200);
+
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(body["features"]["webhooks"], false);
+    assert_eq!(body["multi_currency"], false);
+    assert_eq!(body["currency"]["code"], "GBP");
+
+    app_state.features.set(Feature::Webhooks, true);
+
+    let mut response = srv.get("/api/capabilities").send().await.unwrap();
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["features"]["webhooks"], true);
+}
+
+/// Test that reasons are required by default, and can be made optional.
+#[actix_rt::test]
+async fn test_reason_settings() {
+    let (srv, app_state) = setup_app(None);
+    let cookie = login_user(&app_state, "alice").await;
+    login_user(&app_state, "bob").await;
+
+    let response = srv
+        .post("/api/shaft")
+        .cookie(cookie.clone())
+        .send_json(&json!({ "other_user": "bob", "amount": 150, "reason": "  " }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+
+    let mut config = test_config();
+    config.reasons = ReasonSettings {
+        required: false,
+        min_length: 3,
+        max_length: 10,
+    };
+    let (srv, app_state) = setup_app_with_config(config, None);
+    let cookie = login_user(&app_state, "alice").await;
+    login_user(&app_state, "bob").await;
+
+    for (reason, status) in &[
+        ("", 200),
+        ("ab", 400),
+        ("Way too long", 400),
+        (" Tea ", 200),
+    ] {
+        let response = srv
+            .post("/api/shaft")
+            .cookie(cookie.clone())
+            .send_json(&json!({ "other_user": "bob", "amount": 150, "reason": reason }))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), *status, "reason {:?}", reason);
+    }
+
+    let mut reasons: Vec<_> = app_state
+        .database
+        .get_all_transactions(DEFAULT_LEDGER_ID)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|transaction| transaction.reason)
+        .collect();
+    reasons.sort();
+    assert_eq!(reasons, vec!["", "Tea"]);
+}
+
+/// Test that logged out users can only read the ledger in public read mode,
+/// which admins can turn on at runtime.
+#[actix_rt::test]
+async fn test_public_read() {
+    let (srv, app_state) = setup_app(None);
+    let cookie = login_user(&app_state, "alice").await;
+    let admin_cookie = login_user_with_scopes(
+        &app_state,
+        "admin",
+        vec![Scope::Read, Scope::Write, Scope::Admin],
+    )
+    .await;
+
+    let response = srv.get("/api/balances").send().await.unwrap();
+    assert_eq!(response.status(), 302);
+
+    let response = srv
+        .put("/api/admin/features/public_read")
+        .cookie(cookie)
+        .send_json(&json!({ "enabled": true }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 403);
+
+    let mut response = srv
+        .put("/api/admin/features/public_read")
+        .cookie(admin_cookie.clone())
+        .send_json(&json!({ "enabled": true }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["public_read"], true);
+    assert_eq!(body["registration_open"], true);
+
+    let response = srv
+        .put("/api/admin/features/unknown")
+        .cookie(admin_cookie)
+        .send_json(&json!({ "enabled": true }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+
+    let response = srv.get("/api/balances").send().await.unwrap();
+    assert_eq!(response.status(), 200);
+
+    let response = srv.get("/api/transactions").send().await.unwrap();
+    assert_eq!(response.status(), 200);
+
+    // Writes still require a session.
+    let response = srv
+        .post("/api/shaft")
+        .send_json(&json!({
+            "other_user": "bob",
+            "amount": 150,
+            "reason": "Coffee",
+        }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 302);
+}
+
+/// Test that API tokens can only do what their scopes allow.
+#[actix_rt::test]
+async fn test_api_token_scopes() {
+    let (srv, app_state) = setup_app(None);
+    let cookie = login_user(&app_state, "alice").await;
+    login_user(&app_state, "bob").await;
+
+    // Sessions can't create tokens with more access than they have.
+    let response = srv
+        .post("/api/tokens")
+        .cookie(cookie.clone())
+        .send_json(&json!({ "name": "Dashboard", "scopes": ["read", "admin"] }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 403);
+
+    let mut response = srv
+        .post("/api/tokens")
+        .cookie(cookie.clone())
+        .send_json(&json!({ "name": "Dashboard", "scopes": ["read"] }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let body: serde_json::Value = response.json().await.unwrap();
+    let token_id = body["id"].as_i64().unwrap();
+    let auth = format!("Bearer {}", body["token"].as_str().unwrap());
+
+    let response = srv
+        .get("/api/balances")
+        .insert_header(("Authorization", auth.clone()))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let response = srv
+        .post("/api/shaft")
+        .insert_header(("Authorization", auth.clone()))
+        .send_json(&json!({
+            "other_user": "bob",
+            "amount": 150,
+            "reason": "Coffee",
+        }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 403);
+
+    let mut response = srv
+        .get("/api/tokens")
+        .cookie(cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(
+        body,
+        json!([{ "id": token_id, "name": "Dashboard", "scopes": ["read"] }])
+    );
+
+    // Revoked tokens stop working.
+    let response = srv
+        .delete(format!("/api/tokens/{}", token_id))
+        .cookie(cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let response = srv
+        .get("/api/balances")
+        .insert_header(("Authorization", auth.clone()))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 302);
+}
+
+/// Test that actions are recorded in the audit log, which only admins can see.
+#[actix_rt::test]
+async fn test_audit_log() {
+    let (srv, app_state) = setup_app(None);
+    let cookie = login_user(&app_state, "alice").await;
+    let admin_cookie = login_user_with_scopes(
+        &app_state,
+        "admin",
+        vec![Scope::Read, Scope::Write, Scope::Admin],
+    )
+    .await;
+
+    let response = srv
+        .post("/api/tokens")
+        .cookie(cookie.clone())
+        .send_json(&json!({ "name": "Dashboard", "scopes": ["read"] }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let response = srv
+        .get("/api/admin/audit")
+        .cookie(cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 403);
+
+    let mut response = srv
+        .get("/api/admin/audit?actor=alice&action=token.create")
+        .cookie(admin_cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let body: serde_json::Value = response.json().await.unwrap();
+    let entries = body["entries"].as_array().unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0]["actor"], "alice");
+    assert_eq!(entries[0]["action"], "token.create");
+
+    let mut response = srv
+        .get("/api/admin/audit?actor=bob")
+        .cookie(admin_cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert!(body["entries"].as_array().unwrap().is_empty());
+
+    let mut response = srv
+        .get("/api/admin/audit?format=csv")
+        .cookie(admin_cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let body = response.body().await.unwrap();
+    let csv = std::str::from_utf8(&body).unwrap();
+    assert!(csv.starts_with("ID,Time,Actor,Action,Target,Details\r\n"));
+    assert!(csv.contains(",alice,token.create,"));
+
+    // CSV exports include every entry, however many pages they span.
+    for _ in 0..2500 {
+        app_state
+            .database
+            .add_audit_entry(AuditEntry {
+                id: None,
+                actor: "bob".to_owned(),
+                action: "user.poke".to_owned(),
+                target: Some("alice".to_owned()),
+                details: None,
+                datetime: chrono::Utc::now(),
+            })
+            .await
+            .unwrap();
+    }
+
+    let mut response = srv
+        .get("/api/admin/audit?format=csv&actor=bob")
+        .cookie(admin_cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let body = response.body().await.unwrap();
+    let csv = std::str::from_utf8(&body).unwrap();
+    assert_eq!(csv.lines().count(), 2501);
+}
+
+/// Test that users can export their data, and delete their account without
+/// unbalancing the ledger.
+#[actix_rt::test]
+async fn test_profile_export_and_delete() {
+    let (srv, app_state) = setup_app(None);
+    let cookie = login_user(&app_state, "alice").await;
+    let bob_cookie = login_user(&app_state, "bob").await;
+
+    let response = srv
+        .post("/api/shaft")
+        .cookie(cookie.clone())
+        .send_json(&json!({ "other_user": "bob", "amount": 150, "reason": "Coffee" }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    app_state
+        .database
+        .link_slack_user("T1".to_owned(), "U1".to_owned(), "alice".to_owned())
+        .await
+        .unwrap();
+    app_state
+        .database
+        .create_inbound_hook("alice".to_owned(), "IFTTT".to_owned(), Outbox::none())
+        .await
+        .unwrap();
+    app_state
+        .database
+        .add_audit_entry(AuditEntry {
+            id: None,
+            actor: "bob".to_owned(),
+            action: "user.poke".to_owned(),
+            target: Some("alice".to_owned()),
+            details: None,
+            datetime: chrono::Utc::now(),
+        })
+        .await
+        .unwrap();
+    app_state
+        .database
+        .add_security_event(SecurityEvent {
+            id: None,
+            kind: "token.invalid".to_owned(),
+            user_id: Some("alice".to_owned()),
+            ip: None,
+            details: None,
+            datetime: chrono::Utc::now(),
+        })
+        .await
+        .unwrap();
+
+    let mut response = srv
+        .get("/api/profile/export")
+        .cookie(cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["user"]["balance"], 150);
+    assert_eq!(body["github_ids"], json!(["alice"]));
+    assert_eq!(body["transactions"].as_array().unwrap().len(), 1);
+    assert_eq!(body["sessions"], 1);
+    assert_eq!(
+        body["slack_links"],
+        json!([{ "team_id": "T1", "slack_user_id": "U1" }])
+    );
+    assert_eq!(body["inbound_hooks"][0]["name"], "IFTTT");
+    assert_eq!(body["ledgers"][0]["id"], DEFAULT_LEDGER_ID);
+    assert_eq!(body["security_events"][0]["kind"], "token.invalid");
+
+    // Deleting needs a confirmation token.
+    let mut response = srv
+        .post("/api/profile/delete")
+        .cookie(cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    let confirmation_token = body["confirmation_token"].as_str().unwrap().to_string();
+
+    let response = srv
+        .post("/api/profile/delete")
+        .cookie(cookie.clone())
+        .send_json(&json!({ "confirmation_token": "wrong" }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 403);
+
+    // Other users can't use the token.
+    let response = srv
+        .post("/api/profile/delete")
+        .cookie(bob_cookie.clone())
+        .send_json(&json!({ "confirmation_token": confirmation_token }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 403);
+
+    let response = srv
+        .post("/api/profile/delete")
+        .cookie(cookie.clone())
+        .send_json(&json!({ "confirmation_token": confirmation_token }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    // Their session no longer works.
+    let response = srv.get("/api/me").cookie(cookie).send().await.unwrap();
+    assert_eq!(response.status(), 302);
+
+    // They're anonymised in the ledger, which still balances.
+    let mut response = srv
+        .get("/api/balances")
+        .cookie(bob_cookie)
+        .send()
+        .await
+        .unwrap();
+    let body: serde_json::Value = response.json().await.unwrap();
+    let users = body.as_object().unwrap();
+    assert!(!users.contains_key("alice"));
+    assert_eq!(users["bob"]["balance"], -150);
+
+    let (anonymous_id, deleted) = users
+        .iter()
+        .find(|(user_id, _)| user_id.starts_with("deleted-"))
+        .expect("anonymised user");
+    assert_eq!(deleted["display_name"], "Deleted user");
+    assert_eq!(deleted["balance"], 150);
+
+    assert_eq!(
+        app_state
+            .database
+            .get_user_by_github_id("alice".to_owned())
+            .await
+            .unwrap(),
+        None
+    );
+    let export = app_state
+        .database
+        .export_user(anonymous_id.clone())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(export
+        .audit_log
+        .iter()
+        .any(|entry| entry.action == "user.delete"));
+    assert_eq!(export.security_events.len(), 1);
+
+    // Nothing still refers to them by their old ID.
+    let audit = app_state
+        .database
+        .get_audit_entries(AuditFilter {
+            limit: 100,
+            ..AuditFilter::default()
+        })
+        .await
+        .unwrap();
+    assert!(audit
+        .iter()
+        .any(|entry| entry.target.as_ref() == Some(anonymous_id)));
+    assert!(audit
+        .iter()
+        .all(|entry| entry.target.as_deref() != Some("alice")));
+}
+
+/// Test the compact widget payload and its ETag.
+#[actix_rt::test]
+async fn test_widget() {
+    let (srv, app_state) = setup_app(None);
+    let cookie = login_user(&app_state, "alice").await;
+    let bob_cookie = login_user(&app_state, "bob").await;
+    login_user(&app_state, "carol").await;
+
+    for (other_user, amount) in &[("bob", 150), ("carol", -50)] {
+        let response = srv
+            .post("/api/shaft")
+            .cookie(cookie.clone())
+            .send_json(&json!({ "other_user": other_user, "amount": amount, "reason": "Coffee" }))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+    }
+
+    let mut response = srv
+        .get("/api/widget")
+        .cookie(cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let etag = response.headers().get("etag").expect("etag header").clone();
+
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["schema_version"], 1);
+    assert_eq!(body["user_id"], "alice");
+    assert_eq!(body["balance"], 100);
+    assert_eq!(
+        body["creditors"],
+        json!([{ "user_id": "carol", "display_name": "carol", "balance": 50 }])
+    );
+    assert_eq!(
+        body["debtors"],
+        json!([{ "user_id": "bob", "display_name": "bob", "balance": -150 }])
+    );
+    assert_eq!(body["last_transaction"]["amount"], -50);
+    assert_eq!(body["last_transaction"]["shaftee"], "carol");
+
+    let response = srv
+        .get("/api/widget")
+        .cookie(cookie.clone())
+        .insert_header(("If-None-Match", etag.clone()))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 304);
+
+    // The ETag is personal, so doesn't match for another user.
+    let response = srv
+        .get("/api/widget")
+        .cookie(bob_cookie)
+        .insert_header(("If-None-Match", etag))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+}
+
+/// Test that debts between pairs are listed largest first, with names.
+#[actix_rt::test]
+async fn test_debts() {
+    let (srv, app_state) = setup_app(None);
+    let cookie = login_user(&app_state, "alice").await;
+    login_user(&app_state, "bob").await;
+    login_user(&app_state, "carol").await;
+
+    for (other_user, amount) in &[("bob", 150), ("carol", -50)] {
+        let response = srv
+            .post("/api/shaft")
+            .cookie(cookie.clone())
+            .send_json(&json!({ "other_user": other_user, "amount": amount, "reason": "Coffee" }))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+    }
+
+    let mut response = srv
+        .get("/api/debts")
+        .cookie(cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    let largest = body["largest"].as_array().unwrap();
+    assert_eq!(largest.len(), 2);
+    assert_eq!(largest[0]["debtor"], "bob");
+    assert_eq!(largest[0]["creditor_name"], "alice");
+    assert_eq!(largest[0]["amount"], 150);
+    assert_eq!(largest[0]["last_settled"], serde_json::Value::Null);
+    assert_eq!(largest[1]["debtor"], "alice");
+    assert_eq!(largest[1]["creditor"], "carol");
+    assert_eq!(largest[1]["amount"], 50);
+    assert_eq!(body["longest_unsettled"].as_array().unwrap().len(), 2);
+
+    let mut response = srv
+        .get("/api/debts?limit=1")
+        .cookie(cookie)
+        .send()
+        .await
+        .unwrap();
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["largest"].as_array().unwrap().len(), 1);
+    assert_eq!(body["longest_unsettled"].as_array().unwrap().len(), 1);
+}
+
+/// Test exporting the user's transactions as ledger and beancount journals.
+#[actix_rt::test]
+async fn test_export_ledger() {
+    let (srv, app_state) = setup_app(None);
+    let cookie = login_user(&app_state, "alice").await;
+    let bob_cookie = login_user(&app_state, "bob").await;
+
+    for (cookie, other_user, reason) in &[
+        (&cookie, "bob", "Coffee"),
+        (&bob_cookie, "alice", "Tea \"to go\""),
+    ] {
+        let response = srv
+            .post("/api/shaft")
+            .cookie((*cookie).clone())
+            .send_json(&json!({ "other_user": other_user, "amount": 150, "reason": reason }))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+    }
+
+    let date = chrono::Utc::now().format("%Y-%m-%d");
+
+    let mut response = srv
+        .get("/api/export/ledger")
+        .cookie(cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body = String::from_utf8(response.body().await.unwrap().to_vec()).unwrap();
+    assert_eq!(
+        body,
+        format!(
+            "{date} * Coffee\n  Assets:Shaft:Bob  1.50 GBP\n  Equity:Shaft  -1.50 GBP\n\n\
+            {date} * Tea \"to go\"\n  Assets:Shaft:Bob  -1.50 GBP\n  Equity:Shaft  1.50 GBP\n\n",
+            date = date
+        )
+    );
+
+    let mut response = srv
+        .get("/api/export/ledger?format=beancount&currency=EUR&account.bob=Liabilities:Bob")
+        .cookie(cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body = String::from_utf8(response.body().await.unwrap().to_vec()).unwrap();
+    assert_eq!(
+        body,
+        format!(
+            "{date} open Equity:Shaft\n{date} open Liabilities:Bob\n\n\
+            {date} * \"Coffee\"\n  Liabilities:Bob  1.50 EUR\n  Equity:Shaft  -1.50 EUR\n\n\
+            {date} * \"Tea \\\"to go\\\"\"\n  Liabilities:Bob  -1.50 EUR\n  Equity:Shaft  1.50 EUR\n\n",
+            date = date
+        )
+    );
+}
+
+/// Test exporting the ledger as a Splitwise CSV, with a column per user
+/// holding their share of each expense.
+#[actix_rt::test]
+async fn test_export_splitwise() {
+    let (srv, app_state) = setup_app(None);
+    let cookie = login_user(&app_state, "alice").await;
+    let bob_cookie = login_user(&app_state, "bob").await;
+    login_user(&app_state, "carol").await;
+
+    for (cookie, other_user, amount, reason) in &[
+        (&cookie, "bob", 150, "Coffee"),
+        (&bob_cookie, "alice", 50, "Tea, to go"),
+    ] {
+        let response = srv
+            .post("/api/shaft")
+            .cookie((*cookie).clone())
+            .send_json(&json!({ "other_user": other_user, "amount": amount, "reason": reason }))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+    }
+
+    let date = chrono::Utc::now().format("%Y-%m-%d");
+
+    let mut response = srv
+        .get("/api/export/splitwise")
+        .cookie(cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body = String::from_utf8(response.body().await.unwrap().to_vec()).unwrap();
+    assert_eq!(
+        body,
+        format!(
+            "Date,Description,Category,Cost,Currency,bob,carol,alice\r\n\
+            {date},Coffee,General,1.50,GBP,-1.50,0.00,1.50\r\n\
+            {date},\"Tea, to go\",General,0.50,GBP,0.50,0.00,-0.50\r\n",
+            date = date
+        )
+    );
+
+    let mut response = srv
+        .get("/api/export/splitwise?currency=EUR")
+        .cookie(cookie)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body = String::from_utf8(response.body().await.unwrap().to_vec()).unwrap();
+    assert!(body.contains(",Coffee,General,1.50,EUR,"), "{}", body);
+
+    let response = srv.get("/api/export/splitwise").send().await.unwrap();
+    assert_eq!(response.status(), 302);
+}
+
+/// Test that uploaded attachments are passed to the receipt processor, whose
+/// suggestions only the parties to the transaction can see.
+#[actix_rt::test]
+async fn test_receipt_suggestions() {
+    let mut mock_http_client = MockGenericHttpClient::new();
+    mock_http_client
+        .expect_request()
+        .withf(|req: &Request<Body>| {
+            req.method() == "POST"
+                && req.uri() == "https://receipts.example.com/process"
+                && req.headers()["content-type"] == "image/png"
+        })
+        .returning(
+            |_| -> BoxFuture<'static, Result<Response<Body>, HttpError>> {
+                future::ready(
+                    Response::builder().status(200).body(
+                        json!({ "amount": 1250, "merchant": "Cafe" })
+                            .to_string()
+                            .into(),
+                    ),
+                )
+                .map_err(|source| HttpError::Http { source })
+                .boxed()
+            },
+        );
+    mock_http_client
+        .expect_request()
+        .withf(|req: &Request<Body>| req.headers()["content-type"] == "image/jpeg")
+        .returning(
+            |_| -> BoxFuture<'static, Result<Response<Body>, HttpError>> {
+                future::ready(Response::builder().status(500).body(Body::empty()))
+                    .map_err(|source| HttpError::Http { source })
+                    .boxed()
+            },
+        );
+
+    let database = SqliteDatabase::with_path(":memory:");
+    database.migrate().unwrap();
+
+    let mut app_state = AppState::new(
+        test_config(),
+        Handlebars::new(),
+        Arc::new(database),
+        Arc::new(MockGenericHttpClient::new()),
+    );
+    app_state.receipt_processor = Arc::new(HttpReceiptProcessor::new(
+        "https://receipts.example.com/process".to_owned(),
+        Arc::new(mock_http_client),
+    ));
+    let (srv, app_state) = start_app(app_state);
+    let cookie = login_user(&app_state, "alice").await;
+    login_user(&app_state, "bob").await;
+    let carol = login_user(&app_state, "carol").await;
+
+    let mut response = srv
+        .post("/api/shaft")
+        .cookie(cookie.clone())
+        .send_json(&json!({ "other_user": "bob", "amount": 1250, "reason": "Lunch" }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    let path = format!("/api/transactions/{}", body["transaction_id"]);
+
+    let mut response = srv
+        .post(format!("{}/attachments", path))
+        .cookie(cookie.clone())
+        .insert_header(("Content-Type", "image/png"))
+        .send_body("receipt")
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    let attachment_id = body["attachment_id"].as_i64().unwrap();
+
+    // A failure to process the receipt doesn't fail the upload, but nothing
+    // is suggested from it.
+    let response = srv
+        .post(format!("{}/attachments", path))
+        .cookie(cookie.clone())
+        .insert_header(("Content-Type", "image/jpeg"))
+        .send_body("blurry receipt")
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let mut response = srv
+        .get(format!("{}/suggestions", path))
+        .cookie(cookie)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(
+        body,
+        json!([{ "attachment_id": attachment_id, "amount": 1250, "merchant": "Cafe" }])
+    );
+
+    let response = srv
+        .get(format!("{}/suggestions", path))
+        .cookie(carol)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+}
+
+/// Test the Home Assistant sensor can be read with a sensor-only token.
+#[actix_rt::test]
+async fn test_home_assistant_sensor() {
+    let (srv, app_state) = setup_app(None);
+    let cookie = login_user(&app_state, "alice").await;
+    login_user(&app_state, "bob").await;
+
+    let response = srv
+        .post("/api/shaft")
+        .cookie(cookie.clone())
+        .send_json(&json!({ "other_user": "bob", "amount": 150, "reason": "Coffee" }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    // Sessions can create sensor tokens, as reading implies the sensor scope.
+    let mut response = srv
+        .post("/api/tokens")
+        .cookie(cookie.clone())
+        .send_json(&json!({ "name": "Kitchen dashboard", "scopes": ["sensor"] }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    let auth = format!("Bearer {}", body["token"].as_str().unwrap());
+
+    let mut response = srv
+        .get("/api/home_assistant/sensor")
+        .insert_header(("Authorization", auth.clone()))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(
+        body,
+        json!({
+            "value": 1.5,
+            "attributes": {
+                "user_id": "alice",
+                "display_name": "alice",
+                "balance_pence": 150,
+                "balances": { "alice": 1.5, "bob": -1.5 },
+            },
+        })
+    );
+
+    // Sensor tokens can't read anything else.
+    let response = srv
+        .get("/api/balances")
+        .insert_header(("Authorization", auth))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 403);
+}
+
+/// Test creating transactions through an inbound hook.
+#[actix_rt::test]
+async fn test_inbound_hooks() {
+    let (srv, app_state) = setup_app(None);
+    let cookie = login_user(&app_state, "alice").await;
+    login_user(&app_state, "bob").await;
+
+    let response = srv
+        .post("/api/inbound_hooks")
+        .cookie(cookie.clone())
+        .send_json(&json!({ "name": "Internet" }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 403);
+
+    app_state.features.set(Feature::Webhooks, true);
+
+    let mut response = srv
+        .post("/api/inbound_hooks")
+        .cookie(cookie.clone())
+        .send_json(&json!({ "name": "Internet" }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let body: serde_json::Value = response.json().await.unwrap();
+    let id = body["id"].as_i64().unwrap();
+    let url = url::Url::parse(body["url"].as_str().unwrap()).unwrap();
+    assert!(url.path().starts_with("/integrations/inbound/"));
+
+    // The hook doesn't need a session.
+    let mut response = srv
+        .post(url.path())
+        .send_json(&json!({ "other_user": "bob", "amount": 500, "reason": "Internet" }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert!(body["transaction_id"].is_i64());
+
+    let transactions = app_state
+        .database
+        .get_last_transactions(DEFAULT_LEDGER_ID, 1)
+        .await
+        .unwrap();
+    assert_eq!(transactions[0].shafter, "alice");
+    assert_eq!(transactions[0].shaftee, "bob");
+    assert_eq!(transactions[0].amount.minor_units(), 500);
+
+    let response = srv
+        .post("/integrations/inbound/not_a_hook")
+        .send_json(&json!({ "other_user": "bob", "amount": 500, "reason": "Internet" }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+
+    let mut response = srv
+        .get("/api/inbound_hooks")
+        .cookie(cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body[0]["id"], id);
+    assert_eq!(body[0]["name"], "Internet");
+    assert!(body[0].get("url").is_none());
+
+    // Deleted hooks stop working.
+    let response = srv
+        .delete(format!("/api/inbound_hooks/{}", id))
+        .cookie(cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let response = srv
+        .post(url.path())
+        .send_json(&json!({ "other_user": "bob", "amount": 500, "reason": "Internet" }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+}
+
+/// Test that JSON responses can be pretty-printed.
+#[actix_rt::test]
+async fn test_pretty_json() {
+    let (srv, app_state) = setup_app(None);
+    let cookie = login_user(&app_state, "alice").await;
+
+    let mut response = srv
+        .get("/api/balances")
+        .cookie(cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        response.headers().get("content-type").unwrap(),
+        "application/json; charset=utf-8"
+    );
+    let body = response.body().await.unwrap();
+    assert!(!body.contains(&b'\n'));
+
+    let mut response = srv
+        .get("/api/balances?pretty=1")
+        .cookie(cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    let body = response.body().await.unwrap();
+    assert!(std::str::from_utf8(&body)
+        .unwrap()
+        .contains("\n  \"alice\""));
+
+    let mut response = srv
+        .get("/api/balances")
+        .cookie(cookie.clone())
+        .insert_header(("Accept", "text/html, application/json; indent=4"))
+        .send()
+        .await
+        .unwrap();
+    let body = response.body().await.unwrap();
+    assert!(std::str::from_utf8(&body)
+        .unwrap()
+        .contains("\n    \"alice\""));
+}
+
+/// Test that requests are turned away with a 503 when too many database
+/// operations are queued.
+#[actix_rt::test]
+async fn test_database_busy() {
+    let database = SqliteDatabase::with_settings(
+        ":memory:",
+        &DatabasePoolSettings {
+            max_queued: 0,
+            ..DatabasePoolSettings::default()
+        },
+        &SqliteSettings::default(),
+    );
+    database.migrate().unwrap();
+
+    let (srv, _) = setup_app_with_database(test_config(), database, None);
+
+    // Authenticating the token needs the database.
+    let response = srv
+        .get("/api/balances")
+        .insert_header(("Authorization", "Bearer some_token"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 503);
+    assert_eq!(response.headers().get("retry-after").unwrap(), "1");
+}
+
+/// Test that admins can see the database pool stats.
+#[actix_rt::test]
+async fn test_pool_stats() {
+    let (srv, app_state) = setup_app(None);
+    let cookie = login_user(&app_state, "alice").await;
+    let admin_cookie = login_user_with_scopes(
+        &app_state,
+        "admin",
+        vec![Scope::Read, Scope::Write, Scope::Admin],
+    )
+    .await;
+
+    let response = srv
+        .get("/api/admin/pool")
+        .cookie(cookie)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 403);
+
+    let mut response = srv
+        .get("/api/admin/pool")
+        .cookie(admin_cookie)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["in_flight"], 0);
+    assert_eq!(body["max_in_flight"], 64);
+    // The test database is in memory, so only has the one connection.
+    assert_eq!(body["max_connections"], 1);
+}
+
+/// Test that `/api/me` returns the logged in user with their balance.
+#[actix_rt::test]
+async fn test_me() {
+    let (srv, app_state) = setup_app(None);
+    let cookie = login_user(&app_state, "alice").await;
+    login_user(&app_state, "bob").await;
+
+    let response = srv
+        .post("/api/shaft")
+        .cookie(cookie.clone())
+        .send_json(&json!({
+            "other_user": "bob",
+            "amount": 150,
+            "reason": "Coffee",
+        }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let mut response = srv.get("/api/me").cookie(cookie).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(
+        body,
+        json!({ "user_id": "alice", "display_name": "alice", "balance": 150 })
+    );
+}
+
+/// Test that request errors get a 4xx rather than a 500.
+#[actix_rt::test]
+async fn test_error_statuses() {
+    let (srv, app_state) = setup_app(None);
+    let cookie = login_user(&app_state, "alice").await;
+
+    let response = srv
+        .post("/api/shaft")
+        .cookie(cookie.clone())
+        .send_json(&json!({
+            "other_user": "nobody",
+            "amount": 150,
+            "reason": "Coffee",
+        }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+
+    let response = srv
+        .get("/api/transactions/1234")
+        .cookie(cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+
+    let response = srv
+        .delete("/api/transactions/1234")
+        .cookie(cookie)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 428);
+}
+
+/// Test that admins can record adjustments, which are labelled as such.
+#[actix_rt::test]
+async fn test_admin_adjustments() {
+    let (srv, app_state) = setup_app(None);
+    let cookie = login_user(&app_state, "alice").await;
+    login_user(&app_state, "bob").await;
+    let admin_cookie = login_user_with_scopes(
+        &app_state,
+        "admin",
+        vec![Scope::Read, Scope::Write, Scope::Admin],
+    )
+    .await;
+
+    let adjustment = json!({
+        "user": "alice",
+        "other_user": "bob",
+        "amount": "£12.50",
+        "reason": "Opening balance",
+    });
+
+    let response = srv
+        .post("/api/admin/adjustments")
+        .cookie(cookie.clone())
+        .send_json(&adjustment)
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 403);
+
+    let response = srv
+        .post("/api/admin/adjustments")
+        .cookie(admin_cookie.clone())
+        .send_json(&json!({
+            "user": "alice",
+            "other_user": "nobody",
+            "amount": 100,
+            "reason": "Opening balance",
+        }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+
+    let response = srv
+        .post("/api/admin/adjustments")
+        .cookie(admin_cookie.clone())
+        .send_json(&adjustment)
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let users = app_state
+        .database
+        .get_all_users(DEFAULT_LEDGER_ID)
+        .await
+        .unwrap();
+    assert_eq!(users["alice"].balance.minor_units(), 1250);
+    assert_eq!(users["bob"].balance.minor_units(), -1250);
+
+    let mut response = srv
+        .get("/api/transactions")
+        .cookie(cookie)
+        .send()
+        .await
+        .unwrap();
+    let body: serde_json::Value = response.json().await.unwrap();
+    let transactions = body.as_array().unwrap();
+    assert_eq!(transactions.len(), 1);
+    assert_eq!(transactions[0]["kind"], "adjustment");
+
+    let mut response = srv
+        .get("/api/admin/audit?action=transaction.adjust")
+        .cookie(admin_cookie)
+        .send()
+        .await
+        .unwrap();
+    let body: serde_json::Value = response.json().await.unwrap();
+    let entries = body["entries"].as_array().unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0]["actor"], "admin");
+}
+
+/// Test that ledgers created by an admin are only visible to their members,
+/// and kept separate from the default ledger.
+#[actix_rt::test]
+async fn test_ledgers() {
+    let (srv, app_state) = setup_app(None);
+    let admin_cookie = login_user_with_scopes(
+        &app_state,
+        "admin",
+        vec![Scope::Read, Scope::Write, Scope::Admin],
+    )
+    .await;
+    let alice = login_user(&app_state, "alice").await;
+    let bob = login_user(&app_state, "bob").await;
+    login_user(&app_state, "carol").await;
+
+    let response = srv
+        .post("/api/admin/ledgers")
+        .cookie(admin_cookie.clone())
+        .send_json(&json!({ "name": "Holiday 2024", "display_name": "Holiday" }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+
+    let response = srv
+        .post("/api/admin/ledgers")
+        .cookie(alice.clone())
+        .send_json(&json!({ "name": "holiday", "display_name": "Holiday" }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 403);
+
+    let mut response = srv
+        .post("/api/admin/ledgers")
+        .cookie(admin_cookie.clone())
+        .send_json(&json!({ "name": "holiday", "display_name": "Holiday" }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let ledger: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(ledger["name"], "holiday");
+
+    for user_id in &["alice", "carol"] {
+        let response = srv
+            .put(format!("/api/admin/ledgers/holiday/members/{}", user_id))
+            .cookie(admin_cookie.clone())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+    }
+
+    // Bob isn't a member, so can't see the ledger or be shafted in it.
+    let response = srv
+        .get("/l/holiday/api/balances")
+        .cookie(bob.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+
+    let response = srv
+        .post("/l/holiday/api/shaft")
+        .cookie(alice.clone())
+        .send_json(&json!({ "other_user": "bob", "amount": 500, "reason": "Hotel" }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+
+    let mut response = srv
+        .post("/l/holiday/api/shaft")
+        .cookie(alice.clone())
+        .send_json(&json!({ "other_user": "carol", "amount": 500, "reason": "Hotel" }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    let transaction_id = body["transaction_id"].as_i64().unwrap();
+
+    let mut response = srv
+        .get("/l/holiday/api/balances")
+        .cookie(alice.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let balances: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(balances.as_object().unwrap().len(), 2);
+    assert_eq!(balances["alice"]["balance"], 500);
+
+    // The default ledger is untouched, and doesn't have the transaction.
+    let mut response = srv
+        .get("/api/me")
+        .cookie(alice.clone())
+        .send()
+        .await
+        .unwrap();
+    let me: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(me["balance"], 0);
+
+    let response = srv
+        .get(format!("/api/transactions/{}", transaction_id))
+        .cookie(alice.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+
+    let response = srv
+        .get(format!("/l/holiday/api/transactions/{}", transaction_id))
+        .cookie(alice.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let mut response = srv
+        .get("/api/ledgers")
+        .cookie(alice.clone())
+        .send()
+        .await
+        .unwrap();
+    let ledgers: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(ledgers.as_array().unwrap().len(), 2);
+
+    let response = srv
+        .get("/l/missing/api/balances")
+        .cookie(alice)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+}
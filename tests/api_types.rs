@@ -0,0 +1,136 @@
+use chrono::TimeZone;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::json;
+
+use std::fmt::Debug;
+
+use shaft::amount::AmountInput;
+use shaft::api_types::{
+    BalancesResponse, BulkShaftResponse, BulkShaftResult, ErrorResponse, ShaftUserBody,
+    ShaftUserResponse, TransactionEntry, UserBalance,
+};
+use shaft::db::TransactionKind;
+
+/// Check that the value survives being serialized and deserialized.
+fn assert_round_trip<T>(value: T)
+where
+    T: Serialize + DeserializeOwned + PartialEq + Debug,
+{
+    let json = serde_json::to_string(&value).unwrap();
+    let parsed: T = serde_json::from_str(&json).unwrap();
+    assert_eq!(parsed, value, "{}", json);
+}
+
+#[test]
+fn test_round_trips() {
+    assert_round_trip(ShaftUserBody {
+        other_user: "bob".to_string(),
+        amount: AmountInput::Minor(-150),
+        reason: "Coffee".to_string(),
+    });
+    assert_round_trip(ShaftUserBody {
+        other_user: "bob".to_string(),
+        amount: AmountInput::Text("£12.50".to_string()),
+        reason: String::new(),
+    });
+    assert_round_trip(ShaftUserResponse { transaction_id: 3 });
+    assert_round_trip(BulkShaftResponse {
+        committed: false,
+        results: vec![
+            BulkShaftResult::default(),
+            BulkShaftResult {
+                transaction_id: None,
+                error: Some("Unknown user: carol".to_string()),
+            },
+        ],
+    });
+    assert_round_trip(BalancesResponse {
+        users: vec![UserBalance {
+            user_id: "alice".to_string(),
+            display_name: "Alice".to_string(),
+            balance: 150,
+        }],
+    });
+    assert_round_trip(TransactionEntry {
+        id: 1,
+        shafter: "alice".to_string(),
+        shaftee: "bob".to_string(),
+        amount: 150,
+        datetime: chrono::Utc.timestamp(1_600_000_000, 0),
+        reason: "Coffee".to_string(),
+        kind: TransactionKind::Adjustment,
+    });
+    assert_round_trip(ErrorResponse {
+        kind: "NotFound".to_string(),
+        error: "Unknown user".to_string(),
+    });
+}
+
+/// Test the JSON matches what existing clients expect.
+#[test]
+fn test_wire_format() {
+    let transaction = TransactionEntry {
+        id: 1,
+        shafter: "alice".to_string(),
+        shaftee: "bob".to_string(),
+        amount: 150,
+        datetime: chrono::Utc.timestamp(1_600_000_000, 0),
+        reason: "Coffee".to_string(),
+        kind: TransactionKind::Shaft,
+    };
+    assert_eq!(
+        serde_json::to_value(&transaction).unwrap(),
+        json!({
+            "id": 1,
+            "shafter": "alice",
+            "shaftee": "bob",
+            "amount": 150,
+            "datetime": 1_600_000_000,
+            "reason": "Coffee",
+            "kind": "shaft",
+        })
+    );
+
+    // Empty fields are left out of bulk results.
+    assert_eq!(
+        serde_json::to_value(&BulkShaftResult {
+            transaction_id: Some(4),
+            error: None,
+        })
+        .unwrap(),
+        json!({ "transaction_id": 4 })
+    );
+}
+
+/// Test that unknown fields are ignored and missing optional ones defaulted,
+/// so that clients and servers of different versions work together.
+#[test]
+fn test_compatibility() {
+    let body: ShaftUserBody = serde_json::from_value(json!({
+        "other_user": "bob",
+        "amount": "1.50",
+        "category": "food",
+    }))
+    .unwrap();
+    assert_eq!(body.amount, AmountInput::Text("1.50".to_string()));
+    assert_eq!(body.reason, "");
+
+    let transaction: TransactionEntry = serde_json::from_value(json!({
+        "id": 1,
+        "shafter": "alice",
+        "shaftee": "bob",
+        "amount": 150,
+        "datetime": 1_600_000_000,
+        "reason": "Coffee",
+        "revision": 2,
+    }))
+    .unwrap();
+    assert_eq!(transaction.kind, TransactionKind::Shaft);
+
+    let error: ErrorResponse = serde_json::from_value(json!({ "error": "Oops" })).unwrap();
+    assert_eq!(error.kind, "");
+
+    let response: BulkShaftResponse = serde_json::from_value(json!({ "committed": true })).unwrap();
+    assert!(response.results.is_empty());
+}
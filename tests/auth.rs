@@ -0,0 +1,174 @@
+use actix_web::{test, web, App};
+
+use awc::cookie::Cookie;
+
+use std::time::Duration;
+
+use shaft::db::Outbox;
+use shaft::rest::{AuthenticateUser, AuthenticatedUser, MiddlewareLogger, SessionRefresh};
+use shaft::settings::{CookieSettings, HttpServerSettings};
+
+mod common;
+
+use common::{login_user, setup_app};
+
+/// Returns who the request was authenticated as, if anyone.
+async fn whoami(req: actix_web::HttpRequest) -> String {
+    use actix_web::HttpMessage;
+
+    req.extensions()
+        .get::<AuthenticatedUser>()
+        .map(|user| user.user_id.clone())
+        .unwrap_or_else(|| "anonymous".to_string())
+}
+
+/// Test that requests to skipped paths aren't authenticated.
+#[actix_rt::test]
+async fn test_skipped_paths() {
+    let (_srv, app_state) = setup_app(None);
+    let cookie = login_user(&app_state, "alice").await;
+
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let app = test::init_service(
+        App::new()
+            .wrap(
+                AuthenticateUser::new(app_state.database.clone(), app_state.auth_cache.clone())
+                    .with_skipped_paths(vec!["/health".to_string()]),
+            )
+            .wrap(MiddlewareLogger::new(logger))
+            .route("/health/whoami", web::get().to(whoami))
+            .route("/whoami", web::get().to(whoami)),
+    )
+    .await;
+
+    let req = test::TestRequest::get()
+        .uri("/whoami")
+        .cookie(cookie.clone())
+        .to_request();
+    assert_eq!(test::read_response(&app, req).await, "alice");
+
+    let req = test::TestRequest::get()
+        .uri("/health/whoami")
+        .cookie(cookie)
+        .to_request();
+    assert_eq!(test::read_response(&app, req).await, "anonymous");
+}
+
+/// Test that static files and webhooks aren't authenticated by default, but
+/// the pages next to them are.
+#[actix_rt::test]
+async fn test_default_skipped_paths() {
+    let (_srv, app_state) = setup_app(None);
+    let cookie = login_user(&app_state, "alice").await;
+
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let app = test::init_service(
+        App::new()
+            .wrap(
+                AuthenticateUser::new(app_state.database.clone(), app_state.auth_cache.clone())
+                    .with_skipped_paths(HttpServerSettings::default().skip_auth_paths),
+            )
+            .wrap(MiddlewareLogger::new(logger))
+            .route("/static/whoami", web::get().to(whoami))
+            .route("/integrations/inbound/{hook_id}", web::get().to(whoami))
+            .route("/integrations/slack/command", web::get().to(whoami))
+            .route("/integrations/slack/link", web::get().to(whoami)),
+    )
+    .await;
+
+    for (uri, expected) in &[
+        ("/static/whoami", "anonymous"),
+        ("/integrations/inbound/abc", "anonymous"),
+        ("/integrations/slack/command", "anonymous"),
+        ("/integrations/slack/link", "alice"),
+    ] {
+        let req = test::TestRequest::get()
+            .uri(uri)
+            .cookie(cookie.clone())
+            .to_request();
+        assert_eq!(test::read_response(&app, req).await, *expected, "{}", uri);
+    }
+}
+
+/// Test that sessions are extended once they've been used for a while, and
+/// that remembered sessions get a new cookie.
+#[actix_rt::test]
+async fn test_session_refresh() {
+    let (_srv, app_state) = setup_app(None);
+    login_user(&app_state, "alice").await;
+
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let app = test::init_service(
+        App::new()
+            .wrap(
+                AuthenticateUser::new(app_state.database.clone(), app_state.auth_cache.clone())
+                    .with_session_refresh(SessionRefresh {
+                        refresh_after: Duration::from_secs(60 * 60),
+                        session_lifetime: Duration::from_secs(24 * 60 * 60),
+                        cookies: CookieSettings::default(),
+                        web_root: String::new(),
+                    }),
+            )
+            .wrap(MiddlewareLogger::new(logger))
+            .route("/whoami", web::get().to(whoami)),
+    )
+    .await;
+
+    let now = chrono::Utc::now();
+    let hours_ago = now - chrono::Duration::hours(2);
+
+    // (issued, lifetime, refreshed, new cookie)
+    let cases = vec![
+        (now, chrono::Duration::days(14), false, false),
+        (hours_ago, chrono::Duration::days(1), true, false),
+        (hours_ago, chrono::Duration::days(14), true, true),
+    ];
+
+    for (issued, lifetime, refreshed, new_cookie) in cases {
+        let token = app_state
+            .database
+            .create_token_for_user(
+                "alice".to_owned(),
+                vec![],
+                issued + lifetime,
+                Outbox::none(),
+            )
+            .await
+            .unwrap();
+        app_state
+            .database
+            .refresh_token(token.clone(), issued, issued + lifetime)
+            .await
+            .unwrap();
+
+        let req = test::TestRequest::get()
+            .uri("/whoami")
+            .cookie(Cookie::new("token", token.clone()))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.headers().contains_key("set-cookie"), new_cookie);
+        if new_cookie {
+            let cookie = res.headers().get("set-cookie").unwrap().to_str().unwrap();
+            assert!(
+                cookie.starts_with(&format!("token={};", token)),
+                "{}",
+                cookie
+            );
+            assert!(cookie.contains("Expires="), "{}", cookie);
+        }
+        assert_eq!(test::read_body(res).await, "alice");
+
+        let user = app_state
+            .database
+            .get_user_from_token(token)
+            .await
+            .unwrap()
+            .unwrap();
+        let expires = user.expires.unwrap();
+        if refreshed {
+            assert!(expires >= now + lifetime - chrono::Duration::seconds(1));
+        } else {
+            assert_eq!(expires.timestamp(), (issued + lifetime).timestamp());
+        }
+    }
+}
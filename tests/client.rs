@@ -0,0 +1,65 @@
+#![cfg(feature = "client")]
+
+use reqwest::StatusCode;
+
+use shaft::amount::AmountInput;
+use shaft::api_types::ShaftUserBody;
+use shaft::client::{Client, ClientError};
+use shaft::db::TransactionKind;
+
+mod common;
+
+use common::{login_user, setup_app_with_config, test_config};
+
+#[actix_rt::test]
+async fn test_client() {
+    let (srv, app_state) = setup_app_with_config(test_config(), None);
+    let token = login_user(&app_state, "alice").await.value().to_string();
+    login_user(&app_state, "bob").await;
+
+    let client = Client::new(&srv.url("/"), token).unwrap();
+
+    let transaction_id = client
+        .shaft(&ShaftUserBody {
+            other_user: "bob".to_string(),
+            amount: AmountInput::Text("1.50".to_string()),
+            reason: "Coffee".to_string(),
+        })
+        .await
+        .unwrap();
+
+    let balances = client.balances().await.unwrap();
+    assert_eq!(balances.len(), 2);
+    assert_eq!(balances[0].user_id, "bob");
+    assert_eq!(balances[0].balance, -150);
+    assert_eq!(balances[1].user_id, "alice");
+    assert_eq!(balances[1].balance, 150);
+
+    let transactions = client.transactions().await.unwrap();
+    assert_eq!(transactions.len(), 1);
+    assert_eq!(transactions[0].id, transaction_id);
+    assert_eq!(transactions[0].shafter, "alice");
+    assert_eq!(transactions[0].shaftee, "bob");
+    assert_eq!(transactions[0].amount, 150);
+    assert_eq!(transactions[0].reason, "Coffee");
+    assert_eq!(transactions[0].kind, TransactionKind::Shaft);
+
+    let err = client
+        .shaft(&ShaftUserBody {
+            other_user: "carol".to_string(),
+            amount: AmountInput::Minor(100),
+            reason: "Lunch".to_string(),
+        })
+        .await
+        .unwrap_err();
+    match err {
+        ClientError::ApiError { status, kind, .. } => {
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert_eq!(kind, "DatabaseError");
+        }
+        err => panic!("Unexpected error: {}", err),
+    }
+
+    let client = Client::new(&srv.url("/"), "not-a-token").unwrap();
+    assert!(client.balances().await.is_err());
+}
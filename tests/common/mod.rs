@@ -0,0 +1,169 @@
+//! Helpers shared between the integration tests.
+
+use actix_web::web;
+use awc::cookie::Cookie;
+use handlebars::Handlebars;
+
+use std::sync::Arc;
+
+use shaft::db::{Outbox, Scope, SqliteDatabase};
+use shaft::http_client::MockGenericHttpClient;
+use shaft::money::MoneyHelper;
+use shaft::rest::{
+    register_helpers, register_tenant_servlets, AppConfig, AppState, AuthenticateUser,
+    MiddlewareLogger,
+};
+use shaft::settings::{
+    ApiSettings, BalancesSettings, CookieSettings, CurrencySettings, HttpServerSettings,
+    OutboxSettings, ReasonSettings, UiSettings,
+};
+
+pub fn setup_app(http_client: Option<MockGenericHttpClient>) -> (actix_test::TestServer, AppState) {
+    setup_app_with_config(test_config(), http_client)
+}
+
+/// The config used by [setup_app].
+pub fn test_config() -> AppConfig {
+    AppConfig {
+        github_client_id: "fake_client_id".to_owned(),
+        github_client_secret: "fake_client_secret".to_owned(),
+        github_state: "fake_state".to_owned(),
+        web_root: String::new(),
+        required_org: "fake_org".to_owned(),
+        resource_dir: "res".to_owned(),
+        admins: Vec::new(),
+        session_lifetime_secs: 24 * 60 * 60,
+        remember_me_lifetime_secs: 14 * 24 * 60 * 60,
+        slack: None,
+        invites: None,
+        currency: CurrencySettings::default(),
+        reasons: ReasonSettings::default(),
+        balances: BalancesSettings::default(),
+        ui: UiSettings::default(),
+        api: ApiSettings::default(),
+        cookies: CookieSettings::default(),
+        webhooks: Vec::new(),
+        outbox: OutboxSettings::default(),
+    }
+}
+
+/// The templates in `res`, with the helpers they use, as loaded by `main`.
+pub fn templates() -> Handlebars<'static> {
+    let mut hb = Handlebars::new();
+    for name in &[
+        "admin",
+        "base",
+        "index",
+        "login",
+        "login_error",
+        "logout",
+        "setup",
+        "slack_link",
+        "transactions",
+    ] {
+        hb.register_template_file(name, format!("res/{}.hbs", name))
+            .unwrap();
+    }
+    hb.register_helper(
+        "pence-as-pounds",
+        Box::new(MoneyHelper::new(CurrencySettings::default())),
+    );
+    register_helpers(&mut hb, &UiSettings::default()).unwrap();
+    hb
+}
+
+pub fn setup_app_with_config(
+    config: AppConfig,
+    http_client: Option<MockGenericHttpClient>,
+) -> (actix_test::TestServer, AppState) {
+    let database = SqliteDatabase::with_path(":memory:");
+    database.migrate().unwrap();
+
+    setup_app_with_database(config, database, http_client)
+}
+
+pub fn setup_app_with_database(
+    config: AppConfig,
+    database: SqliteDatabase,
+    http_client: Option<MockGenericHttpClient>,
+) -> (actix_test::TestServer, AppState) {
+    let mock_http_client = http_client.unwrap_or_default();
+
+    let app_state = AppState::new(
+        config,
+        templates(),
+        Arc::new(database),
+        Arc::new(mock_http_client),
+    );
+
+    start_app(app_state)
+}
+
+/// The authentication middleware for an app state whose routes are under the
+/// given path prefix, as set up by `main`.
+fn authenticate_user(state: &AppState, prefix: &str) -> AuthenticateUser {
+    let skipped_paths = HttpServerSettings::default()
+        .skip_auth_paths
+        .iter()
+        .map(|path| format!("{}{}", prefix, path))
+        .collect();
+
+    AuthenticateUser::new(state.database.clone(), state.auth_cache.clone())
+        .with_rate_limiter(state.rate_limiter.clone())
+        .with_skipped_paths(skipped_paths)
+}
+
+/// Start a test server for the given app state, along with its tenants.
+///
+/// The server's client doesn't follow redirects, so that tests can check
+/// where they go.
+pub fn start_app(app_state: AppState) -> (actix_test::TestServer, AppState) {
+    let drain = slog::Discard;
+    let logger = slog::Logger::root(drain, slog::o!());
+    let logger_middleware = MiddlewareLogger::new(logger).with_metrics(app_state.metrics.clone());
+
+    let state = app_state.clone();
+    let config = actix_test::config().disable_redirects();
+    let srv = actix_test::start_with(config, move || {
+        actix_web::App::new()
+            .app_data(web::Data::new(state.clone()))
+            .wrap(logger_middleware.clone())
+            .configure(|config| register_tenant_servlets(config, &state, &authenticate_user))
+    });
+
+    (srv, app_state)
+}
+
+/// Create a user with the given ID and return a session cookie for them.
+#[allow(dead_code)]
+pub async fn login_user(app_state: &AppState, user_id: &str) -> Cookie<'static> {
+    login_user_with_scopes(app_state, user_id, vec![Scope::Read, Scope::Write]).await
+}
+
+/// Create a user with the given ID and return a session cookie for them with
+/// the given scopes.
+#[allow(dead_code)]
+pub async fn login_user_with_scopes(
+    app_state: &AppState,
+    user_id: &str,
+    scopes: Vec<Scope>,
+) -> Cookie<'static> {
+    app_state
+        .database
+        .add_user_by_github_id(user_id.to_owned(), user_id.to_owned(), Outbox::none())
+        .await
+        .unwrap();
+
+    let token = app_state
+        .database
+        .create_token_for_user(
+            user_id.to_owned(),
+            scopes,
+            chrono::Utc::now() + chrono::Duration::days(1),
+            Outbox::none(),
+        )
+        .await
+        .unwrap();
+
+    Cookie::new("token", token)
+}
@@ -0,0 +1,226 @@
+use toml::value::{Table, Value};
+
+use shaft::config_bundle::{BundleError, BundleFormat, ConfigBundle};
+use shaft::features::FeatureFlags;
+use shaft::settings::FeatureSettings;
+
+use std::path::Path;
+
+fn table(text: &str) -> Table {
+    toml::from_str(text).unwrap()
+}
+
+const OLD_HOST: &str = r#"
+bind = "127.0.0.1:8975"
+public_read = true
+
+[github]
+client_id = "client_id"
+client_secret = "old_secret"
+state = "old_state"
+required_org = "org"
+
+[slack]
+signing_secret = "old_slack_secret"
+public_url = "https://shaft.example.com"
+
+[currency]
+symbol = "€"
+
+[redis]
+url = "redis://:old_redis_password@redis.example.com"
+"#;
+
+/// Test that bundles leave out secrets, survive a round trip in both formats,
+/// and take secrets from the new host's config when imported.
+#[test]
+fn test_config_bundle_round_trip() {
+    let mut features = FeatureSettings::default();
+    features.categories = true;
+    let bundle = ConfigBundle::new(table(OLD_HOST), &FeatureFlags::new(&features));
+
+    assert_eq!(
+        bundle.redacted,
+        vec![
+            "github.client_secret",
+            "github.state",
+            "slack.signing_secret",
+            "redis.url",
+        ]
+    );
+    assert_eq!(bundle.features.get("categories"), Some(&true));
+    assert_eq!(bundle.features.get("webhooks"), Some(&false));
+
+    for format in &[BundleFormat::Toml, BundleFormat::Json] {
+        let text = bundle.write(*format).unwrap();
+        assert!(!text.contains("old_secret"), "{}", text);
+        assert!(!text.contains("old_state"), "{}", text);
+        assert!(!text.contains("old_redis_password"), "{}", text);
+        assert_eq!(ConfigBundle::parse(&text, *format).unwrap(), bundle);
+    }
+
+    let new_host = table(
+        r#"
+[github]
+client_secret = "new_secret"
+state = "new_state"
+
+[slack]
+signing_secret = "new_slack_secret"
+
+[redis]
+url = "redis://:new_redis_password@redis.example.com"
+"#,
+    );
+    let imported = bundle.clone().into_settings(&new_host).unwrap();
+    assert!(imported.missing_secrets.is_empty());
+
+    let settings = imported.settings;
+    let github = settings["github"].as_table().unwrap();
+    assert_eq!(github["client_id"], Value::from("client_id"));
+    assert_eq!(github["client_secret"], Value::from("new_secret"));
+    assert_eq!(github["state"], Value::from("new_state"));
+    assert_eq!(
+        settings["slack"]["signing_secret"],
+        Value::from("new_slack_secret")
+    );
+    assert_eq!(settings["currency"]["symbol"], Value::from("€"));
+    assert_eq!(settings["features"]["categories"], Value::from(true));
+    assert!(!settings.contains_key("public_read"));
+
+    // Secrets the new host doesn't have are reported rather than guessed.
+    let imported = bundle
+        .into_settings(&table("[github]\nclient_secret = \"new_secret\"\n"))
+        .unwrap();
+    assert_eq!(
+        imported.missing_secrets,
+        vec!["github.state", "slack.signing_secret", "redis.url"]
+    );
+}
+
+#[test]
+fn test_config_bundle_errors() {
+    let bundle = ConfigBundle::new(table(OLD_HOST), &FeatureFlags::default());
+
+    let mut future = bundle.clone();
+    future.version += 1;
+    let text = future.write(BundleFormat::Json).unwrap();
+    assert!(matches!(
+        ConfigBundle::parse(&text, BundleFormat::Json),
+        Err(BundleError::UnsupportedVersion { .. })
+    ));
+
+    let mut unknown = bundle.clone();
+    unknown.features.insert("teleport".to_string(), true);
+    assert!(matches!(
+        unknown.into_settings(&table(OLD_HOST)),
+        Err(BundleError::UnknownFeature { .. })
+    ));
+
+    // The settings are checked once every secret is filled in.
+    let mut invalid = bundle;
+    invalid
+        .settings
+        .get_mut("github")
+        .and_then(Value::as_table_mut)
+        .unwrap()
+        .remove("client_id");
+    assert!(matches!(
+        invalid.into_settings(&table(OLD_HOST)),
+        Err(BundleError::InvalidSettings { .. })
+    ));
+
+    assert_eq!(
+        BundleFormat::from_path(Path::new("bundle.JSON")),
+        BundleFormat::Json
+    );
+    assert_eq!(
+        BundleFormat::from_path(Path::new("bundle.toml")),
+        BundleFormat::Toml
+    );
+}
+
+const TENANTS_HOST: &str = r#"
+bind = "127.0.0.1:8975"
+
+[github]
+client_id = "client_id"
+client_secret = "old_secret"
+state = "old_state"
+required_org = "org"
+
+[[webhooks]]
+id = "ci"
+url = "https://ci.example.com/hook"
+secret = "old_webhook_secret"
+
+[[tenants]]
+name = "flat"
+database_file = "flat.db"
+
+[tenants.github]
+client_id = "tenant_client_id"
+client_secret = "old_tenant_secret"
+state = "old_tenant_state"
+required_org = "org"
+
+[[tenants.webhooks]]
+id = "chat"
+url = "https://chat.example.com/hook"
+secret = "old_tenant_webhook_secret"
+"#;
+
+/// Test that secrets within arrays of tables, e.g. each webhook's, are left
+/// out, and taken from the matching entry in the new host's config.
+#[test]
+fn test_config_bundle_arrays() {
+    let bundle = ConfigBundle::new(table(TENANTS_HOST), &FeatureFlags::default());
+
+    assert_eq!(
+        bundle.redacted,
+        vec![
+            "github.client_secret",
+            "github.state",
+            "webhooks.0.secret",
+            "tenants.0.github.client_secret",
+            "tenants.0.github.state",
+            "tenants.0.webhooks.0.secret",
+        ]
+    );
+    let text = bundle.write(BundleFormat::Toml).unwrap();
+    assert!(!text.contains("old_"), "{}", text);
+    assert!(text.contains("tenant_client_id"), "{}", text);
+
+    let new_host = table(&TENANTS_HOST.replace("old_", "new_"));
+    let imported = bundle.clone().into_settings(&new_host).unwrap();
+    assert!(imported.missing_secrets.is_empty());
+
+    let settings = imported.settings;
+    assert_eq!(
+        settings["webhooks"][0]["secret"],
+        Value::from("new_webhook_secret")
+    );
+    let tenant = &settings["tenants"][0];
+    assert_eq!(tenant["name"], Value::from("flat"));
+    assert_eq!(
+        tenant["github"]["client_secret"],
+        Value::from("new_tenant_secret")
+    );
+    assert_eq!(tenant["github"]["state"], Value::from("new_tenant_state"));
+    assert_eq!(
+        tenant["webhooks"][0]["secret"],
+        Value::from("new_tenant_webhook_secret")
+    );
+
+    // A host without the webhook can't supply its secret.
+    let imported = bundle.into_settings(&table(OLD_HOST)).unwrap();
+    assert_eq!(
+        imported.missing_secrets,
+        vec![
+            "webhooks.0.secret",
+            "tenants.0.github.client_secret",
+            "tenants.0.github.state",
+            "tenants.0.webhooks.0.secret",
+        ]
+    );
+}
@@ -0,0 +1,214 @@
+use std::sync::Arc;
+
+use shaft::data_dir::{bootstrap_admin, needs_setup, DataDir, BOOTSTRAP_ADMIN};
+use shaft::db::{Database, Outbox, Scope, SqliteDatabase};
+use shaft::http_client::MockGenericHttpClient;
+use shaft::rest::{AppState, Setup};
+use shaft::settings::{GithubSettings, Settings};
+
+mod common;
+
+use common::{start_app, test_config};
+
+/// Test that a data directory gets a demo config that gives valid settings,
+/// which is left alone once it exists.
+#[test]
+fn test_data_dir_init() {
+    let path = std::env::temp_dir().join(format!("shaft-data-dir-{}", std::process::id()));
+    let data_dir = DataDir::new(&path);
+
+    assert!(data_dir.init().unwrap());
+    let text = std::fs::read_to_string(data_dir.settings_file()).unwrap();
+
+    let settings: Settings = toml::from_str(&text).unwrap();
+    assert_eq!(
+        settings.database_file,
+        data_dir.database_file().display().to_string()
+    );
+    assert_eq!(settings.admins, vec![BOOTSTRAP_ADMIN]);
+    assert!(settings.github.client_id.is_empty());
+    assert_eq!(settings.github.state.len(), 32);
+
+    // Tokens replace each other, and are only readable by us.
+    data_dir.write_bootstrap_token("first").unwrap();
+    let token_file = data_dir.write_bootstrap_token("second").unwrap();
+    assert_eq!(std::fs::read_to_string(&token_file).unwrap(), "second");
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&token_file).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+    data_dir.remove_bootstrap_token().unwrap();
+    assert!(!token_file.exists());
+    data_dir.remove_bootstrap_token().unwrap();
+
+    std::fs::write(data_dir.settings_file(), "# Edited\n").unwrap();
+    assert!(!data_dir.init().unwrap());
+    assert_eq!(
+        std::fs::read_to_string(data_dir.settings_file()).unwrap(),
+        "# Edited\n"
+    );
+
+    std::fs::remove_dir_all(path).unwrap();
+}
+
+/// Test that an expiring admin token is only created for an instance with no
+/// other users.
+#[actix_rt::test]
+async fn test_bootstrap_admin() {
+    let database = SqliteDatabase::with_path(":memory:");
+    database.migrate().unwrap();
+
+    let token = bootstrap_admin(&database)
+        .await
+        .unwrap()
+        .expect("a bootstrap token");
+
+    let user = database
+        .get_user_from_token(token.clone())
+        .await
+        .unwrap()
+        .expect("a valid token");
+    assert_eq!(user.user_id, BOOTSTRAP_ADMIN);
+    assert!(user.scopes.contains(&Scope::Admin));
+    let expires = user.expires.expect("an expiring token");
+    assert!(expires <= chrono::Utc::now() + chrono::Duration::days(1));
+
+    // Restarting before setup gives a new token.
+    let second = bootstrap_admin(&database)
+        .await
+        .unwrap()
+        .expect("another bootstrap token");
+    assert_ne!(second, token);
+
+    database
+        .add_user_by_github_id("alice".to_string(), "Alice".to_string(), Outbox::none())
+        .await
+        .unwrap();
+    assert_eq!(bootstrap_admin(&database).await.unwrap(), None);
+}
+
+/// Test that setup is only offered until GitHub login or real users exist.
+#[actix_rt::test]
+async fn test_needs_setup() {
+    let database = SqliteDatabase::with_path(":memory:");
+    database.migrate().unwrap();
+
+    let mut github = GithubSettings {
+        client_id: String::new(),
+        client_secret: String::new(),
+        state: "state".to_string(),
+        required_org: String::new(),
+    };
+    assert!(needs_setup(&database, &github).await.unwrap());
+
+    bootstrap_admin(&database).await.unwrap();
+    assert!(needs_setup(&database, &github).await.unwrap());
+
+    database
+        .add_user_by_github_id("alice".to_string(), "Alice".to_string(), Outbox::none())
+        .await
+        .unwrap();
+    assert!(!needs_setup(&database, &github).await.unwrap());
+
+    let database = SqliteDatabase::with_path(":memory:");
+    database.migrate().unwrap();
+    github.client_id = "client_id".to_string();
+    assert!(!needs_setup(&database, &github).await.unwrap());
+}
+
+/// Test that the setup page needs its token, creates the first admin, saves
+/// the settings, revokes the bootstrap admin's token, and can only be
+/// completed once.
+#[actix_rt::test]
+async fn test_setup() {
+    let path = std::env::temp_dir().join(format!("shaft-setup-{}", std::process::id()));
+    let data_dir = DataDir::new(&path);
+    data_dir.init().unwrap();
+
+    let mut hb = handlebars::Handlebars::new();
+    hb.register_template_file("setup", "res/setup.hbs").unwrap();
+    let database = SqliteDatabase::with_path(":memory:");
+    database.migrate().unwrap();
+    let bootstrap_token = bootstrap_admin(&database).await.unwrap().unwrap();
+    data_dir.write_bootstrap_token(&bootstrap_token).unwrap();
+    let mut config = test_config();
+    config.admins = vec![BOOTSTRAP_ADMIN.to_string()];
+    let mut app_state = AppState::new(
+        config,
+        hb,
+        Arc::new(database),
+        Arc::new(MockGenericHttpClient::new()),
+    );
+    let setup = Arc::new(Setup::new(&data_dir));
+    let token = setup.token().unwrap();
+    app_state.setup = Some(setup.clone());
+    let (srv, app_state) = start_app(app_state);
+
+    let response = srv.get("/setup?token=wrong").send().await.unwrap();
+    assert_eq!(response.status(), 404);
+
+    let mut response = srv
+        .get(format!("/setup?token={}", token))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body = response.body().await.unwrap();
+    let body = std::str::from_utf8(&body).unwrap();
+    assert!(body.contains(&token), "{}", body);
+
+    let form = |admin_login: &'static str| {
+        vec![
+            ("token", token.clone()),
+            ("client_id", "client_id".to_string()),
+            ("client_secret", "client_secret".to_string()),
+            ("required_org", "org".to_string()),
+            ("admin_login", admin_login.to_string()),
+            ("admin_name", String::new()),
+        ]
+    };
+
+    let response = srv.post("/setup").send_form(&form(" ")).await.unwrap();
+    assert_eq!(response.status(), 400);
+
+    // If the settings can't be saved the token still works.
+    std::fs::remove_dir_all(&path).unwrap();
+    let response = srv.post("/setup").send_form(&form("alice")).await.unwrap();
+    assert!(response.status().is_server_error());
+    assert_eq!(setup.token(), Some(token.clone()));
+    std::fs::create_dir_all(&path).unwrap();
+    data_dir.write_bootstrap_token(&bootstrap_token).unwrap();
+
+    let response = srv.post("/setup").send_form(&form("alice")).await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(setup.token(), None);
+
+    let text = std::fs::read_to_string(data_dir.setup_file()).unwrap();
+    let saved: toml::Value = toml::from_str(&text).unwrap();
+    assert_eq!(saved["admins"], toml::Value::from(vec!["alice"]));
+    assert_eq!(saved["github"]["client_id"], toml::Value::from("client_id"));
+    assert_eq!(saved["github"]["required_org"], toml::Value::from("org"));
+
+    let user_id = app_state
+        .database
+        .get_user_by_github_id("alice".to_string())
+        .await
+        .unwrap();
+    assert_eq!(user_id.as_deref(), Some("alice"));
+
+    assert!(app_state
+        .database
+        .get_user_from_token(bootstrap_token)
+        .await
+        .unwrap()
+        .is_none());
+    assert!(!data_dir.bootstrap_token_file().exists());
+
+    // The token only works once.
+    let response = srv.post("/setup").send_form(&form("bob")).await.unwrap();
+    assert_eq!(response.status(), 404);
+
+    std::fs::remove_dir_all(path).unwrap();
+}
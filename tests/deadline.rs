@@ -0,0 +1,45 @@
+use actix_web::{web, App, HttpRequest};
+
+use std::time::Duration;
+
+use shaft::rest::{set_request_stage, RequestDeadline, RequestStage};
+
+/// Sleeps for longer than the deadline used in the tests.
+async fn slow() -> &'static str {
+    actix_web::rt::time::sleep(Duration::from_secs(5)).await;
+    "OK"
+}
+
+/// Like [slow], but while waiting on an upstream service.
+async fn slow_upstream(req: HttpRequest) -> &'static str {
+    set_request_stage(&req, RequestStage::Upstream);
+    slow().await
+}
+
+/// Test that requests exceeding the deadline are failed with the right status.
+#[actix_rt::test]
+async fn test_request_deadline() {
+    let srv = actix_test::start(|| {
+        App::new()
+            .wrap(RequestDeadline::new(Duration::from_millis(100)))
+            .route("/fast", web::get().to(|| async { "OK" }))
+            .route("/slow", web::get().to(slow))
+            .route("/slow_upstream", web::get().to(slow_upstream))
+    });
+
+    let response = srv.get("/fast").send().await.unwrap();
+    assert_eq!(response.status(), 200);
+
+    let response = srv.get("/slow").send().await.unwrap();
+    assert_eq!(response.status(), 503);
+    assert_eq!(
+        response
+            .headers()
+            .get("retry-after")
+            .and_then(|v| v.to_str().ok()),
+        Some("1")
+    );
+
+    let response = srv.get("/slow_upstream").send().await.unwrap();
+    assert_eq!(response.status(), 504);
+}
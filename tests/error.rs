@@ -0,0 +1,100 @@
+use actix_web::http::StatusCode;
+use actix_web::ResponseError;
+use snafu::GenerateBacktrace;
+
+use shaft::db::{DatabaseError, Scope};
+use shaft::error::ShaftError;
+use shaft::rest::RequestStage;
+
+fn database_error(source: DatabaseError) -> ShaftError {
+    ShaftError::DatabaseError {
+        source,
+        backtrace: GenerateBacktrace::generate(),
+    }
+}
+
+/// Test that each error maps to the right status code.
+#[test]
+fn test_status_codes() {
+    let cases = vec![
+        (
+            database_error(DatabaseError::Busy),
+            StatusCode::SERVICE_UNAVAILABLE,
+        ),
+        (
+            database_error(DatabaseError::UnknownUser {
+                user_id: "bob".to_string(),
+            }),
+            StatusCode::BAD_REQUEST,
+        ),
+        (
+            database_error(DatabaseError::UnknownTransaction { transaction_id: 1 }),
+            StatusCode::NOT_FOUND,
+        ),
+        (
+            database_error(DatabaseError::RevisionMismatch {
+                transaction_id: 1,
+                revision: 2,
+                expected_revision: 1,
+            }),
+            StatusCode::PRECONDITION_FAILED,
+        ),
+        (
+            database_error(DatabaseError::BackendError {
+                source: "broken".into(),
+            }),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ),
+        (
+            ShaftError::BadRequest {
+                message: "Bad".to_string(),
+            },
+            StatusCode::BAD_REQUEST,
+        ),
+        (
+            ShaftError::Forbidden { reason: "No" },
+            StatusCode::FORBIDDEN,
+        ),
+        (
+            ShaftError::MissingScope {
+                scope: Scope::Admin,
+            },
+            StatusCode::FORBIDDEN,
+        ),
+        (
+            ShaftError::NotFound {
+                what: "transaction",
+            },
+            StatusCode::NOT_FOUND,
+        ),
+        (
+            ShaftError::MissingIfMatch,
+            StatusCode::PRECONDITION_REQUIRED,
+        ),
+        (
+            ShaftError::PayloadTooLarge { limit: 1 },
+            StatusCode::PAYLOAD_TOO_LARGE,
+        ),
+        (
+            ShaftError::MissingAppData { name: "AppState" },
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ),
+        (
+            ShaftError::DeadlineExceeded {
+                stage: RequestStage::Upstream,
+            },
+            StatusCode::GATEWAY_TIMEOUT,
+        ),
+        (
+            ShaftError::DeadlineExceeded {
+                stage: RequestStage::Handling,
+            },
+            StatusCode::SERVICE_UNAVAILABLE,
+        ),
+    ];
+
+    for (err, status) in cases {
+        assert_eq!(err.status_code(), status, "{}", err.kind());
+        assert_eq!(err.error_response().status(), status, "{}", err.kind());
+    }
+}
@@ -0,0 +1,117 @@
+use actix_web::{web, App, HttpResponse};
+use futures::future::{self, BoxFuture, FutureExt};
+use hyper::{Body, Request, Response};
+
+use std::sync::{Arc, Mutex};
+
+use shaft::db::Scope;
+use shaft::error::ShaftError;
+use shaft::error_reporting::{ErrorReport, ErrorReporter, SentryReporter};
+use shaft::http_client::{HttpError, MockGenericHttpClient};
+use shaft::rest::{MiddlewareLogger, ReportErrors};
+
+/// An [ErrorReporter] that remembers what it was sent.
+#[derive(Default)]
+struct RecordingReporter {
+    reports: Mutex<Vec<ErrorReport>>,
+}
+
+impl ErrorReporter for RecordingReporter {
+    fn report(&self, report: ErrorReport) -> BoxFuture<'static, Result<(), HttpError>> {
+        self.reports.lock().unwrap().push(report);
+        future::ok(()).boxed()
+    }
+}
+
+async fn broken() -> Result<HttpResponse, ShaftError> {
+    Err(ShaftError::MissingAppData { name: "test" })
+}
+
+async fn forbidden() -> Result<HttpResponse, ShaftError> {
+    Err(ShaftError::MissingScope {
+        scope: Scope::Admin,
+    })
+}
+
+/// Test that only server errors are reported, with details of the request.
+#[actix_rt::test]
+async fn test_report_errors() {
+    let reporter = Arc::new(RecordingReporter::default());
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+
+    let middleware = ReportErrors::new(reporter.clone());
+    let srv = actix_test::start(move || {
+        App::new()
+            .wrap(middleware.clone())
+            .wrap(MiddlewareLogger::new(logger.clone()))
+            .route("/broken", web::get().to(broken))
+            .route("/forbidden", web::get().to(forbidden))
+    });
+
+    let response = srv.get("/forbidden").send().await.unwrap();
+    assert_eq!(response.status(), 403);
+    assert!(reporter.reports.lock().unwrap().is_empty());
+
+    let response = srv.get("/broken").send().await.unwrap();
+    assert_eq!(response.status(), 500);
+
+    let reports = reporter.reports.lock().unwrap();
+    assert_eq!(reports.len(), 1);
+    assert_eq!(reports[0].kind, "MissingAppData");
+    assert_eq!(reports[0].status, 500);
+    assert_eq!(reports[0].method, "GET");
+    assert_eq!(reports[0].path, "/broken");
+    assert!(reports[0].request_id.is_some());
+    assert_eq!(reports[0].user_id, None);
+}
+
+/// Test that errors are sent to the store endpoint for the DSN.
+#[actix_rt::test]
+async fn test_sentry_reporter() {
+    let mut mock_http_client = MockGenericHttpClient::new();
+
+    mock_http_client
+        .expect_request()
+        .withf(|req: &Request<Body>| {
+            req.method() == "POST"
+                && req.uri().to_string() == "https://sentry.example.com/sentry/api/42/store/"
+                && req
+                    .headers()
+                    .get("X-Sentry-Auth")
+                    .and_then(|value| value.to_str().ok())
+                    .map_or(false, |value| value.ends_with("sentry_key=public"))
+        })
+        .returning(
+            |_| -> BoxFuture<'static, Result<Response<Body>, HttpError>> {
+                future::ok(Response::new(Body::empty())).boxed()
+            },
+        );
+
+    let reporter = SentryReporter::new(
+        "https://public@sentry.example.com/sentry/42",
+        Some("test".to_string()),
+        Arc::new(mock_http_client),
+    )
+    .unwrap();
+
+    reporter
+        .report(ErrorReport {
+            kind: "DatabaseError",
+            message: "Database is busy".to_string(),
+            backtrace: None,
+            status: 500,
+            method: "GET".to_string(),
+            path: "/api/balances".to_string(),
+            request_id: Some(1),
+            user_id: Some("alice".to_string()),
+        })
+        .await
+        .unwrap();
+
+    assert!(SentryReporter::new(
+        "https://sentry.example.com/42",
+        None,
+        Arc::new(MockGenericHttpClient::new())
+    )
+    .is_err());
+}
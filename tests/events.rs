@@ -0,0 +1,39 @@
+use serde_json::json;
+
+use shaft::events::Event;
+
+mod common;
+
+use common::{login_user, setup_app};
+
+/// Test that creating a transaction publishes an event to subscribers.
+#[actix_rt::test]
+async fn test_transaction_created_event() {
+    let (srv, app_state) = setup_app(None);
+    let cookie = login_user(&app_state, "alice").await;
+    login_user(&app_state, "bob").await;
+
+    let mut events = app_state.events.subscribe();
+
+    let response = srv
+        .post("/api/shaft")
+        .cookie(cookie)
+        .send_json(&json!({
+            "other_user": "bob",
+            "amount": 150,
+            "reason": "Coffee",
+        }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    match events.recv().await.unwrap() {
+        Event::TransactionCreated { transaction } => {
+            assert!(transaction.id.is_some());
+            assert_eq!(transaction.shafter, "alice");
+            assert_eq!(transaction.shaftee, "bob");
+            assert_eq!(transaction.amount.minor_units(), 150);
+        }
+        event => panic!("Unexpected event: {:?}", event),
+    }
+}
@@ -1,63 +1,23 @@
-use actix_http::httpmessage::HttpMessage;
-use actix_web::test;
 use awc::cookie::SameSite;
 use bytes::Bytes;
 use futures::future::{self, BoxFuture, FutureExt, TryFutureExt};
-use handlebars::Handlebars;
-use http::header::HeaderValue;
+use http::header::{self, HeaderValue};
 use hyper::{self, Body, Request, Response};
 use serde_json::{self, json};
 use url::Url;
 
 use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
 
-use shaft::db::SqliteDatabase;
-use shaft::github::{HttpError, MockGenericHttpClient};
-use shaft::rest::{register_servlets, AppConfig, AppState, AuthenticateUser, MiddlewareLogger};
-
-const SCHEMA: &str = r#"
-    CREATE TABLE tokens ( user_id TEXT NOT NULL, token TEXT NOT NULL );
-    CREATE TABLE github_users (user_id text primary key not null, github_id text not null);
-    CREATE TABLE users ( user_id TEXT NOT NULL UNIQUE, display_name TEXT );
-    CREATE TABLE IF NOT EXISTS "transactions" (id integer primary key autoincrement not null, shafter TEXT NOT NULL, shaftee TEXT NOT NULL, amount BIGINT NOT NULL, time_sec BIGINT NOT NULL, reason TEXT NOT NULL);
-"#;
-
-fn setup_app(http_client: Option<MockGenericHttpClient>) -> (test::TestServer, AppState) {
-    let config = AppConfig {
-        github_client_id: "fake_client_id".to_owned(),
-        github_client_secret: "fake_client_secret".to_owned(),
-        github_state: "fake_state".to_owned(),
-        web_root: String::new(),
-        required_org: "fake_org".to_owned(),
-        resource_dir: "res".to_owned(),
-    };
-
-    let database = SqliteDatabase::with_path(":memory:");
-    database.run_statements(SCHEMA).unwrap();
-
-    let mock_http_client = http_client.unwrap_or_default();
-
-    let app_state =
-        AppState::with_http_client(config, Handlebars::new(), database, mock_http_client);
+use shaft::db::{SecurityEventFilter, SqliteDatabase};
+use shaft::http_client::{HostMonitor, HttpError, InstrumentedHttpClient, MockGenericHttpClient};
+use shaft::rest::AppState;
+use shaft::settings::CookieSettings;
 
-    let drain = slog::Discard;
-    let logger = slog::Logger::root(drain, slog::o!());
-    let logger_middleware = MiddlewareLogger::new(logger);
+mod common;
 
-    let state = app_state.clone();
-    let srv = test::start(move || {
-        let logger_middleware = logger_middleware.clone();
-
-        actix_web::App::new()
-            .data(state.clone())
-            .app_data(state.clone())
-            .wrap(AuthenticateUser::new(state.database.clone()))
-            .wrap_fn(move |req, srv| logger_middleware.wrap(req, srv))
-            .configure(|config| register_servlets(config, &state))
-    });
-
-    (srv, app_state)
-}
+use common::{login_user, setup_app, setup_app_with_config, start_app, test_config};
 
 #[actix_rt::test]
 async fn test_health() {
@@ -81,7 +41,25 @@ async fn test_initial_redirect() {
 
     assert_eq!(
         response.headers().get("location"),
-        Some(&HeaderValue::from_static("login"))
+        Some(&HeaderValue::from_static("/login"))
+    );
+
+    // The redirect is under the web root, so it works behind a path prefix.
+    let mut config = test_config();
+    config.web_root = "https://example.com/shaft".to_string();
+    let (srv, app_state) = setup_app_with_config(config, None);
+
+    let response = srv.get("/").send().await.unwrap();
+    assert_eq!(
+        response.headers().get("location"),
+        Some(&HeaderValue::from_static("https://example.com/shaft/login"))
+    );
+
+    let cookie = login_user(&app_state, "alice").await;
+    let response = srv.get("/").cookie(cookie).send().await.unwrap();
+    assert_eq!(
+        response.headers().get("location"),
+        Some(&HeaderValue::from_static("https://example.com/shaft/home"))
     );
 }
 
@@ -342,3 +320,400 @@ async fn test_github_callback_wrong_org() {
         std::str::from_utf8(&body).expect("valid utf8 response")
     );
 }
+
+/// Test the github callback API tells the user when GitHub rejects the code.
+#[actix_rt::test]
+async fn test_github_callback_bad_code() {
+    let mut mock_http_client = MockGenericHttpClient::new();
+
+    mock_http_client
+        .expect_request()
+        .withf(|req: &Request<Body>| {
+            req.method() == "POST" && req.uri().path() == "/login/oauth/access_token"
+        })
+        .returning(
+            |_| -> BoxFuture<'static, Result<Response<Body>, HttpError>> {
+                future::ready(
+                    Response::builder().status(200).body(
+                        serde_json::to_string(&json!({
+                            "error": "bad_verification_code",
+                            "error_description": "The code passed is incorrect or expired.",
+                        }))
+                        .unwrap()
+                        .into(),
+                    ),
+                )
+                .map_err(|source| HttpError::Http { source })
+                .boxed()
+            },
+        );
+
+    let (srv, _) = setup_app(Some(mock_http_client));
+
+    let mut response = srv
+        .get("/github/callback?code=1234&state=fake_state")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+
+    let body = response.body().await.unwrap();
+    let body = std::str::from_utf8(&body).unwrap();
+    assert!(body.contains("incorrect or expired"), "body: {}", body);
+}
+
+/// Test the github callback API reports GitHub being down as a bad gateway.
+#[actix_rt::test]
+async fn test_github_callback_github_unavailable() {
+    let mut mock_http_client = MockGenericHttpClient::new();
+
+    mock_http_client
+        .expect_request()
+        .withf(|req: &Request<Body>| {
+            req.method() == "POST" && req.uri().path() == "/login/oauth/access_token"
+        })
+        .returning(
+            |_| -> BoxFuture<'static, Result<Response<Body>, HttpError>> {
+                future::err(HttpError::Timeout).boxed()
+            },
+        );
+
+    let (srv, _) = setup_app(Some(mock_http_client));
+
+    let response = srv
+        .get("/github/callback?code=1234&state=fake_state")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 502);
+}
+
+/// Test that logins fail fast once GitHub has been failing.
+#[actix_rt::test]
+async fn test_github_callback_circuit_open() {
+    let mut mock_http_client = MockGenericHttpClient::new();
+
+    mock_http_client.expect_request().times(2).returning(
+        |_| -> BoxFuture<'static, Result<Response<Body>, HttpError>> {
+            future::err(HttpError::Timeout).boxed()
+        },
+    );
+
+    let database = SqliteDatabase::with_path(":memory:");
+    database.migrate().unwrap();
+
+    let monitor = Arc::new(HostMonitor::new(2, Duration::from_secs(60)));
+    let http_client = InstrumentedHttpClient::new(Arc::new(mock_http_client), monitor.clone());
+    let mut app_state = AppState::new(
+        test_config(),
+        handlebars::Handlebars::new(),
+        Arc::new(database),
+        Arc::new(http_client),
+    );
+    app_state.host_monitor = monitor;
+
+    let (srv, _) = start_app(app_state);
+
+    for _ in 0..2 {
+        let response = srv
+            .get("/github/callback?code=1234&state=fake_state")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 502);
+    }
+
+    // GitHub isn't asked again, which the mock checks.
+    let mut response = srv
+        .get("/github/callback?code=1234&state=fake_state")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 503);
+    let body = response.body().await.unwrap();
+    let body = std::str::from_utf8(&body).unwrap();
+    assert!(body.contains("logging in is paused"), "body: {}", body);
+
+    let mut response = srv.get("/metrics").send().await.unwrap();
+    let body = response.body().await.unwrap();
+    let body = std::str::from_utf8(&body).unwrap();
+    assert!(body.contains("shaft_http_client_errors_total{host=\"github.com\"} 2"));
+    assert!(body.contains("shaft_http_client_rejected_total{host=\"github.com\"} 1"));
+}
+
+/// A mock GitHub that grants a token for the `fake_login` user, who is a
+/// member of the required org.
+fn mock_github_member() -> MockGenericHttpClient {
+    let mut mock_http_client = MockGenericHttpClient::new();
+
+    let responses = vec![
+        (
+            "/login/oauth/access_token",
+            json!({ "access_token": "fake_token", "scope": "fake_scope" }),
+        ),
+        (
+            "/user",
+            json!({ "login": "fake_login", "name": "fake_name" }),
+        ),
+        (
+            "/user/memberships/orgs/fake_org",
+            json!({ "state": "fake_state", "role": "fake_role" }),
+        ),
+    ];
+
+    for (path, body) in responses {
+        mock_http_client
+            .expect_request()
+            .withf(move |req: &Request<Body>| req.uri().path() == path)
+            .returning(
+                move |_| -> BoxFuture<'static, Result<Response<Body>, HttpError>> {
+                    future::ok(Response::new(body.to_string().into())).boxed()
+                },
+            );
+    }
+
+    mock_http_client
+}
+
+/// Test that users are returned to the page they asked for after logging in,
+/// but only if it is within the app.
+#[actix_rt::test]
+async fn test_login_next() {
+    let (srv, app_state) = setup_app(Some(mock_github_member()));
+
+    let response = srv.get("/transactions?limit=5").send().await.unwrap();
+    assert_eq!(response.status(), 302);
+    assert_eq!(
+        response.headers().get("location"),
+        Some(&HeaderValue::from_static(
+            "/login?next=%2Ftransactions%3Flimit%3D5"
+        ))
+    );
+
+    for (next, expected_state) in &[
+        ("/transactions", "fake_state:next=%2Ftransactions"),
+        ("//evil.example.com", "fake_state"),
+        ("https://evil.example.com", "fake_state"),
+    ] {
+        let mut url = Url::parse("http://localhost/github/login").unwrap();
+        url.query_pairs_mut().append_pair("next", next);
+
+        let response = srv
+            .get(format!("/github/login?{}", url.query().unwrap()))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 302);
+
+        let location = Url::parse(
+            response
+                .headers()
+                .get(header::LOCATION)
+                .unwrap()
+                .to_str()
+                .unwrap(),
+        )
+        .unwrap();
+        let state = location
+            .query_pairs()
+            .find(|(key, _)| key == "state")
+            .map(|(_, value)| value.into_owned());
+        assert_eq!(state.as_deref(), Some(*expected_state), "next: {}", next);
+    }
+
+    let response = srv
+        .get("/github/callback?code=1234&state=fake_state_other")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+
+    let events = app_state
+        .database
+        .get_security_events(SecurityEventFilter {
+            limit: 10,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].kind, "login.state_mismatch");
+
+    let response = srv
+        .get("/github/callback?code=1234&state=fake_state%3Anext%3D%252Ftransactions")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 302);
+    assert_eq!(
+        response.headers().get("location"),
+        Some(&HeaderValue::from_static("/transactions"))
+    );
+}
+
+/// Test that users get a session cookie unless they ask to stay logged in.
+#[actix_rt::test]
+async fn test_login_remember_me() {
+    let (srv, _) = setup_app(Some(mock_github_member()));
+
+    let response = srv
+        .get("/github/login?next=%2Ftransactions&remember=true")
+        .send()
+        .await
+        .unwrap();
+    let location = Url::parse(
+        response
+            .headers()
+            .get(header::LOCATION)
+            .unwrap()
+            .to_str()
+            .unwrap(),
+    )
+    .unwrap();
+    let state = location
+        .query_pairs()
+        .find(|(key, _)| key == "state")
+        .map(|(_, value)| value.into_owned());
+    assert_eq!(
+        state.as_deref(),
+        Some("fake_state:next=%2Ftransactions&remember=1")
+    );
+
+    let response = srv
+        .get("/github/callback?code=1234&state=fake_state")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 302);
+    let cookies = response.cookies().expect("cookie");
+    assert_eq!(cookies[0].name(), "token");
+    assert_eq!(cookies[0].expires(), None);
+
+    let response = srv
+        .get("/github/callback?code=1234&state=fake_state%3Aremember%3D1")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 302);
+    let cookies = response.cookies().expect("cookie");
+    assert_eq!(cookies[0].name(), "token");
+    assert!(cookies[0].expires().is_some());
+}
+
+/// Test that the session cookie's attributes follow the settings.
+#[actix_rt::test]
+async fn test_login_cookie_settings() {
+    let mut config = test_config();
+    config.cookies = CookieSettings {
+        secure: false,
+        same_site: shaft::settings::SameSite::Strict,
+    };
+    let (srv, _) = setup_app_with_config(config, Some(mock_github_member()));
+
+    let response = srv
+        .get("/github/callback?code=1234&state=fake_state")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 302);
+    let cookies = response.cookies().expect("cookie");
+    assert_eq!(cookies[0].name(), "token");
+    assert_eq!(cookies[0].http_only(), Some(true));
+    assert_eq!(cookies[0].secure(), None);
+    assert_eq!(cookies[0].same_site(), Some(SameSite::Strict));
+}
+
+/// Test that the login page offers each provider, and passes on where to go
+/// next.
+#[actix_rt::test]
+async fn test_login_page() {
+    let mut hb = handlebars::Handlebars::new();
+    hb.register_template_file("login", "res/login.hbs").unwrap();
+    let database = SqliteDatabase::with_path(":memory:");
+    database.migrate().unwrap();
+    let app_state = AppState::new(
+        test_config(),
+        hb,
+        Arc::new(database),
+        Arc::new(MockGenericHttpClient::new()),
+    );
+    let (srv, _) = start_app(app_state);
+
+    let mut response = srv.get("/login?next=%2Ftransactions").send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    let body = response.body().await.unwrap();
+    let body = std::str::from_utf8(&body).unwrap();
+    assert!(body.contains(r#"formaction="github/login""#), "{}", body);
+    assert!(body.contains("Login with GitHub"), "{}", body);
+    assert!(body.contains(r#"value="/transactions""#), "{}", body);
+}
+
+/// Test that GET only asks for confirmation, and that POST logs out and
+/// redirects within the app.
+#[actix_rt::test]
+async fn test_logout() {
+    let mut hb = handlebars::Handlebars::new();
+    hb.register_template_file("logout", "res/logout.hbs")
+        .unwrap();
+    let database = SqliteDatabase::with_path(":memory:");
+    database.migrate().unwrap();
+    let app_state = AppState::new(
+        test_config(),
+        hb,
+        Arc::new(database),
+        Arc::new(MockGenericHttpClient::new()),
+    );
+    let (srv, app_state) = start_app(app_state);
+    let cookie = login_user(&app_state, "alice").await;
+
+    let mut response = srv
+        .get("/logout?redirect=%2Ftransactions")
+        .cookie(cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert!(response.headers().get("set-cookie").is_none());
+    let body = response.body().await.unwrap();
+    let body = std::str::from_utf8(&body).unwrap();
+    assert!(
+        body.contains(r#"action="/logout?redirect=%2Ftransactions""#),
+        "{}",
+        body
+    );
+
+    let response = srv
+        .get("/api/me")
+        .cookie(cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let response = srv
+        .post("/logout?redirect=%2Ftransactions")
+        .cookie(cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 302);
+    assert_eq!(
+        response.headers().get("location"),
+        Some(&HeaderValue::from_static("/transactions"))
+    );
+
+    // Logged out users are sent to the login page.
+    let response = srv.get("/api/me").cookie(cookie).send().await.unwrap();
+    assert_eq!(response.status(), 302);
+
+    let response = srv
+        .post("/logout?redirect=https%3A%2F%2Fevil.example.com")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 302);
+    assert_eq!(
+        response.headers().get("location"),
+        Some(&HeaderValue::from_static("/"))
+    );
+}
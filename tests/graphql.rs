@@ -0,0 +1,123 @@
+use serde_json::json;
+use shaft::db::Scope;
+
+mod common;
+
+use common::{login_user, login_user_with_scopes, setup_app};
+
+/// Test the shaft mutation, and querying users and transactions.
+#[actix_rt::test]
+async fn test_graphql() {
+    let (srv, app_state) = setup_app(None);
+    let cookie = login_user(&app_state, "alice").await;
+    login_user(&app_state, "bob").await;
+
+    for amount in &[150, 250, 350] {
+        let mut response = srv
+            .post("/graphql")
+            .cookie(cookie.clone())
+            .send_json(&json!({
+                "query": "mutation($amount: Int!) {
+                    shaft(otherUser: \"bob\", amount: $amount, reason: \"Coffee\") { id amount }
+                }",
+                "variables": { "amount": amount },
+            }))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["errors"], serde_json::Value::Null);
+        assert_eq!(body["data"]["shaft"]["amount"], *amount);
+    }
+
+    let mut response = srv
+        .post("/graphql")
+        .cookie(cookie.clone())
+        .send_json(&json!({
+            "query": "{
+                me { userId balance }
+                user(userId: \"bob\") { displayName balance }
+                transactions(user: \"bob\", limit: 2) {
+                    transactions { amount shafter shaftee }
+                    nextBefore
+                }
+            }",
+        }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(
+        body["data"]["me"],
+        json!({ "userId": "alice", "balance": 750 })
+    );
+    assert_eq!(
+        body["data"]["user"],
+        json!({ "displayName": "bob", "balance": -750 })
+    );
+
+    let page = &body["data"]["transactions"];
+    let amounts: Vec<_> = page["transactions"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|transaction| transaction["amount"].as_i64().unwrap())
+        .collect();
+    assert_eq!(amounts, vec![350, 250]);
+
+    let mut response = srv
+        .post("/graphql")
+        .cookie(cookie.clone())
+        .send_json(&json!({
+            "query": "query($before: Int) {
+                transactions(limit: 2, before: $before) { transactions { amount } nextBefore }
+            }",
+            "variables": { "before": page["nextBefore"] },
+        }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(
+        body["data"]["transactions"],
+        json!({ "transactions": [{ "amount": 150 }], "nextBefore": null })
+    );
+}
+
+/// Test that the shaft mutation needs the write scope.
+#[actix_rt::test]
+async fn test_graphql_read_only() {
+    let (srv, app_state) = setup_app(None);
+    let cookie = login_user_with_scopes(&app_state, "alice", vec![Scope::Read]).await;
+    login_user(&app_state, "bob").await;
+
+    let mut response = srv
+        .post("/graphql")
+        .cookie(cookie.clone())
+        .send_json(&json!({
+            "query": "mutation { shaft(otherUser: \"bob\", amount: 100, reason: \"Coffee\") { id } }",
+        }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["errors"][0]["extensions"]["kind"], "MissingScope");
+
+    let mut response = srv
+        .post("/graphql")
+        .cookie(cookie)
+        .send_json(&json!({ "query": "{ users { userId } }" }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(
+        body["data"]["users"],
+        json!([{ "userId": "alice" }, { "userId": "bob" }])
+    );
+}
@@ -0,0 +1,115 @@
+use chrono::TimeZone;
+use handlebars::Handlebars;
+use serde_json::json;
+
+use shaft::rest::{format_percent, format_relative_time, pluralise, register_helpers};
+use shaft::settings::UiSettings;
+
+fn helpers(ui: &UiSettings) -> Handlebars<'static> {
+    let mut hb = Handlebars::new();
+    register_helpers(&mut hb, ui).unwrap();
+    hb
+}
+
+#[test]
+fn test_relative_time() {
+    let now = chrono::Utc.timestamp(1_600_000_000, 0);
+    let before = |secs| format_relative_time(now - chrono::Duration::seconds(secs), now);
+
+    assert_eq!(before(0), "just now");
+    assert_eq!(before(59), "just now");
+    assert_eq!(before(-30), "just now");
+    assert_eq!(before(60), "1 minute ago");
+    assert_eq!(before(45 * 60), "45 minutes ago");
+    assert_eq!(before(2 * 60 * 60 + 59 * 60), "2 hours ago");
+    assert_eq!(before(3 * 24 * 60 * 60), "3 days ago");
+    assert_eq!(before(65 * 24 * 60 * 60), "2 months ago");
+    assert_eq!(before(400 * 24 * 60 * 60), "1 year ago");
+    assert_eq!(before(-2 * 60 * 60), "in 2 hours");
+}
+
+#[test]
+fn test_pluralise() {
+    assert_eq!(pluralise(1, "transaction", None), "1 transaction");
+    assert_eq!(pluralise(0, "transaction", None), "0 transactions");
+    assert_eq!(pluralise(-1, "pound", None), "-1 pound");
+    assert_eq!(pluralise(3, "person", Some("people")), "3 people");
+}
+
+#[test]
+fn test_format_percent() {
+    assert_eq!(format_percent(0.25, 0), "25%");
+    assert_eq!(format_percent(0.125, 1), "12.5%");
+    assert_eq!(format_percent(1.0 / 3.0, 2), "33.33%");
+    assert_eq!(format_percent(0.0, 0), "0%");
+}
+
+#[test]
+fn test_helpers() {
+    let mut hb = helpers(&UiSettings::default());
+    hb.register_template_string(
+        "page",
+        concat!(
+            "{{format-date datetime}}|",
+            "{{format-date timestamp \"%H:%M\"}}|",
+            "{{pluralise count \"person\" \"people\"}}|",
+            "{{percent rate 1}}",
+        ),
+    )
+    .unwrap();
+
+    let page = hb
+        .render(
+            "page",
+            &json!({
+                "datetime": chrono::Utc.timestamp(1_600_000_000, 0),
+                "timestamp": 1_600_000_000,
+                "count": 1,
+                "rate": 0.5,
+            }),
+        )
+        .unwrap();
+    assert_eq!(page, "13 Sep 2020|12:26|1 person|50.0%");
+
+    hb.register_template_string("relative", "{{relative-time datetime}}")
+        .unwrap();
+    let page = hb
+        .render("relative", &json!({ "datetime": chrono::Utc::now() }))
+        .unwrap();
+    assert_eq!(page, "just now");
+
+    assert!(hb
+        .render("relative", &json!({ "datetime": "yesterday" }))
+        .is_err());
+}
+
+/// Test that dates are shown in the configured locale, and that unknown
+/// locales are rejected.
+#[test]
+fn test_date_locale() {
+    let mut hb = helpers(&UiSettings {
+        locale: "fr_FR".to_string(),
+        date_format: "%d %B %Y".to_string(),
+        ..UiSettings::default()
+    });
+    hb.register_template_string("date", "{{format-date datetime}}")
+        .unwrap();
+
+    let page = hb
+        .render(
+            "date",
+            &json!({ "datetime": chrono::Utc.timestamp(1_600_000_000, 0) }),
+        )
+        .unwrap();
+    assert_eq!(page, "13 septembre 2020");
+
+    let mut hb = Handlebars::new();
+    assert!(register_helpers(
+        &mut hb,
+        &UiSettings {
+            locale: "xx_XX".to_string(),
+            ..UiSettings::default()
+        }
+    )
+    .is_err());
+}
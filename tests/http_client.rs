@@ -0,0 +1,73 @@
+use futures::future::{self, BoxFuture, FutureExt};
+use hyper::{Body, Request, Response};
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use shaft::http_client::{
+    GenericHttpClient, HostMonitor, HttpError, InstrumentedHttpClient, MockGenericHttpClient,
+};
+
+fn request() -> Request<Body> {
+    Request::get("https://api.github.com/user")
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Test that a failing host is cut off, then let back in once a trial
+/// request after the cool-down succeeds.
+#[actix_rt::test]
+async fn test_circuit_breaker() {
+    let up = Arc::new(AtomicBool::new(false));
+    let calls = Arc::new(AtomicUsize::new(0));
+
+    let mut mock_http_client = MockGenericHttpClient::new();
+    let (mock_up, mock_calls) = (up.clone(), calls.clone());
+    mock_http_client.expect_request().returning(
+        move |_| -> BoxFuture<'static, Result<Response<Body>, HttpError>> {
+            mock_calls.fetch_add(1, Ordering::SeqCst);
+            if mock_up.load(Ordering::SeqCst) {
+                future::ok(Response::new(Body::empty())).boxed()
+            } else {
+                future::err(HttpError::Timeout).boxed()
+            }
+        },
+    );
+
+    let monitor = Arc::new(HostMonitor::new(3, Duration::from_millis(100)));
+    let client = InstrumentedHttpClient::new(Arc::new(mock_http_client), monitor.clone());
+
+    for _ in 0..3 {
+        match client.request(request()).await {
+            Err(HttpError::Timeout) => {}
+            res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+        }
+    }
+
+    // The circuit is now open, so requests fail without being sent.
+    match client.request(request()).await {
+        Err(HttpError::CircuitOpen { host }) => assert_eq!(host, "api.github.com"),
+        res => panic!("Unexpected result: {:?}", res.map(|_| ())),
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+    let stats = &monitor.stats()["api.github.com"];
+    assert_eq!(stats.requests, 3);
+    assert_eq!(stats.errors, 3);
+    assert_eq!(stats.rejected, 1);
+    assert!(stats.circuit_open);
+
+    // After the cool-down a trial request is sent, and closes the circuit.
+    up.store(true, Ordering::SeqCst);
+    actix_rt::time::sleep(Duration::from_millis(150)).await;
+
+    client.request(request()).await.unwrap();
+    client.request(request()).await.unwrap();
+    assert_eq!(calls.load(Ordering::SeqCst), 5);
+
+    let stats = &monitor.stats()["api.github.com"];
+    assert_eq!(stats.requests, 5);
+    assert_eq!(stats.errors, 3);
+    assert!(!stats.circuit_open);
+}
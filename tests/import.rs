@@ -0,0 +1,203 @@
+use chrono::{NaiveDate, TimeZone};
+
+use shaft::db::{Transaction, TransactionKind};
+use shaft::import::{self, ImportError, StatementFormat, StatementLine};
+use shaft::money::{Currency, Money};
+use shaft::settings::CurrencySettings;
+
+fn line(date: (i32, u32, u32), amount: i64, description: &str) -> StatementLine {
+    StatementLine {
+        date: NaiveDate::from_ymd(date.0, date.1, date.2),
+        amount: Money::new(amount, Currency::GBP),
+        description: description.to_owned(),
+    }
+}
+
+#[test]
+fn test_parse_ofx_sgml() {
+    let statement = r#"OFXHEADER:100
+DATA:OFXSGML
+
+<OFX>
+<BANKMSGSRSV1><STMTTRNRS><STMTRS><BANKTRANLIST>
+<STMTTRN>
+<TRNTYPE>DEBIT
+<DTPOSTED>20200124120000[0:GMT]
+<TRNAMT>-12.50
+<FITID>1
+<NAME>PIZZA PLACE
+<MEMO>CARD 1234
+<STMTTRN>
+<TRNTYPE>CREDIT
+<DTPOSTED>20200125
+<TRNAMT>3
+<FITID>2
+<NAME>BOB
+</BANKTRANLIST></STMTRS></STMTTRNRS></BANKMSGSRSV1>
+</OFX>
+"#;
+
+    let lines = import::parse_statement(
+        StatementFormat::Ofx,
+        statement,
+        &CurrencySettings::default(),
+    )
+    .unwrap();
+
+    assert_eq!(
+        lines,
+        vec![
+            line((2020, 1, 24), -1250, "PIZZA PLACE CARD 1234"),
+            line((2020, 1, 25), 300, "BOB"),
+        ]
+    );
+}
+
+#[test]
+fn test_parse_ofx_xml() {
+    let statement = r#"<?xml version="1.0"?>
+<OFX><BANKTRANLIST>
+<STMTTRN><TRNTYPE>DEBIT</TRNTYPE><DTPOSTED>20200124</DTPOSTED><TRNAMT>-1.05</TRNAMT><NAME>SHOP</NAME></STMTTRN>
+</BANKTRANLIST></OFX>
+"#;
+
+    let lines = import::parse_ofx(statement, &CurrencySettings::default()).unwrap();
+
+    assert_eq!(lines, vec![line((2020, 1, 24), -105, "SHOP")]);
+}
+
+#[test]
+fn test_parse_qif() {
+    let statement = "!Type:Bank
+D24/01/2020
+T-1,200.00
+PLANDLORD
+MRent
+^
+D2020-01-25
+T4.5
+PBOB
+^
+";
+
+    let lines = import::parse_statement(
+        StatementFormat::Qif,
+        statement,
+        &CurrencySettings::default(),
+    )
+    .unwrap();
+
+    assert_eq!(
+        lines,
+        vec![
+            line((2020, 1, 24), -120000, "LANDLORD Rent"),
+            line((2020, 1, 25), 450, "BOB"),
+        ]
+    );
+}
+
+#[test]
+fn test_parse_qif_missing_amount() {
+    let statement = "!Type:Bank\nD24/01/2020\nPBOB\n^\n";
+
+    match import::parse_qif(statement, &CurrencySettings::default()) {
+        Err(ImportError::MissingField { field, .. }) => assert_eq!(field, "amount"),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn test_parse_csv() {
+    let statement = "Date,Description,Amount
+24/01/2020,\"Pizza, large\",-12.50
+2020-01-25,\"Bob \"\"the builder\"\"\",3.00
+";
+
+    let lines = import::parse_statement(
+        StatementFormat::Csv,
+        statement,
+        &CurrencySettings::default(),
+    )
+    .unwrap();
+
+    assert_eq!(
+        lines,
+        vec![
+            line((2020, 1, 24), -1250, "Pizza, large"),
+            line((2020, 1, 25), 300, "Bob \"the builder\""),
+        ]
+    );
+}
+
+#[test]
+fn test_parse_csv_bad_amount() {
+    let statement = "date,amount\n24/01/2020,twelve\n";
+
+    match import::parse_csv(statement, &CurrencySettings::default()) {
+        Err(ImportError::InvalidAmount { entry, value }) => {
+            assert_eq!(entry, 0);
+            assert_eq!(value, "twelve");
+        }
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn test_parse_csv_missing_column() {
+    match import::parse_csv("date,description\n", &CurrencySettings::default()) {
+        Err(ImportError::MissingColumn { column }) => assert_eq!(column, "amount"),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn test_unknown_format() {
+    assert!("ofx".parse::<StatementFormat>().is_ok());
+    assert!("QIF".parse::<StatementFormat>().is_ok());
+    assert!("xls".parse::<StatementFormat>().is_err());
+}
+
+#[test]
+fn test_propose_matches() {
+    let existing = vec![
+        Transaction {
+            id: Some(1),
+            shafter: "alice".to_owned(),
+            shaftee: "bob".to_owned(),
+            amount: Money::new(1250, Currency::GBP),
+            datetime: chrono::Utc.ymd(2020, 1, 23).and_hms(20, 0, 0),
+            reason: "Pizza".to_owned(),
+            kind: TransactionKind::Shaft,
+        },
+        Transaction {
+            id: Some(2),
+            shafter: "carol".to_owned(),
+            shaftee: "dave".to_owned(),
+            amount: Money::new(300, Currency::GBP),
+            datetime: chrono::Utc.ymd(2020, 1, 25).and_hms(20, 0, 0),
+            reason: "Not ours".to_owned(),
+            kind: TransactionKind::Shaft,
+        },
+    ];
+
+    let lines = vec![
+        line((2020, 1, 24), -1250, "PIZZA PLACE"),
+        // Same amount again, but the only candidate has already been matched.
+        line((2020, 1, 24), -1250, "PIZZA PLACE"),
+        // Matching amount but doesn't involve the user.
+        line((2020, 1, 25), 300, "BOB"),
+        // Matching amount but too far away.
+        line((2020, 2, 24), 1250, "PIZZA PLACE"),
+    ];
+
+    let matches = import::propose_matches("alice", lines, &existing);
+
+    assert_eq!(matches.len(), 4);
+    assert_eq!(
+        matches[0].existing.as_ref().map(|t| &t.reason as &str),
+        Some("Pizza")
+    );
+    assert!(matches[1].existing.is_none());
+    assert!(matches[2].existing.is_none());
+    assert!(matches[3].existing.is_none());
+}
@@ -0,0 +1,147 @@
+use futures::future::{self, BoxFuture, FutureExt};
+use handlebars::Handlebars;
+use serde_json::json;
+
+use std::sync::{Arc, Mutex};
+
+use shaft::db::{Scope, SqliteDatabase};
+use shaft::http_client::MockGenericHttpClient;
+use shaft::mailer::{Email, MailError, Mailer};
+use shaft::rest::AppState;
+use shaft::settings::InviteSettings;
+
+mod common;
+
+use common::{login_user, login_user_with_scopes, start_app, test_config};
+
+/// A mailer that remembers the emails it was asked to send.
+#[derive(Default)]
+struct TestMailer {
+    sent: Mutex<Vec<Email>>,
+}
+
+impl Mailer for TestMailer {
+    fn send(&self, email: Email) -> BoxFuture<'static, Result<(), MailError>> {
+        self.sent.lock().unwrap().push(email);
+        future::ok(()).boxed()
+    }
+}
+
+/// Test that ledger members can email invitations, and that following the
+/// link joins the ledger.
+#[actix_rt::test]
+async fn test_invites() {
+    let database = SqliteDatabase::with_path(":memory:");
+    database.migrate().unwrap();
+
+    let mut config = test_config();
+    config.invites = Some(InviteSettings {
+        signing_secret: "secret".to_owned(),
+        public_url: "https://shaft.example.com/".to_owned(),
+        lifetime_secs: 60 * 60,
+    });
+
+    let mut app_state = AppState::new(
+        config,
+        Handlebars::new(),
+        Arc::new(database),
+        Arc::new(MockGenericHttpClient::new()),
+    );
+    let mailer = Arc::new(TestMailer::default());
+    app_state.mailer = mailer.clone();
+
+    let (srv, app_state) = start_app(app_state);
+    let admin_cookie = login_user_with_scopes(
+        &app_state,
+        "admin",
+        vec![Scope::Read, Scope::Write, Scope::Admin],
+    )
+    .await;
+    let alice = login_user(&app_state, "alice").await;
+    let bob = login_user(&app_state, "bob").await;
+
+    let response = srv
+        .post("/api/admin/ledgers")
+        .cookie(admin_cookie.clone())
+        .send_json(&json!({ "name": "holiday", "display_name": "Holiday" }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let response = srv
+        .put("/api/admin/ledgers/holiday/members/alice")
+        .cookie(admin_cookie)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    // Only members can invite people, and only to named ledgers.
+    let response = srv
+        .post("/l/holiday/api/invites")
+        .cookie(bob.clone())
+        .send_json(&json!({ "email": "carol@example.com" }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+
+    let response = srv
+        .post("/api/invites")
+        .cookie(alice.clone())
+        .send_json(&json!({ "email": "carol@example.com" }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+
+    let response = srv
+        .post("/l/holiday/api/invites")
+        .cookie(alice.clone())
+        .send_json(&json!({ "email": "not an email" }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+    assert!(mailer.sent.lock().unwrap().is_empty());
+
+    let response = srv
+        .post("/l/holiday/api/invites")
+        .cookie(alice)
+        .send_json(&json!({ "email": "bob@example.com" }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let email = mailer.sent.lock().unwrap().pop().expect("an email");
+    assert_eq!(email.to, "bob@example.com");
+    assert!(email.subject.contains("Holiday"));
+
+    let prefix = "https://shaft.example.com/invites/accept?";
+    let start = email.text.find(prefix).expect("a link");
+    let link = email.text[start..].split_whitespace().next().unwrap();
+    let path = &link["https://shaft.example.com".len()..];
+
+    // The link needs a login, and returns to itself afterwards.
+    let response = srv.get(path).send().await.unwrap();
+    assert_eq!(response.status(), 302);
+    let location = response.headers().get("location").unwrap();
+    assert!(location.to_str().unwrap().starts_with("/login?next="));
+
+    // Tampering with the link invalidates it.
+    let tampered = path.replace("bob%40example.com", "mallory%40example.com");
+    assert_ne!(tampered, path);
+    let response = srv.get(tampered).cookie(bob.clone()).send().await.unwrap();
+    assert_eq!(response.status(), 403);
+
+    let response = srv.get(path).cookie(bob.clone()).send().await.unwrap();
+    assert_eq!(response.status(), 302);
+    assert_eq!(
+        response.headers().get("location").unwrap(),
+        "/l/holiday/home"
+    );
+
+    let response = srv
+        .get("/l/holiday/api/balances")
+        .cookie(bob)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+}
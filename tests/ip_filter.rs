@@ -0,0 +1,76 @@
+use actix_web::{web, App};
+
+use shaft::rest::{Cidr, IpFilter, IpRules};
+
+fn rules(allow: &[&str], deny: &[&str]) -> IpRules {
+    let to_strings = |list: &[&str]| list.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+    IpRules::parse(&to_strings(allow), &to_strings(deny)).unwrap()
+}
+
+#[test]
+fn test_cidr_contains() {
+    let cidr: Cidr = "192.168.1.0/24".parse().unwrap();
+    assert!(cidr.contains("192.168.1.7".parse().unwrap()));
+    assert!(!cidr.contains("192.168.2.7".parse().unwrap()));
+    assert!(cidr.contains("::ffff:192.168.1.7".parse().unwrap()));
+    assert!(!cidr.contains("fe80::1".parse().unwrap()));
+
+    let cidr: Cidr = "fd00::/8".parse().unwrap();
+    assert!(cidr.contains("fd12:3456::1".parse().unwrap()));
+    assert!(!cidr.contains("fe80::1".parse().unwrap()));
+
+    let cidr: Cidr = "10.0.0.1".parse().unwrap();
+    assert!(cidr.contains("10.0.0.1".parse().unwrap()));
+    assert!(!cidr.contains("10.0.0.2".parse().unwrap()));
+
+    let cidr: Cidr = "0.0.0.0/0".parse().unwrap();
+    assert!(cidr.contains("8.8.8.8".parse().unwrap()));
+}
+
+#[test]
+fn test_cidr_invalid() {
+    assert!("192.168.1.0/33".parse::<Cidr>().is_err());
+    assert!("192.168.1.0/x".parse::<Cidr>().is_err());
+    assert!("192.168.1/24".parse::<Cidr>().is_err());
+    assert!("fd00::/129".parse::<Cidr>().is_err());
+}
+
+#[test]
+fn test_rules() {
+    let everything = rules(&[], &[]);
+    assert!(everything.permits("8.8.8.8".parse().unwrap()));
+
+    let lan = rules(&["192.168.0.0/16"], &["192.168.66.0/24"]);
+    assert!(lan.permits("192.168.1.1".parse().unwrap()));
+    assert!(!lan.permits("192.168.66.1".parse().unwrap()));
+    assert!(!lan.permits("8.8.8.8".parse().unwrap()));
+}
+
+/// Test that the admin rules only apply to admin paths.
+#[actix_rt::test]
+async fn test_ip_filter_middleware() {
+    let srv = actix_test::start(|| {
+        App::new()
+            .wrap(IpFilter::new(
+                rules(&["127.0.0.0/8"], &[]),
+                rules(&[], &["127.0.0.1"]),
+            ))
+            .route("/health", web::get().to(|| async { "OK" }))
+            .route("/api/admin/audit", web::get().to(|| async { "OK" }))
+            .route("/admin", web::get().to(|| async { "OK" }))
+            .route("/t/flat/admin", web::get().to(|| async { "OK" }))
+            .route("/administrivia", web::get().to(|| async { "OK" }))
+    });
+
+    let response = srv.get("/health").send().await.unwrap();
+    assert_eq!(response.status(), 200);
+
+    for path in &["/api/admin/audit", "/admin", "/t/flat/admin"] {
+        let response = srv.get(*path).send().await.unwrap();
+        assert_eq!(response.status(), 403, "{}", path);
+    }
+
+    // Only the dashboard itself, not everything starting with its path.
+    let response = srv.get("/administrivia").send().await.unwrap();
+    assert_eq!(response.status(), 200);
+}
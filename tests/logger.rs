@@ -0,0 +1,151 @@
+use actix_web::HttpMessage;
+use actix_web::{test, web, App, HttpRequest};
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use shaft::rest::{Histogram, MiddlewareLogger, ReqLogger, RequestID, RouteMetrics};
+
+/// Returns the request ID the middleware assigned to the request.
+async fn request_id((req, ReqLogger(logger)): (HttpRequest, ReqLogger)) -> String {
+    slog::info!(logger, "Handling request");
+
+    let RequestID(request_id) = *req
+        .extensions()
+        .get::<RequestID>()
+        .expect("request ID installed");
+    request_id.to_string()
+}
+
+/// Test that the request ID and logger are available to handlers.
+#[actix_rt::test]
+async fn test_request_id_propagated() {
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let app = test::init_service(
+        App::new()
+            .wrap(MiddlewareLogger::new(logger))
+            .route("/request_id", web::get().to(request_id)),
+    )
+    .await;
+
+    let req = test::TestRequest::get().uri("/request_id").to_request();
+    let first = test::read_response(&app, req).await;
+    let first: u32 = std::str::from_utf8(&first).unwrap().parse().unwrap();
+
+    let req = test::TestRequest::get().uri("/request_id").to_request();
+    let second = test::read_response(&app, req).await;
+    let second: u32 = std::str::from_utf8(&second).unwrap().parse().unwrap();
+
+    // Each request gets its own ID.
+    assert_ne!(first, second);
+}
+
+/// Test that handlers needing the logger fail cleanly without the middleware.
+#[actix_rt::test]
+async fn test_missing_logger() {
+    let app = test::init_service(App::new().route("/request_id", web::get().to(request_id))).await;
+
+    let req = test::TestRequest::get().uri("/request_id").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 500);
+}
+
+/// A drain that remembers the messages logged.
+#[derive(Clone, Default)]
+struct CaptureDrain(Arc<Mutex<Vec<String>>>);
+
+impl slog::Drain for CaptureDrain {
+    type Ok = ();
+    type Err = slog::Never;
+
+    fn log(&self, record: &slog::Record, _: &slog::OwnedKVList) -> Result<(), slog::Never> {
+        self.0.lock().unwrap().push(record.msg().to_string());
+        Ok(())
+    }
+}
+
+/// Test that requests to quiet paths are only logged if they fail.
+#[actix_rt::test]
+async fn test_quiet_paths() {
+    let drain = CaptureDrain::default();
+    let logger = slog::Logger::root(drain.clone(), slog::o!());
+    let app = test::init_service(
+        App::new()
+            .wrap(MiddlewareLogger::new(logger).with_quiet_paths(vec!["/health".to_string()]))
+            .route("/health", web::get().to(|| async { "OK" }))
+            .route(
+                "/health/db",
+                web::get().to(|| async { actix_web::HttpResponse::ServiceUnavailable().finish() }),
+            )
+            .route("/home", web::get().to(|| async { "OK" })),
+    )
+    .await;
+
+    for uri in &["/health", "/health", "/health/db", "/home"] {
+        let req = test::TestRequest::get().uri(uri).to_request();
+        test::call_service(&app, req).await;
+    }
+
+    let logged = drain.0.lock().unwrap();
+    assert_eq!(*logged, vec!["Processed request", "Processed request"]);
+}
+
+/// Test that latencies are recorded against the route pattern, not the path.
+#[actix_rt::test]
+async fn test_route_metrics() {
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let metrics = Arc::new(RouteMetrics::new());
+    let app = test::init_service(
+        App::new()
+            .wrap(MiddlewareLogger::new(logger).with_metrics(metrics.clone()))
+            .route("/items/{id}", web::get().to(|| async { "OK" })),
+    )
+    .await;
+
+    for uri in &["/items/1", "/items/2", "/missing"] {
+        let req = test::TestRequest::get().uri(uri).to_request();
+        test::call_service(&app, req).await;
+    }
+
+    let snapshot = metrics.snapshot();
+    let routes: Vec<_> = snapshot.keys().cloned().collect();
+    assert_eq!(
+        routes,
+        vec![
+            ("GET".to_string(), "/items/{id}".to_string()),
+            ("GET".to_string(), "unmatched".to_string()),
+        ]
+    );
+    assert_eq!(snapshot[&routes[0]].count(), 2);
+
+    let rendered = metrics.render();
+    assert!(rendered.contains(
+        "shaft_http_request_duration_seconds_count{method=\"GET\",route=\"/items/{id}\"} 2"
+    ));
+    assert!(rendered.contains("quantile=\"0.99\""));
+}
+
+/// Test that quantiles are estimated from the buckets.
+// Plain `test` is actix's test module here.
+#[core::prelude::v1::test]
+fn test_histogram_quantiles() {
+    let mut histogram = Histogram::default();
+    assert_eq!(histogram.quantile(0.5), None);
+
+    // 90 fast requests and 10 slow ones.
+    for _ in 0..90 {
+        histogram.observe(Duration::from_micros(500));
+    }
+    for _ in 0..10 {
+        histogram.observe(Duration::from_millis(200));
+    }
+
+    let p50 = histogram.quantile(0.5).unwrap();
+    assert!(p50 > 0.0 && p50 <= 0.001, "p50 = {}", p50);
+    let p99 = histogram.quantile(0.99).unwrap();
+    assert!(p99 > 0.1 && p99 <= 0.25, "p99 = {}", p99);
+
+    // Anything slower than the last bucket is reported as its bound.
+    histogram.observe(Duration::from_secs(60));
+    assert_eq!(histogram.quantile(1.0), Some(10.0));
+}
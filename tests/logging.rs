@@ -0,0 +1,77 @@
+#[macro_use]
+extern crate slog;
+
+use slog::{Drain, Logger, OwnedKVList, Record};
+use sloggers::types::Severity;
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use shaft::logging::{LogLevelConfig, LogLevels, ModuleLevelFilter};
+
+/// A drain that remembers the messages logged.
+#[derive(Clone, Default)]
+struct CollectingDrain {
+    messages: Arc<Mutex<Vec<String>>>,
+}
+
+impl Drain for CollectingDrain {
+    type Ok = ();
+    type Err = slog::Never;
+
+    fn log(&self, record: &Record, _: &OwnedKVList) -> Result<(), slog::Never> {
+        self.messages.lock().unwrap().push(record.msg().to_string());
+        Ok(())
+    }
+}
+
+fn test_levels() -> LogLevels {
+    let mut modules = BTreeMap::new();
+    modules.insert("rest".to_string(), Severity::Debug);
+    modules.insert("rest::api".to_string(), Severity::Error);
+    modules.insert("db".to_string(), Severity::Warning);
+
+    LogLevels::new(LogLevelConfig {
+        default: Severity::Info,
+        modules,
+    })
+}
+
+/// Test that the most specific module override wins.
+#[test]
+fn test_level_for() {
+    let levels = test_levels();
+
+    assert_eq!(levels.level_for("shaft"), Severity::Info);
+    assert_eq!(levels.level_for("shaft::rest"), Severity::Debug);
+    assert_eq!(levels.level_for("shaft::rest::web"), Severity::Debug);
+    assert_eq!(levels.level_for("shaft::rest::api"), Severity::Error);
+    assert_eq!(levels.level_for("shaft::db::sqlite"), Severity::Warning);
+    // Only whole module names match.
+    assert_eq!(levels.level_for("shaft::dbx"), Severity::Info);
+}
+
+/// Test that records are filtered by their module's level, and that changes
+/// take effect immediately.
+#[test]
+fn test_module_level_filter() {
+    let drain = CollectingDrain::default();
+    let levels = LogLevels::default();
+    let logger = Logger::root(ModuleLevelFilter::new(drain.clone(), levels.clone()), o!());
+
+    // These tests are their own crate, so aren't in any `shaft` module.
+    debug!(logger, "hidden");
+    info!(logger, "shown");
+
+    let mut modules = BTreeMap::new();
+    modules.insert(module_path!().to_string(), Severity::Debug);
+    levels.set(LogLevelConfig {
+        default: Severity::Info,
+        modules,
+    });
+
+    debug!(logger, "now shown");
+    trace!(logger, "still hidden");
+
+    assert_eq!(*drain.messages.lock().unwrap(), vec!["shown", "now shown"]);
+}
@@ -0,0 +1,162 @@
+use handlebars::Handlebars;
+use serde_json::json;
+
+use shaft::money::{format, format_decimal, Currency, CurrencyCode, Money, MoneyHelper};
+use shaft::settings::CurrencySettings;
+
+fn gbp(amount: i64) -> Money {
+    Money::new(amount, Currency::GBP)
+}
+
+/// Format an amount in the settings' currency.
+fn format_in(amount: i64, settings: &CurrencySettings) -> String {
+    format(Money::new(amount, settings.currency()), settings)
+}
+
+#[test]
+fn test_format() {
+    let gbp = CurrencySettings::default();
+    assert_eq!(format_in(0, &gbp), "£0.00");
+    assert_eq!(format_in(5, &gbp), "£0.05");
+    assert_eq!(format_in(1250, &gbp), "£12.50");
+    assert_eq!(format_in(-1250, &gbp), "-£12.50");
+    assert_eq!(format_in(123_456_789, &gbp), "£1,234,567.89");
+    assert_eq!(format_in(100_000, &gbp), "£1,000.00");
+    assert_eq!(format_in(99_999, &gbp), "£999.99");
+    assert_eq!(format_in(i64::MIN, &gbp), "-£92,233,720,368,547,758.08");
+
+    let eur = CurrencySettings {
+        symbol: "€".to_string(),
+        code: "EUR".parse().unwrap(),
+        minor_units: 2,
+        thousands_separator: ".".to_string(),
+        decimal_separator: ",".to_string(),
+    };
+    assert_eq!(format_in(-123_456, &eur), "-€1.234,56");
+
+    let jpy = CurrencySettings {
+        symbol: "¥".to_string(),
+        code: "JPY".parse().unwrap(),
+        minor_units: 0,
+        thousands_separator: ",".to_string(),
+        decimal_separator: ".".to_string(),
+    };
+    assert_eq!(format_in(1_200_000, &jpy), "¥1,200,000");
+
+    let bhd = CurrencySettings {
+        symbol: "BD ".to_string(),
+        code: "BHD".parse().unwrap(),
+        minor_units: 3,
+        thousands_separator: String::new(),
+        decimal_separator: ".".to_string(),
+    };
+    assert_eq!(format_in(1_234_005, &bhd), "BD 1234.005");
+}
+
+#[test]
+fn test_format_decimal() {
+    let jpy = Currency {
+        code: "JPY".parse().unwrap(),
+        minor_units: 0,
+    };
+    let bhd = Currency {
+        code: "BHD".parse().unwrap(),
+        minor_units: 3,
+    };
+
+    assert_eq!(format_decimal(gbp(1250)), "12.50");
+    assert_eq!(format_decimal(gbp(-5)), "-0.05");
+    assert_eq!(format_decimal(gbp(123_456)), "1234.56");
+    assert_eq!(format_decimal(Money::new(1200, jpy)), "1200");
+    assert_eq!(format_decimal(Money::new(-1, bhd)), "-0.001");
+}
+
+#[test]
+fn test_to_major_units() {
+    let jpy = Currency {
+        code: "JPY".parse().unwrap(),
+        minor_units: 0,
+    };
+
+    assert_eq!(gbp(150).to_major_units(), 1.5);
+    assert_eq!(Money::new(-150, jpy).to_major_units(), -150.0);
+}
+
+#[test]
+fn test_currency_code() {
+    assert_eq!("GBP".parse::<CurrencyCode>(), Ok(CurrencyCode::GBP));
+    assert_eq!(CurrencyCode::GBP.to_string(), "GBP");
+
+    for invalid in &["", "GB", "GBPX", "gbp", "G1P", "£££"] {
+        assert!(
+            invalid.parse::<CurrencyCode>().is_err(),
+            "{:?} should be invalid",
+            invalid
+        );
+    }
+
+    let settings: CurrencySettings = serde_json::from_value(json!({ "code": "EUR" })).unwrap();
+    assert_eq!(settings.code.as_str(), "EUR");
+    assert!(serde_json::from_value::<CurrencySettings>(json!({ "code": "euro" })).is_err());
+}
+
+#[test]
+fn test_arithmetic() {
+    let mut total = gbp(100) + gbp(50) - gbp(25);
+    assert_eq!(total, gbp(125));
+
+    total -= gbp(200);
+    assert_eq!(total, gbp(-75));
+    assert!(total.is_negative());
+    assert_eq!(total.abs(), gbp(75));
+    assert_eq!(-total, gbp(75));
+
+    total += gbp(75);
+    assert!(total.is_zero());
+    assert_eq!(total, Money::zero(Currency::GBP));
+}
+
+#[test]
+#[should_panic]
+fn test_arithmetic_mixed_currencies() {
+    let eur = Currency {
+        code: "EUR".parse().unwrap(),
+        minor_units: 2,
+    };
+
+    let _ = gbp(100) + Money::new(100, eur);
+}
+
+#[test]
+fn test_serialize() {
+    assert_eq!(serde_json::to_value(gbp(-1250)).unwrap(), json!(-1250));
+
+    #[derive(serde::Serialize)]
+    struct Decimal {
+        #[serde(serialize_with = "shaft::money::serialize_decimal")]
+        amount: Money,
+    }
+
+    assert_eq!(
+        serde_json::to_value(Decimal { amount: gbp(-1250) }).unwrap(),
+        json!({ "amount": "-12.50" })
+    );
+}
+
+#[test]
+fn test_money_helper() {
+    let mut hb = Handlebars::new();
+    hb.register_helper(
+        "pence-as-pounds",
+        Box::new(MoneyHelper::new(CurrencySettings::default())),
+    );
+    hb.register_template_string("balance", "{{pence-as-pounds balance}}")
+        .unwrap();
+
+    let page = hb
+        .render("balance", &json!({ "balance": -123_456 }))
+        .unwrap();
+    assert_eq!(page, "-£1,234.56");
+
+    assert!(hb.render("balance", &json!({ "balance": "12" })).is_err());
+}
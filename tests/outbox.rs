@@ -0,0 +1,559 @@
+use futures::future::{self, BoxFuture, FutureExt};
+use handlebars::Handlebars;
+use hyper::{Body, Request, Response};
+use serde_json::json;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use shaft::db::{
+    Database, NewOutboxMessage, Outbox, OutboxAttempt, OutboxFilter, OutboxKind, OutboxRouter,
+    OutboxStatus, Scope, SqliteDatabase, Transaction, TransactionKind, DEFAULT_LEDGER_ID,
+};
+use shaft::events::Event;
+use shaft::features::Feature;
+use shaft::http_client::{HttpError, MockGenericHttpClient};
+use shaft::money::{Currency, Money};
+use shaft::rest::{dispatch_due, next_attempt, AppState};
+use shaft::settings::WebhookSettings;
+use shaft::webhooks::verify_signature;
+
+mod common;
+
+use common::{login_user, login_user_with_scopes, start_app, test_config};
+
+fn database() -> Arc<SqliteDatabase> {
+    let database = SqliteDatabase::with_path(":memory:");
+    database.migrate().unwrap();
+    Arc::new(database)
+}
+
+fn logger() -> slog::Logger {
+    slog::Logger::root(slog::Discard, slog::o!())
+}
+
+/// Test that only due pending messages are claimed, and that attempts update
+/// the status.
+#[actix_rt::test]
+async fn test_outbox_database() {
+    let database = database();
+    let now = chrono::Utc::now();
+
+    let ids = database
+        .add_outbox_messages(
+            vec![
+                NewOutboxMessage {
+                    kind: OutboxKind::Webhook,
+                    target: "hook".to_string(),
+                    payload: "{}".to_string(),
+                },
+                NewOutboxMessage {
+                    kind: OutboxKind::Notification,
+                    target: "alice".to_string(),
+                    payload: "{}".to_string(),
+                },
+            ],
+            now,
+        )
+        .await
+        .unwrap();
+    assert_eq!(ids.len(), 2);
+
+    let later = now + chrono::Duration::seconds(60);
+    database
+        .add_outbox_messages(
+            vec![NewOutboxMessage {
+                kind: OutboxKind::Webhook,
+                target: "hook".to_string(),
+                payload: "{}".to_string(),
+            }],
+            later,
+        )
+        .await
+        .unwrap();
+
+    let claimed = database.claim_outbox_messages(later, 10).await.unwrap();
+    let claimed_ids: Vec<_> = claimed.iter().map(|message| message.id).collect();
+    assert_eq!(claimed_ids, ids);
+    assert_eq!(claimed[1].kind, OutboxKind::Notification);
+
+    // Claimed messages aren't claimed again until the lease runs out.
+    assert!(database
+        .claim_outbox_messages(later, 10)
+        .await
+        .unwrap()
+        .is_empty());
+
+    database
+        .record_outbox_attempt(
+            ids[0],
+            OutboxAttempt {
+                error: None,
+                next_attempt: None,
+                status_code: Some(204),
+                latency_ms: 12,
+                response: None,
+            },
+        )
+        .await
+        .unwrap();
+    database
+        .record_outbox_attempt(
+            ids[1],
+            OutboxAttempt {
+                error: Some("Unreachable".to_string()),
+                next_attempt: None,
+                status_code: None,
+                latency_ms: 3000,
+                response: None,
+            },
+        )
+        .await
+        .unwrap();
+
+    let messages = database
+        .get_outbox_messages(OutboxFilter {
+            target: Some("hook".to_string()),
+            limit: 10,
+            ..OutboxFilter::default()
+        })
+        .await
+        .unwrap();
+    let statuses: Vec<_> = messages.iter().map(|message| message.status).collect();
+    assert_eq!(
+        statuses,
+        vec![OutboxStatus::Pending, OutboxStatus::Delivered]
+    );
+    assert_eq!(messages[1].attempts, 1);
+    assert!(messages[1].finished.is_some());
+
+    let failed = database
+        .get_outbox_messages(OutboxFilter {
+            status: Some(OutboxStatus::Failed),
+            limit: 10,
+            ..OutboxFilter::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(failed.len(), 1);
+    assert_eq!(failed[0].last_error.as_deref(), Some("Unreachable"));
+
+    let attempts = database.get_outbox_attempts(ids.clone()).await.unwrap();
+    assert_eq!(attempts.len(), 2);
+    assert_eq!(attempts[0].outbox_id, ids[0]);
+    assert_eq!(attempts[0].status_code, Some(204));
+    assert_eq!(attempts[0].latency_ms, 12);
+    assert_eq!(attempts[1].error.as_deref(), Some("Unreachable"));
+
+    let message = database.get_outbox_message(ids[1]).await.unwrap().unwrap();
+    assert_eq!(message.status, OutboxStatus::Failed);
+    assert!(database.get_outbox_message(1000).await.unwrap().is_none());
+
+    // Only finished messages are pruned.
+    let pruned = database
+        .prune_outbox(now + chrono::Duration::seconds(10))
+        .await
+        .unwrap();
+    assert_eq!(pruned, 2);
+    assert!(database.get_outbox_attempts(ids).await.unwrap().is_empty());
+}
+
+/// Routes every event to one webhook.
+struct AllEvents;
+
+impl OutboxRouter for AllEvents {
+    fn messages(&self, event: &Event) -> Vec<NewOutboxMessage> {
+        vec![NewOutboxMessage {
+            kind: OutboxKind::Webhook,
+            target: "hook".to_string(),
+            payload: serde_json::to_string(event).unwrap(),
+        }]
+    }
+}
+
+/// Test that a change's outbox messages are added along with it, and not at
+/// all if it fails.
+#[actix_rt::test]
+async fn test_change_outbox() {
+    let database = database();
+    for user_id in &["alice", "bob"] {
+        database
+            .add_user_by_github_id(user_id.to_string(), user_id.to_string(), Outbox::none())
+            .await
+            .unwrap();
+    }
+
+    let transaction = |shaftee: &str| Transaction {
+        id: None,
+        shafter: "alice".to_string(),
+        shaftee: shaftee.to_string(),
+        amount: Money::new(500, Currency::GBP),
+        datetime: chrono::Utc::now(),
+        reason: "Food".to_string(),
+        kind: TransactionKind::Shaft,
+    };
+    let outbox = || {
+        Outbox::new(Some(Arc::new(AllEvents)), |&transaction_id| {
+            vec![Event::TransactionDeleted {
+                actor: "alice".to_string(),
+                transaction_id,
+            }]
+        })
+    };
+
+    let failed = outbox();
+    assert!(database
+        .shaft_user(DEFAULT_LEDGER_ID, transaction("carol"), failed.clone())
+        .await
+        .is_err());
+    assert!(failed.take_events().is_empty());
+
+    let created = outbox();
+    let transaction_id = database
+        .shaft_user(DEFAULT_LEDGER_ID, transaction("bob"), created.clone())
+        .await
+        .unwrap();
+    assert_eq!(created.take_events().len(), 1);
+
+    let messages = database
+        .get_outbox_messages(OutboxFilter {
+            limit: 10,
+            ..OutboxFilter::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&messages[0].payload).unwrap(),
+        json!({
+            "type": "transaction_deleted",
+            "actor": "alice",
+            "transaction_id": transaction_id,
+        })
+    );
+}
+
+#[test]
+fn test_next_attempt() {
+    let now = chrono::Utc::now();
+
+    assert_eq!(
+        next_attempt(1, 5, now),
+        Some(now + chrono::Duration::seconds(30))
+    );
+    assert_eq!(
+        next_attempt(3, 5, now),
+        Some(now + chrono::Duration::seconds(120))
+    );
+    assert_eq!(
+        next_attempt(4, 100, now.clone()).map(|time| time - now),
+        Some(chrono::Duration::seconds(240))
+    );
+    assert_eq!(
+        next_attempt(50, 100, now),
+        Some(now + chrono::Duration::hours(1))
+    );
+    assert_eq!(next_attempt(5, 5, now), None);
+}
+
+/// Test that events are stored for webhooks while they're enabled, then
+/// delivered signed by the dispatcher.
+#[actix_rt::test]
+async fn test_webhook_delivery() {
+    let sent = Arc::new(Mutex::new(Vec::new()));
+
+    let mut http_client = MockGenericHttpClient::new();
+    let requests = sent.clone();
+    http_client.expect_request().returning(
+        move |req: Request<Body>| -> BoxFuture<'static, Result<Response<Body>, HttpError>> {
+            requests
+                .lock()
+                .unwrap()
+                .push((req.uri().to_string(), req.headers().clone()));
+            future::ok(Response::new(Body::empty())).boxed()
+        },
+    );
+
+    let mut config = test_config();
+    config.webhooks = vec![
+        WebhookSettings {
+            id: "home".to_string(),
+            url: "https://home.example.com/shaft".to_string(),
+            secret: "secret".to_string(),
+            events: vec!["transaction_created".to_string()],
+        },
+        WebhookSettings {
+            id: "pokes".to_string(),
+            url: "https://pokes.example.com/shaft".to_string(),
+            secret: "other".to_string(),
+            events: vec!["user_poked".to_string()],
+        },
+    ];
+
+    let mut app_state = AppState::new(config, Handlebars::new(), database(), Arc::new(http_client));
+    app_state.enable_webhooks();
+    let (srv, app_state) = start_app(app_state);
+    let alice = login_user(&app_state, "alice").await;
+    login_user(&app_state, "bob").await;
+
+    // Nothing is stored while webhooks are disabled.
+    let response = srv
+        .post("/api/shaft")
+        .cookie(alice.clone())
+        .send_json(&json!({ "other_user": "bob", "amount": 150, "reason": "Coffee" }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    app_state.features.set(Feature::Webhooks, true);
+    let response = srv
+        .post("/api/shaft")
+        .cookie(alice.clone())
+        .send_json(&json!({ "other_user": "bob", "amount": 250, "reason": "Lunch" }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let pending = app_state
+        .database
+        .get_outbox_messages(OutboxFilter {
+            status: Some(OutboxStatus::Pending),
+            limit: 10,
+            ..OutboxFilter::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].target, "home");
+    let payload: serde_json::Value = serde_json::from_str(&pending[0].payload).unwrap();
+    assert_eq!(payload["type"], "transaction_created");
+    assert_eq!(payload["transaction"]["reason"], "Lunch");
+
+    let attempted = dispatch_due(&app_state, &logger()).await.unwrap();
+    assert_eq!(attempted, 1);
+
+    {
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        let (url, headers) = &sent[0];
+        assert_eq!(url, "https://home.example.com/shaft");
+        assert_eq!(headers["X-Shaft-Delivery"], pending[0].id.to_string());
+        verify_signature("secret", headers, pending[0].payload.as_bytes()).unwrap();
+    }
+
+    let delivered = app_state
+        .database
+        .get_outbox_messages(OutboxFilter {
+            status: Some(OutboxStatus::Delivered),
+            limit: 10,
+            ..OutboxFilter::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(delivered.len(), 1);
+}
+
+/// Test that failed webhook deliveries are scheduled for a retry, and listed
+/// for admins.
+#[actix_rt::test]
+async fn test_failed_delivery() {
+    let mut http_client = MockGenericHttpClient::new();
+    http_client.expect_request().returning(
+        |_| -> BoxFuture<'static, Result<Response<Body>, HttpError>> {
+            future::ok(Response::builder().status(503).body(Body::empty()).unwrap()).boxed()
+        },
+    );
+
+    let mut config = test_config();
+    config.webhooks = vec![WebhookSettings {
+        id: "home".to_string(),
+        url: "https://home.example.com/shaft".to_string(),
+        secret: "secret".to_string(),
+        events: Vec::new(),
+    }];
+
+    let mut app_state = AppState::new(config, Handlebars::new(), database(), Arc::new(http_client));
+    app_state.features.set(Feature::Webhooks, true);
+    app_state.enable_webhooks();
+    let (srv, app_state) = start_app(app_state);
+    let alice = login_user(&app_state, "alice").await;
+    login_user(&app_state, "bob").await;
+    let admin = login_user_with_scopes(
+        &app_state,
+        "admin",
+        vec![Scope::Read, Scope::Write, Scope::Admin],
+    )
+    .await;
+
+    let response = srv
+        .post("/api/shaft")
+        .cookie(alice.clone())
+        .send_json(&json!({ "other_user": "bob", "amount": 150, "reason": "Coffee" }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    assert_eq!(dispatch_due(&app_state, &logger()).await.unwrap(), 1);
+    // The retry isn't due yet.
+    assert_eq!(dispatch_due(&app_state, &logger()).await.unwrap(), 0);
+
+    let response = srv
+        .get("/api/admin/outbox")
+        .cookie(alice)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 403);
+
+    let mut response = srv
+        .get("/api/admin/outbox?status=pending")
+        .cookie(admin)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    let deliveries = body["deliveries"].as_array().unwrap();
+    assert_eq!(deliveries.len(), 1);
+    assert_eq!(deliveries[0]["kind"], "webhook");
+    assert_eq!(deliveries[0]["target"], "home");
+    assert_eq!(deliveries[0]["attempts"], 1);
+    assert_eq!(
+        deliveries[0]["last_error"],
+        "Got 503 Service Unavailable response"
+    );
+    assert!(deliveries[0]["next_attempt"].is_i64());
+}
+
+/// Test that a webhook's delivery attempts are listed with their responses,
+/// and that deliveries can be sent again.
+#[actix_rt::test]
+async fn test_webhook_deliveries() {
+    let calls = Arc::new(AtomicUsize::new(0));
+
+    let mut http_client = MockGenericHttpClient::new();
+    let counter = calls.clone();
+    http_client.expect_request().returning(
+        move |_| -> BoxFuture<'static, Result<Response<Body>, HttpError>> {
+            // The first attempt fails, later ones succeed.
+            let resp = if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                Response::builder()
+                    .status(500)
+                    .body(Body::from("Automation engine unavailable"))
+            } else {
+                Response::builder().status(204).body(Body::empty())
+            };
+            future::ok(resp.unwrap()).boxed()
+        },
+    );
+
+    let mut config = test_config();
+    config.webhooks = vec![WebhookSettings {
+        id: "home".to_string(),
+        url: "https://home.example.com/shaft".to_string(),
+        secret: "secret".to_string(),
+        events: Vec::new(),
+    }];
+
+    let mut app_state = AppState::new(config, Handlebars::new(), database(), Arc::new(http_client));
+    app_state.features.set(Feature::Webhooks, true);
+    app_state.enable_webhooks();
+    let (srv, app_state) = start_app(app_state);
+    let alice = login_user(&app_state, "alice").await;
+    login_user(&app_state, "bob").await;
+    let admin = login_user_with_scopes(
+        &app_state,
+        "admin",
+        vec![Scope::Read, Scope::Write, Scope::Admin],
+    )
+    .await;
+
+    let response = srv
+        .post("/api/shaft")
+        .cookie(alice.clone())
+        .send_json(&json!({ "other_user": "bob", "amount": 150, "reason": "Coffee" }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(dispatch_due(&app_state, &logger()).await.unwrap(), 1);
+
+    let response = srv
+        .get("/api/admin/webhooks/home/deliveries")
+        .cookie(alice.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 403);
+
+    let response = srv
+        .get("/api/admin/webhooks/unknown/deliveries")
+        .cookie(admin.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+
+    let mut response = srv
+        .get("/api/admin/webhooks/home/deliveries")
+        .cookie(admin.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    let deliveries = body["deliveries"].as_array().unwrap();
+    assert_eq!(deliveries.len(), 1);
+    assert_eq!(deliveries[0]["status"], "pending");
+    assert!(deliveries[0]["next_attempt"].is_i64());
+    let attempt_log = deliveries[0]["attempt_log"].as_array().unwrap();
+    assert_eq!(attempt_log.len(), 1);
+    assert_eq!(attempt_log[0]["status_code"], 500);
+    assert_eq!(attempt_log[0]["response"], "Automation engine unavailable");
+    assert_eq!(
+        attempt_log[0]["error"],
+        "Got 500 Internal Server Error response"
+    );
+    assert!(attempt_log[0]["latency_ms"].is_i64());
+
+    let delivery_id = deliveries[0]["id"].as_i64().unwrap();
+
+    let response = srv
+        .post(format!(
+            "/api/admin/webhooks/home/deliveries/{}/redeliver",
+            delivery_id + 100
+        ))
+        .cookie(admin.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+
+    let mut response = srv
+        .post(format!(
+            "/api/admin/webhooks/home/deliveries/{}/redeliver",
+            delivery_id
+        ))
+        .cookie(admin.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    let new_id = body["delivery_id"].as_i64().unwrap();
+    assert_ne!(new_id, delivery_id);
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+    let mut response = srv
+        .get("/api/admin/webhooks/home/deliveries?status=delivered")
+        .cookie(admin)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    let deliveries = body["deliveries"].as_array().unwrap();
+    assert_eq!(deliveries.len(), 1);
+    assert_eq!(deliveries[0]["id"], new_id);
+    assert_eq!(deliveries[0]["attempt_log"][0]["status_code"], 204);
+    assert!(deliveries[0]["attempt_log"][0]["response"].is_null());
+}
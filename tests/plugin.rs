@@ -0,0 +1,78 @@
+use actix_web::web::{self, ServiceConfig};
+use bytes::Bytes;
+use futures::future::{self, FutureExt, LocalBoxFuture};
+use handlebars::Handlebars;
+use serde_json::json;
+
+use std::sync::{Arc, Mutex};
+
+use shaft::db::{SqliteDatabase, Transaction};
+use shaft::http_client::MockGenericHttpClient;
+use shaft::plugin::ShaftPlugin;
+use shaft::rest::AppState;
+
+mod common;
+
+use common::{login_user, start_app, test_config};
+
+/// A plugin that remembers the transactions it has seen and serves a page.
+#[derive(Default)]
+struct TestPlugin {
+    transactions: Mutex<Vec<Transaction>>,
+}
+
+impl ShaftPlugin for TestPlugin {
+    fn name(&self) -> &'static str {
+        "test"
+    }
+
+    fn on_transaction(&self, transaction: &Transaction) -> LocalBoxFuture<'static, ()> {
+        self.transactions.lock().unwrap().push(transaction.clone());
+        future::ready(()).boxed_local()
+    }
+
+    fn register_routes(&self, config: &mut ServiceConfig) {
+        config.route("/plugin/hello", web::get().to(|| async { "Hello" }));
+    }
+}
+
+/// Test that plugins get their hooks called and can serve routes.
+#[actix_rt::test]
+async fn test_plugin() {
+    let database = SqliteDatabase::with_path(":memory:");
+    database.migrate().unwrap();
+
+    let mut app_state = AppState::new(
+        test_config(),
+        Handlebars::new(),
+        Arc::new(database),
+        Arc::new(MockGenericHttpClient::new()),
+    );
+
+    let plugin = Arc::new(TestPlugin::default());
+    app_state.add_plugin(plugin.clone());
+
+    let (srv, app_state) = start_app(app_state);
+    let cookie = login_user(&app_state, "alice").await;
+    login_user(&app_state, "bob").await;
+
+    let mut response = srv.get("/plugin/hello").send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.body().await.unwrap(), Bytes::from_static(b"Hello"));
+
+    let response = srv
+        .post("/api/shaft")
+        .cookie(cookie)
+        .send_json(&json!({
+            "other_user": "bob",
+            "amount": 150,
+            "reason": "Coffee",
+        }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let transactions = plugin.transactions.lock().unwrap();
+    assert_eq!(transactions.len(), 1);
+    assert_eq!(transactions[0].shaftee, "bob");
+}
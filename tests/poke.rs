@@ -0,0 +1,124 @@
+use futures::future::{self, BoxFuture, FutureExt};
+use handlebars::Handlebars;
+use serde_json::json;
+
+use std::sync::{Arc, Mutex};
+
+use shaft::db::{AuditFilter, SqliteDatabase};
+use shaft::http_client::MockGenericHttpClient;
+use shaft::notifier::{Notification, Notifier, NotifyError};
+use shaft::rest::AppState;
+
+mod common;
+
+use common::{login_user, start_app, test_config};
+
+/// A notifier that remembers the notifications it was asked to send.
+#[derive(Default)]
+struct TestNotifier {
+    sent: Mutex<Vec<Notification>>,
+}
+
+impl Notifier for TestNotifier {
+    fn notify(&self, notification: Notification) -> BoxFuture<'static, Result<(), NotifyError>> {
+        self.sent.lock().unwrap().push(notification);
+        future::ok(()).boxed()
+    }
+}
+
+/// Test that poking notifies the user of their balance, is audited, and is
+/// limited to once a day per pair.
+#[actix_rt::test]
+async fn test_poke() {
+    let database = SqliteDatabase::with_path(":memory:");
+    database.migrate().unwrap();
+
+    let mut app_state = AppState::new(
+        test_config(),
+        Handlebars::new(),
+        Arc::new(database),
+        Arc::new(MockGenericHttpClient::new()),
+    );
+    let notifier = Arc::new(TestNotifier::default());
+    app_state.notifier = notifier.clone();
+
+    let (srv, app_state) = start_app(app_state);
+    let alice = login_user(&app_state, "alice").await;
+    let carol = login_user(&app_state, "carol").await;
+    login_user(&app_state, "bob").await;
+
+    let response = srv
+        .post("/api/shaft")
+        .cookie(alice.clone())
+        .send_json(&json!({ "other_user": "bob", "amount": 150, "reason": "Coffee" }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let response = srv
+        .post("/api/users/alice/poke")
+        .cookie(alice.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+
+    let response = srv
+        .post("/api/users/nobody/poke")
+        .cookie(alice.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+
+    let response = srv
+        .post("/api/users/bob/poke")
+        .cookie(alice.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let notification = notifier.sent.lock().unwrap().pop().expect("a notification");
+    assert_eq!(notification.user_id, "bob");
+    assert_eq!(notification.title, "alice poked you");
+    assert!(
+        notification.text.contains("-£1.50"),
+        "{}",
+        notification.text
+    );
+
+    let response = srv
+        .post("/api/users/bob/poke")
+        .cookie(alice.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 429);
+    assert!(response.headers().contains_key("retry-after"));
+    assert!(notifier.sent.lock().unwrap().is_empty());
+
+    // The limit is per pair.
+    let response = srv
+        .post("/api/users/bob/poke")
+        .cookie(carol)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let entries = app_state
+        .database
+        .get_audit_entries(AuditFilter {
+            action: Some("user.poke".to_owned()),
+            limit: 10,
+            ..AuditFilter::default()
+        })
+        .await
+        .unwrap();
+    let pokes: Vec<_> = entries
+        .iter()
+        .map(|entry| (entry.actor.as_str(), entry.target.as_deref()))
+        .collect();
+    assert_eq!(pokes, vec![("carol", Some("bob")), ("alice", Some("bob"))]);
+}
@@ -0,0 +1,75 @@
+use shaft::rate_limit::{InMemoryRateLimiter, RateLimiter};
+use shaft::rest::INVALID_TOKEN_LIMIT;
+
+use std::time::Duration;
+
+mod common;
+
+use common::setup_app;
+
+#[actix_rt::test]
+async fn test_in_memory_rate_limiter() {
+    let limiter = InMemoryRateLimiter::new();
+    let window = Duration::from_secs(60);
+
+    for _ in 0..3 {
+        let res = limiter.hit("alice".to_string(), 3, window).await.unwrap();
+        assert_eq!(res, None);
+    }
+
+    let retry_after = limiter
+        .hit("alice".to_string(), 3, window)
+        .await
+        .unwrap()
+        .expect("limit exceeded");
+    assert!(retry_after <= window);
+
+    // Keys are counted separately.
+    let res = limiter.hit("bob".to_string(), 3, window).await.unwrap();
+    assert_eq!(res, None);
+}
+
+#[actix_rt::test]
+async fn test_rate_limiter_window_expires() {
+    let limiter = InMemoryRateLimiter::new();
+    let window = Duration::from_millis(50);
+
+    limiter.hit("alice".to_string(), 1, window).await.unwrap();
+    assert!(limiter
+        .hit("alice".to_string(), 1, window)
+        .await
+        .unwrap()
+        .is_some());
+
+    actix_web::rt::time::sleep(window).await;
+
+    let res = limiter.hit("alice".to_string(), 1, window).await.unwrap();
+    assert_eq!(res, None);
+}
+
+/// Test that requests with invalid tokens are denied once there are too
+/// many from the same IP, whatever address they claim to be forwarded for.
+#[actix_rt::test]
+async fn test_invalid_tokens_rate_limited() {
+    let (srv, _) = setup_app(None);
+
+    for idx in 0..INVALID_TOKEN_LIMIT {
+        let response = srv
+            .get("/api/me")
+            .insert_header(("Authorization", "Bearer not-a-token"))
+            .insert_header(("X-Forwarded-For", format!("10.0.0.{}", idx)))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 302);
+    }
+
+    let response = srv
+        .get("/api/me")
+        .insert_header(("Authorization", "Bearer not-a-token"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 429);
+    assert!(response.headers().contains_key("Retry-After"));
+}
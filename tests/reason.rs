@@ -0,0 +1,100 @@
+use shaft::content_policy::{AllowAllPolicy, ContentPolicy, DenylistPolicy, PolicyError};
+use shaft::reason::{check_reason, validate_reason, ReasonError};
+use shaft::settings::{ContentPolicySettings, DenylistAction, ReasonSettings};
+
+#[test]
+fn test_validate_reason() {
+    let settings = ReasonSettings::default();
+
+    assert_eq!(
+        validate_reason("  Lunch \n", &settings),
+        Ok("Lunch".to_string())
+    );
+    assert_eq!(validate_reason("", &settings), Err(ReasonError::Missing));
+    assert_eq!(validate_reason("   ", &settings), Err(ReasonError::Missing));
+
+    let long = "x".repeat(settings.max_length + 1);
+    assert_eq!(
+        validate_reason(&long, &settings),
+        Err(ReasonError::TooLong {
+            max: settings.max_length
+        })
+    );
+}
+
+#[test]
+fn test_validate_optional_reason() {
+    let settings = ReasonSettings {
+        required: false,
+        min_length: 3,
+        max_length: 5,
+    };
+
+    // Optional reasons may be left out, but must fit the limits if given.
+    assert_eq!(validate_reason(" ", &settings), Ok(String::new()));
+    assert_eq!(
+        validate_reason("ab", &settings),
+        Err(ReasonError::TooShort { min: 3 })
+    );
+    assert_eq!(validate_reason("abc", &settings), Ok("abc".to_string()));
+    // Lengths are in characters, not bytes.
+    assert_eq!(validate_reason("£££££", &settings), Ok("£££££".to_string()));
+    assert_eq!(
+        validate_reason("abcdef", &settings),
+        Err(ReasonError::TooLong { max: 5 })
+    );
+}
+
+#[test]
+fn test_denylist_policy() {
+    let settings = ContentPolicySettings {
+        denylist: vec!["darn".to_string(), "oh heck".to_string(), "💩".to_string()],
+        action: DenylistAction::Mask,
+    };
+    let policy = DenylistPolicy::new(&settings);
+
+    assert_eq!(policy.check("Lunch"), Ok("Lunch".to_string()));
+    // Matching ignores case, but only whole words.
+    assert_eq!(policy.check("Darn lunch"), Ok("**** lunch".to_string()));
+    assert_eq!(policy.check("Darned lunch"), Ok("Darned lunch".to_string()));
+    assert_eq!(policy.check("Oh heck, tea"), Ok("*******, tea".to_string()));
+    // Emoji match anywhere.
+    assert_eq!(policy.check("Tea💩"), Ok("Tea*".to_string()));
+
+    let policy = DenylistPolicy::new(&ContentPolicySettings {
+        action: DenylistAction::Reject,
+        ..settings
+    });
+    assert_eq!(policy.check("Darn lunch"), Err(PolicyError::Disallowed));
+    assert_eq!(policy.check("Lunch"), Ok("Lunch".to_string()));
+}
+
+#[test]
+fn test_check_reason() {
+    let settings = ReasonSettings {
+        required: false,
+        min_length: 0,
+        max_length: 10,
+    };
+    let policy = DenylistPolicy::new(&ContentPolicySettings {
+        denylist: vec!["darn".to_string()],
+        action: DenylistAction::Reject,
+    });
+
+    assert_eq!(
+        check_reason(" Lunch ", &settings, &AllowAllPolicy),
+        Ok("Lunch".to_string())
+    );
+    assert_eq!(
+        check_reason("darn", &settings, &policy),
+        Err(ReasonError::Disallowed {
+            source: PolicyError::Disallowed
+        })
+    );
+    // The length is checked first.
+    assert_eq!(
+        check_reason("darn it all", &settings, &policy),
+        Err(ReasonError::TooLong { max: 10 })
+    );
+    assert_eq!(check_reason("", &settings, &policy), Ok(String::new()));
+}
@@ -0,0 +1,37 @@
+use handlebars::Handlebars;
+use serde_json::json;
+
+use shaft::rest::RenderCache;
+
+/// Test that pages are cached by context until the cache is cleared.
+#[test]
+fn test_render_cache() {
+    let mut hb = Handlebars::new();
+    hb.register_template_string("greeting", "Hello {{name}}")
+        .unwrap();
+
+    let cache = RenderCache::new();
+    let page = cache
+        .render(&hb, "greeting", &json!({ "name": "alice" }))
+        .unwrap();
+    assert_eq!(page, "Hello alice");
+
+    let page = cache
+        .render(&hb, "greeting", &json!({ "name": "bob" }))
+        .unwrap();
+    assert_eq!(page, "Hello bob");
+
+    // Changing the template doesn't affect cached pages until cleared.
+    hb.register_template_string("greeting", "Bye {{name}}")
+        .unwrap();
+    let page = cache
+        .render(&hb, "greeting", &json!({ "name": "alice" }))
+        .unwrap();
+    assert_eq!(page, "Hello alice");
+
+    cache.clear();
+    let page = cache
+        .render(&hb, "greeting", &json!({ "name": "alice" }))
+        .unwrap();
+    assert_eq!(page, "Bye alice");
+}
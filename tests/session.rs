@@ -0,0 +1,65 @@
+use chrono::TimeZone;
+
+use shaft::rest::SessionCookie;
+use shaft::settings::{CookieSettings, SameSite};
+
+/// Test that the session cookie has the default attributes.
+#[test]
+fn test_session_cookie() {
+    let cookie = SessionCookie::with_settings(&CookieSettings::default(), "", "abc123");
+    assert_eq!(
+        cookie.to_string(),
+        "token=abc123; HttpOnly; Secure; Path=/; SameSite=Lax"
+    );
+}
+
+/// Test that the expiry is in the format browsers expect.
+#[test]
+fn test_session_cookie_expires() {
+    let cookie = SessionCookie::with_settings(&CookieSettings::default(), "", "abc123")
+        .expires(chrono::Utc.ymd(2021, 3, 4).and_hms(5, 6, 7));
+    assert_eq!(
+        cookie.to_string(),
+        "token=abc123; HttpOnly; Secure; Path=/; SameSite=Lax; Expires=Thu, 04 Mar 2021 05:06:07 GMT"
+    );
+}
+
+/// Test that the epoch is formatted the way removal cookies expect.
+#[test]
+fn test_session_cookie_removal() {
+    let cookie = SessionCookie::with_settings(&CookieSettings::default(), "", "")
+        .expires(chrono::Utc.timestamp(0, 0));
+    assert_eq!(
+        cookie.to_string(),
+        "token=; HttpOnly; Secure; Path=/; SameSite=Lax; Expires=Thu, 01 Jan 1970 00:00:00 GMT"
+    );
+}
+
+/// Test that the settings and web root are used.
+#[test]
+fn test_session_cookie_settings() {
+    let cookies = CookieSettings {
+        secure: false,
+        same_site: SameSite::Strict,
+    };
+
+    for (web_root, path) in &[
+        ("", "/"),
+        ("/", "/"),
+        ("/shaft", "/shaft"),
+        ("/shaft/", "/shaft"),
+        ("https://example.com/shaft/", "/shaft"),
+        ("https://example.com", "/"),
+    ] {
+        let cookie = SessionCookie::with_settings(&cookies, web_root, "abc123");
+        assert_eq!(
+            cookie.to_string(),
+            format!("token=abc123; HttpOnly; Path={}; SameSite=Strict", path),
+            "{}",
+            web_root
+        );
+    }
+
+    let cookie = SessionCookie::with_settings(&cookies, "", "abc123").name("other");
+    assert!(cookie.to_string().starts_with("other=abc123;"));
+}
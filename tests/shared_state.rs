@@ -0,0 +1,194 @@
+use handlebars::Handlebars;
+use shaft::db::SqliteDatabase;
+use shaft::features::Feature;
+use shaft::http_client::MockGenericHttpClient;
+use shaft::rest::AppState;
+use shaft::shared_state::{DatabaseSharedState, InMemorySharedState, Invalidation, SharedState};
+
+use std::sync::Arc;
+use std::time::Duration;
+
+mod common;
+
+use common::{login_user, start_app, test_config};
+
+/// Two app states sharing one database, as if run by separate instances.
+fn two_instances() -> (AppState, AppState) {
+    let database = SqliteDatabase::with_path(":memory:");
+    database.migrate().unwrap();
+    let database = Arc::new(database);
+
+    let instance = || {
+        let mut app_state = AppState::new(
+            test_config(),
+            Handlebars::new(),
+            database.clone(),
+            Arc::new(MockGenericHttpClient::default()),
+        );
+        app_state.set_shared_state(Arc::new(DatabaseSharedState::new(database.clone())));
+        app_state
+    };
+
+    (instance(), instance())
+}
+
+#[actix_rt::test]
+async fn test_in_memory_values() {
+    let store = InMemorySharedState::new();
+
+    store
+        .put("a".to_string(), "1".to_string(), Duration::from_secs(60))
+        .await
+        .unwrap();
+    store
+        .put("b".to_string(), "2".to_string(), Duration::from_secs(0))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        store.take("a".to_string()).await.unwrap(),
+        Some("1".to_string())
+    );
+    // Values can only be taken once.
+    assert_eq!(store.take("a".to_string()).await.unwrap(), None);
+    // Expired values are gone.
+    assert_eq!(store.take("b".to_string()).await.unwrap(), None);
+
+    store
+        .publish(Invalidation::User {
+            user_id: "alice".to_string(),
+        })
+        .await
+        .unwrap();
+    assert!(store.poll().await.unwrap().is_empty());
+}
+
+/// Test that values and invalidations are seen by other instances, but
+/// instances don't see their own invalidations.
+#[actix_rt::test]
+async fn test_database_shared_state() {
+    let database = SqliteDatabase::with_path(":memory:");
+    database.migrate().unwrap();
+    let database = Arc::new(database);
+
+    let first = DatabaseSharedState::new(database.clone());
+    let second = DatabaseSharedState::new(database.clone());
+    assert_ne!(first.instance_id(), second.instance_id());
+
+    // Invalidations from before an instance starts polling are skipped.
+    first
+        .publish(Invalidation::User {
+            user_id: "old".to_string(),
+        })
+        .await
+        .unwrap();
+    assert!(first.poll().await.unwrap().is_empty());
+    assert!(second.poll().await.unwrap().is_empty());
+
+    first
+        .put(
+            "code".to_string(),
+            "alice".to_string(),
+            Duration::from_secs(60),
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        second.take("code".to_string()).await.unwrap(),
+        Some("alice".to_string())
+    );
+    assert_eq!(first.take("code".to_string()).await.unwrap(), None);
+
+    let invalidation = Invalidation::Feature {
+        feature: Feature::Webhooks,
+        enabled: true,
+    };
+    first.publish(invalidation.clone()).await.unwrap();
+    assert!(first.poll().await.unwrap().is_empty());
+    assert_eq!(second.poll().await.unwrap(), vec![invalidation]);
+    assert!(second.poll().await.unwrap().is_empty());
+
+    first.prune().await.unwrap();
+}
+
+/// Test that a confirmation code issued by one instance can be used on
+/// another, once.
+#[actix_rt::test]
+async fn test_shared_confirmations() {
+    let (first, second) = two_instances();
+
+    let code = first.slack_link_codes.issue("alice").await.unwrap();
+    assert_eq!(
+        second.slack_link_codes.redeem(&code).await.unwrap(),
+        Some("alice".to_string())
+    );
+    assert_eq!(first.slack_link_codes.redeem(&code).await.unwrap(), None);
+
+    // Issuing a new token replaces the old one.
+    let old = first.confirmations.issue("alice").await.unwrap();
+    let new = second.confirmations.issue("alice").await.unwrap();
+    assert!(!first.confirmations.consume("alice", &old).await.unwrap());
+    assert!(!first.confirmations.consume("bob", &new).await.unwrap());
+
+    let new = second.confirmations.issue("alice").await.unwrap();
+    assert!(first.confirmations.consume("alice", &new).await.unwrap());
+}
+
+/// Test that logging out on one instance evicts the session from another
+/// instance's auth cache, and that feature toggles are followed.
+#[actix_rt::test]
+async fn test_cross_instance_invalidation() {
+    let (first, second) = two_instances();
+    let (first_srv, first) = start_app(first);
+    let (second_srv, second) = start_app(second);
+
+    // Start both polling, as the poller would.
+    assert!(first.shared_state.poll().await.unwrap().is_empty());
+    assert!(second.shared_state.poll().await.unwrap().is_empty());
+
+    let cookie = login_user(&first, "alice").await;
+
+    // Caches the session on the second instance.
+    let response = second_srv
+        .get("/api/me")
+        .cookie(cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let response = first_srv
+        .post("/logout")
+        .cookie(cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 302);
+
+    for invalidation in second.shared_state.poll().await.unwrap() {
+        second.apply_invalidation(&invalidation).await.unwrap();
+    }
+
+    let response = second_srv
+        .get("/api/me")
+        .cookie(cookie)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 302);
+
+    first
+        .invalidate(Invalidation::Feature {
+            feature: Feature::Categories,
+            enabled: true,
+        })
+        .await
+        .unwrap();
+    assert!(first.features.is_enabled(Feature::Categories));
+    assert!(!second.features.is_enabled(Feature::Categories));
+
+    for invalidation in second.shared_state.poll().await.unwrap() {
+        second.apply_invalidation(&invalidation).await.unwrap();
+    }
+    assert!(second.features.is_enabled(Feature::Categories));
+}
@@ -0,0 +1,173 @@
+use bytes::Bytes;
+use futures::channel::mpsc;
+use futures::future::{BoxFuture, FutureExt};
+use futures::StreamExt;
+use hyper::{Body, Request, Response};
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use shaft::db::DEFAULT_LEDGER_ID;
+use shaft::http_client::{HttpError, MockGenericHttpClient};
+use shaft::settings::SlackSettings;
+
+use std::time::Duration;
+
+mod common;
+
+use common::{login_user, setup_app_with_config, test_config};
+
+const SIGNING_SECRET: &str = "fake_signing_secret";
+const RESPONSE_URL: &str = "https://hooks.slack.com/commands/T1/1234/abcd";
+
+/// Build a slash command request body, and the headers Slack would sign it
+/// with at the given time.
+fn signed_command(user_id: &str, text: &str, timestamp: i64) -> (String, String, String) {
+    let body = url::form_urlencoded::Serializer::new(String::new())
+        .append_pair("team_id", "T1")
+        .append_pair("user_id", user_id)
+        .append_pair("command", "/shaft")
+        .append_pair("text", text)
+        .append_pair("response_url", RESPONSE_URL)
+        .finish();
+
+    let timestamp = timestamp.to_string();
+
+    let key = PKey::hmac(SIGNING_SECRET.as_bytes()).unwrap();
+    let mut signer = Signer::new(MessageDigest::sha256(), &key).unwrap();
+    signer
+        .update(format!("v0:{}:{}", timestamp, body).as_bytes())
+        .unwrap();
+    let signature: String = signer
+        .sign_to_vec()
+        .unwrap()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+
+    (body, timestamp, format!("v0={}", signature))
+}
+
+/// Test linking Slack accounts and recording a transaction from Slack.
+#[actix_rt::test]
+async fn test_slack_command() {
+    let (replies_tx, mut replies_rx) = mpsc::unbounded();
+
+    let mut mock_http_client = MockGenericHttpClient::new();
+    mock_http_client
+        .expect_request()
+        .withf(|req: &Request<Body>| req.method() == "POST" && req.uri() == RESPONSE_URL)
+        .returning(
+            move |req| -> BoxFuture<'static, Result<Response<Body>, HttpError>> {
+                let replies_tx = replies_tx.clone();
+                async move {
+                    let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                    replies_tx.unbounded_send(body).unwrap();
+                    Ok(Response::new(Body::empty()))
+                }
+                .boxed()
+            },
+        );
+
+    let mut config = test_config();
+    config.slack = Some(SlackSettings {
+        signing_secret: SIGNING_SECRET.to_owned(),
+        public_url: "https://shaft.example.com".to_owned(),
+    });
+    let (srv, app_state) = setup_app_with_config(config, Some(mock_http_client));
+    login_user(&app_state, "alice").await;
+    login_user(&app_state, "bob").await;
+
+    let send = |user_id: &str, text: &str| {
+        let (body, timestamp, signature) =
+            signed_command(user_id, text, chrono::Utc::now().timestamp());
+        srv.post("/integrations/slack/command")
+            .insert_header(("Content-Type", "application/x-www-form-urlencoded"))
+            .insert_header(("X-Slack-Request-Timestamp", timestamp))
+            .insert_header(("X-Slack-Signature", signature))
+            .send_body(body)
+    };
+
+    // Unlinked users are told how to link their account.
+    let mut response = send("U1", "<@U2|bob> 12.50 pizza").await.unwrap();
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["response_type"], "ephemeral");
+    assert!(body["text"]
+        .as_str()
+        .unwrap()
+        .contains("https://shaft.example.com/integrations/slack/link"));
+
+    let mut response = send("U1", "link not_a_code").await.unwrap();
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert!(body["text"].as_str().unwrap().contains("invalid"));
+
+    for (slack_user_id, user_id) in &[("U1", "alice"), ("U2", "bob")] {
+        let code = app_state.slack_link_codes.issue(user_id).await.unwrap();
+        let mut response = send(slack_user_id, &format!("link {}", code))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert!(body["text"].as_str().unwrap().contains(user_id));
+    }
+
+    let mut response = send("U1", "<@U2|bob> 12.50 pizza &amp; chips")
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["response_type"], "ephemeral");
+
+    // The outcome is posted to the response URL.
+    let reply: Bytes = actix_rt::time::timeout(Duration::from_secs(5), replies_rx.next())
+        .await
+        .expect("reply posted to response_url")
+        .unwrap();
+    let reply: serde_json::Value = serde_json::from_slice(&reply).unwrap();
+    assert_eq!(reply["response_type"], "in_channel");
+    assert!(reply["text"].as_str().unwrap().contains("pizza & chips"));
+
+    let transactions = app_state
+        .database
+        .get_last_transactions(DEFAULT_LEDGER_ID, 1)
+        .await
+        .unwrap();
+    assert_eq!(transactions[0].shafter, "alice");
+    assert_eq!(transactions[0].shaftee, "bob");
+    assert_eq!(transactions[0].amount.minor_units(), 1250);
+    assert_eq!(transactions[0].reason, "pizza & chips");
+}
+
+/// Test that requests not signed with the signing secret are rejected.
+#[actix_rt::test]
+async fn test_slack_command_bad_signature() {
+    let mut config = test_config();
+    config.slack = Some(SlackSettings {
+        signing_secret: SIGNING_SECRET.to_owned(),
+        public_url: "https://shaft.example.com".to_owned(),
+    });
+    let (srv, _) = setup_app_with_config(config, None);
+
+    let (body, timestamp, _) = signed_command("U1", "help", chrono::Utc::now().timestamp());
+    let response = srv
+        .post("/integrations/slack/command")
+        .insert_header(("X-Slack-Request-Timestamp", timestamp))
+        .insert_header(("X-Slack-Signature", "v0=0000"))
+        .send_body(body)
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 403);
+
+    // Old requests are rejected even if correctly signed.
+    for sent_at in &[1_500_000_000, i64::MIN] {
+        let (body, timestamp, signature) = signed_command("U1", "help", *sent_at);
+        let response = srv
+            .post("/integrations/slack/command")
+            .insert_header(("X-Slack-Request-Timestamp", timestamp))
+            .insert_header(("X-Slack-Signature", signature))
+            .send_body(body)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 403);
+    }
+}
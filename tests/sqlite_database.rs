@@ -0,0 +1,801 @@
+use shaft::db::{
+    anonymise_database, AnonymiseError, Database, DatabaseError, IntegrityProblem,
+    IntegrityProblemKind, Outbox, Scope, SecurityEvent, SecurityEventFilter, SqliteDatabase,
+    Transaction, TransactionFilter, TransactionKind, DEFAULT_LEDGER_ID,
+};
+use shaft::maintenance::{self, parse_age};
+use shaft::money::{Currency, Money};
+use shaft::seed::{seed, SeedError, SeedOptions, SeedSummary};
+use shaft::settings::{DatabasePoolSettings, SqliteSettings};
+
+/// A migrated in memory database. Uses a single connection, as each
+/// connection to `:memory:` gets its own database.
+fn setup_database() -> SqliteDatabase {
+    let database = SqliteDatabase::with_settings(
+        ":memory:",
+        &DatabasePoolSettings {
+            max_connections: 1,
+            ..DatabasePoolSettings::default()
+        },
+        &SqliteSettings::default(),
+    );
+    database.migrate().unwrap();
+    database
+}
+
+/// Test that the index check finds the migrated indexes, and notices when one
+/// is missing.
+#[test]
+fn test_missing_indexes() {
+    let database = setup_database();
+    assert!(database.missing_indexes().unwrap().is_empty());
+
+    database
+        .run_statements("DROP INDEX transactions_shafter;")
+        .unwrap();
+    assert_eq!(
+        database.missing_indexes().unwrap(),
+        vec!["transactions(shafter)"]
+    );
+}
+
+/// Test that login session tokens stop working once they expire.
+#[actix_rt::test]
+async fn test_expired_token() {
+    let database = setup_database();
+    database
+        .add_user_by_github_id("alice".to_owned(), "Alice".to_owned(), Outbox::none())
+        .await
+        .unwrap();
+
+    let valid = database
+        .create_token_for_user(
+            "alice".to_owned(),
+            vec![Scope::Read],
+            chrono::Utc::now() + chrono::Duration::hours(1),
+            Outbox::none(),
+        )
+        .await
+        .unwrap();
+    let expired = database
+        .create_token_for_user(
+            "alice".to_owned(),
+            vec![Scope::Read],
+            chrono::Utc::now() - chrono::Duration::hours(1),
+            Outbox::none(),
+        )
+        .await
+        .unwrap();
+
+    assert!(database
+        .get_user_from_token(valid.clone())
+        .await
+        .unwrap()
+        .is_some());
+    assert_eq!(
+        database.get_user_id_for_token(valid).await.unwrap(),
+        Some("alice".to_owned())
+    );
+
+    assert!(database
+        .get_user_from_token(expired.clone())
+        .await
+        .unwrap()
+        .is_none());
+    assert_eq!(database.get_user_id_for_token(expired).await.unwrap(), None);
+}
+
+/// Test that pruning deletes expired tokens, and old sessions if asked, then
+/// that the database can be vacuumed.
+#[actix_rt::test]
+async fn test_prune_tokens_and_vacuum() {
+    let database = setup_database();
+    database
+        .add_user_by_github_id("alice".to_owned(), "Alice".to_owned(), Outbox::none())
+        .await
+        .unwrap();
+
+    let now = chrono::Utc::now();
+    let mut tokens = Vec::new();
+    for (issued_days_ago, expires_in_days) in &[(0, 1), (0, -1), (100, 1)] {
+        let token = database
+            .create_token_for_user(
+                "alice".to_owned(),
+                vec![Scope::Read],
+                now + chrono::Duration::days(*expires_in_days),
+                Outbox::none(),
+            )
+            .await
+            .unwrap();
+        database
+            .refresh_token(
+                token.clone(),
+                now - chrono::Duration::days(*issued_days_ago),
+                now + chrono::Duration::days(*expires_in_days),
+            )
+            .await
+            .unwrap();
+        tokens.push(token);
+    }
+
+    assert_eq!(
+        maintenance::prune_tokens(&database, None, Outbox::none())
+            .await
+            .unwrap(),
+        1
+    );
+    assert_eq!(
+        maintenance::prune_tokens(&database, Some(parse_age("90d").unwrap()), Outbox::none())
+            .await
+            .unwrap(),
+        1
+    );
+    assert!(database
+        .get_user_from_token(tokens[0].clone())
+        .await
+        .unwrap()
+        .is_some());
+
+    maintenance::vacuum(&database).await.unwrap();
+}
+
+/// Test that the integrity check finds rows referencing unknown users, and
+/// that repairing keeps the balances of unknown users in transactions.
+#[actix_rt::test]
+async fn test_check_integrity() {
+    let database = setup_database();
+    database
+        .add_user_by_github_id("alice".to_owned(), "Alice".to_owned(), Outbox::none())
+        .await
+        .unwrap();
+    assert!(maintenance::check_integrity(&database, false)
+        .await
+        .unwrap()
+        .is_empty());
+
+    database
+        .run_statements(
+            r#"
+            INSERT INTO transactions (shafter, shaftee, amount, time_sec, reason)
+                VALUES ('alice', 'ghost', 150, 0, 'Coffee'), ('ghost', 'alice', 50, 0, 'Tea');
+            INSERT INTO tokens (user_id, token, scopes) VALUES ('gone', 'abc', 'read');
+            INSERT INTO github_users (user_id, github_id) VALUES ('gone', '123');
+            "#,
+        )
+        .unwrap();
+
+    let expected = vec![
+        IntegrityProblem {
+            kind: IntegrityProblemKind::TransactionUnknownUser,
+            user_id: "ghost".to_owned(),
+            count: 2,
+        },
+        IntegrityProblem {
+            kind: IntegrityProblemKind::OrphanedToken,
+            user_id: "gone".to_owned(),
+            count: 1,
+        },
+        IntegrityProblem {
+            kind: IntegrityProblemKind::OrphanedGithubUser,
+            user_id: "gone".to_owned(),
+            count: 1,
+        },
+    ];
+    assert_eq!(
+        maintenance::check_integrity(&database, false)
+            .await
+            .unwrap(),
+        expected
+    );
+    assert_eq!(
+        maintenance::check_integrity(&database, true).await.unwrap(),
+        expected
+    );
+    assert!(maintenance::check_integrity(&database, false)
+        .await
+        .unwrap()
+        .is_empty());
+
+    let users = database.get_all_users(DEFAULT_LEDGER_ID).await.unwrap();
+    assert_eq!(users["ghost"].balance.minor_units(), -100);
+    assert!(database
+        .get_user_from_token("abc".to_owned())
+        .await
+        .unwrap()
+        .is_none());
+}
+
+/// Test that seeding gives the same data for the same seed, and reuses the
+/// seeded users.
+#[actix_rt::test]
+async fn test_seed() {
+    let options = SeedOptions {
+        users: 5,
+        transactions: 20,
+        seed: 42,
+    };
+
+    let mut seeded = Vec::new();
+    for _ in 0..2 {
+        let database = setup_database();
+        let summary = seed(&database, Currency::GBP, options).await.unwrap();
+        assert_eq!(
+            summary,
+            SeedSummary {
+                users: 5,
+                transactions: 20
+            }
+        );
+
+        let transactions = database
+            .get_transactions(
+                DEFAULT_LEDGER_ID,
+                TransactionFilter {
+                    limit: 100,
+                    ..TransactionFilter::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(transactions.len(), 20);
+        assert!(transactions.iter().all(|txn| txn.shafter != txn.shaftee));
+
+        let summary = seed(&database, Currency::GBP, options).await.unwrap();
+        assert_eq!(summary.users, 0);
+        assert_eq!(
+            database
+                .get_all_users(DEFAULT_LEDGER_ID)
+                .await
+                .unwrap()
+                .len(),
+            5
+        );
+
+        seeded.push(
+            transactions
+                .into_iter()
+                .map(|txn| {
+                    (
+                        txn.shafter,
+                        txn.shaftee,
+                        txn.amount.minor_units(),
+                        txn.reason,
+                    )
+                })
+                .collect::<Vec<_>>(),
+        );
+    }
+    assert_eq!(seeded[0], seeded[1]);
+
+    let err = seed(
+        &setup_database(),
+        Currency::GBP,
+        SeedOptions {
+            users: 1,
+            ..options
+        },
+    )
+    .await
+    .unwrap_err();
+    assert!(matches!(err, SeedError::TooFewUsers));
+}
+
+/// Test that ages are parsed with their units.
+#[test]
+fn test_parse_age() {
+    assert_eq!(parse_age("90d"), Ok(chrono::Duration::days(90)));
+    assert_eq!(parse_age("12h"), Ok(chrono::Duration::hours(12)));
+    assert_eq!(parse_age("2w"), Ok(chrono::Duration::weeks(2)));
+    assert_eq!(parse_age("30s"), Ok(chrono::Duration::seconds(30)));
+    for invalid in &["", "d", "90", "90y", "-1d", "1.5d"] {
+        assert!(parse_age(invalid).is_err(), "{}", invalid);
+    }
+}
+
+/// A path in the temp directory that doesn't exist yet.
+fn temp_path(name: &str) -> std::path::PathBuf {
+    let suffix: u64 = rand::random();
+    std::env::temp_dir().join(format!("shaft-test-{}-{:x}.db", name, suffix))
+}
+
+/// Test that an anonymised copy keeps who owes whom, but not who they are.
+#[actix_rt::test]
+async fn test_anonymise_database() {
+    let source = temp_path("source");
+    let dest = temp_path("anonymised");
+
+    let database = SqliteDatabase::with_path(&source);
+    database.migrate().unwrap();
+    for user_id in &["alice", "bob", "carol"] {
+        database
+            .add_user_by_github_id(user_id.to_string(), user_id.to_uppercase(), Outbox::none())
+            .await
+            .unwrap();
+    }
+    for (shafter, shaftee, amount) in &[("alice", "bob", 500), ("carol", "alice", 200)] {
+        database
+            .shaft_user(
+                DEFAULT_LEDGER_ID,
+                Transaction {
+                    id: None,
+                    shafter: shafter.to_string(),
+                    shaftee: shaftee.to_string(),
+                    amount: Money::new(*amount, Currency::GBP),
+                    datetime: chrono::Utc::now(),
+                    reason: "Secret reason".to_owned(),
+                    kind: TransactionKind::Shaft,
+                },
+                Outbox::none(),
+            )
+            .await
+            .unwrap();
+    }
+    let token = database
+        .create_token_for_user(
+            "alice".to_owned(),
+            vec![Scope::Read],
+            chrono::Utc::now() + chrono::Duration::days(1),
+            Outbox::none(),
+        )
+        .await
+        .unwrap();
+    database
+        .add_security_event(SecurityEvent {
+            id: None,
+            kind: "token.invalid".to_owned(),
+            user_id: Some("alice".to_owned()),
+            ip: Some("192.0.2.1".to_owned()),
+            details: Some("Unknown or expired session token".to_owned()),
+            datetime: chrono::Utc::now(),
+        })
+        .await
+        .unwrap();
+
+    let summary = anonymise_database(&source, &dest).unwrap();
+    assert_eq!(summary.users, 3);
+    assert_eq!(summary.transactions, 2);
+
+    let copy = SqliteDatabase::with_path(&dest);
+    let users = copy.get_all_users(DEFAULT_LEDGER_ID).await.unwrap();
+    assert_eq!(users.len(), 3);
+    for (user_id, user) in users.iter() {
+        assert!(user_id.starts_with("user"), "{}", user_id);
+        assert!(!["ALICE", "BOB", "CAROL"].contains(&user.display_name.as_str()));
+    }
+
+    // Balances keep their order and sign.
+    let mut balances: Vec<i64> = users
+        .iter()
+        .map(|(_, user)| user.balance.minor_units())
+        .collect();
+    balances.sort();
+    assert!(balances[0] < 0 && balances[1] > 0 && balances[2] > 0);
+    assert_eq!(balances.iter().sum::<i64>(), 0);
+
+    for transaction in copy.get_all_transactions(DEFAULT_LEDGER_ID).await.unwrap() {
+        assert_ne!(transaction.reason, "Secret reason");
+    }
+    assert!(copy.get_user_from_token(token).await.unwrap().is_none());
+    let events = copy
+        .get_security_events(SecurityEventFilter {
+            limit: 10,
+            ..SecurityEventFilter::default()
+        })
+        .await
+        .unwrap();
+    assert!(events.is_empty(), "{:?}", events);
+
+    // The original is untouched, and the copy isn't overwritten.
+    assert!(database
+        .get_all_users(DEFAULT_LEDGER_ID)
+        .await
+        .unwrap()
+        .contains_key("alice"));
+    assert!(matches!(
+        anonymise_database(&source, &dest),
+        Err(AnonymiseError::OutputExists { .. })
+    ));
+
+    for path in &[source, dest] {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+fn transaction(shafter: &str, shaftee: &str, amount: i64) -> Transaction {
+    Transaction {
+        id: None,
+        shafter: shafter.to_owned(),
+        shaftee: shaftee.to_owned(),
+        amount: Money::new(amount, Currency::GBP),
+        datetime: chrono::Utc::now(),
+        reason: "Rent".to_owned(),
+        kind: TransactionKind::Shaft,
+    }
+}
+
+/// Test that batches bigger than one insert get the right IDs, and that an
+/// unknown shaftee rolls back the whole batch.
+#[actix_rt::test]
+async fn test_insert_transactions_batch() {
+    let database = setup_database();
+    for user_id in &["alice", "bob"] {
+        database
+            .add_user_by_github_id(user_id.to_string(), user_id.to_uppercase(), Outbox::none())
+            .await
+            .unwrap();
+    }
+
+    let transactions: Vec<_> = (1..=250)
+        .map(|amount| transaction("alice", "bob", amount))
+        .collect();
+    let ids = database
+        .insert_transactions_batch(DEFAULT_LEDGER_ID, transactions, Outbox::none())
+        .await
+        .unwrap();
+    assert_eq!(ids.len(), 250);
+    for (amount, id) in (1..=250).zip(&ids) {
+        let stored = database
+            .get_transaction(DEFAULT_LEDGER_ID, *id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.amount.minor_units(), amount);
+    }
+
+    let mut transactions: Vec<_> = (1..=150)
+        .map(|amount| transaction("alice", "bob", amount))
+        .collect();
+    transactions.push(transaction("alice", "unknown", 100));
+    let err = database
+        .insert_transactions_batch(DEFAULT_LEDGER_ID, transactions, Outbox::none())
+        .await
+        .unwrap_err();
+    assert!(matches!(err, DatabaseError::UnknownUser { .. }));
+    assert_eq!(
+        database
+            .get_all_transactions(DEFAULT_LEDGER_ID)
+            .await
+            .unwrap()
+            .len(),
+        250
+    );
+}
+
+/// Test that balances between pairs of users net off, leaving out anyone
+/// who's square.
+#[actix_rt::test]
+async fn test_get_balances_by_counterparty() {
+    let database = setup_database();
+    for user_id in &["alice", "bob", "carol", "dave"] {
+        database
+            .add_user_by_github_id(user_id.to_string(), user_id.to_uppercase(), Outbox::none())
+            .await
+            .unwrap();
+    }
+    database
+        .insert_transactions_batch(
+            DEFAULT_LEDGER_ID,
+            vec![
+                transaction("alice", "bob", 500),
+                transaction("bob", "alice", 200),
+                transaction("carol", "alice", 300),
+                transaction("alice", "dave", 100),
+                transaction("dave", "alice", 100),
+                transaction("bob", "carol", 1000),
+            ],
+            Outbox::none(),
+        )
+        .await
+        .unwrap();
+
+    let counterparties = database
+        .get_balances_by_counterparty(DEFAULT_LEDGER_ID, "alice".to_owned())
+        .await
+        .unwrap();
+    let balances: Vec<_> = counterparties
+        .iter()
+        .map(|user| (user.display_name.as_str(), user.balance.minor_units()))
+        .collect();
+    assert_eq!(balances, vec![("CAROL", -300), ("BOB", 300)]);
+}
+
+/// Test that outstanding debts are largest first, and know when each pair
+/// last went from even to owing and when they were last even.
+#[actix_rt::test]
+async fn test_get_outstanding_debts() {
+    let database = setup_database();
+    for user_id in &["alice", "bob", "carol"] {
+        database
+            .add_user_by_github_id(user_id.to_string(), user_id.to_uppercase(), Outbox::none())
+            .await
+            .unwrap();
+    }
+
+    let now = chrono::Utc::now();
+    let days_ago = |days| now - chrono::Duration::days(days);
+    database
+        .insert_transactions_batch(
+            DEFAULT_LEDGER_ID,
+            vec![
+                Transaction {
+                    datetime: days_ago(20),
+                    ..transaction("carol", "alice", 100)
+                },
+                Transaction {
+                    datetime: days_ago(10),
+                    ..transaction("alice", "bob", 500)
+                },
+                Transaction {
+                    datetime: days_ago(8),
+                    ..transaction("bob", "alice", 500)
+                },
+                Transaction {
+                    datetime: days_ago(5),
+                    ..transaction("alice", "bob", 300)
+                },
+            ],
+            Outbox::none(),
+        )
+        .await
+        .unwrap();
+
+    let debts = database
+        .get_outstanding_debts(DEFAULT_LEDGER_ID)
+        .await
+        .unwrap();
+    let debts: Vec<_> = debts
+        .iter()
+        .map(|debt| {
+            (
+                debt.debtor.as_str(),
+                debt.creditor.as_str(),
+                debt.amount.minor_units(),
+                debt.since.timestamp(),
+                debt.last_settled.map(|time| time.timestamp()),
+            )
+        })
+        .collect();
+    assert_eq!(
+        debts,
+        vec![
+            (
+                "bob",
+                "alice",
+                300,
+                days_ago(5).timestamp(),
+                Some(days_ago(8).timestamp())
+            ),
+            ("alice", "carol", 100, days_ago(20).timestamp(), None),
+        ]
+    );
+}
+
+/// Test that each ledger only sees its own members and transactions.
+#[actix_rt::test]
+async fn test_ledgers() {
+    let database = setup_database();
+    for user_id in &["alice", "bob", "carol"] {
+        database
+            .add_user_by_github_id(user_id.to_string(), user_id.to_uppercase(), Outbox::none())
+            .await
+            .unwrap();
+    }
+
+    let flat = database
+        .create_ledger("flat".to_owned(), "Flat".to_owned(), Outbox::none())
+        .await
+        .unwrap();
+    assert!(matches!(
+        database
+            .create_ledger("flat".to_owned(), "Flat".to_owned(), Outbox::none())
+            .await,
+        Err(DatabaseError::LedgerExists { .. })
+    ));
+    assert_eq!(
+        database
+            .get_ledger_by_name("flat".to_owned())
+            .await
+            .unwrap(),
+        Some(flat.clone())
+    );
+
+    for user_id in &["alice", "carol"] {
+        database
+            .add_ledger_member(flat.id, user_id.to_string(), Outbox::none())
+            .await
+            .unwrap();
+    }
+    assert!(matches!(
+        database
+            .add_ledger_member(flat.id, "dave".to_owned(), Outbox::none())
+            .await,
+        Err(DatabaseError::UnknownUser { .. })
+    ));
+
+    database
+        .shaft_user(
+            DEFAULT_LEDGER_ID,
+            transaction("alice", "bob", 500),
+            Outbox::none(),
+        )
+        .await
+        .unwrap();
+    let flat_transaction = database
+        .shaft_user(flat.id, transaction("alice", "carol", 300), Outbox::none())
+        .await
+        .unwrap();
+    assert!(matches!(
+        database
+            .shaft_user(flat.id, transaction("alice", "bob", 100), Outbox::none())
+            .await,
+        Err(DatabaseError::UnknownUser { .. })
+    ));
+
+    let users = database.get_all_users(flat.id).await.unwrap();
+    assert_eq!(users.len(), 2);
+    assert_eq!(users["alice"].balance.minor_units(), 300);
+    assert_eq!(users["carol"].balance.minor_units(), -300);
+    assert_eq!(
+        database
+            .get_balance_for_user(DEFAULT_LEDGER_ID, "alice".to_owned())
+            .await
+            .unwrap()
+            .minor_units(),
+        500
+    );
+
+    assert_eq!(
+        database.get_all_transactions(flat.id).await.unwrap().len(),
+        1
+    );
+    assert!(database
+        .get_transaction(DEFAULT_LEDGER_ID, flat_transaction)
+        .await
+        .unwrap()
+        .is_none());
+    assert!(matches!(
+        database
+            .delete_transaction(DEFAULT_LEDGER_ID, flat_transaction, None, Outbox::none())
+            .await,
+        Err(DatabaseError::UnknownTransaction { .. })
+    ));
+
+    let changes = database
+        .get_changes_since(DEFAULT_LEDGER_ID, 0, 100)
+        .await
+        .unwrap();
+    assert_eq!(changes.transactions.len(), 1);
+
+    let ledgers = database
+        .get_ledgers_for_user("carol".to_owned())
+        .await
+        .unwrap();
+    assert_eq!(ledgers.len(), 2);
+    assert!(!database
+        .is_ledger_member(flat.id, "bob".to_owned())
+        .await
+        .unwrap());
+}
+
+/// Test that changes to users and transactions are tracked for
+/// `Last-Modified`.
+#[actix_rt::test]
+async fn test_get_last_modified() {
+    let database = setup_database();
+    assert_eq!(
+        database
+            .get_last_modified(DEFAULT_LEDGER_ID, None)
+            .await
+            .unwrap(),
+        None
+    );
+
+    let before = chrono::Utc::now().timestamp();
+    database
+        .add_user_by_github_id("alice".to_owned(), "Alice".to_owned(), Outbox::none())
+        .await
+        .unwrap();
+
+    let last_modified = database
+        .get_last_modified(DEFAULT_LEDGER_ID, Some("alice".to_owned()))
+        .await
+        .unwrap()
+        .expect("last modified");
+    assert!(last_modified.timestamp() >= before);
+    assert!(last_modified <= chrono::Utc::now());
+}
+
+/// Test that syncing returns the users whose balances changed, and that
+/// compacting the change log makes clients that missed a deletion resync.
+#[actix_rt::test]
+async fn test_compact_changes() {
+    let database = setup_database();
+    for user_id in &["alice", "bob"] {
+        database
+            .add_user_by_github_id(user_id.to_string(), user_id.to_uppercase(), Outbox::none())
+            .await
+            .unwrap();
+    }
+    let cursor = database
+        .get_changes_since(DEFAULT_LEDGER_ID, 0, 1000)
+        .await
+        .unwrap()
+        .next_cursor;
+
+    database
+        .shaft_user(
+            DEFAULT_LEDGER_ID,
+            transaction("alice", "bob", 100),
+            Outbox::none(),
+        )
+        .await
+        .unwrap();
+    let changes = database
+        .get_changes_since(DEFAULT_LEDGER_ID, cursor, 1000)
+        .await
+        .unwrap();
+    assert_eq!(changes.transactions.len(), 1);
+    let balances: Vec<_> = changes
+        .users
+        .iter()
+        .map(|user| (user.user_id.as_str(), user.balance.minor_units()))
+        .collect();
+    assert_eq!(balances, vec![("alice", 100), ("bob", -100)]);
+
+    let cursor = changes.next_cursor;
+    let deleted = database
+        .shaft_user(
+            DEFAULT_LEDGER_ID,
+            transaction("alice", "bob", 50),
+            Outbox::none(),
+        )
+        .await
+        .unwrap();
+    database
+        .delete_transaction(DEFAULT_LEDGER_ID, deleted, None, Outbox::none())
+        .await
+        .unwrap();
+    database
+        .shaft_user(
+            DEFAULT_LEDGER_ID,
+            transaction("bob", "alice", 20),
+            Outbox::none(),
+        )
+        .await
+        .unwrap();
+    let latest = database
+        .get_changes_since(DEFAULT_LEDGER_ID, cursor, 1000)
+        .await
+        .unwrap();
+    assert_eq!(latest.deleted_transactions, vec![deleted]);
+
+    assert!(database.compact_changes().await.unwrap() > 0);
+
+    // The deletion is gone from the log, so a client that hadn't seen it
+    // has to start again.
+    let changes = database
+        .get_changes_since(DEFAULT_LEDGER_ID, cursor, 1000)
+        .await
+        .unwrap();
+    assert!(changes.resync_required);
+    assert_eq!(changes.next_cursor, latest.next_cursor);
+    assert!(changes.transactions.is_empty());
+
+    // Syncing from scratch still gets everything that exists.
+    let changes = database
+        .get_changes_since(DEFAULT_LEDGER_ID, 0, 1000)
+        .await
+        .unwrap();
+    assert!(!changes.resync_required);
+    assert_eq!(changes.transactions.len(), 2);
+    assert!(changes.deleted_transactions.is_empty());
+    assert_eq!(changes.users.len(), 2);
+    assert_eq!(changes.next_cursor, latest.next_cursor);
+
+    let changes = database
+        .get_changes_since(DEFAULT_LEDGER_ID, latest.next_cursor, 1000)
+        .await
+        .unwrap();
+    assert!(!changes.resync_required);
+    assert!(changes.users.is_empty());
+}
@@ -0,0 +1,359 @@
+//! Tests for the sqlx database backend. Only built with the `sqlx` feature.
+#![cfg(feature = "sqlx")]
+
+use shaft::db::{
+    Database, DatabaseError, Outbox, Scope, SqlxDatabase, Transaction, TransactionKind, UserFilter,
+    UserSort, DEFAULT_LEDGER_ID,
+};
+use shaft::money::{Currency, Money};
+use shaft::settings::{DatabasePoolSettings, SqliteSettings};
+
+/// Connect to a fresh, migrated database in a temporary file.
+async fn setup_database(name: &str) -> SqlxDatabase {
+    let path = std::env::temp_dir().join(format!("shaft-sqlx-{}-{}.db", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let database = SqlxDatabase::connect(
+        &path,
+        &DatabasePoolSettings::default(),
+        &SqliteSettings::default(),
+    )
+    .await
+    .unwrap();
+    database.migrate().await.unwrap();
+
+    database
+}
+
+fn transaction(shafter: &str, shaftee: &str, amount: i64) -> Transaction {
+    Transaction {
+        id: None,
+        shafter: shafter.to_owned(),
+        shaftee: shaftee.to_owned(),
+        amount: Money::new(amount, Currency::GBP),
+        datetime: chrono::Utc::now(),
+        reason: "Lunch".to_owned(),
+        kind: TransactionKind::Shaft,
+    }
+}
+
+/// Test that users, tokens and transactions round trip.
+#[actix_rt::test]
+async fn test_users_and_transactions() {
+    let database = setup_database("users").await;
+
+    database
+        .add_user_by_github_id("alice".to_owned(), "Alice".to_owned(), Outbox::none())
+        .await
+        .unwrap();
+    database
+        .add_user_by_github_id("bob".to_owned(), "Bob".to_owned(), Outbox::none())
+        .await
+        .unwrap();
+
+    let token = database
+        .create_token_for_user(
+            "alice".to_owned(),
+            vec![Scope::Read],
+            chrono::Utc::now() + chrono::Duration::days(1),
+            Outbox::none(),
+        )
+        .await
+        .unwrap();
+    let user = database
+        .get_user_from_token(token.clone())
+        .await
+        .unwrap()
+        .expect("token to be valid");
+    assert_eq!(user.user_id, "alice");
+    assert_eq!(user.scopes, vec![Scope::Read]);
+    assert!(user.issued.is_some());
+
+    let issued = chrono::Utc::now();
+    let expires = issued + chrono::Duration::days(2);
+    database
+        .refresh_token(token.clone(), issued, expires)
+        .await
+        .unwrap();
+    let user = database
+        .get_user_from_token(token)
+        .await
+        .unwrap()
+        .expect("token to be valid");
+    assert_eq!(user.issued.unwrap().timestamp(), issued.timestamp());
+    assert_eq!(user.expires.unwrap().timestamp(), expires.timestamp());
+
+    database
+        .shaft_user(
+            DEFAULT_LEDGER_ID,
+            transaction("alice", "bob", 500),
+            Outbox::none(),
+        )
+        .await
+        .unwrap();
+
+    let users = database.get_all_users(DEFAULT_LEDGER_ID).await.unwrap();
+    assert_eq!(users["alice"].balance.minor_units(), 500);
+    assert_eq!(users["bob"].balance.minor_units(), -500);
+
+    let changes = database
+        .get_changes_since(DEFAULT_LEDGER_ID, 0, 100)
+        .await
+        .unwrap();
+    assert_eq!(changes.transactions.len(), 1);
+    assert_eq!(changes.users.len(), 2);
+    assert_eq!(
+        changes.next_cursor,
+        database.get_ledger_version().await.unwrap()
+    );
+}
+
+/// Test that bulk shafts are atomic and edits check the revision.
+#[actix_rt::test]
+async fn test_insert_transactions_batch_and_revisions() {
+    let database = setup_database("revisions").await;
+
+    database
+        .add_user_by_github_id("alice".to_owned(), "Alice".to_owned(), Outbox::none())
+        .await
+        .unwrap();
+
+    let res = database
+        .insert_transactions_batch(
+            DEFAULT_LEDGER_ID,
+            vec![
+                transaction("alice", "alice", 100),
+                transaction("alice", "unknown", 100),
+            ],
+            Outbox::none(),
+        )
+        .await;
+    match res {
+        Err(DatabaseError::UnknownUser { user_id }) => assert_eq!(user_id, "unknown"),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    assert!(database
+        .get_all_transactions(DEFAULT_LEDGER_ID)
+        .await
+        .unwrap()
+        .is_empty());
+
+    let ids = database
+        .insert_transactions_batch(
+            DEFAULT_LEDGER_ID,
+            vec![transaction("alice", "alice", 100)],
+            Outbox::none(),
+        )
+        .await
+        .unwrap();
+
+    let revision = database
+        .update_transaction(
+            DEFAULT_LEDGER_ID,
+            ids[0],
+            Some(1),
+            Money::new(200, Currency::GBP),
+            "Dinner".to_owned(),
+            Outbox::none(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(revision, 2);
+
+    let res = database
+        .update_transaction(
+            DEFAULT_LEDGER_ID,
+            ids[0],
+            Some(1),
+            Money::new(300, Currency::GBP),
+            "Dinner".to_owned(),
+            Outbox::none(),
+        )
+        .await;
+    match res {
+        Err(DatabaseError::RevisionMismatch { revision, .. }) => assert_eq!(revision, 2),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    database
+        .delete_transaction(DEFAULT_LEDGER_ID, ids[0], Some(2), Outbox::none())
+        .await
+        .unwrap();
+    assert!(database
+        .get_transaction(DEFAULT_LEDGER_ID, ids[0])
+        .await
+        .unwrap()
+        .is_none());
+}
+
+/// Test that ledgers are kept separate and only members can be shafted.
+#[actix_rt::test]
+async fn test_ledgers() {
+    let database = setup_database("ledgers").await;
+
+    for (user_id, name) in &[("alice", "Alice"), ("bob", "Bob"), ("carol", "Carol")] {
+        database
+            .add_user_by_github_id(user_id.to_string(), name.to_string(), Outbox::none())
+            .await
+            .unwrap();
+    }
+
+    let flat = database
+        .create_ledger("flat".to_owned(), "Flat".to_owned(), Outbox::none())
+        .await
+        .unwrap();
+    assert_ne!(flat.id, DEFAULT_LEDGER_ID);
+    match database
+        .create_ledger("flat".to_owned(), "Other flat".to_owned(), Outbox::none())
+        .await
+    {
+        Err(DatabaseError::LedgerExists { name }) => assert_eq!(name, "flat"),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    database
+        .add_ledger_member(flat.id, "alice".to_owned(), Outbox::none())
+        .await
+        .unwrap();
+    database
+        .add_ledger_member(flat.id, "carol".to_owned(), Outbox::none())
+        .await
+        .unwrap();
+
+    database
+        .shaft_user(
+            DEFAULT_LEDGER_ID,
+            transaction("alice", "bob", 500),
+            Outbox::none(),
+        )
+        .await
+        .unwrap();
+    let flat_id = database
+        .shaft_user(flat.id, transaction("alice", "carol", 300), Outbox::none())
+        .await
+        .unwrap();
+
+    match database
+        .shaft_user(flat.id, transaction("alice", "bob", 100), Outbox::none())
+        .await
+    {
+        Err(DatabaseError::UnknownUser { user_id }) => assert_eq!(user_id, "bob"),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+
+    let users = database.get_all_users(flat.id).await.unwrap();
+    assert_eq!(users.len(), 2);
+    assert_eq!(users["alice"].balance.minor_units(), 300);
+    assert_eq!(users["carol"].balance.minor_units(), -300);
+
+    let users = database.get_all_users(DEFAULT_LEDGER_ID).await.unwrap();
+    assert_eq!(users["alice"].balance.minor_units(), 500);
+    assert_eq!(users["carol"].balance.minor_units(), 0);
+
+    assert!(database
+        .get_transaction(DEFAULT_LEDGER_ID, flat_id)
+        .await
+        .unwrap()
+        .is_none());
+
+    let ledgers = database
+        .get_ledgers_for_user("alice".to_owned())
+        .await
+        .unwrap();
+    assert_eq!(
+        ledgers.iter().map(|l| l.name.as_str()).collect::<Vec<_>>(),
+        vec!["default", "flat"]
+    );
+    assert!(!database
+        .is_ledger_member(flat.id, "bob".to_owned())
+        .await
+        .unwrap());
+}
+
+/// Test that users can be filtered and sorted.
+#[actix_rt::test]
+async fn test_get_users() {
+    let database = setup_database("get_users").await;
+
+    for (user_id, name) in &[("alice", "Alice"), ("bob", "bob"), ("carol", "Carol_1")] {
+        database
+            .add_user_by_github_id(user_id.to_string(), name.to_string(), Outbox::none())
+            .await
+            .unwrap();
+    }
+    database
+        .shaft_user(
+            DEFAULT_LEDGER_ID,
+            transaction("bob", "alice", 500),
+            Outbox::none(),
+        )
+        .await
+        .unwrap();
+    database
+        .deactivate_user("carol".to_owned(), Outbox::none())
+        .await
+        .unwrap();
+
+    let user_ids = |entries: Vec<shaft::db::UserEntry>| -> Vec<String> {
+        entries
+            .into_iter()
+            .map(|entry| entry.user.user_id)
+            .collect()
+    };
+
+    let users = database
+        .get_users(DEFAULT_LEDGER_ID, UserFilter::default())
+        .await
+        .unwrap();
+    assert_eq!(user_ids(users), vec!["alice", "carol", "bob"]);
+
+    let users = database
+        .get_users(
+            DEFAULT_LEDGER_ID,
+            UserFilter {
+                sort: UserSort::Name,
+                ..UserFilter::default()
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(user_ids(users), vec!["alice", "bob", "carol"]);
+
+    let users = database
+        .get_users(
+            DEFAULT_LEDGER_ID,
+            UserFilter {
+                active: Some(false),
+                ..UserFilter::default()
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(users.len(), 1);
+    assert!(!users[0].active);
+
+    // Wildcards in the prefix are matched literally.
+    let users = database
+        .get_users(
+            DEFAULT_LEDGER_ID,
+            UserFilter {
+                prefix: Some("CAROL_".to_owned()),
+                ..UserFilter::default()
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(user_ids(users), vec!["carol"]);
+
+    let users = database
+        .get_users(
+            DEFAULT_LEDGER_ID,
+            UserFilter {
+                prefix: Some("A_".to_owned()),
+                ..UserFilter::default()
+            },
+        )
+        .await
+        .unwrap();
+    assert!(users.is_empty());
+}
@@ -0,0 +1,218 @@
+use handlebars::Handlebars;
+use serde_json::Value;
+use shaft::db::{Scope, SqliteDatabase};
+use shaft::http_client::MockGenericHttpClient;
+use shaft::rest::{
+    tenant_config, tenant_path, validate_tenants, AppState, Tenant, TenantError, TenantRegistry,
+};
+use shaft::settings::{FeatureSettings, TenantSettings};
+
+use std::sync::Arc;
+
+mod common;
+
+use common::{login_user, login_user_with_scopes, start_app, test_config};
+
+fn tenant_settings(name: &str, hosts: &[&str]) -> TenantSettings {
+    TenantSettings {
+        name: name.to_string(),
+        database_file: format!("{}.db", name),
+        hosts: hosts.iter().map(|host| host.to_string()).collect(),
+        admins: None,
+        github: None,
+        currency: None,
+        reasons: None,
+        features: None,
+        webhooks: Vec::new(),
+    }
+}
+
+/// Start an app hosting the given tenants, each with its own in-memory
+/// database.
+fn setup_tenants(tenants: &[TenantSettings]) -> (actix_test::TestServer, AppState) {
+    let database = SqliteDatabase::with_path(":memory:");
+    database.migrate().unwrap();
+
+    let mut app_state = AppState::new(
+        test_config(),
+        Handlebars::new(),
+        Arc::new(database),
+        Arc::new(MockGenericHttpClient::default()),
+    );
+
+    let tenants = tenants
+        .iter()
+        .map(|tenant| {
+            let database = SqliteDatabase::with_path(":memory:");
+            database.migrate().unwrap();
+
+            Tenant {
+                name: tenant.name.clone(),
+                hosts: tenant.hosts.clone(),
+                path: tenant_path(tenant),
+                state: app_state.for_tenant(
+                    tenant_config(&app_state.config, tenant),
+                    Handlebars::new(),
+                    Arc::new(database),
+                    &FeatureSettings::default(),
+                ),
+            }
+        })
+        .collect();
+    app_state.tenants = Arc::new(TenantRegistry::new(tenants));
+
+    start_app(app_state)
+}
+
+#[test]
+fn test_validate_tenants() {
+    assert!(validate_tenants(&[]).is_ok());
+    assert!(validate_tenants(&[
+        tenant_settings("flat-1", &[]),
+        tenant_settings("flat-2", &["flat2.example.com"]),
+    ])
+    .is_ok());
+
+    assert!(matches!(
+        validate_tenants(&[tenant_settings("Flat", &[])]),
+        Err(TenantError::InvalidName { .. })
+    ));
+    assert!(matches!(
+        validate_tenants(&[tenant_settings("", &[])]),
+        Err(TenantError::InvalidName { .. })
+    ));
+    assert!(matches!(
+        validate_tenants(&[tenant_settings("flat", &[]), tenant_settings("flat", &[])]),
+        Err(TenantError::DuplicateName { .. })
+    ));
+    assert!(matches!(
+        validate_tenants(&[
+            tenant_settings("a", &["flat.example.com"]),
+            tenant_settings("b", &["FLAT.example.com"]),
+        ]),
+        Err(TenantError::DuplicateHost { .. })
+    ));
+}
+
+#[test]
+fn test_tenant_config() {
+    let mut base = test_config();
+    base.web_root = "https://shaft.example.com".to_string();
+    base.admins = vec!["alice".to_string()];
+
+    let mut tenant = tenant_settings("flat", &[]);
+    tenant.admins = Some(vec!["bob".to_string()]);
+
+    let config = tenant_config(&base, &tenant);
+    assert_eq!(config.web_root, "https://shaft.example.com/t/flat");
+    assert_eq!(config.admins, vec!["bob".to_string()]);
+    assert_eq!(config.github_client_id, base.github_client_id);
+
+    // Tenants on their own hostnames keep the web root.
+    let config = tenant_config(&base, &tenant_settings("flat", &["flat.example.com"]));
+    assert_eq!(config.web_root, base.web_root);
+    assert_eq!(config.admins, base.admins);
+}
+
+/// Test that a path tenant is served from its own database, and that logins
+/// only work for the tenant they belong to.
+#[actix_rt::test]
+async fn test_path_tenant() {
+    let (srv, app_state) = setup_tenants(&[tenant_settings("flat", &[])]);
+    let tenant_state = app_state.tenants.get("flat").unwrap().state.clone();
+
+    let root_cookie = login_user(&app_state, "alice").await;
+    let tenant_cookie = login_user(&tenant_state, "bob").await;
+
+    let mut response = srv
+        .get("/t/flat/api/me")
+        .cookie(tenant_cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["user_id"], "bob");
+
+    let mut response = srv
+        .get("/api/me")
+        .cookie(root_cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["user_id"], "alice");
+
+    // Logins for the other database redirect to that tenant's own login page.
+    // The page to return to is relative to the tenant's web root, which the
+    // login callback adds back.
+    let response = srv
+        .get("/t/flat/api/me")
+        .cookie(root_cookie)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 302);
+    assert_eq!(
+        response.headers().get("location").unwrap(),
+        "/t/flat/login?next=%2Fapi%2Fme"
+    );
+
+    let response = srv
+        .get("/api/me")
+        .cookie(tenant_cookie)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 302);
+}
+
+/// Test that a tenant with hostnames is served on them, and only them.
+#[actix_rt::test]
+async fn test_host_tenant() {
+    let (srv, app_state) = setup_tenants(&[tenant_settings("flat", &["flat.example.com"])]);
+    let tenant_state = app_state.tenants.get("flat").unwrap().state.clone();
+
+    let cookie = login_user(&tenant_state, "bob").await;
+
+    let mut response = srv
+        .get("/api/me")
+        .insert_header(("Host", "flat.example.com"))
+        .cookie(cookie.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["user_id"], "bob");
+
+    let response = srv.get("/api/me").cookie(cookie).send().await.unwrap();
+    assert_eq!(response.status(), 302);
+}
+
+/// Test that instance admins can list the tenants.
+#[actix_rt::test]
+async fn test_list_tenants() {
+    let (srv, app_state) = setup_tenants(&[
+        tenant_settings("flat", &[]),
+        tenant_settings("office", &["office.example.com"]),
+    ]);
+    let cookie = login_user_with_scopes(&app_state, "alice", vec![Scope::Read, Scope::Admin]).await;
+
+    let mut response = srv
+        .get("/api/admin/tenants")
+        .cookie(cookie)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let body: Value = response.json().await.unwrap();
+    let tenants = body["tenants"].as_array().unwrap();
+    assert_eq!(tenants.len(), 2);
+    assert_eq!(tenants[0]["name"], "flat");
+    assert_eq!(tenants[0]["path"], "/t/flat");
+    assert_eq!(tenants[1]["hosts"][0], "office.example.com");
+    assert!(tenants[1]["path"].is_null());
+}
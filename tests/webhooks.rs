@@ -0,0 +1,63 @@
+use hyper::header::HeaderValue;
+use hyper::HeaderMap;
+
+use shaft::webhooks::{sign, verify_signature, VerifyError, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+
+fn signed_headers(secret: &str, timestamp: i64, body: &[u8]) -> HeaderMap {
+    let timestamp = timestamp.to_string();
+    let signature = sign(secret, &timestamp, body).unwrap();
+
+    let mut headers = HeaderMap::new();
+    headers.insert(TIMESTAMP_HEADER, HeaderValue::from_str(&timestamp).unwrap());
+    headers.insert(SIGNATURE_HEADER, HeaderValue::from_str(&signature).unwrap());
+    headers
+}
+
+#[test]
+fn test_sign() {
+    // The HMAC-SHA256 of "v1:1600000000:{}" keyed with "secret".
+    assert_eq!(
+        sign("secret", "1600000000", b"{}").unwrap(),
+        "v1=da866fc9b892ec2d2b009cd153f746dd4139e394492df958c57807d817e72be7"
+    );
+}
+
+#[test]
+fn test_verify_signature() {
+    let body = br#"{"type":"user_poked","actor":"alice","user_id":"bob"}"#;
+    let now = chrono::Utc::now().timestamp();
+
+    let headers = signed_headers("secret", now, body);
+    verify_signature("secret", &headers, body).unwrap();
+
+    assert!(matches!(
+        verify_signature("other", &headers, body),
+        Err(VerifyError::InvalidSignature)
+    ));
+    assert!(matches!(
+        verify_signature("secret", &headers, b"{}"),
+        Err(VerifyError::InvalidSignature)
+    ));
+
+    for sent_at in &[now - 60 * 60, i64::MIN, i64::MAX] {
+        let headers = signed_headers("secret", *sent_at, body);
+        assert!(matches!(
+            verify_signature("secret", &headers, body),
+            Err(VerifyError::TooOld)
+        ));
+    }
+
+    let mut headers = signed_headers("secret", now, body);
+    headers.remove(SIGNATURE_HEADER);
+    assert!(matches!(
+        verify_signature("secret", &headers, body),
+        Err(VerifyError::MissingHeader { .. })
+    ));
+
+    let mut headers = signed_headers("secret", now, body);
+    headers.insert(TIMESTAMP_HEADER, HeaderValue::from_static("yesterday"));
+    assert!(matches!(
+        verify_signature("secret", &headers, body),
+        Err(VerifyError::InvalidTimestamp)
+    ));
+}